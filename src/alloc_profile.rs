@@ -0,0 +1,435 @@
+//! Report per-method allocation statistics of handlers via the [`metrics`][::metrics] facade.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! This middleware measures allocations made while calling and polling each handler, and records
+//! the following histograms to the installed [`metrics::Recorder`][::metrics::Recorder], helping
+//! to find allocation-heavy handlers without external profilers.
+//!
+//! | Name | Labels | Description |
+//! |------|--------|-------------|
+//! | `lsp_request_allocations` | `method` | Allocations of completed requests. |
+//! | `lsp_request_allocated_bytes` | `method` | Bytes allocated by completed requests. |
+//! | `lsp_notification_allocations` | `method` | Allocations of notification handlers. |
+//! | `lsp_notification_allocated_bytes` | `method` | Bytes allocated by notification handlers. |
+//!
+//! The name prefix `lsp` can be changed via [`AllocProfileLayer::prefix`].
+//!
+//! Allocations are counted per thread by [`CountingAllocator`], which must be installed as the
+//! global allocator. Other allocators with per-thread statistics, eg. jemalloc, can be plugged in
+//! via [`AllocProfileLayer::snapshot`] instead. Allocations of tasks spawned by handlers are not
+//! attributed to them, nor are those of requests dropped before completion.
+//!
+//! ```
+//! use async_lsp::alloc_profile::{AllocProfileLayer, CountingAllocator};
+//!
+//! #[global_allocator]
+//! static ALLOC: CountingAllocator = CountingAllocator::system();
+//!
+//! fn main() {
+//!     let layer = AllocProfileLayer::new().prefix("my_server");
+//!     // Apply the layer to the service.
+//! #   drop(layer);
+//! }
+//! ```
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::future::Future;
+use std::ops::ControlFlow;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use ::metrics::histogram;
+use pin_project_lite::pin_project;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{AnyEvent, AnyNotification, AnyRequest, EventHints, LspService, Result};
+
+/// Allocation statistics of a thread, or the difference between two of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AllocStats {
+    /// The number of allocations, including reallocations.
+    pub allocations: u64,
+    /// The number of bytes allocated. Deallocations are not subtracted.
+    pub bytes: u64,
+}
+
+impl AllocStats {
+    /// Create statistics, eg. from counters of another allocator.
+    #[must_use]
+    pub fn new(allocations: u64, bytes: u64) -> Self {
+        Self { allocations, bytes }
+    }
+
+    /// Get allocations made since `earlier`.
+    #[must_use]
+    pub fn since(self, earlier: Self) -> Self {
+        Self {
+            allocations: self.allocations.wrapping_sub(earlier.allocations),
+            bytes: self.bytes.wrapping_sub(earlier.bytes),
+        }
+    }
+}
+
+thread_local! {
+    static THREAD_STATS: Cell<AllocStats> = const {
+        Cell::new(AllocStats {
+            allocations: 0,
+            bytes: 0,
+        })
+    };
+}
+
+/// Get allocation statistics of the current thread counted by [`CountingAllocator`].
+#[must_use]
+pub fn thread_stats() -> AllocStats {
+    THREAD_STATS.try_with(Cell::get).unwrap_or_default()
+}
+
+fn count(size: usize) {
+    // The thread local is unavailable during thread destruction.
+    let _ = THREAD_STATS.try_with(|stats| {
+        let mut cur = stats.get();
+        cur.allocations = cur.allocations.wrapping_add(1);
+        cur.bytes = cur.bytes.wrapping_add(size as u64);
+        stats.set(cur);
+    });
+}
+
+/// The [`GlobalAlloc`] wrapper counting allocations per thread, for [`thread_stats`].
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CountingAllocator<A = System> {
+    inner: A,
+}
+
+impl CountingAllocator {
+    /// Count allocations of the [`System`] allocator.
+    #[must_use]
+    pub const fn system() -> Self {
+        Self::new(System)
+    }
+}
+
+impl<A> CountingAllocator<A> {
+    /// Count allocations of the `inner` allocator.
+    #[must_use]
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+// SAFETY: All operations are forwarded to the inner allocator unchanged.
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size);
+        self.inner.realloc(ptr, layout, new_size)
+    }
+}
+
+#[derive(Debug)]
+struct Names {
+    request_allocations: String,
+    request_bytes: String,
+    notification_allocations: String,
+    notification_bytes: String,
+}
+
+impl Names {
+    fn new(prefix: &str) -> Self {
+        Self {
+            request_allocations: format!("{prefix}_request_allocations"),
+            request_bytes: format!("{prefix}_request_allocated_bytes"),
+            notification_allocations: format!("{prefix}_notification_allocations"),
+            notification_bytes: format!("{prefix}_notification_allocated_bytes"),
+        }
+    }
+}
+
+type SnapshotFn = fn() -> AllocStats;
+
+/// The middleware reporting allocation statistics of handlers.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+pub struct AllocProfile<S> {
+    service: S,
+    names: Arc<Names>,
+    snapshot: SnapshotFn,
+}
+
+define_getters!(impl[S] AllocProfile<S>, service: S);
+
+impl<S: LspService> Service<AnyRequest> for AllocProfile<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        let method = req.method.clone();
+        // Handlers of `Router` are called synchronously here.
+        let start = (self.snapshot)();
+        let fut = self.service.call(req);
+        let stats = (self.snapshot)().since(start);
+        ResponseFuture {
+            fut,
+            names: self.names.clone(),
+            snapshot: self.snapshot,
+            method,
+            stats,
+        }
+    }
+}
+
+impl<S: LspService> LspService for AllocProfile<S> {
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        let method = notif.method.clone();
+        let start = (self.snapshot)();
+        let ret = self.service.notify(notif);
+        let stats = (self.snapshot)().since(start);
+        let names = &*self.names;
+        record(
+            &names.notification_allocations,
+            &names.notification_bytes,
+            method,
+            stats,
+        );
+        ret
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.service.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+fn record(allocations: &str, bytes: &str, method: String, stats: AllocStats) {
+    histogram!(allocations.to_owned(), "method" => method.clone()).record(stats.allocations as f64);
+    histogram!(bytes.to_owned(), "method" => method).record(stats.bytes as f64);
+}
+
+pin_project! {
+    /// The [`Future`] type used by the [`AllocProfile`] middleware.
+    pub struct ResponseFuture<Fut> {
+        #[pin]
+        fut: Fut,
+        names: Arc<Names>,
+        snapshot: SnapshotFn,
+        method: String,
+        stats: AllocStats,
+    }
+}
+
+impl<Fut: Future> Future for ResponseFuture<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let start = (this.snapshot)();
+        let ret = this.fut.poll(cx);
+        let stats = (this.snapshot)().since(start);
+        this.stats.allocations = this.stats.allocations.wrapping_add(stats.allocations);
+        this.stats.bytes = this.stats.bytes.wrapping_add(stats.bytes);
+        let ret = ready!(ret);
+        record(
+            &this.names.request_allocations,
+            &this.names.request_bytes,
+            std::mem::take(this.method),
+            *this.stats,
+        );
+        Poll::Ready(ret)
+    }
+}
+
+/// A [`tower_layer::Layer`] which builds [`AllocProfile`].
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct AllocProfileLayer {
+    names: Arc<Names>,
+    snapshot: SnapshotFn,
+}
+
+impl Default for AllocProfileLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AllocProfileLayer {
+    /// Create the layer with the default metric name prefix `lsp`, counting allocations by
+    /// [`CountingAllocator`].
+    pub fn new() -> Self {
+        Self {
+            names: Arc::new(Names::new("lsp")),
+            snapshot: thread_stats,
+        }
+    }
+
+    /// Set the prefix of metric names, eg. `my_server` for `my_server_request_allocations`.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.names = Arc::new(Names::new(prefix));
+        self
+    }
+
+    /// Set the function taking statistics of the current thread, to use another allocator than
+    /// [`CountingAllocator`]. Default is [`thread_stats`].
+    pub fn snapshot(mut self, snapshot: fn() -> AllocStats) -> Self {
+        self.snapshot = snapshot;
+        self
+    }
+}
+
+impl<S> Layer<S> for AllocProfileLayer {
+    type Service = AllocProfile<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AllocProfile {
+            service: inner,
+            names: self.names.clone(),
+            snapshot: self.snapshot,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+
+    use futures::FutureExt;
+    use lsp_types::notification::{self, Notification};
+    use lsp_types::request::{self, Request};
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::{RequestId, ResponseError};
+
+    #[test]
+    fn counting_allocator() {
+        let alloc = CountingAllocator::system();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let start = thread_stats();
+        // SAFETY: The layout is non-zero sized, and the pointer is freed with the same layout.
+        unsafe {
+            let ptr = alloc.alloc(layout);
+            assert!(!ptr.is_null());
+            let ptr = alloc.realloc(ptr, layout, 128);
+            assert!(!ptr.is_null());
+            alloc.dealloc(ptr, Layout::from_size_align(128, 8).unwrap());
+        }
+        assert_eq!(thread_stats().since(start), AllocStats::new(2, 192));
+    }
+
+    thread_local! {
+        static FAKE_STATS: Cell<AllocStats> = Cell::default();
+    }
+
+    fn fake_alloc(bytes: u64) {
+        FAKE_STATS.with(|stats| {
+            let cur = stats.get();
+            stats.set(AllocStats::new(cur.allocations + 1, cur.bytes + bytes));
+        });
+    }
+
+    #[test]
+    fn record() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        ::metrics::with_local_recorder(&recorder, || {
+            let mut router = Router::<(), ResponseError>::new(());
+            router
+                .request::<request::Shutdown, _>(|_, ()| {
+                    fake_alloc(10);
+                    async {
+                        fake_alloc(20);
+                        Ok(())
+                    }
+                })
+                .notification::<notification::Initialized>(|_, _| {
+                    fake_alloc(5);
+                    ControlFlow::Continue(())
+                });
+            let mut svc = AllocProfileLayer::new()
+                .prefix("test")
+                .snapshot(|| FAKE_STATS.with(Cell::get))
+                .layer(router);
+
+            poll_fn(|cx| svc.poll_ready(cx))
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+            let fut = svc.call(AnyRequest {
+                id: RequestId::Number(0),
+                method: request::Shutdown::METHOD.into(),
+                params: json!(null),
+                receipt: None,
+            });
+            // Allocations outside of handlers are not counted.
+            fake_alloc(100);
+            fut.now_or_never().unwrap().unwrap();
+            let _ = svc.notify(AnyNotification {
+                method: notification::Initialized::METHOD.into(),
+                params: json!({}),
+                receipt: None,
+            });
+
+            let snapshot = snapshotter.snapshot().into_vec();
+            let get = |name: &str, method: &str| {
+                snapshot
+                    .iter()
+                    .find(|(key, ..)| {
+                        let key = key.key();
+                        key.name() == name
+                            && key
+                                .labels()
+                                .map(|label| (label.key(), label.value()))
+                                .eq([("method", method)])
+                    })
+                    .map(|(.., value)| value)
+            };
+            let hist = |v: f64| Some(DebugValue::Histogram(vec![v.into()]));
+            assert_eq!(
+                get("test_request_allocations", "shutdown"),
+                hist(2.0).as_ref()
+            );
+            assert_eq!(
+                get("test_request_allocated_bytes", "shutdown"),
+                hist(30.0).as_ref()
+            );
+            assert_eq!(
+                get("test_notification_allocations", "initialized"),
+                hist(1.0).as_ref()
+            );
+            assert_eq!(
+                get("test_notification_allocated_bytes", "initialized"),
+                hist(5.0).as_ref()
+            );
+        });
+    }
+}
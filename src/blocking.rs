@@ -0,0 +1,217 @@
+//! Run main loops without an async runtime.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! Some environments forbid async runtimes like tokio, eg. certain editor plugins or sandboxes.
+//! [`MainLoop::run_blocking`] drives a main loop, with all its routers and middlewares, on the
+//! current thread using a minimal executor which parks the thread until some future is woken. It
+//! returns when the main loop exits.
+//!
+//! Since blocking reads would stall handlers, `input` is read on a dedicated thread, and chunks
+//! are passed to the main loop via a channel of bounded capacity. `output` is written on the
+//! current thread in a blocking manner.
+//!
+//! Handler futures are polled on the current thread, so they must not block it. Futures relying on
+//! a specific runtime, eg. timers of tokio, do not work either. Use runtime-agnostic ones, eg.
+//! from `async-io`, instead.
+//!
+//! ```no_run
+//! # use async_lsp::router::Router;
+//! # use async_lsp::MainLoop;
+//! let (mainloop, _client) = MainLoop::new_server(|_client| {
+//!     let router = Router::new(());
+//!     // Register handlers and add middlewares...
+//!     router
+//! });
+//! mainloop.run_blocking(std::io::stdin(), std::io::stdout()).unwrap();
+//! ```
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread::{self, Thread};
+
+use futures::channel::mpsc;
+use futures::io::AsyncWrite;
+use futures::task::ArcWake;
+use futures::{pin_mut, SinkExt, TryStreamExt};
+use serde_json::Value as JsonValue;
+
+use crate::{LspService, MainLoop, ResponseError, Result};
+
+/// The size of chunks read from the input.
+const CHUNK_SIZE: usize = 8 << 10;
+
+impl<S> MainLoop<S>
+where
+    S: LspService<Response = JsonValue>,
+    ResponseError: From<S::Error>,
+{
+    /// Drive the service main loop on the current thread, without an async runtime.
+    ///
+    /// The reader thread of `input` is detached, and may keep blocking on `input` after the main
+    /// loop exits. See [module level documentations](crate::blocking) for details.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`MainLoop::run`].
+    pub fn run_blocking(self, input: impl Read + Send + 'static, output: impl Write) -> Result<()> {
+        let (tx, rx) = mpsc::channel(1);
+        thread::Builder::new()
+            .name("lsp-input".into())
+            .spawn(move || read_chunks(input, tx))?;
+        block_on(self.run_buffered(rx.into_async_read(), BlockingWrite(output)))
+    }
+}
+
+/// Forward chunks of `input` into `tx`, until EOF, an error, or the main loop exits.
+fn read_chunks(mut input: impl Read, mut tx: mpsc::Sender<io::Result<Vec<u8>>>) {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let ret = match input.read(&mut buf) {
+            Ok(0) => return,
+            Ok(len) => Ok(buf[..len].to_vec()),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => Err(err),
+        };
+        let is_err = ret.is_err();
+        if block_on(tx.send(ret)).is_err() || is_err {
+            return;
+        }
+    }
+}
+
+/// Poll `fut` to completion on the current thread, parking it while pending.
+fn block_on<F: Future>(fut: F) -> F::Output {
+    struct Unparker(Thread);
+
+    impl ArcWake for Unparker {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.unpark();
+        }
+    }
+
+    let waker = futures::task::waker(Arc::new(Unparker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    pin_mut!(fut);
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(ret) => return ret,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// The [`AsyncWrite`] adapter writing to a blocking [`Write`] in place.
+struct BlockingWrite<W>(W);
+
+// `W` is never pinned.
+impl<W> Unpin for BlockingWrite<W> {}
+
+impl<W: Write> AsyncWrite for BlockingWrite<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.get_mut().0.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.get_mut().0.flush())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc as std_mpsc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use futures::channel::oneshot;
+    use lsp_types::request::{self, Request};
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::Error;
+
+    #[test]
+    fn run_blocking() {
+        let (input_tx, input_rx) = std_mpsc::channel::<Vec<u8>>();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let (mainloop, _client) = MainLoop::new_server(|_| {
+            let mut router = Router::new(());
+            router.request::<request::Shutdown, _>(|_, ()| {
+                // Complete on another thread, to wake the parked main loop.
+                let (tx, rx) = oneshot::channel();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(10));
+                    tx.send(()).unwrap();
+                });
+                async move {
+                    rx.await.unwrap();
+                    Ok(())
+                }
+            });
+            router
+        });
+        let mainloop = thread::spawn({
+            let output = SharedBuf(output.clone());
+            move || mainloop.run_blocking(ChannelReader(input_rx, Vec::new()), output)
+        });
+
+        let req = json!({ "jsonrpc": "2.0", "id": 1, "method": request::Shutdown::METHOD });
+        let content = req.to_string();
+        let frame = format!("Content-Length: {}\r\n\r\n{content}", content.len());
+        input_tx.send(frame.into_bytes()).unwrap();
+
+        let expect = r#"{"jsonrpc":"2.0","id":1,"result":null}"#;
+        let expect = format!("Content-Length: {}\r\n\r\n{expect}", expect.len());
+        for _ in 0..500 {
+            if output.lock().unwrap().len() >= expect.len() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(&*output.lock().unwrap(), expect.as_bytes());
+
+        drop(input_tx);
+        assert!(matches!(mainloop.join().unwrap(), Err(Error::Eof)));
+    }
+
+    struct ChannelReader(std_mpsc::Receiver<Vec<u8>>, Vec<u8>);
+
+    impl Read for ChannelReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.1.is_empty() {
+                match self.0.recv() {
+                    Ok(data) => self.1 = data,
+                    Err(_) => return Ok(0),
+                }
+            }
+            let len = buf.len().min(self.1.len());
+            buf[..len].copy_from_slice(&self.1[..len]);
+            self.1.drain(..len);
+            Ok(len)
+        }
+    }
+
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
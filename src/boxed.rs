@@ -0,0 +1,299 @@
+//! Type-erased services.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! [`BoxLspService`] erases the type of an [`LspService`], eg. to choose services at runtime, or
+//! to shorten long types of layered services. [`BoxCloneLspService`] is additionally [`Clone`],
+//! so services can be stored in collections, swapped at runtime, or cloned into per-connection
+//! instances for servers accepting multiple connections.
+//!
+//! Both are `Send` and `Sync` as long as the inner service is `Send`, since the inner service is
+//! only shared behind a lock, which is only taken by [`LspService::event_hints`] and cloning.
+//!
+//! ```
+//! # use async_lsp::boxed::BoxLspService;
+//! # use async_lsp::router::Router;
+//! # use async_lsp::server::LifecycleLayer;
+//! # use tower_layer::Layer;
+//! # let lifecycle = true;
+//! let router: Router<()> = Router::new(());
+//! let service = if lifecycle {
+//!     BoxLspService::new(LifecycleLayer::default().layer(router))
+//! } else {
+//!     BoxLspService::new(router)
+//! };
+//! ```
+use std::fmt;
+use std::ops::ControlFlow;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde_json::Value as JsonValue;
+use tower_layer::{layer_fn, LayerFn};
+use tower_service::Service;
+
+use crate::{AnyEvent, AnyNotification, AnyRequest, EventHints, LspService, ResponseError, Result};
+
+type BoxedFuture<Response, Error> = BoxFuture<'static, Result<Response, Error>>;
+
+/// An object-safe [`LspService`] with boxed futures.
+trait ErasedLspService<Response, Error>:
+    LspService<Response = Response, Error = Error, Future = BoxedFuture<Response, Error>> + Send
+{
+}
+
+impl<S, Response, Error> ErasedLspService<Response, Error> for S where
+    S: LspService<Response = Response, Error = Error, Future = BoxedFuture<Response, Error>> + Send
+{
+}
+
+/// An object-safe [`LspService`] with boxed futures, which can be cloned into a box.
+trait CloneLspService<Response, Error>: ErasedLspService<Response, Error> {
+    fn clone_box(&self) -> Box<dyn CloneLspService<Response, Error>>;
+}
+
+impl<S, Response, Error> CloneLspService<Response, Error> for S
+where
+    S: ErasedLspService<Response, Error> + Clone + 'static,
+{
+    fn clone_box(&self) -> Box<dyn CloneLspService<Response, Error>> {
+        Box::new(self.clone())
+    }
+}
+
+/// The adapter boxing futures of the inner service.
+#[derive(Clone)]
+struct MapBoxFuture<S>(S);
+
+impl<S> Service<AnyRequest> for MapBoxFuture<S>
+where
+    S: LspService,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxedFuture<S::Response, S::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        self.0.call(req).boxed()
+    }
+}
+
+impl<S> LspService for MapBoxFuture<S>
+where
+    S: LspService,
+    S::Future: Send + 'static,
+{
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        self.0.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.0.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.0.event_hints(event)
+    }
+}
+
+macro_rules! impl_boxed {
+    ($name:ident) => {
+        impl<Response, Error> fmt::Debug for $name<Response, Error> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.debug_struct(stringify!($name)).finish_non_exhaustive()
+            }
+        }
+
+        impl<Response, Error> Service<AnyRequest> for $name<Response, Error> {
+            type Response = Response;
+            type Error = Error;
+            type Future = BoxedFuture<Response, Error>;
+
+            fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+                self.service.get_mut().unwrap().poll_ready(cx)
+            }
+
+            fn call(&mut self, req: AnyRequest) -> Self::Future {
+                self.service.get_mut().unwrap().call(req)
+            }
+        }
+
+        impl<Response, Error> LspService for $name<Response, Error> {
+            fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+                self.service.get_mut().unwrap().notify(notif)
+            }
+
+            fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+                self.service.get_mut().unwrap().emit(event)
+            }
+
+            fn event_hints(&self, event: &AnyEvent) -> EventHints {
+                self.service.lock().unwrap().event_hints(event)
+            }
+        }
+    };
+}
+
+/// A type-erased [`LspService`] which is `Send` and `Sync`.
+///
+/// See [module level documentations](self) for details.
+pub struct BoxLspService<Response = JsonValue, Error = ResponseError> {
+    service: Mutex<Box<dyn ErasedLspService<Response, Error>>>,
+}
+
+impl<Response, Error> BoxLspService<Response, Error> {
+    /// Erase the type of `service`.
+    #[must_use]
+    pub fn new<S>(service: S) -> Self
+    where
+        S: LspService<Response = Response, Error = Error> + Send + 'static,
+        S::Future: Send + 'static,
+    {
+        Self {
+            service: Mutex::new(Box::new(MapBoxFuture(service))),
+        }
+    }
+
+    /// Create a [`tower_layer::Layer`] erasing the type of the service it wraps.
+    #[must_use]
+    pub fn layer<S>() -> LayerFn<fn(S) -> Self>
+    where
+        S: LspService<Response = Response, Error = Error> + Send + 'static,
+        S::Future: Send + 'static,
+    {
+        layer_fn(Self::new)
+    }
+}
+
+impl_boxed!(BoxLspService);
+
+/// A type-erased [`LspService`] which is `Send`, `Sync` and [`Clone`].
+///
+/// See [module level documentations](self) for details.
+pub struct BoxCloneLspService<Response = JsonValue, Error = ResponseError> {
+    service: Mutex<Box<dyn CloneLspService<Response, Error>>>,
+}
+
+impl<Response, Error> BoxCloneLspService<Response, Error> {
+    /// Erase the type of `service`.
+    #[must_use]
+    pub fn new<S>(service: S) -> Self
+    where
+        S: LspService<Response = Response, Error = Error> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+    {
+        Self {
+            service: Mutex::new(Box::new(MapBoxFuture(service))),
+        }
+    }
+
+    /// Create a [`tower_layer::Layer`] erasing the type of the service it wraps.
+    #[must_use]
+    pub fn layer<S>() -> LayerFn<fn(S) -> Self>
+    where
+        S: LspService<Response = Response, Error = Error> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+    {
+        layer_fn(Self::new)
+    }
+}
+
+impl<Response, Error> Clone for BoxCloneLspService<Response, Error> {
+    fn clone(&self) -> Self {
+        Self {
+            service: Mutex::new(self.service.lock().unwrap().clone_box()),
+        }
+    }
+}
+
+impl_boxed!(BoxCloneLspService);
+
+#[cfg(test)]
+mod tests {
+    use std::future::{poll_fn, ready, Ready};
+
+    use serde_json::json;
+    use tower_layer::Layer;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::RequestId;
+
+    fn _assert_send_sync<T: Send + Sync>(_: T) {}
+
+    fn _assert_boxed_send_sync(a: BoxLspService, b: BoxCloneLspService) {
+        _assert_send_sync(a);
+        _assert_send_sync(b);
+    }
+
+    /// Responds with the number of requests it handled.
+    #[derive(Clone, Default)]
+    struct Counter(u64);
+
+    impl Service<AnyRequest> for Counter {
+        type Response = JsonValue;
+        type Error = ResponseError;
+        type Future = Ready<Result<JsonValue, ResponseError>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _req: AnyRequest) -> Self::Future {
+            self.0 += 1;
+            ready(Ok(json!(self.0)))
+        }
+    }
+
+    impl LspService for Counter {
+        fn notify(&mut self, _notif: AnyNotification) -> ControlFlow<Result<()>> {
+            ControlFlow::Continue(())
+        }
+
+        fn emit(&mut self, _event: AnyEvent) -> ControlFlow<Result<()>> {
+            ControlFlow::Continue(())
+        }
+    }
+
+    async fn call(service: &mut impl LspService<Response = JsonValue>) -> JsonValue {
+        poll_fn(|cx| service.poll_ready(cx))
+            .await
+            .unwrap_or_else(|_| panic!("not ready"));
+        let req = AnyRequest {
+            id: RequestId::Number(0),
+            method: "foo".into(),
+            params: JsonValue::Null,
+            receipt: None,
+        };
+        service
+            .call(req)
+            .await
+            .unwrap_or_else(|_| panic!("request failed"))
+    }
+
+    #[tokio::test]
+    async fn boxed() {
+        let mut router = Router::new(());
+        router.unhandled_request(|_, _| ready(Ok(json!("router"))));
+        let mut services = [
+            BoxLspService::new(router),
+            BoxLspService::layer().layer(Counter::default()),
+        ];
+        assert_eq!(call(&mut services[0]).await, json!("router"));
+        assert_eq!(call(&mut services[1]).await, json!(1));
+
+        let mut counter = BoxCloneLspService::new(Counter::default());
+        assert_eq!(call(&mut counter).await, json!(1));
+        let mut cloned = counter.clone();
+        assert_eq!(call(&mut cloned).await, json!(2));
+        assert_eq!(call(&mut cloned).await, json!(3));
+        assert_eq!(call(&mut counter).await, json!(2));
+    }
+}
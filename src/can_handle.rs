@@ -0,0 +1,243 @@
+//! Composing independent services by the messages they handle.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! A service implementing [`CanHandle`] tells whether it handles a request, notification or
+//! event without consuming it. [`Router`](crate::router::Router) implements it based on its
+//! registered handlers. The [`Fallback`] combinator routes each message to the first
+//! service if it can handle it, or to the fallback service otherwise. Combinators can be nested,
+//! so a modular server can be assembled from independent feature services, eg.
+//! `Fallback::new(diagnostics, Fallback::new(completion, formatting))`, where the last service is
+//! responsible for all remaining messages.
+use std::ops::ControlFlow;
+use std::task::{ready, Context, Poll};
+
+use futures::future::Either;
+use tower_service::Service;
+
+use crate::{AnyEvent, AnyNotification, AnyRequest, EventHints, LspService, Result};
+
+/// A service which can tell whether it handles a message of type `T`.
+///
+/// See [module level documentations](self) for details.
+pub trait CanHandle<T> {
+    /// Returns whether `msg` would be handled by this service, instead of being rejected or
+    /// ignored as unhandled.
+    fn can_handle(&self, msg: &T) -> bool;
+}
+
+/// The combinator routing messages to the first service if it can handle them, or to the
+/// fallback service otherwise.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone)]
+pub struct Fallback<A, B> {
+    first: A,
+    fallback: B,
+}
+
+impl<A, B> Fallback<A, B> {
+    /// Create the combinator routing messages to `first` if it can handle them, or to `fallback`
+    /// otherwise.
+    #[must_use]
+    pub fn new(first: A, fallback: B) -> Self {
+        Self { first, fallback }
+    }
+
+    /// Get references to the first and the fallback service.
+    #[must_use]
+    pub fn get_ref(&self) -> (&A, &B) {
+        (&self.first, &self.fallback)
+    }
+
+    /// Get mutable references to the first and the fallback service.
+    #[must_use]
+    pub fn get_mut(&mut self) -> (&mut A, &mut B) {
+        (&mut self.first, &mut self.fallback)
+    }
+
+    /// Consume self, returning the first and the fallback service.
+    #[must_use]
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.fallback)
+    }
+}
+
+impl<A, B> Service<AnyRequest> for Fallback<A, B>
+where
+    A: LspService + CanHandle<AnyRequest>,
+    B: LspService<Response = A::Response, Error = A::Error>,
+{
+    type Response = A::Response;
+    type Error = A::Error;
+    type Future = Either<A::Future, B::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Which service to call is unknown yet. Both must be ready.
+        ready!(self.first.poll_ready(cx))?;
+        self.fallback.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        if self.first.can_handle(&req) {
+            Either::Left(self.first.call(req))
+        } else {
+            Either::Right(self.fallback.call(req))
+        }
+    }
+}
+
+impl<A, B> LspService for Fallback<A, B>
+where
+    A: LspService + CanHandle<AnyRequest> + CanHandle<AnyNotification> + CanHandle<AnyEvent>,
+    B: LspService<Response = A::Response, Error = A::Error>,
+{
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        if self.first.can_handle(&notif) {
+            self.first.notify(notif)
+        } else {
+            self.fallback.notify(notif)
+        }
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        if self.first.can_handle(&event) {
+            self.first.emit(event)
+        } else {
+            self.fallback.emit(event)
+        }
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        if self.first.can_handle(event) {
+            self.first.event_hints(event)
+        } else {
+            self.fallback.event_hints(event)
+        }
+    }
+}
+
+impl<T, A: CanHandle<T>, B: CanHandle<T>> CanHandle<T> for Fallback<A, B> {
+    fn can_handle(&self, msg: &T) -> bool {
+        self.first.can_handle(msg) || self.fallback.can_handle(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{poll_fn, ready, Ready};
+
+    use serde_json::{json, Value as JsonValue};
+
+    use super::*;
+    use crate::{RequestId, ResponseError};
+
+    /// A feature service handling a fixed set of methods, and recording notifications.
+    struct Feature {
+        name: &'static str,
+        methods: &'static [&'static str],
+        notified: Vec<String>,
+    }
+
+    impl Feature {
+        fn new(name: &'static str, methods: &'static [&'static str]) -> Self {
+            Self {
+                name,
+                methods,
+                notified: Vec::new(),
+            }
+        }
+    }
+
+    impl Service<AnyRequest> for Feature {
+        type Response = JsonValue;
+        type Error = ResponseError;
+        type Future = Ready<Result<JsonValue, ResponseError>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _req: AnyRequest) -> Self::Future {
+            ready(Ok(json!(self.name)))
+        }
+    }
+
+    impl LspService for Feature {
+        fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+            self.notified.push(notif.method);
+            ControlFlow::Continue(())
+        }
+
+        fn emit(&mut self, _event: AnyEvent) -> ControlFlow<Result<()>> {
+            ControlFlow::Continue(())
+        }
+    }
+
+    impl CanHandle<AnyRequest> for Feature {
+        fn can_handle(&self, req: &AnyRequest) -> bool {
+            self.methods.contains(&&*req.method)
+        }
+    }
+
+    impl CanHandle<AnyNotification> for Feature {
+        fn can_handle(&self, notif: &AnyNotification) -> bool {
+            self.methods.contains(&&*notif.method)
+        }
+    }
+
+    impl CanHandle<AnyEvent> for Feature {
+        fn can_handle(&self, _event: &AnyEvent) -> bool {
+            false
+        }
+    }
+
+    #[tokio::test]
+    async fn fallback() {
+        let mut service = Fallback::new(
+            Feature::new(
+                "diagnostics",
+                &["textDocument/diagnostic", "textDocument/didOpen"],
+            ),
+            Fallback::new(
+                Feature::new("completion", &["textDocument/completion"]),
+                Feature::new("rest", &[]),
+            ),
+        );
+        assert!(service.can_handle(&AnyNotification {
+            method: "textDocument/didOpen".into(),
+            params: json!(null),
+            receipt: None,
+        }));
+        assert!(!service.can_handle(&AnyEvent::new(())));
+
+        for (method, expect) in [
+            ("textDocument/diagnostic", "diagnostics"),
+            ("textDocument/completion", "completion"),
+            ("textDocument/hover", "rest"),
+        ] {
+            poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+            let req = AnyRequest {
+                id: RequestId::Number(0),
+                method: method.into(),
+                params: json!(null),
+                receipt: None,
+            };
+            assert_eq!(service.call(req).await.unwrap(), json!(expect));
+        }
+
+        for method in ["textDocument/didOpen", "textDocument/didClose"] {
+            let notif = AnyNotification {
+                method: method.into(),
+                params: json!(null),
+                receipt: None,
+            };
+            assert!(matches!(service.notify(notif), ControlFlow::Continue(())));
+        }
+        let (diagnostics, rest) = service.into_inner();
+        let (completion, rest) = rest.into_inner();
+        assert_eq!(diagnostics.notified, ["textDocument/didOpen"]);
+        assert!(completion.notified.is_empty());
+        assert_eq!(rest.notified, ["textDocument/didClose"]);
+    }
+}
@@ -0,0 +1,467 @@
+//! Stream large payloads as a sequence of ordered chunk notifications.
+//!
+//! *Applies to both Language Servers and Language Clients, but requires both peers to understand
+//! the chunk protocol, eg. both built on this crate.*
+//!
+//! Some editors or transports impose a limit on the size of a single message. Instead of
+//! responding a huge value directly, the handler can use [`ChunkSender::send`] to split the
+//! serialized value into ordered [`Chunk`] notifications (method `$/asyncLsp/chunk`), and respond
+//! the returned small [`ChunkedResponse`] which carries the stream token, the number of chunks,
+//! the total length and a checksum.
+//!
+//! The peer installs [`ChunkReceiver::receive`] as the notification handler of [`Chunk`], and
+//! calls [`ChunkReceiver::finish`] on the [`ChunkedResponse`] it got to reassemble and verify the
+//! original value.
+//!
+//! Note that chunk notifications and the final response are queued separately in the main loop.
+//! The response may be received before some trailing chunks, thus [`ChunkReceiver::finish`]
+//! returns a `Future` waiting for all chunks of the stream.
+//!
+//! Chunks of a stream are buffered before its response is claimed by [`ChunkReceiver::finish`].
+//! Streams may never be claimed, eg. if the request is cancelled. To bound the memory, at most
+//! [`ChunkReceiver::DEFAULT_MAX_UNCLAIMED`] unclaimed streams are kept, and the oldest ones are
+//! evicted when new ones arrive. Later chunks of evicted streams are ignored, and claiming them
+//! fails.
+use std::collections::{HashMap, VecDeque};
+use std::future::{poll_fn, Future};
+use std::num::NonZeroUsize;
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+
+use lsp_types::notification::Notification;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::{ClientSocket, Error, PeerSocket, Result, ServerSocket};
+
+/// The chunk notification carrying a piece of a serialized value.
+#[derive(Debug)]
+pub enum Chunk {}
+
+impl Notification for Chunk {
+    type Params = ChunkParams;
+    const METHOD: &'static str = "$/asyncLsp/chunk";
+}
+
+/// The parameters of [`Chunk`] notification.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChunkParams {
+    /// The token identifying the stream this chunk belongs to.
+    pub token: String,
+    /// The zero-based sequence number of this chunk in the stream.
+    pub seq: u32,
+    /// The piece of serialized JSON text.
+    pub data: String,
+}
+
+/// The final small response of a chunked stream, to be returned in place of the original value.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChunkedResponse {
+    /// The token identifying the stream.
+    pub token: String,
+    /// The total number of chunks sent.
+    pub chunks: u32,
+    /// The total length in bytes of the serialized JSON text.
+    pub length: usize,
+    /// The checksum of the serialized JSON text, as 64-bit FNV-1a hash in lower hex.
+    pub checksum: String,
+}
+
+/// The sender splitting values into [`Chunk`] notifications.
+///
+/// It can be created from either a [`ClientSocket`] or a [`ServerSocket`].
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct ChunkSender {
+    socket: PeerSocket,
+    chunk_size: NonZeroUsize,
+}
+
+impl From<ClientSocket> for ChunkSender {
+    fn from(socket: ClientSocket) -> Self {
+        Self::new(socket.0)
+    }
+}
+
+impl From<ServerSocket> for ChunkSender {
+    fn from(socket: ServerSocket) -> Self {
+        Self::new(socket.0)
+    }
+}
+
+impl ChunkSender {
+    /// The default maximum size in bytes of each chunk: 64KiB.
+    pub const DEFAULT_CHUNK_SIZE: usize = 64 << 10;
+
+    fn new(socket: PeerSocket) -> Self {
+        Self {
+            socket,
+            chunk_size: NonZeroUsize::new(Self::DEFAULT_CHUNK_SIZE).unwrap(),
+        }
+    }
+
+    /// Set the maximum size in bytes of each chunk.
+    ///
+    /// Chunks are always split on UTF-8 character boundaries, thus a chunk may contain a whole
+    /// character exceeding this limit if it is less than 4 bytes.
+    pub fn chunk_size(mut self, chunk_size: NonZeroUsize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Serialize `value` and send it as a stream of [`Chunk`] notifications.
+    ///
+    /// The returned [`ChunkedResponse`] should be sent to the peer afterwards, typically as the
+    /// response of the current request.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::ServiceStopped`] when the service main loop stopped.
+    /// - [`Error::Deserialize`] when `value` fails to serialize.
+    pub fn send<T: Serialize>(&self, value: &T) -> Result<ChunkedResponse> {
+        let text = serde_json::to_string(value)?;
+        let token = crate::unique_token("chunk");
+        let mut seq = 0u32;
+        let mut rest = &*text;
+        while !rest.is_empty() {
+            let mut end = self.chunk_size.get().min(rest.len());
+            while !rest.is_char_boundary(end) {
+                end += 1;
+            }
+            let (data, tail) = rest.split_at(end);
+            self.socket.notify::<Chunk>(ChunkParams {
+                token: token.clone(),
+                seq,
+                data: data.into(),
+            })?;
+            seq += 1;
+            rest = tail;
+        }
+        Ok(ChunkedResponse {
+            token,
+            chunks: seq,
+            length: text.len(),
+            checksum: checksum(text.as_bytes()),
+        })
+    }
+}
+
+/// The receiver reassembling values from [`Chunk`] notifications.
+///
+/// This is a cheaply cloneable shared handle. Typically one clone is stored in the state for the
+/// notification handler, and another is used where requests are sent.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone, Default)]
+pub struct ChunkReceiver {
+    streams: Arc<Mutex<Streams>>,
+}
+
+#[derive(Debug)]
+struct Streams {
+    streams: HashMap<String, Stream>,
+    max_unclaimed: usize,
+    /// The sequence number of the next new stream.
+    next_seq: u64,
+    /// Tokens of recently evicted streams, at most `max_unclaimed` ones.
+    evicted: VecDeque<String>,
+}
+
+impl Default for Streams {
+    fn default() -> Self {
+        Self {
+            streams: HashMap::new(),
+            max_unclaimed: ChunkReceiver::DEFAULT_MAX_UNCLAIMED,
+            next_seq: 0,
+            evicted: VecDeque::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stream {
+    data: String,
+    received: u32,
+    waker: Option<Waker>,
+    /// Whether [`ChunkReceiver::finish`] is waiting for it.
+    claimed: bool,
+    seq: u64,
+}
+
+impl Streams {
+    fn get_or_insert(&mut self, token: String) -> &mut Stream {
+        if !self.streams.contains_key(&token) {
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            self.streams.insert(
+                token.clone(),
+                Stream {
+                    seq,
+                    ..Stream::default()
+                },
+            );
+        }
+        self.streams.get_mut(&token).expect("Inserted")
+    }
+
+    /// Evict the oldest unclaimed streams exceeding the limit.
+    fn evict(&mut self) {
+        loop {
+            let unclaimed = self.streams.iter().filter(|(_, stream)| !stream.claimed);
+            if unclaimed.clone().count() <= self.max_unclaimed {
+                return;
+            }
+            let token = unclaimed
+                .min_by_key(|(_, stream)| stream.seq)
+                .map(|(token, _)| token.clone())
+                .expect("Not empty");
+            self.streams.remove(&token);
+            #[cfg(feature = "tracing")]
+            ::tracing::warn!(token, "unclaimed chunk stream evicted");
+            if self.evicted.len() >= self.max_unclaimed {
+                self.evicted.pop_front();
+            }
+            self.evicted.push_back(token);
+        }
+    }
+}
+
+impl ChunkReceiver {
+    /// The default maximum number of unclaimed streams buffered: 16.
+    pub const DEFAULT_MAX_UNCLAIMED: usize = 16;
+
+    /// Create an empty receiver.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the maximum number of streams buffered before being claimed by
+    /// [`ChunkReceiver::finish`]. The oldest ones are evicted when it is exceeded.
+    ///
+    /// This should be called before cloning the receiver.
+    #[must_use]
+    pub fn max_unclaimed(self, streams: usize) -> Self {
+        self.streams.lock().unwrap().max_unclaimed = streams;
+        self
+    }
+
+    /// The handler of [`Chunk`] notifications.
+    ///
+    /// It breaks the main loop with [`Error::Protocol`] if chunks of a stream are out of order.
+    /// Chunks of evicted streams are ignored.
+    pub fn receive(&self, params: ChunkParams) -> ControlFlow<Result<()>> {
+        let mut streams = self.streams.lock().unwrap();
+        if streams.evicted.contains(&params.token) {
+            return ControlFlow::Continue(());
+        }
+        let stream = streams.get_or_insert(params.token.clone());
+        if stream.received != params.seq {
+            return ControlFlow::Break(Err(Error::Protocol(format!(
+                "Unexpected chunk sequence number {}, expecting {}",
+                params.seq, stream.received,
+            ))));
+        }
+        stream.data.push_str(&params.data);
+        stream.received += 1;
+        if let Some(waker) = stream.waker.take() {
+            waker.wake();
+        }
+        streams.evict();
+        ControlFlow::Continue(())
+    }
+
+    /// Wait for all chunks of the stream described by `resp`, verify and deserialize the value.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Protocol`] if the stream is evicted, or the reassembled text mismatches the
+    ///   length or checksum.
+    /// - [`Error::Deserialize`] if the reassembled text fails to deserialize into `T`.
+    pub fn finish<T: DeserializeOwned>(
+        &self,
+        resp: ChunkedResponse,
+    ) -> impl Future<Output = Result<T>> {
+        let streams = self.streams.clone();
+        async move {
+            let _guard = ClaimGuard {
+                streams: streams.clone(),
+                token: resp.token.clone(),
+            };
+            let data = poll_fn(|cx| {
+                let mut streams = streams.lock().unwrap();
+                if streams.evicted.contains(&resp.token) {
+                    return Poll::Ready(Err(Error::Protocol(format!(
+                        "Chunked stream {} is evicted",
+                        resp.token
+                    ))));
+                }
+                let stream = streams.get_or_insert(resp.token.clone());
+                stream.claimed = true;
+                if stream.received < resp.chunks {
+                    stream.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+                let stream = streams.streams.remove(&resp.token).expect("Inserted");
+                Poll::Ready(Ok(stream.data))
+            })
+            .await?;
+            if data.len() != resp.length || checksum(data.as_bytes()) != resp.checksum {
+                return Err(Error::Protocol(format!(
+                    "Chunked stream {} is corrupted",
+                    resp.token
+                )));
+            }
+            Ok(serde_json::from_str(&data)?)
+        }
+    }
+}
+
+/// Unclaim the stream if [`ChunkReceiver::finish`] is dropped before completion, so that it can
+/// be evicted again.
+struct ClaimGuard {
+    streams: Arc<Mutex<Streams>>,
+    token: String,
+}
+
+impl Drop for ClaimGuard {
+    fn drop(&mut self) {
+        let mut streams = self.streams.lock().unwrap();
+        // Already removed if completed.
+        if let Some(stream) = streams.streams.get_mut(&self.token) {
+            stream.claimed = false;
+            stream.waker = None;
+            streams.evict();
+        }
+    }
+}
+
+/// 64-bit FNV-1a hash in lower hex.
+fn checksum(bytes: &[u8]) -> String {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    let hash = bytes.iter().fold(OFFSET_BASIS, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(PRIME)
+    });
+    format!("{hash:016x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{MainLoopEvent, Message};
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let sender = ChunkSender::new(PeerSocket { tx }).chunk_size(NonZeroUsize::new(3).unwrap());
+        let value = vec!["hello".to_owned(), "世界".to_owned()];
+        let resp = sender.send(&value).unwrap();
+        drop(sender);
+        assert!(resp.chunks > 1);
+
+        let receiver = ChunkReceiver::new();
+        let fut = receiver.finish::<Vec<String>>(resp);
+        while let Some(event) = rx.next().await {
+            let notif = match event {
+                MainLoopEvent::Outgoing(Message::Notification(notif)) => notif,
+                _ => panic!("unexpected event"),
+            };
+            assert_eq!(notif.method, Chunk::METHOD);
+            let params = serde_json::from_value(notif.params).unwrap();
+            assert!(matches!(
+                receiver.receive(params),
+                ControlFlow::Continue(())
+            ));
+        }
+        assert_eq!(fut.await.unwrap(), value);
+    }
+
+    #[test]
+    fn out_of_order() {
+        let receiver = ChunkReceiver::new();
+        let ret = receiver.receive(ChunkParams {
+            token: "foo".into(),
+            seq: 1,
+            data: "{}".into(),
+        });
+        assert!(matches!(ret, ControlFlow::Break(Err(Error::Protocol(_)))));
+    }
+
+    #[tokio::test]
+    async fn evict_unclaimed() {
+        let receiver = ChunkReceiver::new().max_unclaimed(2);
+        let chunk = |token: &str, seq| ChunkParams {
+            token: token.into(),
+            seq,
+            data: "1".into(),
+        };
+        let resp = |token: &str| ChunkedResponse {
+            token: token.into(),
+            chunks: 1,
+            length: 1,
+            checksum: checksum(b"1"),
+        };
+
+        // Claimed streams are never evicted.
+        let claimed = receiver.finish::<u32>(ChunkedResponse {
+            chunks: 2,
+            length: 2,
+            checksum: checksum(b"11"),
+            ..resp("claimed")
+        });
+        futures::pin_mut!(claimed);
+        assert!(futures::poll!(claimed.as_mut()).is_pending());
+        for token in ["claimed", "a", "b", "c"] {
+            assert!(receiver.receive(chunk(token, 0)).is_continue());
+        }
+        assert_eq!(receiver.streams.lock().unwrap().streams.len(), 3);
+
+        // Later chunks of the evicted stream are ignored, and claiming it fails.
+        assert!(receiver.receive(chunk("a", 1)).is_continue());
+        assert!(matches!(
+            receiver.finish::<u32>(resp("a")).await,
+            Err(Error::Protocol(_))
+        ));
+        assert_eq!(receiver.finish::<u32>(resp("b")).await.unwrap(), 1);
+        assert!(receiver.receive(chunk("claimed", 1)).is_continue());
+        assert_eq!(claimed.await.unwrap(), 11);
+        assert_eq!(receiver.finish::<u32>(resp("c")).await.unwrap(), 1);
+        assert!(receiver.streams.lock().unwrap().streams.is_empty());
+    }
+
+    #[tokio::test]
+    async fn evict_dropped_claim() {
+        let receiver = ChunkReceiver::new().max_unclaimed(1);
+        let chunk = |token: &str| ChunkParams {
+            token: token.into(),
+            seq: 0,
+            data: "1".into(),
+        };
+
+        let mut claimed = Box::pin(receiver.finish::<u32>(ChunkedResponse {
+            token: "dropped".into(),
+            chunks: 2,
+            length: 2,
+            checksum: checksum(b"11"),
+        }));
+        assert!(futures::poll!(claimed.as_mut()).is_pending());
+        assert!(receiver.receive(chunk("dropped")).is_continue());
+        drop(claimed);
+        assert!(!receiver.streams.lock().unwrap().streams["dropped"].claimed);
+
+        // The abandoned stream is the oldest unclaimed one.
+        assert!(receiver.receive(chunk("a")).is_continue());
+        let streams = receiver.streams.lock().unwrap();
+        assert_eq!(streams.streams.keys().collect::<Vec<_>>(), ["a"]);
+        assert_eq!(streams.evicted, ["dropped"]);
+    }
+}
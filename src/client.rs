@@ -0,0 +1,605 @@
+//! Language Client side helpers of the server lifecycle.
+//!
+//! *Only applies to Language Clients.*
+//!
+//! The LSP specification forbids sending requests other than `initialize` before the server
+//! responded to it. [`UninitializedServerSocket`] is a type-state wrapper of [`ServerSocket`]
+//! which can only perform the initialization handshake, and converts into an
+//! [`InitializedServerSocket`] after it completes. This makes it a compile error to send regular
+//! requests too early.
+//!
+//! ```
+//! # use async_lsp::client::UninitializedServerSocket;
+//! # use async_lsp::lsp_types::{request, InitializeParams};
+//! # async fn work(server: async_lsp::ServerSocket) -> async_lsp::Result<()> {
+//! let server = UninitializedServerSocket::new(server);
+//! // server.request::<request::HoverRequest>(..); // Does not compile.
+//! let (server, init_ret) = server.initialize(InitializeParams::default()).await?;
+//! println!("Server capabilities: {:?}", init_ret.capabilities);
+//! server.request::<request::Shutdown>(()).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! When the socket is shared by multiple tasks, or requests are sent before knowing whether the
+//! handshake completes, [`ClientLifecycleLayer`] enforces the same rules at runtime instead. It
+//! wraps the [`ServerSocket`] into a [`LifecycleServerSocket`], which:
+//! - Sends `initialized` after the `initialize` request succeeds.
+//! - Queues other requests until then, and rejects them before `initialize` is sent.
+//! - Rejects all requests after `shutdown`, and all notifications after `exit`.
+//! - Sends `exit` when all clones are dropped, if it is not sent yet.
+//!
+//! As a middleware of the client service, [`ClientLifecycle`] rejects requests from the server
+//! before it is initialized, except `window/showMessageRequest`, and after `shutdown`.
+//!
+//! ```
+//! # use async_lsp::client::ClientLifecycleLayer;
+//! # use async_lsp::lsp_types::{request, InitializeParams};
+//! # use async_lsp::router::Router;
+//! # use async_lsp::MainLoop;
+//! # use tower::ServiceBuilder;
+//! # async fn work() -> async_lsp::Result<()> {
+//! let lifecycle = ClientLifecycleLayer::new();
+//! let (mainloop, server) = MainLoop::new_client(|_| {
+//!     ServiceBuilder::new()
+//!         .layer(lifecycle.clone())
+//!         .service(Router::new(()))
+//! });
+//! let server = lifecycle.socket(server);
+//! let init = server.request::<request::Initialize>(InitializeParams::default());
+//! // Sent after `initialize` succeeds.
+//! let shutdown = server.request::<request::Shutdown>(());
+//! # Ok(())
+//! # }
+//! ```
+use std::future::{ready, Future, Ready};
+use std::ops::{ControlFlow, Deref};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::channel::oneshot;
+use futures::future::Either;
+use lsp_types::notification::{self, Notification};
+use lsp_types::request::{self, Request};
+use lsp_types::{InitializeParams, InitializeResult, InitializedParams};
+use pin_project_lite::pin_project;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{
+    AnyEvent, AnyNotification, AnyRequest, Error, ErrorCode, EventHints, LspService, ResponseError,
+    Result, ServerSocket,
+};
+
+/// A [`ServerSocket`] before the initialization handshake completes.
+///
+/// It is intentionally not [`Clone`], so that the handshake can only be performed once.
+///
+/// See [module level documentations](self) for details.
+///
+/// Requests other than `initialize` cannot be sent before the handshake:
+///
+/// ```compile_fail
+/// # use async_lsp::client::UninitializedServerSocket;
+/// # use async_lsp::lsp_types::request;
+/// # async fn work(server: UninitializedServerSocket) {
+/// server.request::<request::Shutdown>(()).await;
+/// # }
+/// ```
+///
+/// Nor can `initialized` be sent manually:
+///
+/// ```compile_fail
+/// # use async_lsp::client::UninitializedServerSocket;
+/// # use async_lsp::lsp_types::{notification, InitializedParams};
+/// # fn work(server: UninitializedServerSocket) {
+/// server.notify::<notification::Initialized>(InitializedParams {});
+/// # }
+/// ```
+///
+/// The handshake, which sends `initialized`, cannot be performed twice:
+///
+/// ```compile_fail
+/// # use async_lsp::client::UninitializedServerSocket;
+/// # use async_lsp::lsp_types::InitializeParams;
+/// # async fn work(server: UninitializedServerSocket) -> async_lsp::Result<()> {
+/// let _ = server.initialize(InitializeParams::default()).await?;
+/// let _ = server.initialize(InitializeParams::default()).await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// ```compile_fail
+/// # use async_lsp::client::UninitializedServerSocket;
+/// # fn work(server: UninitializedServerSocket) {
+/// let _ = server.clone();
+/// # }
+/// ```
+#[derive(Debug)]
+#[must_use]
+pub struct UninitializedServerSocket {
+    socket: ServerSocket,
+}
+
+impl UninitializedServerSocket {
+    /// Wrap a fresh [`ServerSocket`] whose server is not initialized yet.
+    pub fn new(socket: ServerSocket) -> Self {
+        Self { socket }
+    }
+
+    /// Perform the initialization handshake: send the `initialize` request, wait for its
+    /// response, and then send the `initialized` notification.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the `initialize` request fails, or the service main loop stopped.
+    pub async fn initialize(
+        self,
+        params: InitializeParams,
+    ) -> Result<(InitializedServerSocket, InitializeResult)> {
+        let ret = self.socket.request::<request::Initialize>(params).await?;
+        self.socket
+            .notify::<notification::Initialized>(InitializedParams {})?;
+        Ok((
+            InitializedServerSocket {
+                socket: self.socket,
+            },
+            ret,
+        ))
+    }
+
+    /// Emit an arbitrary loopback event object to the client service handler.
+    ///
+    /// Events never reach the server, thus it is allowed before initialization.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::ServiceStopped`][crate::Error::ServiceStopped] when the service main loop
+    ///   stopped.
+    pub fn emit<E: Send + 'static>(&self, event: E) -> Result<()> {
+        self.socket.emit(event)
+    }
+}
+
+/// A [`ServerSocket`] after the initialization handshake completes.
+///
+/// It dereferences to the inner [`ServerSocket`] for all regular requests and notifications.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone)]
+pub struct InitializedServerSocket {
+    socket: ServerSocket,
+}
+
+impl Deref for InitializedServerSocket {
+    type Target = ServerSocket;
+
+    fn deref(&self) -> &Self::Target {
+        &self.socket
+    }
+}
+
+impl InitializedServerSocket {
+    /// Consume self, returning the inner [`ServerSocket`].
+    #[must_use]
+    pub fn into_inner(self) -> ServerSocket {
+        self.socket
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+    #[default]
+    Uninitialized,
+    Initializing,
+    Ready,
+    ShuttingDown,
+    Exited,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: State,
+    /// Requests waiting for the `initialize` request to complete.
+    waiters: Vec<oneshot::Sender<()>>,
+}
+
+/// The client-side lifecycle state shared by [`LifecycleServerSocket`] and the
+/// [`ClientLifecycle`] middleware.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone, Default)]
+#[must_use]
+pub struct ClientLifecycleLayer {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl ClientLifecycleLayer {
+    /// Create a layer in uninitialized state.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wrap the [`ServerSocket`] of the main loop, sharing the lifecycle state with this layer.
+    pub fn socket(&self, socket: ServerSocket) -> LifecycleServerSocket {
+        LifecycleServerSocket {
+            inner: Arc::new(SocketInner {
+                socket,
+                shared: self.shared.clone(),
+            }),
+        }
+    }
+}
+
+impl<S> Layer<S> for ClientLifecycleLayer {
+    type Service = ClientLifecycle<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ClientLifecycle {
+            service: inner,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// A [`ServerSocket`] enforcing the client-side lifecycle at runtime.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone)]
+pub struct LifecycleServerSocket {
+    inner: Arc<SocketInner>,
+}
+
+#[derive(Debug)]
+struct SocketInner {
+    socket: ServerSocket,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Drop for SocketInner {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.state != State::Exited {
+            shared.state = State::Exited;
+            // Ignore if the main loop stopped.
+            let _: Result<_> = self.socket.notify::<notification::Exit>(());
+        }
+    }
+}
+
+fn protocol_error(msg: &str) -> Error {
+    Error::Protocol(msg.into())
+}
+
+impl LifecycleServerSocket {
+    /// Send a request to the server and wait for its response.
+    ///
+    /// Requests other than `initialize` are queued until the `initialize` request succeeds.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Protocol`] if the request is not allowed in the current state, or it is queued
+    ///   but the `initialize` request fails.
+    /// - Other errors of [`ServerSocket::request`].
+    pub async fn request<R: Request>(&self, params: R::Params) -> Result<R::Result> {
+        while let Some(wait) = self.check_request(R::METHOD)? {
+            wait.await
+                .map_err(|_| protocol_error("Server failed to initialize"))?;
+        }
+        if R::METHOD != request::Initialize::METHOD {
+            return self.inner.socket.request::<R>(params).await;
+        }
+
+        let ret = self.inner.socket.request::<R>(params).await;
+        let mut shared = self.inner.shared.lock().unwrap();
+        match &ret {
+            Ok(_) => {
+                // Notify before waking queued requests, so that it is sent before them.
+                self.inner
+                    .socket
+                    .notify::<notification::Initialized>(InitializedParams {})?;
+                shared.state = State::Ready;
+                for waiter in shared.waiters.drain(..) {
+                    // The request may be cancelled.
+                    let _: Result<_, _> = waiter.send(());
+                }
+            }
+            Err(_) => {
+                shared.state = State::Uninitialized;
+                shared.waiters.clear();
+            }
+        }
+        ret
+    }
+
+    /// Check and transit the state for sending a request, or return a receiver to wait for the
+    /// initialization.
+    fn check_request(&self, method: &str) -> Result<Option<oneshot::Receiver<()>>> {
+        let mut shared = self.inner.shared.lock().unwrap();
+        match (shared.state, method) {
+            (State::Uninitialized, request::Initialize::METHOD) => {
+                shared.state = State::Initializing;
+                Ok(None)
+            }
+            (_, request::Initialize::METHOD) => {
+                Err(protocol_error("Server is already initialized"))
+            }
+            (State::Uninitialized, _) => Err(protocol_error("Server is not initialized yet")),
+            (State::Initializing, _) => {
+                let (tx, rx) = oneshot::channel();
+                shared.waiters.push(tx);
+                Ok(Some(rx))
+            }
+            (State::Ready, _) => {
+                if method == request::Shutdown::METHOD {
+                    shared.state = State::ShuttingDown;
+                }
+                Ok(None)
+            }
+            (State::ShuttingDown | State::Exited, _) => {
+                Err(protocol_error("Server is shutting down"))
+            }
+        }
+    }
+
+    /// Send a notification to the server.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Protocol`] if the notification is not allowed in the current state.
+    /// - Other errors of [`ServerSocket::notify`].
+    pub fn notify<N: Notification>(&self, params: N::Params) -> Result<()> {
+        let mut shared = self.inner.shared.lock().unwrap();
+        match (shared.state, N::METHOD) {
+            (State::Exited, _) => Err(protocol_error("Server has exited")),
+            (_, notification::Exit::METHOD) => {
+                shared.state = State::Exited;
+                self.inner.socket.notify::<N>(params)
+            }
+            (State::Uninitialized | State::Initializing, _) => {
+                Err(protocol_error("Server is not initialized yet"))
+            }
+            (State::ShuttingDown, _) => Err(protocol_error("Server is shutting down")),
+            (State::Ready, _) => self.inner.socket.notify::<N>(params),
+        }
+    }
+
+    /// Emit an arbitrary loopback event object to the client service handler.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::ServiceStopped`] when the service main loop stopped.
+    pub fn emit<E: Send + 'static>(&self, event: E) -> Result<()> {
+        self.inner.socket.emit(event)
+    }
+}
+
+/// The middleware rejecting requests from the server in invalid lifecycle states.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+pub struct ClientLifecycle<S> {
+    service: S,
+    shared: Arc<Mutex<Shared>>,
+}
+
+define_getters!(impl[S] ClientLifecycle<S>, service: S);
+
+impl<S: LspService> Service<AnyRequest> for ClientLifecycle<S>
+where
+    S::Error: From<ResponseError>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        let state = self.shared.lock().unwrap().state;
+        let message = match (state, &*req.method) {
+            (State::Ready, _)
+            | (State::Uninitialized | State::Initializing, request::ShowMessageRequest::METHOD) => {
+                return ResponseFuture {
+                    inner: Either::Left(self.service.call(req)),
+                }
+            }
+            (State::Uninitialized | State::Initializing, _) => "Server is not initialized yet",
+            (State::ShuttingDown | State::Exited, _) => "Server is shutting down",
+        };
+        let err = ResponseError::new(ErrorCode::INVALID_REQUEST, message);
+        ResponseFuture {
+            inner: Either::Right(ready(Err(err.into()))),
+        }
+    }
+}
+
+impl<S: LspService> LspService for ClientLifecycle<S>
+where
+    S::Error: From<ResponseError>,
+{
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        self.service.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.service.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+pin_project! {
+    /// The [`Future`] type used by the [`ClientLifecycle`] middleware.
+    pub struct ResponseFuture<Fut: Future> {
+        #[pin]
+        inner: Either<Fut, Ready<Fut::Output>>,
+    }
+}
+
+impl<Fut: Future> Future for ResponseFuture<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.project().inner.poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::{AnyResponse, MainLoopEvent, Message, PeerSocket, RequestId};
+
+    #[tokio::test]
+    async fn type_state() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let server = UninitializedServerSocket::new(ServerSocket(PeerSocket { tx }));
+        server.emit(()).unwrap();
+        assert!(matches!(rx.next().await.unwrap(), MainLoopEvent::Any(_)));
+
+        let peer = tokio::spawn(async move {
+            let mut methods = Vec::new();
+            while let Some(event) = rx.next().await {
+                match event {
+                    MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                        methods.push(notif.method);
+                    }
+                    MainLoopEvent::OutgoingRequest(req, resp_tx) => {
+                        let result = match &*req.method {
+                            request::Initialize::METHOD => json!({ "capabilities": {} }),
+                            _ => json!(null),
+                        };
+                        methods.push(req.method);
+                        resp_tx
+                            .send(AnyResponse {
+                                id: req.id,
+                                result: Some(result),
+                                error: None,
+                                raw: None,
+                            })
+                            .unwrap();
+                    }
+                    _ => panic!("unexpected event"),
+                }
+            }
+            methods
+        });
+
+        let (server, _) = server
+            .initialize(InitializeParams::default())
+            .await
+            .unwrap();
+        server.request::<request::Shutdown>(()).await.unwrap();
+        server.notify::<notification::Exit>(()).unwrap();
+        drop(server);
+        assert_eq!(
+            peer.await.unwrap(),
+            [
+                request::Initialize::METHOD,
+                notification::Initialized::METHOD,
+                request::Shutdown::METHOD,
+                notification::Exit::METHOD,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn socket_lifecycle() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let layer = ClientLifecycleLayer::new();
+        let server = layer.socket(ServerSocket(PeerSocket { tx }));
+        assert!(server.request::<request::Shutdown>(()).await.is_err());
+
+        let init = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .request::<request::Initialize>(InitializeParams::default())
+                    .await
+            }
+        });
+        let resp_tx = match rx.next().await.unwrap() {
+            MainLoopEvent::OutgoingRequest(req, resp_tx) => {
+                assert_eq!(req.method, request::Initialize::METHOD);
+                resp_tx
+            }
+            _ => panic!("unexpected event"),
+        };
+        // Queued until initialized.
+        let shutdown = tokio::spawn({
+            let server = server.clone();
+            async move { server.request::<request::Shutdown>(()).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(server
+            .notify::<notification::Initialized>(InitializedParams {})
+            .is_err());
+        resp_tx
+            .send(AnyResponse {
+                id: RequestId::Number(0),
+                result: Some(json!({ "capabilities": {} })),
+                error: None,
+                raw: None,
+            })
+            .unwrap();
+        init.await.unwrap().unwrap();
+
+        let mut methods = Vec::new();
+        for _ in 0..2 {
+            match rx.next().await.unwrap() {
+                MainLoopEvent::Outgoing(Message::Notification(notif)) => methods.push(notif.method),
+                MainLoopEvent::OutgoingRequest(req, resp_tx) => {
+                    methods.push(req.method);
+                    resp_tx
+                        .send(AnyResponse {
+                            id: req.id,
+                            result: Some(json!(null)),
+                            error: None,
+                            raw: None,
+                        })
+                        .unwrap();
+                }
+                _ => panic!("unexpected event"),
+            }
+        }
+        assert_eq!(
+            methods,
+            [notification::Initialized::METHOD, request::Shutdown::METHOD]
+        );
+        shutdown.await.unwrap().unwrap();
+
+        // Incoming requests are rejected after shutdown.
+        let mut service = layer.layer(Router::<()>::new(()));
+        let err = service
+            .call(AnyRequest {
+                id: RequestId::Number(0),
+                method: request::ShowMessageRequest::METHOD.into(),
+                params: json!(null),
+                receipt: None,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::INVALID_REQUEST);
+        assert!(server.request::<request::Shutdown>(()).await.is_err());
+
+        // Send `exit` on drop.
+        drop(server);
+        match rx.next().await.unwrap() {
+            MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                assert_eq!(notif.method, notification::Exit::METHOD);
+            }
+            _ => panic!("unexpected event"),
+        }
+        assert!(rx.next().await.is_none());
+    }
+}
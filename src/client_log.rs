@@ -0,0 +1,350 @@
+//! Forward [`tracing`][::tracing] logs to the Language Client via `window/logMessage`.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! [`ClientLogLayer`] is a [`tracing_subscriber::Layer`] which turns log events into
+//! [`window/logMessage`](https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#window_logMessage)
+//! notifications, so that server logs show up in the output pane of the editor. It features:
+//! - Level mapping: `ERROR`, `WARN` and `INFO` map to the corresponding [`MessageType`], while
+//!   `DEBUG` and `TRACE` map to [`MessageType::LOG`].
+//! - A maximum level, defaults to `INFO`.
+//! - Rate limiting, defaults to 100 messages per second. Dropped messages are counted and
+//!   reported in the next forwarded one.
+//! - A cap on message length, defaults to 4096 bytes.
+//! - Runtime toggling via the custom request [`SetClientLog`].
+//!
+//! Since the subscriber is typically installed globally before the main loop is created, the
+//! [`ClientSocket`] is attached later via [`ClientLogLayer::set_client`]. Events are silently
+//! discarded before that, or after the main loop stopped.
+//!
+//! Trace events of the wire messages emitted by this crate itself are never forwarded, to avoid
+//! feedback loops.
+//!
+//! ```
+//! # use async_lsp::client_log::{ClientLogLayer, SetClientLog};
+//! # use async_lsp::router::Router;
+//! use tracing_subscriber::layer::SubscriberExt;
+//!
+//! let client_log = ClientLogLayer::new();
+//! let _subscriber = tracing_subscriber::registry().with(client_log.clone());
+//!
+//! let (_mainloop, _client) = async_lsp::MainLoop::new_server(|client| {
+//!     client_log.set_client(client);
+//!     let mut router = Router::new(());
+//!     router.request::<SetClientLog, _>(move |_, params| {
+//!         client_log.apply(params);
+//!         async { Ok(()) }
+//!     });
+//!     router
+//! });
+//! ```
+use std::fmt::{self, Write};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lsp_types::notification::LogMessage;
+use lsp_types::request::Request;
+use lsp_types::{LogMessageParams, MessageType};
+use serde::{Deserialize, Serialize};
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+use crate::ClientSocket;
+
+/// The custom request to toggle or reconfigure log forwarding at runtime.
+#[derive(Debug)]
+pub enum SetClientLog {}
+
+impl Request for SetClientLog {
+    type Params = SetClientLogParams;
+    type Result = ();
+    const METHOD: &'static str = "$/asyncLsp/setClientLog";
+}
+
+/// The parameters of [`SetClientLog`]. Absent fields are kept unchanged.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetClientLogParams {
+    /// Enable or disable log forwarding.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    /// The maximum level to forward, one of `off`, `error`, `warn`, `info`, `debug` and `trace`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+}
+
+/// The [`tracing_subscriber::Layer`] forwarding log events to the Language Client.
+///
+/// This is a cheaply cloneable shared handle. All clones share the same configuration.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone)]
+pub struct ClientLogLayer {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    client: Option<ClientSocket>,
+    enabled: bool,
+    max_level: LevelFilter,
+    max_message_len: usize,
+    max_per_second: u32,
+    window_start: Instant,
+    window_count: u32,
+    dropped: u64,
+}
+
+impl fmt::Debug for ClientLogLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        f.debug_struct("ClientLogLayer")
+            .field("enabled", &inner.enabled)
+            .field("max_level", &inner.max_level)
+            .field("max_message_len", &inner.max_message_len)
+            .field("max_per_second", &inner.max_per_second)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for ClientLogLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClientLogLayer {
+    /// Create the layer with the default configuration, without a client attached.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                client: None,
+                enabled: true,
+                max_level: LevelFilter::INFO,
+                max_message_len: 4096,
+                max_per_second: 100,
+                window_start: Instant::now(),
+                window_count: 0,
+                dropped: 0,
+            })),
+        }
+    }
+
+    /// Attach the socket to send `window/logMessage` notifications to.
+    pub fn set_client(&self, client: ClientSocket) {
+        self.inner.lock().unwrap().client = Some(client);
+    }
+
+    /// Enable or disable log forwarding.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.inner.lock().unwrap().enabled = enabled;
+    }
+
+    /// Set the maximum level to forward.
+    pub fn set_max_level(&self, level: impl Into<LevelFilter>) {
+        self.inner.lock().unwrap().max_level = level.into();
+    }
+
+    /// Set the maximum length in bytes of each message. Longer messages are truncated.
+    pub fn set_max_message_len(&self, len: usize) {
+        self.inner.lock().unwrap().max_message_len = len;
+    }
+
+    /// Set the maximum number of messages forwarded per second.
+    pub fn set_max_per_second(&self, n: u32) {
+        self.inner.lock().unwrap().max_per_second = n;
+    }
+
+    /// Apply the configuration from a [`SetClientLog`] request. Unknown levels are ignored.
+    pub fn apply(&self, params: SetClientLogParams) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(enabled) = params.enabled {
+            inner.enabled = enabled;
+        }
+        if let Some(level) = params.level.and_then(|s| LevelFilter::from_str(&s).ok()) {
+            inner.max_level = level;
+        }
+    }
+}
+
+impl Inner {
+    fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.window_count = 0;
+        }
+        if self.window_count >= self.max_per_second {
+            self.dropped += 1;
+            return false;
+        }
+        self.window_count += 1;
+        true
+    }
+}
+
+impl<S: Subscriber> Layer<S> for ClientLogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let meta = event.metadata();
+        // Wire message traces of the main loop.
+        if meta.target() == "async_lsp" {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.enabled || inner.client.is_none() || *meta.level() > inner.max_level {
+            return;
+        }
+        if !inner.try_acquire() {
+            return;
+        }
+
+        let mut message = String::new();
+        if inner.dropped != 0 {
+            let _ = write!(message, "({} messages dropped) ", inner.dropped);
+            inner.dropped = 0;
+        }
+        event.record(&mut MessageVisitor(&mut message));
+        if message.len() > inner.max_message_len {
+            let mut end = inner.max_message_len;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+            message.push('…');
+        }
+
+        let typ = match *meta.level() {
+            Level::ERROR => MessageType::ERROR,
+            Level::WARN => MessageType::WARNING,
+            Level::INFO => MessageType::INFO,
+            _ => MessageType::LOG,
+        };
+        let client = inner.client.as_ref().expect("checked");
+        if client
+            .notify::<LogMessage>(LogMessageParams { typ, message })
+            .is_err()
+        {
+            // The main loop stopped. Detach to skip all later events quickly.
+            inner.client = None;
+        }
+    }
+}
+
+struct MessageVisitor<'a>(&'a mut String);
+
+impl Visit for MessageVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if !self.0.is_empty() && !self.0.ends_with(' ') {
+            self.0.push(' ');
+        }
+        if field.name() == "message" {
+            let _ = write!(self.0, "{value:?}");
+        } else {
+            let _ = write!(self.0, "{}={value:?}", field.name());
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.record_debug(field, &format_args!("{value}"));
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc;
+    use lsp_types::notification::Notification;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::{MainLoopEvent, Message, PeerSocket};
+
+    fn logs(rx: &mut mpsc::UnboundedReceiver<MainLoopEvent>) -> Vec<(MessageType, String)> {
+        let mut logs = Vec::new();
+        while let Ok(Some(event)) = rx.try_next() {
+            match event {
+                MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                    assert_eq!(notif.method, LogMessage::METHOD);
+                    let params: LogMessageParams = serde_json::from_value(notif.params).unwrap();
+                    logs.push((params.typ, params.message));
+                }
+                _ => panic!("unexpected event"),
+            }
+        }
+        logs
+    }
+
+    #[test]
+    fn forward() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let layer = ClientLogLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            // Discarded before a client is attached.
+            tracing::info!("early");
+            layer.set_client(ClientSocket(PeerSocket { tx }));
+
+            // Level mapping, and the default maximum level.
+            tracing::error!("e");
+            tracing::warn!("w");
+            tracing::info!(answer = 42, "i");
+            tracing::debug!("d");
+            assert_eq!(
+                logs(&mut rx),
+                [
+                    (MessageType::ERROR, "e".into()),
+                    (MessageType::WARNING, "w".into()),
+                    (MessageType::INFO, "i answer=42".into()),
+                ]
+            );
+
+            // Toggled by `SetClientLog`.
+            layer.apply(SetClientLogParams {
+                enabled: Some(false),
+                level: None,
+            });
+            tracing::error!("off");
+            assert_eq!(logs(&mut rx), []);
+            layer.apply(SetClientLogParams {
+                enabled: Some(true),
+                level: Some("trace".into()),
+            });
+            tracing::trace!("t");
+            assert_eq!(logs(&mut rx), [(MessageType::LOG, "t".into())]);
+
+            // Truncation on character boundaries.
+            layer.set_max_message_len(4);
+            tracing::info!("ab世界");
+            assert_eq!(logs(&mut rx), [(MessageType::INFO, "ab…".into())]);
+
+            // Rate limiting, and the report of dropped messages.
+            layer.set_max_message_len(4096);
+            layer.set_max_per_second(2);
+            layer.inner.lock().unwrap().window_start = Instant::now();
+            layer.inner.lock().unwrap().window_count = 0;
+            for i in 0..5 {
+                tracing::info!("{i}");
+            }
+            assert_eq!(
+                logs(&mut rx),
+                [
+                    (MessageType::INFO, "0".into()),
+                    (MessageType::INFO, "1".into())
+                ]
+            );
+            layer.inner.lock().unwrap().window_start -= Duration::from_secs(1);
+            tracing::info!("5");
+            assert_eq!(
+                logs(&mut rx),
+                [(MessageType::INFO, "(3 messages dropped) 5".into())]
+            );
+        });
+    }
+}
@@ -16,7 +16,14 @@
 //!
 //! And this middleware does exactly this monitor mechanism.
 //!
+//! Process ids can be reused after a process exits. If the client is gone before the server
+//! starts monitoring, an unrelated process reusing its id would keep the server alive. When the
+//! server is spawned by the client, eg. via stdio, enable
+//! [`ClientProcessMonitorBuilder::spawned_by_client`] to treat processes started after the server
+//! as exited. [`ProcessStartTime`] is also usable by the spawning side to detect reused ids.
+//!
 //! Implementation: See crate [`waitpid_any`].
+use std::io;
 use std::ops::ControlFlow;
 use std::task::{Context, Poll};
 
@@ -24,16 +31,85 @@ use lsp_types::request::{self, Request};
 use tower_layer::Layer;
 use tower_service::Service;
 
-use crate::{AnyEvent, AnyNotification, AnyRequest, ClientSocket, Error, LspService, Result};
+use crate::{
+    AnyEvent, AnyNotification, AnyRequest, ClientSocket, Error, EventHints, LspService, Result,
+};
 
 struct ClientProcessExited;
 
+/// The start time of a process, which distinguishes processes reusing the same process id.
+///
+/// Values are only comparable on the same boot of the same machine. Currently, it is only
+/// available on Linux and Android via `procfs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProcessStartTime(u64);
+
+impl ProcessStartTime {
+    /// Get the start time of the process `pid`, or `None` if unsupported on the current platform.
+    ///
+    /// # Errors
+    ///
+    /// - [`io::ErrorKind::NotFound`] if the process does not exist.
+    /// - Other errors when reading or parsing the process information.
+    pub fn of(pid: u32) -> io::Result<Option<Self>> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid /proc/<pid>/stat");
+            let stat = std::fs::read_to_string(format!("/proc/{pid}/stat"))?;
+            // The command name in parentheses may contain spaces, thus skip over it. The start
+            // time is the 22nd field, and the 20th after the command name.
+            let (_, fields) = stat.rsplit_once(')').ok_or_else(invalid)?;
+            let start = fields
+                .split_whitespace()
+                .nth(19)
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(invalid)?;
+            Ok(Some(Self(start)))
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        {
+            let _ = pid;
+            Ok(None)
+        }
+    }
+
+    /// Get the start time of the current process, or `None` if unsupported on the current
+    /// platform.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`ProcessStartTime::of`].
+    pub fn current() -> io::Result<Option<Self>> {
+        Self::of(std::process::id())
+    }
+
+    /// Check whether the process `pid` is still the one started at `self`.
+    ///
+    /// It returns `false` if the process does not exist, and `true` if it cannot be checked.
+    #[must_use]
+    pub fn matches(&self, pid: u32) -> bool {
+        match Self::of(pid) {
+            Ok(start) => start.map_or(true, |start| start == *self),
+            Err(err) => err.kind() != io::ErrorKind::NotFound,
+        }
+    }
+}
+
+/// Whether the process `pid` started after the current process, thus cannot be its parent.
+fn started_after_current(pid: u32) -> bool {
+    match (ProcessStartTime::of(pid), ProcessStartTime::current()) {
+        (Ok(Some(start)), Ok(Some(current))) => start > current,
+        _ => false,
+    }
+}
+
 /// The middleware stopping the main loop when the Language Client process aborted unexpectedly.
 ///
 /// See [module level documentations](self) for details.
 pub struct ClientProcessMonitor<S> {
     service: S,
     client: ClientSocket,
+    spawned_by_client: bool,
 }
 
 impl<S: LspService> Service<AnyRequest> for ClientProcessMonitor<S> {
@@ -55,6 +131,13 @@ impl<S: LspService> Service<AnyRequest> for ClientProcessMonitor<S> {
                 .try_into()
                 .ok()
         })() {
+            if self.spawned_by_client && u32::try_from(pid).map_or(false, started_after_current) {
+                #[cfg(feature = "tracing")]
+                ::tracing::warn!("Peer process id {pid} is reused by another process");
+                // Ignore channel close.
+                let _: Result<_, _> = self.client.emit(ClientProcessExited);
+                return self.service.call(req);
+            }
             match waitpid_any::WaitHandle::open(pid) {
                 Ok(mut handle) => {
                     let client = self.client.clone();
@@ -112,18 +195,37 @@ impl<S: LspService> LspService for ClientProcessMonitor<S> {
             Err(event) => self.service.emit(event),
         }
     }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
 }
 
 /// The builder of [`ClientProcessMonitor`] middleware.
 #[must_use]
 pub struct ClientProcessMonitorBuilder {
     client: ClientSocket,
+    spawned_by_client: bool,
 }
 
 impl ClientProcessMonitorBuilder {
     /// Create the middleware builder with a given [`ClientSocket`] to inject exit events.
     pub fn new(client: ClientSocket) -> Self {
-        Self { client }
+        Self {
+            client,
+            spawned_by_client: false,
+        }
+    }
+
+    /// Assume the server process is spawned by the client, thus the client process must start
+    /// earlier. A process with the given id but started later is treated as exited, since its id
+    /// is reused. Default is `false`.
+    ///
+    /// This must not be enabled if the server may be started before the client, eg. serving
+    /// over a socket.
+    pub fn spawned_by_client(mut self) -> Self {
+        self.spawned_by_client = true;
+        self
     }
 }
 
@@ -138,6 +240,30 @@ impl<S> Layer<S> for ClientProcessMonitorBuilder {
         ClientProcessMonitor {
             service: inner,
             client: self.client.clone(),
+            spawned_by_client: self.spawned_by_client,
         }
     }
 }
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn process_start_time() {
+        let current = ProcessStartTime::current().unwrap().unwrap();
+        assert!(current.matches(std::process::id()));
+
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let start = ProcessStartTime::of(child.id()).unwrap().unwrap();
+        assert!(start >= current);
+        assert!(start.matches(child.id()));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(!start.matches(child.id()));
+    }
+}
@@ -0,0 +1,234 @@
+//! Pluggable time sources for time-based middlewares and utilities.
+//!
+//! Since this crate is runtime-agnostic, everything waiting for or measuring time takes a
+//! [`Clock`], eg. [`TimeoutLayer`](crate::timeout::TimeoutLayer), debouncing of
+//! [`DocumentPipeline`](crate::pipeline::DocumentPipeline) and
+//! [`MainLoop::write_retry`](crate::MainLoop::write_retry).
+//!
+//! Any timer function like `tokio::time::sleep` is a [`Clock`] measuring time with
+//! [`Instant::now`]. Embedders can implement [`Clock`] for other monotonic sources of their
+//! platforms. [`ManualClock`] is a deterministic implementation for tests, which only advances
+//! when told to, so time-dependent behaviors can be tested without real sleeps.
+//!
+//! ```
+//! # use async_lsp::clock::ManualClock;
+//! # use async_lsp::timeout::TimeoutLayer;
+//! # use std::time::Duration;
+//! let clock = ManualClock::new();
+//! let layer = TimeoutLayer::new(clock.clone()).timeout(Duration::from_secs(1));
+//! // ...
+//! clock.advance(Duration::from_secs(1));
+//! ```
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+
+/// A monotonic time source with timers.
+///
+/// See [module level documentations](self) for details.
+pub trait Clock: Send + Sync {
+    /// Get the current time.
+    fn now(&self) -> Instant;
+
+    /// Create a future resolving after `duration` elapsed on this clock.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+impl<F, Fut> Clock for F
+where
+    F: Fn(Duration) -> Fut + Send + Sync,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self(duration).boxed()
+    }
+}
+
+pub(crate) type SharedClock = Arc<dyn Clock>;
+
+/// The deterministic [`Clock`] which only advances by [`ManualClock::advance`].
+///
+/// It is cheaply cloneable and clones share the same time.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone)]
+pub struct ManualClock(Arc<Mutex<State>>);
+
+struct State {
+    origin: Instant,
+    elapsed: Duration,
+    sleepers: Vec<Sleeper>,
+    next_id: u64,
+}
+
+struct Sleeper {
+    id: u64,
+    deadline: Duration,
+    waker: Waker,
+}
+
+impl fmt::Debug for ManualClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.0.lock().unwrap();
+        f.debug_struct("ManualClock")
+            .field("elapsed", &state.elapsed)
+            .field("sleepers", &state.sleepers.len())
+            .finish()
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    /// Create a clock starting at the current [`Instant`].
+    #[must_use]
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(State {
+            origin: Instant::now(),
+            elapsed: Duration::ZERO,
+            sleepers: Vec::new(),
+            next_id: 0,
+        })))
+    }
+
+    /// Get the time elapsed since the creation.
+    #[must_use]
+    pub fn elapsed(&self) -> Duration {
+        self.0.lock().unwrap().elapsed
+    }
+
+    /// Advance the time by `duration`, and wake up all sleeps due.
+    pub fn advance(&self, duration: Duration) {
+        let wakers = {
+            let mut state = self.0.lock().unwrap();
+            state.elapsed += duration;
+            let elapsed = state.elapsed;
+            let (due, pending) = std::mem::take(&mut state.sleepers)
+                .into_iter()
+                .partition::<Vec<_>, _>(|s| s.deadline <= elapsed);
+            state.sleepers = pending;
+            due
+        };
+        for sleeper in wakers {
+            sleeper.waker.wake();
+        }
+    }
+
+    /// Get the number of pending sleeps which are polled but not due yet.
+    ///
+    /// It is useful for tests to wait until a timer is set before advancing the time.
+    #[must_use]
+    pub fn sleepers(&self) -> usize {
+        self.0.lock().unwrap().sleepers.len()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        let state = self.0.lock().unwrap();
+        state.origin + state.elapsed
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let (deadline, id) = {
+            let mut state = self.0.lock().unwrap();
+            state.next_id += 1;
+            (state.elapsed.saturating_add(duration), state.next_id)
+        };
+        Box::pin(Sleep {
+            state: self.0.clone(),
+            deadline,
+            id,
+        })
+    }
+}
+
+/// The [`Future`] of [`ManualClock::sleep`].
+struct Sleep {
+    state: Arc<Mutex<State>>,
+    deadline: Duration,
+    id: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        if state.elapsed >= self.deadline {
+            return Poll::Ready(());
+        }
+        let waker = cx.waker().clone();
+        match state.sleepers.iter_mut().find(|s| s.id == self.id) {
+            Some(sleeper) => sleeper.waker = waker,
+            None => state.sleepers.push(Sleeper {
+                id: self.id,
+                deadline: self.deadline,
+                waker,
+            }),
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.sleepers.retain(|s| s.id != self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::poll;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn manual() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut short = clock.sleep(Duration::from_secs(1));
+        let mut long = clock.sleep(Duration::from_secs(3));
+        assert_eq!(poll!(&mut short), Poll::Pending);
+        assert_eq!(poll!(&mut long), Poll::Pending);
+        assert_eq!(clock.sleepers(), 2);
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.now() - start, Duration::from_secs(2));
+        assert_eq!(clock.sleepers(), 1);
+        assert_eq!(poll!(&mut short), Poll::Ready(()));
+        assert_eq!(poll!(&mut long), Poll::Pending);
+        assert_eq!(clock.sleepers(), 1);
+        drop(short);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(poll!(long), Poll::Ready(()));
+        assert_eq!(clock.sleepers(), 0);
+        assert_eq!(clock.elapsed(), Duration::from_secs(3));
+        clock.sleep(Duration::ZERO).await;
+
+        let sleep = clock.sleep(Duration::from_secs(1));
+        let waiter = tokio::spawn(sleep);
+        while clock.sleepers() == 0 {
+            tokio::task::yield_now().await;
+        }
+        clock.advance(Duration::from_secs(1));
+        waiter.await.unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+//! Stale request coalescing.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Under fast typing, clients may send a request like `textDocument/completion` for each
+//! keystroke, while only the latest one is useful. This middleware cancels the previous ongoing
+//! request of the same method on the same document when a newer one arrives, and replies the
+//! older one with [`ErrorCode::CONTENT_MODIFIED`]. Requests are keyed by the method and
+//! `params.textDocument.uri`. Requests without a document are unaffected.
+//!
+//! ```
+//! # use async_lsp::coalesce::CoalesceLayer;
+//! # use async_lsp::lsp_types::request::{Completion, HoverRequest, SemanticTokensFullRequest};
+//! let layer = CoalesceLayer::new()
+//!     .method::<Completion>()
+//!     .method::<HoverRequest>()
+//!     .method::<SemanticTokensFullRequest>();
+//! ```
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::ops::ControlFlow;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::stream::{AbortHandle, Abortable};
+use lsp_types::request::Request;
+use lsp_types::Url;
+use pin_project_lite::pin_project;
+use serde_json::Value as JsonValue;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{
+    AnyEvent, AnyNotification, AnyRequest, ErrorCode, EventHints, LspService, ResponseError, Result,
+};
+
+/// The middleware cancelling superseded requests.
+///
+/// See [module level documentations](self) for details.
+pub struct Coalesce<S> {
+    service: S,
+    methods: HashSet<String>,
+    ongoing: HashMap<(String, Url), AbortHandle>,
+    /// The size of `ongoing` to trigger the next purge.
+    purge_threshold: usize,
+}
+
+define_getters!(impl[S] Coalesce<S>, service: S);
+
+impl<S: LspService> Service<AnyRequest> for Coalesce<S>
+where
+    S::Error: From<ResponseError>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        let key = if self.methods.contains(&req.method) {
+            text_document_uri(&req.params).map(|uri| (req.method.clone(), uri))
+        } else {
+            None
+        };
+        let (handle, registration) = AbortHandle::new_pair();
+        if let Some(key) = key {
+            // Regularly purge completed tasks, with amortized O(1) time cost.
+            if self.ongoing.len() >= self.purge_threshold {
+                self.ongoing.retain(|_, handle| !handle.is_aborted());
+                self.purge_threshold = (self.ongoing.len() * 2).max(DEFAULT_PURGE_THRESHOLD);
+            }
+            if let Some(prev) = self.ongoing.insert(key, handle.clone()) {
+                prev.abort();
+            }
+        }
+        ResponseFuture {
+            fut: Abortable::new(self.service.call(req), registration),
+            _abort_on_drop: AbortOnDrop(handle),
+        }
+    }
+}
+
+const DEFAULT_PURGE_THRESHOLD: usize = 64;
+
+/// Get `params.textDocument.uri` of a request.
+pub(crate) fn text_document_uri(params: &JsonValue) -> Option<Url> {
+    let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+    Url::parse(uri).ok()
+}
+
+/// Mark the request as completed, so that it can be purged from the `ongoing` map.
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pin_project! {
+    /// The [`Future`] type used by the [`Coalesce`] middleware.
+    pub struct ResponseFuture<Fut> {
+        #[pin]
+        fut: Abortable<Fut>,
+        _abort_on_drop: AbortOnDrop,
+    }
+}
+
+impl<Fut, Response, Error> Future for ResponseFuture<Fut>
+where
+    Fut: Future<Output = Result<Response, Error>>,
+    Error: From<ResponseError>,
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project().fut.poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(inner_ret)) => Poll::Ready(inner_ret),
+            Poll::Ready(Err(_aborted)) => Poll::Ready(Err(ResponseError::new(
+                ErrorCode::CONTENT_MODIFIED,
+                "Request is superseded by a newer one",
+            )
+            .into())),
+        }
+    }
+}
+
+impl<S: LspService> LspService for Coalesce<S>
+where
+    S::Error: From<ResponseError>,
+{
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        self.service.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.service.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+/// The builder of [`Coalesce`] middleware.
+///
+/// It's [`Default`] configuration coalesces no methods.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone, Debug, Default)]
+#[must_use]
+pub struct CoalesceBuilder {
+    methods: HashSet<String>,
+}
+
+impl CoalesceBuilder {
+    /// Create the middleware coalescing no methods.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Coalesce requests of method `R`.
+    pub fn method<R: Request>(self) -> Self {
+        self.method_name(R::METHOD)
+    }
+
+    /// Same as [`CoalesceBuilder::method`] but with a method name, eg. of a custom request or from
+    /// a configuration file.
+    pub fn method_name(mut self, method: impl Into<String>) -> Self {
+        self.methods.insert(method.into());
+        self
+    }
+}
+
+/// A type alias of [`CoalesceBuilder`] conforming to the naming convention of [`tower_layer`].
+pub type CoalesceLayer = CoalesceBuilder;
+
+impl<S> Layer<S> for CoalesceBuilder {
+    type Service = Coalesce<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Coalesce {
+            service: inner,
+            methods: self.methods.clone(),
+            ongoing: HashMap::new(),
+            purge_threshold: DEFAULT_PURGE_THRESHOLD,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{pending, poll_fn};
+
+    use lsp_types::request::{self, Request};
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::RequestId;
+
+    #[tokio::test]
+    async fn cancel_superseded() {
+        let mut router = Router::new(());
+        router.request::<request::HoverRequest, _>(|_, _| pending());
+        let mut service = CoalesceLayer::new()
+            .method::<request::HoverRequest>()
+            .layer(router);
+
+        let mut hover = |id, uri: &str| {
+            service.call(AnyRequest {
+                id: RequestId::Number(id),
+                method: request::HoverRequest::METHOD.into(),
+                params: json!({
+                    "textDocument": { "uri": uri },
+                    "position": { "line": 0, "character": 0 },
+                }),
+                receipt: None,
+            })
+        };
+        let first = hover(1, "file:///foo");
+        let other = hover(2, "file:///bar");
+        let second = hover(3, "file:///foo");
+
+        let err = first.await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::CONTENT_MODIFIED);
+        let mut other = Box::pin(other);
+        let mut second = Box::pin(second);
+        poll_fn(|cx| {
+            assert!(other.as_mut().poll(cx).is_pending());
+            assert!(second.as_mut().poll(cx).is_pending());
+            Poll::Ready(())
+        })
+        .await;
+    }
+}
@@ -0,0 +1,315 @@
+//! Framing of the LSP [base protocol](https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#baseProtocol).
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! Each message is a header part followed by a JSON content part. Headers are parsed tolerantly:
+//! - Header fields may come in any order, and names are case-insensitive.
+//! - `Content-Length` is required.
+//! - `Content-Type` is optional. Its `charset` parameter, if any, must be `utf-8` or `utf8`.
+//! - Unknown header fields are ignored.
+//! - Lines may end with either `\r\n` or `\n`, and whitespaces around values are trimmed.
+//! - Empty lines before a header part are skipped.
+//!
+//! A header part, including empty lines before it, is limited to [`MAX_HEADER_SIZE`] bytes and
+//! [`MAX_HEADER_LINES`] lines, exceeding which fails with [`Error::MessageTooLarge`]. This bounds
+//! the memory buffered for malicious peers sending endless header lines.
+//!
+//! The same parser is used by [`MainLoop`][crate::MainLoop]. With feature `codec`,
+//! `LspCodec` exposes it as an `Encoder` and `Decoder` pair of `tokio_util::codec`, usable with
+//! `tokio_util::codec::Framed` for building custom transports.
+use crate::{Error, Result};
+
+pub(crate) const CONTENT_LENGTH: &str = "Content-Length";
+const CONTENT_TYPE: &str = "Content-Type";
+
+/// The maximum number of bytes of a header part.
+pub const MAX_HEADER_SIZE: usize = 8 << 10;
+
+/// The maximum number of lines of a header part.
+pub const MAX_HEADER_LINES: usize = 64;
+
+/// The header part of a message being parsed.
+#[derive(Debug, Default)]
+pub(crate) struct Headers {
+    content_length: Option<usize>,
+    seen_any: bool,
+    size: usize,
+    lines: usize,
+}
+
+impl Headers {
+    /// Feed a header line, with or without the line terminator.
+    ///
+    /// Returns `true` if the header part ends.
+    pub(crate) fn feed_line(&mut self, line: &[u8]) -> Result<bool> {
+        self.size += line.len();
+        self.lines += 1;
+        if self.size > MAX_HEADER_SIZE {
+            return Err(header_too_large(self.size));
+        }
+        if self.lines > MAX_HEADER_LINES {
+            return Err(Error::MessageTooLarge {
+                size: self.lines,
+                limit: MAX_HEADER_LINES,
+            });
+        }
+        let line = std::str::from_utf8(line)
+            .map_err(|_| Error::Protocol("Invalid header encoding".into()))?;
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.trim().is_empty() {
+            return Ok(self.seen_any);
+        }
+        self.seen_any = true;
+        let (name, value) = line
+            .split_once(':')
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .filter(|(name, _)| !name.is_empty())
+            .ok_or_else(|| Error::Protocol(format!("Invalid header: {line:?}")))?;
+        if name.eq_ignore_ascii_case(CONTENT_LENGTH) {
+            let len = value
+                .parse::<usize>()
+                .map_err(|_| Error::Protocol(format!("Invalid content-length: {value}")))?;
+            self.content_length = Some(len);
+        } else if name.eq_ignore_ascii_case(CONTENT_TYPE) {
+            for param in value.split(';').skip(1) {
+                let (key, val) = param.split_once('=').unwrap_or((param, ""));
+                let val = val.trim().trim_matches('"');
+                if key.trim().eq_ignore_ascii_case("charset")
+                    && !val.eq_ignore_ascii_case("utf-8")
+                    && !val.eq_ignore_ascii_case("utf8")
+                {
+                    return Err(Error::Protocol(format!("Unsupported charset: {val}")));
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Get the number of bytes a header line can have at most.
+    pub(crate) fn remaining(&self) -> usize {
+        MAX_HEADER_SIZE.saturating_sub(self.size)
+    }
+
+    /// Check if no header line is fed yet, ignoring empty lines.
+    pub(crate) fn is_empty(&self) -> bool {
+        !self.seen_any
+    }
+
+    /// Get the content length after the header part ends.
+    pub(crate) fn content_length(&self) -> Result<usize> {
+        self.content_length
+            .ok_or_else(|| Error::Protocol("Missing content-length".into()))
+    }
+}
+
+/// The error of an unterminated header part of `size` bytes, exceeding [`MAX_HEADER_SIZE`].
+pub(crate) fn header_too_large(size: usize) -> Error {
+    Error::MessageTooLarge {
+        size,
+        limit: MAX_HEADER_SIZE,
+    }
+}
+
+/// Check the content length against the limit, if any.
+pub(crate) fn check_size(size: usize, limit: Option<usize>) -> Result<()> {
+    match limit {
+        Some(limit) if size > limit => Err(Error::MessageTooLarge { size, limit }),
+        _ => Ok(()),
+    }
+}
+
+/// Parse the header part at the start of `buf`.
+///
+/// Returns the length of the header part and the content length, or `None` if it is incomplete.
+pub(crate) fn parse_header_part(buf: &[u8]) -> Result<Option<(usize, usize)>> {
+    // Only scan the possible header part, not the whole buffer.
+    let buf = &buf[..buf.len().min(MAX_HEADER_SIZE + 1)];
+    let mut headers = Headers::default();
+    let mut pos = 0;
+    loop {
+        let end = match buf[pos..].iter().position(|&b| b == b'\n') {
+            Some(i) => pos + i + 1,
+            None if buf.len() > MAX_HEADER_SIZE => return Err(header_too_large(buf.len())),
+            None => return Ok(None),
+        };
+        let line = &buf[pos..end];
+        pos = end;
+        if headers.feed_line(line)? {
+            return Ok(Some((pos, headers.content_length()?)));
+        }
+    }
+}
+
+/// The codec of LSP base protocol messages, decoding into and encoding from JSON values.
+///
+/// See [module level documentations](self) for details.
+#[cfg(feature = "codec")]
+#[cfg_attr(docsrs, doc(cfg(feature = "codec")))]
+#[derive(Debug, Clone, Default)]
+pub struct LspCodec {
+    /// The content length of the current message, after its header part is consumed.
+    content_length: Option<usize>,
+    max_message_size: Option<usize>,
+}
+
+#[cfg(feature = "codec")]
+mod codec_impl {
+    use bytes::{Buf, BufMut, BytesMut};
+    use serde::Serialize;
+    use serde_json::Value as JsonValue;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::*;
+
+    impl LspCodec {
+        /// Create a codec.
+        #[must_use]
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Reject messages whose `Content-Length` exceeds `bytes` with
+        /// [`Error::MessageTooLarge`], before reserving any buffer for them. Default is no limit.
+        #[must_use]
+        pub fn max_message_size(mut self, bytes: usize) -> Self {
+            self.max_message_size = Some(bytes);
+            self
+        }
+    }
+
+    impl Decoder for LspCodec {
+        type Item = JsonValue;
+        type Error = Error;
+
+        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+            let len = match self.content_length {
+                Some(len) => len,
+                None => {
+                    let (pos, len) = match parse_header_part(src)? {
+                        Some(ret) => ret,
+                        None => return Ok(None),
+                    };
+                    check_size(len, self.max_message_size)?;
+                    src.advance(pos);
+                    self.content_length = Some(len);
+                    len
+                }
+            };
+            if src.len() < len {
+                src.reserve(len - src.len());
+                return Ok(None);
+            }
+            let content = src.split_to(len);
+            self.content_length = None;
+            Ok(Some(serde_json::from_slice(&content)?))
+        }
+    }
+
+    impl<T: Serialize> Encoder<T> for LspCodec {
+        type Error = Error;
+
+        fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<()> {
+            let content = serde_json::to_vec(&item)?;
+            let header = format!("{CONTENT_LENGTH}: {}\r\n\r\n", content.len());
+            dst.reserve(header.len() + content.len());
+            dst.put_slice(header.as_bytes());
+            dst.put_slice(&content);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(lines: &[&str]) -> Result<usize> {
+        let mut headers = Headers::default();
+        for line in lines {
+            if headers.feed_line(line.as_bytes())? {
+                return headers.content_length();
+            }
+        }
+        panic!("header part does not end");
+    }
+
+    #[test]
+    fn headers() {
+        assert_eq!(parse(&["Content-Length: 42\r\n", "\r\n"]).unwrap(), 42);
+        assert_eq!(
+            parse(&[
+                "\r\n",
+                "content-type:application/vscode-jsonrpc; charset=\"UTF-8\"\n",
+                "X-Foo: bar\n",
+                "CONTENT-LENGTH:  7 \n",
+                "\n",
+            ])
+            .unwrap(),
+            7
+        );
+        assert!(parse(&["Content-Type: text/plain; charset=utf-16\r\n"]).is_err());
+        assert!(parse(&["Content-Length: x\r\n"]).is_err());
+        assert!(parse(&["garbage\r\n"]).is_err());
+        assert!(parse(&["X-Foo: bar\r\n", "\r\n"]).is_err());
+
+        let long = format!("X-Foo: {}\r\n", "x".repeat(MAX_HEADER_SIZE));
+        assert!(matches!(
+            parse(&[&long]),
+            Err(Error::MessageTooLarge {
+                limit: MAX_HEADER_SIZE,
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse(&["X-Foo: bar\n"; MAX_HEADER_LINES + 1]),
+            Err(Error::MessageTooLarge {
+                limit: MAX_HEADER_LINES,
+                ..
+            })
+        ));
+    }
+
+    #[cfg(feature = "codec")]
+    #[test]
+    fn codec() {
+        use bytes::BytesMut;
+        use serde_json::json;
+        use tokio_util::codec::{Decoder, Encoder};
+
+        let mut codec = LspCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(json!({ "a": 1 }), &mut buf).unwrap();
+        codec.encode(json!([2]), &mut buf).unwrap();
+        let mut tail = buf.split_off(buf.len() - 3);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(json!({ "a": 1 })));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.unsplit(tail.split());
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(json!([2])));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+
+        let mut codec = LspCodec::new().max_message_size(3);
+        codec.encode(json!([2]), &mut buf).unwrap();
+        codec.encode(json!({ "a": 1 }), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(json!([2])));
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(Error::MessageTooLarge { size: 7, limit: 3 })
+        ));
+
+        // An unterminated header line fails once it exceeds the limit.
+        let mut codec = LspCodec::new();
+        let mut buf = BytesMut::from(&b"X-Foo: "[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&[b'x'; MAX_HEADER_SIZE]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(Error::MessageTooLarge {
+                limit: MAX_HEADER_SIZE,
+                ..
+            })
+        ));
+    }
+}
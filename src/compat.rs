@@ -0,0 +1,85 @@
+//! Deprecated shims of renamed APIs.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! Items in this module keep old names compiling with deprecation warnings, so that API
+//! additions do not strand existing users. They forward to the current APIs without any behavior
+//! change, and will be removed in the next breaking release.
+//!
+//! | Deprecated               | Replacement              |
+//! |--------------------------|--------------------------|
+//! | [`Frontend::new_client`] | [`MainLoop::new_client`] |
+//! | [`Server::new`]          | [`MainLoop::new_server`] |
+//!
+//! Migration is a plain rename:
+//!
+//! ```
+//! # use async_lsp::router::Router;
+//! # use async_lsp::MainLoop;
+//! // Before: `async_lsp::compat::Server::new(|_client| Router::new(()))`.
+//! let (mainloop, client) = MainLoop::new_server(|_client| Router::<()>::new(()));
+//! ```
+#![allow(deprecated)]
+use serde_json::Value as JsonValue;
+
+use crate::{ClientSocket, LspService, MainLoop, ResponseError};
+
+/// The former name of a Language Client main loop.
+///
+/// See [module level documentations](self) for details.
+#[deprecated(note = "Use `MainLoop` instead")]
+pub type Frontend<S> = MainLoop<S>;
+
+/// The former entry of creating a Language Server main loop.
+///
+/// See [module level documentations](self) for details.
+#[deprecated(note = "Use `MainLoop::new_server` instead")]
+#[derive(Debug)]
+pub enum Server {}
+
+impl Server {
+    /// Create a Language Server main loop.
+    #[deprecated(note = "Use `MainLoop::new_server` instead")]
+    #[must_use]
+    // Kept as is for compatibility.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new<S>(builder: impl FnOnce(ClientSocket) -> S) -> (MainLoop<S>, ClientSocket)
+    where
+        S: LspService<Response = JsonValue>,
+        ResponseError: From<S::Error>,
+    {
+        MainLoop::new_server(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::AsyncReadExt;
+    use lsp_types::request::Shutdown;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::*;
+    use crate::router::Router;
+
+    // The shims must keep working as the replacements do, until they are removed.
+    #[tokio::test]
+    async fn shims() {
+        let (server_main, _client) = Server::new(|_| {
+            let mut router = Router::new(());
+            router.request::<Shutdown, _>(|_, ()| async { Ok(()) });
+            router
+        });
+        let (client_main, server) = Frontend::new_client(|_| Router::new(()));
+
+        let (server_stream, client_stream) = tokio::io::duplex(64 << 10);
+        let (server_rx, server_tx) = server_stream.compat().split();
+        let (client_rx, client_tx) = client_stream.compat().split();
+        let server_main = tokio::spawn(server_main.run_buffered(server_rx, server_tx));
+        let client_main = tokio::spawn(client_main.run_buffered(client_rx, client_tx));
+
+        server.request::<Shutdown>(()).await.unwrap();
+
+        server_main.abort();
+        client_main.abort();
+    }
+}
@@ -0,0 +1,193 @@
+//! Completion result capping and incremental refinement.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Responding the full completion list of a large codebase, with tens of thousands of items, can
+//! freeze editors. [`CompletionCache`] caps the returned list to a maximum size, and sets
+//! [`CompletionList::is_incomplete`] if there are more matching items, so that the editor asks
+//! again as the user keeps typing. The full result set is cached, and subsequent requests which
+//! refine the same word are served by filtering the cache, without recomputing.
+//!
+//! A result set is keyed by the [`CompletionKey`]. It is reused for a later request in the same
+//! document if the word starts at the same anchor position, and the typed prefix extends the
+//! cached one. Since each keystroke bumps the document version, newer versions are accepted, while
+//! requests of versions older than the cached one are not served.
+//!
+//! ```
+//! # use async_lsp::completion::{CompletionCache, CompletionKey};
+//! # use async_lsp::lsp_types::{CompletionItem, CompletionList};
+//! # async fn compute(key: &CompletionKey) -> Vec<CompletionItem> { Vec::new() }
+//! async fn complete(cache: &CompletionCache, key: CompletionKey) -> CompletionList {
+//!     if let Some(list) = cache.get(&key) {
+//!         return list;
+//!     }
+//!     let items = compute(&key).await;
+//!     cache.insert(key, items)
+//! }
+//! ```
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use lsp_types::{CompletionItem, CompletionList, Position, Url};
+
+/// The identity of a completion request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompletionKey {
+    /// The document.
+    pub uri: Url,
+    /// The document version.
+    pub version: i32,
+    /// The start position of the word being completed.
+    pub anchor: Position,
+    /// The typed prefix of the word being completed, from `anchor` to the cursor.
+    pub prefix: String,
+}
+
+impl CompletionKey {
+    /// Create a key.
+    #[must_use]
+    pub fn new(uri: Url, version: i32, anchor: Position, prefix: impl Into<String>) -> Self {
+        Self {
+            uri,
+            version,
+            anchor,
+            prefix: prefix.into(),
+        }
+    }
+}
+
+/// The cache of full completion result sets, one per document.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+pub struct CompletionCache {
+    max_items: usize,
+    entries: Mutex<HashMap<Url, Entry>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    version: i32,
+    anchor: Position,
+    prefix: String,
+    items: Arc<[CompletionItem]>,
+}
+
+impl CompletionCache {
+    /// Create a cache returning at most `max_items` items per response.
+    #[must_use]
+    pub fn new(max_items: usize) -> Self {
+        Self {
+            max_items,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Serve a request from the cached result set, if it can be refined for `key`.
+    #[must_use]
+    pub fn get(&self, key: &CompletionKey) -> Option<CompletionList> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&key.uri)?;
+        if entry.anchor != key.anchor
+            || entry.version > key.version
+            || !key.prefix.starts_with(&entry.prefix)
+        {
+            return None;
+        }
+        let items = entry.items.clone();
+        drop(entries);
+        Some(self.filter(&items, &key.prefix))
+    }
+
+    /// Cache the full result set `items` for `key`, replacing the previous one of the document.
+    /// Returns the capped response.
+    ///
+    /// `items` should be ordered by relevance, since the response keeps the first matching ones.
+    pub fn insert(&self, key: CompletionKey, items: Vec<CompletionItem>) -> CompletionList {
+        let items: Arc<[CompletionItem]> = items.into();
+        let list = self.filter(&items, &key.prefix);
+        let entry = Entry {
+            version: key.version,
+            anchor: key.anchor,
+            prefix: key.prefix,
+            items,
+        };
+        self.entries.lock().unwrap().insert(key.uri, entry);
+        list
+    }
+
+    /// Invalidate the cached result set of a document, eg. when it is closed.
+    pub fn remove(&self, uri: &Url) {
+        self.entries.lock().unwrap().remove(uri);
+    }
+
+    fn filter(&self, items: &[CompletionItem], prefix: &str) -> CompletionList {
+        let mut matched = items.iter().filter(|item| {
+            let text = item.filter_text.as_deref().unwrap_or(&item.label);
+            fuzzy_match(text, prefix)
+        });
+        let items = matched.by_ref().take(self.max_items).cloned().collect();
+        CompletionList {
+            is_incomplete: matched.next().is_some(),
+            items,
+        }
+    }
+}
+
+/// Whether `pattern` is a case-insensitive subsequence of `text`.
+///
+/// This is intentionally permissive, leaving precise ranking to the editor.
+fn fuzzy_match(text: &str, pattern: &str) -> bool {
+    let mut text = text.chars().flat_map(char::to_lowercase);
+    pattern
+        .chars()
+        .flat_map(char::to_lowercase)
+        .all(|p| text.any(|c| c == p))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(list: &CompletionList) -> Vec<&str> {
+        list.items.iter().map(|item| &*item.label).collect()
+    }
+
+    #[test]
+    fn cap_and_refine() {
+        let cache = CompletionCache::new(2);
+        let uri = Url::parse("file:///foo.rs").unwrap();
+        let key =
+            |version, prefix| CompletionKey::new(uri.clone(), version, Position::new(1, 4), prefix);
+        let items = ["format", "from_str", "fold", "for_each", "map"]
+            .into_iter()
+            .map(|label| CompletionItem::new_simple(label.into(), String::new()))
+            .collect();
+
+        assert_eq!(cache.get(&key(1, "f")), None);
+        let list = cache.insert(key(1, "f"), items);
+        assert!(list.is_incomplete);
+        assert_eq!(labels(&list), ["format", "from_str"]);
+
+        let list = cache.get(&key(2, "fo")).unwrap();
+        assert!(list.is_incomplete);
+        assert_eq!(labels(&list), ["format", "from_str"]);
+        let list = cache.get(&key(3, "fol")).unwrap();
+        assert!(!list.is_incomplete);
+        assert_eq!(labels(&list), ["fold"]);
+        let list = cache.get(&key(3, "foE")).unwrap();
+        assert!(!list.is_incomplete);
+        assert_eq!(labels(&list), ["for_each"]);
+
+        // Not a refinement.
+        assert_eq!(cache.get(&key(3, "m")), None);
+        let moved = CompletionKey::new(uri.clone(), 3, Position::new(2, 0), "fo");
+        assert_eq!(cache.get(&moved), None);
+
+        // Stale.
+        assert_eq!(cache.get(&key(0, "fo")), None);
+
+        cache.remove(&uri);
+        assert_eq!(cache.get(&key(2, "fo")), None);
+    }
+}
@@ -6,24 +6,40 @@
 //! out-of-box, while this middleware is to provides these additional features:
 //! 1. Limit concurrent incoming requests to at most `max_concurrency`.
 //! 2. Cancellation of incoming requests via client notification `$/cancelRequest`.
-use std::collections::HashMap;
+//!
+//! Instead of a fixed number, the limit can also be adjusted at runtime by a
+//! [`ConcurrencyPolicy`], eg. [`LatencyPolicy`] which shrinks the limit when handlers become slow,
+//! so that servers remain responsive on small machines without hand-tuning per deployment.
+//!
+//! Methods can also have their own limits via [`ConcurrencyBuilder::method`], eg. at most 1
+//! concurrent `textDocument/completion` but 8 `textDocument/hover`, which count towards the global
+//! limit as well. What happens to requests exceeding limits is controlled by [`Saturation`].
+//! Requests queued by their per-method limits do not take global slots until they are dequeued,
+//! so a burst of one slow method never blocks other methods.
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
 use std::future::Future;
 use std::num::NonZeroUsize;
 use std::ops::ControlFlow;
 use std::pin::Pin;
-use std::sync::{Arc, Weak};
-use std::task::{Context, Poll};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{ready, Context, Poll, Waker};
 use std::thread::available_parallelism;
+use std::time::{Duration, Instant};
 
 use futures::stream::{AbortHandle, Abortable};
 use futures::task::AtomicWaker;
 use lsp_types::notification::{self, Notification};
+use lsp_types::request::Request;
 use pin_project_lite::pin_project;
 use tower_layer::Layer;
 use tower_service::Service;
 
+use crate::flags::FeatureFlags;
 use crate::{
-    AnyEvent, AnyNotification, AnyRequest, ErrorCode, LspService, RequestId, ResponseError, Result,
+    AnyEvent, AnyNotification, AnyRequest, ErrorCode, EventHints, LspService, RequestId,
+    ResponseError, Result,
 };
 
 /// The middleware for incoming request multiplexing limits and cancellation.
@@ -32,13 +48,212 @@ use crate::{
 pub struct Concurrency<S> {
     service: S,
     max_concurrency: NonZeroUsize,
-    /// A specialized single-acquire-multiple-release semaphore, using `Arc::weak_count` as tokens.
-    semaphore: Arc<AtomicWaker>,
+    flags: Option<FeatureFlags>,
+    policy: Option<Arc<dyn ConcurrencyPolicy>>,
+    saturation: Saturation,
+    methods: HashMap<String, Arc<MethodSemaphore>>,
+    semaphore: Arc<GlobalSemaphore>,
     ongoing: HashMap<RequestId, AbortHandle>,
 }
 
+/// What to do with requests exceeding concurrency limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Saturation {
+    /// Queue them until they fit in the limits. This is the default.
+    ///
+    /// Requests exceeding the global limit are not dispatched until others complete. Requests
+    /// exceeding their per-method limits are dispatched but not polled until others of the same
+    /// method complete, in the order they arrived. They take slots of the global limit only after
+    /// that, and take precedence over requests not dispatched yet.
+    #[default]
+    Queue,
+    /// Immediately fail them with an error code, eg. [`ErrorCode::SERVER_CANCELLED`] which
+    /// indicates the client may retry.
+    Reject(ErrorCode),
+}
+
+/// A specialized semaphore of the global limit, using `Arc::weak_count` as tokens.
+///
+/// New requests are acquired by `poll_ready` and `call` of the single service. Requests dequeued
+/// from their [`MethodSemaphore`] wait in `gated` in order, which take precedence.
+#[derive(Debug)]
+struct GlobalSemaphore {
+    /// The waker of `poll_ready`.
+    waker: AtomicWaker,
+    /// The limit checked by the last `poll_ready` or `call`.
+    limit: AtomicUsize,
+    gated: Mutex<MethodState>,
+}
+
+impl GlobalSemaphore {
+    fn new(limit: usize) -> Self {
+        Self {
+            waker: AtomicWaker::new(),
+            limit: AtomicUsize::new(limit),
+            gated: Mutex::default(),
+        }
+    }
+
+    /// The number of free slots for new requests.
+    fn available(self: &Arc<Self>, limit: usize) -> usize {
+        self.limit.store(limit, Ordering::Relaxed);
+        let used = Arc::weak_count(self) + self.gated.lock().unwrap().queue.len();
+        limit.saturating_sub(used)
+    }
+}
+
+/// The global slot of a request, acquired either in `call`, or after its method permit.
+enum GlobalPermit {
+    Acquired {
+        _guard: SemaphoreGuard,
+    },
+    /// `None` if not queued yet.
+    Waiting(Arc<GlobalSemaphore>, Option<u64>),
+}
+
+impl GlobalPermit {
+    fn poll_acquire(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let (sema, ticket) = match self {
+            Self::Acquired { .. } => return Poll::Ready(()),
+            Self::Waiting(sema, ticket) => (sema, ticket),
+        };
+        let mut st = sema.gated.lock().unwrap();
+        let ticket = *ticket.get_or_insert_with(|| {
+            let ticket = st.next_ticket;
+            st.next_ticket += 1;
+            st.queue.push_back((ticket, None));
+            ticket
+        });
+        let limit = sema.limit.load(Ordering::Relaxed);
+        if Arc::weak_count(sema) < limit && st.queue.front().map(|(t, _)| *t) == Some(ticket) {
+            st.queue.pop_front();
+            // The next one may also fit.
+            st.wake_front();
+            drop(st);
+            *self = Self::Acquired {
+                _guard: SemaphoreGuard(Arc::downgrade(sema)),
+            };
+            return Poll::Ready(());
+        }
+        if let Some((_, waker)) = st.queue.iter_mut().find(|(t, _)| *t == ticket) {
+            *waker = Some(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for GlobalPermit {
+    fn drop(&mut self) {
+        if let Self::Waiting(sema, Some(ticket)) = self {
+            let mut st = sema.gated.lock().unwrap();
+            st.queue.retain(|(t, _)| t != ticket);
+            st.wake_front();
+        }
+    }
+}
+
+/// A FIFO semaphore of a method.
+#[derive(Debug)]
+struct MethodSemaphore {
+    limit: usize,
+    state: Mutex<MethodState>,
+}
+
+#[derive(Debug, Default)]
+struct MethodState {
+    running: usize,
+    next_ticket: u64,
+    /// Tickets of queued requests, in order.
+    queue: VecDeque<(u64, Option<Waker>)>,
+}
+
+impl MethodState {
+    fn wake_front(&mut self) {
+        if let Some(waker) = self.queue.front_mut().and_then(|(_, waker)| waker.take()) {
+            waker.wake();
+        }
+    }
+}
+
+/// A queued or acquired slot of a [`MethodSemaphore`].
+struct MethodPermit {
+    sema: Arc<MethodSemaphore>,
+    /// `None` if acquired.
+    ticket: Option<u64>,
+}
+
+impl MethodPermit {
+    /// Acquire immediately if there are free slots and no queued requests.
+    fn try_acquire(sema: &Arc<MethodSemaphore>) -> Option<Self> {
+        let mut st = sema.state.lock().unwrap();
+        (st.running < sema.limit && st.queue.is_empty()).then(|| {
+            st.running += 1;
+            Self {
+                sema: sema.clone(),
+                ticket: None,
+            }
+        })
+    }
+
+    fn enqueue(sema: &Arc<MethodSemaphore>) -> Self {
+        let mut st = sema.state.lock().unwrap();
+        let ticket = st.next_ticket;
+        st.next_ticket += 1;
+        st.queue.push_back((ticket, None));
+        Self {
+            sema: sema.clone(),
+            ticket: Some(ticket),
+        }
+    }
+
+    fn poll_acquire(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let ticket = match self.ticket {
+            Some(ticket) => ticket,
+            None => return Poll::Ready(()),
+        };
+        let mut st = self.sema.state.lock().unwrap();
+        if st.running < self.sema.limit && st.queue.front().map(|(t, _)| *t) == Some(ticket) {
+            st.queue.pop_front();
+            st.running += 1;
+            self.ticket = None;
+            // The next one may also fit.
+            st.wake_front();
+            return Poll::Ready(());
+        }
+        if let Some((_, waker)) = st.queue.iter_mut().find(|(t, _)| *t == ticket) {
+            *waker = Some(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for MethodPermit {
+    fn drop(&mut self) {
+        let mut st = self.sema.state.lock().unwrap();
+        match self.ticket {
+            None => st.running -= 1,
+            Some(ticket) => st.queue.retain(|(t, _)| *t != ticket),
+        }
+        st.wake_front();
+    }
+}
+
 define_getters!(impl[S] Concurrency<S>, service: S);
 
+impl<S> Concurrency<S> {
+    /// The current concurrency limit, which may be overridden by [`FeatureFlags`] or the
+    /// [`ConcurrencyPolicy`], in that order.
+    fn limit(&self) -> usize {
+        self.flags
+            .as_ref()
+            .and_then(FeatureFlags::max_concurrency)
+            .or_else(|| self.policy.as_ref().map(|policy| policy.limit()))
+            .unwrap_or(self.max_concurrency)
+            .get()
+    }
+}
+
 impl<S: LspService> Service<AnyRequest> for Concurrency<S>
 where
     S::Error: From<ResponseError>,
@@ -48,55 +263,89 @@ where
     type Future = ResponseFuture<S::Future>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        if Arc::weak_count(&self.semaphore) >= self.max_concurrency.get() {
+        // Excessive requests are rejected in `call`.
+        if let Saturation::Reject(_) = self.saturation {
+            return Poll::Ready(Ok(()));
+        }
+        let limit = self.limit();
+        if self.semaphore.available(limit) == 0 {
             // Implicit `Acquire`.
-            self.semaphore.register(cx.waker());
+            self.semaphore.waker.register(cx.waker());
             // No guards dropped between the check and register?
-            if Arc::weak_count(&self.semaphore) >= self.max_concurrency.get() {
+            if self.semaphore.available(limit) == 0 {
                 return Poll::Pending;
             }
         }
 
-        // Here we have `weak_count < max_concurrency`. The service is ready for new calls.
+        // Here we have free slots. The service is ready for new calls.
         Poll::Ready(Ok(()))
     }
 
     fn call(&mut self, req: AnyRequest) -> Self::Future {
-        let guard = SemaphoreGuard(Arc::downgrade(&self.semaphore));
-        debug_assert!(
-            Arc::weak_count(&self.semaphore) <= self.max_concurrency.get(),
-            "`poll_ready` is not called before `call`",
-        );
+        let limit = self.limit();
+        let permit = self.methods.get(&*req.method).map(|sema| {
+            match (MethodPermit::try_acquire(sema), self.saturation) {
+                (Some(permit), _) => Ok(permit),
+                (None, Saturation::Queue) => Ok(MethodPermit::enqueue(sema)),
+                (None, Saturation::Reject(code)) => Err(code),
+            }
+        });
+        let permit = match (permit, self.saturation) {
+            (Some(Err(code)), _) => return ResponseFuture::rejected(code, &req.method),
+            (_, Saturation::Reject(code)) if self.semaphore.available(limit) == 0 => {
+                return ResponseFuture::rejected(code, &req.method);
+            }
+            (permit, _) => permit.map(|permit| permit.expect("Checked")),
+        };
+        // Queued requests take the global slot after their method permits.
+        // NB. The count may exceed the current limit, since flags or the policy can lower it
+        // between `poll_ready` and `call`. Ongoing requests are not affected by lowering anyway.
+        let global = match &permit {
+            Some(permit) if permit.ticket.is_some() => {
+                GlobalPermit::Waiting(self.semaphore.clone(), None)
+            }
+            _ => GlobalPermit::Acquired {
+                _guard: SemaphoreGuard(Arc::downgrade(&self.semaphore)),
+            },
+        };
 
         let (handle, registration) = AbortHandle::new_pair();
 
         // Regularly purge completed or dead tasks. See also `AbortOnDrop` below.
         // This costs 2*N time to remove at least N tasks, results in amortized O(1) time cost
         // for each spawned task.
-        if self.ongoing.len() >= self.max_concurrency.get() * 2 {
+        if self.ongoing.len() >= limit * 2 {
             self.ongoing.retain(|_, handle| !handle.is_aborted());
         }
         self.ongoing.insert(req.id.clone(), handle.clone());
 
-        let fut = self.service.call(req);
+        let fut = Gated {
+            permit,
+            global,
+            policy: self.policy.clone(),
+            start: None,
+            fut: self.service.call(req),
+        };
         let fut = Abortable::new(fut, registration);
         ResponseFuture {
-            fut,
-            _abort_on_drop: AbortOnDrop(handle),
-            _guard: guard,
+            _abort_on_drop: Some(AbortOnDrop(handle)),
+            fut: Some(fut),
+            rejected: None,
         }
     }
 }
 
-struct SemaphoreGuard(Weak<AtomicWaker>);
+struct SemaphoreGuard(Weak<GlobalSemaphore>);
 
 impl Drop for SemaphoreGuard {
     fn drop(&mut self) {
         if let Some(sema) = self.0.upgrade() {
-            if let Some(waker) = sema.take() {
-                // Return the token first.
-                drop(sema);
-                // Wake up `poll_ready`. Implicit "Release".
+            // Return the token first.
+            self.0 = Weak::new();
+            // Gated requests take precedence, since `poll_ready` counts them.
+            sema.gated.lock().unwrap().wake_front();
+            // Wake up `poll_ready`. Implicit "Release".
+            if let Some(waker) = sema.waker.take() {
                 waker.wake();
             }
         }
@@ -117,12 +366,59 @@ impl Drop for AbortOnDrop {
 pin_project! {
     /// The [`Future`] type used by the [`Concurrency`] middleware.
     pub struct ResponseFuture<Fut> {
+        // NB. Comes before `fut` holding the `SemaphoreGuard`. So that when the guard wake up the
+        // caller, it is able to purge the current future from `ongoing` map immediately.
+        _abort_on_drop: Option<AbortOnDrop>,
+        // `None` if rejected.
         #[pin]
-        fut: Abortable<Fut>,
-        // NB. Comes before `SemaphoreGuard`. So that when the guard wake up the caller, it is able
-        // to purge the current future from `ongoing` map immediately.
-        _abort_on_drop: AbortOnDrop,
-        _guard: SemaphoreGuard,
+        fut: Option<Abortable<Gated<Fut>>>,
+        rejected: Option<ResponseError>,
+    }
+}
+
+pin_project! {
+    /// The inner future which is not polled until the method permit and the global slot are
+    /// acquired.
+    struct Gated<Fut> {
+        permit: Option<MethodPermit>,
+        global: GlobalPermit,
+        policy: Option<Arc<dyn ConcurrencyPolicy>>,
+        // When the permit is acquired, so that the latency excludes the time in queue.
+        start: Option<Instant>,
+        #[pin]
+        fut: Fut,
+    }
+}
+
+impl<Fut: Future> Future for Gated<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if let Some(permit) = this.permit {
+            ready!(permit.poll_acquire(cx));
+        }
+        ready!(this.global.poll_acquire(cx));
+        let start = *this.start.get_or_insert_with(Instant::now);
+        let ret = ready!(this.fut.poll(cx));
+        // Report before the guard is released, so the next `poll_ready` sees the new limit.
+        if let Some(policy) = this.policy.take() {
+            policy.on_complete(start.elapsed());
+        }
+        Poll::Ready(ret)
+    }
+}
+
+impl<Fut> ResponseFuture<Fut> {
+    fn rejected(code: ErrorCode, method: &str) -> Self {
+        Self {
+            _abort_on_drop: None,
+            fut: None,
+            rejected: Some(ResponseError::new(
+                code,
+                format_args!("Server is busy with {method} requests"),
+            )),
+        }
     }
 }
 
@@ -134,7 +430,15 @@ where
     type Output = Fut::Output;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match self.project().fut.poll(cx) {
+        let this = self.project();
+        let fut = match this.fut.as_pin_mut() {
+            Some(fut) => fut,
+            None => {
+                let err = this.rejected.take().expect("Polled after completion");
+                return Poll::Ready(Err(err.into()));
+            }
+        };
+        match fut.poll(cx) {
             Poll::Pending => Poll::Pending,
             Poll::Ready(Ok(inner_ret)) => Poll::Ready(inner_ret),
             Poll::Ready(Err(_aborted)) => Poll::Ready(Err(ResponseError {
@@ -154,7 +458,9 @@ where
     fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
         if notif.method == notification::Cancel::METHOD {
             if let Ok(params) = serde_json::from_value::<lsp_types::CancelParams>(notif.params) {
-                self.ongoing.remove(&params.id);
+                if let Some(handle) = self.ongoing.remove(&params.id) {
+                    handle.abort();
+                }
             }
             return ControlFlow::Continue(());
         }
@@ -164,6 +470,89 @@ where
     fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
         self.service.emit(event)
     }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+/// A policy adjusting the concurrency limit of [`Concurrency`] at runtime.
+///
+/// See [module level documentations](self) for details.
+pub trait ConcurrencyPolicy: Send + Sync {
+    /// The current concurrency limit.
+    ///
+    /// This is called on each readiness check, thus should be cheap.
+    fn limit(&self) -> NonZeroUsize;
+
+    /// Observe the latency of a completed request. Cancelled requests are not reported.
+    ///
+    /// The default implementation does nothing.
+    fn on_complete(&self, latency: Duration) {
+        let _ = latency;
+    }
+}
+
+/// A [`ConcurrencyPolicy`] adjusting the limit by the observed handler latency.
+///
+/// The limit starts at `max`. Each request slower than the `target` latency halves the limit,
+/// while each `limit` consecutive requests within the `target` increase it by one (AIMD). The
+/// limit always stays within `min..=max`.
+#[derive(Debug)]
+pub struct LatencyPolicy {
+    target: Duration,
+    min: NonZeroUsize,
+    max: NonZeroUsize,
+    state: Mutex<LatencyState>,
+}
+
+#[derive(Debug)]
+struct LatencyState {
+    limit: NonZeroUsize,
+    fast_count: usize,
+}
+
+impl LatencyPolicy {
+    /// Create a policy keeping request latencies within `target`, with the limit in `min..=max`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min > max`.
+    #[must_use]
+    pub fn new(target: Duration, min: NonZeroUsize, max: NonZeroUsize) -> Self {
+        assert!(min <= max, "min > max");
+        Self {
+            target,
+            min,
+            max,
+            state: Mutex::new(LatencyState {
+                limit: max,
+                fast_count: 0,
+            }),
+        }
+    }
+}
+
+impl ConcurrencyPolicy for LatencyPolicy {
+    fn limit(&self) -> NonZeroUsize {
+        self.state.lock().unwrap().limit
+    }
+
+    fn on_complete(&self, latency: Duration) {
+        let mut st = self.state.lock().unwrap();
+        if latency > self.target {
+            st.fast_count = 0;
+            st.limit = NonZeroUsize::new(st.limit.get() / 2)
+                .unwrap_or(self.min)
+                .max(self.min);
+        } else {
+            st.fast_count += 1;
+            if st.fast_count >= st.limit.get() {
+                st.fast_count = 0;
+                st.limit = st.limit.saturating_add(1).min(self.max);
+            }
+        }
+    }
 }
 
 /// The builder of [`Concurrency`] middleware.
@@ -172,10 +561,25 @@ where
 /// [`std::thread::available_parallelism`], fallback to `1` if it fails.
 ///
 /// See [module level documentations](self) for details.
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 #[must_use]
 pub struct ConcurrencyBuilder {
     max_concurrency: NonZeroUsize,
+    flags: Option<FeatureFlags>,
+    policy: Option<Arc<dyn ConcurrencyPolicy>>,
+    saturation: Saturation,
+    methods: HashMap<String, NonZeroUsize>,
+}
+
+impl fmt::Debug for ConcurrencyBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConcurrencyBuilder")
+            .field("max_concurrency", &self.max_concurrency)
+            .field("flags", &self.flags)
+            .field("saturation", &self.saturation)
+            .field("methods", &self.methods)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Default for ConcurrencyBuilder {
@@ -187,7 +591,50 @@ impl Default for ConcurrencyBuilder {
 impl ConcurrencyBuilder {
     /// Create the middleware with concurrency limit `max_concurrency`.
     pub fn new(max_concurrency: NonZeroUsize) -> Self {
-        Self { max_concurrency }
+        Self {
+            max_concurrency,
+            flags: None,
+            policy: None,
+            saturation: Saturation::Queue,
+            methods: HashMap::new(),
+        }
+    }
+
+    /// Limit concurrent requests of method `R` to at most `limit`, in addition to the global
+    /// limit.
+    pub fn method<R: Request>(self, limit: NonZeroUsize) -> Self {
+        self.method_name(R::METHOD, limit)
+    }
+
+    /// Same as [`ConcurrencyBuilder::method`] but with a method name, eg. of a custom request or
+    /// from a configuration file.
+    pub fn method_name(mut self, method: impl Into<String>, limit: NonZeroUsize) -> Self {
+        self.methods.insert(method.into(), limit);
+        self
+    }
+
+    /// Set what to do with requests exceeding limits. Default is [`Saturation::Queue`].
+    pub fn saturation(mut self, saturation: Saturation) -> Self {
+        self.saturation = saturation;
+        self
+    }
+
+    /// Adjust the concurrency limit at runtime by `policy`, instead of the fixed
+    /// `max_concurrency`. [`FeatureFlags::max_concurrency`] still takes precedence if set.
+    ///
+    /// Lowering the limit does not affect ongoing requests.
+    pub fn policy(mut self, policy: impl ConcurrencyPolicy + 'static) -> Self {
+        self.policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Consult [`FeatureFlags::max_concurrency`] of `flags` on each request, which overrides
+    /// `max_concurrency` when set.
+    ///
+    /// Lowering the limit does not affect ongoing requests.
+    pub fn flags(mut self, flags: FeatureFlags) -> Self {
+        self.flags = Some(flags);
+        self
     }
 }
 
@@ -201,7 +648,21 @@ impl<S> Layer<S> for ConcurrencyBuilder {
         Concurrency {
             service: inner,
             max_concurrency: self.max_concurrency,
-            semaphore: Arc::new(AtomicWaker::new()),
+            flags: self.flags.clone(),
+            policy: self.policy.clone(),
+            saturation: self.saturation,
+            methods: self
+                .methods
+                .iter()
+                .map(|(method, limit)| {
+                    let sema = MethodSemaphore {
+                        limit: limit.get(),
+                        state: Mutex::default(),
+                    };
+                    (method.clone(), Arc::new(sema))
+                })
+                .collect(),
+            semaphore: Arc::new(GlobalSemaphore::new(self.max_concurrency.get())),
             // See `Concurrency::call` for why the factor 2.
             ongoing: HashMap::with_capacity(
                 self.max_concurrency
@@ -212,3 +673,282 @@ impl<S> Layer<S> for ConcurrencyBuilder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::future::{pending, poll_fn};
+
+    use lsp_types::request::{self, Request};
+    use lsp_types::CancelParams;
+    use serde_json::{json, Value as JsonValue};
+
+    use super::*;
+    use crate::router::Router;
+
+    #[tokio::test]
+    async fn cancel_request() {
+        let mut router = Router::new(());
+        router.request::<request::HoverRequest, _>(|_, _| pending());
+        let mut service = ConcurrencyLayer::new(NonZeroUsize::new(1).unwrap()).layer(router);
+
+        poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+        let fut = service.call(AnyRequest {
+            id: RequestId::Number(1),
+            method: request::HoverRequest::METHOD.into(),
+            params: json!({
+                "textDocument": { "uri": "file:///foo" },
+                "position": { "line": 0, "character": 0 },
+            }),
+            receipt: None,
+        });
+        let notif = AnyNotification {
+            method: notification::Cancel::METHOD.into(),
+            params: serde_json::to_value(CancelParams {
+                id: lsp_types::NumberOrString::Number(1),
+            })
+            .unwrap(),
+            receipt: None,
+        };
+        assert!(matches!(service.notify(notif), ControlFlow::Continue(())));
+
+        let err = fut.await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::REQUEST_CANCELLED);
+        // The slot is released.
+        poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+    }
+
+    #[test]
+    fn flags_override_limit() {
+        let flags = FeatureFlags::new();
+        let mut service = ConcurrencyLayer::new(NonZeroUsize::new(1).unwrap())
+            .flags(flags.clone())
+            .layer(Router::<()>::new(()));
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let _fut1 = service.call(AnyRequest {
+            id: RequestId::Number(1),
+            method: "foo".into(),
+            params: JsonValue::Null,
+            receipt: None,
+        });
+        assert!(service.poll_ready(&mut cx).is_pending());
+        flags.set_max_concurrency(NonZeroUsize::new(2));
+        assert!(service.poll_ready(&mut cx).is_ready());
+
+        // Lowering the limit between `poll_ready` and `call` is fine.
+        flags.set_max_concurrency(NonZeroUsize::new(1));
+        let _fut2 = service.call(AnyRequest {
+            id: RequestId::Number(2),
+            method: "foo".into(),
+            params: JsonValue::Null,
+            receipt: None,
+        });
+        assert!(service.poll_ready(&mut cx).is_pending());
+    }
+
+    #[tokio::test]
+    async fn per_method_limit() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Default)]
+        struct Counter {
+            running: AtomicUsize,
+            max: AtomicUsize,
+        }
+
+        async fn run(saturation: Saturation) -> (Vec<Result<JsonValue, ResponseError>>, usize) {
+            let counter = Arc::new(Counter::default());
+            let mut router = Router::new(counter.clone());
+            router.request::<request::Shutdown, _>(|counter, ()| {
+                let counter = counter.clone();
+                async move {
+                    let cur = counter.running.fetch_add(1, Ordering::SeqCst) + 1;
+                    counter.max.fetch_max(cur, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    counter.running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(())
+                }
+            });
+            let mut service = ConcurrencyLayer::new(NonZeroUsize::new(8).unwrap())
+                .method::<request::Shutdown>(NonZeroUsize::new(1).unwrap())
+                .saturation(saturation)
+                .layer(router);
+
+            let mut futs = Vec::new();
+            for id in 0..3 {
+                poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+                futs.push(service.call(AnyRequest {
+                    id: RequestId::Number(id),
+                    method: request::Shutdown::METHOD.into(),
+                    params: JsonValue::Null,
+                    receipt: None,
+                }));
+            }
+            let rets = futures::future::join_all(futs).await;
+            (rets, counter.max.load(Ordering::SeqCst))
+        }
+
+        let (rets, max) = run(Saturation::Queue).await;
+        assert!(rets.iter().all(Result::is_ok));
+        assert_eq!(max, 1);
+
+        let (rets, max) = run(Saturation::Reject(ErrorCode::SERVER_CANCELLED)).await;
+        assert!(rets[0].is_ok());
+        for ret in &rets[1..] {
+            assert_eq!(ret.as_ref().unwrap_err().code, ErrorCode::SERVER_CANCELLED);
+        }
+        assert_eq!(max, 1);
+    }
+
+    #[tokio::test]
+    async fn queued_method_not_block_others() {
+        let mut router = Router::new(());
+        router
+            .request::<request::Completion, _>(|_, _| pending())
+            .request::<request::HoverRequest, _>(|_, _| async { Ok(None) });
+        let mut service = ConcurrencyLayer::new(NonZeroUsize::new(2).unwrap())
+            .method::<request::Completion>(NonZeroUsize::new(1).unwrap())
+            .layer(router);
+        let params = json!({
+            "textDocument": { "uri": "file:///foo" },
+            "position": { "line": 0, "character": 0 },
+        });
+
+        let mut completions = Vec::new();
+        for id in 0..3 {
+            poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+            let mut fut = Box::pin(service.call(AnyRequest {
+                id: RequestId::Number(id),
+                method: request::Completion::METHOD.into(),
+                params: params.clone(),
+                receipt: None,
+            }));
+            assert!(futures::poll!(fut.as_mut()).is_pending());
+            completions.push(fut);
+        }
+
+        // Only the running completion takes a global slot.
+        let ready = futures::poll!(poll_fn(|cx| service.poll_ready(cx)));
+        assert!(matches!(ready, Poll::Ready(Ok(()))));
+        let ret = service
+            .call(AnyRequest {
+                id: RequestId::Number(3),
+                method: request::HoverRequest::METHOD.into(),
+                params: params.clone(),
+                receipt: None,
+            })
+            .await;
+        assert_eq!(ret.unwrap(), JsonValue::Null);
+
+        // The dequeued completion takes the global slot, along with a new request.
+        completions.remove(0);
+        assert!(futures::poll!(completions[0].as_mut()).is_pending());
+        poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+        let _hover = service.call(AnyRequest {
+            id: RequestId::Number(4),
+            method: request::HoverRequest::METHOD.into(),
+            params: params.clone(),
+            receipt: None,
+        });
+        let ready = futures::poll!(poll_fn(|cx| service.poll_ready(cx)));
+        assert!(ready.is_pending());
+        drop(completions);
+        let ready = futures::poll!(poll_fn(|cx| service.poll_ready(cx)));
+        assert!(matches!(ready, Poll::Ready(Ok(()))));
+    }
+
+    #[test]
+    fn latency_policy() {
+        let n = |n| NonZeroUsize::new(n).unwrap();
+        let policy = LatencyPolicy::new(Duration::from_millis(100), n(2), n(8));
+        assert_eq!(policy.limit(), n(8));
+
+        policy.on_complete(Duration::from_secs(1));
+        assert_eq!(policy.limit(), n(4));
+        policy.on_complete(Duration::from_secs(1));
+        policy.on_complete(Duration::from_secs(1));
+        assert_eq!(policy.limit(), n(2));
+
+        policy.on_complete(Duration::ZERO);
+        assert_eq!(policy.limit(), n(2));
+        policy.on_complete(Duration::ZERO);
+        assert_eq!(policy.limit(), n(3));
+        for _ in 0..100 {
+            policy.on_complete(Duration::ZERO);
+        }
+        assert_eq!(policy.limit(), n(8));
+    }
+
+    #[tokio::test]
+    async fn policy_observes_latency() {
+        let n = |n| NonZeroUsize::new(n).unwrap();
+        let mut router = Router::new(());
+        router.request::<request::Shutdown, _>(|_, _| async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(())
+        });
+        let mut service = ConcurrencyLayer::new(n(1))
+            .policy(LatencyPolicy::new(Duration::from_millis(1), n(1), n(4)))
+            .layer(router);
+
+        for _ in 0..2 {
+            poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+            let fut = service.call(AnyRequest {
+                id: RequestId::Number(1),
+                method: request::Shutdown::METHOD.into(),
+                params: JsonValue::Null,
+                receipt: None,
+            });
+            fut.await.unwrap();
+        }
+        assert_eq!(service.limit(), 1);
+    }
+
+    #[tokio::test]
+    async fn latency_excludes_queue() {
+        #[derive(Default)]
+        struct Record(Mutex<Vec<Duration>>);
+
+        impl ConcurrencyPolicy for Arc<Record> {
+            fn limit(&self) -> NonZeroUsize {
+                NonZeroUsize::new(8).unwrap()
+            }
+
+            fn on_complete(&self, latency: Duration) {
+                self.0.lock().unwrap().push(latency);
+            }
+        }
+
+        let mut router = Router::new(());
+        router.request::<request::Shutdown, _>(|_, _| async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(())
+        });
+        let record = Arc::new(Record::default());
+        let mut service = ConcurrencyLayer::new(NonZeroUsize::new(8).unwrap())
+            .method::<request::Shutdown>(NonZeroUsize::new(1).unwrap())
+            .policy(record.clone())
+            .layer(router);
+
+        let mut futs = Vec::new();
+        for id in 0..3 {
+            poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+            futs.push(service.call(AnyRequest {
+                id: RequestId::Number(id),
+                method: request::Shutdown::METHOD.into(),
+                params: JsonValue::Null,
+                receipt: None,
+            }));
+        }
+        for ret in futures::future::join_all(futs).await {
+            ret.unwrap();
+        }
+        // The last one waited for 200ms in queue.
+        let latencies = record.0.lock().unwrap();
+        assert_eq!(latencies.len(), 3);
+        assert!(latencies
+            .iter()
+            .all(|latency| *latency < Duration::from_millis(200)));
+    }
+}
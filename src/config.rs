@@ -0,0 +1,322 @@
+//! Configuration of built-in middlewares loadable from settings files.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! [`AsyncLspConfig`] captures tunables of built-in middlewares in a [`Deserialize`]able struct,
+//! so that servers can expose them in their user-facing settings, eg. a JSON or TOML file. All
+//! fields are optional and use `camelCase` names, eg.
+//!
+//! ```json
+//! {
+//!     "concurrency": { "max": 4, "methods": { "textDocument/completion": 1 } },
+//!     "timeout": { "defaultMs": 10000, "methodsMs": { "textDocument/hover": 1000 } },
+//!     "coalesce": ["textDocument/completion"],
+//!     "debounceMs": 200,
+//!     "tracing": true,
+//!     "notifyPanic": "continue",
+//!     "restartable": false,
+//!     "spawnedByClient": true
+//! }
+//! ```
+//!
+//! Each middleware builder can be created from the configuration individually, eg.
+//! [`AsyncLspConfig::concurrency_layer`], or all together as the standard stack via
+//! [`AsyncLspConfig::build_server`].
+//!
+//! ```
+//! # use async_lsp::config::AsyncLspConfig;
+//! # use async_lsp::router::Router;
+//! # use async_lsp::ClientSocket;
+//! # fn work(client: ClientSocket, router: Router<()>, settings: &str) -> serde_json::Result<()> {
+//! let config = serde_json::from_str::<AsyncLspConfig>(settings)?;
+//! let service = config.build_server(client, tokio::time::sleep, router);
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+use crate::coalesce::CoalesceLayer;
+use crate::concurrency::{ConcurrencyLayer, Saturation};
+use crate::panic::{CatchUnwindLayer, PanicPolicy};
+use crate::server::LifecycleLayer;
+use crate::timeout::TimeoutLayer;
+use crate::ErrorCode;
+
+/// Tunables of built-in middlewares.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+#[non_exhaustive]
+pub struct AsyncLspConfig {
+    /// Settings of [`Concurrency`](crate::concurrency::Concurrency).
+    pub concurrency: ConcurrencyConfig,
+    /// Settings of [`Timeout`](crate::timeout::Timeout).
+    pub timeout: TimeoutConfig,
+    /// Methods coalesced by [`Coalesce`](crate::coalesce::Coalesce).
+    pub coalesce: Vec<String>,
+    /// The debouncing duration of [`DocumentPipeline`](crate::pipeline::DocumentPipeline) in
+    /// milliseconds, see [`AsyncLspConfig::debounce`].
+    pub debounce_ms: Option<u64>,
+    /// Whether [`Tracing`](crate::tracing::Tracing) attaches its default spans. Default is
+    /// `true`.
+    pub tracing: bool,
+    /// The action on panics in notification or event handlers, see
+    /// [`CatchUnwindBuilder::notify_policy`](crate::panic::CatchUnwindBuilder::notify_policy).
+    pub notify_panic: PanicPolicy,
+    /// Whether [`Lifecycle`](crate::server::Lifecycle) accepts re-initialization after shutdown.
+    pub restartable: bool,
+    /// See
+    /// [`ClientProcessMonitorBuilder::spawned_by_client`](crate::client_monitor::ClientProcessMonitorBuilder::spawned_by_client).
+    pub spawned_by_client: bool,
+}
+
+impl Default for AsyncLspConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: ConcurrencyConfig::default(),
+            timeout: TimeoutConfig::default(),
+            coalesce: Vec::new(),
+            debounce_ms: None,
+            tracing: true,
+            notify_panic: PanicPolicy::default(),
+            restartable: false,
+            spawned_by_client: false,
+        }
+    }
+}
+
+/// Settings of [`Concurrency`](crate::concurrency::Concurrency).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+#[non_exhaustive]
+pub struct ConcurrencyConfig {
+    /// The global limit. Default is the available parallelism.
+    pub max: Option<NonZeroUsize>,
+    /// Per-method limits.
+    pub methods: HashMap<String, NonZeroUsize>,
+    /// Reject requests exceeding limits with [`ErrorCode::SERVER_CANCELLED`], instead of queueing
+    /// them.
+    pub reject: bool,
+}
+
+/// Settings of [`Timeout`](crate::timeout::Timeout).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+#[non_exhaustive]
+pub struct TimeoutConfig {
+    /// The deadline of requests in milliseconds. Default is no deadline.
+    pub default_ms: Option<u64>,
+    /// Per-method deadlines in milliseconds.
+    pub methods_ms: HashMap<String, u64>,
+}
+
+impl AsyncLspConfig {
+    /// Create the builder of [`Concurrency`](crate::concurrency::Concurrency).
+    pub fn concurrency_layer(&self) -> ConcurrencyLayer {
+        let cfg = &self.concurrency;
+        let mut layer = cfg
+            .max
+            .map_or_else(ConcurrencyLayer::default, ConcurrencyLayer::new);
+        for (method, &limit) in &cfg.methods {
+            layer = layer.method_name(method.clone(), limit);
+        }
+        if cfg.reject {
+            layer = layer.saturation(Saturation::Reject(ErrorCode::SERVER_CANCELLED));
+        }
+        layer
+    }
+
+    /// Create the builder of [`Timeout`](crate::timeout::Timeout) using `clock`, eg. the timer
+    /// function `tokio::time::sleep`.
+    pub fn timeout_layer(&self, clock: impl Clock + 'static) -> TimeoutLayer {
+        let cfg = &self.timeout;
+        let mut layer = TimeoutLayer::new(clock);
+        if let Some(ms) = cfg.default_ms {
+            layer = layer.timeout(Duration::from_millis(ms));
+        }
+        for (method, &ms) in &cfg.methods_ms {
+            layer = layer.method_name(method.clone(), Duration::from_millis(ms));
+        }
+        layer
+    }
+
+    /// Create the builder of [`Coalesce`](crate::coalesce::Coalesce).
+    pub fn coalesce_layer(&self) -> CoalesceLayer {
+        self.coalesce
+            .iter()
+            .fold(CoalesceLayer::new(), |layer, method| {
+                layer.method_name(method.clone())
+            })
+    }
+
+    /// Create the builder of [`CatchUnwind`](crate::panic::CatchUnwind).
+    pub fn catch_unwind_layer(&self) -> CatchUnwindLayer {
+        CatchUnwindLayer::default().notify_policy(self.notify_panic)
+    }
+
+    /// Create the builder of [`Lifecycle`](crate::server::Lifecycle).
+    pub fn lifecycle_layer(&self) -> LifecycleLayer {
+        let layer = LifecycleLayer::default();
+        if self.restartable {
+            layer.restartable()
+        } else {
+            layer
+        }
+    }
+
+    /// Create the builder of [`Tracing`](crate::tracing::Tracing).
+    #[cfg(feature = "tracing")]
+    pub fn tracing_layer(&self) -> crate::tracing::TracingLayer {
+        if self.tracing {
+            crate::tracing::TracingLayer::default()
+        } else {
+            crate::tracing::TracingLayer::new()
+        }
+    }
+
+    /// Create the builder of [`ClientProcessMonitor`](crate::client_monitor::ClientProcessMonitor)
+    /// injecting exit events into `client`.
+    #[cfg(feature = "client-monitor")]
+    pub fn client_monitor_layer(
+        &self,
+        client: crate::ClientSocket,
+    ) -> crate::client_monitor::ClientProcessMonitorLayer {
+        let layer = crate::client_monitor::ClientProcessMonitorLayer::new(client);
+        if self.spawned_by_client {
+            layer.spawned_by_client()
+        } else {
+            layer
+        }
+    }
+
+    /// Get the debouncing duration to pass to
+    /// [`DocumentPipeline::debounce`](crate::pipeline::DocumentPipeline::debounce), if set.
+    #[must_use]
+    pub fn debounce(&self) -> Option<Duration> {
+        self.debounce_ms.map(Duration::from_millis)
+    }
+}
+
+#[cfg(all(feature = "client-monitor", feature = "tracing"))]
+mod server_impl {
+    use tower_layer::Layer;
+
+    use super::*;
+    use crate::client_monitor::ClientProcessMonitor;
+    use crate::coalesce::Coalesce;
+    use crate::concurrency::Concurrency;
+    use crate::panic::CatchUnwind;
+    use crate::server::Lifecycle;
+    use crate::timeout::Timeout;
+    use crate::tracing::Tracing;
+    use crate::{ClientSocket, LspService, ResponseError};
+
+    /// The standard middleware stack built by [`AsyncLspConfig::build_server`].
+    pub type ServerStack<S> =
+        Tracing<Lifecycle<CatchUnwind<Concurrency<Timeout<Coalesce<ClientProcessMonitor<S>>>>>>>;
+
+    impl AsyncLspConfig {
+        /// Wrap `service` with the standard middleware stack for Language Servers, from the
+        /// outermost to the innermost: [`Tracing`], [`Lifecycle`], [`CatchUnwind`],
+        /// [`Concurrency`], [`Timeout`], [`Coalesce`] and [`ClientProcessMonitor`].
+        ///
+        /// `client` is used to inject exit events, and `clock` is used for timeouts, eg. the
+        /// timer function `tokio::time::sleep`.
+        pub fn build_server<S>(
+            &self,
+            client: ClientSocket,
+            clock: impl Clock + 'static,
+            service: S,
+        ) -> ServerStack<S>
+        where
+            S: LspService<Error = ResponseError>,
+        {
+            let service = self.client_monitor_layer(client).layer(service);
+            let service = self.coalesce_layer().layer(service);
+            let service = self.timeout_layer(clock).layer(service);
+            let service = self.concurrency_layer().layer(service);
+            let service = self.catch_unwind_layer().layer(service);
+            let service = self.lifecycle_layer().layer(service);
+            self.tracing_layer().layer(service)
+        }
+    }
+}
+
+#[cfg(all(feature = "client-monitor", feature = "tracing"))]
+#[cfg_attr(docsrs, doc(cfg(all(feature = "client-monitor", feature = "tracing"))))]
+pub use server_impl::ServerStack;
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn deserialize() {
+        let config = serde_json::from_value::<AsyncLspConfig>(json!({})).unwrap();
+        assert_eq!(config, AsyncLspConfig::default());
+        assert!(config.tracing);
+
+        let config = serde_json::from_value::<AsyncLspConfig>(json!({
+            "concurrency": { "max": 4, "methods": { "textDocument/completion": 1 } },
+            "timeout": { "defaultMs": 10 },
+            "debounceMs": 200,
+            "tracing": false,
+            "notifyPanic": "continue",
+        }))
+        .unwrap();
+        assert_eq!(config.concurrency.max, NonZeroUsize::new(4));
+        assert_eq!(
+            config.concurrency.methods["textDocument/completion"],
+            NonZeroUsize::new(1).unwrap()
+        );
+        assert_eq!(config.timeout.default_ms, Some(10));
+        assert_eq!(config.debounce(), Some(Duration::from_millis(200)));
+        assert!(!config.tracing);
+        assert_eq!(config.notify_panic, PanicPolicy::Continue);
+        assert!(!config.restartable);
+
+        serde_json::from_value::<AsyncLspConfig>(json!({ "concurrency": { "max": 0 } }))
+            .unwrap_err();
+    }
+
+    #[cfg(all(feature = "client-monitor", feature = "tracing"))]
+    #[tokio::test]
+    async fn build_server() {
+        use std::future::{pending, poll_fn};
+
+        use lsp_types::request::{self, Request};
+        use tower_service::Service;
+
+        use crate::router::Router;
+        use crate::{AnyRequest, ClientSocket, RequestId};
+
+        let mut router = Router::new(());
+        router.request::<request::Initialize, _>(|_, _| pending());
+        let config = serde_json::from_value::<AsyncLspConfig>(json!({
+            "timeout": { "defaultMs": 1 },
+        }))
+        .unwrap();
+        let mut service =
+            config.build_server(ClientSocket::new_closed(), |_: Duration| async {}, router);
+
+        poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+        let err = service
+            .call(AnyRequest {
+                id: RequestId::Number(1),
+                method: request::Initialize::METHOD.into(),
+                params: json!({ "capabilities": {} }),
+                receipt: None,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::REQUEST_FAILED);
+    }
+}
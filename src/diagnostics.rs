@@ -0,0 +1,794 @@
+//! Helpers for publishing diagnostics.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! ## Publishing
+//!
+//! [`DiagnosticsPublisher`] handles the boilerplate around `textDocument/publishDiagnostics`. It
+//! tracks versions of opened documents to attach to published diagnostics, skips publishing when
+//! the diagnostic set of a document is unchanged, optionally debounces frequent updates, and
+//! clears diagnostics when a document is closed.
+//!
+//! ## Pull diagnostics
+//!
+//! In the pull model of LSP 3.17, the editor requests diagnostics via `textDocument/diagnostic`
+//! and `workspace/diagnostic`, passing result IDs of previous reports, and the server may reply
+//! that a report is unchanged. [`DiagnosticsCache`] generates and caches result IDs of reported
+//! diagnostic sets, so handlers only compute diagnostics and the cache decides between full and
+//! unchanged reports. For `workspace/diagnostic`, [`DiagnosticsCache::workspace`] creates a
+//! [`WorkspaceDiagnosticsSink`] streaming reports as partial results when the editor accepts
+//! them.
+//!
+//! The cache does not distinguish the `identifier` of requests. Use separate caches for
+//! diagnostic providers registered with different identifiers.
+//!
+//! ## Aggregation
+//!
+//! `textDocument/publishDiagnostics` always replaces the whole diagnostic set of a document. When
+//! diagnostics come from multiple independent subsystems (eg. a linter and a type checker), each
+//! of them would override the others. [`DiagnosticsAggregator`] keeps diagnostic sets per
+//! document and per source tag, so that each subsystem only replaces its own entries, and
+//! publishes the merged and deduplicated set.
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{self, BoxFuture};
+use futures::FutureExt;
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, PublishDiagnostics,
+};
+use lsp_types::{
+    Diagnostic, DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+    DocumentDiagnosticParams, DocumentDiagnosticReport, DocumentDiagnosticReportKind,
+    DocumentDiagnosticReportResult, FullDocumentDiagnosticReport, PublishDiagnosticsParams,
+    RelatedFullDocumentDiagnosticReport, RelatedUnchangedDocumentDiagnosticReport,
+    UnchangedDocumentDiagnosticReport, Url, WorkspaceDiagnosticParams, WorkspaceDiagnosticReport,
+    WorkspaceDiagnosticReportPartialResult, WorkspaceDiagnosticReportResult,
+    WorkspaceDocumentDiagnosticReport, WorkspaceFullDocumentDiagnosticReport,
+    WorkspaceUnchangedDocumentDiagnosticReport,
+};
+
+use crate::clock::{Clock, SharedClock};
+use crate::progress::{PartialResultProgress, PartialResultProgressParams, PartialResultToken};
+use crate::router::Router;
+use crate::{ClientSocket, ResponseError, Result};
+
+/// The cheaply cloneable publisher of diagnostics with change detection and debouncing.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone)]
+#[must_use]
+pub struct DiagnosticsPublisher {
+    client: ClientSocket,
+    debounce: Option<(Duration, SharedClock)>,
+    docs: Arc<Mutex<HashMap<Url, PublishState>>>,
+}
+
+#[derive(Debug, Default)]
+struct PublishState {
+    /// The version of the opened document.
+    version: Option<i32>,
+    /// The last published diagnostics.
+    published: Vec<Diagnostic>,
+    /// Bumped on each update, to drop superseded debounced ones.
+    generation: u64,
+}
+
+impl fmt::Debug for DiagnosticsPublisher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiagnosticsPublisher")
+            .field("debounce", &self.debounce.as_ref().map(|(dur, _)| dur))
+            .field("documents", &self.docs.lock().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl DiagnosticsPublisher {
+    /// Create a publisher sending diagnostics via `client`, without debouncing.
+    pub fn new(client: ClientSocket) -> Self {
+        Self {
+            client,
+            debounce: None,
+            docs: Arc::default(),
+        }
+    }
+
+    /// Wait for `duration` without further updates of a document before publishing its
+    /// diagnostics. Default is no debouncing.
+    ///
+    /// Since this crate is runtime-agnostic, the [`Clock`] should be provided, eg. the timer
+    /// function `tokio::time::sleep`.
+    pub fn debounce(mut self, duration: Duration, clock: impl Clock + 'static) -> Self {
+        self.debounce = Some((duration, Arc::new(clock)));
+        self
+    }
+
+    /// Get the last published diagnostics of a document.
+    #[must_use]
+    pub fn published(&self, uri: &Url) -> Vec<Diagnostic> {
+        self.docs
+            .lock()
+            .unwrap()
+            .get(uri)
+            .map(|doc| doc.published.clone())
+            .unwrap_or_default()
+    }
+
+    /// Register handlers of `textDocument/didOpen`, `textDocument/didChange` and
+    /// `textDocument/didClose` to `router`, which track document versions and clear diagnostics
+    /// of closed documents.
+    ///
+    /// If these notifications are also handled elsewhere, call [`DiagnosticsPublisher::did_open`]
+    /// and friends from those handlers instead.
+    pub fn register<St, Error>(&self, router: &mut Router<St, Error>)
+    where
+        Error: From<ResponseError> + Send + 'static,
+    {
+        let this = self.clone();
+        router.notification::<DidOpenTextDocument>(move |_, params| {
+            this.did_open(&params);
+            ControlFlow::Continue(())
+        });
+        let this = self.clone();
+        router.notification::<DidChangeTextDocument>(move |_, params| {
+            this.did_change(&params);
+            ControlFlow::Continue(())
+        });
+        let this = self.clone();
+        router.notification::<DidCloseTextDocument>(move |_, params| {
+            match this.did_close(&params) {
+                Ok(()) => ControlFlow::Continue(()),
+                Err(err) => ControlFlow::Break(Err(err)),
+            }
+        });
+    }
+
+    /// Handle `textDocument/didOpen`.
+    pub fn did_open(&self, params: &DidOpenTextDocumentParams) {
+        let doc = &params.text_document;
+        let mut docs = self.docs.lock().unwrap();
+        docs.entry(doc.uri.clone()).or_default().version = Some(doc.version);
+    }
+
+    /// Handle `textDocument/didChange`. Changes of documents which are not opened are ignored.
+    pub fn did_change(&self, params: &DidChangeTextDocumentParams) {
+        let doc = &params.text_document;
+        if let Some(state) = self.docs.lock().unwrap().get_mut(&doc.uri) {
+            if state.version.is_some() {
+                state.version = Some(doc.version);
+            }
+        }
+    }
+
+    /// Handle `textDocument/didClose`. Pending debounced diagnostics of the document are dropped,
+    /// and published ones are cleared.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::ServiceStopped`][crate::Error::ServiceStopped] when the service main loop
+    ///   stopped.
+    pub fn did_close(&self, params: &DidCloseTextDocumentParams) -> Result<()> {
+        let uri = &params.text_document.uri;
+        let state = self.docs.lock().unwrap().remove(uri);
+        match state {
+            Some(state) if !state.published.is_empty() => {
+                self.client
+                    .notify::<PublishDiagnostics>(PublishDiagnosticsParams {
+                        uri: uri.clone(),
+                        diagnostics: Vec::new(),
+                        version: None,
+                    })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Publish `diagnostics` of document `uri`, attached with its version if it is opened.
+    /// Nothing is sent if the diagnostic set is the same as the last published one, regardless
+    /// of the order.
+    ///
+    /// Without debouncing, diagnostics are published before returning, and the returned future
+    /// resolves immediately. With debouncing, the returned future must be driven, eg. spawned,
+    /// to publish after the debouncing duration, and it resolves without publishing if
+    /// superseded by a later update or [`DiagnosticsPublisher::did_close`].
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::ServiceStopped`][crate::Error::ServiceStopped] when the service main loop
+    ///   stopped.
+    pub fn publish(
+        &self,
+        uri: Url,
+        diagnostics: Vec<Diagnostic>,
+    ) -> BoxFuture<'static, Result<()>> {
+        let generation = {
+            let mut docs = self.docs.lock().unwrap();
+            let state = docs.entry(uri.clone()).or_default();
+            state.generation += 1;
+            state.generation
+        };
+        let (duration, clock) = match &self.debounce {
+            Some((duration, clock)) => (*duration, clock.clone()),
+            None => return future::ready(self.flush(uri, diagnostics, generation)).boxed(),
+        };
+        let this = self.clone();
+        async move {
+            clock.sleep(duration).await;
+            this.flush(uri, diagnostics, generation)
+        }
+        .boxed()
+    }
+
+    fn flush(&self, uri: Url, diagnostics: Vec<Diagnostic>, generation: u64) -> Result<()> {
+        let mut docs = self.docs.lock().unwrap();
+        let state = match docs.get_mut(&uri) {
+            Some(state) if state.generation == generation => state,
+            _ => return Ok(()),
+        };
+        let unchanged = same_set(&state.published, &diagnostics);
+        let version = state.version;
+        if diagnostics.is_empty() && version.is_none() {
+            docs.remove(&uri);
+        } else {
+            state.published = diagnostics.clone();
+        }
+        drop(docs);
+        if unchanged {
+            return Ok(());
+        }
+        self.client
+            .notify::<PublishDiagnostics>(PublishDiagnosticsParams {
+                uri,
+                diagnostics,
+                version,
+            })
+    }
+}
+
+/// Check if two diagnostic lists are the same regardless of the order.
+fn same_set(lhs: &[Diagnostic], rhs: &[Diagnostic]) -> bool {
+    lhs.len() == rhs.len() && lhs.iter().all(|diag| rhs.contains(diag))
+}
+
+/// The cheaply cloneable cache of result IDs for pull diagnostics.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone, Default)]
+pub struct DiagnosticsCache(Arc<Mutex<CacheState>>);
+
+#[derive(Default)]
+struct CacheState {
+    next_id: u64,
+    /// The result ID and diagnostics of the last report of each document.
+    reports: HashMap<Url, (String, Vec<Diagnostic>)>,
+}
+
+impl fmt::Debug for DiagnosticsCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiagnosticsCache")
+            .field("documents", &self.0.lock().unwrap().reports.len())
+            .finish()
+    }
+}
+
+impl DiagnosticsCache {
+    /// Create an empty cache.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create the report of `diagnostics` for document `uri`.
+    ///
+    /// If `diagnostics` are the same as the last report regardless of the order, the result ID is
+    /// kept, and an unchanged report is returned if `previous_result_id` matches it. Otherwise, a
+    /// full report with a new result ID is returned.
+    #[must_use]
+    pub fn report(
+        &self,
+        uri: &Url,
+        previous_result_id: Option<&str>,
+        diagnostics: Vec<Diagnostic>,
+    ) -> DocumentDiagnosticReportKind {
+        let mut state = self.0.lock().unwrap();
+        let state = &mut *state;
+        let result_id = match state.reports.get(uri) {
+            Some((result_id, prev)) if same_set(prev, &diagnostics) => {
+                if previous_result_id == Some(result_id) {
+                    return DocumentDiagnosticReportKind::Unchanged(
+                        UnchangedDocumentDiagnosticReport {
+                            result_id: result_id.clone(),
+                        },
+                    );
+                }
+                result_id.clone()
+            }
+            _ => {
+                state.next_id += 1;
+                let result_id = state.next_id.to_string();
+                state
+                    .reports
+                    .insert(uri.clone(), (result_id.clone(), diagnostics.clone()));
+                result_id
+            }
+        };
+        DocumentDiagnosticReportKind::Full(FullDocumentDiagnosticReport {
+            result_id: Some(result_id),
+            items: diagnostics,
+        })
+    }
+
+    /// Create the response of `textDocument/diagnostic` with `params`, reporting `diagnostics`.
+    ///
+    /// See [`DiagnosticsCache::report`] for details.
+    #[must_use]
+    pub fn document_report(
+        &self,
+        params: &DocumentDiagnosticParams,
+        diagnostics: Vec<Diagnostic>,
+    ) -> DocumentDiagnosticReportResult {
+        let uri = &params.text_document.uri;
+        let report = match self.report(uri, params.previous_result_id.as_deref(), diagnostics) {
+            DocumentDiagnosticReportKind::Full(report) => {
+                DocumentDiagnosticReport::Full(RelatedFullDocumentDiagnosticReport {
+                    related_documents: None,
+                    full_document_diagnostic_report: report,
+                })
+            }
+            DocumentDiagnosticReportKind::Unchanged(report) => {
+                DocumentDiagnosticReport::Unchanged(RelatedUnchangedDocumentDiagnosticReport {
+                    related_documents: None,
+                    unchanged_document_diagnostic_report: report,
+                })
+            }
+        };
+        DocumentDiagnosticReportResult::Report(report)
+    }
+
+    /// Create a sink of reports for `workspace/diagnostic` with `params`.
+    ///
+    /// # Panics
+    ///
+    /// If the `partialResultToken` is still in use. See [`PartialResultToken::new`].
+    pub fn workspace(
+        &self,
+        client: &ClientSocket,
+        params: &WorkspaceDiagnosticParams,
+    ) -> WorkspaceDiagnosticsSink {
+        WorkspaceDiagnosticsSink {
+            cache: self.clone(),
+            client: client.clone(),
+            token: params
+                .partial_result_params
+                .partial_result_token
+                .clone()
+                .map(PartialResultToken::new),
+            previous: params
+                .previous_result_ids
+                .iter()
+                .map(|prev| (prev.uri.clone(), prev.value.clone()))
+                .collect(),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Forget the last report of a document, eg. when it is deleted.
+    pub fn remove(&self, uri: &Url) {
+        self.0.lock().unwrap().reports.remove(uri);
+    }
+}
+
+/// The sink of document reports of a `workspace/diagnostic` request, streaming them as partial
+/// results.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+#[must_use = "Buffered reports must be returned in the response"]
+pub struct WorkspaceDiagnosticsSink {
+    cache: DiagnosticsCache,
+    client: ClientSocket,
+    token: Option<PartialResultToken>,
+    previous: HashMap<Url, String>,
+    buffer: Vec<WorkspaceDocumentDiagnosticReport>,
+}
+
+impl WorkspaceDiagnosticsSink {
+    /// Report `diagnostics` of document `uri`, with its `version` if it is opened.
+    ///
+    /// See [`WorkspaceDiagnosticsSink::extend`] for details.
+    pub fn push(&mut self, uri: Url, version: Option<i64>, diagnostics: Vec<Diagnostic>) {
+        self.extend([(uri, version, diagnostics)]);
+    }
+
+    /// Report a batch of documents with their versions and diagnostics.
+    ///
+    /// The batch is sent as a partial result if the editor accepts them, or buffered otherwise.
+    /// Empty batches are never sent.
+    pub fn extend(&mut self, docs: impl IntoIterator<Item = (Url, Option<i64>, Vec<Diagnostic>)>) {
+        let items = docs
+            .into_iter()
+            .map(|(uri, version, diagnostics)| {
+                let prev = self.previous.get(&uri).map(|id| &**id);
+                match self.cache.report(&uri, prev, diagnostics) {
+                    DocumentDiagnosticReportKind::Full(report) => {
+                        WorkspaceDocumentDiagnosticReport::Full(
+                            WorkspaceFullDocumentDiagnosticReport {
+                                uri,
+                                version,
+                                full_document_diagnostic_report: report,
+                            },
+                        )
+                    }
+                    DocumentDiagnosticReportKind::Unchanged(report) => {
+                        WorkspaceDocumentDiagnosticReport::Unchanged(
+                            WorkspaceUnchangedDocumentDiagnosticReport {
+                                uri,
+                                version,
+                                unchanged_document_diagnostic_report: report,
+                            },
+                        )
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        let token = match &self.token {
+            Some(token) => token.get(),
+            None => return self.buffer.extend(items),
+        };
+        if items.is_empty() {
+            return;
+        }
+        let value = WorkspaceDiagnosticReportPartialResult { items };
+        // Errors mean the main loop stopped, and there is nobody to report to.
+        let _: Result<_> =
+            self.client
+                .notify::<PartialResultProgress>(PartialResultProgressParams {
+                    token: token.clone(),
+                    value: serde_json::to_value(value).expect("Failed to serialize"),
+                });
+    }
+
+    /// Finish streaming, and get the final response.
+    ///
+    /// It contains all buffered reports if the editor does not accept partial results, or no
+    /// reports otherwise, since they are all sent as partial results.
+    pub fn finish(self) -> WorkspaceDiagnosticReportResult {
+        WorkspaceDiagnosticReportResult::Report(WorkspaceDiagnosticReport { items: self.buffer })
+    }
+}
+
+/// Aggregator of diagnostics from multiple sources.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+pub struct DiagnosticsAggregator {
+    client: ClientSocket,
+    docs: HashMap<Url, DocDiagnostics>,
+}
+
+#[derive(Debug, Default)]
+struct DocDiagnostics {
+    version: Option<i32>,
+    sources: BTreeMap<String, Vec<Diagnostic>>,
+}
+
+impl DocDiagnostics {
+    fn merged(&self) -> Vec<Diagnostic> {
+        let mut ret = Vec::<Diagnostic>::new();
+        for diag in self.sources.values().flatten() {
+            if !ret.contains(diag) {
+                ret.push(diag.clone());
+            }
+        }
+        ret
+    }
+}
+
+impl DiagnosticsAggregator {
+    /// Create an empty aggregator publishing via `client`.
+    #[must_use]
+    pub fn new(client: ClientSocket) -> Self {
+        Self {
+            client,
+            docs: HashMap::new(),
+        }
+    }
+
+    /// Get the current merged diagnostics of a document.
+    #[must_use]
+    pub fn merged(&self, uri: &Url) -> Vec<Diagnostic> {
+        self.docs
+            .get(uri)
+            .map(DocDiagnostics::merged)
+            .unwrap_or_default()
+    }
+
+    /// Replace the diagnostics owned by `source` for document `uri`, and publish the merged set.
+    ///
+    /// `version` is the document version these diagnostics are computed on, if known. It is
+    /// recorded as the latest version of the document.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::ServiceStopped`][crate::Error::ServiceStopped] when the service main loop
+    ///   stopped.
+    pub fn update(
+        &mut self,
+        uri: Url,
+        source: impl Into<String>,
+        diagnostics: Vec<Diagnostic>,
+        version: Option<i32>,
+    ) -> Result<()> {
+        let doc = self.docs.entry(uri.clone()).or_default();
+        if version.is_some() {
+            doc.version = version;
+        }
+        if diagnostics.is_empty() {
+            doc.sources.remove(&source.into());
+        } else {
+            doc.sources.insert(source.into(), diagnostics);
+        }
+        self.publish(uri)
+    }
+
+    /// Clear the diagnostics owned by `source` in all documents, and publish the changed ones.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::ServiceStopped`][crate::Error::ServiceStopped] when the service main loop
+    ///   stopped.
+    pub fn clear_source(&mut self, source: &str) -> Result<()> {
+        let changed = self
+            .docs
+            .iter_mut()
+            .filter_map(|(uri, doc)| doc.sources.remove(source).map(|_| uri.clone()))
+            .collect::<Vec<_>>();
+        changed.into_iter().try_for_each(|uri| self.publish(uri))
+    }
+
+    /// Clear all diagnostics of document `uri`, typically when it is closed.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::ServiceStopped`][crate::Error::ServiceStopped] when the service main loop
+    ///   stopped.
+    pub fn clear(&mut self, uri: &Url) -> Result<()> {
+        if self.docs.remove(uri).is_none() {
+            return Ok(());
+        }
+        self.client
+            .notify::<PublishDiagnostics>(PublishDiagnosticsParams {
+                uri: uri.clone(),
+                diagnostics: Vec::new(),
+                version: None,
+            })
+    }
+
+    fn publish(&mut self, uri: Url) -> Result<()> {
+        let doc = &self.docs[&uri];
+        let params = PublishDiagnosticsParams {
+            uri: uri.clone(),
+            diagnostics: doc.merged(),
+            version: doc.version,
+        };
+        if doc.sources.is_empty() {
+            self.docs.remove(&uri);
+        }
+        self.client.notify::<PublishDiagnostics>(params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc;
+    use lsp_types::notification::Notification;
+    use lsp_types::{
+        NumberOrString, PreviousResultId, Range, TextDocumentIdentifier, TextDocumentItem,
+        VersionedTextDocumentIdentifier,
+    };
+
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::{MainLoopEvent, Message, PeerSocket};
+
+    fn published(rx: &mut mpsc::UnboundedReceiver<MainLoopEvent>) -> PublishDiagnosticsParams {
+        match rx.try_next().unwrap().unwrap() {
+            MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                assert_eq!(notif.method, PublishDiagnostics::METHOD);
+                serde_json::from_value(notif.params).unwrap()
+            }
+            _ => panic!("unexpected event"),
+        }
+    }
+
+    #[test]
+    fn publish() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let publisher = DiagnosticsPublisher::new(ClientSocket(PeerSocket { tx }));
+        let uri = Url::parse("file:///foo").unwrap();
+        let diag = |msg: &str| Diagnostic::new_simple(Range::default(), msg.into());
+        let publish = |diags: Vec<Diagnostic>| {
+            publisher
+                .publish(uri.clone(), diags)
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        };
+
+        publisher.did_open(&DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(uri.clone(), "rust".into(), 1, String::new()),
+        });
+        publish(vec![diag("a"), diag("b")]);
+        let params = published(&mut rx);
+        assert_eq!(params.diagnostics, [diag("a"), diag("b")]);
+        assert_eq!(params.version, Some(1));
+
+        // Unchanged sets are not published.
+        publish(vec![diag("b"), diag("a")]);
+        assert!(rx.try_next().is_err());
+
+        publisher.did_change(&DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(uri.clone(), 2),
+            content_changes: Vec::new(),
+        });
+        publish(vec![diag("b")]);
+        let params = published(&mut rx);
+        assert_eq!(params.diagnostics, [diag("b")]);
+        assert_eq!(params.version, Some(2));
+        assert_eq!(publisher.published(&uri), [diag("b")]);
+
+        publisher
+            .did_close(&DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+            })
+            .unwrap();
+        let params = published(&mut rx);
+        assert!(params.diagnostics.is_empty());
+        assert_eq!(params.version, None);
+        assert!(publisher.published(&uri).is_empty());
+
+        // Documents not opened have no versions.
+        publish(vec![diag("c")]);
+        assert_eq!(published(&mut rx).version, None);
+        publish(Vec::new());
+        assert!(published(&mut rx).diagnostics.is_empty());
+        assert!(rx.try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn debounce() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let clock = ManualClock::new();
+        let publisher = DiagnosticsPublisher::new(ClientSocket(PeerSocket { tx }))
+            .debounce(Duration::from_millis(100), clock.clone());
+        let uri = Url::parse("file:///foo").unwrap();
+        let diag = |msg: &str| Diagnostic::new_simple(Range::default(), msg.into());
+
+        let first = tokio::spawn(publisher.publish(uri.clone(), vec![diag("a")]));
+        let second = tokio::spawn(publisher.publish(uri.clone(), vec![diag("b")]));
+        while clock.sleepers() < 2 {
+            tokio::task::yield_now().await;
+        }
+        assert!(rx.try_next().is_err());
+
+        clock.advance(Duration::from_millis(100));
+        first.await.unwrap().unwrap();
+        second.await.unwrap().unwrap();
+        assert_eq!(published(&mut rx).diagnostics, [diag("b")]);
+        assert!(rx.try_next().is_err());
+    }
+
+    #[test]
+    fn pull() {
+        let cache = DiagnosticsCache::new();
+        let uri = Url::parse("file:///foo").unwrap();
+        let diag = |msg: &str| Diagnostic::new_simple(Range::default(), msg.into());
+        let params = |prev: Option<&str>| DocumentDiagnosticParams {
+            text_document: TextDocumentIdentifier::new(uri.clone()),
+            identifier: None,
+            previous_result_id: prev.map(Into::into),
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+        let full = |ret: DocumentDiagnosticReportResult| match ret {
+            DocumentDiagnosticReportResult::Report(DocumentDiagnosticReport::Full(report)) => {
+                report.full_document_diagnostic_report
+            }
+            ret => panic!("unexpected report: {ret:?}"),
+        };
+
+        let report = full(cache.document_report(&params(None), vec![diag("a"), diag("b")]));
+        let id = report.result_id.unwrap();
+        // Same diagnostics keep the result ID.
+        let report = full(cache.document_report(&params(None), vec![diag("b"), diag("a")]));
+        assert_eq!(report.result_id.as_ref(), Some(&id));
+        match cache.document_report(&params(Some(&id)), vec![diag("b"), diag("a")]) {
+            DocumentDiagnosticReportResult::Report(DocumentDiagnosticReport::Unchanged(report)) => {
+                assert_eq!(report.unchanged_document_diagnostic_report.result_id, id);
+            }
+            ret => panic!("unexpected report: {ret:?}"),
+        }
+        let report = full(cache.document_report(&params(Some(&id)), vec![diag("c")]));
+        assert_ne!(report.result_id.as_ref(), Some(&id));
+        assert_eq!(report.items, [diag("c")]);
+
+        // Workspace reports without partial results are buffered.
+        let (tx, mut rx) = mpsc::unbounded();
+        let client = ClientSocket(PeerSocket { tx });
+        let bar = Url::parse("file:///bar").unwrap();
+        let mut params = WorkspaceDiagnosticParams {
+            identifier: None,
+            previous_result_ids: vec![PreviousResultId {
+                uri: uri.clone(),
+                value: report.result_id.unwrap(),
+            }],
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+        let mut sink = cache.workspace(&client, &params);
+        sink.push(uri.clone(), Some(1), vec![diag("c")]);
+        sink.push(bar.clone(), None, vec![diag("d")]);
+        let items = match sink.finish() {
+            WorkspaceDiagnosticReportResult::Report(report) => report.items,
+            ret => panic!("unexpected report: {ret:?}"),
+        };
+        assert!(matches!(
+            &items[..],
+            [
+                WorkspaceDocumentDiagnosticReport::Unchanged(_),
+                WorkspaceDocumentDiagnosticReport::Full(_),
+            ]
+        ));
+        assert!(rx.try_next().is_err());
+
+        // Or streamed as partial results.
+        params.partial_result_params.partial_result_token = Some(NumberOrString::Number(1));
+        let mut sink = cache.workspace(&client, &params);
+        sink.extend([(bar.clone(), None, vec![diag("d")])]);
+        sink.extend([]);
+        match sink.finish() {
+            WorkspaceDiagnosticReportResult::Report(report) => assert!(report.items.is_empty()),
+            ret => panic!("unexpected report: {ret:?}"),
+        }
+        match rx.try_next().unwrap().unwrap() {
+            MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                assert_eq!(notif.params["token"], 1);
+                let items = &notif.params["value"]["items"];
+                assert_eq!(items[0]["kind"], "full");
+                assert_eq!(items[0]["uri"], "file:///bar");
+            }
+            _ => panic!("unexpected event"),
+        }
+        assert!(rx.try_next().is_err());
+    }
+
+    #[test]
+    fn aggregate() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let mut agg = DiagnosticsAggregator::new(ClientSocket(PeerSocket { tx }));
+        let uri = Url::parse("file:///foo").unwrap();
+        let diag = |msg: &str| Diagnostic::new_simple(Range::default(), msg.into());
+
+        agg.update(uri.clone(), "lint", vec![diag("a"), diag("b")], Some(1))
+            .unwrap();
+        assert_eq!(published(&mut rx).diagnostics.len(), 2);
+
+        agg.update(uri.clone(), "check", vec![diag("b"), diag("c")], None)
+            .unwrap();
+        let params = published(&mut rx);
+        // Sources are merged in order of their tags, and duplicates are removed.
+        assert_eq!(params.diagnostics, [diag("b"), diag("c"), diag("a")]);
+        assert_eq!(params.version, Some(1));
+        assert_eq!(agg.merged(&uri), params.diagnostics);
+
+        agg.clear_source("lint").unwrap();
+        assert_eq!(published(&mut rx).diagnostics, [diag("b"), diag("c")]);
+
+        agg.clear(&uri).unwrap();
+        assert!(published(&mut rx).diagnostics.is_empty());
+        assert!(rx.try_next().is_err());
+    }
+}
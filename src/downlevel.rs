@@ -0,0 +1,396 @@
+//! Protocol version detection and downlevel adaptation.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Language Clients do not declare which LSP version they implement, but it can be inferred from
+//! the shape of their capabilities: eg. `general.positionEncodings` only exists since 3.17.
+//! Older clients may fail to deserialize fields introduced by later versions, like
+//! [`CompletionItem::label_details`] of 3.17, or reject the whole response.
+//!
+//! This middleware detects the [`ProtocolVersion`] on `initialize` into a shared
+//! [`ProtocolLevel`] handle, and strips newer fields from responses of `textDocument/completion`
+//! and `completionItem/resolve` for older clients. Handlers can keep a clone of the handle to
+//! query the version, or to adapt other messages, eg. diagnostics via
+//! [`ProtocolLevel::adapt_diagnostic`].
+use std::future::Future;
+use std::ops::ControlFlow;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{ready, Context, Poll};
+
+use lsp_types::request::{self, Request};
+use lsp_types::{
+    CompletionItem, CompletionResponse, CompletionTextEdit, Diagnostic, InitializeParams, TextEdit,
+};
+use pin_project_lite::pin_project;
+use serde_json::Value as JsonValue;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{AnyEvent, AnyNotification, AnyRequest, EventHints, LspService, Result};
+
+/// A minor version of the Language Server Protocol 3.x.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub enum ProtocolVersion {
+    /// LSP 3.15 or earlier.
+    V3_15,
+    /// LSP 3.16.
+    V3_16,
+    /// LSP 3.17.
+    V3_17,
+}
+
+impl ProtocolVersion {
+    /// The latest version supported by this crate.
+    pub const LATEST: Self = Self::V3_17;
+
+    /// Infer the version of a Language Client from its `initialize` parameters.
+    ///
+    /// It is the earliest version defining all the capabilities and parameters used, thus may
+    /// underestimate clients advertising few capabilities.
+    #[must_use]
+    pub fn detect(params: &InitializeParams) -> Self {
+        let caps = &params.capabilities;
+        let general = caps.general.as_ref();
+        let text = caps.text_document.as_ref();
+        let workspace = caps.workspace.as_ref();
+
+        let v3_17 = general.map_or(false, |c| c.position_encodings.is_some())
+            || text.map_or(false, |c| {
+                c.inlay_hint.is_some()
+                    || c.diagnostic.is_some()
+                    || c.inline_value.is_some()
+                    || c.type_hierarchy.is_some()
+            })
+            || workspace.map_or(false, |c| {
+                c.inlay_hint.is_some() || c.inline_value.is_some() || c.diagnostic.is_some()
+            });
+        if v3_17 {
+            return Self::V3_17;
+        }
+
+        let v3_16 = params.locale.is_some()
+            || general.map_or(false, |c| {
+                c.markdown.is_some()
+                    || c.regular_expressions.is_some()
+                    || c.stale_request_support.is_some()
+            })
+            || text.map_or(false, |c| {
+                c.semantic_tokens.is_some()
+                    || c.call_hierarchy.is_some()
+                    || c.linked_editing_range.is_some()
+                    || c.moniker.is_some()
+            })
+            || workspace.map_or(false, |c| {
+                c.semantic_tokens.is_some() || c.code_lens.is_some() || c.file_operations.is_some()
+            })
+            || caps
+                .window
+                .as_ref()
+                .map_or(false, |c| c.show_document.is_some());
+        if v3_16 {
+            return Self::V3_16;
+        }
+        Self::V3_15
+    }
+}
+
+/// A cheaply cloneable shared handle to the detected [`ProtocolVersion`] of the client.
+///
+/// Before `initialize`, the version is unknown and messages are not adapted.
+#[derive(Debug, Clone, Default)]
+pub struct ProtocolLevel {
+    version: Arc<RwLock<Option<ProtocolVersion>>>,
+}
+
+impl ProtocolLevel {
+    /// Create a handle with an unknown version.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the version detected on `initialize`, if any.
+    #[must_use]
+    pub fn get(&self) -> Option<ProtocolVersion> {
+        *self.version.read().unwrap()
+    }
+
+    /// Override the current version.
+    pub fn set(&self, version: Option<ProtocolVersion>) {
+        *self.version.write().unwrap() = version;
+    }
+
+    /// Whether the client supports `version`, assuming the latest version if unknown.
+    #[must_use]
+    pub fn supports(&self, version: ProtocolVersion) -> bool {
+        self.get().unwrap_or(ProtocolVersion::LATEST) >= version
+    }
+
+    /// Strip fields of `item` unknown to the client.
+    ///
+    /// - Before 3.17, `labelDetails` is removed.
+    /// - Before 3.16, `insertTextMode` is removed, and insert-replace edits are converted into
+    ///   plain [`TextEdit`]s of the insert range.
+    pub fn adapt_completion_item(&self, item: &mut CompletionItem) {
+        if !self.supports(ProtocolVersion::V3_17) {
+            item.label_details = None;
+        }
+        if !self.supports(ProtocolVersion::V3_16) {
+            item.insert_text_mode = None;
+            if let Some(CompletionTextEdit::InsertAndReplace(edit)) = &item.text_edit {
+                let edit = TextEdit::new(edit.insert, edit.new_text.clone());
+                item.text_edit = Some(CompletionTextEdit::Edit(edit));
+            }
+        }
+    }
+
+    /// Strip fields of all items in `resp` unknown to the client. See
+    /// [`ProtocolLevel::adapt_completion_item`].
+    pub fn adapt_completion(&self, resp: &mut CompletionResponse) {
+        let items = match resp {
+            CompletionResponse::Array(items) => items,
+            CompletionResponse::List(list) => &mut list.items,
+        };
+        for item in items {
+            self.adapt_completion_item(item);
+        }
+    }
+
+    /// Strip fields of `diag` unknown to the client.
+    ///
+    /// - Before 3.16, `codeDescription` and `data` are removed.
+    pub fn adapt_diagnostic(&self, diag: &mut Diagnostic) {
+        if !self.supports(ProtocolVersion::V3_16) {
+            diag.code_description = None;
+            diag.data = None;
+        }
+    }
+
+    /// Adapt the JSON response of a request method, if it needs adaptation.
+    fn adapt_response(&self, method: &str, resp: &mut JsonValue) {
+        match method {
+            request::Completion::METHOD => {
+                adapt_json(resp, |resp: &mut CompletionResponse| {
+                    self.adapt_completion(resp);
+                });
+            }
+            request::ResolveCompletionItem::METHOD => {
+                adapt_json(resp, |item: &mut CompletionItem| {
+                    self.adapt_completion_item(item);
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Adapt `value` as `T`. Values which cannot be deserialized as `T`, including `null`, are kept
+/// unchanged.
+fn adapt_json<T>(value: &mut JsonValue, f: impl FnOnce(&mut T))
+where
+    T: serde::de::DeserializeOwned + serde::Serialize,
+{
+    if let Ok(mut v) = serde_json::from_value::<T>(value.clone()) {
+        f(&mut v);
+        *value = serde_json::to_value(v).expect("Failed to serialize");
+    }
+}
+
+/// The middleware detecting the protocol version and adapting responses for older clients.
+///
+/// See [module level documentations](self) for details.
+pub struct Downlevel<S> {
+    service: S,
+    level: ProtocolLevel,
+}
+
+define_getters!(impl[S] Downlevel<S>, service: S);
+
+impl<S: LspService<Response = JsonValue>> Service<AnyRequest> for Downlevel<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        if req.method == request::Initialize::METHOD {
+            let version = serde_json::from_value::<InitializeParams>(req.params.clone())
+                .ok()
+                .map(|params| ProtocolVersion::detect(&params));
+            self.level.set(version);
+        }
+        let method = (!self.level.supports(ProtocolVersion::LATEST)).then(|| req.method.clone());
+        ResponseFuture {
+            fut: self.service.call(req),
+            level: self.level.clone(),
+            method,
+        }
+    }
+}
+
+impl<S: LspService<Response = JsonValue>> LspService for Downlevel<S> {
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        self.service.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.service.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+pin_project! {
+    /// The [`Future`] type used by the [`Downlevel`] middleware.
+    pub struct ResponseFuture<Fut> {
+        #[pin]
+        fut: Fut,
+        level: ProtocolLevel,
+        // The request method, if the response may need adaptation.
+        method: Option<String>,
+    }
+}
+
+impl<Fut, Error> Future for ResponseFuture<Fut>
+where
+    Fut: Future<Output = Result<JsonValue, Error>>,
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let mut ret = ready!(this.fut.poll(cx));
+        if let (Some(method), Ok(resp)) = (this.method.as_deref(), &mut ret) {
+            this.level.adapt_response(method, resp);
+        }
+        Poll::Ready(ret)
+    }
+}
+
+/// A [`tower_layer::Layer`] which builds [`Downlevel`].
+#[derive(Clone, Debug, Default)]
+#[must_use]
+pub struct DownlevelLayer {
+    level: ProtocolLevel,
+}
+
+impl DownlevelLayer {
+    /// Create the layer sharing the `level` handle.
+    pub fn new(level: ProtocolLevel) -> Self {
+        Self { level }
+    }
+}
+
+impl<S> Layer<S> for DownlevelLayer {
+    type Service = Downlevel<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Downlevel {
+            service: inner,
+            level: self.level.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{poll_fn, ready};
+
+    use lsp_types::{CompletionItemLabelDetails, InsertReplaceEdit, Position, Range};
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::{ErrorCode, RequestId, ResponseError};
+
+    #[test]
+    fn detect() {
+        let detect =
+            |params: JsonValue| ProtocolVersion::detect(&serde_json::from_value(params).unwrap());
+        assert_eq!(
+            detect(json!({ "capabilities": {} })),
+            ProtocolVersion::V3_15
+        );
+        assert_eq!(
+            detect(json!({ "capabilities": {}, "locale": "en" })),
+            ProtocolVersion::V3_16
+        );
+        assert_eq!(
+            detect(json!({ "capabilities": { "general": { "positionEncodings": ["utf-16"] } } })),
+            ProtocolVersion::V3_17
+        );
+    }
+
+    #[tokio::test]
+    async fn adapt_completion() {
+        let range = |col| Range::new(Position::new(0, 0), Position::new(0, col));
+        let item = CompletionItem {
+            label: "foo".into(),
+            label_details: Some(CompletionItemLabelDetails {
+                detail: Some("()".into()),
+                description: None,
+            }),
+            text_edit: Some(CompletionTextEdit::InsertAndReplace(InsertReplaceEdit {
+                new_text: "foo".into(),
+                insert: range(1),
+                replace: range(2),
+            })),
+            ..CompletionItem::default()
+        };
+
+        let level = ProtocolLevel::new();
+        let mut router = Router::<(), ResponseError>::new(());
+        router
+            .request::<request::Initialize, _>(|_, _| ready(Ok(Default::default())))
+            .request::<request::Completion, _>(move |_, _| {
+                ready(Ok(Some(CompletionResponse::Array(vec![item.clone()]))))
+            });
+        let mut svc = DownlevelLayer::new(level.clone()).layer(router);
+        let req = |method: &str, params| AnyRequest {
+            id: RequestId::Number(0),
+            method: method.into(),
+            params,
+            receipt: None,
+        };
+
+        poll_fn(|cx| svc.poll_ready(cx)).await.unwrap();
+        svc.call(req(
+            request::Initialize::METHOD,
+            json!({ "capabilities": {} }),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(level.get(), Some(ProtocolVersion::V3_15));
+
+        let params = json!({
+            "textDocument": { "uri": "file:///foo" },
+            "position": { "line": 0, "character": 0 },
+        });
+        let resp = svc
+            .call(req(request::Completion::METHOD, params))
+            .await
+            .unwrap();
+        let items = match serde_json::from_value(resp).unwrap() {
+            Some(CompletionResponse::Array(items)) => items,
+            resp => panic!("unexpected response: {resp:?}"),
+        };
+        assert_eq!(items[0].label_details, None);
+        assert_eq!(
+            items[0].text_edit,
+            Some(CompletionTextEdit::Edit(TextEdit::new(
+                range(1),
+                "foo".into()
+            )))
+        );
+
+        let err = svc.call(req("foo", json!(null))).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::METHOD_NOT_FOUND);
+    }
+}
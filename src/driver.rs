@@ -0,0 +1,242 @@
+//! Drive main loops by external reactors.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! [`MainLoop::run`] is a future, which requires a Rust async runtime to drive it. Bindings
+//! embedding async-lsp into other languages, eg. PyO3 for Python's asyncio or napi for Node's
+//! event loop, usually prefer integrating into the host's event loop instead of spawning a
+//! separate runtime thread. [`Driver`] owns a socket-and-loop pair and exposes a poll-based
+//! interface for this purpose:
+//!
+//! 1. Register a wake callback via [`Driver::on_wake`] (or a raw [`Waker`] via
+//!    [`Driver::set_waker`]). It is called, possibly from other threads, when the main loop wants
+//!    to be polled again. It should schedule a call to [`Driver::poll`] on the host event loop,
+//!    eg. via `loop.call_soon_threadsafe` in asyncio or a threadsafe function in napi.
+//! 2. Feed incoming messages via [`Driver::push`], then [`Driver::poll`].
+//! 3. After each [`Driver::poll`], take outgoing messages via [`Driver::pull`] until it returns
+//!    `None`, and write them to the peer.
+//!
+//! Messages are complete JSON-RPC objects encoded in UTF-8, without base protocol headers.
+//!
+//! [`Driver`] is not [`Send`], since the service and its futures are polled on the host thread
+//! only. For PyO3, it can be wrapped in an `unsendable` class.
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+use futures::channel::mpsc;
+use futures::future::LocalBoxFuture;
+use futures::io::AsyncWrite;
+use futures::task::{noop_waker, ArcWake};
+use futures::{FutureExt, TryStreamExt};
+use serde_json::Value as JsonValue;
+
+use crate::codec::{parse_header_part, CONTENT_LENGTH};
+use crate::{ClientSocket, LspService, MainLoop, ResponseError, Result, ServerSocket};
+
+/// A main loop driven by an external reactor.
+///
+/// See [module level documentations](self) for details.
+pub struct Driver {
+    fut: Option<LocalBoxFuture<'static, Result<()>>>,
+    ret: Option<Result<()>>,
+    input: mpsc::UnboundedSender<io::Result<Vec<u8>>>,
+    output: Rc<RefCell<Vec<u8>>>,
+    waker: Waker,
+    // Keep the main loop alive even if the service does not hold the socket.
+    _socket: Box<dyn std::any::Any>,
+}
+
+impl fmt::Debug for Driver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Driver")
+            .field("running", &self.fut.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Driver {
+    /// Create a driver of a Language Server main loop.
+    #[must_use]
+    pub fn new_server<S>(builder: impl FnOnce(ClientSocket) -> S) -> Self
+    where
+        S: LspService<Response = JsonValue> + 'static,
+        ResponseError: From<S::Error>,
+    {
+        let (mainloop, socket) = MainLoop::new_server(builder);
+        Self::new(mainloop, Box::new(socket))
+    }
+
+    /// Create a driver of a Language Client main loop.
+    #[must_use]
+    pub fn new_client<S>(builder: impl FnOnce(ServerSocket) -> S) -> Self
+    where
+        S: LspService<Response = JsonValue> + 'static,
+        ResponseError: From<S::Error>,
+    {
+        let (mainloop, socket) = MainLoop::new_client(builder);
+        Self::new(mainloop, Box::new(socket))
+    }
+
+    fn new<S>(mainloop: MainLoop<S>, socket: Box<dyn std::any::Any>) -> Self
+    where
+        S: LspService<Response = JsonValue> + 'static,
+        ResponseError: From<S::Error>,
+    {
+        let (input, rx) = mpsc::unbounded();
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let fut = mainloop
+            .run(rx.into_async_read(), Outbox(output.clone()))
+            .boxed_local();
+        Self {
+            fut: Some(fut),
+            ret: None,
+            input,
+            output,
+            waker: noop_waker(),
+            _socket: socket,
+        }
+    }
+
+    /// Set the [`Waker`] to be woken when the main loop should be polled again.
+    ///
+    /// By default, it never wakes, and the host must poll it regularly.
+    pub fn set_waker(&mut self, waker: Waker) {
+        self.waker = waker;
+    }
+
+    /// Set the callback to be called when the main loop should be polled again.
+    ///
+    /// It may be called from any thread.
+    pub fn on_wake(&mut self, f: impl Fn() + Send + Sync + 'static) {
+        struct Callback<F>(F);
+
+        impl<F: Fn() + Send + Sync + 'static> ArcWake for Callback<F> {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                (arc_self.0)();
+            }
+        }
+
+        self.waker = futures::task::waker(Arc::new(Callback(f)));
+    }
+
+    /// Queue an incoming JSON message. Returns `false` if the main loop already exited.
+    ///
+    /// The message is processed on the next [`Driver::poll`].
+    pub fn push(&mut self, msg: &[u8]) -> bool {
+        let mut buf = format!("{CONTENT_LENGTH}: {}\r\n\r\n", msg.len()).into_bytes();
+        buf.extend_from_slice(msg);
+        self.fut.is_some() && self.input.unbounded_send(Ok(buf)).is_ok()
+    }
+
+    /// Drive the main loop until it is blocked. Returns the result if it exited.
+    pub fn poll(&mut self) -> Option<&Result<()>> {
+        if let Some(fut) = &mut self.fut {
+            let mut cx = Context::from_waker(&self.waker);
+            if let Poll::Ready(ret) = fut.poll_unpin(&mut cx) {
+                self.exit(ret);
+            }
+        }
+        self.ret.as_ref()
+    }
+
+    /// Stop the main loop with `ret`, dropping it.
+    pub(crate) fn exit(&mut self, ret: Result<()>) {
+        self.fut = None;
+        self.ret = Some(ret);
+    }
+
+    /// Get the length of the next outgoing JSON message, if any.
+    #[must_use]
+    pub fn peek_len(&self) -> Option<usize> {
+        self.peek().map(|(_, len)| len)
+    }
+
+    /// Take the next outgoing JSON message, if any.
+    pub fn pull(&mut self) -> Option<Vec<u8>> {
+        let (start, len) = self.peek()?;
+        let mut output = self.output.borrow_mut();
+        let msg = output[start..start + len].to_vec();
+        output.drain(..start + len);
+        Some(msg)
+    }
+
+    /// Get the offset and length of the next complete outgoing message.
+    fn peek(&self) -> Option<(usize, usize)> {
+        let output = self.output.borrow();
+        // Outgoing messages are always well-formed.
+        let (start, len) = parse_header_part(&output).ok()??;
+        (output.len() - start >= len).then_some((start, len))
+    }
+}
+
+/// The writer collecting outgoing bytes.
+struct Outbox(Rc<RefCell<Vec<u8>>>);
+
+impl AsyncWrite for Outbox {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use lsp_types::request::Shutdown;
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+
+    #[tokio::test]
+    async fn wake_and_pull() {
+        let mut driver = Driver::new_server(|_| {
+            let mut router = Router::new(());
+            router.request::<Shutdown, _>(|_, ()| async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Ok(())
+            });
+            router
+        });
+        let (wake_tx, mut wake_rx) = mpsc::unbounded();
+        driver.on_wake(move || {
+            let _ = wake_tx.unbounded_send(());
+        });
+
+        let req = json!({ "jsonrpc": "2.0", "id": 1, "method": "shutdown" });
+        assert!(driver.push(req.to_string().as_bytes()));
+        assert!(driver.poll().is_none());
+        assert_eq!(driver.peek_len(), None);
+
+        // Woken by the timer of the handler.
+        while driver.peek_len().is_none() {
+            wake_rx.next().await.unwrap();
+            assert!(driver.poll().is_none());
+        }
+        let len = driver.peek_len().unwrap();
+        let msg = driver.pull().unwrap();
+        assert_eq!(msg.len(), len);
+        let resp: JsonValue = serde_json::from_slice(&msg).unwrap();
+        assert_eq!(resp, json!({ "jsonrpc": "2.0", "id": 1, "result": null }));
+        assert_eq!(driver.pull(), None);
+    }
+}
@@ -0,0 +1,330 @@
+//! C-compatible interface for embedding a main loop into non-Rust hosts.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! Editors or host applications not written in Rust can embed an async-lsp based server or
+//! client core as a shared library. The library crate creates an [`LspHandle`], which owns a
+//! socket-and-loop pair, and passes it to the host via [`LspHandle::into_raw`]:
+//!
+//! ```
+//! # use async_lsp::ffi::LspHandle;
+//! # use async_lsp::router::Router;
+//! #[no_mangle]
+//! pub extern "C" fn my_server_new() -> *mut LspHandle {
+//!     LspHandle::new_server(|client| {
+//!         let mut router = Router::new(client);
+//!         // Register handlers.
+//!         router
+//!     })
+//!     .into_raw()
+//! }
+//! ```
+//!
+//! The host then exchanges messages via the exported functions below, which has a stable C ABI.
+//! Messages are complete JSON-RPC objects encoded in UTF-8, without base protocol headers.
+//!
+//! ```c
+//! typedef struct LspHandle LspHandle;
+//! int async_lsp_set_wake_callback(LspHandle *h, void (*wake)(void *), void *user_data);
+//! int async_lsp_push(LspHandle *h, const uint8_t *msg, size_t len);
+//! int async_lsp_poll(LspHandle *h);
+//! size_t async_lsp_pull(LspHandle *h, uint8_t *buf, size_t cap);
+//! int async_lsp_free(LspHandle *h);
+//! ```
+//!
+//! The main loop is not driven by any runtime, but by the host via a [`Driver`]. The host should
+//! call [`async_lsp_poll`] after pushing messages and whenever the wake callback is called, then
+//! [`async_lsp_pull`] outgoing messages until it returns zero. All functions except the wake
+//! callback are called on the host thread owning the handle.
+//!
+//! Panics never unwind across the C ABI. They are caught and reported as error codes, and the main
+//! loop exits since its state may be broken.
+use std::ffi::c_void;
+use std::os::raw::c_int;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+
+use futures::task::{noop_waker, ArcWake};
+use serde_json::Value as JsonValue;
+
+use crate::driver::Driver;
+use crate::{ClientSocket, LspService, ResponseError, ServerSocket};
+
+/// The wake callback registered by the host.
+pub type WakeFn = extern "C" fn(user_data: *mut c_void);
+
+/// An embedded main loop with its socket, exchanging messages with a non-Rust host.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+pub struct LspHandle {
+    driver: Driver,
+}
+
+impl LspHandle {
+    /// Create a handle of a Language Server main loop.
+    #[must_use]
+    pub fn new_server<S>(builder: impl FnOnce(ClientSocket) -> S) -> Self
+    where
+        S: LspService<Response = JsonValue> + 'static,
+        ResponseError: From<S::Error>,
+    {
+        Self {
+            driver: Driver::new_server(builder),
+        }
+    }
+
+    /// Create a handle of a Language Client main loop.
+    #[must_use]
+    pub fn new_client<S>(builder: impl FnOnce(ServerSocket) -> S) -> Self
+    where
+        S: LspService<Response = JsonValue> + 'static,
+        ResponseError: From<S::Error>,
+    {
+        Self {
+            driver: Driver::new_client(builder),
+        }
+    }
+
+    /// Transfer the ownership to the host. It should be released by [`async_lsp_free`].
+    #[must_use]
+    pub fn into_raw(self) -> *mut Self {
+        Box::into_raw(Box::new(self))
+    }
+}
+
+struct WakeCallback {
+    wake: WakeFn,
+    user_data: *mut c_void,
+}
+
+// SAFETY: The host guarantees the callback is safe to call from any thread.
+unsafe impl Send for WakeCallback {}
+unsafe impl Sync for WakeCallback {}
+
+impl ArcWake for WakeCallback {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        (arc_self.wake)(arc_self.user_data);
+    }
+}
+
+/// Register the callback called when the main loop should be polled again.
+///
+/// It may be called from any thread, and should only schedule a call to [`async_lsp_poll`] on
+/// the host thread. Passing a null `wake` unregisters it.
+///
+/// Returns 0 on success, or -1 if it panicked.
+///
+/// # Safety
+///
+/// `handle` must be a valid pointer from [`LspHandle::into_raw`]. `wake` must be safe to call
+/// with `user_data` from any thread, until the handle is freed.
+#[no_mangle]
+pub unsafe extern "C" fn async_lsp_set_wake_callback(
+    handle: *mut LspHandle,
+    wake: Option<WakeFn>,
+    user_data: *mut c_void,
+) -> c_int {
+    let handle = &mut *handle;
+    let ret = guard(&mut handle.driver, |driver| {
+        driver.set_waker(match wake {
+            Some(wake) => futures::task::waker(Arc::new(WakeCallback { wake, user_data })),
+            None => noop_waker(),
+        });
+    });
+    match ret {
+        Some(()) => 0,
+        None => -1,
+    }
+}
+
+/// Queue an incoming JSON message of `len` bytes at `msg`.
+///
+/// Returns 0 on success, or -1 if the main loop already exited or it panicked.
+///
+/// # Safety
+///
+/// `handle` must be a valid pointer from [`LspHandle::into_raw`]. `msg` must be valid for reads
+/// of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn async_lsp_push(
+    handle: *mut LspHandle,
+    msg: *const u8,
+    len: usize,
+) -> c_int {
+    let handle = &mut *handle;
+    let msg = if len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(msg, len)
+    };
+    match guard(&mut handle.driver, |driver| driver.push(msg)) {
+        Some(true) => 0,
+        Some(false) | None => -1,
+    }
+}
+
+/// Drive the main loop until it is blocked.
+///
+/// Returns 0 if it is still running, 1 if it exited successfully, or -1 if it exited with an error
+/// or panicked.
+///
+/// # Safety
+///
+/// `handle` must be a valid pointer from [`LspHandle::into_raw`].
+#[no_mangle]
+pub unsafe extern "C" fn async_lsp_poll(handle: *mut LspHandle) -> c_int {
+    let handle = &mut *handle;
+    match guard(&mut handle.driver, |driver| {
+        driver.poll().map(Result::is_ok)
+    }) {
+        Some(None) => 0,
+        Some(Some(true)) => 1,
+        Some(Some(false)) | None => -1,
+    }
+}
+
+/// Get the next outgoing JSON message.
+///
+/// Returns the length of the next message, or 0 if there is none. The message is copied into
+/// `buf` and consumed only if `cap` is not less than its length. Otherwise, the host should retry
+/// with a larger buffer. Returns `SIZE_MAX` if it panicked.
+///
+/// # Safety
+///
+/// `handle` must be a valid pointer from [`LspHandle::into_raw`]. `buf` must be valid for writes
+/// of `cap` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn async_lsp_pull(handle: *mut LspHandle, buf: *mut u8, cap: usize) -> usize {
+    let handle = &mut *handle;
+    let ret = guard(&mut handle.driver, |driver| {
+        let len = driver.peek_len()?;
+        if len <= cap {
+            let msg = driver.pull().expect("Peeked");
+            std::ptr::copy_nonoverlapping(msg.as_ptr(), buf, len);
+        }
+        Some(len)
+    });
+    match ret {
+        Some(len) => len.unwrap_or(0),
+        None => usize::MAX,
+    }
+}
+
+/// Release the handle, dropping the main loop and the service.
+///
+/// Returns 0 on success, or -1 if dropping panicked. The handle is released either way.
+///
+/// # Safety
+///
+/// `handle` must be null or a valid pointer from [`LspHandle::into_raw`], and must not be used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn async_lsp_free(handle: *mut LspHandle) -> c_int {
+    if handle.is_null() {
+        return 0;
+    }
+    match catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(handle)))) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Call `f` on `driver`, catching panics. The main loop exits on panics, since its state may be
+/// broken.
+fn guard<T>(driver: &mut Driver, f: impl FnOnce(&mut Driver) -> T) -> Option<T> {
+    match catch_unwind(AssertUnwindSafe(|| f(driver))) {
+        Ok(ret) => Some(ret),
+        Err(_) => {
+            driver.exit(Err(crate::Error::ServiceStopped));
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::ControlFlow;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use lsp_types::notification::Exit;
+    use lsp_types::request::Shutdown;
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn wake(_: *mut c_void) {
+        WAKES.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn push_poll_pull() {
+        let handle = LspHandle::new_server(|_| {
+            let mut router = Router::new(());
+            router
+                .request::<Shutdown, _>(|_, ()| std::future::ready(Ok(())))
+                .notification::<Exit>(|_, ()| ControlFlow::Break(Ok(())));
+            router
+        })
+        .into_raw();
+        let req = json!({ "jsonrpc": "2.0", "id": 1, "method": "shutdown" }).to_string();
+        unsafe {
+            assert_eq!(
+                async_lsp_set_wake_callback(handle, Some(wake), std::ptr::null_mut()),
+                0
+            );
+            assert_eq!(async_lsp_poll(handle), 0);
+            assert_eq!(async_lsp_pull(handle, std::ptr::null_mut(), 0), 0);
+            assert_eq!(async_lsp_push(handle, req.as_ptr(), req.len()), 0);
+            assert_eq!(async_lsp_poll(handle), 0);
+
+            let len = async_lsp_pull(handle, std::ptr::null_mut(), 0);
+            assert_ne!(len, 0);
+            let mut buf = vec![0u8; len];
+            assert_eq!(async_lsp_pull(handle, buf.as_mut_ptr(), len), len);
+            let resp: JsonValue = serde_json::from_slice(&buf).unwrap();
+            assert_eq!(resp, json!({ "jsonrpc": "2.0", "id": 1, "result": null }));
+            assert_eq!(async_lsp_pull(handle, buf.as_mut_ptr(), len), 0);
+
+            let exit = json!({ "jsonrpc": "2.0", "method": "exit" }).to_string();
+            assert_eq!(async_lsp_push(handle, exit.as_ptr(), exit.len()), 0);
+            assert_eq!(async_lsp_poll(handle), 1);
+            assert_eq!(async_lsp_push(handle, exit.as_ptr(), exit.len()), -1);
+            assert_eq!(async_lsp_free(handle), 0);
+        }
+    }
+
+    #[test]
+    fn catch_panics() {
+        let handle = LspHandle::new_server(|_| {
+            let mut router = Router::new(());
+            router.notification::<Exit>(|_, ()| panic!("exit"));
+            router
+        })
+        .into_raw();
+        let exit = json!({ "jsonrpc": "2.0", "method": "exit" }).to_string();
+        unsafe {
+            assert_eq!(async_lsp_push(handle, exit.as_ptr(), exit.len()), 0);
+            assert_eq!(async_lsp_poll(handle), -1);
+            assert_eq!(async_lsp_push(handle, exit.as_ptr(), exit.len()), -1);
+            assert_eq!(async_lsp_pull(handle, std::ptr::null_mut(), 0), 0);
+            assert_eq!(async_lsp_free(handle), 0);
+        }
+
+        struct PanicOnDrop;
+
+        impl Drop for PanicOnDrop {
+            fn drop(&mut self) {
+                panic!("dropped");
+            }
+        }
+
+        let handle = LspHandle::new_server(|_| Router::new(PanicOnDrop)).into_raw();
+        unsafe {
+            assert_eq!(async_lsp_free(handle), -1);
+        }
+    }
+}
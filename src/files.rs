@@ -0,0 +1,567 @@
+//! File watching via `workspace/didChangeWatchedFiles`.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Servers usually need to know about changes of files not opened in the editor, eg. build
+//! configurations or generated sources. [`FileWatcher`] registers watchers of glob patterns
+//! dynamically via `client/registerCapability`, when the editor supports dynamic registration of
+//! `workspace/didChangeWatchedFiles`. Otherwise, it falls back to a [`NativeWatcher`] if set, eg.
+//! one implemented with the [`notify`](https://docs.rs/notify) crate. Watchers can be matched
+//! against changes via [`watcher_matches`], since native watchers usually watch whole
+//! directories.
+//!
+//! Either way, changes are delivered as [`FilesChanged`] loopback events into the service, which
+//! can be handled via [`Router::event`]. [`FileWatcher::register`] turns
+//! `workspace/didChangeWatchedFiles` notifications from the editor into the same event, so the
+//! server handles a single stream of file changes regardless of the source.
+//!
+//! ```no_run
+//! # use async_lsp::files::{FilesChanged, FileWatcher};
+//! # use async_lsp::lsp_types::{ClientCapabilities, FileSystemWatcher, GlobPattern};
+//! # use async_lsp::router::Router;
+//! # use std::ops::ControlFlow;
+//! # async fn work(client: async_lsp::ClientSocket, caps: ClientCapabilities, router: &mut Router<()>) {
+//! let watcher = FileWatcher::new(client, &caps);
+//! watcher.register(router);
+//! router.event::<FilesChanged>(|_, event| {
+//!     println!("Changed: {:?}", event.changes);
+//!     ControlFlow::Continue(())
+//! });
+//! // After initialization.
+//! watcher
+//!     .watch(
+//!         "cargo",
+//!         vec![FileSystemWatcher {
+//!             glob_pattern: GlobPattern::String("**/Cargo.toml".into()),
+//!             kind: None,
+//!         }],
+//!     )
+//!     .await
+//!     .unwrap();
+//! # }
+//! ```
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io;
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex};
+
+use lsp_types::notification::{DidChangeWatchedFiles, Notification};
+use lsp_types::request::{RegisterCapability, UnregisterCapability};
+use lsp_types::{
+    ClientCapabilities, DidChangeWatchedFilesRegistrationOptions, FileChangeType, FileEvent,
+    FileSystemWatcher, GlobPattern, OneOf, Registration, RegistrationParams, Unregistration,
+    UnregistrationParams, Url, WatchKind,
+};
+
+use crate::router::Router;
+use crate::workspace::{relative_path, segments};
+use crate::{ClientSocket, ResponseError};
+
+/// The loopback event of file changes, either from the editor or a [`NativeWatcher`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FilesChanged {
+    /// The changes.
+    pub changes: Vec<FileEvent>,
+}
+
+/// Errors of [`FileWatcher`].
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum WatchError {
+    /// The editor does not support dynamic registration of `workspace/didChangeWatchedFiles`,
+    /// and no [`NativeWatcher`] is set.
+    #[error("file watching is not supported")]
+    Unsupported,
+    /// The [`NativeWatcher`] fails.
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    /// The registration request fails.
+    #[error("{0}")]
+    Request(#[from] crate::Error),
+}
+
+/// The fallback watcher of the local file system, used when the editor does not support
+/// dynamic registration of `workspace/didChangeWatchedFiles`.
+///
+/// See [module level documentations](self) for details.
+pub trait NativeWatcher: Send {
+    /// Start watching `watchers` identified by `id`, and report changes via `sink`.
+    ///
+    /// # Errors
+    ///
+    /// Errors from the underlying watcher.
+    fn watch(
+        &mut self,
+        id: &str,
+        watchers: &[FileSystemWatcher],
+        sink: FileEventSink,
+    ) -> io::Result<()>;
+
+    /// Stop watching `id`.
+    fn unwatch(&mut self, id: &str);
+}
+
+/// The sink of changes detected by a [`NativeWatcher`].
+#[derive(Debug, Clone)]
+pub struct FileEventSink(ClientSocket);
+
+impl FileEventSink {
+    /// Emit `changes` as a [`FilesChanged`] event. Empty changes are ignored.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::ServiceStopped`][crate::Error::ServiceStopped] when the service main loop
+    ///   stopped, in which case the watcher should stop.
+    pub fn send(&self, changes: Vec<FileEvent>) -> crate::Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        self.0.emit(FilesChanged { changes })
+    }
+}
+
+/// The cheaply cloneable handle registering file watchers.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone)]
+#[must_use]
+pub struct FileWatcher {
+    client: ClientSocket,
+    dynamic: bool,
+    native: Option<Arc<Mutex<dyn NativeWatcher>>>,
+    ids: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl fmt::Debug for FileWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileWatcher")
+            .field("dynamic", &self.dynamic)
+            .field("native", &self.native.is_some())
+            .field("ids", &self.ids.lock().unwrap())
+            .finish_non_exhaustive()
+    }
+}
+
+impl FileWatcher {
+    /// Create a watcher registering via `client`, which has `capabilities` from
+    /// `initialize` parameters.
+    pub fn new(client: ClientSocket, capabilities: &ClientCapabilities) -> Self {
+        let dynamic = capabilities
+            .workspace
+            .as_ref()
+            .and_then(|caps| caps.did_change_watched_files.as_ref())
+            .and_then(|caps| caps.dynamic_registration)
+            .unwrap_or(false);
+        Self {
+            client,
+            dynamic,
+            native: None,
+            ids: Arc::default(),
+        }
+    }
+
+    /// Set the fallback watcher when the editor does not support dynamic registration. Default
+    /// is none, and watching fails with [`WatchError::Unsupported`] in that case.
+    pub fn native(mut self, watcher: impl NativeWatcher + 'static) -> Self {
+        self.native = Some(Arc::new(Mutex::new(watcher)));
+        self
+    }
+
+    /// Check if watchers are registered to the editor, rather than the [`NativeWatcher`].
+    #[must_use]
+    pub fn is_dynamic(&self) -> bool {
+        self.dynamic
+    }
+
+    /// Register a handler of `workspace/didChangeWatchedFiles` to `router`, which emits the
+    /// changes as a [`FilesChanged`] event.
+    pub fn register<St, Error>(&self, router: &mut Router<St, Error>)
+    where
+        Error: From<ResponseError> + Send + 'static,
+    {
+        let client = self.client.clone();
+        router.notification::<DidChangeWatchedFiles>(move |_, params| {
+            match FileEventSink(client.clone()).send(params.changes) {
+                Ok(()) => ControlFlow::Continue(()),
+                Err(err) => ControlFlow::Break(Err(err)),
+            }
+        });
+    }
+
+    /// Watch `watchers` identified by `id`, replacing the previous ones with the same `id`.
+    ///
+    /// # Errors
+    ///
+    /// - [`WatchError::Unsupported`] if neither the editor nor a [`NativeWatcher`] can watch.
+    /// - [`WatchError::Io`] if the [`NativeWatcher`] fails.
+    /// - [`WatchError::Request`] if the registration request fails.
+    pub async fn watch(
+        &self,
+        id: impl Into<String>,
+        watchers: Vec<FileSystemWatcher>,
+    ) -> Result<(), WatchError> {
+        let id = id.into();
+        if self.ids.lock().unwrap().contains(&id) {
+            self.unwatch(&id).await?;
+        }
+        if self.dynamic {
+            let options = DidChangeWatchedFilesRegistrationOptions { watchers };
+            self.client
+                .request::<RegisterCapability>(RegistrationParams {
+                    registrations: vec![Registration {
+                        id: id.clone(),
+                        method: DidChangeWatchedFiles::METHOD.into(),
+                        register_options: Some(
+                            serde_json::to_value(options).expect("Failed to serialize"),
+                        ),
+                    }],
+                })
+                .await?;
+        } else {
+            let native = self.native.as_ref().ok_or(WatchError::Unsupported)?;
+            let sink = FileEventSink(self.client.clone());
+            native.lock().unwrap().watch(&id, &watchers, sink)?;
+        }
+        self.ids.lock().unwrap().insert(id);
+        Ok(())
+    }
+
+    /// Stop watching `id`. It does nothing if `id` is not watched.
+    ///
+    /// # Errors
+    ///
+    /// - [`WatchError::Request`] if the unregistration request fails.
+    pub async fn unwatch(&self, id: &str) -> Result<(), WatchError> {
+        if !self.ids.lock().unwrap().remove(id) {
+            return Ok(());
+        }
+        if self.dynamic {
+            self.client
+                .request::<UnregisterCapability>(UnregistrationParams {
+                    unregisterations: vec![Unregistration {
+                        id: id.into(),
+                        method: DidChangeWatchedFiles::METHOD.into(),
+                    }],
+                })
+                .await?;
+        } else if let Some(native) = &self.native {
+            native.lock().unwrap().unwatch(id);
+        }
+        Ok(())
+    }
+}
+
+/// Check if a change of `typ` on `uri` is of interest to `watcher`, by its glob pattern and kind.
+///
+/// Glob patterns are matched against percent-decoded paths, with the syntax of the LSP
+/// specification: `*`, `?`, `**`, `{a,b}`, `[0-9]` and `[!0-9]`. String patterns are matched
+/// against the whole path, and relative patterns against the path relative to their bases.
+#[must_use]
+pub fn watcher_matches(watcher: &FileSystemWatcher, uri: &Url, typ: FileChangeType) -> bool {
+    let kind = match typ {
+        FileChangeType::CREATED => WatchKind::Create,
+        FileChangeType::CHANGED => WatchKind::Change,
+        FileChangeType::DELETED => WatchKind::Delete,
+        _ => return false,
+    };
+    if !watcher.kind.unwrap_or(WatchKind::all()).contains(kind) {
+        return false;
+    }
+    let (pattern, path) = match &watcher.glob_pattern {
+        GlobPattern::String(pattern) => (pattern, segments(uri).join("/")),
+        GlobPattern::Relative(rel) => {
+            let base = match &rel.base_uri {
+                OneOf::Left(folder) => &folder.uri,
+                OneOf::Right(base) => base,
+            };
+            match relative_path(base, uri) {
+                Some(path) => (&rel.pattern, path),
+                None => return false,
+            }
+        }
+    };
+    let path = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    expand_braces(pattern).iter().any(|pattern| {
+        let pattern = pattern
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+        match_segments(&pattern, &path)
+    })
+}
+
+/// Expand `{a,b}` groups into alternatives. Unbalanced braces are kept literally.
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let open = match pattern.find('{') {
+        Some(open) => open,
+        None => return vec![pattern.into()],
+    };
+    let mut depth = 0;
+    let mut alts = Vec::new();
+    let mut start = open + 1;
+    for (i, c) in pattern.char_indices().skip_while(|&(i, _)| i <= open) {
+        match c {
+            '{' => depth += 1,
+            '}' if depth > 0 => depth -= 1,
+            ',' if depth == 0 => {
+                alts.push(&pattern[start..i]);
+                start = i + 1;
+            }
+            '}' => {
+                alts.push(&pattern[start..i]);
+                let (prefix, suffix) = (&pattern[..open], &pattern[i + 1..]);
+                return alts
+                    .into_iter()
+                    .flat_map(|alt| expand_braces(&format!("{prefix}{alt}{suffix}")))
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+    vec![pattern.into()]
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| match_segments(rest, &path[skip..])),
+        Some((seg, rest)) => match path.split_first() {
+            Some((name, path)) => {
+                let (seg, name) = (
+                    seg.chars().collect::<Vec<_>>(),
+                    name.chars().collect::<Vec<_>>(),
+                );
+                match_segment(&seg, &name) && match_segments(rest, path)
+            }
+            None => false,
+        },
+    }
+}
+
+fn match_segment(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| match_segment(rest, &name[skip..])),
+        Some(('?', rest)) => !name.is_empty() && match_segment(rest, &name[1..]),
+        Some(('[', rest)) => {
+            let close = match rest.iter().skip(1).position(|&c| c == ']') {
+                Some(pos) => pos + 1,
+                None => return name.first() == Some(&'[') && match_segment(rest, &name[1..]),
+            };
+            let c = match name.first() {
+                Some(&c) => c,
+                None => return false,
+            };
+            let (negated, set) = match rest[..close].split_first() {
+                Some(('!', set)) => (true, set),
+                _ => (false, &rest[..close]),
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < set.len() {
+                if i + 2 < set.len() && set[i + 1] == '-' {
+                    found |= set[i] <= c && c <= set[i + 2];
+                    i += 3;
+                } else {
+                    found |= set[i] == c;
+                    i += 1;
+                }
+            }
+            found != negated && match_segment(&rest[close + 1..], &name[1..])
+        }
+        Some((&p, rest)) => name.first() == Some(&p) && match_segment(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use lsp_types::request::Request;
+    use lsp_types::{
+        DidChangeWatchedFilesClientCapabilities, RelativePattern, WorkspaceClientCapabilities,
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::{AnyNotification, AnyResponse, LspService, MainLoopEvent, PeerSocket};
+
+    #[test]
+    fn glob() {
+        let uri = Url::parse("file:///home/me/proj/src/a%20b.rs").unwrap();
+        let watcher = |pattern: &str, kind| FileSystemWatcher {
+            glob_pattern: GlobPattern::String(pattern.into()),
+            kind,
+        };
+        let matches =
+            |pattern| watcher_matches(&watcher(pattern, None), &uri, FileChangeType::CHANGED);
+        assert!(matches("**/*.rs"));
+        assert!(matches("**/*.{ts,rs}"));
+        assert!(matches("/home/**/src/a b.rs"));
+        assert!(matches("**/src/?[ !]b.rs"));
+        assert!(matches("**/[a-c]*"));
+        assert!(!matches("**/[!a-c]*"));
+        assert!(!matches("*.rs"));
+        assert!(!matches("**/*.toml"));
+        assert!(!watcher_matches(
+            &watcher("**", Some(WatchKind::Create | WatchKind::Delete)),
+            &uri,
+            FileChangeType::CHANGED,
+        ));
+
+        let relative = |base: &str, pattern: &str| FileSystemWatcher {
+            glob_pattern: GlobPattern::Relative(RelativePattern {
+                base_uri: OneOf::Right(Url::parse(base).unwrap()),
+                pattern: pattern.into(),
+            }),
+            kind: None,
+        };
+        let created = FileChangeType::CREATED;
+        assert!(watcher_matches(
+            &relative("file:///home/me/proj", "src/*.rs"),
+            &uri,
+            created
+        ));
+        assert!(!watcher_matches(
+            &relative("file:///home/me/proj", "*.rs"),
+            &uri,
+            created
+        ));
+        assert!(!watcher_matches(
+            &relative("file:///other", "**"),
+            &uri,
+            created
+        ));
+    }
+
+    #[derive(Default)]
+    struct MockWatcher(Arc<Mutex<Vec<String>>>);
+
+    impl NativeWatcher for MockWatcher {
+        fn watch(
+            &mut self,
+            id: &str,
+            _watchers: &[FileSystemWatcher],
+            sink: FileEventSink,
+        ) -> io::Result<()> {
+            self.0.lock().unwrap().push(format!("watch {id}"));
+            let uri = Url::parse("file:///foo").unwrap();
+            sink.send(vec![FileEvent::new(uri, FileChangeType::CREATED)])
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+        }
+
+        fn unwatch(&mut self, id: &str) {
+            self.0.lock().unwrap().push(format!("unwatch {id}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn native() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let client = ClientSocket(PeerSocket { tx });
+        let log = Arc::default();
+        let watcher = FileWatcher::new(client, &ClientCapabilities::default())
+            .native(MockWatcher(Arc::clone(&log)));
+        assert!(!watcher.is_dynamic());
+
+        watcher.watch("foo", Vec::new()).await.unwrap();
+        watcher.watch("foo", Vec::new()).await.unwrap();
+        watcher.unwatch("foo").await.unwrap();
+        watcher.unwatch("foo").await.unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["watch foo", "unwatch foo", "watch foo", "unwatch foo"],
+        );
+        for _ in 0..2 {
+            match rx.next().await.unwrap() {
+                MainLoopEvent::Any(event) => {
+                    let event = event.downcast::<FilesChanged>().unwrap();
+                    assert_eq!(event.changes[0].typ, FileChangeType::CREATED);
+                }
+                _ => panic!("unexpected event"),
+            }
+        }
+
+        let watcher = FileWatcher::new(watcher.client, &ClientCapabilities::default());
+        assert!(matches!(
+            watcher.watch("foo", Vec::new()).await,
+            Err(WatchError::Unsupported)
+        ));
+    }
+
+    #[tokio::test]
+    async fn dynamic() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let client = ClientSocket(PeerSocket { tx });
+        let caps = ClientCapabilities {
+            workspace: Some(WorkspaceClientCapabilities {
+                did_change_watched_files: Some(DidChangeWatchedFilesClientCapabilities {
+                    dynamic_registration: Some(true),
+                    relative_pattern_support: None,
+                }),
+                ..WorkspaceClientCapabilities::default()
+            }),
+            ..ClientCapabilities::default()
+        };
+        let watcher = FileWatcher::new(client.clone(), &caps);
+        assert!(watcher.is_dynamic());
+
+        let editor = tokio::spawn(async move {
+            let mut methods = Vec::new();
+            while let Some(event) = rx.next().await {
+                match event {
+                    MainLoopEvent::OutgoingRequest(req, resp_tx) => {
+                        methods.push((req.method, req.params));
+                        resp_tx
+                            .send(AnyResponse {
+                                id: req.id,
+                                result: Some(json!(null)),
+                                error: None,
+                                raw: None,
+                            })
+                            .unwrap();
+                    }
+                    MainLoopEvent::Any(event) => {
+                        let event = event.downcast::<FilesChanged>().unwrap();
+                        methods.push(("event".into(), json!(event.changes)));
+                    }
+                    _ => panic!("unexpected event"),
+                }
+            }
+            methods
+        });
+
+        let fs_watcher = FileSystemWatcher {
+            glob_pattern: GlobPattern::String("**/*.rs".into()),
+            kind: None,
+        };
+        watcher.watch("rs", vec![fs_watcher]).await.unwrap();
+        watcher.unwatch("rs").await.unwrap();
+
+        let mut router = Router::new(());
+        watcher.register(&mut router);
+        let changes = json!([{ "uri": "file:///foo", "type": 2 }]);
+        let ret = router.notify(AnyNotification {
+            method: DidChangeWatchedFiles::METHOD.into(),
+            params: json!({ "changes": changes }),
+            receipt: None,
+        });
+        assert!(ret.is_continue());
+        drop((watcher, router, client));
+
+        let methods = editor.await.unwrap();
+        assert_eq!(methods[0].0, RegisterCapability::METHOD);
+        assert_eq!(
+            methods[0].1["registrations"][0]["registerOptions"]["watchers"][0]["globPattern"],
+            "**/*.rs",
+        );
+        assert_eq!(methods[1].0, UnregisterCapability::METHOD);
+        assert_eq!(methods[1].1["unregisterations"][0]["id"], "rs");
+        assert_eq!(methods[2], ("event".into(), changes));
+    }
+}
@@ -0,0 +1,186 @@
+//! Live-tunable feature flags for middlewares.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! [`FeatureFlags`] is a cheaply cloneable shared handle of settings, consulted by built-in
+//! middlewares and helpers on each use, so that their behavior can be tuned at runtime without
+//! reconnecting:
+//! - [`FeatureFlags::max_concurrency`] overrides the limit of
+//!   [`Concurrency`](crate::concurrency::Concurrency).
+//! - [`FeatureFlags::debounce`] overrides the debouncing duration of
+//!   [`DocumentPipeline`](crate::pipeline::DocumentPipeline).
+//! - [`FeatureFlags::tracing`] toggles spans of [`Tracing`](crate::tracing::Tracing).
+//!
+//! Flags are typically updated from a `workspace/didChangeConfiguration` handler via
+//! [`FeatureFlags::update`]. Arbitrary user-defined flags are also stored and can be queried via
+//! [`FeatureFlags::get`].
+//!
+//! ```
+//! # use async_lsp::flags::FeatureFlags;
+//! # use async_lsp::lsp_types::notification::DidChangeConfiguration;
+//! # use async_lsp::router::Router;
+//! # use std::ops::ControlFlow;
+//! # fn work(router: &mut Router<()>) {
+//! let flags = FeatureFlags::new();
+//! router.notification::<DidChangeConfiguration>(move |_, params| {
+//!     // Eg. `{ "myServer": { "maxConcurrency": 2, "debounceMs": 100, "tracing": false } }`
+//!     flags.update(&params.settings["myServer"]);
+//!     ControlFlow::Continue(())
+//! });
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde_json::Value as JsonValue;
+
+/// The shared handle of live-tunable feature flags.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone, Default)]
+pub struct FeatureFlags {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Zero for unset.
+    max_concurrency: AtomicUsize,
+    tracing_disabled: AtomicBool,
+    debounce: RwLock<Option<Duration>>,
+    values: RwLock<HashMap<String, JsonValue>>,
+}
+
+impl FeatureFlags {
+    /// Create a handle with no flags set.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the overridden concurrency limit, if set.
+    #[must_use]
+    pub fn max_concurrency(&self) -> Option<NonZeroUsize> {
+        NonZeroUsize::new(self.inner.max_concurrency.load(Ordering::Relaxed))
+    }
+
+    /// Override or reset the concurrency limit.
+    pub fn set_max_concurrency(&self, limit: Option<NonZeroUsize>) {
+        let limit = limit.map_or(0, NonZeroUsize::get);
+        self.inner.max_concurrency.store(limit, Ordering::Relaxed);
+    }
+
+    /// Get the overridden debouncing duration, if set.
+    #[must_use]
+    pub fn debounce(&self) -> Option<Duration> {
+        *self.inner.debounce.read().unwrap()
+    }
+
+    /// Override or reset the debouncing duration.
+    pub fn set_debounce(&self, duration: Option<Duration>) {
+        *self.inner.debounce.write().unwrap() = duration;
+    }
+
+    /// Whether tracing spans are enabled. Default is `true`.
+    #[must_use]
+    pub fn tracing(&self) -> bool {
+        !self.inner.tracing_disabled.load(Ordering::Relaxed)
+    }
+
+    /// Enable or disable tracing spans.
+    pub fn set_tracing(&self, enabled: bool) {
+        self.inner
+            .tracing_disabled
+            .store(!enabled, Ordering::Relaxed);
+    }
+
+    /// Get a user-defined flag, or `None` if it is unset or fails to deserialize into `T`.
+    #[must_use]
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let values = self.inner.values.read().unwrap();
+        T::deserialize(values.get(key)?).ok()
+    }
+
+    /// Set a user-defined flag. Setting `null` unsets it.
+    pub fn set(&self, key: impl Into<String>, value: JsonValue) {
+        let mut values = self.inner.values.write().unwrap();
+        if value.is_null() {
+            values.remove(&key.into());
+        } else {
+            values.insert(key.into(), value);
+        }
+    }
+
+    /// Update flags from a settings object.
+    ///
+    /// Built-in flags are read from keys `maxConcurrency` (integer), `debounceMs` (integer) and
+    /// `tracing` (boolean). A `null` value resets the flag. Keys absent or of unexpected types are
+    /// left unchanged. All keys are also stored as user-defined flags. Non-object settings are
+    /// ignored.
+    pub fn update(&self, settings: &JsonValue) {
+        let obj = match settings.as_object() {
+            Some(obj) => obj,
+            None => return,
+        };
+        match obj.get("maxConcurrency") {
+            Some(JsonValue::Null) => self.set_max_concurrency(None),
+            Some(v) => {
+                if let Some(n) = v.as_u64().and_then(|n| NonZeroUsize::new(n as usize)) {
+                    self.set_max_concurrency(Some(n));
+                }
+            }
+            None => {}
+        }
+        match obj.get("debounceMs") {
+            Some(JsonValue::Null) => self.set_debounce(None),
+            Some(v) => {
+                if let Some(ms) = v.as_u64() {
+                    self.set_debounce(Some(Duration::from_millis(ms)));
+                }
+            }
+            None => {}
+        }
+        if let Some(enabled) = obj.get("tracing").and_then(JsonValue::as_bool) {
+            self.set_tracing(enabled);
+        }
+        for (key, value) in obj {
+            self.set(key.clone(), value.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn update() {
+        let flags = FeatureFlags::new();
+        assert_eq!(flags.max_concurrency(), None);
+        assert!(flags.tracing());
+
+        flags.update(&json!({
+            "maxConcurrency": 3,
+            "debounceMs": 100,
+            "tracing": false,
+            "custom": "foo",
+        }));
+        let flags2 = flags.clone();
+        assert_eq!(flags2.max_concurrency(), NonZeroUsize::new(3));
+        assert_eq!(flags2.debounce(), Some(Duration::from_millis(100)));
+        assert!(!flags2.tracing());
+        assert_eq!(flags2.get::<String>("custom").as_deref(), Some("foo"));
+        assert_eq!(flags2.get::<i32>("custom"), None);
+
+        flags.update(&json!({ "maxConcurrency": null, "debounceMs": "bad", "custom": null }));
+        assert_eq!(flags2.max_concurrency(), None);
+        assert_eq!(flags2.debounce(), Some(Duration::from_millis(100)));
+        assert_eq!(flags2.get::<String>("custom"), None);
+    }
+}
@@ -37,7 +37,7 @@ impl Future for PeerSocketResponseFuture {
 impl PeerSocket {
     fn on_call(&mut self, req: AnyRequest) -> PeerSocketResponseFuture {
         let (tx, rx) = oneshot::channel();
-        let _: Result<_, _> = self.send(MainLoopEvent::OutgoingRequest(req, tx));
+        let _: Result<_, _> = self.send(MainLoopEvent::ForwardRequest(req, tx));
         PeerSocketResponseFuture { rx }
     }
 
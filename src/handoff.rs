@@ -0,0 +1,420 @@
+//! Experimental hand-off of a connection to a new version of the Language Server.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! This allows a server to update itself without the editor noticing a restart. The old process
+//! spawns the new version with [`HandoffSender::spawn`], which inherits a Unix domain socket for
+//! coordination. The old process then sends an [`asyncLsp/handoff`](Handoff) request over it,
+//! carrying a serialized summary of its state, and the file descriptors of the editor connection
+//! passed via `SCM_RIGHTS`. The new process picks it up with [`HandoffReceiver::from_env`],
+//! restores its state, and either [accepts](PendingHandoff::accept) to take over the connection,
+//! or [rejects](PendingHandoff::reject) it, eg. due to an incompatible state, in which case the old
+//! process keeps serving.
+//!
+//! The old process should stop reading from the connection before handing it off. Bytes already
+//! read but not processed, eg. left in the buffer of [`MainLoop::run_session`] input, should be
+//! sent in [`HandoffParams::buffered`]. Requests still in flight in the old process are dropped,
+//! so it should hand off when idle. After the hand-off, the old process can wait for the new one
+//! and exit with its status, so the process watched by the editor stays alive.
+//!
+//! All operations here are blocking, since they happen once outside of the main loop.
+//!
+//! ```no_run
+//! # use async_lsp::handoff::{HandoffParams, HandoffReceiver, HandoffSender};
+//! # use std::os::unix::io::AsFd;
+//! # use std::process::Command;
+//! # fn work() -> Result<(), Box<dyn std::error::Error>> {
+//! // In the new process, on startup.
+//! if let Some(receiver) = HandoffReceiver::from_env()? {
+//!     let handoff = receiver.recv()?;
+//!     // Restore states from `handoff.params` and serve on `handoff.fds`.
+//!     handoff.accept()?;
+//! }
+//!
+//! // In the old process, once it is idle and stopped reading.
+//! # let state = serde_json::Value::Null;
+//! let (mut sender, mut child) = HandoffSender::spawn(&mut Command::new(std::env::current_exe()?))?;
+//! let params = HandoffParams::new(env!("CARGO_PKG_VERSION"), state);
+//! let (stdin, stdout) = (std::io::stdin(), std::io::stdout());
+//! sender.send(&params, &[stdin.as_fd(), stdout.as_fd()])?;
+//! let status = child.wait()?;
+//! std::process::exit(status.code().unwrap_or(1));
+//! # }
+//! ```
+//!
+//! [`MainLoop::run_session`]: crate::MainLoop::run_session
+use std::io::{self, IoSlice, IoSliceMut, Write};
+use std::os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::process::{Child, Command};
+use std::time::Duration;
+
+use lsp_types::request::Request;
+use rustix::io::{fcntl_getfd, fcntl_setfd, FdFlags};
+use rustix::net::{
+    recvmsg, sendmsg, RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags, SendAncillaryBuffer,
+    SendAncillaryMessage, SendFlags,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+use crate::{AnyRequest, AnyResponse, ErrorCode, Message, RawMessage, RequestId, ResponseError};
+
+/// The environment variable of the inherited coordination socket in the new process.
+pub const HANDOFF_FD_ENV: &str = "ASYNC_LSP_HANDOFF_FD";
+
+/// The maximum number of file descriptors passed in a hand-off.
+pub const MAX_FDS: usize = 8;
+
+/// The request sent from the old process to the new one, over the coordination socket.
+#[derive(Debug)]
+pub enum Handoff {}
+
+impl Request for Handoff {
+    type Params = HandoffParams;
+    type Result = ();
+    const METHOD: &'static str = "asyncLsp/handoff";
+}
+
+/// The parameters of [`Handoff`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[non_exhaustive]
+pub struct HandoffParams {
+    /// The version of the old process, eg. `CARGO_PKG_VERSION`.
+    pub version: String,
+    /// The serialized state summary.
+    pub state: JsonValue,
+    /// Bytes read from the connection but not processed by the old process.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub buffered: Vec<u8>,
+}
+
+impl HandoffParams {
+    /// Create parameters with `version` of the old process and its `state`.
+    #[must_use]
+    pub fn new(version: impl Into<String>, state: JsonValue) -> Self {
+        Self {
+            version: version.into(),
+            state,
+            buffered: Vec::new(),
+        }
+    }
+}
+
+/// Errors of hand-off.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum HandoffError {
+    /// The coordination socket fails.
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    /// The peer sends an invalid message.
+    #[error("invalid handoff message: {0}")]
+    Protocol(String),
+    /// The new process rejects the hand-off.
+    #[error("handoff is rejected: {0}")]
+    Rejected(ResponseError),
+}
+
+/// The old side of a hand-off.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+pub struct HandoffSender {
+    stream: UnixStream,
+}
+
+impl HandoffSender {
+    /// Create from a connected coordination socket.
+    #[must_use]
+    pub fn new(stream: UnixStream) -> Self {
+        Self { stream }
+    }
+
+    /// Spawn the new process via `command`, which inherits the other end of the coordination
+    /// socket, and is told about it via [`HANDOFF_FD_ENV`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if the socket cannot be created, or the process cannot be spawned.
+    pub fn spawn(command: &mut Command) -> io::Result<(Self, Child)> {
+        let (ours, theirs) = UnixStream::pair()?;
+        // Let it survive `exec`. It is closed here right after spawning.
+        fcntl_setfd(&theirs, FdFlags::empty())?;
+        command.env(HANDOFF_FD_ENV, theirs.as_raw_fd().to_string());
+        let child = command.spawn()?;
+        drop(theirs);
+        Ok((Self::new(ours), child))
+    }
+
+    /// Set the timeout of sending the request and waiting for the response. Default is none.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the timeout is zero.
+    pub fn timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.set_write_timeout(timeout)
+    }
+
+    /// Send `params` and `fds` to the new process and wait for its decision.
+    ///
+    /// On success, the new process owns duplicates of `fds`, and the old process should stop
+    /// using them.
+    ///
+    /// # Errors
+    ///
+    /// - [`HandoffError::Rejected`] if the new process rejects it, in which case the old process
+    ///   should keep serving.
+    /// - [`HandoffError::Io`] or [`HandoffError::Protocol`] if the coordination fails, eg. the
+    ///   new process exits before responding.
+    pub fn send(
+        &mut self,
+        params: &HandoffParams,
+        fds: &[BorrowedFd<'_>],
+    ) -> Result<(), HandoffError> {
+        if fds.len() > MAX_FDS {
+            return Err(HandoffError::Protocol(format!(
+                "too many fds: {}",
+                fds.len()
+            )));
+        }
+        let req = Message::Request(AnyRequest {
+            id: RequestId::Number(0),
+            method: Handoff::METHOD.into(),
+            params: serde_json::to_value(params).expect("Failed to serialize"),
+            receipt: None,
+        });
+        write_frame(&mut self.stream, &req, fds)?;
+        let (msg, _) = read_frame(&self.stream)?;
+        match msg {
+            Message::Response(AnyResponse {
+                error: Some(err), ..
+            }) => Err(HandoffError::Rejected(err)),
+            Message::Response(_) => Ok(()),
+            _ => Err(HandoffError::Protocol("expecting a response".into())),
+        }
+    }
+}
+
+/// The new side of a hand-off, before receiving the request.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+pub struct HandoffReceiver {
+    stream: UnixStream,
+}
+
+impl HandoffReceiver {
+    /// Create from a connected coordination socket.
+    #[must_use]
+    pub fn new(stream: UnixStream) -> Self {
+        Self { stream }
+    }
+
+    /// Take the coordination socket inherited from [`HandoffSender::spawn`], if any.
+    ///
+    /// The environment variable is removed, so it is not inherited by further child processes.
+    ///
+    /// # Errors
+    ///
+    /// Fails if [`HANDOFF_FD_ENV`] is set but not an open file descriptor.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let fd = match std::env::var(HANDOFF_FD_ENV) {
+            Ok(fd) => fd,
+            Err(_) => return Ok(None),
+        };
+        std::env::remove_var(HANDOFF_FD_ENV);
+        let fd = fd
+            .parse::<RawFd>()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        // SAFETY: It is only used to check validity.
+        fcntl_getfd(unsafe { BorrowedFd::borrow_raw(fd) })?;
+        // SAFETY: The fd is open, and inherited solely for us by `HandoffSender::spawn`.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        fcntl_setfd(&fd, FdFlags::CLOEXEC)?;
+        Ok(Some(Self::new(UnixStream::from(fd))))
+    }
+
+    /// Wait for the [`Handoff`] request.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the coordination socket fails, or the request is invalid.
+    pub fn recv(self) -> Result<PendingHandoff, HandoffError> {
+        let (msg, fds) = read_frame(&self.stream)?;
+        let req = match msg {
+            Message::Request(req) if req.method == Handoff::METHOD => req,
+            _ => return Err(HandoffError::Protocol("expecting a handoff request".into())),
+        };
+        let params = serde_json::from_value(req.params)
+            .map_err(|err| HandoffError::Protocol(err.to_string()))?;
+        Ok(PendingHandoff {
+            params,
+            fds,
+            stream: self.stream,
+            id: req.id,
+        })
+    }
+}
+
+/// A received [`Handoff`] request waiting for the decision.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct PendingHandoff {
+    /// The parameters from the old process.
+    pub params: HandoffParams,
+    /// The passed file descriptors of the connection, in the order sent.
+    pub fds: Vec<OwnedFd>,
+    stream: UnixStream,
+    id: RequestId,
+}
+
+impl PendingHandoff {
+    /// Take over the connection, after which the old process stops serving.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the response cannot be sent, in which case the old process may still serve.
+    pub fn accept(self) -> io::Result<()> {
+        self.respond(None)
+    }
+
+    /// Reject the hand-off with `message`, so the old process keeps serving.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the response cannot be sent.
+    pub fn reject(self, message: impl std::fmt::Display) -> io::Result<()> {
+        self.respond(Some(ResponseError::new(ErrorCode::REQUEST_FAILED, message)))
+    }
+
+    fn respond(mut self, error: Option<ResponseError>) -> io::Result<()> {
+        let resp = Message::Response(AnyResponse {
+            id: self.id,
+            result: error.is_none().then_some(JsonValue::Null),
+            error,
+            raw: None,
+        });
+        write_frame(&mut self.stream, &resp, &[])
+    }
+}
+
+/// Write a message with the base protocol header, passing `fds` along with the first bytes.
+fn write_frame(stream: &mut UnixStream, msg: &Message, fds: &[BorrowedFd<'_>]) -> io::Result<()> {
+    let body = serde_json::to_vec(&RawMessage::new(msg)).expect("Failed to serialize");
+    let mut buf = format!("Content-Length: {}\r\n\r\n", body.len()).into_bytes();
+    buf.extend_from_slice(&body);
+
+    let mut space = [0u8; rustix::cmsg_space!(ScmRights(MAX_FDS))];
+    let mut control = SendAncillaryBuffer::new(&mut space);
+    if !fds.is_empty() {
+        let pushed = control.push(SendAncillaryMessage::ScmRights(fds));
+        assert!(pushed, "Checked by MAX_FDS");
+    }
+    let written = sendmsg(
+        &*stream,
+        &[IoSlice::new(&buf)],
+        &mut control,
+        SendFlags::empty(),
+    )?;
+    stream.write_all(&buf[written..])
+}
+
+/// Read a message with the base protocol header, and file descriptors passed along.
+fn read_frame(stream: &UnixStream) -> Result<(Message, Vec<OwnedFd>), HandoffError> {
+    let mut buf = Vec::new();
+    let mut fds = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let mut space = [0u8; rustix::cmsg_space!(ScmRights(MAX_FDS))];
+    loop {
+        if let Some(body) = parse_frame(&buf)? {
+            let msg = serde_json::from_slice::<RawMessage<Message>>(body)
+                .map_err(|err| HandoffError::Protocol(err.to_string()))?;
+            return Ok((msg.inner, fds));
+        }
+        let mut control = RecvAncillaryBuffer::new(&mut space);
+        let ret = recvmsg(
+            stream,
+            &mut [IoSliceMut::new(&mut chunk)],
+            &mut control,
+            RecvFlags::empty(),
+        )
+        .map_err(io::Error::from)?;
+        for msg in control.drain() {
+            if let RecvAncillaryMessage::ScmRights(received) = msg {
+                fds.extend(received);
+            }
+        }
+        if ret.bytes == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        buf.extend_from_slice(&chunk[..ret.bytes]);
+    }
+}
+
+/// Get the body if `buf` contains a complete message.
+fn parse_frame(buf: &[u8]) -> Result<Option<&[u8]>, HandoffError> {
+    let header_end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    let header = std::str::from_utf8(&buf[..header_end])
+        .map_err(|err| HandoffError::Protocol(err.to_string()))?;
+    let len = header
+        .split("\r\n")
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .and_then(|len| len.parse::<usize>().ok())
+        .ok_or_else(|| HandoffError::Protocol("missing Content-Length".into()))?;
+    Ok(buf[header_end + 4..].get(..len))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::os::unix::io::AsFd;
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn handoff() {
+        let (old, new) = UnixStream::pair().unwrap();
+        let (mut editor, conn) = UnixStream::pair().unwrap();
+        let receiver = std::thread::spawn(move || {
+            let mut results = Vec::new();
+            for stream in [new.try_clone().unwrap(), new] {
+                let mut handoff = HandoffReceiver::new(stream).recv().unwrap();
+                if handoff.params.version != "2" {
+                    handoff.reject("incompatible").unwrap();
+                    continue;
+                }
+                let mut conn = UnixStream::from(handoff.fds.pop().unwrap());
+                conn.write_all(&handoff.params.buffered).unwrap();
+                results.push(handoff.params.state.clone());
+                handoff.accept().unwrap();
+            }
+            results
+        });
+
+        let mut sender = HandoffSender::new(old);
+        let err = sender
+            .send(&HandoffParams::new("1", json!(null)), &[conn.as_fd()])
+            .unwrap_err();
+        assert!(matches!(err, HandoffError::Rejected(err) if err.message == "incompatible"));
+
+        let mut params = HandoffParams::new("2", json!({ "docs": ["file:///foo"] }));
+        params.buffered = b"Content-".to_vec();
+        sender.send(&params, &[conn.as_fd()]).unwrap();
+        drop(conn);
+
+        assert_eq!(receiver.join().unwrap(), [params.state]);
+        let mut buf = Vec::new();
+        editor.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"Content-");
+    }
+}
@@ -0,0 +1,280 @@
+//! Document highlight and linked editing range by identifier matching.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Precise `textDocument/documentHighlight` and `textDocument/linkedEditingRange` need name
+//! resolution, which early-stage servers usually lack. Matching identifiers by text is a good
+//! enough approximation for most languages, and lets servers advertise both features from day
+//! one. [`WordHighlights`] reads documents from a [`DocumentPipeline`], splits them into
+//! identifiers with a [`Tokenizer`], and reports all occurrences of the identifier under the
+//! cursor.
+//!
+//! The default [`WordTokenizer`] treats maximal runs of alphanumeric characters and underscores,
+//! not starting with a digit, as identifiers. Provide a custom [`Tokenizer`] for languages with
+//! different identifier rules, or to skip comments and strings.
+//!
+//! ```
+//! # use async_lsp::highlight::WordHighlights;
+//! # use async_lsp::lsp_types::ServerCapabilities;
+//! # use async_lsp::pipeline::DocumentPipeline;
+//! # use async_lsp::router::Router;
+//! # fn work(pipeline: DocumentPipeline, router: &mut Router<()>) {
+//! let highlights = WordHighlights::new(pipeline)
+//!     // Also allow `-` in identifiers, eg. for CSS.
+//!     .tokenizer(|text: &str| {
+//!         let mut ret = Vec::new();
+//!         let mut start = None;
+//!         for (i, c) in text.char_indices().chain([(text.len(), ' ')]) {
+//!             match (start, c.is_alphanumeric() || c == '-' || c == '_') {
+//!                 (None, true) => start = Some(i),
+//!                 (Some(s), false) => {
+//!                     ret.push(s..i);
+//!                     start = None;
+//!                 }
+//!                 _ => {}
+//!             }
+//!         }
+//!         ret
+//!     });
+//! let mut caps = ServerCapabilities::default();
+//! highlights.fill_capabilities(&mut caps);
+//! highlights.register(router);
+//! # }
+//! ```
+use std::fmt;
+use std::future::ready;
+use std::ops;
+use std::sync::Arc;
+
+use lsp_types::request::{DocumentHighlightRequest, LinkedEditingRange};
+use lsp_types::{
+    DocumentHighlight, DocumentHighlightKind, LinkedEditingRangeServerCapabilities,
+    LinkedEditingRanges, OneOf, Position, Range, ServerCapabilities,
+};
+
+use crate::pipeline::{offset_of, DocumentPipeline};
+use crate::router::Router;
+use crate::ResponseError;
+
+/// A splitter of text into identifiers.
+///
+/// It is implemented for functions of the same signature as [`Tokenizer::identifiers`].
+pub trait Tokenizer: Send + Sync {
+    /// Get byte ranges of identifiers in `text`. They must be non-overlapping, and sorted by
+    /// their start offsets.
+    fn identifiers(&self, text: &str) -> Vec<ops::Range<usize>>;
+}
+
+impl<F> Tokenizer for F
+where
+    F: Fn(&str) -> Vec<ops::Range<usize>> + Send + Sync,
+{
+    fn identifiers(&self, text: &str) -> Vec<ops::Range<usize>> {
+        self(text)
+    }
+}
+
+/// The default [`Tokenizer`] matching word-like identifiers.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WordTokenizer;
+
+impl Tokenizer for WordTokenizer {
+    fn identifiers(&self, text: &str) -> Vec<ops::Range<usize>> {
+        let mut ret = Vec::new();
+        let mut start = None;
+        // The sentinel ends the last word.
+        for (i, c) in text.char_indices().chain([(text.len(), ' ')]) {
+            match (start, c.is_alphanumeric() || c == '_') {
+                (None, true) => start = Some(i),
+                (Some(s), false) => {
+                    start = None;
+                    if !text[s..].starts_with(|c: char| c.is_ascii_digit()) {
+                        ret.push(s..i);
+                    }
+                }
+                _ => {}
+            }
+        }
+        ret
+    }
+}
+
+/// The cheaply cloneable provider of `textDocument/documentHighlight` and
+/// `textDocument/linkedEditingRange` by identifier matching.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone)]
+#[must_use]
+pub struct WordHighlights {
+    pipeline: DocumentPipeline,
+    tokenizer: Arc<dyn Tokenizer>,
+}
+
+impl fmt::Debug for WordHighlights {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WordHighlights")
+            .field("pipeline", &self.pipeline)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WordHighlights {
+    /// Create a provider reading documents from `pipeline`, using [`WordTokenizer`].
+    pub fn new(pipeline: DocumentPipeline) -> Self {
+        Self {
+            pipeline,
+            tokenizer: Arc::new(WordTokenizer),
+        }
+    }
+
+    /// Set the [`Tokenizer`] to find identifiers.
+    pub fn tokenizer(mut self, tokenizer: impl Tokenizer + 'static) -> Self {
+        self.tokenizer = Arc::new(tokenizer);
+        self
+    }
+
+    /// Advertise capabilities of providers registered by [`WordHighlights::register`].
+    pub fn fill_capabilities(&self, caps: &mut ServerCapabilities) {
+        caps.document_highlight_provider = Some(OneOf::Left(true));
+        caps.linked_editing_range_provider =
+            Some(LinkedEditingRangeServerCapabilities::Simple(true));
+    }
+
+    /// Register handlers of `textDocument/documentHighlight` and
+    /// `textDocument/linkedEditingRange` to `router`.
+    ///
+    /// Documents are read via [`DocumentPipeline::read`]. Unreadable documents result in `None`.
+    pub fn register<St, Error>(&self, router: &mut Router<St, Error>)
+    where
+        Error: From<ResponseError> + Send + 'static,
+    {
+        let this = self.clone();
+        router.request::<DocumentHighlightRequest, _>(move |_, params| {
+            let pos = params.text_document_position_params;
+            let text = this.pipeline.read(&pos.text_document.uri).ok();
+            ready(Ok(
+                text.and_then(|text| this.highlights(&text, pos.position))
+            ))
+        });
+        let this = self.clone();
+        router.request::<LinkedEditingRange, _>(move |_, params| {
+            let pos = params.text_document_position_params;
+            let text = this.pipeline.read(&pos.text_document.uri).ok();
+            ready(Ok(
+                text.and_then(|text| this.linked_ranges(&text, pos.position))
+            ))
+        });
+    }
+
+    /// Get highlights of all occurrences of the identifier at `pos` in `text`, or `None` if
+    /// there is no identifier at `pos`.
+    #[must_use]
+    pub fn highlights(&self, text: &str, pos: Position) -> Option<Vec<DocumentHighlight>> {
+        let ranges = self.occurrences(text, pos)?;
+        let highlights = ranges
+            .into_iter()
+            .map(|range| DocumentHighlight {
+                range,
+                kind: Some(DocumentHighlightKind::TEXT),
+            })
+            .collect();
+        Some(highlights)
+    }
+
+    /// Get ranges of all occurrences of the identifier at `pos` in `text` to be edited together,
+    /// or `None` if there is no identifier at `pos`.
+    #[must_use]
+    pub fn linked_ranges(&self, text: &str, pos: Position) -> Option<LinkedEditingRanges> {
+        let ranges = self.occurrences(text, pos)?;
+        Some(LinkedEditingRanges {
+            ranges,
+            word_pattern: None,
+        })
+    }
+
+    fn occurrences(&self, text: &str, pos: Position) -> Option<Vec<Range>> {
+        let offset = offset_of(text, pos);
+        let idents = self.tokenizer.identifiers(text);
+        // Prefer the one starting at the cursor over the one ending at it.
+        let target = idents
+            .iter()
+            .rev()
+            .find(|ident| ident.start <= offset && offset <= ident.end)?;
+        let name = &text[target.clone()];
+        let matched = idents
+            .iter()
+            .filter(|ident| &text[(*ident).clone()] == name);
+        Some(to_ranges(text, matched))
+    }
+}
+
+/// Convert sorted byte ranges into UTF-16 based ranges in a single pass.
+fn to_ranges<'a>(text: &str, ranges: impl Iterator<Item = &'a ops::Range<usize>>) -> Vec<Range> {
+    let (mut offset, mut pos) = (0, Position::new(0, 0));
+    let mut advance = |to: usize| {
+        for c in text[offset..to].chars() {
+            if c == '\n' {
+                pos = Position::new(pos.line + 1, 0);
+            } else {
+                pos.character += c.len_utf16() as u32;
+            }
+        }
+        offset = to;
+        pos
+    };
+    ranges
+        .map(|range| Range::new(advance(range.start), advance(range.end)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use super::*;
+    use crate::jobs::Jobs;
+    use crate::ClientSocket;
+
+    fn tokens(text: &str) -> Vec<&str> {
+        WordTokenizer
+            .identifiers(text)
+            .into_iter()
+            .map(|range| &text[range])
+            .collect()
+    }
+
+    #[test]
+    fn word_tokenizer() {
+        assert_eq!(tokens("let x1 = 42 + _y;"), ["let", "x1", "_y"]);
+        assert_eq!(tokens("été.µs"), ["été", "µs"]);
+        assert!(tokens("  1.5e3 ").is_empty());
+    }
+
+    #[test]
+    fn occurrences() {
+        let (jobs, _runner) = Jobs::new(NonZeroUsize::new(1).unwrap());
+        let pipeline =
+            DocumentPipeline::new(ClientSocket::new_closed(), jobs, |_| async { Vec::new() });
+        let highlights = WordHighlights::new(pipeline);
+        let text = "let 😀 = foo;\nfoo(foo_bar, foo)";
+        let range =
+            |line, start, end| Range::new(Position::new(line, start), Position::new(line, end));
+        let expect = [range(0, 9, 12), range(1, 0, 3), range(1, 13, 16)];
+
+        // At the start, in the middle, and at the end.
+        for pos in [
+            Position::new(0, 9),
+            Position::new(1, 14),
+            Position::new(1, 16),
+        ] {
+            let ret = highlights.highlights(text, pos).unwrap();
+            assert_eq!(ret.iter().map(|hl| hl.range).collect::<Vec<_>>(), expect);
+        }
+        let ret = highlights.linked_ranges(text, Position::new(1, 0)).unwrap();
+        assert_eq!(ret.ranges, expect);
+
+        assert_eq!(highlights.highlights(text, Position::new(0, 7)), None);
+    }
+}
@@ -0,0 +1,877 @@
+//! Background job scheduling with priorities.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! Language Servers often have heavy background work, like indexing, which should not starve
+//! latency-sensitive requests like completion. [`Jobs`] is a lightweight scheduler where handlers
+//! enqueue background jobs with a [`Priority`] and optional dependencies on other jobs. Jobs are
+//! executed by the [`JobRunner`] future, which should be spawned onto the runtime, so that they
+//! never block the main loop.
+//!
+//! The scheduler is aware of the request load via the [`RequestLoad`] middleware created by
+//! [`Jobs::load_layer`]:
+//! - [`Priority::Interactive`] jobs are started as soon as their dependencies finish.
+//! - [`Priority::Background`] jobs are not started while there are requests in flight.
+//! - [`Priority::Idle`] jobs are additionally not started while higher priority jobs are pending
+//!   or running.
+//!
+//! Ready jobs of higher priority always start first, and jobs of the same priority start in the
+//! order they are enqueued. At most `max_concurrency` jobs run concurrently. Note that running
+//! jobs are never preempted.
+//!
+//! ## Progress
+//!
+//! Jobs spawned via [`Jobs::spawn_with_progress`] are reported to the Language Client as
+//! [work done progress](https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#workDoneProgress).
+//! The scheduler creates the progress token via `window/workDoneProgress/create`, begins the
+//! progress when the job starts, and ends it when the job completes, fails or is cancelled. The
+//! job can report intermediate states via the [`JobProgress`] handle.
+//!
+//! When the user cancels the progress in the editor, the `window/workDoneProgress/cancel`
+//! notification is intercepted by the [`RequestLoad`] middleware and cancels the job. Progresses
+//! of jobs are registered in a [`ProgressCancellation`] registry, which is a private one unless
+//! set via [`Jobs::set_cancellation`]. Setting the registry shared with a
+//! [`CancelProgress`](crate::progress::CancelProgress) middleware routes cancellations of both
+//! jobs and other progresses through either middleware, so they can be stacked in any order.
+//! Notifications of unknown tokens are passed to the inner service. Titles and messages are translated via the
+//! [`Locale`] set by [`Jobs::set_locale`], if any, including the end messages of failed or
+//! cancelled jobs.
+//!
+//! ```
+//! # use async_lsp::jobs::{Jobs, Priority};
+//! # use std::num::NonZeroUsize;
+//! # async fn work() {
+//! let (jobs, runner) = Jobs::new(NonZeroUsize::new(4).unwrap());
+//! tokio::spawn(runner);
+//! let parse = jobs.spawn(Priority::Background, async { /* Parse files. */ });
+//! jobs.spawn_after(Priority::Idle, [parse], async { /* Build the index. */ });
+//! # }
+//! ```
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::ops::ControlFlow;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures::future::{AbortHandle, Abortable, BoxFuture};
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+use lsp_types::notification::{self, Notification};
+use lsp_types::{
+    ProgressParams, ProgressParamsValue, ProgressToken, WorkDoneProgress, WorkDoneProgressBegin,
+    WorkDoneProgressCancelParams, WorkDoneProgressEnd, WorkDoneProgressReport,
+};
+use pin_project_lite::pin_project;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::locale::Locale;
+use crate::progress::{ProgressCancellation, WorkDoneToken};
+use crate::status::StatusReporter;
+use crate::{AnyEvent, AnyNotification, AnyRequest, ClientSocket, EventHints, LspService, Result};
+
+/// The maximum number of job completions processed in a single poll of [`JobRunner`] before
+/// yielding to the runtime.
+const POLL_BUDGET: usize = 32;
+
+/// The priority of a job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Only runs when there is nothing else to do.
+    Idle,
+    /// Does not run while requests are in flight.
+    Background,
+    /// Runs as soon as possible.
+    Interactive,
+}
+
+/// The identifier of a job in a [`Jobs`] scheduler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct JobId(u64);
+
+/// The cheaply cloneable handle to enqueue and manage jobs.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone)]
+pub struct Jobs {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    next_id: u64,
+    max_concurrency: NonZeroUsize,
+    queue: Vec<PendingJob>,
+    /// Pending or running jobs.
+    unfinished: HashSet<JobId>,
+    running: HashMap<JobId, (Priority, AbortHandle)>,
+    in_flight_requests: usize,
+    waker: Option<Waker>,
+    client: Option<ClientSocket>,
+    status: Option<StatusReporter>,
+    locale: Option<Locale>,
+    cancellation: ProgressCancellation,
+}
+
+struct PendingJob {
+    id: JobId,
+    priority: Priority,
+    deps: Vec<JobId>,
+    fut: BoxFuture<'static, ()>,
+}
+
+impl fmt::Debug for Jobs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("Jobs")
+            .field("max_concurrency", &state.max_concurrency)
+            .field("pending", &state.queue.len())
+            .field("running", &state.running.len())
+            .field("in_flight_requests", &state.in_flight_requests)
+            .finish()
+    }
+}
+
+impl State {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn finish(&mut self, id: JobId) {
+        self.unfinished.remove(&id);
+        self.running.remove(&id);
+    }
+
+    fn is_admitted(&self, priority: Priority) -> bool {
+        match priority {
+            Priority::Interactive => true,
+            Priority::Background => self.in_flight_requests == 0,
+            Priority::Idle => {
+                self.in_flight_requests == 0
+                    && self.queue.iter().all(|job| job.priority == Priority::Idle)
+                    && self
+                        .running
+                        .values()
+                        .all(|(prio, _)| *prio == Priority::Idle)
+            }
+        }
+    }
+
+    /// Take the next job to start, if any.
+    fn take_next(&mut self) -> Option<PendingJob> {
+        let mut best: Option<usize> = None;
+        for (i, job) in self.queue.iter().enumerate() {
+            if best.map_or(false, |b| self.queue[b].priority >= job.priority)
+                || job.deps.iter().any(|dep| self.unfinished.contains(dep))
+                || !self.is_admitted(job.priority)
+            {
+                continue;
+            }
+            best = Some(i);
+        }
+        best.map(|i| self.queue.remove(i))
+    }
+}
+
+impl Jobs {
+    /// Create a scheduler running at most `max_concurrency` jobs concurrently, and its runner.
+    ///
+    /// The returned [`JobRunner`] should be spawned onto the runtime. It never completes, and
+    /// dropping it cancels all jobs.
+    pub fn new(max_concurrency: NonZeroUsize) -> (Self, JobRunner) {
+        let state = Arc::new(Mutex::new(State {
+            next_id: 0,
+            max_concurrency,
+            queue: Vec::new(),
+            unfinished: HashSet::new(),
+            running: HashMap::new(),
+            in_flight_requests: 0,
+            waker: None,
+            client: None,
+            status: None,
+            locale: None,
+            cancellation: ProgressCancellation::new(),
+        }));
+        let runner = JobRunner {
+            state: state.clone(),
+            running: FuturesUnordered::new(),
+        };
+        (Self { state }, runner)
+    }
+
+    /// Enqueue a job.
+    pub fn spawn(
+        &self,
+        priority: Priority,
+        fut: impl Future<Output = ()> + Send + 'static,
+    ) -> JobId {
+        self.spawn_after(priority, [], fut)
+    }
+
+    /// Enqueue a job which only starts after all jobs in `deps` finish or are cancelled.
+    pub fn spawn_after(
+        &self,
+        priority: Priority,
+        deps: impl IntoIterator<Item = JobId>,
+        fut: impl Future<Output = ()> + Send + 'static,
+    ) -> JobId {
+        self.enqueue(priority, deps.into_iter().collect(), |_, _| fut.boxed())
+    }
+
+    fn enqueue(
+        &self,
+        priority: Priority,
+        deps: Vec<JobId>,
+        make_fut: impl FnOnce(&mut State, JobId) -> BoxFuture<'static, ()>,
+    ) -> JobId {
+        let mut state = self.state.lock().unwrap();
+        let id = JobId(state.next_id);
+        state.next_id += 1;
+        state.unfinished.insert(id);
+        let fut = make_fut(&mut state, id);
+        state.queue.push(PendingJob {
+            id,
+            priority,
+            deps,
+            fut,
+        });
+        state.wake();
+        id
+    }
+
+    /// Attach the socket to report progress of jobs spawned via [`Jobs::spawn_with_progress`].
+    pub fn set_client(&self, client: ClientSocket) {
+        self.state.lock().unwrap().client = Some(client);
+    }
+
+    /// Report running jobs spawned via [`Jobs::spawn_with_progress`] as
+    /// [`ServerState::Indexing`](crate::status::ServerState::Indexing) to `status`, whether or
+    /// not a client is attached.
+    pub fn set_status(&self, status: StatusReporter) {
+        self.state.lock().unwrap().status = Some(status);
+    }
+
+    /// Translate titles and messages of jobs spawned via [`Jobs::spawn_with_progress`] via
+    /// `locale`.
+    pub fn set_locale(&self, locale: Locale) {
+        self.state.lock().unwrap().locale = Some(locale);
+    }
+
+    /// Register progresses of jobs spawned later via [`Jobs::spawn_with_progress`] in
+    /// `cancellation`, eg. the registry shared with a
+    /// [`CancelProgress`](crate::progress::CancelProgress) middleware.
+    ///
+    /// See [module level documentations](self) for details.
+    pub fn set_cancellation(&self, cancellation: ProgressCancellation) {
+        self.state.lock().unwrap().cancellation = cancellation;
+    }
+
+    /// Enqueue a job whose progress is reported to the Language Client with `title`.
+    ///
+    /// If no client is attached, or the client rejects the progress creation, the job still runs
+    /// but reports nothing. If the job fails, the error message is reported in the end of the
+    /// progress.
+    ///
+    /// See [module level documentations](self) for details.
+    pub fn spawn_with_progress<F, Fut, E>(
+        &self,
+        priority: Priority,
+        title: impl Into<String>,
+        f: F,
+    ) -> JobId
+    where
+        F: FnOnce(JobProgress) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: Display,
+    {
+        let title = title.into();
+        let jobs = Arc::downgrade(&self.state);
+        self.enqueue(priority, Vec::new(), |state, id| {
+            let client = state.client.clone();
+            let status = state.status.clone();
+            let locale = state.locale.clone();
+            let cancellation = state.cancellation.clone();
+            async move {
+                let token = match &client {
+                    Some(client) => client.create_progress_token().await.ok(),
+                    None => None,
+                };
+                let client = client.zip(token).map(|(client, token)| {
+                    cancellation.on_cancel(token.clone(), move || {
+                        if let Some(state) = jobs.upgrade() {
+                            Jobs { state }.cancel(id);
+                        }
+                    });
+                    (client, Arc::new(WorkDoneToken::new(token)))
+                });
+                let progress = JobProgress {
+                    client,
+                    status: status.map(|status| (status, id)),
+                    locale,
+                    cancellation,
+                };
+                let title = progress.localize(title);
+                progress.with_status(|status, id| status.begin_task(id.0, title.clone()));
+                progress.send(WorkDoneProgress::Begin(WorkDoneProgressBegin {
+                    title,
+                    cancellable: Some(true),
+                    ..WorkDoneProgressBegin::default()
+                }));
+                let mut guard = ProgressEndGuard {
+                    message: Some(progress.localize("Cancelled".into())),
+                    progress: progress.clone(),
+                };
+                let ret = f(progress).await;
+                guard.message = ret
+                    .err()
+                    .map(|err| guard.progress.localize(err.to_string()));
+            }
+            .boxed()
+        })
+    }
+
+    /// Cancel the job reporting progress with `token`, or any other progress of `token` in the
+    /// registry set via [`Jobs::set_cancellation`].
+    ///
+    /// Returns `false` if there is no such progress, or it already finished.
+    pub fn cancel_progress(&self, token: &ProgressToken) -> bool {
+        let cancellation = self.state.lock().unwrap().cancellation.clone();
+        cancellation.cancel(token)
+    }
+
+    /// Cancel a pending or running job. Running jobs are dropped at their next suspension point.
+    ///
+    /// Returns `false` if the job already finished.
+    pub fn cancel(&self, id: JobId) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.unfinished.contains(&id) {
+            return false;
+        }
+        // Drop the future after unlocking, in case its destructor touches the scheduler.
+        let _job = state
+            .queue
+            .iter()
+            .position(|job| job.id == id)
+            .map(|i| state.queue.remove(i));
+        if let Some((_, handle)) = state.running.get(&id) {
+            handle.abort();
+        }
+        state.finish(id);
+        // Dependents may be ready now.
+        state.wake();
+        drop(state);
+        true
+    }
+
+    /// Check if a job finished or is cancelled.
+    #[must_use]
+    pub fn is_finished(&self, id: JobId) -> bool {
+        !self.state.lock().unwrap().unfinished.contains(&id)
+    }
+
+    /// Create a [`RequestLoadLayer`] which reports in-flight requests to this scheduler.
+    pub fn load_layer(&self) -> RequestLoadLayer {
+        RequestLoadLayer { jobs: self.clone() }
+    }
+}
+
+/// The future executing jobs of a [`Jobs`] scheduler.
+///
+/// See [module level documentations](self) for details.
+#[must_use = "futures do nothing unless polled"]
+pub struct JobRunner {
+    state: Arc<Mutex<State>>,
+    running: FuturesUnordered<BoxFuture<'static, JobId>>,
+}
+
+impl fmt::Debug for JobRunner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JobRunner")
+            .field("running", &self.running.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Future for JobRunner {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        for _ in 0..POLL_BUDGET {
+            {
+                let mut state = this.state.lock().unwrap();
+                while this.running.len() < state.max_concurrency.get() {
+                    let job = match state.take_next() {
+                        Some(job) => job,
+                        None => break,
+                    };
+                    let (handle, registration) = AbortHandle::new_pair();
+                    state.running.insert(job.id, (job.priority, handle));
+                    let id = job.id;
+                    this.running.push(
+                        Abortable::new(job.fut, registration)
+                            .map(move |_| id)
+                            .boxed(),
+                    );
+                }
+                state.waker = Some(cx.waker().clone());
+            }
+
+            match this.running.poll_next_unpin(cx) {
+                Poll::Ready(Some(id)) => this.state.lock().unwrap().finish(id),
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+        // Yield to other tasks under heavy load.
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// The middleware reporting in-flight requests to a [`Jobs`] scheduler.
+///
+/// See [module level documentations](self) for details.
+pub struct RequestLoad<S> {
+    service: S,
+    jobs: Jobs,
+}
+
+define_getters!(impl[S] RequestLoad<S>, service: S);
+
+impl<S: LspService> Service<AnyRequest> for RequestLoad<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        self.jobs.state.lock().unwrap().in_flight_requests += 1;
+        ResponseFuture {
+            fut: self.service.call(req),
+            _guard: LoadGuard(self.jobs.clone()),
+        }
+    }
+}
+
+impl<S: LspService> LspService for RequestLoad<S> {
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        if notif.method == notification::WorkDoneProgressCancel::METHOD {
+            if let Ok(params) =
+                serde_json::from_value::<WorkDoneProgressCancelParams>(notif.params.clone())
+            {
+                if self.jobs.cancel_progress(&params.token) {
+                    return ControlFlow::Continue(());
+                }
+            }
+        }
+        self.service.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.service.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+/// The handle for a job to report its progress.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone)]
+pub struct JobProgress {
+    /// `None` if the progress is not reported to the client.
+    client: Option<(ClientSocket, Arc<WorkDoneToken>)>,
+    status: Option<(StatusReporter, JobId)>,
+    locale: Option<Locale>,
+    cancellation: ProgressCancellation,
+}
+
+impl JobProgress {
+    /// Get the progress token, or `None` if the progress is not reported to the client.
+    #[must_use]
+    pub fn token(&self) -> Option<&ProgressToken> {
+        self.client.as_ref().map(|(_, token)| token.get())
+    }
+
+    /// Report an intermediate state, with an optional message and an optional percentage in
+    /// `0..=100`.
+    pub fn report(&self, message: Option<String>, percentage: Option<u32>) {
+        let message = message.map(|message| self.localize(message));
+        self.with_status(|status, id| status.report_task(id.0, message.clone(), percentage));
+        self.send(WorkDoneProgress::Report(WorkDoneProgressReport {
+            cancellable: Some(true),
+            message,
+            percentage,
+        }));
+    }
+
+    fn localize(&self, message: String) -> String {
+        match self.locale.as_ref().map(|locale| locale.localize(&message)) {
+            Some(Cow::Owned(translated)) => translated,
+            _ => message,
+        }
+    }
+
+    fn with_status(&self, f: impl FnOnce(&StatusReporter, JobId)) {
+        if let Some((status, id)) = &self.status {
+            f(status, *id);
+        }
+    }
+
+    fn send(&self, value: WorkDoneProgress) {
+        if let Some((client, token)) = &self.client {
+            // Errors mean the main loop stopped, which will also cancel the job soon.
+            let _: Result<_> = client.notify::<notification::Progress>(ProgressParams {
+                token: token.get().clone(),
+                value: ProgressParamsValue::WorkDone(value),
+            });
+        }
+    }
+}
+
+/// Ends the progress on drop, so that cancelled jobs end it as well.
+struct ProgressEndGuard {
+    progress: JobProgress,
+    message: Option<String>,
+}
+
+impl Drop for ProgressEndGuard {
+    fn drop(&mut self) {
+        if let Some((_, token)) = &self.progress.client {
+            self.progress.cancellation.remove(token.get());
+        }
+        self.progress
+            .with_status(|status, id| status.end_task(id.0));
+        self.progress
+            .send(WorkDoneProgress::End(WorkDoneProgressEnd {
+                message: self.message.take(),
+            }));
+    }
+}
+
+struct LoadGuard(Jobs);
+
+impl Drop for LoadGuard {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.in_flight_requests -= 1;
+        if state.in_flight_requests == 0 {
+            state.wake();
+        }
+    }
+}
+
+pin_project! {
+    /// The [`Future`] type used by the [`RequestLoad`] middleware.
+    pub struct ResponseFuture<Fut> {
+        #[pin]
+        fut: Fut,
+        _guard: LoadGuard,
+    }
+}
+
+impl<Fut: Future> Future for ResponseFuture<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.project().fut.poll(cx)
+    }
+}
+
+/// A [`tower_layer::Layer`] which builds [`RequestLoad`].
+///
+/// It is created by [`Jobs::load_layer`].
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct RequestLoadLayer {
+    jobs: Jobs,
+}
+
+impl<S> Layer<S> for RequestLoadLayer {
+    type Service = RequestLoad<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestLoad {
+            service: inner,
+            jobs: self.jobs.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::{mpsc, oneshot};
+    use futures::future::{pending, select};
+    use lsp_types::request::{Request, WorkDoneProgressCreate};
+    use lsp_types::NumberOrString;
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::status::ServerState;
+    use crate::{AnyResponse, MainLoopEvent, Message, PeerSocket};
+
+    #[tokio::test]
+    async fn priorities_and_dependencies() {
+        let (jobs, runner) = Jobs::new(NonZeroUsize::new(1).unwrap());
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let record = |name: &'static str| {
+            let order = order.clone();
+            async move { order.lock().unwrap().push(name) }
+        };
+
+        let (done_tx, done_rx) = oneshot::channel();
+        let idle = record("idle");
+        jobs.spawn(Priority::Idle, async move {
+            idle.await;
+            done_tx.send(()).unwrap();
+        });
+        let bg = jobs.spawn(Priority::Background, record("background"));
+        jobs.spawn_after(Priority::Interactive, [bg], record("dependent"));
+        jobs.spawn(Priority::Interactive, record("interactive"));
+        let cancelled = jobs.spawn(Priority::Interactive, record("cancelled"));
+        assert!(jobs.cancel(cancelled));
+        assert!(jobs.is_finished(cancelled));
+
+        // Background jobs wait for in-flight requests.
+        let guard = {
+            jobs.state.lock().unwrap().in_flight_requests += 1;
+            LoadGuard(jobs.clone())
+        };
+        let mut runner = runner;
+        let _ = select(&mut runner, Box::pin(tokio::task::yield_now())).await;
+        assert_eq!(*order.lock().unwrap(), ["interactive"]);
+        drop(guard);
+
+        select(runner, done_rx).await;
+        assert_eq!(
+            *order.lock().unwrap(),
+            ["interactive", "background", "dependent", "idle"]
+        );
+    }
+
+    #[tokio::test]
+    async fn cancel_progress() {
+        let (jobs, runner) = Jobs::new(NonZeroUsize::new(1).unwrap());
+        let (tx, mut rx) = mpsc::unbounded();
+        jobs.set_client(ClientSocket(PeerSocket { tx }));
+        let (status_tx, mut status_rx) = mpsc::unbounded();
+        let status = StatusReporter::new(ClientSocket(PeerSocket { tx: status_tx }));
+        status.set_ready(true);
+        jobs.set_status(status);
+        let mut service = jobs.load_layer().layer(Router::new(()));
+        let (started_tx, started_rx) = oneshot::channel();
+        jobs.spawn_with_progress(Priority::Interactive, "Indexing", |progress| async move {
+            progress.report(Some("Half".into()), Some(50));
+            started_tx.send(progress.token().cloned().unwrap()).unwrap();
+            pending::<Result<(), String>>().await
+        });
+        let runner = tokio::spawn(runner);
+
+        match rx.next().await.unwrap() {
+            MainLoopEvent::OutgoingRequest(req, resp_tx) => {
+                assert_eq!(req.method, WorkDoneProgressCreate::METHOD);
+                resp_tx
+                    .send(AnyResponse {
+                        id: req.id,
+                        result: Some(json!(null)),
+                        error: None,
+                        raw: None,
+                    })
+                    .unwrap();
+            }
+            _ => panic!("unexpected event"),
+        }
+        let token = started_rx.await.unwrap();
+        // Allocated via the shared progress token API.
+        assert!(
+            matches!(&token, NumberOrString::String(s) if s.starts_with("async-lsp/progress/"))
+        );
+        let notif = AnyNotification {
+            method: notification::WorkDoneProgressCancel::METHOD.into(),
+            params: json!({ "token": token }),
+            receipt: None,
+        };
+        assert!(matches!(service.notify(notif), ControlFlow::Continue(())));
+
+        let mut kinds = Vec::new();
+        for _ in 0..3 {
+            match rx.next().await.unwrap() {
+                MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                    let params: ProgressParams = serde_json::from_value(notif.params).unwrap();
+                    assert_eq!(params.token, token);
+                    kinds.push(match params.value {
+                        ProgressParamsValue::WorkDone(WorkDoneProgress::Begin(_)) => "begin",
+                        ProgressParamsValue::WorkDone(WorkDoneProgress::Report(_)) => "report",
+                        ProgressParamsValue::WorkDone(WorkDoneProgress::End(end)) => {
+                            assert_eq!(end.message.as_deref(), Some("Cancelled"));
+                            "end"
+                        }
+                    });
+                }
+                _ => panic!("unexpected event"),
+            }
+        }
+        assert_eq!(kinds, ["begin", "report", "end"]);
+        assert_eq!(jobs.state.lock().unwrap().unfinished.len(), 0);
+        runner.abort();
+
+        let mut states = Vec::<ServerState>::new();
+        while let Ok(Some(event)) = status_rx.try_next() {
+            match event {
+                MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                    states.push(serde_json::from_value(notif.params).unwrap());
+                }
+                _ => panic!("unexpected event"),
+            }
+        }
+        let indexing = |message: Option<&str>, percentage| ServerState::Indexing {
+            title: "Indexing".into(),
+            message: message.map(Into::into),
+            percentage,
+        };
+        assert_eq!(
+            states,
+            [
+                ServerState::Ready,
+                indexing(None, None),
+                indexing(Some("Half"), Some(50)),
+                ServerState::Ready,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn localize_progress() {
+        let (jobs, runner) = Jobs::new(NonZeroUsize::new(1).unwrap());
+        let (tx, mut rx) = mpsc::unbounded();
+        jobs.set_client(ClientSocket(PeerSocket { tx }));
+        let locale = Locale::new(|locale: &str, message: &str| {
+            let translated = match (locale, message) {
+                ("de", "Indexing") => "Indizierung",
+                ("de", "Broken") => "Kaputt",
+                ("de", "Cancelled") => "Abgebrochen",
+                _ => return None,
+            };
+            Some(translated.into())
+        });
+        locale.set(Some("de".into()));
+        jobs.set_locale(locale);
+        jobs.spawn_with_progress(Priority::Interactive, "Indexing", |_| async {
+            Err("Broken")
+        });
+        let cancelled = jobs.spawn_with_progress(Priority::Interactive, "Indexing", |_| {
+            pending::<Result<(), String>>()
+        });
+        let runner = tokio::spawn(runner);
+
+        let mut progress = Vec::new();
+        for i in 0..6 {
+            match rx.next().await.unwrap() {
+                MainLoopEvent::OutgoingRequest(req, resp_tx) => {
+                    assert_eq!(req.method, WorkDoneProgressCreate::METHOD);
+                    resp_tx
+                        .send(AnyResponse {
+                            id: req.id,
+                            result: Some(json!(null)),
+                            error: None,
+                            raw: None,
+                        })
+                        .unwrap();
+                }
+                MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                    let params: ProgressParams = serde_json::from_value(notif.params).unwrap();
+                    match params.value {
+                        ProgressParamsValue::WorkDone(WorkDoneProgress::Begin(begin)) => {
+                            progress.push(begin.title);
+                        }
+                        ProgressParamsValue::WorkDone(WorkDoneProgress::End(end)) => {
+                            progress.push(end.message.unwrap());
+                        }
+                        ProgressParamsValue::WorkDone(WorkDoneProgress::Report(_)) => {
+                            panic!("unexpected report");
+                        }
+                    }
+                }
+                _ => panic!("unexpected event"),
+            }
+            // Cancel the second job after it begins.
+            if i == 4 {
+                assert!(jobs.cancel(cancelled));
+            }
+        }
+        runner.abort();
+        assert_eq!(
+            progress,
+            ["Indizierung", "Kaputt", "Indizierung", "Abgebrochen"]
+        );
+    }
+
+    #[tokio::test]
+    async fn stacked_cancellation() {
+        let (jobs, runner) = Jobs::new(NonZeroUsize::new(1).unwrap());
+        let (tx, mut rx) = mpsc::unbounded();
+        let client = ClientSocket(PeerSocket { tx });
+        jobs.set_client(client.clone());
+        let cancellation = ProgressCancellation::new();
+        jobs.set_cancellation(cancellation.clone());
+        // `CancelProgress` comes first, and handles cancellations of both.
+        let mut service = cancellation
+            .layer()
+            .layer(jobs.load_layer().layer(Router::new(())));
+        let (started_tx, started_rx) = oneshot::channel();
+        let job = jobs.spawn_with_progress(Priority::Interactive, "Indexing", |progress| {
+            started_tx.send(progress.token().cloned().unwrap()).unwrap();
+            pending::<Result<(), String>>()
+        });
+        let runner = tokio::spawn(runner);
+
+        match rx.next().await.unwrap() {
+            MainLoopEvent::OutgoingRequest(req, resp_tx) => {
+                assert_eq!(req.method, WorkDoneProgressCreate::METHOD);
+                resp_tx
+                    .send(AnyResponse {
+                        id: req.id,
+                        result: Some(json!(null)),
+                        error: None,
+                        raw: None,
+                    })
+                    .unwrap();
+            }
+            _ => panic!("unexpected event"),
+        }
+        let token = started_rx.await.unwrap();
+        let other = cancellation.begin(client, NumberOrString::Number(42), "Other");
+        let cancel = |token: &ProgressToken| AnyNotification {
+            method: notification::WorkDoneProgressCancel::METHOD.into(),
+            params: json!({ "token": token }),
+            receipt: None,
+        };
+        for token in [&token, other.token()] {
+            assert!(matches!(
+                service.notify(cancel(token)),
+                ControlFlow::Continue(())
+            ));
+        }
+        assert!(jobs.is_finished(job));
+        assert!(other.is_cancelled());
+        // Cancelled progresses are no longer registered, and notifications of them are passed to
+        // the router, which rejects unknown notifications.
+        assert!(!jobs.cancel_progress(&token));
+        assert!(matches!(
+            service.notify(cancel(&token)),
+            ControlFlow::Break(Err(_))
+        ));
+        runner.abort();
+    }
+}
@@ -0,0 +1,263 @@
+//! Bounded journal of recent incoming messages for crash forensics.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! Full wire logs via [`crate::record`] are often too heavy to enable for all users. This
+//! middleware instead keeps summaries of the last N incoming requests and notifications in a
+//! [`RequestJournal`]: the method, the request id, the size of parameters, and the timestamp.
+//!
+//! When the journal has a file set via [`RequestJournal::file`], it is dumped there on panics
+//! caught by [`CatchUnwind`](crate::panic::CatchUnwind) configured with
+//! [`CatchUnwindBuilder::journal`](crate::panic::CatchUnwindBuilder::journal). The file is
+//! overwritten with all entries in the journal, one JSON object per line, so it never grows
+//! beyond the capacity. Crash reports can then include the message sequence leading to the
+//! failure.
+//!
+//! ```
+//! # use async_lsp::journal::{JournalLayer, RequestJournal};
+//! # use async_lsp::panic::CatchUnwindLayer;
+//! let journal = RequestJournal::new(64).file(std::env::temp_dir().join("my-server.journal"));
+//! let catch_unwind = CatchUnwindLayer::default().journal(journal.clone());
+//! let journal_layer = JournalLayer::new(journal);
+//! ```
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use serde::{Deserialize, Serialize};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::record::timestamp;
+use crate::{
+    json_size, AnyEvent, AnyNotification, AnyRequest, EventHints, LspService, RequestId, Result,
+};
+
+/// The summary of an incoming message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[non_exhaustive]
+pub struct JournalEntry {
+    /// Milliseconds since the UNIX epoch when the message is received.
+    pub timestamp: u64,
+    /// The method.
+    pub method: String,
+    /// The request id, or `None` for notifications.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
+    /// The size of serialized parameters in bytes.
+    pub size: usize,
+}
+
+/// The shared handle of a bounded journal of recent incoming messages.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone)]
+pub struct RequestJournal {
+    entries: Arc<Mutex<VecDeque<JournalEntry>>>,
+    capacity: usize,
+    path: Option<Arc<Path>>,
+}
+
+impl RequestJournal {
+    /// Create a journal keeping at most `capacity` latest entries.
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+            path: None,
+        }
+    }
+
+    /// Set the file to dump entries to. Default is none, and [`RequestJournal::dump`] does
+    /// nothing.
+    #[must_use]
+    pub fn file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into().into());
+        self
+    }
+
+    /// Get entries in the journal, from the oldest to the latest.
+    #[must_use]
+    pub fn entries(&self) -> Vec<JournalEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Append an entry, evicting the oldest one if the journal is full.
+    pub fn push(&self, entry: JournalEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Overwrite the file, if set, with all entries in JSON Lines format.
+    ///
+    /// # Errors
+    ///
+    /// Errors from creating or writing the file.
+    pub fn dump(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let entries = self.entries();
+        let mut writer = BufWriter::new(File::create(path)?);
+        for entry in &entries {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()
+    }
+}
+
+/// The middleware journaling incoming requests and notifications.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+pub struct Journal<S> {
+    service: S,
+    journal: RequestJournal,
+}
+
+define_getters!(impl[S] Journal<S>, service: S);
+
+impl<S: LspService> Service<AnyRequest> for Journal<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        self.journal.push(JournalEntry {
+            timestamp: timestamp(),
+            method: req.method.clone(),
+            id: Some(req.id.clone()),
+            size: json_size(&req.params),
+        });
+        self.service.call(req)
+    }
+}
+
+impl<S: LspService> LspService for Journal<S> {
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        self.journal.push(JournalEntry {
+            timestamp: timestamp(),
+            method: notif.method.clone(),
+            id: None,
+            size: json_size(&notif.params),
+        });
+        self.service.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.service.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+/// A [`tower_layer::Layer`] which builds [`Journal`].
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct JournalLayer {
+    journal: RequestJournal,
+}
+
+impl JournalLayer {
+    /// Create the layer appending entries to `journal`.
+    pub fn new(journal: RequestJournal) -> Self {
+        Self { journal }
+    }
+}
+
+impl<S> Layer<S> for JournalLayer {
+    type Service = Journal<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Journal {
+            service: inner,
+            journal: self.journal.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use std::io::BufRead;
+
+    use lsp_types::notification::{self, Notification};
+    use lsp_types::request::{self, Request};
+    use serde_json::json;
+
+    use super::*;
+    use crate::panic::CatchUnwindLayer;
+    use crate::router::Router;
+    use crate::ResponseError;
+
+    #[tokio::test]
+    async fn dump_on_panic() {
+        let path = std::env::temp_dir().join(format!("async-lsp-journal-{}", std::process::id()));
+        let journal = RequestJournal::new(2).file(&path);
+
+        let mut router = Router::<(), ResponseError>::new(());
+        router
+            .request::<request::Shutdown, _>(|_, ()| async { panic!("oops") })
+            .notification::<notification::Initialized>(|_, _| ControlFlow::Continue(()));
+        let router = JournalLayer::new(journal.clone()).layer(router);
+        let mut service = CatchUnwindLayer::default()
+            .journal(journal.clone())
+            .layer(router);
+
+        for _ in 0..2 {
+            let notif = AnyNotification {
+                method: notification::Initialized::METHOD.into(),
+                params: json!({}),
+                receipt: None,
+            };
+            assert!(matches!(service.notify(notif), ControlFlow::Continue(())));
+        }
+        assert!(!path.exists());
+        poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+        let req = AnyRequest {
+            id: RequestId::Number(42),
+            method: request::Shutdown::METHOD.into(),
+            params: json!(null),
+            receipt: None,
+        };
+        service.call(req).await.unwrap_err();
+
+        let dumped = io::BufReader::new(File::open(&path).unwrap())
+            .lines()
+            .map(|line| serde_json::from_str::<JournalEntry>(&line.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(dumped, journal.entries());
+        let summary = dumped
+            .iter()
+            .map(|entry| (&*entry.method, entry.id.clone(), entry.size))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (notification::Initialized::METHOD, None, 2),
+                (request::Shutdown::METHOD, Some(RequestId::Number(42)), 4),
+            ],
+        );
+    }
+}
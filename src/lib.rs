@@ -13,14 +13,33 @@
 //! LSP functionalities, see their documentations for details.
 //! - [`concurrency::Concurrency`]: Incoming request multiplexing and cancellation.
 //! - [`panic::CatchUnwind`]: Turn panics into errors.
+//! - [`journal::Journal`]: Bounded journal of recent incoming messages for crash forensics.
 //! - [`tracing::Tracing`]: Logger spans with methods instrumenting handlers.
 //! - [`server::Lifecycle`]: Server initialization, shutting down, and exit handling.
+//! - [`client::ClientLifecycle`]: Client-side counterpart of [`server::Lifecycle`].
 //! - [`client_monitor::ClientProcessMonitor`]: Client process monitor.
+//! - [`locale::Localization`]: Locale negotiation and localization of user-visible messages.
+//! - [`downlevel::Downlevel`]: Protocol version detection and adaptation for older clients.
+//! - [`quirks::Quirks`]: Client-specific workarounds as post-processing on responses.
+//! - [`timeout::Timeout`]: Incoming request timeout.
+//! - [`metrics::Metrics`]: Request statistics via the [`metrics`][::metrics] facade.
+//! - [`alloc_profile::AllocProfile`]: Per-method allocation statistics of handlers.
+//! - [`coalesce::Coalesce`]: Cancellation of requests superseded by newer ones.
+//! - [`pending::Pending`]: Queriable registry of pending incoming requests.
+//! - [`text_document::DocumentSync`]: Text synchronization of opened documents.
+//! - [`response_size::ResponseSize`]: Per-method accounting of response sizes.
+//! - [`jobs::RequestLoad`]: Request load tracking for background job scheduling.
+//! - [`progress::CancelProgress`]: Work done progress cancellation from the editor UI.
+//! - [`can_handle::Fallback`]: Composition of services by the messages they handle.
+//! - [`mux::Mux`]: Multiple Language Servers behind one frontend, routed by documents.
+//! - [`proxy::Proxy`]: Forwarding of unhandled messages to an upstream Language Server.
 //! - [`router::Router`]: "Root" service to dispatch requests, notifications and events.
 //!
 //! Users are free to select and layer middlewares to run a Language Server or Language Client.
 //! They can also implement their own middlewares for like timeout, metering, request
 //! transformation and etc.
+//! The standard stack for Language Servers can also be built from user-facing settings via
+//! [`config::AsyncLspConfig`].
 //!
 //! ## Usages
 //!
@@ -46,35 +65,64 @@
 //!   *Enabled by default.*
 //! - `tracing`: Integration with crate [`tracing`][::tracing] and the [`tracing`] middleware.
 //!   *Enabled by default.*
+//! - `codec`: `LspCodec` implementing `tokio_util::codec` traits for custom transports, see
+//!   [`codec`].
+//!   *Disabled by default.*
+//! - `client-log`: Forward [`tracing`][::tracing] logs to the Language Client, see
+//!   [`client_log`].
+//!   *Disabled by default.*
+//! - `ffi`: C-compatible interface for embedding main loops into non-Rust hosts, see [`ffi`].
+//!   *Disabled by default.*
 //! - `forward`: Impl [`LspService`] for `{Client,Server}Socket`. This collides some method names
 //!   but allows easy service forwarding. See `examples/inspector.rs` for a possible use case.
 //!   *Disabled by default.*
+//! - `handoff`: Experimental hand-off of connections to a new version of the Language Server
+//!   on Unix, see [`handoff`].
+//!   *Disabled by default.*
+//! - `metrics`: Report request statistics via crate [`metrics`][::metrics], see [`metrics`].
+//!   *Disabled by default.*
+//! - `net`: Serve Language Servers over TCP or Unix domain sockets via [`tokio`], see [`net`].
+//!   *Disabled by default.*
+//! - `testing`: Utilities for testing Language Servers and Language Clients, see [`testing`].
+//!   *Disabled by default.*
 //! - `tokio`: Enable compatible methods for [`tokio`](https://crates.io/crates/tokio) runtime.
 //!   *Disabled by default.*
+//! - `tree-sitter`: Keep [`tree-sitter`](https://crates.io/crates/tree-sitter) syntax trees of
+//!   documents in sync, and provide syntax based features, see [`tree_sitter`].
+//!   *Disabled by default.*
+//! - `websocket`: Drive main loops over WebSocket text frames, see [`websocket`].
+//!   *Disabled by default.*
+//! - `workspace-scan`: Workspace-wide file scanner honoring `.gitignore`, see [`workspace_scan`].
+//!   *Disabled by default.*
 #![cfg_attr(docsrs, feature(doc_cfg))]
 #![warn(missing_docs)]
 use std::any::{type_name, Any, TypeId};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::future::{poll_fn, Future};
 use std::marker::PhantomData;
 use std::ops::ControlFlow;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
 use std::{fmt, io};
 
 use futures::channel::{mpsc, oneshot};
+use futures::future::{BoxFuture, Fuse, FusedFuture};
 use futures::io::BufReader;
 use futures::stream::FuturesUnordered;
 use futures::{
     pin_mut, select_biased, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite,
     AsyncWriteExt, FutureExt, SinkExt, StreamExt,
 };
-use lsp_types::notification::Notification;
+use lsp_types::notification::{self, Notification};
 use lsp_types::request::Request;
-use lsp_types::NumberOrString;
+use lsp_types::{NumberOrString, ProgressToken, WorkDoneProgress};
 use pin_project_lite::pin_project;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 use serde_json::Value as JsonValue;
 use thiserror::Error;
 use tower_service::Service;
@@ -106,15 +154,66 @@ macro_rules! define_getters {
     };
 }
 
+pub mod blocking;
+pub mod boxed;
+pub mod can_handle;
+pub mod chunk;
+pub mod client;
+pub mod clock;
+pub mod coalesce;
+pub mod codec;
+pub mod compat;
+pub mod completion;
 pub mod concurrency;
+pub mod config;
+pub mod diagnostics;
+pub mod downlevel;
+pub mod driver;
+pub mod files;
+pub mod flags;
+pub mod highlight;
+pub mod jobs;
+pub mod journal;
+pub mod locale;
+pub mod mux;
+pub mod outgoing;
 pub mod panic;
+pub mod pending;
+pub mod pipeline;
+pub mod progress;
+pub mod quickstart;
+pub mod quirks;
+pub mod record;
+pub mod registration;
+pub mod rename;
+pub mod replay;
+pub mod response_size;
 pub mod router;
 pub mod server;
+pub mod shedding;
+pub mod signature_help;
+pub mod status;
+pub mod syntax;
+pub mod timeout;
+pub mod vfs;
+pub mod workspace;
+
+#[cfg(feature = "ffi")]
+#[cfg_attr(docsrs, doc(cfg(feature = "ffi")))]
+pub mod ffi;
 
 #[cfg(feature = "forward")]
 #[cfg_attr(docsrs, doc(cfg(feature = "forward")))]
 mod forward;
 
+#[cfg(feature = "alloc-profile")]
+#[cfg_attr(docsrs, doc(cfg(feature = "alloc-profile")))]
+pub mod alloc_profile;
+
+#[cfg(feature = "client-log")]
+#[cfg_attr(docsrs, doc(cfg(feature = "client-log")))]
+pub mod client_log;
+
 #[cfg(feature = "client-monitor")]
 #[cfg_attr(docsrs, doc(cfg(feature = "client-monitor")))]
 pub mod client_monitor;
@@ -123,15 +222,57 @@ pub mod client_monitor;
 #[cfg_attr(docsrs, doc(cfg(all(feature = "stdio", unix))))]
 pub mod stdio;
 
+#[cfg(all(feature = "handoff", unix))]
+#[cfg_attr(docsrs, doc(cfg(all(feature = "handoff", unix))))]
+pub mod handoff;
+
+#[cfg(feature = "metrics")]
+#[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+pub mod metrics;
+
+#[cfg(feature = "net")]
+#[cfg_attr(docsrs, doc(cfg(feature = "net")))]
+pub mod net;
+
+#[cfg(feature = "forward")]
+#[cfg_attr(docsrs, doc(cfg(feature = "forward")))]
+pub mod proxy;
+
+#[cfg(feature = "testing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "testing")))]
+pub mod testing;
+
+#[cfg(feature = "text-document")]
+#[cfg_attr(docsrs, doc(cfg(feature = "text-document")))]
+pub mod text_document;
+
+#[cfg(feature = "text-document")]
+#[cfg_attr(docsrs, doc(cfg(feature = "text-document")))]
+pub mod transaction;
+
 #[cfg(feature = "tracing")]
 #[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
 pub mod tracing;
 
+#[cfg(feature = "tree-sitter")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tree-sitter")))]
+pub mod tree_sitter;
+
+#[cfg(feature = "websocket")]
+#[cfg_attr(docsrs, doc(cfg(feature = "websocket")))]
+pub mod websocket;
+
+#[cfg(feature = "workspace-scan")]
+#[cfg_attr(docsrs, doc(cfg(feature = "workspace-scan")))]
+pub mod workspace_scan;
+
 #[cfg(feature = "omni-trait")]
 mod omni_trait;
 #[cfg(feature = "omni-trait")]
 #[cfg_attr(docsrs, doc(cfg(feature = "omni-trait")))]
-pub use omni_trait::{LanguageClient, LanguageServer};
+pub use omni_trait::{
+    ContextualLanguageServer, LanguageClient, LanguageServer, ServerContext, WithClient,
+};
 
 /// A convenient type alias for `Result` with `E` = [`enum@crate::Error`].
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -152,12 +293,51 @@ pub enum Error {
     /// The peer violates the Language Server Protocol.
     #[error("protocol error: {0}")]
     Protocol(String),
-    /// Input/output errors from the underlying channels.
+    /// Input/output errors from the underlying channels, other than [`Error::UnexpectedEof`] and
+    /// [`Error::BrokenPipe`].
     #[error("{0}")]
-    Io(#[from] io::Error),
-    /// The underlying channel reached EOF (end of file).
+    Io(io::Error),
+    /// Writing to the underlying channel failed, leaving some messages unsent.
+    ///
+    /// Messages parked in the main loop are collected, so callers know which requests,
+    /// notifications and responses the peer never received.
+    #[error("{error} ({} messages unsent)", .unsent.len())]
+    Unsent {
+        /// The error from writing.
+        #[source]
+        error: Box<Error>,
+        /// The unsent messages, in the order they would be sent.
+        unsent: Vec<UnsentMessage>,
+    },
+    /// The underlying channel reached EOF (end of file) between messages.
+    ///
+    /// For Language Clients, it usually means the server exited by itself.
     #[error("the underlying channel reached EOF")]
     Eof,
+    /// The underlying channel reached EOF in the middle of a message.
+    ///
+    /// The peer likely crashed or got killed.
+    #[error("the underlying channel reached EOF unexpectedly: {0}")]
+    UnexpectedEof(io::Error),
+    /// The peer closed or reset the underlying channel, which is detected when writing to it.
+    #[error("the underlying channel is closed by the peer: {0}")]
+    BrokenPipe(io::Error),
+    /// The peer sends a message larger than the limit set by [`MainLoop::max_message_size`], or
+    /// a header part exceeding [`codec::MAX_HEADER_SIZE`] bytes or [`codec::MAX_HEADER_LINES`]
+    /// lines.
+    ///
+    /// The content is not read, thus the main loop cannot recover from it.
+    #[error("message of size {size} exceeds the limit of {limit}")]
+    MessageTooLarge {
+        /// The `Content-Length` of the message, or the number of bytes or lines of the header part
+        /// read so far.
+        size: usize,
+        /// The limit.
+        limit: usize,
+    },
+    /// A notification or event handler panicked, see [`panic::CatchUnwind`].
+    #[error("{0}")]
+    Panicked(String),
     /// No handlers for events or mandatory notifications (not starting with `$/`).
     ///
     /// Will not occur when catch-all handlers ([`router::Router::unhandled_event`] and
@@ -166,6 +346,44 @@ pub enum Error {
     Routing(String),
 }
 
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => Self::UnexpectedEof(err),
+            io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted => Self::BrokenPipe(err),
+            _ => Self::Io(err),
+        }
+    }
+}
+
+impl Error {
+    /// Get the underlying IO error, if any.
+    #[must_use]
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match self {
+            Self::Io(err) | Self::UnexpectedEof(err) | Self::BrokenPipe(err) => Some(err),
+            Self::Unsent { error, .. } => error.io_error(),
+            _ => None,
+        }
+    }
+
+    /// Check if the error is caused by the peer going away, ie. [`Error::Eof`],
+    /// [`Error::UnexpectedEof`] or [`Error::BrokenPipe`].
+    ///
+    /// Language Clients may restart the server on these errors, while other errors likely repeat
+    /// after a restart.
+    #[must_use]
+    pub fn is_disconnected(&self) -> bool {
+        match self {
+            Self::Eof | Self::UnexpectedEof(_) | Self::BrokenPipe(_) => true,
+            Self::Unsent { error, .. } => error.is_disconnected(),
+            _ => false,
+        }
+    }
+}
+
 /// The core service abstraction, representing either a Language Server or Language Client.
 pub trait LspService: Service<AnyRequest> {
     /// The handler of [LSP notifications](https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#notificationMessage).
@@ -182,18 +400,26 @@ pub trait LspService: Service<AnyRequest> {
     ///
     /// Events are emitted by users or middlewares via [`ClientSocket::emit`] or
     /// [`ServerSocket::emit`], for user-defined purposes. Events are delivered in order and
-    /// synchronously.
+    /// synchronously, unless their [`LspService::event_hints`] say otherwise.
     ///
     /// # Return
     ///
     /// The return value decides the action to either break or continue the main loop.
     fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>>;
+
+    /// Get the scheduling hints of an [`AnyEvent`] before it is emitted.
+    ///
+    /// The default implementation returns the default [`EventHints`], delivering events in order
+    /// as soon as possible. Middlewares should forward it to the inner service.
+    fn event_hints(&self, _event: &AnyEvent) -> EventHints {
+        EventHints::default()
+    }
 }
 
 /// A JSON-RPC error code.
 ///
 /// Codes defined and/or used by LSP are defined as associated constants, eg.
-/// [`ErrorCode::REQUEST_FAILED`].
+/// [`ErrorCode::REQUEST_FAILED`]. Use [`ErrorCode::kind`] to match them exhaustively.
 ///
 /// See:
 /// <https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#errorCodes>
@@ -300,6 +526,119 @@ impl ErrorCode {
     pub const LSP_RESERVED_ERROR_RANGE_END: Self = Self(-32800);
 }
 
+impl From<ErrorCode> for i32 {
+    fn from(code: ErrorCode) -> Self {
+        code.0
+    }
+}
+
+impl From<ErrorCodeKind> for ErrorCode {
+    fn from(kind: ErrorCodeKind) -> Self {
+        Self(kind.into())
+    }
+}
+
+impl ErrorCode {
+    /// Categorize this code.
+    #[must_use]
+    pub fn kind(self) -> ErrorCodeKind {
+        self.0.into()
+    }
+
+    /// Whether the same request may succeed if it is sent again, ie. the code is
+    /// [`ErrorCode::CONTENT_MODIFIED`] or [`ErrorCode::SERVER_CANCELLED`].
+    #[must_use]
+    pub fn is_retryable(self) -> bool {
+        self == Self::CONTENT_MODIFIED || self == Self::SERVER_CANCELLED
+    }
+
+    /// Whether the code is [`ErrorCode::REQUEST_CANCELLED`], ie. the request is cancelled by the
+    /// requester itself.
+    #[must_use]
+    pub fn is_request_cancelled(self) -> bool {
+        self == Self::REQUEST_CANCELLED
+    }
+}
+
+/// The category of an [`ErrorCode`].
+///
+/// It covers all codes defined by JSON-RPC and LSP, so that they can be matched exhaustively,
+/// and it is losslessly convertible from and into `i32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCodeKind {
+    /// [`ErrorCode::PARSE_ERROR`].
+    ParseError,
+    /// [`ErrorCode::INVALID_REQUEST`].
+    InvalidRequest,
+    /// [`ErrorCode::METHOD_NOT_FOUND`].
+    MethodNotFound,
+    /// [`ErrorCode::INVALID_PARAMS`].
+    InvalidParams,
+    /// [`ErrorCode::INTERNAL_ERROR`].
+    InternalError,
+    /// [`ErrorCode::SERVER_NOT_INITIALIZED`].
+    ServerNotInitialized,
+    /// [`ErrorCode::UNKNOWN_ERROR_CODE`].
+    UnknownErrorCode,
+    /// [`ErrorCode::REQUEST_FAILED`].
+    RequestFailed,
+    /// [`ErrorCode::SERVER_CANCELLED`].
+    ServerCancelled,
+    /// [`ErrorCode::CONTENT_MODIFIED`].
+    ContentModified,
+    /// [`ErrorCode::REQUEST_CANCELLED`].
+    RequestCancelled,
+    /// Other implementation-defined server errors in the JSON-RPC reserved range
+    /// `-32099..=-32000`.
+    ServerError(i32),
+    /// Any other code.
+    Unknown(i32),
+}
+
+impl From<i32> for ErrorCodeKind {
+    fn from(code: i32) -> Self {
+        match ErrorCode(code) {
+            ErrorCode::PARSE_ERROR => Self::ParseError,
+            ErrorCode::INVALID_REQUEST => Self::InvalidRequest,
+            ErrorCode::METHOD_NOT_FOUND => Self::MethodNotFound,
+            ErrorCode::INVALID_PARAMS => Self::InvalidParams,
+            ErrorCode::INTERNAL_ERROR => Self::InternalError,
+            ErrorCode::SERVER_NOT_INITIALIZED => Self::ServerNotInitialized,
+            ErrorCode::UNKNOWN_ERROR_CODE => Self::UnknownErrorCode,
+            ErrorCode::REQUEST_FAILED => Self::RequestFailed,
+            ErrorCode::SERVER_CANCELLED => Self::ServerCancelled,
+            ErrorCode::CONTENT_MODIFIED => Self::ContentModified,
+            ErrorCode::REQUEST_CANCELLED => Self::RequestCancelled,
+            _ if (ErrorCode::JSONRPC_RESERVED_ERROR_RANGE_START.0
+                ..=ErrorCode::JSONRPC_RESERVED_ERROR_RANGE_END.0)
+                .contains(&code) =>
+            {
+                Self::ServerError(code)
+            }
+            _ => Self::Unknown(code),
+        }
+    }
+}
+
+impl From<ErrorCodeKind> for i32 {
+    fn from(kind: ErrorCodeKind) -> Self {
+        match kind {
+            ErrorCodeKind::ParseError => ErrorCode::PARSE_ERROR.0,
+            ErrorCodeKind::InvalidRequest => ErrorCode::INVALID_REQUEST.0,
+            ErrorCodeKind::MethodNotFound => ErrorCode::METHOD_NOT_FOUND.0,
+            ErrorCodeKind::InvalidParams => ErrorCode::INVALID_PARAMS.0,
+            ErrorCodeKind::InternalError => ErrorCode::INTERNAL_ERROR.0,
+            ErrorCodeKind::ServerNotInitialized => ErrorCode::SERVER_NOT_INITIALIZED.0,
+            ErrorCodeKind::UnknownErrorCode => ErrorCode::UNKNOWN_ERROR_CODE.0,
+            ErrorCodeKind::RequestFailed => ErrorCode::REQUEST_FAILED.0,
+            ErrorCodeKind::ServerCancelled => ErrorCode::SERVER_CANCELLED.0,
+            ErrorCodeKind::ContentModified => ErrorCode::CONTENT_MODIFIED.0,
+            ErrorCodeKind::RequestCancelled => ErrorCode::REQUEST_CANCELLED.0,
+            ErrorCodeKind::ServerError(code) | ErrorCodeKind::Unknown(code) => code,
+        }
+    }
+}
+
 /// The identifier of requests and responses.
 ///
 /// Though `null` is technically a valid id for responses, we reject it since it hardly makes sense
@@ -348,6 +687,10 @@ pub struct AnyRequest {
     #[serde(default)]
     #[serde(skip_serializing_if = "serde_json::Value::is_null")]
     pub params: serde_json::Value,
+    /// When the request is received from the peer. It is `None` for requests constructed
+    /// locally.
+    #[serde(skip)]
+    pub receipt: Option<Receipt>,
 }
 
 /// A dynamic runtime [LSP notification](https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#notificationMessage).
@@ -360,6 +703,34 @@ pub struct AnyNotification {
     #[serde(default)]
     #[serde(skip_serializing_if = "serde_json::Value::is_null")]
     pub params: JsonValue,
+    /// When the notification is received from the peer. It is `None` for notifications
+    /// constructed locally.
+    #[serde(skip)]
+    pub receipt: Option<Receipt>,
+}
+
+/// The sequence number and timestamp of an incoming message, stamped by the main loop once the
+/// message is completely read.
+///
+/// Sequence numbers start from 0 and increase by one for each incoming request or notification,
+/// in the order they are read, across sessions of the same [`MainLoop`]. Messages in a batch
+/// share the same timestamp. Middlewares can measure the queueing delay of a message, ie. the
+/// time from its arrival to the start of its handler, via [`Receipt::elapsed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct Receipt {
+    /// The sequence number.
+    pub seq: u64,
+    /// The time when the message is received.
+    pub time: Instant,
+}
+
+impl Receipt {
+    /// Get the time elapsed since the message is received.
+    #[must_use]
+    pub fn elapsed(&self) -> Duration {
+        self.time.elapsed()
+    }
 }
 
 /// A dynamic runtime response.
@@ -371,6 +742,179 @@ struct AnyResponse {
     result: Option<JsonValue>,
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<ResponseError>,
+    /// The pre-serialized result from a [`RawResponse`], sent in place of `result`.
+    #[serde(
+        rename = "result",
+        skip_serializing_if = "Option::is_none",
+        skip_deserializing
+    )]
+    raw: Option<Box<RawValue>>,
+}
+
+/// A pre-serialized result of a request, which is sent to the peer as is.
+///
+/// Handlers can respond with cached results, or ones from another server, without deserializing
+/// and re-serializing them. It can be returned from handlers registered via
+/// [`Router::request_raw`](router::Router::request_raw), or as [`ResponseValue::Raw`] from a
+/// custom [`LspService`].
+#[derive(Debug, Clone)]
+pub struct RawResponse(Box<RawValue>);
+
+impl RawResponse {
+    /// Wrap `raw` as the result of request `R`.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if `raw` does not deserialize into `R::Result`.
+    #[must_use]
+    pub fn new<R: Request>(raw: Box<RawValue>) -> Self {
+        if cfg!(debug_assertions) {
+            if let Err(err) = serde_json::from_str::<R::Result>(raw.get()) {
+                panic!("Invalid raw response of {}: {err}", R::METHOD);
+            }
+        }
+        Self(raw)
+    }
+
+    /// Same as [`RawResponse::new`] but from a JSON string, eg. cached bytes.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `json` is not valid JSON.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if `json` does not deserialize into `R::Result`.
+    pub fn from_string<R: Request>(json: String) -> serde_json::Result<Self> {
+        Ok(Self::new::<R>(RawValue::from_string(json)?))
+    }
+
+    /// Get the pre-serialized result.
+    #[must_use]
+    pub fn get(&self) -> &RawValue {
+        &self.0
+    }
+}
+
+impl From<RawResponse> for JsonValue {
+    fn from(resp: RawResponse) -> Self {
+        serde_json::from_str(resp.0.get()).expect("Validated by RawValue")
+    }
+}
+
+/// The successful result of a request, responded by an [`LspService`].
+///
+/// Services responding [`JsonValue`]s are the common case. Services responding this type, eg. a
+/// [`Router`](router::Router) created via [`Router::new_raw`](router::Router::new_raw), can also
+/// respond with pre-serialized results.
+#[derive(Debug, Clone)]
+pub enum ResponseValue {
+    /// A result to be serialized.
+    Json(JsonValue),
+    /// A pre-serialized result, which is sent as is.
+    Raw(RawResponse),
+}
+
+impl From<JsonValue> for ResponseValue {
+    fn from(v: JsonValue) -> Self {
+        Self::Json(v)
+    }
+}
+
+impl From<RawResponse> for ResponseValue {
+    fn from(resp: RawResponse) -> Self {
+        Self::Raw(resp)
+    }
+}
+
+impl From<ResponseValue> for JsonValue {
+    fn from(v: ResponseValue) -> Self {
+        match v {
+            ResponseValue::Json(v) => v,
+            ResponseValue::Raw(resp) => resp.into(),
+        }
+    }
+}
+
+/// A progress notification of an outgoing request sent via `request_with_progress` of
+/// [`ClientSocket`] or [`ServerSocket`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum RequestProgress {
+    /// A [work done progress](https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#workDoneProgress)
+    /// reported via the `workDoneToken`.
+    WorkDone(WorkDoneProgress),
+    /// A batch of [partial results](https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#partialResults)
+    /// reported via the `partialResultToken`. Its type depends on the request.
+    PartialResult(JsonValue),
+}
+
+/// The state of a main loop after a graceful shutdown via `graceful_shutdown` of
+/// [`ClientSocket`] or [`ServerSocket`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ShutdownReport {
+    /// In-flight incoming requests which did not complete before the timeout. They are cancelled
+    /// and replied with [`ErrorCode::SERVER_CANCELLED`].
+    pub abandoned: Vec<RequestId>,
+    /// Incoming requests which are not dispatched since the shutdown started. They are replied
+    /// with [`ErrorCode::SERVER_CANCELLED`].
+    pub rejected: Vec<RequestId>,
+}
+
+/// The state of a main loop after closing via `close` of [`ClientSocket`] or [`ServerSocket`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CloseReport {
+    /// The number of queued outgoing messages flushed to the peer before the linger period ends.
+    pub flushed: usize,
+    /// Queued outgoing messages which are not flushed before the linger period ends, in the order
+    /// they would be sent.
+    pub dropped: Vec<UnsentMessage>,
+}
+
+/// How ids of outgoing requests forwarded via the [`tower_service::Service`] implementation of
+/// [`ClientSocket`] or [`ServerSocket`] are chosen, eg. by [`proxy::Proxy`](crate::proxy::Proxy).
+/// Requests sent via `request` of sockets always get generated ids.
+///
+/// Unless the policy is [`RequestIdPolicy::Remap`], the peer sees ids derived from the incoming
+/// ones, so the logs of both can be correlated. Forwarded `$/cancelRequest` notifications are
+/// translated to the ids sent to the peer under any policy, so cancellation passes through
+/// transparently. If a derived id collides with another pending outgoing request, eg. when
+/// requests from multiple editors are multiplexed, a generated id is used instead. If pending
+/// forwarded requests share an id, its cancellations go to the earliest one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RequestIdPolicy {
+    /// Replace ids with generated ones, remembering the mapping in a table. This is the default.
+    #[default]
+    Remap,
+    /// Preserve the ids of incoming requests.
+    Preserve,
+    /// Prepend a prefix to the ids of incoming requests, making them strings, eg.
+    /// `editor/42` for `42` with prefix `editor/`.
+    Prefix(String),
+}
+
+/// A message which is not sent to the peer, see [`Error::Unsent`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct UnsentMessage {
+    /// The id of the request or response, or `None` for notifications.
+    pub id: Option<RequestId>,
+    /// The method of the request or notification, or `None` for responses.
+    pub method: Option<String>,
+}
+
+impl UnsentMessage {
+    fn new(msg: &Message) -> Self {
+        let (id, method) = match msg {
+            Message::Request(req) => (Some(req.id.clone()), Some(req.method.clone())),
+            Message::Response(resp) => (Some(resp.id.clone()), None),
+            Message::Notification(notif) => (None, Some(notif.method.clone())),
+        };
+        Self { id, method }
+    }
 }
 
 /// The error object in case a request fails.
@@ -412,50 +956,82 @@ impl ResponseError {
     }
 }
 
-impl Message {
-    const CONTENT_LENGTH: &'static str = "Content-Length";
+/// The content of a base protocol message, which is either a single message or a
+/// [JSON-RPC batch](https://www.jsonrpc.org/specification#batch).
+#[derive(Debug)]
+enum Frame {
+    Single(Message),
+    Batch(Vec<Message>),
+}
 
-    async fn read(mut reader: impl AsyncBufRead + Unpin) -> Result<Self> {
-        let mut line = String::new();
-        let mut content_len = None;
+impl Frame {
+    async fn read(mut reader: impl AsyncBufRead + Unpin, max_size: Option<usize>) -> Result<Self> {
+        let mut line = Vec::new();
+        let mut headers = codec::Headers::default();
         loop {
             line.clear();
-            reader.read_line(&mut line).await?;
-            if line.is_empty() {
-                return Err(Error::Eof);
+            // Read one more byte than allowed to tell a too large header line from EOF.
+            let limit = headers.remaining() + 1;
+            (&mut reader)
+                .take(limit as u64)
+                .read_until(b'\n', &mut line)
+                .await?;
+            // Only the last line before EOF can be unterminated.
+            if !line.ends_with(b"\n") {
+                if line.len() == limit {
+                    return Err(codec::header_too_large(codec::MAX_HEADER_SIZE + 1));
+                }
+                if headers.is_empty() && line.iter().all(u8::is_ascii_whitespace) {
+                    return Err(Error::Eof);
+                }
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
             }
-            if line == "\r\n" {
+            if headers.feed_line(&line)? {
                 break;
             }
-            // NB. LSP spec is stricter than HTTP spec, the spaces here is required and it's not
-            // explicitly permitted to include extra spaces. We reject them here.
-            let (name, value) = line
-                .strip_suffix("\r\n")
-                .and_then(|line| line.split_once(": "))
-                .ok_or_else(|| Error::Protocol(format!("Invalid header: {line:?}")))?;
-            if name.eq_ignore_ascii_case(Self::CONTENT_LENGTH) {
-                let value = value
-                    .parse::<usize>()
-                    .map_err(|_| Error::Protocol(format!("Invalid content-length: {value}")))?;
-                content_len = Some(value);
-            }
-        }
-        let content_len =
-            content_len.ok_or_else(|| Error::Protocol("Missing content-length".into()))?;
+        }
+        let content_len = headers.content_length()?;
+        codec::check_size(content_len, max_size)?;
         let mut buf = vec![0u8; content_len];
         reader.read_exact(&mut buf).await?;
         #[cfg(feature = "tracing")]
         ::tracing::trace!(msg = %String::from_utf8_lossy(&buf), "incoming");
-        let msg = serde_json::from_slice::<RawMessage<Self>>(&buf)?;
-        Ok(msg.inner)
+        if buf.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'[') {
+            let msgs = serde_json::from_slice::<Vec<RawMessage<Message>>>(&buf)?;
+            return Ok(Self::Batch(msgs.into_iter().map(|msg| msg.inner).collect()));
+        }
+        let msg = serde_json::from_slice::<RawMessage<Message>>(&buf)?;
+        Ok(Self::Single(msg.inner))
+    }
+
+    fn unsent(&self) -> Vec<UnsentMessage> {
+        match self {
+            Self::Single(msg) => vec![UnsentMessage::new(msg)],
+            Self::Batch(msgs) => msgs.iter().map(UnsentMessage::new).collect(),
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let ret = match self {
+            Self::Single(msg) => serde_json::to_value(RawMessage::new(msg)),
+            Self::Batch(msgs) => {
+                serde_json::to_value(msgs.iter().map(RawMessage::new).collect::<Vec<_>>())
+            }
+        };
+        ret.expect("Failed to serialize")
     }
 
     async fn write(&self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
-        let buf = serde_json::to_string(&RawMessage::new(self))?;
+        let buf = match self {
+            Self::Single(msg) => serde_json::to_string(&RawMessage::new(msg))?,
+            Self::Batch(msgs) => {
+                serde_json::to_string(&msgs.iter().map(RawMessage::new).collect::<Vec<_>>())?
+            }
+        };
         #[cfg(feature = "tracing")]
         ::tracing::trace!(msg = %buf, "outgoing");
         writer
-            .write_all(format!("{}: {}\r\n\r\n", Self::CONTENT_LENGTH, buf.len()).as_bytes())
+            .write_all(format!("{}: {}\r\n\r\n", codec::CONTENT_LENGTH, buf.len()).as_bytes())
             .await?;
         writer.write_all(buf.as_bytes()).await?;
         writer.flush().await?;
@@ -467,22 +1043,102 @@ impl Message {
 pub struct MainLoop<S: LspService> {
     service: S,
     rx: mpsc::UnboundedReceiver<MainLoopEvent>,
-    outgoing_id: i32,
-    outgoing: HashMap<RequestId, oneshot::Sender<AnyResponse>>,
+    outgoing: outgoing::OutgoingRequests,
+    request_id_policy: RequestIdPolicy,
+    /// Progress tokens routed to outgoing requests, removed when the response arrives.
+    outgoing_progress: HashMap<RequestId, Vec<ProgressToken>>,
+    progress_routes: HashMap<ProgressToken, ProgressSender>,
     tasks: FuturesUnordered<RequestFuture<S::Future>>,
+    batch_responses: bool,
+    /// Incoming batches waiting for responses, and the batch ids of their requests.
+    batches: HashMap<u64, Batch>,
+    batch_ids: HashMap<RequestId, u64>,
+    next_batch_id: u64,
+    /// Outgoing messages to be sent before any other events.
+    queued: VecDeque<Message>,
+    shutdown: Option<Shutdown>,
+    closing: Option<Closing>,
+    /// Whether the main loop is closed, and can not run again.
+    closed: bool,
+    recorder: Option<Box<dyn record::Recorder>>,
+    write_retry: Option<WriteRetry>,
+    buffer_capacity: usize,
+    max_message_size: Option<usize>,
+    shedding: Option<shedding::SheddingPolicy>,
+    max_pending: usize,
+    message_budget: usize,
+    /// The sequence number of the next incoming request or notification.
+    next_seq: u64,
+    /// Events deferred by their hints, in order.
+    deferred: VecDeque<AnyEvent>,
+    deferred_low: VecDeque<AnyEvent>,
+}
+
+/// The default capacity of the input buffer, same as [`BufReader::new`].
+const DEFAULT_BUFFER_CAPACITY: usize = 8 << 10;
+
+/// The default number of messages and events handled by the main loop before yielding.
+const DEFAULT_MESSAGE_BUDGET: usize = 128;
+
+/// The default maximum number of incoming messages waiting for the service.
+const DEFAULT_MAX_PENDING: usize = 1024;
+
+/// The policy to retry transient write failures.
+#[derive(Clone)]
+struct WriteRetry {
+    retries: u32,
+    backoff: Duration,
+    clock: clock::SharedClock,
+}
+
+/// The state of an ongoing graceful shutdown.
+struct Shutdown {
+    timeout: Fuse<BoxFuture<'static, ()>>,
+    replies: Vec<oneshot::Sender<ShutdownReport>>,
+    report: ShutdownReport,
+}
+
+/// The state of an ongoing close.
+struct Closing {
+    linger: BoxFuture<'static, ()>,
+    replies: Vec<oneshot::Sender<CloseReport>>,
+}
+
+/// Responses of an incoming batch.
+struct Batch {
+    remaining: usize,
+    responses: Vec<Message>,
 }
 
+type ProgressSender = mpsc::UnboundedSender<(ProgressToken, JsonValue)>;
+
 enum MainLoopEvent {
     Outgoing(Message),
     OutgoingRequest(AnyRequest, oneshot::Sender<AnyResponse>),
+    /// An outgoing request whose id is chosen by the [`RequestIdPolicy`].
+    #[cfg_attr(not(feature = "forward"), allow(dead_code))]
+    ForwardRequest(AnyRequest, oneshot::Sender<AnyResponse>),
+    /// An outgoing request, with `$/progress` of the given tokens routed to the sender until the
+    /// response arrives.
+    OutgoingRequestWithProgress(
+        AnyRequest,
+        oneshot::Sender<AnyResponse>,
+        Vec<ProgressToken>,
+        ProgressSender,
+    ),
+    /// Some outgoing requests are given up by dropping their [`RequestHandle`]s.
+    CancelDropped,
     Any(AnyEvent),
+    Shutdown(BoxFuture<'static, ()>, oneshot::Sender<ShutdownReport>),
+    Close(BoxFuture<'static, ()>, oneshot::Sender<CloseReport>),
 }
 
 define_getters!(impl[S: LspService] MainLoop<S>, service: S);
 
 impl<S> MainLoop<S>
 where
-    S: LspService<Response = JsonValue>,
+    S: LspService,
+    S::Response: Into<ResponseValue>,
     ResponseError: From<S::Error>,
 {
     /// Create a Language Server main loop.
@@ -505,131 +1161,888 @@ where
         let this = Self {
             service: builder(socket.clone()),
             rx,
-            outgoing_id: 0,
-            outgoing: HashMap::new(),
+            outgoing: outgoing::OutgoingRequests::new(),
+            request_id_policy: RequestIdPolicy::default(),
+            outgoing_progress: HashMap::new(),
+            progress_routes: HashMap::new(),
             tasks: FuturesUnordered::new(),
+            batch_responses: false,
+            batches: HashMap::new(),
+            batch_ids: HashMap::new(),
+            next_batch_id: 0,
+            queued: VecDeque::new(),
+            shutdown: None,
+            closing: None,
+            closed: false,
+            recorder: None,
+            write_retry: None,
+            buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+            max_message_size: None,
+            shedding: None,
+            max_pending: DEFAULT_MAX_PENDING,
+            message_budget: DEFAULT_MESSAGE_BUDGET,
+            next_seq: 0,
+            deferred: VecDeque::new(),
+            deferred_low: VecDeque::new(),
         };
         (this, socket)
     }
 
+    /// Whether to reply responses of an incoming
+    /// [JSON-RPC batch](https://www.jsonrpc.org/specification#batch) in a batch. Default is
+    /// `false`.
+    ///
+    /// Incoming batches are always split and dispatched as individual messages in order. By
+    /// default, their responses are sent individually once available. If enabled, they are
+    /// collected and sent in a single batch after all of them complete.
+    #[must_use]
+    pub fn batch_responses(mut self, enabled: bool) -> Self {
+        self.batch_responses = enabled;
+        self
+    }
+
+    /// Record all incoming and outgoing messages to `recorder`. See [`record`] for details.
+    #[must_use]
+    pub fn recorder(mut self, recorder: impl record::Recorder) -> Self {
+        self.recorder = Some(Box::new(recorder));
+        self
+    }
+
+    /// Retry writing to the output at most `retries` times on transient failures, ie. errors of
+    /// kind [`io::ErrorKind::WouldBlock`], [`io::ErrorKind::Interrupted`] and
+    /// [`io::ErrorKind::TimedOut`]. Default is no retry.
+    ///
+    /// The delay before the first retry is `backoff`, and doubles on each subsequent retry. Since
+    /// this crate is runtime-agnostic, the [`Clock`](clock::Clock) should be provided, eg. the
+    /// timer function `tokio::time::sleep`.
+    ///
+    /// When writing fails permanently, the main loop returns [`Error::Unsent`] carrying all
+    /// unsent messages.
+    #[must_use]
+    pub fn write_retry(
+        mut self,
+        retries: u32,
+        backoff: Duration,
+        clock: impl clock::Clock + 'static,
+    ) -> Self {
+        self.write_retry = Some(WriteRetry {
+            retries,
+            backoff,
+            clock: Arc::new(clock),
+        });
+        self
+    }
+
+    /// Set how ids of forwarded outgoing requests are chosen. Default is
+    /// [`RequestIdPolicy::Remap`]. See [`RequestIdPolicy`] for details.
+    #[must_use]
+    pub fn request_id_policy(mut self, policy: RequestIdPolicy) -> Self {
+        self.request_id_policy = policy;
+        self
+    }
+
+    /// Set the capacity of the input buffer in bytes, used by [`MainLoop::run_buffered`] and
+    /// other runners wrapping the input in a [`BufReader`]. Default is 8 KiB.
+    ///
+    /// A larger buffer reduces read calls for clients sending large messages, eg. whole documents
+    /// in `textDocument/didOpen`. Messages larger than the buffer are still supported.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    #[must_use]
+    pub fn buffer_capacity(mut self, capacity: usize) -> Self {
+        assert_ne!(capacity, 0, "zero buffer capacity");
+        self.buffer_capacity = capacity;
+        self
+    }
+
+    /// Set the number of messages and events handled by the main loop in a row before yielding to
+    /// the async runtime. Default is 128.
+    ///
+    /// Under sustained floods, eg. notifications of file changes during a branch switch, every
+    /// message may be immediately available, and the main loop would never yield otherwise. This
+    /// starves other tasks on the same thread, and the timers and I/O driven by the runtime. A
+    /// smaller budget improves their latency at the cost of throughput.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `budget` is zero.
+    #[must_use]
+    pub fn message_budget(mut self, budget: usize) -> Self {
+        assert_ne!(budget, 0, "zero message budget");
+        self.message_budget = budget;
+        self
+    }
+
+    /// Reject incoming messages whose `Content-Length` exceeds `bytes`, failing the main loop with
+    /// [`Error::MessageTooLarge`] before allocating any buffer for them. Default is no limit.
+    ///
+    /// This protects servers exposed over sockets from malicious peers claiming huge messages.
+    /// No error response is sent, since the request id is inside the rejected content.
+    #[must_use]
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.max_message_size = Some(bytes);
+        self
+    }
+
+    /// Set the maximum number of incoming requests and notifications waiting for the service to be
+    /// ready. Default is 1024.
+    ///
+    /// While the service is busy, the main loop keeps reading, so that responses of its own
+    /// requests to the peer, `$/progress` of them, and `$/cancelRequest` of waiting requests are
+    /// handled without waiting for the service. Other messages are queued, and once the queue is
+    /// full, the main loop stops reading input until the service catches up. This applies back
+    /// pressure to the peer instead of buffering a flood in memory. Note that responses behind the
+    /// queue are not read either in this case, thus a service waiting for them without being ready
+    /// would deadlock.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `messages` is zero.
+    #[must_use]
+    pub fn max_pending(mut self, messages: usize) -> Self {
+        assert_ne!(messages, 0, "zero max pending messages");
+        self.max_pending = messages;
+        self
+    }
+
+    /// Shed or coalesce incoming notifications by `policy` when the main loop falls behind.
+    /// Default is to dispatch all of them. See [`shedding`] for details.
+    #[must_use]
+    pub fn shedding(mut self, policy: shedding::SheddingPolicy) -> Self {
+        self.shedding = Some(policy);
+        self
+    }
+
     /// Drive the service main loop to provide the service.
     ///
     /// Shortcut to [`MainLoop::run`] that accept an `impl AsyncRead` and implicit wrap it in a
-    /// [`BufReader`].
+    /// [`BufReader`] of capacity set by [`MainLoop::buffer_capacity`].
     // Documented in `Self::run`.
     #[allow(clippy::missing_errors_doc)]
     pub async fn run_buffered(self, input: impl AsyncRead, output: impl AsyncWrite) -> Result<()> {
-        self.run(BufReader::new(input), output).await
+        let input = BufReader::with_capacity(self.buffer_capacity, input);
+        self.run(input, output).await
     }
 
     /// Drive the service main loop to provide the service.
     ///
     /// # Errors
     ///
-    /// - `Error::Io` when the underlying `input` or `output` raises an error.
+    /// - `Error::Eof` when `input` reaches EOF between messages.
+    /// - `Error::UnexpectedEof` when `input` reaches EOF in the middle of a message.
+    /// - `Error::BrokenPipe` when the peer closes or resets `output`.
+    /// - `Error::Io` when the underlying `input` or `output` raises other errors.
+    /// - `Error::Unsent` wrapping one of the errors above when writing to `output` fails.
+    /// - `Error::MessageTooLarge` when the peer sends a message exceeding
+    ///   [`MainLoop::max_message_size`].
     /// - `Error::Deserialize` when the peer sends undecodable or invalid message.
     /// - `Error::Protocol` when the peer violates Language Server Protocol.
+    /// - `Error::ServiceStopped` when the main loop is already closed via `close` of
+    ///   [`ClientSocket`] or [`ServerSocket`].
     /// - Other errors raised from service handlers.
     pub async fn run(mut self, input: impl AsyncBufRead, output: impl AsyncWrite) -> Result<()> {
+        self.run_session(input, output).await
+    }
+
+    /// Drive the service main loop on a single connection, keeping the service for later ones.
+    ///
+    /// This allows a service to be reused across connections, eg. when an editor reconnects to a
+    /// socket-based server, see [`Lifecycle::restartable`][crate::server::Lifecycle::restartable].
+    /// Requests sent to the peer and incoming requests still in flight when the previous session
+    /// ended are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`MainLoop::run`].
+    pub async fn run_session(
+        &mut self,
+        input: impl AsyncBufRead,
+        output: impl AsyncWrite,
+    ) -> Result<()> {
+        if self.closed {
+            return Err(Error::ServiceStopped);
+        }
+        self.reset_session();
+        let output = RetryWriter {
+            inner: output,
+            retry: self.write_retry.clone(),
+            attempts: 0,
+            sleep: None,
+        };
         pin_mut!(input, output);
-        let incoming = futures::stream::unfold(input, |mut input| async move {
-            Some((Message::read(&mut input).await, input))
+        let max_size = self.max_message_size;
+        let incoming = futures::stream::unfold(input, move |mut input| async move {
+            let frame = Frame::read(&mut input, max_size).await;
+            Some((frame.map(|frame| (frame, Instant::now())), input))
         });
-        let outgoing = futures::sink::unfold(output, |mut output, msg| async move {
-            Message::write(&msg, &mut output).await.map(|()| output)
+        let outgoing = futures::sink::unfold(output, |mut output, frame| async move {
+            Frame::write(&frame, &mut output).await.map(|()| output)
         });
         pin_mut!(incoming, outgoing);
 
+        // Incoming messages waiting for the service to be ready, in order.
+        let mut pending = VecDeque::new();
         let mut flush_fut = futures::future::Fuse::terminated();
+        // Messages being written, which are unsent yet if writing fails.
+        let mut in_flight = Vec::new();
+        // The input error encountered when reading ahead.
+        let mut read_error = None;
+        let mut budget = self.message_budget;
         let ret = loop {
+            if budget == 0 {
+                yield_now().await;
+                budget = self.message_budget;
+            }
+            budget -= 1;
+
             // Outgoing > internal > incoming.
-            // Preference on outgoing data provides back pressure in case of
-            // flooding incoming requests.
-            let ctl = select_biased! {
-                // Concurrently flush out the previous message.
-                ret = flush_fut => { ret?; continue; }
-
-                resp = self.tasks.select_next_some() => ControlFlow::Continue(Some(Message::Response(resp))),
-                event = self.rx.next() => self.dispatch_event(event.expect("Sender is alive")),
-                msg = incoming.next() => {
-                    let dispatch_fut = self.dispatch_message(msg.expect("Never ends")?).fuse();
-                    pin_mut!(dispatch_fut);
-                    // NB. Concurrently wait for `poll_ready`, and write out the last message.
-                    // If the service is waiting for client's response of the last request, while
-                    // the last message is not delivered on the first write, it can deadlock.
-                    loop {
-                        select_biased! {
-                            // Dispatch first. It usually succeeds immediately for non-requests,
-                            // and the service is hardly busy.
-                            ctl = dispatch_fut => break ctl,
-                            ret = flush_fut => { ret?; continue }
+            // Preference on outgoing data keeps responses flowing under floods of incoming
+            // messages. Back pressure is applied by bounding `pending`, see `max_pending`.
+            let shutting_down = self.shutdown.is_some();
+            let ctl = if let Some(msg) = self.queued.pop_front() {
+                ControlFlow::Continue(Some(msg))
+            } else if shutting_down
+                && self.tasks.is_empty()
+                && pending.is_empty()
+                && flush_fut.is_terminated()
+            {
+                // All done. Drain events queued before the last request completed, then deferred
+                // ones.
+                if let Ok(Some(event)) = self.rx.try_next() {
+                    self.dispatch_event(event)
+                } else if let Some(event) = self.deferred.pop_front() {
+                    self.emit_deferred(event)
+                } else if let Some(event) = self.deferred_low.pop_front() {
+                    self.emit_deferred(event)
+                } else {
+                    break Ok(());
+                }
+            } else {
+                let pending_full = pending.len() >= self.max_pending;
+                select_biased! {
+                    // Concurrently flush out the previous message.
+                    ret = flush_fut => {
+                        match ret {
+                            Ok(()) => in_flight.clear(),
+                            Err(err) => return Err(self.park_unsent(err, in_flight)),
+                        }
+                        continue;
+                    }
+
+                    // Internal events before responses, so that notifications queued by a handler
+                    // before it completes are sent before its response.
+                    event = self.rx.next() => self.dispatch_event(event.expect("Sender is alive")),
+                    // Deferred events are only dispatched after all queued ones are received,
+                    // thus coalescing events have their latest values.
+                    event = Self::next_deferred(&mut self.deferred) => self.emit_deferred(event),
+                    resp = self.tasks.select_next_some() => ControlFlow::Continue(Some(Message::Response(resp))),
+                    () = poll_fn(|cx| match &mut self.shutdown {
+                        Some(shutdown) => shutdown.timeout.poll_unpin(cx),
+                        None => Poll::Pending,
+                    }).fuse() => {
+                        self.abandon_tasks();
+                        ControlFlow::Continue(None)
+                    }
+                    (msg, ready) = poll_fn(|cx| Self::poll_pending(&mut self.service, &mut pending, shutting_down, cx)).fuse() => {
+                        self.dispatch_message(msg, ready)
+                    }
+                    // NB. Keep reading while the service is busy. If the service is waiting for
+                    // responses of its own requests to the peer, it would deadlock otherwise.
+                    // Requests are queued in `pending` instead, and the concurrency limit, if any,
+                    // is still enforced by `poll_ready`. Reading stops when `pending` is full, and
+                    // resumes in a later iteration after it is popped.
+                    frame = poll_fn(|cx| match read_error.take() {
+                        Some(err) => Poll::Ready(Some(Err(err))),
+                        None if pending_full => Poll::Pending,
+                        None => incoming.poll_next_unpin(cx),
+                    }).fuse() => {
+                        let (frame, time) = frame.expect("Never ends")?;
+                        self.receive(frame, time, &mut pending);
+                        // Read ahead messages already available, so that floods can be shed or
+                        // coalesced before dispatching. Errors are delayed after queued messages.
+                        let read_ahead = self.shedding.as_ref().map_or(0, |policy| policy.read_ahead);
+                        while pending.len() < read_ahead.min(self.max_pending) {
+                            match incoming.next().now_or_never().map(|frame| frame.expect("Never ends")) {
+                                Some(Ok((frame, time))) => self.receive(frame, time, &mut pending),
+                                Some(Err(err)) => {
+                                    read_error = Some(err);
+                                    break;
+                                }
+                                None => break,
+                            }
                         }
+                        ControlFlow::Continue(None)
                     }
+                    event = Self::next_deferred(&mut self.deferred_low) => self.emit_deferred(event),
                 }
             };
-            let msg = match ctl {
-                ControlFlow::Continue(Some(msg)) => msg,
+            let frame = match ctl {
+                ControlFlow::Continue(Some(msg)) => match self.collect_batch(msg) {
+                    Some(frame) => frame,
+                    None => continue,
+                },
                 ControlFlow::Continue(None) => continue,
                 ControlFlow::Break(ret) => break ret,
             };
+            self.record(record::Direction::Outgoing, &frame);
             // Flush the previous one and load a new message to send.
-            outgoing.feed(msg).await?;
+            let unsent = frame.unsent();
+            if let Err(err) = outgoing.feed(frame).await {
+                in_flight.extend(unsent);
+                return Err(self.park_unsent(err, in_flight));
+            }
+            in_flight = unsent;
             flush_fut = outgoing.flush().fuse();
         };
 
+        if let (Ok(()), Some(closing)) = (&ret, self.closing.take()) {
+            return self.linger(closing, outgoing, in_flight).await;
+        }
+
         // Flush the last message. It is enqueued before the event returning `ControlFlow::Break`.
         // To preserve the order at best effort, we send it before exiting the main loop.
         // But the more significant `ControlFlow::Break` error will override the flushing error,
         // if there is any.
-        let flush_ret = outgoing.close().await;
-        ret.and(flush_ret)
+        let flush_ret = match outgoing.close().await {
+            Ok(()) => Ok(()),
+            Err(err) => Err(self.park_unsent(err, in_flight)),
+        };
+        let ret = ret.and(flush_ret);
+        if let (Ok(()), Some(shutdown)) = (&ret, self.shutdown.take()) {
+            for reply in shutdown.replies {
+                // The result may be ignored.
+                let _: Result<_, _> = reply.send(shutdown.report.clone());
+            }
+        }
+        ret
     }
 
-    async fn dispatch_message(&mut self, msg: Message) -> ControlFlow<Result<()>, Option<Message>> {
-        match msg {
-            Message::Request(req) => {
-                if let Err(err) = poll_fn(|cx| self.service.poll_ready(cx)).await {
-                    let resp = AnyResponse {
-                        id: req.id,
-                        result: None,
-                        error: Some(err.into()),
-                    };
-                    return ControlFlow::Continue(Some(Message::Response(resp)));
-                }
-                let id = req.id.clone();
-                let fut = self.service.call(req);
-                self.tasks.push(RequestFuture { fut, id: Some(id) });
-            }
-            Message::Response(resp) => {
-                if let Some(resp_tx) = self.outgoing.remove(&resp.id) {
-                    // The result may be ignored.
-                    let _: Result<_, _> = resp_tx.send(resp);
-                }
-            }
-            Message::Notification(notif) => {
-                self.service.notify(notif)?;
+    fn record(&mut self, direction: record::Direction, frame: &Frame) {
+        if let Some(recorder) = &mut self.recorder {
+            let entry = record::RecordEntry::new(direction, frame.to_json());
+            if let Err(_err) = recorder.record(&entry) {
+                #[cfg(feature = "tracing")]
+                ::tracing::warn!("Failed to record message: {_err}");
             }
         }
+    }
+
+    fn next_deferred(queue: &mut VecDeque<AnyEvent>) -> impl FusedFuture<Output = AnyEvent> + '_ {
+        // The queue only changes in the main loop, so there is nothing to wake.
+        poll_fn(|_| queue.pop_front().map_or(Poll::Pending, Poll::Ready)).fuse()
+    }
+
+    fn emit_deferred(&mut self, event: AnyEvent) -> ControlFlow<Result<()>, Option<Message>> {
+        self.service.emit(event)?;
         ControlFlow::Continue(None)
     }
 
-    fn dispatch_event(&mut self, event: MainLoopEvent) -> ControlFlow<Result<()>, Option<Message>> {
-        match event {
-            MainLoopEvent::OutgoingRequest(mut req, resp_tx) => {
-                req.id = RequestId::Number(self.outgoing_id);
-                assert!(self.outgoing.insert(req.id.clone(), resp_tx).is_none());
-                self.outgoing_id += 1;
-                ControlFlow::Continue(Some(Message::Request(req)))
+    /// Collect unsent messages after a write failure, including `in_flight` ones failing to be
+    /// written, and wrap them into [`Error::Unsent`].
+    fn park_unsent(&mut self, error: Error, mut unsent: Vec<UnsentMessage>) -> Error {
+        unsent.extend(self.queued.drain(..).map(|msg| UnsentMessage::new(&msg)));
+        while let Ok(Some(event)) = self.rx.try_next() {
+            match event {
+                MainLoopEvent::Outgoing(msg) => unsent.push(UnsentMessage::new(&msg)),
+                MainLoopEvent::OutgoingRequest(req, _)
+                | MainLoopEvent::ForwardRequest(req, _)
+                | MainLoopEvent::OutgoingRequestWithProgress(req, ..) => {
+                    unsent.push(UnsentMessage::new(&Message::Request(req)));
+                }
+                MainLoopEvent::CancelDropped
+                | MainLoopEvent::Any(_)
+                | MainLoopEvent::Shutdown(..)
+                | MainLoopEvent::Close(..) => {}
+            }
+        }
+        Error::Unsent {
+            error: Box::new(error),
+            unsent,
+        }
+    }
+
+    /// Drop states bound to the previous connection, if any.
+    fn reset_session(&mut self) {
+        self.outgoing.clear();
+        self.outgoing_progress.clear();
+        self.progress_routes.clear();
+        self.tasks = FuturesUnordered::new();
+        self.batches.clear();
+        self.batch_ids.clear();
+        self.queued.clear();
+    }
+
+    /// Cancel all in-flight requests on shutdown timeout, and queue their error responses.
+    fn abandon_tasks(&mut self) {
+        let shutdown = self.shutdown.as_mut().expect("Shutting down");
+        shutdown.timeout = Fuse::terminated();
+        let ids = Pin::new(&self.tasks)
+            .iter_pin_ref()
+            .filter_map(|fut| fut.project_ref().id.clone())
+            .collect::<Vec<_>>();
+        self.tasks = FuturesUnordered::new();
+        for id in ids {
+            self.queued
+                .push_back(Message::Response(shutdown_response(id.clone())));
+            shutdown.report.abandoned.push(id);
+        }
+    }
+
+    /// Track requests of an incoming batch, if responses should be batched.
+    fn track_batch(&mut self, msgs: &[Message]) {
+        if !self.batch_responses {
+            return;
+        }
+        let ids = msgs
+            .iter()
+            .filter_map(|msg| match msg {
+                Message::Request(req) => Some(req.id.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if ids.is_empty() {
+            return;
+        }
+        let batch_id = self.next_batch_id;
+        self.next_batch_id += 1;
+        self.batches.insert(
+            batch_id,
+            Batch {
+                remaining: ids.len(),
+                responses: Vec::with_capacity(ids.len()),
+            },
+        );
+        for id in ids {
+            self.batch_ids.insert(id, batch_id);
+        }
+    }
+
+    /// Collect an outgoing response into its batch, if any. Returns the frame to send, if any.
+    fn collect_batch(&mut self, msg: Message) -> Option<Frame> {
+        let batch_id = match &msg {
+            Message::Response(resp) => match self.batch_ids.remove(&resp.id) {
+                Some(batch_id) => batch_id,
+                None => return Some(Frame::Single(msg)),
+            },
+            _ => return Some(Frame::Single(msg)),
+        };
+        let batch = self.batches.get_mut(&batch_id).expect("Tracked");
+        batch.responses.push(msg);
+        batch.remaining -= 1;
+        if batch.remaining != 0 {
+            return None;
+        }
+        let batch = self.batches.remove(&batch_id).expect("Tracked");
+        Some(Frame::Batch(batch.responses))
+    }
+
+    /// Pop the first pending message when it can be dispatched, that is, the service is ready
+    /// if it is a request.
+    /// When shutting down, requests are popped without waiting for the service, since they are
+    /// rejected anyway.
+    fn poll_pending(
+        service: &mut S,
+        pending: &mut VecDeque<Message>,
+        shutting_down: bool,
+        cx: &mut Context<'_>,
+    ) -> Poll<(Message, Result<(), S::Error>)> {
+        let ready = match pending.front() {
+            None => return Poll::Pending,
+            Some(Message::Request(_)) if !shutting_down => ready!(service.poll_ready(cx)),
+            Some(_) => Ok(()),
+        };
+        Poll::Ready((pending.pop_front().expect("Checked"), ready))
+    }
+
+    /// Stamp incoming requests and notifications with their [`Receipt`]s.
+    /// Queue messages of an incoming frame received at `time`.
+    fn receive(&mut self, frame: Frame, time: Instant, pending: &mut VecDeque<Message>) {
+        self.record(record::Direction::Incoming, &frame);
+        let mut msgs = match frame {
+            Frame::Single(msg) => vec![msg],
+            Frame::Batch(msgs) => {
+                self.track_batch(&msgs);
+                msgs
+            }
+        };
+        self.stamp(&mut msgs, time);
+        for msg in msgs {
+            let msg = match self.route_incoming(msg) {
+                Some(msg) => msg,
+                None => continue,
+            };
+            if let Some(resp) = Self::cancel_pending(pending, &msg) {
+                self.queued.push_back(Message::Response(resp));
+                continue;
+            }
+            match &self.shedding {
+                Some(policy) => policy.push(pending, msg),
+                None => pending.push_back(msg),
+            }
+        }
+    }
+
+    /// Handle `$/cancelRequest` of a request still waiting in `pending`, which is removed and
+    /// responded with [`ErrorCode::REQUEST_CANCELLED`] without reaching the service.
+    fn cancel_pending(pending: &mut VecDeque<Message>, msg: &Message) -> Option<AnyResponse> {
+        let id = match msg {
+            Message::Notification(notif) if notif.method == notification::Cancel::METHOD => notif
+                .params
+                .get("id")
+                .and_then(|id| RequestId::deserialize(id).ok()),
+            _ => None,
+        };
+        let pos = id.and_then(|id| {
+            pending
+                .iter()
+                .position(|msg| matches!(msg, Message::Request(req) if req.id == id))
+        });
+        match pos.and_then(|pos| pending.remove(pos)) {
+            Some(Message::Request(req)) => Some(AnyResponse {
+                id: req.id,
+                result: None,
+                error: Some(ResponseError::new(
+                    ErrorCode::REQUEST_CANCELLED,
+                    "Request cancelled before dispatching",
+                )),
+                raw: None,
+            }),
+            _ => None,
+        }
+    }
+
+    fn stamp(&mut self, msgs: &mut [Message], time: Instant) {
+        for msg in msgs {
+            let receipt = match msg {
+                Message::Request(req) => &mut req.receipt,
+                Message::Notification(notif) => &mut notif.receipt,
+                Message::Response(_) => continue,
+            };
+            *receipt = Some(Receipt {
+                seq: self.next_seq,
+                time,
+            });
+            self.next_seq += 1;
+        }
+    }
+
+    /// Handle responses and routed progress notifications, which never go to the service.
+    /// Other messages are returned.
+    fn route_incoming(&mut self, msg: Message) -> Option<Message> {
+        match msg {
+            Message::Response(resp) => {
+                for token in self.outgoing_progress.remove(&resp.id).unwrap_or_default() {
+                    self.progress_routes.remove(&token);
+                }
+                // Responses of unknown requests are ignored.
+                let _: Option<_> = self.outgoing.complete_response(resp);
+                None
+            }
+            Message::Notification(notif)
+                if notif.method == notification::Progress::METHOD
+                    && !self.progress_routes.is_empty() =>
+            {
+                let route = notif
+                    .params
+                    .get("token")
+                    .and_then(|token| ProgressToken::deserialize(token).ok())
+                    .and_then(|token| Some((self.progress_routes.get(&token)?, token)));
+                match route {
+                    Some((tx, token)) => {
+                        let value = notif.params.get("value").cloned().unwrap_or_default();
+                        // The result may be ignored.
+                        let _: Result<_, _> = tx.unbounded_send((token, value));
+                        None
+                    }
+                    None => Some(Message::Notification(notif)),
+                }
+            }
+            msg => Some(msg),
+        }
+    }
+
+    fn dispatch_message(
+        &mut self,
+        msg: Message,
+        ready: Result<(), S::Error>,
+    ) -> ControlFlow<Result<()>, Option<Message>> {
+        match msg {
+            Message::Request(req) => {
+                if let Some(shutdown) = &mut self.shutdown {
+                    shutdown.report.rejected.push(req.id.clone());
+                    let resp = shutdown_response(req.id);
+                    return ControlFlow::Continue(Some(Message::Response(resp)));
+                }
+                if let Err(err) = ready {
+                    let resp = AnyResponse {
+                        id: req.id,
+                        result: None,
+                        error: Some(err.into()),
+                        raw: None,
+                    };
+                    return ControlFlow::Continue(Some(Message::Response(resp)));
+                }
+                let id = req.id.clone();
+                let fut = self.service.call(req);
+                self.tasks.push(RequestFuture::new(fut, id));
+            }
+            Message::Notification(notif) => self.service.notify(notif)?,
+            // Already routed in `route_incoming`.
+            Message::Response(_) => {}
+        }
+        ControlFlow::Continue(None)
+    }
+
+    fn dispatch_event(&mut self, event: MainLoopEvent) -> ControlFlow<Result<()>, Option<Message>> {
+        match event {
+            MainLoopEvent::OutgoingRequest(mut req, resp_tx) => {
+                self.outgoing.register_with(&mut req, resp_tx);
+                ControlFlow::Continue(Some(Message::Request(req)))
+            }
+            MainLoopEvent::ForwardRequest(mut req, resp_tx) => {
+                let id = match &self.request_id_policy {
+                    RequestIdPolicy::Remap => None,
+                    RequestIdPolicy::Preserve => Some(req.id.clone()),
+                    RequestIdPolicy::Prefix(prefix) => Some(RequestId::String(match &req.id {
+                        RequestId::Number(id) => format!("{prefix}{id}"),
+                        RequestId::String(id) => format!("{prefix}{id}"),
+                    })),
+                };
+                self.outgoing.forward_with(&mut req, id, resp_tx);
+                ControlFlow::Continue(Some(Message::Request(req)))
+            }
+            MainLoopEvent::OutgoingRequestWithProgress(mut req, resp_tx, tokens, progress_tx) => {
+                for token in &tokens {
+                    self.progress_routes
+                        .insert(token.clone(), progress_tx.clone());
+                }
+                self.outgoing.register_with(&mut req, resp_tx);
+                self.outgoing_progress.insert(req.id.clone(), tokens);
+                ControlFlow::Continue(Some(Message::Request(req)))
+            }
+            MainLoopEvent::Outgoing(Message::Notification(mut notif))
+                if notif.method == notification::Cancel::METHOD =>
+            {
+                // Translate cancellations of forwarded requests.
+                let id = notif
+                    .params
+                    .get("id")
+                    .and_then(|id| RequestId::deserialize(id).ok())
+                    .and_then(|id| self.outgoing.forwarded_id(&id));
+                if let Some(id) = id {
+                    notif.params["id"] = serde_json::to_value(id).expect("Failed to serialize");
+                }
+                ControlFlow::Continue(Some(Message::Notification(notif)))
             }
             MainLoopEvent::Outgoing(msg) => ControlFlow::Continue(Some(msg)),
+            MainLoopEvent::CancelDropped => {
+                for id in self.outgoing.dropped() {
+                    for token in self.outgoing_progress.remove(&id).unwrap_or_default() {
+                        self.progress_routes.remove(&token);
+                    }
+                    if let Some(notif) = self.outgoing.cancel(&id) {
+                        self.queued.push_back(Message::Notification(notif));
+                    }
+                }
+                ControlFlow::Continue(None)
+            }
             MainLoopEvent::Any(event) => {
-                self.service.emit(event)?;
+                let hints = self.service.event_hints(&event);
+                if !hints.coalesce && hints.priority == EventPriority::Normal {
+                    self.service.emit(event)?;
+                    return ControlFlow::Continue(None);
+                }
+                let queue = match hints.priority {
+                    EventPriority::Normal => &mut self.deferred,
+                    EventPriority::Low => &mut self.deferred_low,
+                };
+                if hints.coalesce {
+                    let type_id = event.inner_type_id();
+                    queue.retain(|prev| prev.inner_type_id() != type_id);
+                }
+                queue.push_back(event);
+                ControlFlow::Continue(None)
+            }
+            MainLoopEvent::Shutdown(timeout, reply) => {
+                match &mut self.shutdown {
+                    Some(shutdown) => shutdown.replies.push(reply),
+                    None => {
+                        self.shutdown = Some(Shutdown {
+                            timeout: timeout.fuse(),
+                            replies: vec![reply],
+                            report: ShutdownReport::default(),
+                        });
+                    }
+                }
                 ControlFlow::Continue(None)
             }
+            MainLoopEvent::Close(linger, reply) => {
+                self.closing = Some(Closing {
+                    linger,
+                    replies: vec![reply],
+                });
+                ControlFlow::Break(Ok(()))
+            }
+        }
+    }
+
+    /// Flush queued outgoing messages until the linger period ends, after a close request.
+    async fn linger(
+        &mut self,
+        closing: Closing,
+        mut outgoing: Pin<&mut impl futures::Sink<Frame, Error = Error>>,
+        in_flight: Vec<UnsentMessage>,
+    ) -> Result<()> {
+        self.closed = true;
+        let mut replies = closing.replies;
+        let mut msgs = self.queued.drain(..).collect::<Vec<_>>();
+        // No more events can be sent since now, so this drains all the remaining ones.
+        self.rx.close();
+        while let Ok(Some(event)) = self.rx.try_next() {
+            match event {
+                MainLoopEvent::Close(_, reply) => replies.push(reply),
+                MainLoopEvent::Any(_) | MainLoopEvent::Shutdown(..) => {}
+                event => {
+                    if let ControlFlow::Continue(Some(msg)) = self.dispatch_event(event) {
+                        msgs.push(msg);
+                    }
+                }
+            }
+        }
+
+        // Messages are flushed one by one, so that we know which ones are sent when the linger
+        // period ends.
+        let in_flight_len = in_flight.len();
+        let mut unsent = in_flight;
+        unsent.extend(msgs.iter().map(UnsentMessage::new));
+        let mut flushed = 0;
+        let ret = {
+            let write = async {
+                outgoing.flush().await?;
+                flushed = in_flight_len;
+                for msg in msgs {
+                    let frame = Frame::Single(msg);
+                    self.record(record::Direction::Outgoing, &frame);
+                    outgoing.feed(frame).await?;
+                    outgoing.flush().await?;
+                    flushed += 1;
+                }
+                outgoing.close().await
+            }
+            .fuse();
+            pin_mut!(write);
+            let mut linger = closing.linger.fuse();
+            select_biased! {
+                ret = write => ret,
+                () = linger => Ok(()),
+            }
+        };
+
+        let report = CloseReport {
+            flushed,
+            dropped: unsent.split_off(flushed),
+        };
+        for reply in replies {
+            // The result may be ignored.
+            let _: Result<_, _> = reply.send(report.clone());
+        }
+        ret.map_err(|err| self.park_unsent(err, report.dropped))
+    }
+}
+
+fn shutdown_response(id: RequestId) -> AnyResponse {
+    AnyResponse {
+        id,
+        result: None,
+        error: Some(ResponseError::new(
+            ErrorCode::SERVER_CANCELLED,
+            "Server is shutting down",
+        )),
+        raw: None,
+    }
+}
+
+pin_project! {
+    /// The writer retrying transient failures of the underlying one.
+    struct RetryWriter<W> {
+        #[pin]
+        inner: W,
+        retry: Option<WriteRetry>,
+        attempts: u32,
+        sleep: Option<BoxFuture<'static, ()>>,
+    }
+}
+
+impl<W: AsyncWrite> RetryWriter<W> {
+    fn poll_retry<T>(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        mut f: impl FnMut(Pin<&mut W>, &mut Context<'_>) -> Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        let mut this = self.project();
+        loop {
+            if let Some(sleep) = this.sleep {
+                ready!(sleep.poll_unpin(cx));
+                *this.sleep = None;
+            }
+            let err = match ready!(f(this.inner.as_mut(), cx)) {
+                Err(err) => err,
+                Ok(v) => {
+                    *this.attempts = 0;
+                    return Poll::Ready(Ok(v));
+                }
+            };
+            let transient = matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted | io::ErrorKind::TimedOut
+            );
+            match this.retry {
+                Some(retry) if transient && *this.attempts < retry.retries => {
+                    let delay = retry
+                        .backoff
+                        .checked_mul(2u32.saturating_pow(*this.attempts))
+                        .unwrap_or(Duration::MAX);
+                    *this.attempts += 1;
+                    *this.sleep = Some(retry.clock.sleep(delay));
+                }
+                _ => {
+                    *this.attempts = 0;
+                    return Poll::Ready(Err(err));
+                }
+            }
         }
     }
 }
 
+impl<W: AsyncWrite> AsyncWrite for RetryWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_retry(cx, |w, cx| w.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_retry(cx, AsyncWrite::poll_flush)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_retry(cx, AsyncWrite::poll_close)
+    }
+}
+
+/// Generate a unique token of `kind` for the peer, in the form of `async-lsp/{kind}/{n}`.
+///
+/// All tokens generated by this crate, eg. progress tokens and chunk tokens, share this scheme.
+fn unique_token(kind: &str) -> String {
+    static NEXT_TOKEN: AtomicU64 = AtomicU64::new(0);
+    let n = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed);
+    format!("async-lsp/{kind}/{n}")
+}
+
 pin_project! {
     struct RequestFuture<Fut> {
         #[pin]
@@ -638,24 +2051,33 @@ pin_project! {
     }
 }
 
-impl<Fut, Error> Future for RequestFuture<Fut>
+impl<Fut> RequestFuture<Fut> {
+    fn new(fut: Fut, id: RequestId) -> Self {
+        Self { fut, id: Some(id) }
+    }
+}
+
+impl<Fut, Response, Error> Future for RequestFuture<Fut>
 where
-    Fut: Future<Output = Result<JsonValue, Error>>,
+    Fut: Future<Output = Result<Response, Error>>,
+    Response: Into<ResponseValue>,
     ResponseError: From<Error>,
 {
     type Output = AnyResponse;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
-        let (mut result, mut error) = (None, None);
-        match ready!(this.fut.poll(cx)) {
-            Ok(v) => result = Some(v),
+        let (mut result, mut error, mut raw) = (None, None, None);
+        match ready!(this.fut.poll(cx)).map(Into::into) {
+            Ok(ResponseValue::Json(v)) => result = Some(v),
+            Ok(ResponseValue::Raw(resp)) => raw = Some(resp.0),
             Err(err) => error = Some(err.into()),
         }
         Poll::Ready(AnyResponse {
             id: this.id.take().expect("Future is consumed"),
             result,
             error,
+            raw,
         })
     }
 }
@@ -679,6 +2101,9 @@ macro_rules! impl_socket_wrapper {
 
             /// Send a request to the peer and wait for its response.
             ///
+            /// Dropping the future before the response arrives cancels the request, see
+            /// [`RequestHandle`].
+            ///
             /// # Errors
             /// - [`Error::ServiceStopped`] when the service main loop stopped.
             /// - [`Error::Response`] when the peer replies an error.
@@ -686,10 +2111,41 @@ macro_rules! impl_socket_wrapper {
                 self.0.request::<R>(params).await
             }
 
-            /// Send a notification to the peer and wait for its response.
+            /// Send a request to the peer immediately, and return the handle to wait for its
+            /// response or cancel it.
+            pub fn request_handle<R: Request>(
+                &self,
+                params: R::Params,
+            ) -> RequestHandle<R::Result> {
+                self.0.request::<R>(params)
+            }
+
+            /// Send a request to the peer with generated `workDoneToken` and `partialResultToken`,
+            /// and wait for its response. Meanwhile, `$/progress` notifications of these tokens
+            /// are passed to `on_progress` instead of the service.
+            ///
+            /// The tokens are injected into the parameters, which must serialize to an object.
+            /// Progress reported before the response is always delivered before this returns.
+            ///
+            /// # Errors
+            /// - [`Error::ServiceStopped`] when the service main loop stopped.
+            /// - [`Error::Response`] when the peer replies an error.
+            pub async fn request_with_progress<R: Request>(
+                &self,
+                params: R::Params,
+                on_progress: impl FnMut(RequestProgress),
+            ) -> Result<R::Result> {
+                self.0.request_with_progress::<R>(params, on_progress).await
+            }
+
+            /// Send a notification to the peer.
             ///
-            /// This is done asynchronously. An `Ok` result indicates the message is successfully
-            /// queued, but may not be sent to the peer yet.
+            /// This does not block nor need to be awaited, thus can be called inside synchronous
+            /// handlers, eg. of notifications. An `Ok` result indicates the message is
+            /// successfully queued, but may not be sent to the peer yet.
+            ///
+            /// The queue is unbounded and applies no backpressure: messages pile up in memory if
+            /// the peer reads slower than they are produced.
             ///
             /// # Errors
             /// - [`Error::ServiceStopped`] when the service main loop stopped.
@@ -697,10 +2153,57 @@ macro_rules! impl_socket_wrapper {
                 self.0.notify::<N>(params)
             }
 
+            /// Gracefully shut down the main loop, and wait until it exits.
+            ///
+            /// The main loop stops dispatching incoming requests, and replies them with
+            /// [`ErrorCode::SERVER_CANCELLED`] instead. Incoming notifications, responses and
+            /// events are still handled. It waits for in-flight requests to complete until
+            /// `timeout` resolves, then cancels the remaining ones. Finally, it flushes all queued
+            /// outgoing messages and exits with `Ok(())`.
+            ///
+            /// Since in-flight requests are awaited, calling this inside a request handler waits
+            /// for `timeout` to resolve.
+            ///
+            /// # Errors
+            /// - [`Error::ServiceStopped`] when the service main loop stopped or failed before
+            ///   completing the shutdown.
+            pub async fn graceful_shutdown(
+                &self,
+                timeout: impl Future<Output = ()> + Send + 'static,
+            ) -> Result<ShutdownReport> {
+                self.0.graceful_shutdown(timeout.boxed()).await
+            }
+
+            /// Close the socket and all its clones, flush queued outgoing messages until `linger`
+            /// resolves, and exit the main loop.
+            ///
+            /// Once called, all sends via this socket and its clones fail with
+            /// [`Error::ServiceStopped`], while messages queued before are still sent in order.
+            /// The main loop stops handling incoming messages and events immediately, and drops
+            /// in-flight incoming requests without responding them. Use
+            /// [`graceful_shutdown`](Self::graceful_shutdown) before to complete them instead.
+            ///
+            /// Since this crate is runtime-agnostic, the timer `linger` should be provided, eg.
+            /// `tokio::time::sleep(duration)`. Messages not flushed before it resolves are dropped
+            /// and reported. The main loop then exits with `Ok(())`, and can not run again. Note
+            /// that messages are written in order, so the close request itself waits for earlier
+            /// writes blocked by the peer, except the last one.
+            ///
+            /// # Errors
+            /// - [`Error::ServiceStopped`] when the service main loop stopped or failed before
+            ///   completing the close.
+            pub async fn close(
+                &self,
+                linger: impl Future<Output = ()> + Send + 'static,
+            ) -> Result<CloseReport> {
+                self.0.close(linger.boxed()).await
+            }
+
             /// Emit an arbitrary loopback event object to the service handler.
             ///
-            /// This is done asynchronously. An `Ok` result indicates the message is successfully
-            /// queued, but may not be processed yet.
+            /// Same as [`notify`](Self::notify), this does not block nor need to be awaited. An
+            /// `Ok` result indicates the event is successfully queued in the unbounded queue, but
+            /// may not be processed yet.
             ///
             /// # Errors
             /// - [`Error::ServiceStopped`] when the service main loop stopped.
@@ -712,11 +2215,21 @@ macro_rules! impl_socket_wrapper {
 }
 
 /// The socket for Language Server to communicate with the Language Client peer.
+///
+/// With the `omni-trait` feature, it implements [`LanguageClient`] which provides typed methods
+/// of all standard server-to-client requests and notifications, eg.
+/// `client.apply_edit(params).await`, in addition to the generic [`ClientSocket::request`] and
+/// [`ClientSocket::notify`]. Responses are correlated by request ids.
 #[derive(Debug, Clone)]
 pub struct ClientSocket(PeerSocket);
 impl_socket_wrapper!(ClientSocket);
 
 /// The socket for Language Client to communicate with the Language Server peer.
+///
+/// With the `omni-trait` feature, it implements [`LanguageServer`] which provides typed methods
+/// of all standard client-to-server requests and notifications, eg.
+/// `server.hover(params).await`, in addition to the generic [`ServerSocket::request`] and
+/// [`ServerSocket::notify`]. Responses are correlated by request ids.
 #[derive(Debug, Clone)]
 pub struct ServerSocket(PeerSocket);
 impl_socket_wrapper!(ServerSocket);
@@ -736,26 +2249,96 @@ impl PeerSocket {
         self.tx.unbounded_send(v).map_err(|_| Error::ServiceStopped)
     }
 
-    fn request<R: Request>(&self, params: R::Params) -> PeerSocketRequestFuture<R::Result> {
+    fn request<R: Request>(&self, params: R::Params) -> RequestHandle<R::Result> {
         let req = AnyRequest {
             id: RequestId::Number(0),
             method: R::METHOD.into(),
             params: serde_json::to_value(params).expect("Failed to serialize"),
+            receipt: None,
         };
         let (tx, rx) = oneshot::channel();
         // If this fails, the oneshot channel will also be closed, and it is handled by
-        // `PeerSocketRequestFuture`.
+        // `RequestHandle`.
         let _: Result<_, _> = self.send(MainLoopEvent::OutgoingRequest(req, tx));
-        PeerSocketRequestFuture {
-            rx,
-            _marker: PhantomData,
+        RequestHandle::new(rx, self.clone())
+    }
+
+    async fn request_with_progress<R: Request>(
+        &self,
+        params: R::Params,
+        mut on_progress: impl FnMut(RequestProgress),
+    ) -> Result<R::Result> {
+        let work_done_token = NumberOrString::String(unique_token("workDone"));
+        let partial_result_token = NumberOrString::String(unique_token("partialResult"));
+
+        let mut params = serde_json::to_value(params).expect("Failed to serialize");
+        if let Some(obj) = params.as_object_mut() {
+            obj.insert("workDoneToken".into(), json_token(&work_done_token));
+            obj.insert(
+                "partialResultToken".into(),
+                json_token(&partial_result_token),
+            );
+        }
+        let req = AnyRequest {
+            id: RequestId::Number(0),
+            method: R::METHOD.into(),
+            params,
+            receipt: None,
+        };
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let (progress_tx, mut progress_rx) = mpsc::unbounded();
+        // If this fails, the oneshot channel will also be closed, and it is handled by
+        // `RequestHandle`.
+        let _: Result<_, _> = self.send(MainLoopEvent::OutgoingRequestWithProgress(
+            req,
+            resp_tx,
+            vec![work_done_token.clone(), partial_result_token],
+            progress_tx,
+        ));
+        let resp_fut = RequestHandle::<R::Result>::new(resp_rx, self.clone()).fuse();
+        pin_mut!(resp_fut);
+
+        let mut dispatch = |(token, value): (ProgressToken, JsonValue)| {
+            if token != work_done_token {
+                on_progress(RequestProgress::PartialResult(value));
+            } else if let Ok(progress) = serde_json::from_value(value) {
+                on_progress(RequestProgress::WorkDone(progress));
+            }
+        };
+        loop {
+            select_biased! {
+                item = progress_rx.select_next_some() => dispatch(item),
+                ret = resp_fut => {
+                    // Routed progress is always queued before the response.
+                    while let Ok(Some(item)) = progress_rx.try_next() {
+                        dispatch(item);
+                    }
+                    return ret;
+                }
+            }
         }
     }
 
+    async fn graceful_shutdown(&self, timeout: BoxFuture<'static, ()>) -> Result<ShutdownReport> {
+        let (tx, rx) = oneshot::channel();
+        self.send(MainLoopEvent::Shutdown(timeout, tx))?;
+        rx.await.map_err(|_| Error::ServiceStopped)
+    }
+
+    async fn close(&self, linger: BoxFuture<'static, ()>) -> Result<CloseReport> {
+        let (tx, rx) = oneshot::channel();
+        let ret = self.send(MainLoopEvent::Close(linger, tx));
+        // Reject all later sends of any clones.
+        self.tx.close_channel();
+        ret?;
+        rx.await.map_err(|_| Error::ServiceStopped)
+    }
+
     fn notify<N: Notification>(&self, params: N::Params) -> Result<()> {
         let notif = AnyNotification {
             method: N::METHOD.into(),
             params: serde_json::to_value(params).expect("Failed to serialize"),
+            receipt: None,
         };
         self.send(MainLoopEvent::Outgoing(Message::Notification(notif)))
     }
@@ -765,18 +2348,92 @@ impl PeerSocket {
     }
 }
 
-struct PeerSocketRequestFuture<T> {
+/// Yield to the async runtime once, letting it run other tasks.
+async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await;
+}
+
+/// Get the size of serialized `value` without allocating the output.
+pub(crate) fn json_size(value: &JsonValue) -> usize {
+    struct Counter(usize);
+
+    impl io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    // Serializing a `JsonValue` never fails.
+    let _: Result<_, _> = serde_json::to_writer(&mut counter, value);
+    counter.0
+}
+
+fn json_token(token: &ProgressToken) -> JsonValue {
+    serde_json::to_value(token).expect("Failed to serialize")
+}
+
+/// The future of the response of a request sent via [`ClientSocket::request_handle`] or
+/// [`ServerSocket::request_handle`].
+///
+/// Dropping it before the response arrives, or calling [`RequestHandle::cancel`], sends
+/// `$/cancelRequest` to the peer and forgets the pending request. A late response is ignored.
+///
+/// It fails with [`Error::ServiceStopped`] when the service main loop stopped, or
+/// [`Error::Response`] when the peer replies an error.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct RequestHandle<T> {
     rx: oneshot::Receiver<AnyResponse>,
+    socket: PeerSocket,
+    done: bool,
     _marker: PhantomData<fn() -> T>,
 }
 
-impl<T: DeserializeOwned> Future for PeerSocketRequestFuture<T> {
+impl<T> fmt::Debug for RequestHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestHandle")
+            .field("done", &self.done)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> RequestHandle<T> {
+    fn new(rx: oneshot::Receiver<AnyResponse>, socket: PeerSocket) -> Self {
+        Self {
+            rx,
+            socket,
+            done: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Cancel the request if it is not responded yet. Same as dropping the handle.
+    pub fn cancel(self) {
+        drop(self);
+    }
+}
+
+impl<T: DeserializeOwned> Future for RequestHandle<T> {
     type Output = Result<T>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let resp = ready!(Pin::new(&mut self.rx)
-            .poll(cx)
-            .map_err(|_| Error::ServiceStopped))?;
+        let resp = ready!(Pin::new(&mut self.rx).poll(cx));
+        self.done = true;
+        let resp = resp.map_err(|_| Error::ServiceStopped)?;
         Poll::Ready(match resp.error {
             None => Ok(serde_json::from_value(resp.result.unwrap_or_default())?),
             Some(err) => Err(Error::Response(err)),
@@ -784,6 +2441,17 @@ impl<T: DeserializeOwned> Future for PeerSocketRequestFuture<T> {
     }
 }
 
+impl<T> Drop for RequestHandle<T> {
+    fn drop(&mut self) {
+        if !self.done {
+            // Mark it dropped before the main loop looks for it.
+            self.rx.close();
+            // The main loop may be stopped.
+            let _: Result<_> = self.socket.send(MainLoopEvent::CancelDropped);
+        }
+    }
+}
+
 /// A dynamic runtime event.
 ///
 /// This is a wrapper of `Box<dyn Any + Send>`, but saves the underlying type name for better
@@ -861,6 +2529,59 @@ impl AnyEvent {
     }
 }
 
+/// The scheduling hints of an [`AnyEvent`], returned by [`LspService::event_hints`].
+///
+/// Hints are usually declared per event type via
+/// [`Router::event_with_hints`][router::Router::event_with_hints]. They allow high-frequency
+/// internal events, eg. ticks of a timer, to neither flood the main loop nor delay messages from
+/// the peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct EventHints {
+    /// Whether to only keep the latest event of the same type if multiple ones are queued.
+    pub coalesce: bool,
+    /// The priority relative to messages from the peer.
+    pub priority: EventPriority,
+}
+
+impl EventHints {
+    /// Create the default hints, delivering events in order as soon as possible.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only keep the latest event of the same type if multiple ones are queued. Default is
+    /// `false`.
+    ///
+    /// Coalescing events are deferred until all queued events are received, thus they may be
+    /// delivered after events emitted later.
+    #[must_use]
+    pub fn coalesce(mut self, enabled: bool) -> Self {
+        self.coalesce = enabled;
+        self
+    }
+
+    /// Set the priority relative to messages from the peer. Default is
+    /// [`EventPriority::Normal`].
+    #[must_use]
+    pub fn priority(mut self, priority: EventPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+/// The priority of an [`AnyEvent`] relative to messages from the peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EventPriority {
+    /// Delivered before incoming messages.
+    #[default]
+    Normal,
+    /// Delivered only when there are no incoming messages, responses or other events ready.
+    Low,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -871,7 +2592,8 @@ mod tests {
         output: impl AsyncWrite + Send,
     ) -> impl Send
     where
-        S: LspService<Response = JsonValue> + Send,
+        S: LspService + Send,
+        S::Response: Into<ResponseValue>,
         S::Future: Send,
         S::Error: From<Error> + Send,
         ResponseError: From<S::Error>,
@@ -879,6 +2601,23 @@ mod tests {
         f.run(input, output)
     }
 
+    // Main loops of the typical router-based services can run on multi-threaded runtimes.
+    fn _router_main_loop_future_is_send(
+        f: MainLoop<router::Router<()>>,
+        input: impl AsyncRead + Send,
+        output: impl AsyncWrite + Send,
+    ) -> impl Send {
+        f.run_buffered(input, output)
+    }
+
+    fn _raw_router_main_loop_future_is_send(
+        f: MainLoop<router::Router<(), ResponseError, ResponseValue>>,
+        input: impl AsyncRead + Send,
+        output: impl AsyncWrite + Send,
+    ) -> impl Send {
+        f.run_buffered(input, output)
+    }
+
     #[tokio::test]
     async fn closed_client_socket() {
         let socket = ClientSocket::new_closed();
@@ -907,6 +2646,727 @@ mod tests {
         assert!(matches!(socket.emit(42i32), Err(Error::ServiceStopped)));
     }
 
+    #[tokio::test]
+    async fn request_with_progress() {
+        use lsp_types::request::References;
+        use lsp_types::{
+            Location, ProgressParams, ProgressParamsValue, ReferenceContext, ReferenceParams,
+            TextDocumentIdentifier, TextDocumentPositionParams, WorkDoneProgressBegin,
+        };
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        let loc = Location::new("file:///foo".parse().unwrap(), Default::default());
+        let (server_main, _) = MainLoop::new_server(|client| {
+            let mut router = router::Router::new(client);
+            let loc = loc.clone();
+            router
+                .request::<References, _>(move |client, params| {
+                    // Use inherent methods, not `LspService::notify` with `omni-trait`.
+                    let client = &*client;
+                    let progress = |token: Option<ProgressToken>, value| {
+                        client
+                            .notify::<notification::Progress>(ProgressParams {
+                                token: token.unwrap(),
+                                value,
+                            })
+                            .unwrap();
+                    };
+                    progress(
+                        params.work_done_progress_params.work_done_token,
+                        ProgressParamsValue::WorkDone(WorkDoneProgress::Begin(
+                            WorkDoneProgressBegin::default(),
+                        )),
+                    );
+                    let partial = params.partial_result_params.partial_result_token.unwrap();
+                    client
+                        .notify::<notification::Progress>(ProgressParams {
+                            token: partial,
+                            value: ProgressParamsValue::WorkDone(WorkDoneProgress::End(
+                                Default::default(),
+                            )),
+                        })
+                        .unwrap();
+                    let _ = loc;
+                    async { Ok(Some(Vec::new())) }
+                })
+                .notification::<notification::Progress>(|_, _| panic!("progress should be routed"));
+            router
+        });
+        let (client_main, server) = MainLoop::new_client(|_| {
+            let mut router = router::Router::new(());
+            router
+                .notification::<notification::Progress>(|_, _| panic!("progress should be routed"));
+            router
+        });
+
+        let (server_stream, client_stream) = tokio::io::duplex(64 << 10);
+        let (server_rx, server_tx) = server_stream.compat().split();
+        let (client_rx, client_tx) = client_stream.compat().split();
+        let server_main = tokio::spawn(server_main.run_buffered(server_rx, server_tx));
+        let client_main = tokio::spawn(client_main.run_buffered(client_rx, client_tx));
+
+        let mut progress = Vec::new();
+        let ret = server
+            .request_with_progress::<References>(
+                ReferenceParams {
+                    text_document_position: TextDocumentPositionParams {
+                        text_document: TextDocumentIdentifier::new(loc.uri.clone()),
+                        position: Default::default(),
+                    },
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                    context: ReferenceContext {
+                        include_declaration: false,
+                    },
+                },
+                |p| progress.push(p),
+            )
+            .await
+            .unwrap();
+        assert_eq!(ret, Some(Vec::new()));
+        assert_eq!(progress.len(), 2);
+        assert!(matches!(
+            progress[0],
+            RequestProgress::WorkDone(WorkDoneProgress::Begin(_))
+        ));
+        assert!(matches!(progress[1], RequestProgress::PartialResult(_)));
+
+        server_main.abort();
+        client_main.abort();
+    }
+
+    #[tokio::test]
+    async fn cancel_dropped_request() {
+        use lsp_types::request::WorkspaceSymbolRequest;
+        use lsp_types::{CancelParams, WorkspaceSymbolParams};
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        let (cancel_tx, mut cancel_rx) = mpsc::unbounded();
+        let (server_main, _client) = MainLoop::new_server(|_| {
+            let mut router = router::Router::new(cancel_tx);
+            router
+                .request::<WorkspaceSymbolRequest, _>(|_, _| futures::future::pending())
+                .notification::<notification::Cancel>(|tx, params: CancelParams| {
+                    tx.unbounded_send(params.id).unwrap();
+                    ControlFlow::Continue(())
+                });
+            router
+        });
+        let (client_main, server) = MainLoop::new_client(|_| router::Router::new(()));
+
+        let (server_stream, client_stream) = tokio::io::duplex(64 << 10);
+        let (server_rx, server_tx) = server_stream.compat().split();
+        let (client_rx, client_tx) = client_stream.compat().split();
+        let server_main = tokio::spawn(server_main.run_buffered(server_rx, server_tx));
+        let client_main = tokio::spawn(client_main.run_buffered(client_rx, client_tx));
+
+        let params = || WorkspaceSymbolParams {
+            partial_result_params: Default::default(),
+            work_done_progress_params: Default::default(),
+            query: String::new(),
+        };
+        server
+            .request_handle::<WorkspaceSymbolRequest>(params())
+            .cancel();
+        assert_eq!(cancel_rx.next().await, Some(NumberOrString::Number(0)));
+
+        let fut = server.request::<WorkspaceSymbolRequest>(params());
+        assert!(fut.now_or_never().is_none());
+        assert_eq!(cancel_rx.next().await, Some(NumberOrString::Number(1)));
+
+        server_main.abort();
+        client_main.abort();
+    }
+
+    #[tokio::test]
+    async fn message_budget() {
+        use std::sync::atomic::AtomicBool;
+
+        use tokio::io::AsyncWriteExt;
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        let spawned = Arc::new(AtomicBool::new(false));
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (mainloop, _client) = MainLoop::new_server(|_| {
+            let mut router = router::Router::new((spawned.clone(), seen.clone()));
+            router.notification::<notification::Initialized>(|(spawned, seen), _| {
+                seen.lock().unwrap().push(spawned.load(Ordering::Relaxed));
+                ControlFlow::Continue(())
+            });
+            router
+        });
+        let mainloop = mainloop.message_budget(10);
+
+        let (mut peer, stream) = tokio::io::duplex(64 << 10);
+        let notif = r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#;
+        let frame = format!("Content-Length: {}\r\n\r\n{notif}", notif.len());
+        for _ in 0..100 {
+            peer.write_all(frame.as_bytes()).await.unwrap();
+        }
+        drop(peer);
+        // Only runs when the main loop yields, on the current thread runtime.
+        tokio::spawn({
+            let spawned = spawned.clone();
+            async move { spawned.store(true, Ordering::Relaxed) }
+        });
+        let (input, output) = stream.compat().split();
+        let ret = mainloop.run_buffered(input, output).await;
+        assert!(matches!(ret, Err(Error::Eof)));
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 100);
+        assert!(seen[20]);
+    }
+
+    #[tokio::test]
+    async fn shedding() {
+        use tokio::io::AsyncWriteExt;
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (mainloop, _client) = MainLoop::new_server(|_| {
+            let mut router = router::Router::new(seen.clone());
+            router.notification::<notification::DidChangeTextDocument>(|seen, params| {
+                let texts = params.content_changes.into_iter().map(|change| change.text);
+                seen.lock().unwrap().push(texts.collect::<String>());
+                ControlFlow::Continue(())
+            });
+            router
+        });
+        let policy =
+            shedding::SheddingPolicy::new(2).coalesce::<notification::DidChangeTextDocument>();
+        let mainloop = mainloop.shedding(policy);
+
+        let (mut peer, stream) = tokio::io::duplex(64 << 10);
+        for i in 0..10 {
+            let notif = serde_json::json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didChange",
+                "params": {
+                    "textDocument": { "uri": "file:///a", "version": i },
+                    "contentChanges": [{ "text": i.to_string() }],
+                },
+            })
+            .to_string();
+            let frame = format!("Content-Length: {}\r\n\r\n{notif}", notif.len());
+            peer.write_all(frame.as_bytes()).await.unwrap();
+        }
+        drop(peer);
+        let (input, output) = stream.compat().split();
+        let ret = mainloop.run_buffered(input, output).await;
+        assert!(matches!(ret, Err(Error::Eof)));
+
+        // The first two are queued under the threshold, and the rest are coalesced into the last
+        // full-text change.
+        assert_eq!(*seen.lock().unwrap(), ["0", "9"]);
+    }
+
+    #[tokio::test]
+    async fn max_pending() {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        // A service never ready for requests.
+        struct Stalled;
+
+        impl Service<AnyRequest> for Stalled {
+            type Response = JsonValue;
+            type Error = ResponseError;
+            type Future = BoxFuture<'static, Result<JsonValue, ResponseError>>;
+
+            fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+                Poll::Pending
+            }
+
+            fn call(&mut self, _req: AnyRequest) -> Self::Future {
+                unreachable!()
+            }
+        }
+
+        impl LspService for Stalled {
+            fn notify(&mut self, _notif: AnyNotification) -> ControlFlow<Result<()>> {
+                ControlFlow::Continue(())
+            }
+
+            fn emit(&mut self, _event: AnyEvent) -> ControlFlow<Result<()>> {
+                ControlFlow::Continue(())
+            }
+        }
+
+        let (mainloop, _client) = MainLoop::new_server(|_| Stalled);
+        let mainloop = mainloop.max_pending(4);
+        let (peer, stream) = tokio::io::duplex(1 << 10);
+        let (input, output) = stream.compat().split();
+        let mainloop = tokio::spawn(mainloop.run_buffered(input, output));
+
+        let (peer_rx, mut peer_tx) = tokio::io::split(peer);
+        let writer = tokio::spawn(async move {
+            let frame = |msg: JsonValue| {
+                let msg = msg.to_string();
+                format!("Content-Length: {}\r\n\r\n{msg}", msg.len())
+            };
+            let cancel = serde_json::json!({
+                "jsonrpc": "2.0",
+                "method": "$/cancelRequest",
+                "params": { "id": 0 },
+            });
+            for i in 0..1000 {
+                let req = serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": i,
+                    "method": "shutdown",
+                });
+                peer_tx.write_all(frame(req).as_bytes()).await.unwrap();
+                if i == 0 {
+                    peer_tx
+                        .write_all(frame(cancel.clone()).as_bytes())
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+
+        // The waiting request is cancelled without reaching the service.
+        let mut peer_rx = BufReader::new(peer_rx);
+        let mut header = String::new();
+        peer_rx.read_line(&mut header).await.unwrap();
+        let len = header["Content-Length: ".len()..].trim().parse().unwrap();
+        peer_rx.read_line(&mut String::new()).await.unwrap();
+        let mut content = vec![0u8; len];
+        peer_rx.read_exact(&mut content).await.unwrap();
+        let resp: JsonValue = serde_json::from_slice(&content).unwrap();
+        assert_eq!(resp["id"], 0);
+        assert_eq!(resp["error"]["code"], ErrorCode::REQUEST_CANCELLED.0);
+
+        // Reading stops once the queue is full, which blocks the peer.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!writer.is_finished());
+
+        writer.abort();
+        mainloop.abort();
+    }
+
+    #[tokio::test]
+    async fn event_hints() {
+        use tokio::io::AsyncWriteExt;
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        struct Tick(u32);
+        struct Idle;
+
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (mainloop, client) = MainLoop::new_server(|_| {
+            let mut router = router::Router::new(log.clone());
+            router
+                .event_with_hints::<Tick>(EventHints::new().coalesce(true), |log, Tick(i)| {
+                    log.lock().unwrap().push(format!("tick {i}"));
+                    ControlFlow::Continue(())
+                })
+                .event_with_hints::<Idle>(
+                    EventHints::new().priority(EventPriority::Low),
+                    |log, Idle| {
+                        log.lock().unwrap().push("idle".into());
+                        ControlFlow::Break(Ok(()))
+                    },
+                )
+                .event::<()>(|log, ()| {
+                    log.lock().unwrap().push("plain".into());
+                    ControlFlow::Continue(())
+                })
+                .notification::<notification::Initialized>(|log, _| {
+                    log.lock().unwrap().push("initialized".into());
+                    ControlFlow::Continue(())
+                });
+            router
+        });
+
+        client.emit(Tick(1)).unwrap();
+        client.emit(Idle).unwrap();
+        client.emit(Tick(2)).unwrap();
+        client.emit(Tick(3)).unwrap();
+        client.emit(()).unwrap();
+
+        let (mut peer, stream) = tokio::io::duplex(64 << 10);
+        let notif = r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#;
+        let frame = format!("Content-Length: {}\r\n\r\n{notif}", notif.len());
+        peer.write_all(frame.as_bytes()).await.unwrap();
+        let (input, output) = stream.compat().split();
+        mainloop.run_buffered(input, output).await.unwrap();
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["plain", "tick 3", "initialized", "idle"],
+        );
+    }
+
+    #[tokio::test]
+    async fn batch() {
+        use tokio::io::AsyncWriteExt;
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        async fn run(batch_responses: bool) -> Vec<Vec<RequestId>> {
+            let (server_main, _client) = MainLoop::new_server(|_| {
+                let mut router = router::Router::new(());
+                router
+                    .request::<lsp_types::request::Shutdown, _>(|_, ()| async { Ok(()) })
+                    .notification::<notification::Initialized>(|_, _| ControlFlow::Continue(()));
+                router
+            });
+            let server_main = server_main.batch_responses(batch_responses);
+            let (server_stream, client_stream) = tokio::io::duplex(64 << 10);
+            let (server_rx, server_tx) = server_stream.compat().split();
+            let server_main = tokio::spawn(server_main.run_buffered(server_rx, server_tx));
+
+            let (client_rx, mut client_tx) = tokio::io::split(client_stream);
+            let content = serde_json::json!([
+                { "jsonrpc": "2.0", "id": 1, "method": "shutdown" },
+                { "jsonrpc": "2.0", "method": "initialized", "params": {} },
+                { "jsonrpc": "2.0", "id": 2, "method": "shutdown" },
+            ])
+            .to_string();
+            let frame = format!("Content-Length: {}\r\n\r\n{content}", content.len());
+            client_tx.write_all(frame.as_bytes()).await.unwrap();
+
+            // Request ids of each response frame.
+            let mut client_rx = BufReader::new(client_rx.compat());
+            let mut frames = Vec::new();
+            while frames.iter().map(Vec::len).sum::<usize>() < 2 {
+                let msgs = match Frame::read(&mut client_rx, None).await.unwrap() {
+                    Frame::Single(msg) => vec![msg],
+                    Frame::Batch(msgs) => msgs,
+                };
+                let mut ids = msgs
+                    .into_iter()
+                    .map(|msg| match msg {
+                        Message::Response(resp) => resp.id,
+                        msg => panic!("unexpected message: {msg:?}"),
+                    })
+                    .collect::<Vec<_>>();
+                ids.sort_by_key(|id| format!("{id:?}"));
+                frames.push(ids);
+            }
+            server_main.abort();
+            frames.sort_by_key(|ids| format!("{ids:?}"));
+            frames
+        }
+
+        let id = RequestId::Number;
+        assert_eq!(run(false).await, [vec![id(1)], vec![id(2)]]);
+        assert_eq!(run(true).await, [vec![id(1), id(2)]]);
+    }
+
+    #[cfg(feature = "forward")]
+    #[tokio::test]
+    async fn request_id_policy() {
+        use std::future::pending;
+
+        use serde_json::json;
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        /// Forward requests of `(id, method)` and a cancellation of `cancel`, returning the ids
+        /// seen by the server in order.
+        async fn run(policy: RequestIdPolicy, reqs: &[(i32, &str)], cancel: i32) -> Vec<JsonValue> {
+            let (seen_tx, mut seen_rx) = mpsc::unbounded();
+            let (server_main, _client) = MainLoop::new_server(|_| {
+                let mut router = router::Router::new(seen_tx);
+                router
+                    .unhandled_request(|tx, req| {
+                        tx.unbounded_send(json!(req.id)).unwrap();
+                        async move {
+                            if req.method == "test/wait" {
+                                pending::<()>().await;
+                            }
+                            Ok(JsonValue::Null)
+                        }
+                    })
+                    .unhandled_notification(|tx, notif| {
+                        tx.unbounded_send(notif.params["id"].clone()).unwrap();
+                        ControlFlow::Continue(())
+                    });
+                router
+            });
+            let (client_main, mut server) = MainLoop::new_client(|_| router::Router::new(()));
+            let client_main = client_main.request_id_policy(policy);
+            let (server_stream, client_stream) = tokio::io::duplex(64 << 10);
+            let (server_rx, server_tx) = server_stream.compat().split();
+            let (client_rx, client_tx) = client_stream.compat().split();
+            let server_main = tokio::spawn(server_main.run_buffered(server_rx, server_tx));
+            let client_main = tokio::spawn(client_main.run_buffered(client_rx, client_tx));
+
+            let mut seen = Vec::new();
+            let mut waiting = Vec::new();
+            for &(id, method) in reqs {
+                let fut = server.call(AnyRequest {
+                    id: RequestId::Number(id),
+                    method: method.into(),
+                    params: JsonValue::Null,
+                    receipt: None,
+                });
+                if method == "test/wait" {
+                    waiting.push(fut);
+                } else {
+                    fut.await.unwrap();
+                }
+                seen.push(seen_rx.next().await.unwrap());
+            }
+            let cancel = AnyNotification {
+                method: notification::Cancel::METHOD.into(),
+                params: json!({ "id": cancel }),
+                receipt: None,
+            };
+            assert!(matches!(
+                LspService::notify(&mut server, cancel),
+                ControlFlow::Continue(())
+            ));
+            seen.push(seen_rx.next().await.unwrap());
+
+            server_main.abort();
+            client_main.abort();
+            seen
+        }
+
+        let reqs = [(7, "test/wait"), (7, "test/echo"), (8, "test/echo")];
+        assert_eq!(
+            run(RequestIdPolicy::Remap, &reqs, 7).await,
+            [json!(0), json!(1), json!(2), json!(0)],
+        );
+        // The second `7` collides with the pending one.
+        assert_eq!(
+            run(RequestIdPolicy::Preserve, &reqs, 7).await,
+            [json!(7), json!(0), json!(8), json!(7)],
+        );
+        assert_eq!(
+            run(RequestIdPolicy::Prefix("editor/".into()), &reqs, 7).await,
+            [
+                json!("editor/7"),
+                json!(0),
+                json!("editor/8"),
+                json!("editor/7")
+            ],
+        );
+        // Cancellations of unknown requests pass through.
+        assert_eq!(
+            run(RequestIdPolicy::Prefix("editor/".into()), &reqs[..1], 9).await,
+            [json!("editor/7"), json!(9)],
+        );
+    }
+
+    #[tokio::test]
+    async fn buffer_capacity() {
+        use tokio::io::AsyncWriteExt;
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        let (server_main, _server) = MainLoop::new_server(|_| {
+            let mut router = router::Router::new(());
+            router.request::<lsp_types::request::WorkspaceSymbolRequest, _>(
+                |_, params| async move {
+                    assert_eq!(params.query.len(), 100);
+                    Ok(None)
+                },
+            );
+            router
+        });
+        // Smaller than messages.
+        let server_main = server_main.buffer_capacity(16);
+        let (server_stream, client_stream) = tokio::io::duplex(64 << 10);
+        let (server_rx, server_tx) = server_stream.compat().split();
+        let server_main = tokio::spawn(server_main.run_buffered(server_rx, server_tx));
+
+        let (client_rx, mut client_tx) = tokio::io::split(client_stream);
+        let mut client_rx = BufReader::new(client_rx.compat());
+        let content = serde_json::json!({
+            "jsonrpc": "2.0", "id": 1, "method": "workspace/symbol",
+            "params": { "query": "x".repeat(100) },
+        })
+        .to_string();
+        let frame = format!("Content-Length: {}\r\n\r\n{content}", content.len());
+        client_tx.write_all(frame.as_bytes()).await.unwrap();
+        match Frame::read(&mut client_rx, None).await.unwrap() {
+            Frame::Single(Message::Response(resp)) => {
+                assert_eq!(resp.id, RequestId::Number(1));
+                assert!(resp.error.is_none(), "{:?}", resp.error);
+            }
+            frame => panic!("unexpected frame: {frame:?}"),
+        }
+        server_main.abort();
+    }
+
+    #[tokio::test]
+    async fn receipt() {
+        let receipts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (server_main, _client) = MainLoop::new_server(|_| {
+            let mut router = router::Router::new(receipts.clone());
+            router
+                .unhandled_request(|st, req| {
+                    st.lock().unwrap().push((req.method, req.receipt.unwrap()));
+                    async { Ok(JsonValue::Null) }
+                })
+                .unhandled_notification(|st, notif| {
+                    st.lock()
+                        .unwrap()
+                        .push((notif.method, notif.receipt.unwrap()));
+                    ControlFlow::Continue(())
+                });
+            router
+        });
+        let mut input = Vec::new();
+        for content in [
+            serde_json::json!({ "jsonrpc": "2.0", "method": "foo" }),
+            serde_json::json!([
+                { "jsonrpc": "2.0", "id": 1, "method": "bar" },
+                { "jsonrpc": "2.0", "method": "baz" },
+            ]),
+        ] {
+            let content = content.to_string();
+            input.extend(format!("Content-Length: {}\r\n\r\n{content}", content.len()).bytes());
+        }
+        let ret = server_main
+            .run_buffered(&input[..], futures::io::sink())
+            .await;
+        assert!(matches!(ret, Err(Error::Eof)), "{ret:?}");
+
+        let receipts = receipts.lock().unwrap();
+        let brief = receipts
+            .iter()
+            .map(|(method, receipt)| (method.as_str(), receipt.seq))
+            .collect::<Vec<_>>();
+        assert_eq!(brief, [("foo", 0), ("bar", 1), ("baz", 2)]);
+        assert!(receipts[0].1.time <= receipts[1].1.time);
+        assert_eq!(receipts[1].1.time, receipts[2].1.time);
+    }
+
+    #[tokio::test]
+    async fn raw_response() {
+        use lsp_types::request::{self, HoverRequest};
+
+        let mut router = router::Router::<(), ResponseError, _>::new_raw(());
+        router.request_raw::<HoverRequest, _>(|_, _| async {
+            Ok(RawResponse::from_string::<HoverRequest>(r#"{ "contents": "x" }"#.into()).unwrap())
+        });
+        poll_fn(|cx| router.poll_ready(cx)).await.unwrap();
+        let fut = router.call(AnyRequest {
+            id: RequestId::Number(1),
+            method: HoverRequest::METHOD.into(),
+            params: serde_json::json!({
+                "textDocument": { "uri": "file:///foo" },
+                "position": { "line": 0, "character": 0 },
+            }),
+            receipt: None,
+        });
+        let resp = RequestFuture::new(fut, RequestId::Number(1)).await;
+        assert!(resp.result.is_none());
+
+        let mut buf = Vec::new();
+        Frame::Single(Message::Response(resp))
+            .write(&mut buf)
+            .await
+            .unwrap();
+        let content = r#"{"jsonrpc":"2.0","id":1,"result":{ "contents": "x" }}"#;
+        let expect = format!("Content-Length: {}\r\n\r\n{content}", content.len());
+        assert_eq!(String::from_utf8(buf).unwrap(), expect);
+
+        // Plain results are still serialized.
+        router.request::<request::Shutdown, _>(|_, ()| async { Ok(()) });
+        let fut = router.call(AnyRequest {
+            id: RequestId::Number(2),
+            method: request::Shutdown::METHOD.into(),
+            params: JsonValue::Null,
+            receipt: None,
+        });
+        let resp = RequestFuture::new(fut, RequestId::Number(2)).await;
+        assert!(resp.raw.is_none());
+        assert_eq!(resp.result, Some(JsonValue::Null));
+
+        // Middlewares can still convert it into the actual result.
+        let raw =
+            RawResponse::from_string::<HoverRequest>(r#"{ "contents": "x" }"#.into()).unwrap();
+        assert_eq!(JsonValue::from(raw), serde_json::json!({ "contents": "x" }));
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic = "Invalid raw response"]
+    fn raw_response_mismatch() {
+        let _ = RawResponse::from_string::<lsp_types::request::HoverRequest>("42".into());
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown() {
+        use std::time::Duration;
+
+        use tokio::io::AsyncWriteExt;
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        let (server_main, server) = MainLoop::new_server(|_| {
+            let mut router = router::Router::new(());
+            router
+                .request::<lsp_types::request::Shutdown, _>(|_, ()| async { Ok(()) })
+                .request::<lsp_types::request::WorkspaceSymbolRequest, _>(|_, _| {
+                    std::future::pending()
+                });
+            router
+        });
+        let (server_stream, client_stream) = tokio::io::duplex(64 << 10);
+        let (server_rx, server_tx) = server_stream.compat().split();
+        let server_main = tokio::spawn(server_main.run_buffered(server_rx, server_tx));
+
+        let (client_rx, mut client_tx) = tokio::io::split(client_stream);
+        let mut client_rx = BufReader::new(client_rx.compat());
+        async fn send(tx: &mut (impl tokio::io::AsyncWrite + Unpin), content: serde_json::Value) {
+            let content = content.to_string();
+            let frame = format!("Content-Length: {}\r\n\r\n{content}", content.len());
+            tx.write_all(frame.as_bytes()).await.unwrap();
+        }
+        async fn recv(rx: &mut (impl AsyncBufRead + Unpin)) -> AnyResponse {
+            match Frame::read(rx, None).await.unwrap() {
+                Frame::Single(Message::Response(resp)) => resp,
+                frame => panic!("unexpected frame: {frame:?}"),
+            }
+        }
+        let id = RequestId::Number;
+
+        send(
+            &mut client_tx,
+            serde_json::json!({
+                "jsonrpc": "2.0", "id": 1, "method": "workspace/symbol", "params": { "query": "" },
+            }),
+        )
+        .await;
+        send(
+            &mut client_tx,
+            serde_json::json!({ "jsonrpc": "2.0", "id": 2, "method": "shutdown" }),
+        )
+        .await;
+        let resp = recv(&mut client_rx).await;
+        assert_eq!(resp.id, id(2));
+        assert!(resp.error.is_none());
+
+        let shutdown = tokio::spawn(async move {
+            server
+                .graceful_shutdown(tokio::time::sleep(Duration::from_millis(200)))
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        send(
+            &mut client_tx,
+            serde_json::json!({ "jsonrpc": "2.0", "id": 3, "method": "shutdown" }),
+        )
+        .await;
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let resp = recv(&mut client_rx).await;
+            assert_eq!(resp.error.unwrap().code, ErrorCode::SERVER_CANCELLED);
+            ids.push(resp.id);
+        }
+        assert_eq!(ids, [id(3), id(1)]);
+
+        let report = shutdown.await.unwrap().unwrap();
+        assert_eq!(report.abandoned, [id(1)]);
+        assert_eq!(report.rejected, [id(3)]);
+        server_main.await.unwrap().unwrap();
+    }
+
     #[test]
     fn any_event() {
         #[derive(Debug, Clone, PartialEq, Eq)]
@@ -930,4 +3390,263 @@ mod tests {
         let inner = any_event.downcast::<MyEvent<String>>().unwrap();
         assert_eq!(inner.0, "hello world");
     }
+
+    #[tokio::test]
+    async fn close() {
+        /// Returns `Pending` for the first `blocked` polls.
+        struct SlowWriter {
+            buf: Vec<u8>,
+            blocked: usize,
+        }
+
+        impl AsyncWrite for SlowWriter {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                if self.blocked > 0 {
+                    self.blocked -= 1;
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                self.buf.extend_from_slice(buf);
+                Poll::Ready(Ok(buf.len()))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        async fn run(
+            blocked: usize,
+            linger: impl Future<Output = ()> + Send + 'static,
+        ) -> (CloseReport, Vec<u8>) {
+            let (mut main_loop, client) = MainLoop::new_server(|_| router::Router::new(()));
+            client.notify::<notification::Exit>(()).unwrap();
+            let mut output = SlowWriter {
+                buf: Vec::new(),
+                blocked,
+            };
+            let (report, ret) = futures::join!(
+                client.close(linger),
+                main_loop.run_session(BufReader::new(PendingReader), &mut output),
+            );
+            ret.unwrap();
+            assert!(matches!(
+                client.notify::<notification::Exit>(()),
+                Err(Error::ServiceStopped)
+            ));
+            let ret = main_loop
+                .run_session(futures::io::empty(), futures::io::sink())
+                .await;
+            assert!(matches!(ret, Err(Error::ServiceStopped)));
+            (report.unwrap(), output.buf)
+        }
+
+        struct PendingReader;
+
+        impl AsyncRead for PendingReader {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                _buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                Poll::Pending
+            }
+        }
+
+        let exit = UnsentMessage {
+            id: None,
+            method: Some(notification::Exit::METHOD.into()),
+        };
+
+        // The write is still in flight when the close request is handled.
+        let (report, output) = run(5, futures::future::pending()).await;
+        assert_eq!(
+            report,
+            CloseReport {
+                flushed: 1,
+                dropped: Vec::new(),
+            }
+        );
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with(r#""method":"exit"}"#));
+
+        let (report, output) = run(usize::MAX, futures::future::ready(())).await;
+        assert_eq!(
+            report,
+            CloseReport {
+                flushed: 0,
+                dropped: vec![exit],
+            }
+        );
+        assert!(output.is_empty());
+    }
+
+    #[tokio::test]
+    async fn write_retry() {
+        use std::time::Duration;
+
+        /// Fails the first `failures` writes with `kind`.
+        struct FlakyWriter {
+            buf: Vec<u8>,
+            failures: usize,
+            kind: io::ErrorKind,
+        }
+
+        impl AsyncWrite for FlakyWriter {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                if self.failures > 0 {
+                    self.failures -= 1;
+                    return Poll::Ready(Err(self.kind.into()));
+                }
+                self.buf.extend_from_slice(buf);
+                Poll::Ready(Ok(buf.len()))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        async fn run(failures: usize, kind: io::ErrorKind) -> (Result<()>, Vec<u8>) {
+            let (mut main_loop, _client) = MainLoop::new_server(|_| {
+                let mut router = router::Router::new(());
+                router
+                    .request::<lsp_types::request::Shutdown, _>(|_, ()| async { Ok(()) })
+                    .notification::<notification::Exit>(|_, ()| ControlFlow::Break(Ok(())));
+                router
+            });
+            main_loop = main_loop.write_retry(3, Duration::from_millis(1), tokio::time::sleep);
+            let input = [
+                serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "shutdown" }),
+                serde_json::json!({ "jsonrpc": "2.0", "method": "exit" }),
+            ]
+            .map(|msg| {
+                let msg = msg.to_string();
+                format!("Content-Length: {}\r\n\r\n{msg}", msg.len())
+            })
+            .concat();
+            let mut output = FlakyWriter {
+                buf: Vec::new(),
+                failures,
+                kind,
+            };
+            let ret = main_loop.run_session(input.as_bytes(), &mut output).await;
+            (ret, output.buf)
+        }
+
+        let (ret, output) = run(3, io::ErrorKind::WouldBlock).await;
+        ret.unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with(r#""id":1,"result":null}"#));
+
+        let (ret, output) = run(4, io::ErrorKind::WouldBlock).await;
+        assert!(output.is_empty());
+        let err = ret.unwrap_err();
+        assert_eq!(err.io_error().unwrap().kind(), io::ErrorKind::WouldBlock);
+        match err {
+            Error::Unsent { unsent, .. } => assert_eq!(
+                unsent,
+                [UnsentMessage {
+                    id: Some(RequestId::Number(1)),
+                    method: None,
+                }]
+            ),
+            err => panic!("unexpected error: {err}"),
+        }
+
+        // Permanent failures are not retried.
+        let (ret, _) = run(1, io::ErrorKind::BrokenPipe).await;
+        assert!(ret.unwrap_err().is_disconnected());
+    }
+
+    #[tokio::test]
+    async fn transport_errors() {
+        async fn read(input: &str) -> Error {
+            Frame::read(input.as_bytes(), Some(10)).await.unwrap_err()
+        }
+        assert!(matches!(read("").await, Error::Eof));
+        assert!(matches!(read("\r\n ").await, Error::Eof));
+        assert!(matches!(read("Content-Le").await, Error::UnexpectedEof(_)));
+        assert!(matches!(
+            read("Content-Length: 10\r\n").await,
+            Error::UnexpectedEof(_)
+        ));
+        assert!(matches!(
+            read("Content-Length: 10\r\n\r\n{}").await,
+            Error::UnexpectedEof(_)
+        ));
+        assert!(matches!(
+            read("Content-Length: 2\r\n\r\n{,").await,
+            Error::Deserialize(_)
+        ));
+        assert!(matches!(
+            read("Content-Length: 99999999999\r\n\r\n{}").await,
+            Error::MessageTooLarge {
+                size: 99999999999,
+                limit: 10
+            }
+        ));
+        // Header lines are not buffered endlessly.
+        let long = format!("X-Foo: {}", "x".repeat(codec::MAX_HEADER_SIZE));
+        assert!(matches!(
+            read(&long).await,
+            Error::MessageTooLarge {
+                limit: codec::MAX_HEADER_SIZE,
+                ..
+            }
+        ));
+        assert!(matches!(
+            read(&"\r\n".repeat(codec::MAX_HEADER_LINES + 1)).await,
+            Error::MessageTooLarge {
+                limit: codec::MAX_HEADER_LINES,
+                ..
+            }
+        ));
+
+        let err = Error::from(io::Error::from(io::ErrorKind::BrokenPipe));
+        assert!(matches!(err, Error::BrokenPipe(_)) && err.is_disconnected());
+        let err = Error::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert_eq!(
+            err.io_error().unwrap().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert!(!err.is_disconnected());
+    }
+
+    #[test]
+    fn error_code_kind() {
+        assert_eq!(
+            ErrorCode::CONTENT_MODIFIED.kind(),
+            ErrorCodeKind::ContentModified
+        );
+        assert_eq!(ErrorCode(-32050).kind(), ErrorCodeKind::ServerError(-32050));
+        assert_eq!(ErrorCode(42).kind(), ErrorCodeKind::Unknown(42));
+        for code in [-32700, -32800, -32803, -32050, 42] {
+            assert_eq!(i32::from(ErrorCodeKind::from(code)), code);
+            assert_eq!(ErrorCode::from(ErrorCode(code).kind()), ErrorCode(code));
+        }
+
+        assert!(ErrorCode::CONTENT_MODIFIED.is_retryable());
+        assert!(!ErrorCode::REQUEST_CANCELLED.is_retryable());
+        assert!(ErrorCode::REQUEST_CANCELLED.is_request_cancelled());
+        assert!(!ErrorCode::SERVER_CANCELLED.is_request_cancelled());
+    }
 }
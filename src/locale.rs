@@ -0,0 +1,343 @@
+//! Capture the negotiated locale and localize user-visible messages.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Since LSP 3.16, the Language Client can tell its UI language via
+//! [`InitializeParams::locale`][lsp_types::InitializeParams::locale]. This middleware captures it
+//! into a shared [`Locale`] handle on `initialize`, and translates messages of error responses via
+//! the [`Localize`] implementation of the handle.
+//!
+//! Messages of errors produced by built-in middlewares (eg. [`crate::server::Lifecycle`] and
+//! [`crate::concurrency::Concurrency`]) are also carried by error responses. Placing this
+//! middleware as the outermost layer localizes them as well.
+//!
+//! Handlers can keep a clone of the [`Locale`] handle to query the locale, or to localize their
+//! own messages like diagnostics via [`Locale::localize`]. Messages sent to the user interface are
+//! localized via the handle as well:
+//! - [`Locale::show_message`] sends a localized `window/showMessage` notification.
+//! - [`Locale::create_progress`] and [`Locale::begin_progress`] begin a work done
+//!   [`Progress`], whose title, and messages of later reports and the end, are localized.
+//! - Progresses of a [`ProgressCancellation`](crate::progress::ProgressCancellation) built via
+//!   [`ProgressCancellation::locale`](crate::progress::ProgressCancellation::locale), and jobs of
+//!   a [`Jobs`](crate::jobs::Jobs) scheduler set via
+//!   [`Jobs::set_locale`](crate::jobs::Jobs::set_locale), are localized the same way.
+use std::borrow::Cow;
+use std::fmt;
+use std::future::Future;
+use std::ops::ControlFlow;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{ready, Context, Poll};
+
+use lsp_types::notification::ShowMessage;
+use lsp_types::request::{self, Request};
+use lsp_types::{MessageType, ShowMessageParams};
+use pin_project_lite::pin_project;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::progress::{Progress, WorkDoneToken};
+use crate::{
+    AnyEvent, AnyNotification, AnyRequest, ClientSocket, EventHints, LspService, ResponseError,
+    Result,
+};
+
+/// Translation of user-visible messages.
+pub trait Localize: Send + Sync + 'static {
+    /// Translate `message` into `locale`, or return `None` to keep it unchanged.
+    fn localize(&self, locale: &str, message: &str) -> Option<String>;
+}
+
+impl<F> Localize for F
+where
+    F: Fn(&str, &str) -> Option<String> + Send + Sync + 'static,
+{
+    fn localize(&self, locale: &str, message: &str) -> Option<String> {
+        self(locale, message)
+    }
+}
+
+/// A cheaply cloneable shared handle to the negotiated locale and the translator.
+///
+/// The [`Default`] handle has no translator and keeps all messages unchanged.
+#[derive(Clone, Default)]
+pub struct Locale {
+    locale: Arc<RwLock<Option<String>>>,
+    localizer: Option<Arc<dyn Localize>>,
+}
+
+impl fmt::Debug for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Locale")
+            .field("locale", &self.get())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Locale {
+    /// Create a handle with a translator. The locale is unset until `initialize`.
+    #[must_use]
+    pub fn new(localizer: impl Localize) -> Self {
+        Self {
+            locale: Arc::default(),
+            localizer: Some(Arc::new(localizer)),
+        }
+    }
+
+    /// Get the locale negotiated by the `initialize` request, if the client specified one.
+    #[must_use]
+    pub fn get(&self) -> Option<String> {
+        self.locale.read().unwrap().clone()
+    }
+
+    /// Override the current locale.
+    pub fn set(&self, locale: Option<String>) {
+        *self.locale.write().unwrap() = locale;
+    }
+
+    /// Translate `message` into the current locale.
+    ///
+    /// It returns `message` unchanged if there is no translator or no locale is negotiated yet, or
+    /// the translator cannot translate it.
+    #[must_use]
+    pub fn localize<'a>(&self, message: &'a str) -> Cow<'a, str> {
+        let translated = self.localizer.as_ref().and_then(|localizer| {
+            let locale = self.locale.read().unwrap();
+            localizer.localize(locale.as_deref()?, message)
+        });
+        match translated {
+            Some(s) => Cow::Owned(s),
+            None => Cow::Borrowed(message),
+        }
+    }
+
+    /// Send a `window/showMessage` notification of `message` translated into the current locale.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::ServiceStopped`](crate::Error::ServiceStopped) when the service main loop
+    ///   stopped.
+    pub fn show_message(
+        &self,
+        client: &ClientSocket,
+        typ: MessageType,
+        message: &str,
+    ) -> Result<()> {
+        client.notify::<ShowMessage>(ShowMessageParams {
+            typ,
+            message: self.localize(message).into_owned(),
+        })
+    }
+
+    /// Same as [`ClientSocket::create_progress`], but the title and messages of the progress are
+    /// translated into the current locale.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`ClientSocket::create_progress`].
+    pub async fn create_progress(&self, client: &ClientSocket, title: &str) -> Result<Progress> {
+        let token = client.create_progress_token().await?;
+        Ok(self.begin_progress(client.clone(), WorkDoneToken::new(token), title))
+    }
+
+    /// Same as [`Progress::begin`], but the title and messages of the progress are translated into
+    /// the current locale.
+    ///
+    /// # Panics
+    ///
+    /// Same as [`Progress::begin`].
+    pub fn begin_progress(
+        &self,
+        client: ClientSocket,
+        token: impl Into<WorkDoneToken>,
+        title: &str,
+    ) -> Progress {
+        Progress::begin_impl(client, token.into(), title.into(), None, Some(self.clone()))
+    }
+}
+
+/// The middleware capturing the negotiated locale and localizing error responses.
+///
+/// See [module level documentations](self) for details.
+pub struct Localization<S> {
+    service: S,
+    locale: Locale,
+}
+
+define_getters!(impl[S] Localization<S>, service: S);
+
+impl<S: LspService> Service<AnyRequest> for Localization<S>
+where
+    S::Error: From<ResponseError>,
+    ResponseError: From<S::Error>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        if req.method == request::Initialize::METHOD {
+            let locale = req
+                .params
+                .get("locale")
+                .and_then(|v| v.as_str())
+                .map(ToOwned::to_owned);
+            self.locale.set(locale);
+        }
+        ResponseFuture {
+            fut: self.service.call(req),
+            locale: self.locale.clone(),
+        }
+    }
+}
+
+impl<S: LspService> LspService for Localization<S>
+where
+    S::Error: From<ResponseError>,
+    ResponseError: From<S::Error>,
+{
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        self.service.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.service.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+pin_project! {
+    /// The [`Future`] type used by the [`Localization`] middleware.
+    pub struct ResponseFuture<Fut> {
+        #[pin]
+        fut: Fut,
+        locale: Locale,
+    }
+}
+
+impl<Fut, Response, Error> Future for ResponseFuture<Fut>
+where
+    Fut: Future<Output = Result<Response, Error>>,
+    Error: From<ResponseError>,
+    ResponseError: From<Error>,
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        Poll::Ready(ready!(this.fut.poll(cx)).map_err(|err| {
+            let mut err = ResponseError::from(err);
+            if let Cow::Owned(msg) = this.locale.localize(&err.message) {
+                err.message = msg;
+            }
+            err.into()
+        }))
+    }
+}
+
+/// A [`tower_layer::Layer`] which builds [`Localization`].
+#[derive(Clone, Debug, Default)]
+#[must_use]
+pub struct LocalizationLayer {
+    locale: Locale,
+}
+
+impl LocalizationLayer {
+    /// Create the layer sharing the `locale` handle.
+    pub fn new(locale: Locale) -> Self {
+        Self { locale }
+    }
+}
+
+impl<S> Layer<S> for LocalizationLayer {
+    type Service = Localization<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Localization {
+            service: inner,
+            locale: self.locale.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+
+    use futures::channel::mpsc;
+    use lsp_types::NumberOrString;
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::{ErrorCode, MainLoopEvent, Message, PeerSocket, RequestId};
+
+    #[tokio::test]
+    async fn localize_error_response() {
+        let locale = Locale::new(|locale: &str, msg: &str| {
+            (locale == "fr" && msg.starts_with("No such method")).then(|| "Méthode inconnue".into())
+        });
+        let mut router = Router::new(());
+        router.request::<request::Initialize, _>(|_, _| async { Ok(Default::default()) });
+        let mut svc = LocalizationLayer::new(locale.clone()).layer(router);
+
+        let req = |method: &str, params| AnyRequest {
+            id: RequestId::Number(0),
+            method: method.into(),
+            params,
+            receipt: None,
+        };
+        poll_fn(|cx| svc.poll_ready(cx)).await.unwrap();
+        svc.call(req(
+            request::Initialize::METHOD,
+            json!({ "capabilities": {}, "locale": "fr" }),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(locale.get().as_deref(), Some("fr"));
+
+        let err = svc.call(req("foo", json!(null))).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::METHOD_NOT_FOUND);
+        assert_eq!(err.message, "Méthode inconnue");
+    }
+
+    #[test]
+    fn localize_messages() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let client = ClientSocket(PeerSocket { tx });
+        let locale =
+            Locale::new(|locale: &str, msg: &str| (locale == "fr").then(|| format!("[fr] {msg}")));
+        locale.set(Some("fr".into()));
+
+        locale
+            .show_message(&client, MessageType::INFO, "Hello")
+            .unwrap();
+        let progress = locale.begin_progress(client, NumberOrString::Number(0), "Indexing");
+        progress.report(Some("foo".into()), None);
+        progress.done(Some("Done".into()));
+
+        let mut params = Vec::new();
+        while let Ok(Some(event)) = rx.try_next() {
+            match event {
+                MainLoopEvent::Outgoing(Message::Notification(notif)) => params.push(notif.params),
+                _ => panic!("unexpected event"),
+            }
+        }
+        assert_eq!(
+            params,
+            [
+                json!({ "type": 3, "message": "[fr] Hello" }),
+                json!({ "token": 0, "value": { "kind": "begin", "title": "[fr] Indexing" } }),
+                json!({ "token": 0, "value": { "kind": "report", "message": "[fr] foo" } }),
+                json!({ "token": 0, "value": { "kind": "end", "message": "[fr] Done" } }),
+            ]
+        );
+    }
+}
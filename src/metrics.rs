@@ -0,0 +1,333 @@
+//! Report request and notification statistics via the [`metrics`][::metrics] facade.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! This middleware records the following metrics to the globally (or locally) installed
+//! [`metrics::Recorder`][::metrics::Recorder], so production Language Servers can export them to
+//! Prometheus or other monitoring systems with any compatible exporter.
+//!
+//! | Name | Kind | Labels | Description |
+//! |------|------|--------|-------------|
+//! | `lsp_requests_total` | counter | `method` | Incoming requests. |
+//! | `lsp_notifications_total` | counter | `method` | Incoming notifications. |
+//! | `lsp_requests_in_flight` | gauge | `method` | Requests being handled. |
+//! | `lsp_request_errors_total` | counter | `method`, `code` | Error responses by error codes. |
+//! | `lsp_request_duration_seconds` | histogram | `method` | Latencies of completed requests. |
+//! | `lsp_queue_delay_seconds` | histogram | `method` | Delays from the arrival of requests and notifications to the start of their handlers. |
+//!
+//! The name prefix `lsp` can be changed via [`MetricsLayer::prefix`].
+//!
+//! Queueing delays are measured from the [`Receipt`](crate::Receipt) of messages, thus only
+//! recorded for ones received from the peer. They include the time waiting for the service to be
+//! ready, and passing through middlewares outside of this one.
+//!
+//! Requests dropped before completion, eg. cancelled by [`crate::concurrency::Concurrency`], are
+//! removed from the in-flight gauge but recorded neither as errors nor into the histogram. Place
+//! this middleware outside of those producing error responses to also count their errors.
+use std::future::Future;
+use std::ops::ControlFlow;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Instant;
+
+use ::metrics::{counter, gauge, histogram};
+use pin_project_lite::pin_project;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{AnyEvent, AnyNotification, AnyRequest, EventHints, LspService, ResponseError, Result};
+
+#[derive(Debug)]
+struct Names {
+    requests: String,
+    notifications: String,
+    in_flight: String,
+    errors: String,
+    duration: String,
+    queue_delay: String,
+}
+
+impl Names {
+    fn new(prefix: &str) -> Self {
+        Self {
+            requests: format!("{prefix}_requests_total"),
+            notifications: format!("{prefix}_notifications_total"),
+            in_flight: format!("{prefix}_requests_in_flight"),
+            errors: format!("{prefix}_request_errors_total"),
+            duration: format!("{prefix}_request_duration_seconds"),
+            queue_delay: format!("{prefix}_queue_delay_seconds"),
+        }
+    }
+}
+
+/// The middleware reporting request and notification statistics.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+pub struct Metrics<S> {
+    service: S,
+    names: Arc<Names>,
+}
+
+define_getters!(impl[S] Metrics<S>, service: S);
+
+impl<S: LspService> Service<AnyRequest> for Metrics<S>
+where
+    S::Error: From<ResponseError>,
+    ResponseError: From<S::Error>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        let method = req.method.clone();
+        counter!(self.names.requests.clone(), "method" => method.clone()).increment(1);
+        gauge!(self.names.in_flight.clone(), "method" => method.clone()).increment(1.0);
+        if let Some(receipt) = req.receipt {
+            histogram!(self.names.queue_delay.clone(), "method" => method.clone())
+                .record(receipt.elapsed());
+        }
+        ResponseFuture {
+            fut: self.service.call(req),
+            guard: InFlight {
+                names: self.names.clone(),
+                method,
+                start: Instant::now(),
+            },
+        }
+    }
+}
+
+impl<S: LspService> LspService for Metrics<S>
+where
+    S::Error: From<ResponseError>,
+    ResponseError: From<S::Error>,
+{
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        counter!(self.names.notifications.clone(), "method" => notif.method.clone()).increment(1);
+        if let Some(receipt) = notif.receipt {
+            histogram!(self.names.queue_delay.clone(), "method" => notif.method.clone())
+                .record(receipt.elapsed());
+        }
+        self.service.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.service.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+/// Decrement the in-flight gauge when the request completes or is dropped.
+struct InFlight {
+    names: Arc<Names>,
+    method: String,
+    start: Instant,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        gauge!(self.names.in_flight.clone(), "method" => self.method.clone()).decrement(1.0);
+    }
+}
+
+pin_project! {
+    /// The [`Future`] type used by the [`Metrics`] middleware.
+    pub struct ResponseFuture<Fut> {
+        #[pin]
+        fut: Fut,
+        guard: InFlight,
+    }
+}
+
+impl<Fut, Response, Error> Future for ResponseFuture<Fut>
+where
+    Fut: Future<Output = Result<Response, Error>>,
+    Error: From<ResponseError>,
+    ResponseError: From<Error>,
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let ret = ready!(this.fut.poll(cx));
+        let guard = &*this.guard;
+        histogram!(guard.names.duration.clone(), "method" => guard.method.clone())
+            .record(guard.start.elapsed());
+        Poll::Ready(ret.map_err(|err| {
+            let err = ResponseError::from(err);
+            counter!(
+                guard.names.errors.clone(),
+                "method" => guard.method.clone(),
+                "code" => err.code.0.to_string(),
+            )
+            .increment(1);
+            err.into()
+        }))
+    }
+}
+
+/// A [`tower_layer::Layer`] which builds [`Metrics`].
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct MetricsLayer {
+    names: Arc<Names>,
+}
+
+impl Default for MetricsLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MetricsLayer {
+    /// Create the layer with the default metric name prefix `lsp`.
+    pub fn new() -> Self {
+        Self {
+            names: Arc::new(Names::new("lsp")),
+        }
+    }
+
+    /// Set the prefix of metric names, eg. `my_server` for `my_server_requests_total`.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.names = Arc::new(Names::new(prefix));
+        self
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = Metrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Metrics {
+            service: inner,
+            names: self.names.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{pending, poll_fn};
+
+    use futures::FutureExt;
+    use lsp_types::notification::{self, Notification};
+    use lsp_types::request::{self, Request};
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::{ErrorCode, Receipt, RequestId};
+
+    #[test]
+    fn record() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        ::metrics::with_local_recorder(&recorder, || {
+            let mut router = Router::<(), ResponseError>::new(());
+            router
+                .request::<request::Shutdown, _>(|_, ()| async { Ok(()) })
+                .request::<request::Initialize, _>(|_, _| pending())
+                .notification::<notification::Initialized>(|_, _| ControlFlow::Continue(()));
+            let mut svc = MetricsLayer::new().prefix("test").layer(router);
+
+            let req = |method: &str| AnyRequest {
+                id: RequestId::Number(0),
+                method: method.into(),
+                params: json!(null),
+                receipt: None,
+            };
+            poll_fn(|cx| svc.poll_ready(cx))
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+            svc.call(req(request::Shutdown::METHOD))
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+            let err = svc.call(req("foo")).now_or_never().unwrap().unwrap_err();
+            assert_eq!(err.code, ErrorCode::METHOD_NOT_FOUND);
+            let in_flight = svc.call(req(request::Initialize::METHOD));
+            let _ = svc.notify(AnyNotification {
+                method: notification::Initialized::METHOD.into(),
+                params: json!({}),
+                receipt: Some(Receipt {
+                    seq: 0,
+                    time: Instant::now(),
+                }),
+            });
+
+            // Snapshots reset recorded values, so take only one.
+            let snapshot = snapshotter.snapshot().into_vec();
+            let get = |name: &str, labels: &[(&str, &str)]| {
+                snapshot
+                    .iter()
+                    .find(|(key, ..)| {
+                        let key = key.key();
+                        key.name() == name
+                            && key
+                                .labels()
+                                .map(|label| (label.key(), label.value()))
+                                .eq(labels.iter().copied())
+                    })
+                    .map(|(.., value)| value)
+            };
+            let init = [("method", request::Initialize::METHOD)];
+            assert_eq!(
+                get("test_requests_in_flight", &init),
+                Some(&DebugValue::Gauge(1.0.into()))
+            );
+            assert_eq!(
+                get("test_requests_in_flight", &[("method", "shutdown")]),
+                Some(&DebugValue::Gauge(0.0.into()))
+            );
+
+            assert_eq!(
+                get("test_requests_total", &[("method", "foo")]),
+                Some(&DebugValue::Counter(1))
+            );
+            assert_eq!(
+                get("test_notifications_total", &[("method", "initialized")]),
+                Some(&DebugValue::Counter(1))
+            );
+            assert_eq!(
+                get(
+                    "test_request_errors_total",
+                    &[("method", "foo"), ("code", "-32601")]
+                ),
+                Some(&DebugValue::Counter(1))
+            );
+            assert_eq!(
+                get(
+                    "test_request_errors_total",
+                    &[("method", "shutdown"), ("code", "0")]
+                ),
+                None
+            );
+            assert!(matches!(
+                get("test_request_duration_seconds", &[("method", "shutdown")]),
+                Some(DebugValue::Histogram(v)) if v.len() == 1
+            ));
+            assert_eq!(get("test_request_duration_seconds", &init), None);
+            assert!(matches!(
+                get("test_queue_delay_seconds", &[("method", "initialized")]),
+                Some(DebugValue::Histogram(v)) if v.len() == 1
+            ));
+            assert_eq!(
+                get("test_queue_delay_seconds", &[("method", "shutdown")]),
+                None
+            );
+            drop(in_flight);
+        });
+    }
+}
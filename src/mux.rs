@@ -0,0 +1,490 @@
+//! Running multiple Language Servers behind one frontend.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! [`Mux`] routes messages to one of several child services, eg. to serve a polyglot workspace
+//! or to embed multiple engines behind one connection to the editor. Each child has a [`Route`]
+//! selecting documents by their language ids or URIs.
+//!
+//! - Documents are assigned to the first child whose route matches on `textDocument/didOpen`, and
+//!   their requests and notifications go to that child until `textDocument/didClose`. Documents
+//!   not opened are routed by their URIs only.
+//! - `initialize` is sent to all children, and their results are merged into one, see
+//!   [`Mux::child`]. `shutdown` is also sent to all children.
+//! - `workspace/executeCommand` goes to the child declaring the command in its
+//!   `executeCommandProvider`.
+//! - Notifications without a document, eg. `initialized`, `exit` and workspace-level ones, are
+//!   sent to all children. Other requests without a document go to the first child.
+//! - Events go to the first child.
+//!
+//! Children of different types can be stored as [`BoxLspService`], the default child type.
+//!
+//! ```
+//! # use async_lsp::mux::{Mux, Route};
+//! # use async_lsp::router::Router;
+//! let rust: Router<()> = Router::new(());
+//! let python: Router<()> = Router::new(());
+//! let mux = Mux::new()
+//!     .child(Route::new().language("rust"), rust)
+//!     .child(
+//!         Route::new()
+//!             .language("python")
+//!             .uri(|uri| uri.path().ends_with(".py")),
+//!         python,
+//!     );
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use futures::future::{self, BoxFuture};
+use futures::FutureExt;
+use lsp_types::notification::{DidCloseTextDocument, DidOpenTextDocument, Notification};
+use lsp_types::request::{ExecuteCommand, Initialize, Request, Shutdown};
+use lsp_types::Url;
+use serde_json::Value as JsonValue;
+use tower_service::Service;
+
+use crate::boxed::BoxLspService;
+use crate::coalesce::text_document_uri;
+use crate::{
+    AnyEvent, AnyNotification, AnyRequest, Error, ErrorCode, EventHints, LspService, ResponseError,
+    Result,
+};
+
+type UriPredicate = Arc<dyn Fn(&Url) -> bool + Send + Sync>;
+
+/// The documents routed to a child of [`Mux`].
+///
+/// A document matches if its language id or URI matches any of the rules.
+#[derive(Clone, Default)]
+#[must_use]
+pub struct Route {
+    languages: Vec<String>,
+    uris: Vec<UriPredicate>,
+}
+
+impl fmt::Debug for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Route")
+            .field("languages", &self.languages)
+            .field("uris", &self.uris.len())
+            .finish()
+    }
+}
+
+impl Route {
+    /// Create a route matching no documents.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Match documents of the language id `language`, eg. `rust`.
+    pub fn language(mut self, language: impl Into<String>) -> Self {
+        self.languages.push(language.into());
+        self
+    }
+
+    /// Match documents whose URIs satisfy `pred`, eg. by extensions.
+    pub fn uri(mut self, pred: impl Fn(&Url) -> bool + Send + Sync + 'static) -> Self {
+        self.uris.push(Arc::new(pred));
+        self
+    }
+
+    fn matches(&self, language: Option<&str>, uri: &Url) -> bool {
+        language.map_or(false, |lang| self.languages.iter().any(|l| l == lang))
+            || self.uris.iter().any(|pred| pred(uri))
+    }
+}
+
+/// The service routing messages to several child services.
+///
+/// See [module level documentations](self) for details.
+#[must_use]
+pub struct Mux<S = BoxLspService> {
+    children: Vec<(Route, S)>,
+    /// Opened documents, mapped to the indices of their children.
+    documents: HashMap<Url, usize>,
+    /// Commands declared on initialization, mapped to the indices of their children.
+    commands: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl<S> fmt::Debug for Mux<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mux")
+            .field(
+                "routes",
+                &self.children.iter().map(|(r, _)| r).collect::<Vec<_>>(),
+            )
+            .field("documents", &self.documents)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> Default for Mux<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Mux<S> {
+    /// Create a multiplexer without children.
+    pub fn new() -> Self {
+        Self {
+            children: Vec::new(),
+            documents: HashMap::new(),
+            commands: Arc::default(),
+        }
+    }
+
+    /// Add a child serving documents selected by `route`. Routes are matched in the order of
+    /// children.
+    ///
+    /// On `initialize`, the result of each child is merged into those of previous children:
+    /// missing fields are filled, objects are merged recursively, and arrays, eg. trigger
+    /// characters and commands, are united. Children should agree on `textDocumentSync`, since
+    /// the one of the first child declaring it is used.
+    pub fn child(mut self, route: Route, service: S) -> Self {
+        self.children.push((route, service));
+        self
+    }
+
+    /// Get a reference to the child at `index`, in the order of addition.
+    #[must_use]
+    pub fn get(&self, index: usize) -> Option<&S> {
+        self.children.get(index).map(|(_, s)| s)
+    }
+
+    /// Get a mutable reference to the child at `index`, in the order of addition.
+    #[must_use]
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut S> {
+        self.children.get_mut(index).map(|(_, s)| s)
+    }
+
+    /// Get the index of the child serving the document `uri`.
+    #[must_use]
+    pub fn route(&self, uri: &Url) -> Option<usize> {
+        self.documents.get(uri).copied().or_else(|| {
+            self.children
+                .iter()
+                .position(|(route, _)| route.matches(None, uri))
+        })
+    }
+}
+
+impl<S> Mux<S>
+where
+    S: LspService<Response = JsonValue, Error = ResponseError>,
+    S::Future: Send + 'static,
+{
+    fn call_all(&mut self, req: &AnyRequest) -> Vec<S::Future> {
+        self.children
+            .iter_mut()
+            .map(|(_, child)| child.call(req.clone()))
+            .collect()
+    }
+
+    fn initialize(
+        &mut self,
+        req: &AnyRequest,
+    ) -> BoxFuture<'static, Result<JsonValue, ResponseError>> {
+        let futs = self.call_all(req);
+        let commands = self.commands.clone();
+        async move {
+            let mut merged = JsonValue::Null;
+            for (i, ret) in future::join_all(futs).await.into_iter().enumerate() {
+                let ret = ret?;
+                let declared = ret
+                    .pointer("/capabilities/executeCommandProvider/commands")
+                    .and_then(JsonValue::as_array)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(JsonValue::as_str);
+                let mut commands = commands.lock().unwrap();
+                for cmd in declared {
+                    commands.entry(cmd.to_owned()).or_insert(i);
+                }
+                merge_json(&mut merged, ret);
+            }
+            Ok(merged)
+        }
+        .boxed()
+    }
+}
+
+/// Merge `from` into `into`, filling missing fields and uniting arrays.
+fn merge_json(into: &mut JsonValue, from: JsonValue) {
+    match (into, from) {
+        (into @ JsonValue::Null, from) => *into = from,
+        (JsonValue::Object(into), JsonValue::Object(from)) => {
+            for (key, value) in from {
+                merge_json(into.entry(key).or_insert(JsonValue::Null), value);
+            }
+        }
+        (JsonValue::Array(into), JsonValue::Array(from)) => {
+            for value in from {
+                if !into.contains(&value) {
+                    into.push(value);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+impl<S> Service<AnyRequest> for Mux<S>
+where
+    S: LspService<Response = JsonValue, Error = ResponseError>,
+    S::Future: Send + 'static,
+{
+    type Response = JsonValue;
+    type Error = ResponseError;
+    type Future = BoxFuture<'static, Result<JsonValue, ResponseError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Which child to call is unknown yet. All must be ready.
+        for (_, child) in &mut self.children {
+            ready!(child.poll_ready(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        let idx = match req.method.as_str() {
+            Initialize::METHOD => return self.initialize(&req),
+            Shutdown::METHOD => {
+                let futs = self.call_all(&req);
+                return async move {
+                    future::try_join_all(futs).await?;
+                    Ok(JsonValue::Null)
+                }
+                .boxed();
+            }
+            ExecuteCommand::METHOD => req
+                .params
+                .get("command")
+                .and_then(JsonValue::as_str)
+                .and_then(|cmd| self.commands.lock().unwrap().get(cmd).copied())
+                .or_else(|| (!self.children.is_empty()).then_some(0)),
+            _ => match text_document_uri(&req.params) {
+                Some(uri) => match self.route(&uri) {
+                    Some(idx) => Some(idx),
+                    None => {
+                        return future::ready(Err(ResponseError::new(
+                            ErrorCode::REQUEST_FAILED,
+                            format_args!("no server for document {uri}"),
+                        )))
+                        .boxed()
+                    }
+                },
+                None => (!self.children.is_empty()).then_some(0),
+            },
+        };
+        match idx {
+            Some(idx) => self.children[idx].1.call(req).boxed(),
+            None => future::ready(Err(ResponseError::new(
+                ErrorCode::METHOD_NOT_FOUND,
+                format_args!("no server for method {}", req.method),
+            )))
+            .boxed(),
+        }
+    }
+}
+
+impl<S> LspService for Mux<S>
+where
+    S: LspService<Response = JsonValue, Error = ResponseError>,
+    S::Future: Send + 'static,
+{
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        let uri = match text_document_uri(&notif.params) {
+            Some(uri) => uri,
+            None => {
+                for (_, child) in &mut self.children {
+                    child.notify(notif.clone())?;
+                }
+                return ControlFlow::Continue(());
+            }
+        };
+        if notif.method == DidOpenTextDocument::METHOD {
+            let language = notif
+                .params
+                .pointer("/textDocument/languageId")
+                .and_then(JsonValue::as_str);
+            match self
+                .children
+                .iter()
+                .position(|(route, _)| route.matches(language, &uri))
+            {
+                Some(idx) => self.documents.insert(uri.clone(), idx),
+                None => return ControlFlow::Continue(()),
+            };
+        }
+        let idx = match self.route(&uri) {
+            Some(idx) => idx,
+            // Notifications of unserved documents are ignored.
+            None => return ControlFlow::Continue(()),
+        };
+        if notif.method == DidCloseTextDocument::METHOD {
+            self.documents.remove(&uri);
+        }
+        self.children[idx].1.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        match self.children.first_mut() {
+            Some((_, child)) => child.emit(event),
+            None => ControlFlow::Break(Err(Error::Routing(format!("Unhandled event: {event:?}")))),
+        }
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.children
+            .first()
+            .map(|(_, child)| child.event_hints(event))
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{poll_fn, ready};
+
+    use lsp_types::notification::DidChangeConfiguration;
+    use lsp_types::request::HoverRequest;
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::RequestId;
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    fn child(name: &'static str, log: &Log, init: JsonValue) -> Router<(&'static str, Log)> {
+        let mut router = Router::new((name, log.clone()));
+        let init: lsp_types::InitializeResult = serde_json::from_value(init).unwrap();
+        router
+            .request::<Initialize, _>(move |_, _| ready(Ok(init.clone())))
+            .unhandled_request(|(name, _), _| ready(Ok(json!(*name))))
+            .unhandled_notification(|(name, log), notif| {
+                log.lock().unwrap().push(format!("{name}:{}", notif.method));
+                ControlFlow::Continue(())
+            });
+        router
+    }
+
+    #[tokio::test]
+    async fn mux() {
+        let log = Log::default();
+        let rust = child(
+            "rust",
+            &log,
+            json!({ "capabilities": {
+                "hoverProvider": true,
+                "completionProvider": { "triggerCharacters": ["."] },
+                "executeCommandProvider": { "commands": ["rust.run"] },
+            } }),
+        );
+        let python = child(
+            "python",
+            &log,
+            json!({ "capabilities": {
+                "definitionProvider": true,
+                "completionProvider": { "triggerCharacters": [".", ":"] },
+                "executeCommandProvider": { "commands": ["python.run"] },
+            } }),
+        );
+        let mut mux = Mux::new()
+            .child(
+                Route::new()
+                    .language("rust")
+                    .uri(|uri| uri.path().ends_with(".rs")),
+                rust,
+            )
+            .child(Route::new().language("python"), python);
+
+        let mut call = |method: &str, params: JsonValue| {
+            poll_fn(|cx| mux.poll_ready(cx))
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+            mux.call(AnyRequest {
+                id: RequestId::Number(0),
+                method: method.into(),
+                params,
+                receipt: None,
+            })
+        };
+        let ret = call(Initialize::METHOD, json!({ "capabilities": {} }))
+            .await
+            .unwrap();
+        assert_eq!(
+            ret,
+            json!({ "capabilities": {
+                "hoverProvider": true,
+                "definitionProvider": true,
+                "completionProvider": { "triggerCharacters": [".", ":"] },
+                "executeCommandProvider": { "commands": ["rust.run", "python.run"] },
+            } }),
+        );
+        let ret = call(ExecuteCommand::METHOD, json!({ "command": "python.run" }))
+            .await
+            .unwrap();
+        assert_eq!(ret, json!("python"));
+
+        for (method, params) in [
+            (
+                DidOpenTextDocument::METHOD,
+                json!({ "textDocument": {
+                    "uri": "file:///script",
+                    "languageId": "python",
+                    "version": 0,
+                    "text": "",
+                } }),
+            ),
+            (DidChangeConfiguration::METHOD, json!({ "settings": {} })),
+        ] {
+            let notif = AnyNotification {
+                method: method.into(),
+                params,
+                receipt: None,
+            };
+            assert!(matches!(mux.notify(notif), ControlFlow::Continue(())));
+        }
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "python:textDocument/didOpen",
+                "rust:workspace/didChangeConfiguration",
+                "python:workspace/didChangeConfiguration",
+            ],
+        );
+
+        for (uri, expect) in [
+            ("file:///script", Ok("python")),
+            ("file:///main.rs", Ok("rust")),
+            ("file:///main.c", Err(ErrorCode::REQUEST_FAILED)),
+        ] {
+            let params = json!({
+                "textDocument": { "uri": uri },
+                "position": { "line": 0, "character": 0 },
+            });
+            poll_fn(|cx| mux.poll_ready(cx)).await.unwrap();
+            let ret = mux
+                .call(AnyRequest {
+                    id: RequestId::Number(0),
+                    method: HoverRequest::METHOD.into(),
+                    params,
+                    receipt: None,
+                })
+                .await;
+            assert_eq!(
+                ret.map_err(|err| err.code),
+                expect.map(|name| json!(name)),
+                "{uri}"
+            );
+        }
+    }
+}
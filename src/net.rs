@@ -0,0 +1,294 @@
+//! Serve Language Servers over TCP or Unix domain sockets.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Besides stdio, many editors and debugging setups connect to Language Servers via a socket, eg.
+//! with a `--port` argument. [`Server`] accepts connections on a listening socket, and serves each
+//! of them with a fresh service and [`MainLoop`] spawned onto the [`tokio`] runtime.
+//! Alternatively, [`Server::serve_sequential`] serves one connection at a time with a single
+//! service, which is reused across reconnecting editors.
+//!
+//! ```no_run
+//! # use async_lsp::net::Server;
+//! # use async_lsp::router::Router;
+//! # async fn work() -> std::io::Result<()> {
+//! let server = Server::bind_tcp("127.0.0.1:9257").await?;
+//! server
+//!     .serve(|client| {
+//!         let mut router = Router::new(client);
+//!         // Register handlers.
+//!         router
+//!     })
+//!     .await
+//! # }
+//! ```
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+
+use futures::io::BufReader;
+use serde_json::Value as JsonValue;
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+use crate::{ClientSocket, LspService, MainLoop, ResponseError};
+
+/// A listening socket accepting Language Client connections.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+pub struct Server {
+    listener: Listener,
+}
+
+#[derive(Debug)]
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Server {
+    /// Listen on a TCP address.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the address cannot be resolved or bound.
+    pub async fn bind_tcp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self {
+            listener: Listener::Tcp(listener),
+        })
+    }
+
+    /// Listen on a Unix domain socket at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the path cannot be bound, eg. it already exists.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn bind_unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        let listener = UnixListener::bind(path)?;
+        Ok(Self {
+            listener: Listener::Unix(listener),
+        })
+    }
+
+    /// Get the local address of a TCP listener, or `None` for other kinds of listeners.
+    ///
+    /// This is useful to get the actual port after binding on port 0.
+    #[must_use]
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+
+    /// Accept connections forever, serving each with a service created by `builder`.
+    ///
+    /// Each connection runs its own [`MainLoop`] in a task spawned via [`tokio::spawn`], thus
+    /// this must be called inside a [`tokio`] runtime. Errors of individual connections are
+    /// logged and do not stop the server.
+    ///
+    /// # Errors
+    ///
+    /// Fails if accepting a connection fails.
+    pub async fn serve<S>(self, mut builder: impl FnMut(ClientSocket) -> S) -> io::Result<()>
+    where
+        S: LspService<Response = JsonValue> + Send + 'static,
+        S::Future: Send,
+        S::Error: Send,
+        ResponseError: From<S::Error>,
+    {
+        loop {
+            match &self.listener {
+                Listener::Tcp(listener) => {
+                    let (stream, _addr) = listener.accept().await?;
+                    spawn_connection(stream, &mut builder);
+                }
+                #[cfg(unix)]
+                Listener::Unix(listener) => {
+                    let (stream, _addr) = listener.accept().await?;
+                    spawn_connection(stream, &mut builder);
+                }
+            }
+        }
+    }
+
+    /// Accept connections forever, serving them one at a time with a single service created by
+    /// `builder`.
+    ///
+    /// Each connection is served by [`MainLoop::run_session`] on the same main loop, until it
+    /// ends. The service should reset itself for a new session, eg. via
+    /// [`Lifecycle::restartable`][crate::server::Lifecycle::restartable]. Errors of individual
+    /// connections are logged and do not stop the server.
+    ///
+    /// # Errors
+    ///
+    /// Fails if accepting a connection fails.
+    pub async fn serve_sequential<S>(
+        self,
+        builder: impl FnOnce(ClientSocket) -> S,
+    ) -> io::Result<()>
+    where
+        S: LspService<Response = JsonValue>,
+        ResponseError: From<S::Error>,
+    {
+        // The main loop requires the socket alive, even if the service does not hold it.
+        let (mut mainloop, _client) = MainLoop::new_server(builder);
+        loop {
+            let ret = match &self.listener {
+                Listener::Tcp(listener) => {
+                    let (stream, _addr) = listener.accept().await?;
+                    run_connection(&mut mainloop, stream).await
+                }
+                #[cfg(unix)]
+                Listener::Unix(listener) => {
+                    let (stream, _addr) = listener.accept().await?;
+                    run_connection(&mut mainloop, stream).await
+                }
+            };
+            if let Err(err) = ret {
+                log_error(err);
+            }
+        }
+    }
+}
+
+async fn run_connection<S, T>(mainloop: &mut MainLoop<S>, stream: T) -> crate::Result<()>
+where
+    S: LspService<Response = JsonValue>,
+    ResponseError: From<S::Error>,
+    T: AsyncRead + AsyncWrite,
+{
+    let (input, output) = tokio::io::split(stream);
+    let input = BufReader::with_capacity(mainloop.buffer_capacity, input.compat());
+    mainloop.run_session(input, output.compat_write()).await
+}
+
+fn log_error(err: crate::Error) {
+    #[cfg(feature = "tracing")]
+    ::tracing::warn!("Connection closed with error: {err}");
+    #[cfg(not(feature = "tracing"))]
+    let _ = err;
+}
+
+fn spawn_connection<S, T>(stream: T, builder: impl FnOnce(ClientSocket) -> S)
+where
+    S: LspService<Response = JsonValue> + Send + 'static,
+    S::Future: Send,
+    S::Error: Send,
+    ResponseError: From<S::Error>,
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mainloop, client) = MainLoop::new_server(builder);
+    let (input, output) = tokio::io::split(stream);
+    tokio::spawn(async move {
+        // The main loop requires the socket alive, even if the service does not hold it.
+        let _client = client;
+        if let Err(err) = mainloop
+            .run_buffered(input.compat(), output.compat_write())
+            .await
+        {
+            log_error(err);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::request::Shutdown;
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::router::Router;
+
+    async fn request_shutdown<T: AsyncRead + AsyncWrite + Send + 'static>(stream: T) {
+        let (mainloop, server) = MainLoop::new_client(|_| Router::<()>::new(()));
+        let (input, output) = tokio::io::split(stream);
+        let task = tokio::spawn(mainloop.run_buffered(input.compat(), output.compat_write()));
+        server.request::<Shutdown>(()).await.unwrap();
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn tcp_and_unix() {
+        let serve = |server: Server| {
+            tokio::spawn(server.serve(|_| {
+                let mut router = Router::new(());
+                router.request::<Shutdown, _>(|_, ()| std::future::ready(Ok(())));
+                router
+            }))
+        };
+
+        let server = Server::bind_tcp("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let tcp = serve(server);
+        // Multiple connections are served independently.
+        for _ in 0..2 {
+            request_shutdown(TcpStream::connect(addr).await.unwrap()).await;
+        }
+        tcp.abort();
+
+        #[cfg(unix)]
+        {
+            let path =
+                std::env::temp_dir().join(format!("async-lsp-net-{}.sock", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let server = Server::bind_unix(&path).unwrap();
+            assert_eq!(server.local_addr(), None);
+            let unix = serve(server);
+            request_shutdown(tokio::net::UnixStream::connect(&path).await.unwrap()).await;
+            unix.abort();
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn sequential() {
+        use lsp_types::notification::{Exit, Initialized};
+        use lsp_types::request::Initialize;
+        use lsp_types::{InitializeParams, InitializeResult, InitializedParams};
+
+        use crate::server::Lifecycle;
+
+        let server = Server::bind_tcp("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let task = tokio::spawn(server.serve_sequential(|_| {
+            let mut router = Router::new(());
+            router
+                .request::<Initialize, _>(|_, _| {
+                    std::future::ready(Ok(InitializeResult::default()))
+                })
+                .request::<Shutdown, _>(|_, ()| std::future::ready(Ok(())))
+                .notification::<Initialized>(|_, _| std::ops::ControlFlow::Continue(()))
+                .notification::<Exit>(|_, ()| std::ops::ControlFlow::Continue(()));
+            Lifecycle::new(router).restartable()
+        }));
+
+        // The same service is initialized again after reconnecting.
+        for _ in 0..2 {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let (mainloop, server) = MainLoop::new_client(|_| Router::<()>::new(()));
+            let (input, output) = tokio::io::split(stream);
+            let client = tokio::spawn(mainloop.run_buffered(input.compat(), output.compat_write()));
+            server
+                .request::<Initialize>(InitializeParams::default())
+                .await
+                .unwrap();
+            server.notify::<Initialized>(InitializedParams {}).unwrap();
+            server.request::<Shutdown>(()).await.unwrap();
+            server.notify::<Exit>(()).unwrap();
+            // The server closes the connection after `exit`.
+            assert!(client.await.unwrap().is_err());
+        }
+        task.abort();
+    }
+}
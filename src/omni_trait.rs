@@ -1,5 +1,6 @@
 use std::future::ready;
 use std::ops::ControlFlow;
+use std::sync::Arc;
 
 use futures::future::BoxFuture;
 use lsp_types::notification::{self, Notification};
@@ -7,7 +8,7 @@ use lsp_types::request::{self, Request};
 use lsp_types::{lsp_notification, lsp_request};
 
 use crate::router::Router;
-use crate::{ClientSocket, ErrorCode, ResponseError, Result, ServerSocket};
+use crate::{ClientSocket, ErrorCode, RequestId, ResponseError, Result, ServerSocket};
 
 use self::sealed::NotifyResult;
 
@@ -41,6 +42,42 @@ mod sealed {
     }
 }
 
+/// The context passed to each handler of [`ContextualLanguageServer`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ServerContext {
+    /// The socket to the Language Client.
+    pub client: ClientSocket,
+    /// The id of the request being handled, or `None` for notifications.
+    pub request_id: Option<RequestId>,
+}
+
+/// States of [`LanguageServer`]s which keep the [`ClientSocket`], eg. to send notifications from
+/// their handlers.
+///
+/// The socket is only available inside the builder closure of
+/// [`MainLoop::new_server`](crate::MainLoop::new_server), so states are usually constructed there.
+/// Implementing this trait allows constructing states beforehand, eg. via [`Default`], and
+/// injecting the socket later by [`Router::from_language_server_with_client`].
+pub trait WithClient {
+    /// Store the socket to the Language Client.
+    fn set_client(&mut self, client: ClientSocket);
+}
+
+impl<S> Router<S>
+where
+    S: LanguageServer<NotifyResult = ControlFlow<crate::Result<()>>> + WithClient,
+    ResponseError: From<S::Error>,
+{
+    /// Create a [`Router`] using its implementation of [`LanguageServer`] as handlers, after
+    /// passing `client` to [`WithClient::set_client`] of `state`.
+    #[must_use]
+    pub fn from_language_server_with_client(mut state: S, client: ClientSocket) -> Self {
+        state.set_client(client);
+        Self::from_language_server(state)
+    }
+}
+
 type ResponseFuture<R, E> = BoxFuture<'static, Result<<R as Request>::Result, E>>;
 
 fn method_not_found<R, E>() -> ResponseFuture<R, E>
@@ -89,6 +126,22 @@ macro_rules! define_server {
             /// Should always be defined to `ControlFlow<Result<()>>` for user implementations.
             type NotifyResult: NotifyResult;
 
+            // Extensions.
+
+            /// Register handlers of custom or extension methods, eg. `experimental/...`, to the
+            /// [`Router`] created by [`Router::from_language_server`]. Default is to register nothing.
+            ///
+            /// Custom methods are declared by implementing [`Request`] or [`Notification`] on
+            /// user types, and handled with typed parameters and results via [`Router::request`]
+            /// and [`Router::notification`]. Handlers of standard methods defined by this trait
+            /// can also be overridden here.
+            fn custom_methods(router: &mut Router<Self>)
+            where
+                Self: Sized,
+            {
+                let _ = router;
+            }
+
             // Requests.
 
             #[must_use]
@@ -147,6 +200,92 @@ macro_rules! define_server {
             )*
         }
 
+        /// A variant of [`LanguageServer`] whose handlers also receive a [`ServerContext`],
+        /// carrying the [`ClientSocket`] and the current request id.
+        ///
+        /// This saves trait-based servers from storing the socket in their states manually. Use
+        /// [`Router::from_contextual_language_server`] to create the service.
+        #[allow(missing_docs)]
+        pub trait ContextualLanguageServer {
+            // Extensions.
+
+            /// Register handlers of custom or extension methods, eg. `experimental/...`, to the
+            /// [`Router`] created by [`Router::from_contextual_language_server`]. Default is to
+            /// register nothing.
+            ///
+            /// Same as [`LanguageServer::custom_methods`], but also receives `client` passed to
+            /// [`Router::from_contextual_language_server`], since handlers registered here
+            /// receive no [`ServerContext`].
+            fn custom_methods(router: &mut Router<Self>, client: &ClientSocket)
+            where
+                Self: Sized,
+            {
+                let _ = (router, client);
+            }
+
+            // Requests.
+
+            #[must_use]
+            fn initialize(
+                &mut self,
+                ctx: &mut ServerContext,
+                params: <request::Initialize as Request>::Params,
+            ) -> ResponseFuture<request::Initialize, ResponseError>;
+
+            #[must_use]
+            fn shutdown(
+                &mut self,
+                ctx: &mut ServerContext,
+                (): <request::Shutdown as Request>::Params,
+            ) -> ResponseFuture<request::Shutdown, ResponseError> {
+                let _ = ctx;
+                Box::pin(ready(Ok(())))
+            }
+
+            $(
+            #[must_use]
+            fn $req_snake(
+                &mut self,
+                ctx: &mut ServerContext,
+                params: <$req as Request>::Params,
+            ) -> ResponseFuture<$req, ResponseError> {
+                let _ = (ctx, params);
+                method_not_found::<$req, _>()
+            }
+            )*
+
+            // Notifications.
+
+            fn initialized(
+                &mut self,
+                ctx: &mut ServerContext,
+                params: <notification::Initialized as Notification>::Params,
+            ) -> ControlFlow<Result<()>> {
+                let _ = (ctx, params);
+                NotifyResult::fallback::<notification::Initialized>()
+            }
+
+            fn exit(
+                &mut self,
+                ctx: &mut ServerContext,
+                (): <notification::Exit as Notification>::Params,
+            ) -> ControlFlow<Result<()>> {
+                let _ = ctx;
+                NotifyResult::fallback::<notification::Exit>()
+            }
+
+            $(
+            fn $notif_snake(
+                &mut self,
+                ctx: &mut ServerContext,
+                params: <$notif as Notification>::Params,
+            ) -> ControlFlow<Result<()>> {
+                let _ = (ctx, params);
+                NotifyResult::fallback::<$notif>()
+            }
+            )*
+        }
+
         macro_rules! impl_server_socket {
             ($ty:ty) => {
                 impl LanguageServer for $ty {
@@ -233,6 +372,51 @@ macro_rules! define_server {
                 this.notification::<notification::Initialized>(|state, params| state.initialized(params));
                 this.notification::<notification::Exit>(|state, params| state.exit(params));
                 $(this.notification::<$notif>(|state, params| state.$notif_snake(params));)*
+                S::custom_methods(&mut this);
+                this
+            }
+        }
+
+        impl<S: ContextualLanguageServer> Router<S> {
+            /// Create a [`Router`] using its implementation of [`ContextualLanguageServer`] as
+            /// handlers, passing contexts with `client` to them.
+            #[must_use]
+            pub fn from_contextual_language_server(state: S, client: ClientSocket) -> Self {
+                let mut this = Self::new(state);
+                // Shared by all handlers, which build the context once per call.
+                let client = Arc::new(client);
+                let ctx = {
+                    let client = client.clone();
+                    move |request_id| ServerContext {
+                        client: ClientSocket::clone(&client),
+                        request_id,
+                    }
+                };
+                this.request_with_id::<request::Initialize, _>({
+                    let ctx = ctx.clone();
+                    move |state, id, params| state.initialize(&mut ctx(Some(id)), params)
+                });
+                this.request_with_id::<request::Shutdown, _>({
+                    let ctx = ctx.clone();
+                    move |state, id, params| state.shutdown(&mut ctx(Some(id)), params)
+                });
+                $(this.request_with_id::<$req, _>({
+                    let ctx = ctx.clone();
+                    move |state, id, params| state.$req_snake(&mut ctx(Some(id)), params)
+                });)*
+                this.notification::<notification::Initialized>({
+                    let ctx = ctx.clone();
+                    move |state, params| state.initialized(&mut ctx(None), params)
+                });
+                this.notification::<notification::Exit>({
+                    let ctx = ctx.clone();
+                    move |state, params| state.exit(&mut ctx(None), params)
+                });
+                $(this.notification::<$notif>({
+                    let ctx = ctx.clone();
+                    move |state, params| state.$notif_snake(&mut ctx(None), params)
+                });)*
+                S::custom_methods(&mut this, &client);
                 this
             }
         }
@@ -253,6 +437,22 @@ macro_rules! define_client {
             /// Should always be defined to `ControlFlow<Result<()>>` for user implementations.
             type NotifyResult: NotifyResult;
 
+            // Extensions.
+
+            /// Register handlers of custom or extension methods, eg. `experimental/...`, to the
+            /// [`Router`] created by [`Router::from_language_client`]. Default is to register nothing.
+            ///
+            /// Custom methods are declared by implementing [`Request`] or [`Notification`] on
+            /// user types, and handled with typed parameters and results via [`Router::request`]
+            /// and [`Router::notification`]. Handlers of standard methods defined by this trait
+            /// can also be overridden here.
+            fn custom_methods(router: &mut Router<Self>)
+            where
+                Self: Sized,
+            {
+                let _ = router;
+            }
+
             // Requests.
             $(
             #[must_use]
@@ -310,6 +510,32 @@ macro_rules! define_client {
         impl_client_socket!(ClientSocket);
         impl_client_socket!(&'_ ClientSocket);
 
+        #[cfg(feature = "testing")]
+        impl LanguageClient for crate::testing::MockClient {
+            type Error = ResponseError;
+            type NotifyResult = ControlFlow<crate::Result<()>>;
+
+            // Requests.
+            $(
+            fn $req_snake(
+                &mut self,
+                params: <$req as Request>::Params,
+            ) -> ResponseFuture<$req, Self::Error> {
+                self.handle_request::<$req>(params)
+            }
+            )*
+
+            // Notifications.
+            $(
+            fn $notif_snake(
+                &mut self,
+                params: <$notif as Notification>::Params,
+            ) -> Self::NotifyResult {
+                self.handle_notification::<$notif>(params)
+            }
+            )*
+        }
+
         impl<S> Router<S>
         where
             S: LanguageClient<NotifyResult = ControlFlow<crate::Result<()>>>,
@@ -324,6 +550,7 @@ macro_rules! define_client {
                     async move { fut.await.map_err(Into::into) }
                 });)*
                 $(this.notification::<$notif>(|state, params| state.$notif_snake(params));)*
+                S::custom_methods(&mut this);
                 this
             }
         }
@@ -331,3 +558,307 @@ macro_rules! define_client {
 }
 
 include!("./omni_trait_generated.rs");
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+
+    use futures::channel::mpsc;
+    use lsp_types::{HoverParams, MessageType, ShowMessageParams};
+    use serde_json::json;
+    use tower_service::Service;
+
+    use super::*;
+    use crate::{
+        AnyNotification, AnyRequest, AnyResponse, LspService, MainLoopEvent, Message, PeerSocket,
+    };
+
+    struct Server;
+
+    enum Ping {}
+
+    impl Request for Ping {
+        type Params = String;
+        type Result = String;
+        const METHOD: &'static str = "experimental/ping";
+    }
+
+    impl ContextualLanguageServer for Server {
+        fn custom_methods(router: &mut Router<Self>, client: &ClientSocket) {
+            let client = client.clone();
+            router.request::<Ping, _>(move |_, params| {
+                let ret = client.notify::<notification::ShowMessage>(ShowMessageParams {
+                    typ: MessageType::INFO,
+                    message: params.clone(),
+                });
+                ready(
+                    ret.map(|()| format!("pong {params}"))
+                        .map_err(|err| ResponseError::new(ErrorCode::INTERNAL_ERROR, err)),
+                )
+            });
+        }
+
+        fn initialize(
+            &mut self,
+            _: &mut ServerContext,
+            _: lsp_types::InitializeParams,
+        ) -> ResponseFuture<request::Initialize, ResponseError> {
+            Box::pin(ready(Ok(lsp_types::InitializeResult::default())))
+        }
+
+        fn hover(
+            &mut self,
+            ctx: &mut ServerContext,
+            _: HoverParams,
+        ) -> ResponseFuture<request::HoverRequest, ResponseError> {
+            let ret = ctx
+                .client
+                .notify::<notification::ShowMessage>(ShowMessageParams {
+                    typ: MessageType::INFO,
+                    message: format!("{:?}", ctx.request_id),
+                });
+            Box::pin(ready(ret.map(|()| None).map_err(|err| {
+                ResponseError::new(ErrorCode::INTERNAL_ERROR, err)
+            })))
+        }
+
+        fn initialized(
+            &mut self,
+            ctx: &mut ServerContext,
+            _: lsp_types::InitializedParams,
+        ) -> ControlFlow<Result<()>> {
+            assert_eq!(ctx.request_id, None);
+            ControlFlow::Continue(())
+        }
+    }
+
+    #[tokio::test]
+    async fn contextual_server() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let mut router =
+            Router::from_contextual_language_server(Server, ClientSocket(PeerSocket { tx }));
+
+        poll_fn(|cx| router.poll_ready(cx)).await.unwrap();
+        let ret = router
+            .call(AnyRequest {
+                id: RequestId::Number(42),
+                method: request::HoverRequest::METHOD.into(),
+                params: json!({
+                    "textDocument": { "uri": "file:///foo" },
+                    "position": { "line": 0, "character": 0 },
+                }),
+                receipt: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(ret, json!(null));
+        match rx.try_next().unwrap().unwrap() {
+            MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                assert_eq!(notif.params["message"], "Some(Number(42))");
+            }
+            _ => panic!("unexpected event"),
+        }
+
+        let notif = AnyNotification {
+            method: notification::Initialized::METHOD.into(),
+            params: json!({}),
+            receipt: None,
+        };
+        assert!(matches!(router.notify(notif), ControlFlow::Continue(())));
+
+        let ret = router
+            .call(AnyRequest {
+                id: RequestId::Number(43),
+                method: Ping::METHOD.into(),
+                params: json!("foo"),
+                receipt: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(ret, json!("pong foo"));
+        match rx.try_next().unwrap().unwrap() {
+            MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                assert_eq!(notif.params["message"], "foo");
+            }
+            _ => panic!("unexpected event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn typed_server_socket() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let mut server = ServerSocket(PeerSocket { tx });
+
+        let hover = server.hover(HoverParams {
+            text_document_position_params: lsp_types::TextDocumentPositionParams::new(
+                lsp_types::TextDocumentIdentifier::new("file:///foo".parse().unwrap()),
+                lsp_types::Position::new(1, 2),
+            ),
+            work_done_progress_params: Default::default(),
+        });
+        match rx.try_next().unwrap().unwrap() {
+            MainLoopEvent::OutgoingRequest(req, resp_tx) => {
+                assert_eq!(req.method, request::HoverRequest::METHOD);
+                assert_eq!(req.params["position"], json!({ "line": 1, "character": 2 }));
+                resp_tx
+                    .send(AnyResponse {
+                        id: req.id,
+                        result: Some(json!(null)),
+                        error: None,
+                        raw: None,
+                    })
+                    .unwrap();
+            }
+            _ => panic!("unexpected event"),
+        }
+        assert_eq!(hover.await.unwrap(), None);
+
+        (&server)
+            .initialized(lsp_types::InitializedParams {})
+            .unwrap();
+        match rx.try_next().unwrap().unwrap() {
+            MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                assert_eq!(notif.method, notification::Initialized::METHOD);
+            }
+            _ => panic!("unexpected event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn typed_client_socket() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let mut client = ClientSocket(PeerSocket { tx });
+
+        let apply = client.apply_edit(lsp_types::ApplyWorkspaceEditParams {
+            label: None,
+            edit: lsp_types::WorkspaceEdit::default(),
+        });
+        let show = (&client).show_message_request(lsp_types::ShowMessageRequestParams {
+            typ: MessageType::INFO,
+            message: "foo".into(),
+            actions: None,
+        });
+        let mut reqs = Vec::new();
+        while let Ok(Some(event)) = rx.try_next() {
+            match event {
+                MainLoopEvent::OutgoingRequest(req, resp_tx) => reqs.push((req, resp_tx)),
+                _ => panic!("unexpected event"),
+            }
+        }
+        let methods = reqs.iter().map(|(req, _)| &*req.method).collect::<Vec<_>>();
+        assert_eq!(
+            methods,
+            [
+                request::ApplyWorkspaceEdit::METHOD,
+                request::ShowMessageRequest::METHOD,
+            ],
+        );
+        // Reply out of order.
+        for (req, resp_tx) in reqs.into_iter().rev() {
+            let result = if req.method == request::ApplyWorkspaceEdit::METHOD {
+                json!({ "applied": true })
+            } else {
+                json!(null)
+            };
+            resp_tx
+                .send(AnyResponse {
+                    id: req.id,
+                    result: Some(result),
+                    error: None,
+                    raw: None,
+                })
+                .unwrap();
+        }
+        assert_eq!(show.await.unwrap(), None);
+        assert!(apply.await.unwrap().applied);
+    }
+
+    #[tokio::test]
+    async fn with_client() {
+        #[derive(Default)]
+        struct Server {
+            client: Option<ClientSocket>,
+        }
+
+        impl WithClient for Server {
+            fn set_client(&mut self, client: ClientSocket) {
+                self.client = Some(client);
+            }
+        }
+
+        impl LanguageServer for Server {
+            type Error = ResponseError;
+            type NotifyResult = ControlFlow<Result<()>>;
+
+            fn initialize(
+                &mut self,
+                _: lsp_types::InitializeParams,
+            ) -> ResponseFuture<request::Initialize, ResponseError> {
+                Box::pin(ready(Ok(lsp_types::InitializeResult::default())))
+            }
+
+            fn initialized(&mut self, _: lsp_types::InitializedParams) -> Self::NotifyResult {
+                let client = self.client.as_ref().unwrap();
+                client
+                    .notify::<notification::ShowMessage>(ShowMessageParams {
+                        typ: MessageType::INFO,
+                        message: "ready".into(),
+                    })
+                    .unwrap();
+                ControlFlow::Continue(())
+            }
+        }
+
+        let (tx, mut rx) = mpsc::unbounded();
+        let mut router = Router::from_language_server_with_client(
+            Server::default(),
+            ClientSocket(PeerSocket { tx }),
+        );
+        let notif = AnyNotification {
+            method: notification::Initialized::METHOD.into(),
+            params: json!({}),
+            receipt: None,
+        };
+        assert!(matches!(router.notify(notif), ControlFlow::Continue(())));
+        match rx.try_next().unwrap().unwrap() {
+            MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                assert_eq!(notif.params["message"], "ready");
+            }
+            _ => panic!("unexpected event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_methods() {
+        struct Server(&'static str);
+
+        impl LanguageServer for Server {
+            type Error = ResponseError;
+            type NotifyResult = ControlFlow<Result<()>>;
+
+            fn custom_methods(router: &mut Router<Self>) {
+                router.request::<Ping, _>(|this, params| ready(Ok(format!("{}{params}", this.0))));
+            }
+
+            fn initialize(
+                &mut self,
+                _: lsp_types::InitializeParams,
+            ) -> ResponseFuture<request::Initialize, ResponseError> {
+                Box::pin(ready(Ok(lsp_types::InitializeResult::default())))
+            }
+        }
+
+        let mut router = Router::from_language_server(Server("pong "));
+        poll_fn(|cx| router.poll_ready(cx)).await.unwrap();
+        let ret = router
+            .call(AnyRequest {
+                id: RequestId::Number(1),
+                method: Ping::METHOD.into(),
+                params: json!("foo"),
+                receipt: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(ret, json!("pong foo"));
+    }
+}
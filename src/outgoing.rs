@@ -0,0 +1,388 @@
+//! Id allocation and response correlation of outgoing requests.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! [`MainLoop`](crate::MainLoop) assigns ids to requests sent to the peer, and correlates incoming
+//! responses back to their callers. [`OutgoingRequests`] is this registry, exposed for custom
+//! transports and proxies which send requests themselves:
+//!
+//! - [`OutgoingRequests::register`] assigns an unused id to a request, and returns a
+//!   [`ResponseFuture`] resolved by the response.
+//! - [`OutgoingRequests::forward`] does the same but prefers a given id, eg. the id of an
+//!   incoming request being forwarded, and remembers the original one for
+//!   [`OutgoingRequests::forwarded_id`].
+//! - [`OutgoingRequests::complete`] correlates an incoming response.
+//! - [`OutgoingRequests::cancel`] gives up a request, and returns the `$/cancelRequest`
+//!   notification to be sent to the peer. [`OutgoingRequests::cancel_dropped`] does the same for
+//!   requests whose [`ResponseFuture`]s are dropped.
+//!
+//! ```
+//! # use async_lsp::outgoing::OutgoingRequests;
+//! # use async_lsp::{AnyRequest, RequestId, ResponseError};
+//! # use serde_json::Value as JsonValue;
+//! # async fn work(mut req: AnyRequest, id: RequestId, result: Result<JsonValue, ResponseError>) {
+//! let mut outgoing = OutgoingRequests::new();
+//! let fut = outgoing.register(&mut req);
+//! // Send `req` to the peer, and later on a response.
+//! if !outgoing.complete(&id, result) {
+//!     println!("Unknown response of {id:?}");
+//! }
+//! let result = fut.await;
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Instant;
+
+use futures::channel::oneshot;
+use lsp_types::notification::{Cancel, Notification};
+use serde_json::{json, Value as JsonValue};
+
+use crate::{
+    AnyNotification, AnyRequest, AnyResponse, Error, ErrorCode, RequestId, ResponseError, Result,
+};
+
+/// An outgoing request which is not responded yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct OutgoingRequest {
+    /// The request id sent to the peer.
+    pub id: RequestId,
+    /// The method.
+    pub method: String,
+    /// The original id if the request is forwarded.
+    pub forwarded_from: Option<RequestId>,
+    /// When the request is registered.
+    pub sent: Instant,
+}
+
+struct Entry {
+    req: OutgoingRequest,
+    seq: u64,
+    tx: oneshot::Sender<AnyResponse>,
+}
+
+/// The registry of outgoing requests waiting for responses.
+///
+/// See [module level documentations](self) for details.
+#[derive(Default)]
+pub struct OutgoingRequests {
+    next_id: i32,
+    next_seq: u64,
+    requests: HashMap<RequestId, Entry>,
+    /// Ids of pending forwarded requests, mapped to the ids sent to the peer.
+    forwarded: HashMap<RequestId, RequestId>,
+}
+
+impl fmt::Debug for OutgoingRequests {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OutgoingRequests")
+            .field("next_id", &self.next_id)
+            .field("requests", &self.requests.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl OutgoingRequests {
+    /// Create an empty registry.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Generate an id for an outgoing request, skipping ids of pending requests.
+    pub fn next_id(&mut self) -> RequestId {
+        loop {
+            let id = RequestId::Number(self.next_id);
+            self.next_id = self.next_id.wrapping_add(1);
+            if !self.requests.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    /// Assign a generated id to `req`, and register it.
+    pub fn register(&mut self, req: &mut AnyRequest) -> ResponseFuture {
+        let (tx, rx) = oneshot::channel();
+        self.register_with(req, tx);
+        ResponseFuture { rx }
+    }
+
+    pub(crate) fn register_with(&mut self, req: &mut AnyRequest, tx: oneshot::Sender<AnyResponse>) {
+        req.id = self.next_id();
+        self.insert(req, None, tx);
+    }
+
+    /// Assign `id` to a forwarded `req` and register it, or a generated id if `id` is `None` or
+    /// collides with a pending request. The original id of `req` can be translated via
+    /// [`OutgoingRequests::forwarded_id`] until the response arrives.
+    pub fn forward(&mut self, req: &mut AnyRequest, id: Option<RequestId>) -> ResponseFuture {
+        let (tx, rx) = oneshot::channel();
+        self.forward_with(req, id, tx);
+        ResponseFuture { rx }
+    }
+
+    pub(crate) fn forward_with(
+        &mut self,
+        req: &mut AnyRequest,
+        id: Option<RequestId>,
+        tx: oneshot::Sender<AnyResponse>,
+    ) {
+        let id = match id {
+            Some(id) if !self.requests.contains_key(&id) => id,
+            Some(_id) => {
+                #[cfg(feature = "tracing")]
+                ::tracing::warn!(id = ?_id, "outgoing request id collides, remapped");
+                self.next_id()
+            }
+            None => self.next_id(),
+        };
+        let orig_id = std::mem::replace(&mut req.id, id.clone());
+        // Cancellations of duplicated ids go to the earliest request.
+        self.forwarded.entry(orig_id.clone()).or_insert(id);
+        self.insert(req, Some(orig_id), tx);
+    }
+
+    fn insert(
+        &mut self,
+        req: &AnyRequest,
+        forwarded_from: Option<RequestId>,
+        tx: oneshot::Sender<AnyResponse>,
+    ) {
+        let entry = Entry {
+            req: OutgoingRequest {
+                id: req.id.clone(),
+                method: req.method.clone(),
+                forwarded_from,
+                sent: Instant::now(),
+            },
+            seq: self.next_seq,
+            tx,
+        };
+        self.next_seq += 1;
+        assert!(
+            self.requests.insert(req.id.clone(), entry).is_none(),
+            "duplicated outgoing request id {:?}",
+            req.id,
+        );
+    }
+
+    fn remove(&mut self, id: &RequestId) -> Option<Entry> {
+        let entry = self.requests.remove(id)?;
+        if let Some(orig_id) = &entry.req.forwarded_from {
+            if self.forwarded.get(orig_id) == Some(id) {
+                self.forwarded.remove(orig_id);
+            }
+        }
+        Some(entry)
+    }
+
+    /// Get the id sent to the peer of the pending forwarded request whose original id is `id`.
+    #[must_use]
+    pub fn forwarded_id(&self, id: &RequestId) -> Option<&RequestId> {
+        self.forwarded.get(id)
+    }
+
+    /// Get the pending request with `id`.
+    #[must_use]
+    pub fn get(&self, id: &RequestId) -> Option<&OutgoingRequest> {
+        self.requests.get(id).map(|entry| &entry.req)
+    }
+
+    /// Get all pending requests, from the oldest to the latest.
+    #[must_use]
+    pub fn all(&self) -> Vec<OutgoingRequest> {
+        let mut entries = self.requests.values().collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.seq);
+        entries.into_iter().map(|entry| entry.req.clone()).collect()
+    }
+
+    /// Get the number of pending requests.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Whether there is no pending request.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Resolve the pending request with `id` by the result of its response. Returns `false` if
+    /// the request is not pending.
+    pub fn complete(&mut self, id: &RequestId, result: Result<JsonValue, ResponseError>) -> bool {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(err) => (None, Some(err)),
+        };
+        self.complete_response(AnyResponse {
+            id: id.clone(),
+            result,
+            error,
+            raw: None,
+        })
+        .is_none()
+    }
+
+    /// Same as [`OutgoingRequests::complete`] but returns `resp` back if the request is not
+    /// pending.
+    pub(crate) fn complete_response(&mut self, resp: AnyResponse) -> Option<AnyResponse> {
+        match self.remove(&resp.id) {
+            Some(entry) => {
+                // The result may be ignored.
+                let _: Result<_, _> = entry.tx.send(resp);
+                None
+            }
+            None => Some(resp),
+        }
+    }
+
+    /// Give up the pending request with `id`, failing it with an
+    /// [`ErrorCode::REQUEST_CANCELLED`] error response. Returns the `$/cancelRequest` notification to be
+    /// sent to the peer, or `None` if the request is not pending.
+    pub fn cancel(&mut self, id: &RequestId) -> Option<AnyNotification> {
+        let entry = self.remove(id)?;
+        let resp = AnyResponse {
+            id: id.clone(),
+            result: None,
+            error: Some(ResponseError::new(
+                ErrorCode::REQUEST_CANCELLED,
+                "Outgoing request cancelled",
+            )),
+            raw: None,
+        };
+        // The result may be ignored.
+        let _: Result<_, _> = entry.tx.send(resp);
+        Some(AnyNotification {
+            method: Cancel::METHOD.into(),
+            params: json!({ "id": id }),
+            receipt: None,
+        })
+    }
+
+    /// Cancel pending requests whose [`ResponseFuture`]s are dropped, via
+    /// [`OutgoingRequests::cancel`]. Forwarded requests are excluded, since cancellations of them
+    /// are forwarded from the original requester instead.
+    pub fn cancel_dropped(&mut self) -> Vec<AnyNotification> {
+        self.dropped()
+            .iter()
+            .filter_map(|id| self.cancel(id))
+            .collect()
+    }
+
+    /// Get ids of pending requests, except forwarded ones, whose receivers are dropped.
+    pub(crate) fn dropped(&self) -> Vec<RequestId> {
+        self.requests
+            .values()
+            .filter(|entry| entry.req.forwarded_from.is_none() && entry.tx.is_canceled())
+            .map(|entry| entry.req.id.clone())
+            .collect()
+    }
+
+    /// Drop all pending requests, failing them with [`Error::ServiceStopped`]. Ids are not reused
+    /// afterwards.
+    pub fn clear(&mut self) {
+        self.requests.clear();
+        self.forwarded.clear();
+    }
+}
+
+/// The future of the result of a registered outgoing request.
+///
+/// It fails with [`Error::Response`] on an error response, or [`Error::ServiceStopped`] if the
+/// request is dropped from the registry without a response, eg. when the connection is closed.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct ResponseFuture {
+    rx: oneshot::Receiver<AnyResponse>,
+}
+
+impl Future for ResponseFuture {
+    type Output = Result<JsonValue>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let resp = ready!(Pin::new(&mut self.rx).poll(cx)).map_err(|_| Error::ServiceStopped)?;
+        Poll::Ready(match resp.error {
+            None => Ok(resp.result.unwrap_or_default()),
+            Some(err) => Err(Error::Response(err)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+
+    fn request(id: RequestId) -> AnyRequest {
+        AnyRequest {
+            id,
+            method: "foo".into(),
+            params: JsonValue::Null,
+            receipt: None,
+        }
+    }
+
+    #[test]
+    fn correlate() {
+        let mut outgoing = OutgoingRequests::new();
+        let mut req1 = request(RequestId::Number(42));
+        let fut1 = outgoing.register(&mut req1);
+        assert_eq!(req1.id, RequestId::Number(0));
+
+        // Forwarded ids are preserved unless they collide.
+        let mut req2 = request(RequestId::Number(1));
+        let fut2 = outgoing.forward(&mut req2, Some(RequestId::Number(1)));
+        let mut req3 = request(RequestId::String("x".into()));
+        let fut3 = outgoing.forward(&mut req3, Some(RequestId::Number(1)));
+        assert_eq!(req2.id, RequestId::Number(1));
+        assert_eq!(req3.id, RequestId::Number(2));
+        assert_eq!(
+            outgoing.forwarded_id(&RequestId::String("x".into())),
+            Some(&RequestId::Number(2)),
+        );
+        // Generated ids skip pending ones.
+        assert_eq!(outgoing.next_id(), RequestId::Number(3));
+
+        let all = outgoing.all();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[2].forwarded_from, Some(RequestId::String("x".into())));
+
+        assert!(outgoing.complete(&req1.id, Ok(json!(1))));
+        assert!(!outgoing.complete(&req1.id, Ok(json!(2))));
+        assert_eq!(fut1.now_or_never().unwrap().unwrap(), json!(1));
+
+        let notif = outgoing.cancel(&req3.id).unwrap();
+        assert_eq!(notif.method, Cancel::METHOD);
+        assert_eq!(notif.params, json!({ "id": 2 }));
+        assert!(outgoing
+            .forwarded_id(&RequestId::String("x".into()))
+            .is_none());
+        match fut3.now_or_never().unwrap() {
+            Err(Error::Response(err)) => assert_eq!(err.code, ErrorCode::REQUEST_CANCELLED),
+            ret => panic!("unexpected result: {ret:?}"),
+        }
+        assert!(outgoing.cancel(&req3.id).is_none());
+
+        // Dropped futures of forwarded requests are left to the original requester.
+        let mut req4 = request(RequestId::Number(0));
+        drop(outgoing.register(&mut req4));
+        drop(outgoing.forward(&mut request(RequestId::Number(9)), None));
+        let notifs = outgoing.cancel_dropped();
+        assert_eq!(notifs.len(), 1);
+        assert_eq!(notifs[0].params, json!({ "id": req4.id }));
+
+        assert_eq!(outgoing.len(), 2);
+        outgoing.clear();
+        assert!(outgoing.is_empty());
+        assert!(matches!(
+            fut2.now_or_never().unwrap(),
+            Err(Error::ServiceStopped)
+        ));
+    }
+}
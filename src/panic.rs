@@ -1,18 +1,44 @@
 //! Catch panics of underlying handlers and turn them into error responses.
 //!
 //! *Applies to both Language Servers and Language Clients.*
+//!
+//! Panics in notification and event handlers have no response to carry them. They either stop
+//! the main loop with [`Error::Panicked`], or are logged and ignored, depending on the
+//! [`PanicPolicy`] set via [`CatchUnwindBuilder::notify_policy`].
+//!
+//! A [`RequestJournal`] set via [`CatchUnwindBuilder::journal`] is dumped on every caught panic,
+//! preserving the messages leading to it. See [`crate::journal`] for details.
+//!
+//! ## `panic=abort`
+//!
+//! Panics can not be caught when compiled with `panic=abort`, which can be checked via
+//! [`can_unwind`]. The process aborts on any panic, and this middleware has no effect. A warning
+//! is logged when it is built in this case, if `tracing` is enabled.
+//!
+//! To still get defined behavior, [`install_crash_hook`] installs a process-wide panic hook. On
+//! panics in handlers under this middleware, right before the process aborts, it logs an error
+//! naming the handler and dumps the [`RequestJournal`] of the middleware. The editor then
+//! observes a crashed server, and usually restarts it.
 use std::any::Any;
+use std::cell::RefCell;
+use std::fmt;
 use std::future::Future;
 use std::ops::ControlFlow;
 use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::task::{Context, Poll};
 
 use pin_project_lite::pin_project;
+use serde::{Deserialize, Serialize};
 use tower_layer::Layer;
 use tower_service::Service;
 
-use crate::{AnyEvent, AnyNotification, AnyRequest, ErrorCode, LspService, ResponseError, Result};
+use crate::journal::RequestJournal;
+use crate::{
+    AnyEvent, AnyNotification, AnyRequest, Error, ErrorCode, EventHints, LspService, ResponseError,
+    Result,
+};
 
 /// The middleware catching panics of underlying handlers and turn them into error responses.
 ///
@@ -20,20 +46,124 @@ use crate::{AnyEvent, AnyNotification, AnyRequest, ErrorCode, LspService, Respon
 pub struct CatchUnwind<S: LspService> {
     service: S,
     handler: Handler<S::Error>,
+    policy: PanicPolicy,
+    journal: Option<RequestJournal>,
 }
 
 define_getters!(impl[S: LspService] CatchUnwind<S>, service: S);
 
 type Handler<E> = fn(method: &str, payload: Box<dyn Any + Send>) -> E;
 
-fn default_handler(method: &str, payload: Box<dyn Any + Send>) -> ResponseError {
-    let msg = match payload.downcast::<String>() {
-        Ok(msg) => *msg,
-        Err(payload) => match payload.downcast::<&'static str>() {
-            Ok(msg) => (*msg).into(),
-            Err(_payload) => "unknown".into(),
-        },
+/// The action on panics in notification or event handlers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum PanicPolicy {
+    /// Stop the main loop with [`Error::Panicked`].
+    #[default]
+    Break,
+    /// Log a warning, if `tracing` is enabled, and continue the main loop.
+    Continue,
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<String>() {
+        Some(msg) => msg,
+        None => payload
+            .downcast_ref::<&'static str>()
+            .copied()
+            .unwrap_or("unknown"),
+    }
+}
+
+fn dump_journal(journal: Option<&RequestJournal>) {
+    if let Some(journal) = journal {
+        if let Err(_err) = journal.dump() {
+            #[cfg(feature = "tracing")]
+            ::tracing::warn!("Failed to dump the request journal: {_err}");
+        }
+    }
+}
+
+/// Check if panics unwind, ie. not compiled with `panic=abort`, so that they can be caught by
+/// [`CatchUnwind`].
+///
+/// See [module level documentations](self) for details.
+#[must_use]
+pub const fn can_unwind() -> bool {
+    cfg!(panic = "unwind")
+}
+
+static CRASH_HOOK: AtomicBool = AtomicBool::new(false);
+
+/// The handler running under [`CatchUnwind`] on the current thread, for the crash hook.
+struct Scope {
+    handler: String,
+    journal: Option<RequestJournal>,
+}
+
+thread_local! {
+    static SCOPE: RefCell<Option<Scope>> = const { RefCell::new(None) };
+}
+
+/// Restore the outer scope on exit, since services may be nested.
+struct ScopeGuard(Option<Scope>);
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        let prev = self.0.take();
+        SCOPE.with(|scope| *scope.borrow_mut() = prev);
+    }
+}
+
+/// Run `f` in the scope of `handler`. It is only tracked when the crash hook is installed and
+/// panics abort.
+fn in_scope<T>(
+    handler: impl FnOnce() -> String,
+    journal: Option<&RequestJournal>,
+    f: impl FnOnce() -> T,
+) -> T {
+    if can_unwind() || !CRASH_HOOK.load(Ordering::Relaxed) {
+        return f();
+    }
+    let scope = Scope {
+        handler: handler(),
+        journal: journal.cloned(),
     };
+    let _guard = ScopeGuard(SCOPE.with(|cur| cur.borrow_mut().replace(scope)));
+    f()
+}
+
+/// Install a process-wide panic hook reporting panics in handlers under [`CatchUnwind`] when
+/// compiled with `panic=abort`. The previous hook is called afterwards.
+///
+/// It only has effect when [`can_unwind`] is `false`, and installing it more than once does
+/// nothing.
+///
+/// See [module level documentations](self) for details.
+pub fn install_crash_hook() {
+    if CRASH_HOOK.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let prev = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if !can_unwind() {
+            let scope = SCOPE.with(|scope| scope.try_borrow_mut().ok()?.take());
+            if let Some(scope) = scope {
+                let _msg = format!("{} panicked, aborting: {info}", scope.handler);
+                #[cfg(feature = "tracing")]
+                ::tracing::error!("{_msg}");
+                #[cfg(not(feature = "tracing"))]
+                eprintln!("{_msg}");
+                dump_journal(scope.journal.as_ref());
+            }
+        }
+        prev(info);
+    }));
+}
+
+fn default_handler(method: &str, payload: Box<dyn Any + Send>) -> ResponseError {
+    let msg = panic_message(&*payload);
     ResponseError {
         code: ErrorCode::INTERNAL_ERROR,
         message: format!("Request handler of {method} panicked: {msg}"),
@@ -52,15 +182,25 @@ impl<S: LspService> Service<AnyRequest> for CatchUnwind<S> {
 
     fn call(&mut self, req: AnyRequest) -> Self::Future {
         let method = req.method.clone();
+        let journal = self.journal.as_ref();
+        let call = || {
+            in_scope(
+                || format!("Request handler of {method}"),
+                journal,
+                || self.service.call(req),
+            )
+        };
         // FIXME: Clarify conditions of UnwindSafe.
-        match catch_unwind(AssertUnwindSafe(|| self.service.call(req)))
-            .map_err(|err| (self.handler)(&method, err))
-        {
+        match catch_unwind(AssertUnwindSafe(call)).map_err(|err| {
+            dump_journal(self.journal.as_ref());
+            (self.handler)(&method, err)
+        }) {
             Ok(fut) => ResponseFuture {
                 inner: ResponseFutureInner::Future {
                     fut,
                     method,
                     handler: self.handler,
+                    journal: self.journal.clone(),
                 },
             },
             Err(err) => ResponseFuture {
@@ -86,6 +226,7 @@ pin_project! {
             fut: Fut,
             method: String,
             handler: Handler<Error>,
+            journal: Option<RequestJournal>,
         },
         Ready {
             err: Option<Error>,
@@ -105,11 +246,22 @@ where
                 fut,
                 method,
                 handler,
+                journal,
             } => {
+                let poll = || {
+                    in_scope(
+                        || format!("Request handler of {method}"),
+                        journal.as_ref(),
+                        || fut.poll(cx),
+                    )
+                };
                 // FIXME: Clarify conditions of UnwindSafe.
-                match catch_unwind(AssertUnwindSafe(|| fut.poll(cx))) {
+                match catch_unwind(AssertUnwindSafe(poll)) {
                     Ok(poll) => poll,
-                    Err(payload) => Poll::Ready(Err(handler(method, payload))),
+                    Err(payload) => {
+                        dump_journal(journal.as_ref());
+                        Poll::Ready(Err(handler(method, payload)))
+                    }
                 }
             }
             ResponseFutureProj::Ready { err } => Poll::Ready(Err(err.take().expect("Completed"))),
@@ -119,11 +271,58 @@ where
 
 impl<S: LspService> LspService for CatchUnwind<S> {
     fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
-        self.service.notify(notif)
+        let method = notif.method.clone();
+        let (service, journal) = (&mut self.service, self.journal.as_ref());
+        let notify = || {
+            in_scope(
+                || format!("Notification handler of {method}"),
+                journal,
+                || service.notify(notif),
+            )
+        };
+        // FIXME: Clarify conditions of UnwindSafe.
+        catch_unwind(AssertUnwindSafe(notify)).unwrap_or_else(|payload| {
+            self.on_panic(format_args!("Notification handler of {method}"), payload)
+        })
     }
 
     fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
-        self.service.emit(event)
+        let type_name = event.type_name();
+        let (service, journal) = (&mut self.service, self.journal.as_ref());
+        let emit = || {
+            in_scope(
+                || format!("Event handler of {type_name}"),
+                journal,
+                || service.emit(event),
+            )
+        };
+        // FIXME: Clarify conditions of UnwindSafe.
+        catch_unwind(AssertUnwindSafe(emit)).unwrap_or_else(|payload| {
+            self.on_panic(format_args!("Event handler of {type_name}"), payload)
+        })
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+impl<S: LspService> CatchUnwind<S> {
+    fn on_panic(
+        &self,
+        handler: fmt::Arguments<'_>,
+        payload: Box<dyn Any + Send>,
+    ) -> ControlFlow<Result<()>> {
+        let msg = format!("{handler} panicked: {}", panic_message(&*payload));
+        dump_journal(self.journal.as_ref());
+        match self.policy {
+            PanicPolicy::Break => ControlFlow::Break(Err(Error::Panicked(msg))),
+            PanicPolicy::Continue => {
+                #[cfg(feature = "tracing")]
+                ::tracing::warn!("{msg}");
+                ControlFlow::Continue(())
+            }
+        }
     }
 }
 
@@ -136,6 +335,8 @@ impl<S: LspService> LspService for CatchUnwind<S> {
 #[must_use]
 pub struct CatchUnwindBuilder<Error = ResponseError> {
     handler: Handler<Error>,
+    policy: PanicPolicy,
+    journal: Option<RequestJournal>,
 }
 
 impl Default for CatchUnwindBuilder<ResponseError> {
@@ -148,7 +349,24 @@ impl<Error> CatchUnwindBuilder<Error> {
     /// Create the builder of [`CatchUnwind`] middleware with a custom handler converting panic
     /// payloads into [`ResponseError`].
     pub fn new_with_handler(handler: Handler<Error>) -> Self {
-        Self { handler }
+        Self {
+            handler,
+            policy: PanicPolicy::default(),
+            journal: None,
+        }
+    }
+
+    /// Set the action on panics in notification or event handlers. Default is
+    /// [`PanicPolicy::Break`].
+    pub fn notify_policy(mut self, policy: PanicPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Dump `journal` on every caught panic. Default is none.
+    pub fn journal(mut self, journal: RequestJournal) -> Self {
+        self.journal = Some(journal);
+        self
     }
 }
 
@@ -159,9 +377,72 @@ impl<S: LspService> Layer<S> for CatchUnwindBuilder<S::Error> {
     type Service = CatchUnwind<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
+        #[cfg(feature = "tracing")]
+        if !can_unwind() {
+            static WARNED: AtomicBool = AtomicBool::new(false);
+            if !WARNED.swap(true, Ordering::Relaxed) {
+                ::tracing::warn!(
+                    "CatchUnwind has no effect under panic=abort, \
+                     consider async_lsp::panic::install_crash_hook",
+                );
+            }
+        }
         CatchUnwind {
             service: inner,
             handler: self.handler,
+            policy: self.policy,
+            journal: self.journal.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::notification::{self, Notification};
+
+    use super::*;
+    use crate::router::Router;
+
+    #[test]
+    fn notify_panic() {
+        let mut router = Router::new(());
+        router.notification::<notification::Initialized>(|_, _| panic!("oops"));
+        let notif = || AnyNotification {
+            method: notification::Initialized::METHOD.into(),
+            params: serde_json::json!({}),
+            receipt: None,
+        };
+
+        let mut service = CatchUnwindLayer::default().layer(router);
+        match service.notify(notif()) {
+            ControlFlow::Break(Err(Error::Panicked(msg))) => {
+                assert_eq!(msg, "Notification handler of initialized panicked: oops");
+            }
+            ret => panic!("unexpected result: {ret:?}"),
+        }
+
+        let mut service = CatchUnwindLayer::default()
+            .notify_policy(PanicPolicy::Continue)
+            .layer(service.into_inner());
+        assert!(matches!(service.notify(notif()), ControlFlow::Continue(())));
+    }
+
+    #[test]
+    fn crash_hook() {
+        assert!(can_unwind());
+        install_crash_hook();
+        install_crash_hook();
+
+        // Panics are still caught with the hook when unwinding.
+        let mut router = Router::new(());
+        router.notification::<notification::Initialized>(|_, _| panic!("oops"));
+        let mut service = CatchUnwindLayer::default().layer(router);
+        let ret = service.notify(AnyNotification {
+            method: notification::Initialized::METHOD.into(),
+            params: serde_json::json!({}),
+            receipt: None,
+        });
+        assert!(matches!(ret, ControlFlow::Break(Err(Error::Panicked(_)))));
+        assert!(SCOPE.with(|scope| scope.borrow().is_none()));
+    }
+}
@@ -0,0 +1,290 @@
+//! Queriable registry of pending incoming requests.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! Clients may send storms of identical requests, eg. a `textDocument/documentSymbol` from each
+//! of several views of the same document after a refresh. Unlike [`Coalesce`](crate::coalesce),
+//! which always cancels the older ones, this middleware only records requests into a shared
+//! [`PendingRequests`] handle until they are responded or cancelled. Handlers can then query
+//! other pending requests of the same method on the same document, and decide themselves whether
+//! to piggyback on an existing computation.
+//!
+//! Requests are recorded before the inner service is called, so a handler also sees its own
+//! request, as the latest one. Documents are taken from `params.textDocument.uri`.
+//!
+//! ```
+//! # use async_lsp::pending::{PendingLayer, PendingRequests};
+//! # use async_lsp::lsp_types::request::DocumentSymbolRequest;
+//! # use async_lsp::lsp_types::Url;
+//! let pending = PendingRequests::new();
+//! let layer = PendingLayer::new(pending.clone());
+//! // Later, eg. in a request handler.
+//! let uri = Url::parse("file:///foo.rs").unwrap();
+//! let duplicates = pending.find::<DocumentSymbolRequest>(&uri).len().saturating_sub(1);
+//! ```
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::ops::ControlFlow;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use lsp_types::request::Request;
+use lsp_types::Url;
+use pin_project_lite::pin_project;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::coalesce::text_document_uri;
+use crate::{AnyEvent, AnyNotification, AnyRequest, EventHints, LspService, RequestId, Result};
+
+/// An incoming request which is not responded yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PendingRequest {
+    /// The request id.
+    pub id: RequestId,
+    /// The method.
+    pub method: String,
+    /// The document of the request, or `None` if it has no `params.textDocument.uri`.
+    pub uri: Option<Url>,
+    /// When the request is received.
+    pub received: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    /// Pending requests keyed by the arrival order.
+    requests: BTreeMap<u64, PendingRequest>,
+    next_seq: u64,
+}
+
+/// The shared handle of pending incoming requests.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone, Default)]
+pub struct PendingRequests(Arc<Mutex<Registry>>);
+
+impl PendingRequests {
+    /// Create an empty handle.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the pending request with `id`.
+    #[must_use]
+    pub fn get(&self, id: &RequestId) -> Option<PendingRequest> {
+        let registry = self.0.lock().unwrap();
+        registry
+            .requests
+            .values()
+            .find(|req| req.id == *id)
+            .cloned()
+    }
+
+    /// Get all pending requests, from the oldest to the latest.
+    #[must_use]
+    pub fn all(&self) -> Vec<PendingRequest> {
+        self.0.lock().unwrap().requests.values().cloned().collect()
+    }
+
+    /// Get pending requests of method `R` on the document `uri`, from the oldest to the latest.
+    #[must_use]
+    pub fn find<R: Request>(&self, uri: &Url) -> Vec<PendingRequest> {
+        self.find_method(R::METHOD, uri)
+    }
+
+    /// Same as [`PendingRequests::find`] but with a method name, eg. of a custom request.
+    #[must_use]
+    pub fn find_method(&self, method: &str, uri: &Url) -> Vec<PendingRequest> {
+        let registry = self.0.lock().unwrap();
+        registry
+            .requests
+            .values()
+            .filter(|req| req.method == method && req.uri.as_ref() == Some(uri))
+            .cloned()
+            .collect()
+    }
+
+    fn insert(&self, req: PendingRequest) -> Remove {
+        let mut registry = self.0.lock().unwrap();
+        let seq = registry.next_seq;
+        registry.next_seq += 1;
+        registry.requests.insert(seq, req);
+        Remove {
+            registry: self.clone(),
+            seq,
+        }
+    }
+}
+
+/// Remove the request from the registry when it is responded or cancelled.
+struct Remove {
+    registry: PendingRequests,
+    seq: u64,
+}
+
+impl Drop for Remove {
+    fn drop(&mut self) {
+        self.registry.0.lock().unwrap().requests.remove(&self.seq);
+    }
+}
+
+/// The middleware recording pending incoming requests.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+pub struct Pending<S> {
+    service: S,
+    pending: PendingRequests,
+}
+
+define_getters!(impl[S] Pending<S>, service: S);
+
+impl<S: LspService> Service<AnyRequest> for Pending<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        let remove = self.pending.insert(PendingRequest {
+            id: req.id.clone(),
+            method: req.method.clone(),
+            uri: text_document_uri(&req.params),
+            received: Instant::now(),
+        });
+        ResponseFuture {
+            fut: self.service.call(req),
+            _remove: remove,
+        }
+    }
+}
+
+impl<S: LspService> LspService for Pending<S> {
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        self.service.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.service.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+pin_project! {
+    /// The [`Future`] type used by the [`Pending`] middleware.
+    pub struct ResponseFuture<Fut> {
+        #[pin]
+        fut: Fut,
+        _remove: Remove,
+    }
+}
+
+impl<Fut: Future> Future for ResponseFuture<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.project().fut.poll(cx)
+    }
+}
+
+/// A [`tower_layer::Layer`] which builds [`Pending`].
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct PendingLayer {
+    pending: PendingRequests,
+}
+
+impl PendingLayer {
+    /// Create the layer recording requests into `pending`.
+    pub fn new(pending: PendingRequests) -> Self {
+        Self { pending }
+    }
+}
+
+impl<S> Layer<S> for PendingLayer {
+    type Service = Pending<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Pending {
+            service: inner,
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{pending, poll_fn};
+
+    use lsp_types::TextDocumentPositionParams;
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::ResponseError;
+
+    enum Symbols {}
+
+    impl Request for Symbols {
+        type Params = TextDocumentPositionParams;
+        type Result = usize;
+        const METHOD: &'static str = "test/symbols";
+    }
+
+    #[tokio::test]
+    async fn piggyback() {
+        let registry = PendingRequests::new();
+        let mut router = Router::<_, ResponseError>::new(registry.clone());
+        router.request::<Symbols, _>(|st, params| {
+            // The first request never completes, and later ones see it.
+            let count = st.find::<Symbols>(&params.text_document.uri).len();
+            async move {
+                if count == 1 {
+                    pending::<()>().await;
+                }
+                Ok(count)
+            }
+        });
+        let mut service = PendingLayer::new(registry.clone()).layer(router);
+
+        let mut symbols = |id, uri: &str| {
+            service.call(AnyRequest {
+                id: RequestId::Number(id),
+                method: Symbols::METHOD.into(),
+                params: json!({
+                    "textDocument": { "uri": uri },
+                    "position": { "line": 0, "character": 0 },
+                }),
+                receipt: None,
+            })
+        };
+        let mut first = Box::pin(symbols(1, "file:///foo"));
+        let mut other = Box::pin(symbols(2, "file:///bar"));
+        poll_fn(|cx| {
+            assert!(first.as_mut().poll(cx).is_pending());
+            assert!(other.as_mut().poll(cx).is_pending());
+            Poll::Ready(())
+        })
+        .await;
+        assert_eq!(symbols(3, "file:///foo").await.unwrap(), json!(2));
+
+        let uri = Url::parse("file:///foo").unwrap();
+        let found = registry.find::<Symbols>(&uri);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, RequestId::Number(1));
+        assert_eq!(registry.all().len(), 2);
+        drop(first);
+        assert!(registry.get(&RequestId::Number(1)).is_none());
+        assert_eq!(registry.all()[0].id, RequestId::Number(2));
+    }
+}
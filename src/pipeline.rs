@@ -0,0 +1,423 @@
+//! Per-document analysis pipeline.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Most Language Servers share the same flow for open documents: keep the latest text in sync,
+//! re-analyze a document when it changes, drop stale results when newer edits arrive, and publish
+//! the resulting diagnostics. [`DocumentPipeline`] composes these steps into one opinionated API:
+//!
+//! 1. It stores the text of opened documents, synchronized via `textDocument/didOpen`,
+//!    `textDocument/didChange` and `textDocument/didClose`. Both full and incremental
+//!    synchronization are supported.
+//! 2. On each change, the previous analysis of the document is cancelled, and a new analysis job
+//!    is enqueued to a [`Jobs`] scheduler.
+//! 3. With [`DocumentPipeline::debounce`], the job waits for a quiet period before analyzing, so
+//!    that a burst of keystrokes results in a single analysis.
+//! 4. The user-provided `analyze` function is called with an immutable [`DocumentSnapshot`].
+//! 5. The resulting diagnostics are published with the analyzed version, unless the document has
+//!    been changed or closed in the meantime.
+//!
+//! ```
+//! # use async_lsp::jobs::Jobs;
+//! # use async_lsp::pipeline::DocumentPipeline;
+//! # use async_lsp::router::Router;
+//! # use std::num::NonZeroUsize;
+//! # use std::time::Duration;
+//! # fn work(client: async_lsp::ClientSocket, router: &mut Router<()>) {
+//! let (jobs, runner) = Jobs::new(NonZeroUsize::new(2).unwrap());
+//! tokio::spawn(runner);
+//! let pipeline = DocumentPipeline::new(client, jobs, |doc| async move {
+//!     // Compute diagnostics of `doc.text`.
+//!     Vec::new()
+//! })
+//! .debounce(Duration::from_millis(200), tokio::time::sleep);
+//! pipeline.register(router);
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, PublishDiagnostics,
+};
+use lsp_types::{
+    Diagnostic, DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+    Position, PublishDiagnosticsParams, TextDocumentContentChangeEvent, Url,
+};
+
+use crate::clock::{Clock, SharedClock};
+use crate::flags::FeatureFlags;
+use crate::jobs::{JobId, Jobs, Priority};
+use crate::router::Router;
+use crate::vfs::{DiskVfs, Vfs};
+use crate::{ClientSocket, ResponseError, Result};
+
+type AnalyzeFn = Arc<dyn Fn(DocumentSnapshot) -> BoxFuture<'static, Vec<Diagnostic>> + Send + Sync>;
+
+/// An immutable snapshot of an opened document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DocumentSnapshot {
+    /// The document URI.
+    pub uri: Url,
+    /// The language identifier given on `textDocument/didOpen`.
+    pub language_id: String,
+    /// The version of this snapshot.
+    pub version: i32,
+    /// The full text of this snapshot.
+    pub text: Arc<str>,
+}
+
+/// The cheaply cloneable per-document analysis pipeline.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone)]
+#[must_use]
+pub struct DocumentPipeline {
+    client: ClientSocket,
+    jobs: Jobs,
+    analyze: AnalyzeFn,
+    priority: Priority,
+    debounce: Option<(Duration, SharedClock)>,
+    flags: Option<FeatureFlags>,
+    vfs: Arc<dyn Vfs>,
+    docs: Arc<Mutex<HashMap<Url, Document>>>,
+}
+
+struct Document {
+    snapshot: DocumentSnapshot,
+    /// The latest analysis job.
+    job: Option<JobId>,
+}
+
+impl fmt::Debug for DocumentPipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DocumentPipeline")
+            .field("priority", &self.priority)
+            .field("debounce", &self.debounce.as_ref().map(|(dur, _)| dur))
+            .field("documents", &self.docs.lock().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl DocumentPipeline {
+    /// Create a pipeline running `analyze` on `jobs`, and publishing diagnostics via `client`.
+    pub fn new<F, Fut>(client: ClientSocket, jobs: Jobs, analyze: F) -> Self
+    where
+        F: Fn(DocumentSnapshot) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Vec<Diagnostic>> + Send + 'static,
+    {
+        Self {
+            client,
+            jobs,
+            analyze: Arc::new(move |doc| analyze(doc).boxed()),
+            priority: Priority::Background,
+            debounce: None,
+            flags: None,
+            vfs: Arc::new(DiskVfs),
+            docs: Arc::default(),
+        }
+    }
+
+    /// Set the priority of analysis jobs. Default is [`Priority::Background`].
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Wait for `duration` without further changes before analyzing a document. Default is no
+    /// debouncing.
+    ///
+    /// Since this crate is runtime-agnostic, the [`Clock`] should be provided, eg. the timer
+    /// function `tokio::time::sleep`. Note that a debouncing job occupies a slot of the [`Jobs`]
+    /// scheduler while waiting.
+    pub fn debounce(mut self, duration: Duration, clock: impl Clock + 'static) -> Self {
+        self.debounce = Some((duration, Arc::new(clock)));
+        self
+    }
+
+    /// Consult [`FeatureFlags::debounce`] of `flags` on each change, which overrides the
+    /// debouncing duration when set. It has no effect if debouncing is not enabled via
+    /// [`DocumentPipeline::debounce`].
+    pub fn flags(mut self, flags: FeatureFlags) -> Self {
+        self.flags = Some(flags);
+        self
+    }
+
+    /// Set the [`Vfs`] to read documents which are not opened. Default is [`DiskVfs`].
+    pub fn vfs(mut self, vfs: impl Vfs + 'static) -> Self {
+        self.vfs = Arc::new(vfs);
+        self
+    }
+
+    /// Register handlers of `textDocument/didOpen`, `textDocument/didChange` and
+    /// `textDocument/didClose` to `router`, which forward to this pipeline.
+    pub fn register<St, Error>(&self, router: &mut Router<St, Error>)
+    where
+        Error: From<ResponseError> + Send + 'static,
+    {
+        let this = self.clone();
+        router.notification::<DidOpenTextDocument>(move |_, params| {
+            this.did_open(params);
+            ControlFlow::Continue(())
+        });
+        let this = self.clone();
+        router.notification::<DidChangeTextDocument>(move |_, params| {
+            this.did_change(params);
+            ControlFlow::Continue(())
+        });
+        let this = self.clone();
+        router.notification::<DidCloseTextDocument>(move |_, params| {
+            // Only fails when the main loop stopped, which is noticed elsewhere.
+            let _: Result<_> = this.did_close(params);
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Get the latest snapshot of an opened document.
+    #[must_use]
+    pub fn get(&self, uri: &Url) -> Option<DocumentSnapshot> {
+        let docs = self.docs.lock().unwrap();
+        docs.get(uri).map(|doc| doc.snapshot.clone())
+    }
+
+    /// Read the text of a document, preferring the opened one over the [`Vfs`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if the document is not opened and the [`Vfs`] fails to read it.
+    pub fn read(&self, uri: &Url) -> io::Result<Arc<str>> {
+        match self.get(uri) {
+            Some(snapshot) => Ok(snapshot.text),
+            None => Ok(self.vfs.read_to_string(uri)?.into()),
+        }
+    }
+
+    /// Handle `textDocument/didOpen`, and schedule an analysis of the document.
+    pub fn did_open(&self, params: DidOpenTextDocumentParams) {
+        let doc = params.text_document;
+        self.update(DocumentSnapshot {
+            uri: doc.uri,
+            language_id: doc.language_id,
+            version: doc.version,
+            text: doc.text.into(),
+        });
+    }
+
+    /// Handle `textDocument/didChange`, and re-schedule an analysis of the document.
+    ///
+    /// Changes of documents which are not opened are ignored.
+    pub fn did_change(&self, params: DidChangeTextDocumentParams) {
+        let mut snapshot = match self.get(&params.text_document.uri) {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+        let mut text = String::from(&*snapshot.text);
+        for change in params.content_changes {
+            apply_change(&mut text, change);
+        }
+        snapshot.version = params.text_document.version;
+        snapshot.text = text.into();
+        self.update(snapshot);
+    }
+
+    /// Handle `textDocument/didClose`. The pending analysis is cancelled, and the diagnostics of
+    /// the document are cleared.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::ServiceStopped`][crate::Error::ServiceStopped] when the service main loop
+    ///   stopped.
+    pub fn did_close(&self, params: DidCloseTextDocumentParams) -> Result<()> {
+        let uri = params.text_document.uri;
+        let job = match self.docs.lock().unwrap().remove(&uri) {
+            Some(doc) => doc.job,
+            None => return Ok(()),
+        };
+        if let Some(job) = job {
+            self.jobs.cancel(job);
+        }
+        self.client
+            .notify::<PublishDiagnostics>(PublishDiagnosticsParams {
+                uri,
+                diagnostics: Vec::new(),
+                version: None,
+            })
+    }
+
+    fn update(&self, snapshot: DocumentSnapshot) {
+        let mut docs = self.docs.lock().unwrap();
+        let uri = snapshot.uri.clone();
+        let prev_job = docs.get_mut(&uri).and_then(|doc| doc.job.take());
+        if let Some(job) = prev_job {
+            self.jobs.cancel(job);
+        }
+
+        let this = self.clone();
+        let analyzed = snapshot.clone();
+        let job = self.jobs.spawn(self.priority, async move {
+            if let Some((duration, clock)) = &this.debounce {
+                let duration = this
+                    .flags
+                    .as_ref()
+                    .and_then(FeatureFlags::debounce)
+                    .unwrap_or(*duration);
+                clock.sleep(duration).await;
+            }
+            let (uri, version) = (analyzed.uri.clone(), analyzed.version);
+            let diagnostics = (this.analyze)(analyzed).await;
+            // Drop stale results.
+            let current = this
+                .docs
+                .lock()
+                .unwrap()
+                .get(&uri)
+                .map(|doc| doc.snapshot.version);
+            if current == Some(version) {
+                // Only fails when the main loop stopped, which is noticed elsewhere.
+                let _: Result<_> =
+                    this.client
+                        .notify::<PublishDiagnostics>(PublishDiagnosticsParams {
+                            uri,
+                            diagnostics,
+                            version: Some(version),
+                        });
+            }
+        });
+        docs.insert(
+            uri,
+            Document {
+                snapshot,
+                job: Some(job),
+            },
+        );
+    }
+}
+
+/// Apply a content change on `text`. Positions out of bounds are clamped.
+fn apply_change(text: &mut String, change: TextDocumentContentChangeEvent) {
+    match change.range {
+        None => *text = change.text,
+        Some(range) => {
+            let start = offset_of(text, range.start);
+            let end = offset_of(text, range.end).max(start);
+            text.replace_range(start..end, &change.text);
+        }
+    }
+}
+
+/// Convert a UTF-16 based position into a byte offset of `text`.
+pub(crate) fn offset_of(text: &str, pos: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..pos.line {
+        match text[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let line = &text[line_start..];
+    let line = &line[..line.find('\n').unwrap_or(line.len())];
+    let mut col = 0;
+    for (i, ch) in line.char_indices() {
+        if col >= pos.character {
+            return line_start + i;
+        }
+        col += ch.len_utf16() as u32;
+    }
+    line_start + line.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use lsp_types::notification::Notification;
+    use lsp_types::{
+        Range, TextDocumentIdentifier, TextDocumentItem, VersionedTextDocumentIdentifier,
+    };
+
+    use super::*;
+    use crate::{MainLoopEvent, Message, PeerSocket};
+
+    #[test]
+    fn incremental_change() {
+        let mut text = "a😀b\ncd\n".to_owned();
+        let change = |(l1, c1), (l2, c2), text: &str| TextDocumentContentChangeEvent {
+            range: Some(Range::new(Position::new(l1, c1), Position::new(l2, c2))),
+            range_length: None,
+            text: text.into(),
+        };
+        apply_change(&mut text, change((0, 1), (0, 3), "x"));
+        assert_eq!(text, "axb\ncd\n");
+        apply_change(&mut text, change((0, 3), (1, 1), "y"));
+        assert_eq!(text, "axbyd\n");
+        apply_change(&mut text, change((0, 99), (9, 0), "!"));
+        assert_eq!(text, "axbyd!");
+    }
+
+    #[tokio::test]
+    async fn debounce_and_drop_stale() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let (jobs, runner) = Jobs::new(NonZeroUsize::new(2).unwrap());
+        let runner = tokio::spawn(runner);
+        let pipeline =
+            DocumentPipeline::new(ClientSocket(PeerSocket { tx }), jobs, |doc| async move {
+                vec![Diagnostic::new_simple(
+                    Range::default(),
+                    doc.text.to_string(),
+                )]
+            })
+            .debounce(Duration::from_millis(50), tokio::time::sleep)
+            .vfs({
+                let vfs = crate::vfs::MemoryVfs::new();
+                vfs.insert(Url::parse("file:///foo").unwrap(), "disk");
+                vfs
+            });
+
+        let uri = Url::parse("file:///foo").unwrap();
+        pipeline.did_open(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(uri.clone(), "rust".into(), 1, "a".into()),
+        });
+        pipeline.did_change(DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(uri.clone(), 2),
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: "ab".into(),
+            }],
+        });
+        assert_eq!(&*pipeline.get(&uri).unwrap().text, "ab");
+        assert_eq!(&*pipeline.read(&uri).unwrap(), "ab");
+
+        // Only the latest version is analyzed.
+        let params = match rx.next().await.unwrap() {
+            MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                assert_eq!(notif.method, PublishDiagnostics::METHOD);
+                serde_json::from_value::<PublishDiagnosticsParams>(notif.params).unwrap()
+            }
+            _ => panic!("unexpected event"),
+        };
+        assert_eq!(params.version, Some(2));
+        assert_eq!(params.diagnostics[0].message, "ab");
+        assert!(rx.try_next().is_err());
+
+        pipeline
+            .did_close(DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+            })
+            .unwrap();
+        assert!(pipeline.get(&uri).is_none());
+        // Fallback to the VFS after closing.
+        assert_eq!(&*pipeline.read(&uri).unwrap(), "disk");
+        runner.abort();
+    }
+}
@@ -0,0 +1,1074 @@
+//! Work done progress reporting.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! [Work done progress](https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#workDoneProgress)
+//! requires a sequence of `window/workDoneProgress/create`, and `$/progress` notifications of
+//! `begin`, `report` and `end` with the same token. [`Progress`] is a guard which sends them in
+//! order:
+//! - [`ClientSocket::create_progress`] creates a server-initiated progress with a unique token,
+//!   and begins it.
+//! - [`Progress::begin`] begins a client-initiated progress, with the `workDoneToken` from request
+//!   parameters, which requires no creation.
+//! - [`Progress::report`] reports intermediate states.
+//! - [`Progress::done`] ends the progress. If the guard is dropped without it, eg. the handler
+//!   fails or is cancelled, the progress is ended with no message.
+//!
+//! ```
+//! # use async_lsp::{ClientSocket, Result};
+//! async fn index(client: ClientSocket) -> Result<()> {
+//!     let progress = client.create_progress("Indexing").await?;
+//!     for i in 0..10 {
+//!         progress.report(Some(format!("{i}/10")), Some(i * 10));
+//!     }
+//!     progress.done(Some("Indexed 10 files".into()));
+//!     Ok(())
+//! }
+//! ```
+//!
+//! ## Cancellation
+//!
+//! The user can cancel a progress from the editor UI, which sends a
+//! `window/workDoneProgress/cancel` notification. [`ProgressCancellation`] is a registry routing
+//! them by the progress token, and the [`CancelProgress`] middleware created by
+//! [`ProgressCancellation::layer`] feeds the notifications into it:
+//! - Progresses created via [`ProgressCancellation::create_progress`] or
+//!   [`ProgressCancellation::begin`] are reported as cancellable. Long-running operations can
+//!   check [`Progress::is_cancelled`] or wait on [`Progress::cancelled`] to abort.
+//! - Incoming requests with a `workDoneToken` are aborted with
+//!   [`ErrorCode::REQUEST_CANCELLED`] when their token is cancelled.
+//! - Arbitrary callbacks can be registered via [`ProgressCancellation::on_cancel`].
+//!
+//! Cancellable progresses begun by a handler of an incoming request, while its future is polled
+//! by [`CancelProgress`], belong to that request. Cancelling them also aborts the request with
+//! [`ErrorCode::REQUEST_CANCELLED`], the same as `$/cancelRequest` does via
+//! [`Concurrency`](crate::concurrency::Concurrency). So handlers observe a single cancellation
+//! signal, regardless of which one the editor sends. Progresses begun in spawned tasks are not
+//! tracked this way. It can be disabled via [`CancelProgressLayer::abort_owner`], eg. for handlers
+//! which prefer returning partial results on cancellation by checking [`Progress::is_cancelled`].
+//!
+//! Notifications of unknown tokens are passed to the inner service.
+//!
+//! ## Token reuse
+//!
+//! Reusing a token for two operations at the same time, eg. beginning two progresses with the
+//! same `workDoneToken`, interleaves their notifications and confuses editors. Tokens are thus
+//! claimed as [`WorkDoneToken`] or [`PartialResultToken`] by [`Progress`] and
+//! [`PartialResultSink`] until they end. Claiming a token still in use panics if the check is
+//! enabled, which is the default with debug assertions. It can be enabled in release builds via
+//! [`check_token_reuse`].
+//!
+//! ## Partial results
+//!
+//! Requests like `workspace/symbol` or `textDocument/references` may carry a
+//! [`partialResultToken`](lsp_types::PartialResultParams), allowing the server to stream items
+//! of the result via `$/progress` notifications. [`ClientSocket::partial_result_sink`] creates a
+//! [`PartialResultSink`] from the request parameters:
+//! - With a token, each [`PartialResultSink::push`] sends the items as a partial result
+//!   immediately.
+//! - Without a token, items are buffered.
+//!
+//! [`PartialResultSink::finish`] returns the items to be put in the final response. Per the
+//! specification, they are empty if any partial result is sent, since the whole result must be
+//! reported via `$/progress` then.
+//!
+//! ```
+//! # use async_lsp::ClientSocket;
+//! # use lsp_types::{Location, ReferenceParams};
+//! async fn references(client: ClientSocket, params: ReferenceParams) -> Vec<Location> {
+//!     let mut sink = client.partial_result_sink(&params.partial_result_params);
+//!     for file in ["file:///a.rs", "file:///b.rs"] {
+//!         // Search `file`...
+//!         let found: Vec<Location> = Vec::new();
+//!         sink.push(found);
+//!     }
+//!     sink.finish()
+//! }
+//! ```
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::ops::ControlFlow;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::future::{AbortHandle, Abortable};
+use futures::task::AtomicWaker;
+use lsp_types::notification::{
+    Notification, Progress as ProgressNotification, WorkDoneProgressCancel,
+};
+use lsp_types::request::WorkDoneProgressCreate;
+use lsp_types::{
+    NumberOrString, PartialResultParams, ProgressParams, ProgressParamsValue, ProgressToken,
+    WorkDoneProgress, WorkDoneProgressBegin, WorkDoneProgressCancelParams,
+    WorkDoneProgressCreateParams, WorkDoneProgressEnd, WorkDoneProgressReport,
+};
+use pin_project_lite::pin_project;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::locale::Locale;
+use crate::{
+    AnyEvent, AnyNotification, AnyRequest, ClientSocket, ErrorCode, EventHints, LspService,
+    ResponseError, Result,
+};
+
+impl ClientSocket {
+    /// Create a server-initiated work done progress with a unique token, and begin it with
+    /// `title`.
+    ///
+    /// See [module level documentations](crate::progress) for details.
+    ///
+    /// # Errors
+    /// - [`Error::ServiceStopped`](crate::Error::ServiceStopped) when the service main loop
+    ///   stopped.
+    /// - [`Error::Response`](crate::Error::Response) when the client rejects the creation, eg. it
+    ///   does not support `window.workDoneProgress`.
+    pub async fn create_progress(&self, title: impl Into<String>) -> Result<Progress> {
+        let token = self.create_progress_token().await?;
+        Ok(Progress::begin(self.clone(), token, title))
+    }
+
+    /// Create a sink streaming partial results of a request with `params`.
+    ///
+    /// See [module level documentations](crate::progress) for details.
+    pub fn partial_result_sink<T: Serialize>(
+        &self,
+        params: &PartialResultParams,
+    ) -> PartialResultSink<T> {
+        PartialResultSink::new(self.clone(), params.partial_result_token.clone())
+    }
+
+    pub(crate) async fn create_progress_token(&self) -> Result<ProgressToken> {
+        let token = NumberOrString::String(crate::unique_token("progress"));
+        self.request::<WorkDoneProgressCreate>(WorkDoneProgressCreateParams {
+            token: token.clone(),
+        })
+        .await?;
+        Ok(token)
+    }
+}
+
+/// The guard of an ongoing work done progress.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+#[must_use = "Progress ends when dropped"]
+pub struct Progress {
+    client: ClientSocket,
+    token: WorkDoneToken,
+    ended: bool,
+    cancel: Arc<CancelFlag>,
+    registration: Option<(ProgressCancellation, u64)>,
+    locale: Option<Locale>,
+}
+
+#[derive(Debug, Default)]
+struct CancelFlag {
+    cancelled: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl CancelFlag {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+}
+
+impl Progress {
+    /// Begin a progress with an existing `token`, eg. the `workDoneToken` from request parameters.
+    ///
+    /// # Panics
+    ///
+    /// If `token` is a [`ProgressToken`] still in use. See [`WorkDoneToken::new`].
+    pub fn begin(
+        client: ClientSocket,
+        token: impl Into<WorkDoneToken>,
+        title: impl Into<String>,
+    ) -> Self {
+        Self::begin_impl(client, token.into(), title.into(), None, None)
+    }
+
+    /// Begin a progress, translating its title and messages by `locale` if any.
+    pub(crate) fn begin_impl(
+        client: ClientSocket,
+        token: WorkDoneToken,
+        title: String,
+        cancellation: Option<&ProgressCancellation>,
+        locale: Option<Locale>,
+    ) -> Self {
+        let cancel = Arc::new(CancelFlag::default());
+        let registration = cancellation.map(|cancellation| {
+            let cancel = cancel.clone();
+            let owner = OWNER_REQUEST.with(|owner| owner.borrow().clone());
+            let id = cancellation.register(
+                token.get().clone(),
+                Box::new(move || {
+                    cancel.cancel();
+                    if let Some(owner) = owner {
+                        owner.abort();
+                    }
+                }),
+            );
+            (cancellation.clone(), id)
+        });
+        let this = Self {
+            client,
+            token,
+            ended: false,
+            cancel,
+            registration,
+            locale,
+        };
+        this.send(WorkDoneProgress::Begin(WorkDoneProgressBegin {
+            title: this.localize(title),
+            cancellable: this.registration.is_some().then_some(true),
+            ..WorkDoneProgressBegin::default()
+        }));
+        this
+    }
+
+    /// Get the progress token.
+    #[must_use]
+    pub fn token(&self) -> &ProgressToken {
+        self.token.get()
+    }
+
+    /// Report an intermediate state, with an optional message and an optional percentage in
+    /// `0..=100`.
+    pub fn report(&self, message: Option<String>, percentage: Option<u32>) {
+        self.send(WorkDoneProgress::Report(WorkDoneProgressReport {
+            cancellable: None,
+            message: message.map(|message| self.localize(message)),
+            percentage,
+        }));
+    }
+
+    /// Check if the user cancelled the progress.
+    ///
+    /// It is always `false` for progresses not registered in a [`ProgressCancellation`].
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Wait until the user cancels the progress.
+    ///
+    /// It never completes for progresses not registered in a [`ProgressCancellation`]. Only the
+    /// last concurrent waiter is woken.
+    pub async fn cancelled(&self) {
+        poll_fn(|cx| {
+            self.cancel.waker.register(cx.waker());
+            match self.is_cancelled() {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            }
+        })
+        .await;
+    }
+
+    /// End the progress with an optional message.
+    pub fn done(mut self, message: Option<String>) {
+        self.end(message);
+    }
+
+    fn end(&mut self, message: Option<String>) {
+        if !self.ended {
+            self.ended = true;
+            if let Some((cancellation, id)) = self.registration.take() {
+                cancellation.unregister(self.token.get(), id);
+            }
+            let message = message.map(|message| self.localize(message));
+            self.send(WorkDoneProgress::End(WorkDoneProgressEnd { message }));
+        }
+    }
+
+    fn localize(&self, message: String) -> String {
+        match self.locale.as_ref().map(|locale| locale.localize(&message)) {
+            Some(Cow::Owned(translated)) => translated,
+            _ => message,
+        }
+    }
+
+    fn send(&self, value: WorkDoneProgress) {
+        // Errors mean the main loop stopped, and there is nobody to report to.
+        let _: Result<_> = self.client.notify::<ProgressNotification>(ProgressParams {
+            token: self.token.get().clone(),
+            value: ProgressParamsValue::WorkDone(value),
+        });
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.end(None);
+    }
+}
+
+thread_local! {
+    /// The abort handle of the incoming request whose future is being polled by
+    /// [`CancelProgress`], which owns progresses begun inside.
+    static OWNER_REQUEST: RefCell<Option<AbortHandle>> = const { RefCell::new(None) };
+}
+
+static REUSE_CHECK: AtomicBool = AtomicBool::new(cfg!(debug_assertions));
+
+type TokenKey = (&'static str, ProgressToken);
+
+/// Tokens in use, mapped to the generation of their claims.
+static CLAIMED_TOKENS: Mutex<Option<HashMap<TokenKey, u64>>> = Mutex::new(None);
+
+/// Enable or disable the check of token reuse. Default is enabled with debug assertions, and
+/// disabled otherwise.
+///
+/// Only tokens claimed while it is enabled are checked.
+///
+/// See [module level documentations](self) for details.
+pub fn check_token_reuse(enabled: bool) {
+    REUSE_CHECK.store(enabled, Ordering::Relaxed);
+}
+
+/// A token claimed until dropped.
+#[derive(Debug)]
+struct ClaimedToken {
+    kind: &'static str,
+    token: ProgressToken,
+    // `None` if the check is disabled when claimed.
+    generation: Option<u64>,
+}
+
+impl ClaimedToken {
+    fn claim(kind: &'static str, token: ProgressToken) -> Self {
+        static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+        let mut this = Self {
+            kind,
+            token,
+            generation: None,
+        };
+        if REUSE_CHECK.load(Ordering::Relaxed) {
+            let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+            let reused = match CLAIMED_TOKENS
+                .lock()
+                .unwrap()
+                .get_or_insert_with(HashMap::new)
+                .entry((kind, this.token.clone()))
+            {
+                Entry::Occupied(_) => true,
+                Entry::Vacant(entry) => {
+                    entry.insert(generation);
+                    false
+                }
+            };
+            assert!(
+                !reused,
+                "{kind} token {:?} is reused while still in use",
+                this.token
+            );
+            this.generation = Some(generation);
+        }
+        this
+    }
+}
+
+impl Drop for ClaimedToken {
+    fn drop(&mut self) {
+        let generation = match self.generation {
+            Some(generation) => generation,
+            None => return,
+        };
+        let mut claimed = CLAIMED_TOKENS.lock().unwrap();
+        if let Some(claimed) = claimed.as_mut() {
+            let key = (self.kind, self.token.clone());
+            if claimed.get(&key) == Some(&generation) {
+                claimed.remove(&key);
+            }
+        }
+    }
+}
+
+/// A work done progress token claimed by a single operation until dropped.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+pub struct WorkDoneToken(ClaimedToken);
+
+impl WorkDoneToken {
+    /// Claim `token`.
+    ///
+    /// # Panics
+    ///
+    /// If the check of token reuse is enabled, and `token` is claimed by another
+    /// [`WorkDoneToken`] which is not dropped yet.
+    #[must_use]
+    pub fn new(token: ProgressToken) -> Self {
+        Self(ClaimedToken::claim("Work done", token))
+    }
+
+    /// Get the underlying token.
+    #[must_use]
+    pub fn get(&self) -> &ProgressToken {
+        &self.0.token
+    }
+}
+
+impl From<ProgressToken> for WorkDoneToken {
+    fn from(token: ProgressToken) -> Self {
+        Self::new(token)
+    }
+}
+
+/// A partial result token claimed by a single operation until dropped.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+pub struct PartialResultToken(ClaimedToken);
+
+impl PartialResultToken {
+    /// Claim `token`.
+    ///
+    /// # Panics
+    ///
+    /// If the check of token reuse is enabled, and `token` is claimed by another
+    /// [`PartialResultToken`] which is not dropped yet.
+    #[must_use]
+    pub fn new(token: ProgressToken) -> Self {
+        Self(ClaimedToken::claim("Partial result", token))
+    }
+
+    /// Get the underlying token.
+    #[must_use]
+    pub fn get(&self) -> &ProgressToken {
+        &self.0.token
+    }
+}
+
+impl From<ProgressToken> for PartialResultToken {
+    fn from(token: ProgressToken) -> Self {
+        Self::new(token)
+    }
+}
+
+/// The `$/progress` notification carrying arbitrary partial results.
+pub(crate) enum PartialResultProgress {}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PartialResultProgressParams {
+    pub(crate) token: ProgressToken,
+    pub(crate) value: JsonValue,
+}
+
+impl Notification for PartialResultProgress {
+    type Params = PartialResultProgressParams;
+    const METHOD: &'static str = ProgressNotification::METHOD;
+}
+
+/// The sink streaming items of a request result as partial results.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+#[must_use = "Buffered items must be returned in the response"]
+pub struct PartialResultSink<T> {
+    client: ClientSocket,
+    token: Option<PartialResultToken>,
+    buffer: Vec<T>,
+}
+
+impl<T: Serialize> PartialResultSink<T> {
+    /// Create a sink with an optional `partialResultToken` from request parameters.
+    ///
+    /// # Panics
+    ///
+    /// If `token` is still in use. See [`PartialResultToken::new`].
+    pub fn new(client: ClientSocket, token: Option<ProgressToken>) -> Self {
+        Self {
+            client,
+            token: token.map(PartialResultToken::new),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Get the partial result token, or `None` if the client does not accept partial results.
+    #[must_use]
+    pub fn token(&self) -> Option<&ProgressToken> {
+        self.token.as_ref().map(PartialResultToken::get)
+    }
+
+    /// Send `items` as a partial result if there is a token, or buffer them otherwise. Empty
+    /// batches are never sent.
+    pub fn push(&mut self, items: impl IntoIterator<Item = T>) {
+        let token = match &self.token {
+            Some(token) => token.get(),
+            None => return self.buffer.extend(items),
+        };
+        let items = items.into_iter().collect::<Vec<_>>();
+        if items.is_empty() {
+            return;
+        }
+        // Errors mean the main loop stopped, and there is nobody to report to.
+        let _: Result<_> =
+            self.client
+                .notify::<PartialResultProgress>(PartialResultProgressParams {
+                    token: token.clone(),
+                    value: serde_json::to_value(items).expect("Failed to serialize"),
+                });
+    }
+
+    /// Finish streaming, and get the items for the final response.
+    ///
+    /// They are all buffered items if there is no token, or empty otherwise, since the whole
+    /// result is reported via partial results.
+    #[must_use]
+    pub fn finish(self) -> Vec<T> {
+        self.buffer
+    }
+}
+
+type CancelFn = Box<dyn FnOnce() + Send>;
+
+/// The cheaply cloneable registry routing progress cancellations by tokens.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone, Default)]
+pub struct ProgressCancellation {
+    state: Arc<Mutex<CancelState>>,
+    locale: Option<Locale>,
+}
+
+#[derive(Default)]
+struct CancelState {
+    next_id: u64,
+    callbacks: HashMap<ProgressToken, (u64, CancelFn)>,
+}
+
+impl fmt::Debug for ProgressCancellation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProgressCancellation")
+            .field("tokens", &self.state.lock().unwrap().callbacks.len())
+            .field("locale", &self.locale)
+            .finish()
+    }
+}
+
+impl ProgressCancellation {
+    /// Create an empty registry.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Translate titles and messages of progresses created by this registry via `locale`.
+    ///
+    /// See [`Locale::create_progress`] for details.
+    pub fn locale(mut self, locale: Locale) -> Self {
+        self.locale = Some(locale);
+        self
+    }
+
+    /// Create a cancellable server-initiated progress.
+    ///
+    /// See [`ClientSocket::create_progress`] for details.
+    ///
+    /// # Errors
+    /// Same as [`ClientSocket::create_progress`].
+    pub async fn create_progress(
+        &self,
+        client: &ClientSocket,
+        title: impl Into<String>,
+    ) -> Result<Progress> {
+        let token = client.create_progress_token().await?;
+        Ok(Progress::begin_impl(
+            client.clone(),
+            WorkDoneToken::new(token),
+            title.into(),
+            Some(self),
+            self.locale.clone(),
+        ))
+    }
+
+    /// Begin a cancellable progress with an existing `token`.
+    ///
+    /// See [`Progress::begin`] for details.
+    ///
+    /// # Panics
+    ///
+    /// Same as [`Progress::begin`].
+    pub fn begin(
+        &self,
+        client: ClientSocket,
+        token: impl Into<WorkDoneToken>,
+        title: impl Into<String>,
+    ) -> Progress {
+        Progress::begin_impl(
+            client,
+            token.into(),
+            title.into(),
+            Some(self),
+            self.locale.clone(),
+        )
+    }
+
+    /// Call `f` when the progress of `token` is cancelled. It replaces the previous callback of
+    /// the same token.
+    pub fn on_cancel(&self, token: ProgressToken, f: impl FnOnce() + Send + 'static) {
+        self.register(token, Box::new(f));
+    }
+
+    /// Remove the callback of `token`.
+    pub fn remove(&self, token: &ProgressToken) {
+        self.state.lock().unwrap().callbacks.remove(token);
+    }
+
+    /// Cancel the progress of `token`, calling its callback.
+    ///
+    /// Returns `false` if there is no callback for the token.
+    pub fn cancel(&self, token: &ProgressToken) -> bool {
+        let callback = self.state.lock().unwrap().callbacks.remove(token);
+        match callback {
+            Some((_, f)) => {
+                f();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Create a [`CancelProgressLayer`] which routes cancellations to this registry.
+    pub fn layer(&self) -> CancelProgressLayer {
+        CancelProgressLayer {
+            cancellation: self.clone(),
+            abort_owner: true,
+        }
+    }
+
+    fn register(&self, token: ProgressToken, f: CancelFn) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.callbacks.insert(token, (id, f));
+        id
+    }
+
+    /// Remove the callback of `token` only if it is still the one registered as `id`.
+    fn unregister(&self, token: &ProgressToken, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .callbacks
+            .get(token)
+            .map_or(false, |(cur, _)| *cur == id)
+        {
+            state.callbacks.remove(token);
+        }
+    }
+}
+
+/// The middleware routing `window/workDoneProgress/cancel` to a [`ProgressCancellation`], and
+/// aborting requests by their `workDoneToken`.
+///
+/// See [module level documentations](self) for details.
+pub struct CancelProgress<S> {
+    service: S,
+    cancellation: ProgressCancellation,
+    abort_owner: bool,
+}
+
+define_getters!(impl[S] CancelProgress<S>, service: S);
+
+impl<S: LspService> Service<AnyRequest> for CancelProgress<S>
+where
+    S::Error: From<ResponseError>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        let token = req
+            .params
+            .get("workDoneToken")
+            .and_then(|token| serde_json::from_value::<ProgressToken>(token.clone()).ok());
+        let (handle, registration) = AbortHandle::new_pair();
+        let owner = self.abort_owner.then(|| handle.clone());
+        let guard = token.map(|token| {
+            let id = self
+                .cancellation
+                .register(token.clone(), Box::new(move || handle.abort()));
+            UnregisterGuard {
+                cancellation: self.cancellation.clone(),
+                token,
+                id,
+            }
+        });
+        let fut = OwnerGuard::enter(owner.as_ref(), || self.service.call(req));
+        ResponseFuture {
+            fut: Abortable::new(fut, registration),
+            owner,
+            _guard: guard,
+        }
+    }
+}
+
+/// Unregister the request from the registry when it completes.
+struct UnregisterGuard {
+    cancellation: ProgressCancellation,
+    token: ProgressToken,
+    id: u64,
+}
+
+impl Drop for UnregisterGuard {
+    fn drop(&mut self) {
+        self.cancellation.unregister(&self.token, self.id);
+    }
+}
+
+/// Set the owner request of progresses for the current thread, restoring the previous one on
+/// exit, since services may be nested.
+struct OwnerGuard(Option<AbortHandle>);
+
+impl OwnerGuard {
+    fn enter<T>(owner: Option<&AbortHandle>, f: impl FnOnce() -> T) -> T {
+        let _guard = owner
+            .map(|owner| Self(OWNER_REQUEST.with(|cur| cur.borrow_mut().replace(owner.clone()))));
+        f()
+    }
+}
+
+impl Drop for OwnerGuard {
+    fn drop(&mut self) {
+        let prev = self.0.take();
+        OWNER_REQUEST.with(|cur| *cur.borrow_mut() = prev);
+    }
+}
+
+pin_project! {
+    /// The [`Future`] type used by the [`CancelProgress`] middleware.
+    pub struct ResponseFuture<Fut> {
+        #[pin]
+        fut: Abortable<Fut>,
+        owner: Option<AbortHandle>,
+        _guard: Option<UnregisterGuard>,
+    }
+}
+
+impl<Fut, Response, Error> Future for ResponseFuture<Fut>
+where
+    Fut: Future<Output = Result<Response, Error>>,
+    Error: From<ResponseError>,
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        match OwnerGuard::enter(this.owner.as_ref(), || this.fut.poll(cx)) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(inner_ret)) => Poll::Ready(inner_ret),
+            Poll::Ready(Err(_aborted)) => Poll::Ready(Err(ResponseError::new(
+                ErrorCode::REQUEST_CANCELLED,
+                "Progress is cancelled by the user",
+            )
+            .into())),
+        }
+    }
+}
+
+impl<S: LspService> LspService for CancelProgress<S>
+where
+    S::Error: From<ResponseError>,
+{
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        if notif.method == WorkDoneProgressCancel::METHOD {
+            if let Ok(params) =
+                serde_json::from_value::<WorkDoneProgressCancelParams>(notif.params.clone())
+            {
+                if self.cancellation.cancel(&params.token) {
+                    return ControlFlow::Continue(());
+                }
+            }
+        }
+        self.service.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.service.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+/// A [`tower_layer::Layer`] which builds [`CancelProgress`].
+///
+/// It is created by [`ProgressCancellation::layer`].
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct CancelProgressLayer {
+    cancellation: ProgressCancellation,
+    abort_owner: bool,
+}
+
+impl CancelProgressLayer {
+    /// Set whether cancelling a progress aborts the incoming request which begins it. Default is
+    /// `true`.
+    ///
+    /// See [module level documentations](self) for details.
+    pub fn abort_owner(mut self, enabled: bool) -> Self {
+        self.abort_owner = enabled;
+        self
+    }
+}
+
+impl<S> Layer<S> for CancelProgressLayer {
+    type Service = CancelProgress<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CancelProgress {
+            service: inner,
+            cancellation: self.cancellation.clone(),
+            abort_owner: self.abort_owner,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::pending;
+
+    use futures::channel::{mpsc, oneshot};
+    use futures::StreamExt;
+    use lsp_types::request::Request;
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::{AnyResponse, MainLoopEvent, Message, PeerSocket, RequestId};
+
+    #[tokio::test]
+    async fn create_report_and_end() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let client = ClientSocket(PeerSocket { tx });
+        let task = tokio::spawn({
+            let client = client.clone();
+            async move {
+                let created = client.create_progress("Created").await.unwrap();
+                let given = Progress::begin(client, NumberOrString::Number(42), "Given");
+                created.report(Some("Half".into()), Some(50));
+                created.done(Some("Done".into()));
+                drop(given);
+            }
+        });
+
+        let created = match rx.next().await.unwrap() {
+            MainLoopEvent::OutgoingRequest(req, resp_tx) => {
+                assert_eq!(req.method, WorkDoneProgressCreate::METHOD);
+                resp_tx
+                    .send(AnyResponse {
+                        id: req.id,
+                        result: Some(json!(null)),
+                        error: None,
+                        raw: None,
+                    })
+                    .unwrap();
+                serde_json::from_value::<WorkDoneProgressCreateParams>(req.params)
+                    .unwrap()
+                    .token
+            }
+            _ => panic!("unexpected event"),
+        };
+        task.await.unwrap();
+        drop(client);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.next().await {
+            let params = match event {
+                MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                    serde_json::from_value::<ProgressParams>(notif.params).unwrap()
+                }
+                _ => panic!("unexpected event"),
+            };
+            let ProgressParamsValue::WorkDone(value) = params.value;
+            let kind = match value {
+                WorkDoneProgress::Begin(begin) => begin.title,
+                WorkDoneProgress::Report(report) => format!("{:?}", report.percentage),
+                WorkDoneProgress::End(end) => format!("{:?}", end.message),
+            };
+            events.push((params.token, kind));
+        }
+        let given = NumberOrString::Number(42);
+        assert_eq!(
+            events,
+            [
+                (created.clone(), "Created".into()),
+                (given.clone(), "Given".into()),
+                (created.clone(), "Some(50)".into()),
+                (created, "Some(\"Done\")".into()),
+                (given, "None".into()),
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn partial_result_sink() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let client = ClientSocket(PeerSocket { tx });
+
+        let mut sink = client.partial_result_sink::<u32>(&PartialResultParams::default());
+        sink.push([1, 2]);
+        sink.push([3]);
+        assert_eq!(sink.finish(), [1, 2, 3]);
+
+        let token = NumberOrString::Number(42);
+        let mut sink = client.partial_result_sink::<u32>(&PartialResultParams {
+            partial_result_token: Some(token.clone()),
+        });
+        sink.push([1, 2]);
+        sink.push([]);
+        sink.push([3]);
+        assert!(sink.finish().is_empty());
+        drop(client);
+
+        let mut values = Vec::new();
+        while let Some(event) = rx.next().await {
+            match event {
+                MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                    assert_eq!(notif.method, ProgressNotification::METHOD);
+                    assert_eq!(notif.params["token"], json!(token));
+                    values.push(notif.params["value"].clone());
+                }
+                _ => panic!("unexpected event"),
+            }
+        }
+        assert_eq!(values, [json!([1, 2]), json!([3])]);
+    }
+
+    #[test]
+    fn token_reuse() {
+        check_token_reuse(true);
+        let token = || NumberOrString::String("token_reuse".into());
+        let work_done = WorkDoneToken::new(token());
+        // Kinds are checked separately.
+        let partial_result = PartialResultToken::new(token());
+        let ret = std::panic::catch_unwind(|| WorkDoneToken::new(token()));
+        assert!(ret.is_err());
+        drop(work_done);
+        let _work_done = WorkDoneToken::new(token());
+        let ret = std::panic::catch_unwind(|| PartialResultToken::new(token()));
+        assert!(ret.is_err());
+        drop(partial_result);
+        let _partial_result = PartialResultToken::new(token());
+    }
+
+    #[tokio::test]
+    async fn cancel_progress() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let client = ClientSocket(PeerSocket { tx });
+        let cancellation = ProgressCancellation::new();
+        let mut router = Router::new(());
+        router.unhandled_request(|_, _| pending());
+        let mut service = cancellation.layer().layer(router);
+        let cancel = |token: &ProgressToken| AnyNotification {
+            method: WorkDoneProgressCancel::METHOD.into(),
+            params: json!({ "token": token }),
+            receipt: None,
+        };
+
+        let token = NumberOrString::Number(1);
+        let progress = cancellation.begin(client, token.clone(), "Indexing");
+        match rx.next().await.unwrap() {
+            MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                let params = serde_json::from_value::<ProgressParams>(notif.params).unwrap();
+                let ProgressParamsValue::WorkDone(value) = params.value;
+                match value {
+                    WorkDoneProgress::Begin(begin) => assert_eq!(begin.cancellable, Some(true)),
+                    _ => panic!("unexpected progress"),
+                }
+            }
+            _ => panic!("unexpected event"),
+        }
+        assert!(!progress.is_cancelled());
+        assert!(matches!(
+            service.notify(cancel(&token)),
+            ControlFlow::Continue(())
+        ));
+        progress.cancelled().await;
+        assert!(progress.is_cancelled());
+        drop(progress);
+
+        // Requests are aborted by their `workDoneToken`.
+        let (called_tx, called_rx) = oneshot::channel();
+        cancellation.on_cancel(NumberOrString::Number(2), move || {
+            called_tx.send(()).unwrap();
+        });
+        let fut = service.call(AnyRequest {
+            id: RequestId::Number(1),
+            method: "custom/wait".into(),
+            params: json!({ "workDoneToken": "req" }),
+            receipt: None,
+        });
+        let token = NumberOrString::String("req".into());
+        assert!(service.notify(cancel(&token)).is_continue());
+        assert_eq!(fut.await.unwrap_err().code, ErrorCode::REQUEST_CANCELLED);
+        assert!(service
+            .notify(cancel(&NumberOrString::Number(2)))
+            .is_continue());
+        called_rx.await.unwrap();
+
+        // Completed requests are unregistered.
+        assert!(!cancellation.cancel(&token));
+    }
+
+    #[tokio::test]
+    async fn cancel_owner_request() {
+        let (tx, _rx) = mpsc::unbounded();
+        let client = ClientSocket(PeerSocket { tx });
+        let cancellation = ProgressCancellation::new();
+        let mut router = Router::new(());
+        router.unhandled_request({
+            let cancellation = cancellation.clone();
+            move |_, req| {
+                let (cancellation, client) = (cancellation.clone(), client.clone());
+                async move {
+                    let token = NumberOrString::String(req.method);
+                    let _progress = cancellation.begin(client, token, "Indexing");
+                    pending().await
+                }
+            }
+        });
+        let call = |service: &mut CancelProgress<_>, method: &str| {
+            service.call(AnyRequest {
+                id: RequestId::Number(1),
+                method: method.into(),
+                params: json!(null),
+                receipt: None,
+            })
+        };
+        let token = |method: &str| NumberOrString::String(method.into());
+
+        let mut service = cancellation.layer().layer(router);
+        let mut fut = call(&mut service, "custom/owned");
+        assert!(futures::poll!(&mut fut).is_pending());
+        assert!(cancellation.cancel(&token("custom/owned")));
+        assert_eq!(fut.await.unwrap_err().code, ErrorCode::REQUEST_CANCELLED);
+
+        let mut service = cancellation
+            .layer()
+            .abort_owner(false)
+            .layer(service.into_inner());
+        let mut fut = call(&mut service, "custom/detached");
+        assert!(futures::poll!(&mut fut).is_pending());
+        assert!(cancellation.cancel(&token("custom/detached")));
+        assert!(futures::poll!(&mut fut).is_pending());
+    }
+}
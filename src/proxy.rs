@@ -0,0 +1,386 @@
+//! Augmenting an upstream Language Server.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Wrapper servers add features on top of an existing Language Server, eg. extra code actions for
+//! `rust-analyzer`. This middleware owns a [`ServerSocket`] to the upstream server, and forwards
+//! requests and notifications which the local service can not handle, as told by
+//! [`CanHandle`], to it. Requests handled by both, configured by [`ProxyBuilder::merge`], are sent
+//! to both and their results are merged. Notifications configured by
+//! [`ProxyBuilder::broadcast`], eg. document synchronization, are delivered to both. Events are
+//! always handled by the local service.
+//!
+//! Requests and notifications from the upstream server are not handled here. The main loop
+//! driving the [`ServerSocket`] should forward them to the editor, eg. by using a
+//! [`ClientSocket`](crate::ClientSocket) to the editor as its service. Ids of forwarded requests
+//! are chosen by its [`RequestIdPolicy`](crate::RequestIdPolicy), eg. to preserve the editor's ids.
+//!
+//! ```no_run
+//! # use async_lsp::proxy::ProxyLayer;
+//! # use async_lsp::router::Router;
+//! # use async_lsp::MainLoop;
+//! # use async_lsp::lsp_types::{notification, request};
+//! # use tower_layer::Layer;
+//! # use std::ops::ControlFlow;
+//! let (server_main, _) = MainLoop::new_server(|client| {
+//!     // Requests and notifications from the upstream server go to the editor.
+//!     let (upstream_main, upstream) = MainLoop::new_client(|_| client.clone());
+//!     // Run `upstream_main` on the stdio of the upstream server process.
+//! #   drop(upstream_main);
+//!
+//!     let mut router: Router<()> = Router::new(());
+//!     router
+//!         .request::<request::CodeActionRequest, _>(|_, _| async move {
+//!             Ok(Some(vec![/* Extra code actions. */]))
+//!         })
+//!         .notification::<notification::DidOpenTextDocument>(|_, _| ControlFlow::Continue(()));
+//!     ProxyLayer::new(upstream)
+//!         .merge::<request::CodeActionRequest>(|local, upstream| match (local, upstream) {
+//!             (Some(mut local), Some(upstream)) => {
+//!                 local.extend(upstream);
+//!                 Some(local)
+//!             }
+//!             (local, upstream) => local.or(upstream),
+//!         })
+//!         .broadcast::<notification::DidOpenTextDocument>()
+//!         .layer(router)
+//! });
+//! ```
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::future::BoxFuture;
+use futures::{FutureExt, TryFutureExt};
+use lsp_types::notification::Notification;
+use lsp_types::request::Request;
+use serde_json::Value as JsonValue;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::can_handle::CanHandle;
+use crate::{
+    AnyEvent, AnyNotification, AnyRequest, EventHints, LspService, ResponseError, Result,
+    ServerSocket,
+};
+
+type MergeFn = Arc<dyn Fn(JsonValue, JsonValue) -> JsonValue + Send + Sync>;
+
+#[derive(Default)]
+struct Config {
+    merges: HashMap<String, MergeFn>,
+    broadcasts: HashSet<String>,
+}
+
+/// The middleware forwarding messages unhandled by the local service to an upstream server.
+///
+/// See [module level documentations](self) for details.
+pub struct Proxy<S> {
+    service: S,
+    upstream: ServerSocket,
+    config: Arc<Config>,
+}
+
+define_getters!(impl[S] Proxy<S>, service: S);
+
+impl<S> Service<AnyRequest> for Proxy<S>
+where
+    S: LspService<Response = JsonValue> + CanHandle<AnyRequest>,
+    S::Error: From<ResponseError> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = JsonValue;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<JsonValue, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // The upstream socket is always ready.
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        if !self.service.can_handle(&req) {
+            return self.upstream.call(req).err_into().boxed();
+        }
+        let merge = match self.config.merges.get(&req.method) {
+            Some(merge) => merge.clone(),
+            None => return self.service.call(req).boxed(),
+        };
+        let upstream = self.upstream.call(req.clone());
+        let local = self.service.call(req);
+        async move {
+            match futures::join!(local, upstream) {
+                (Ok(local), Ok(upstream)) => Ok(merge(local, upstream)),
+                (Ok(ret), Err(_)) | (Err(_), Ok(ret)) => Ok(ret),
+                (Err(err), Err(_)) => Err(err),
+            }
+        }
+        .boxed()
+    }
+}
+
+impl<S> LspService for Proxy<S>
+where
+    S: LspService<Response = JsonValue> + CanHandle<AnyRequest> + CanHandle<AnyNotification>,
+    S::Error: From<ResponseError> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        if !self.service.can_handle(&notif) {
+            return LspService::notify(&mut self.upstream, notif);
+        }
+        if self.config.broadcasts.contains(&notif.method) {
+            LspService::notify(&mut self.upstream, notif.clone())?;
+        }
+        self.service.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.service.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+/// The builder of [`Proxy`] middleware.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone)]
+#[must_use]
+pub struct ProxyBuilder {
+    upstream: ServerSocket,
+    config: Arc<Config>,
+}
+
+impl fmt::Debug for ProxyBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyBuilder")
+            .field("upstream", &self.upstream)
+            .field("merges", &self.config.merges.keys())
+            .field("broadcasts", &self.config.broadcasts)
+            .finish()
+    }
+}
+
+impl ProxyBuilder {
+    /// Create the middleware forwarding unhandled messages to `upstream`.
+    pub fn new(upstream: ServerSocket) -> Self {
+        Self {
+            upstream,
+            config: Arc::default(),
+        }
+    }
+
+    /// Send requests of `R` to both services if the local one can handle them, and merge the
+    /// results via `merge(local, upstream)`.
+    ///
+    /// If either request fails, the result of the other one is used. If both fail, the error of
+    /// the local service is replied. The local result is used if either result is invalid.
+    pub fn merge<R: Request>(
+        self,
+        merge: impl Fn(R::Result, R::Result) -> R::Result + Send + Sync + 'static,
+    ) -> Self {
+        self.merge_method(R::METHOD, move |local, upstream| {
+            let parsed = serde_json::from_value::<R::Result>(local.clone())
+                .and_then(|l| Ok((l, serde_json::from_value::<R::Result>(upstream)?)));
+            match parsed {
+                Ok((local, upstream)) => {
+                    serde_json::to_value(merge(local, upstream)).expect("Failed to serialize")
+                }
+                Err(_) => local,
+            }
+        })
+    }
+
+    /// Same as [`ProxyBuilder::merge`] but with a method name and JSON results, eg. of a custom
+    /// request.
+    pub fn merge_method(
+        mut self,
+        method: impl Into<String>,
+        merge: impl Fn(JsonValue, JsonValue) -> JsonValue + Send + Sync + 'static,
+    ) -> Self {
+        config_mut(&mut self.config)
+            .merges
+            .insert(method.into(), Arc::new(merge));
+        self
+    }
+
+    /// Deliver notifications of `N` to both services if the local one can handle them.
+    pub fn broadcast<N: Notification>(self) -> Self {
+        self.broadcast_method(N::METHOD)
+    }
+
+    /// Same as [`ProxyBuilder::broadcast`] but with a method name, eg. of a custom notification.
+    pub fn broadcast_method(mut self, method: impl Into<String>) -> Self {
+        config_mut(&mut self.config)
+            .broadcasts
+            .insert(method.into());
+        self
+    }
+}
+
+/// Get a mutable reference to the config, cloning it if it is shared.
+fn config_mut(config: &mut Arc<Config>) -> &mut Config {
+    if Arc::get_mut(config).is_none() {
+        *config = Arc::new(Config {
+            merges: config.merges.clone(),
+            broadcasts: config.broadcasts.clone(),
+        });
+    }
+    Arc::get_mut(config).expect("Unique")
+}
+
+/// A type alias of [`ProxyBuilder`] conforming to the naming convention of [`tower_layer`].
+pub type ProxyLayer = ProxyBuilder;
+
+impl<S> Layer<S> for ProxyBuilder {
+    type Service = Proxy<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Proxy {
+            service: inner,
+            upstream: self.upstream.clone(),
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{poll_fn, ready};
+
+    use futures::channel::mpsc;
+    use futures::{AsyncReadExt, StreamExt};
+    use lsp_types::notification::{DidCloseTextDocument, DidOpenTextDocument, Initialized};
+    use lsp_types::request::{HoverRequest, WorkspaceSymbolRequest};
+    use lsp_types::{
+        Hover, HoverContents, MarkedString, OneOf, SymbolKind, Url, WorkspaceLocation,
+        WorkspaceSymbol, WorkspaceSymbolResponse,
+    };
+    use serde_json::json;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::{MainLoop, RequestId};
+
+    fn symbols(names: &[&str]) -> Option<WorkspaceSymbolResponse> {
+        let symbols = names
+            .iter()
+            .map(|name| WorkspaceSymbol {
+                name: (*name).into(),
+                kind: SymbolKind::FUNCTION,
+                tags: None,
+                container_name: None,
+                location: OneOf::Right(WorkspaceLocation {
+                    uri: Url::parse("file:///foo").unwrap(),
+                }),
+                data: None,
+            })
+            .collect();
+        Some(WorkspaceSymbolResponse::Nested(symbols))
+    }
+
+    #[tokio::test]
+    async fn proxy() {
+        let (notif_tx, mut notif_rx) = mpsc::unbounded();
+        let (upstream_main, _client) = MainLoop::new_server(|_| {
+            let mut router = Router::new(notif_tx);
+            router
+                .request::<HoverRequest, _>(|_, _| {
+                    ready(Ok(Some(Hover {
+                        contents: HoverContents::Scalar(MarkedString::String("upstream".into())),
+                        range: None,
+                    })))
+                })
+                .request::<WorkspaceSymbolRequest, _>(|_, _| ready(Ok(symbols(&["upstream"]))))
+                .unhandled_notification(|tx, notif| {
+                    tx.unbounded_send(notif.method).unwrap();
+                    ControlFlow::Continue(())
+                });
+            router
+        });
+        let (client_main, upstream) = MainLoop::new_client(|_| Router::new(()));
+        let (server_stream, client_stream) = tokio::io::duplex(64 << 10);
+        let (server_rx, server_tx) = server_stream.compat().split();
+        let (client_rx, client_tx) = client_stream.compat().split();
+        let upstream_main = tokio::spawn(upstream_main.run_buffered(server_rx, server_tx));
+        let client_main = tokio::spawn(client_main.run_buffered(client_rx, client_tx));
+
+        let mut router = Router::<(), ResponseError>::new(());
+        router
+            .request::<WorkspaceSymbolRequest, _>(|_, _| ready(Ok(symbols(&["local"]))))
+            .notification::<DidOpenTextDocument>(|_, _| ControlFlow::Continue(()))
+            .notification::<DidCloseTextDocument>(|_, _| ControlFlow::Continue(()));
+        let mut proxy = ProxyLayer::new(upstream)
+            .merge::<WorkspaceSymbolRequest>(|local, upstream| match (local, upstream) {
+                (
+                    Some(WorkspaceSymbolResponse::Nested(mut local)),
+                    Some(WorkspaceSymbolResponse::Nested(upstream)),
+                ) => {
+                    local.extend(upstream);
+                    Some(WorkspaceSymbolResponse::Nested(local))
+                }
+                (local, _) => local,
+            })
+            .broadcast::<DidOpenTextDocument>()
+            .layer(router);
+
+        let text_document = json!({ "uri": "file:///foo" });
+        for (method, params, expect) in [
+            (
+                HoverRequest::METHOD,
+                json!({ "textDocument": text_document, "position": { "line": 0, "character": 0 } }),
+                json!({ "contents": "upstream" }),
+            ),
+            (
+                WorkspaceSymbolRequest::METHOD,
+                json!({ "query": "" }),
+                serde_json::to_value(symbols(&["local", "upstream"])).unwrap(),
+            ),
+        ] {
+            poll_fn(|cx| proxy.poll_ready(cx)).await.unwrap();
+            let req = AnyRequest {
+                id: RequestId::Number(0),
+                method: method.into(),
+                params,
+                receipt: None,
+            };
+            assert_eq!(proxy.call(req).await.unwrap(), expect);
+        }
+
+        for (method, params) in [
+            (
+                DidOpenTextDocument::METHOD,
+                json!({ "textDocument": {
+                    "uri": "file:///foo",
+                    "languageId": "rust",
+                    "version": 0,
+                    "text": "",
+                } }),
+            ),
+            (
+                DidCloseTextDocument::METHOD,
+                json!({ "textDocument": text_document }),
+            ),
+            (Initialized::METHOD, json!({})),
+        ] {
+            let notif = AnyNotification {
+                method: method.into(),
+                params,
+                receipt: None,
+            };
+            assert!(matches!(proxy.notify(notif), ControlFlow::Continue(())));
+        }
+        // `didClose` is only delivered locally.
+        assert_eq!(notif_rx.next().await.unwrap(), DidOpenTextDocument::METHOD);
+        assert_eq!(notif_rx.next().await.unwrap(), Initialized::METHOD);
+
+        upstream_main.abort();
+        client_main.abort();
+    }
+}
@@ -0,0 +1,222 @@
+//! Ready-made building blocks for common setups.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! These functions assemble the pieces shown in examples of this crate, so that simple servers
+//! and clients can depend on maintained code instead of copying the examples:
+//! - [`stdio_server`] runs a [`Router`] with the standard middleware stack over stdio.
+//! - [`spawn_server`] spawns a Language Server process, and drives a client main loop for it on
+//!   a dedicated thread.
+//! - [`spawn_rust_analyzer`] spawns and initializes `rust-analyzer` for a project.
+//!
+//! They are deliberately opinionated. Assemble the pieces yourself for anything more custom.
+use std::io;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread::{self, JoinHandle};
+
+use lsp_types::notification::Initialized;
+use lsp_types::request::{Initialize, WorkDoneProgressCreate};
+use lsp_types::{
+    ClientCapabilities, InitializeParams, InitializeResult, InitializedParams, Url,
+    WindowClientCapabilities, WorkspaceFolder,
+};
+use serde_json::Value as JsonValue;
+
+use crate::router::Router;
+use crate::{Error, LspService, MainLoop, ResponseError, Result, ServerSocket};
+
+/// Run a Language Server built by `builder` over stdio, with the standard middleware stack, from
+/// the outermost to the innermost: [`Tracing`](crate::tracing::Tracing),
+/// [`Lifecycle`](crate::server::Lifecycle), [`CatchUnwind`](crate::panic::CatchUnwind),
+/// [`Concurrency`](crate::concurrency::Concurrency) and
+/// [`ClientProcessMonitor`](crate::client_monitor::ClientProcessMonitor).
+///
+/// It returns when the main loop exits.
+///
+/// ```no_run
+/// # use async_lsp::router::Router;
+/// # use lsp_types::request::Initialize;
+/// # async fn work() -> async_lsp::Result<()> {
+/// async_lsp::quickstart::stdio_server(|_client| {
+///     let mut router = Router::new(());
+///     router.request::<Initialize, _>(|_, _| async { Ok(Default::default()) });
+///     router
+/// })
+/// .await
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// - `Error::Io` when stdin or stdout cannot be locked or registered to the tokio runtime.
+/// - Errors of [`MainLoop::run_buffered`].
+#[cfg(all(
+    feature = "client-monitor",
+    feature = "stdio",
+    feature = "tokio",
+    feature = "tracing",
+    unix,
+))]
+#[cfg_attr(
+    docsrs,
+    doc(cfg(all(
+        feature = "client-monitor",
+        feature = "stdio",
+        feature = "tokio",
+        feature = "tracing",
+        unix,
+    )))
+)]
+pub async fn stdio_server<St>(builder: impl FnOnce(crate::ClientSocket) -> Router<St>) -> Result<()>
+where
+    St: Send + 'static,
+{
+    use tower_layer::Layer;
+
+    use crate::client_monitor::ClientProcessMonitorLayer;
+    use crate::concurrency::ConcurrencyLayer;
+    use crate::panic::CatchUnwindLayer;
+    use crate::server::LifecycleLayer;
+    use crate::stdio::{PipeStdin, PipeStdout};
+    use crate::tracing::TracingLayer;
+
+    let (server, _) = MainLoop::new_server(|client| {
+        let router = builder(client.clone());
+        let service = ClientProcessMonitorLayer::new(client).layer(router);
+        let service = ConcurrencyLayer::default().layer(service);
+        let service = CatchUnwindLayer::default().layer(service);
+        let service = LifecycleLayer::default().layer(service);
+        TracingLayer::default().layer(service)
+    });
+    let (stdin, stdout) = (PipeStdin::lock_tokio()?, PipeStdout::lock_tokio()?);
+    server.run_buffered(stdin, stdout).await
+}
+
+/// A Language Server running as a child process, with a client main loop driving it.
+///
+/// It is created by [`spawn_server`] or [`spawn_rust_analyzer`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ChildServer {
+    /// The socket to communicate with the server.
+    pub server: ServerSocket,
+    /// The server process. It is not killed when dropped.
+    pub child: Child,
+    /// The thread running the client main loop, which exits when the server closes its stdout.
+    pub mainloop: JoinHandle<Result<()>>,
+}
+
+/// Spawn a Language Server process from `command`, and drive a client main loop built by
+/// `builder` on a dedicated thread via [`MainLoop::run_blocking`].
+///
+/// Stdin and stdout of `command` are piped to the main loop. Its stderr is inherited unless
+/// configured otherwise.
+///
+/// # Errors
+///
+/// Fails if the process or the thread cannot be spawned.
+pub fn spawn_server<S>(
+    command: &mut Command,
+    builder: impl FnOnce(ServerSocket) -> S,
+) -> io::Result<ChildServer>
+where
+    S: LspService<Response = JsonValue> + Send + 'static,
+    S::Future: Send,
+    S::Error: Send,
+    ResponseError: From<S::Error>,
+{
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let input = child.stdout.take().expect("Piped");
+    let output = child.stdin.take().expect("Piped");
+    let (mainloop, server) = MainLoop::new_client(builder);
+    let mainloop = thread::Builder::new()
+        .name("lsp-mainloop".into())
+        .spawn(move || mainloop.run_blocking(input, output))?;
+    Ok(ChildServer {
+        server,
+        child,
+        mainloop,
+    })
+}
+
+/// Spawn `rust-analyzer` from `PATH` for the project at `root`, and initialize it.
+///
+/// The client accepts server-initiated work done progress, and ignores all notifications from
+/// the server, eg. `textDocument/publishDiagnostics`. Use [`spawn_server`] to handle them.
+///
+/// ```no_run
+/// # async fn work() -> async_lsp::Result<()> {
+/// let (ra, init_ret) = async_lsp::quickstart::spawn_rust_analyzer("path/to/project").await?;
+/// println!("{:?}", init_ret.capabilities);
+/// ra.server.request::<lsp_types::request::Shutdown>(()).await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// - `Error::Io` when `root` is invalid, or `rust-analyzer` cannot be spawned.
+/// - Errors of [`ServerSocket::request`] when the initialization fails.
+pub async fn spawn_rust_analyzer(
+    root: impl AsRef<Path>,
+) -> Result<(ChildServer, InitializeResult)> {
+    let root = root.as_ref().canonicalize()?;
+    let uri = Url::from_file_path(&root)
+        .map_err(|()| Error::Io(io::Error::new(io::ErrorKind::InvalidInput, "Invalid root")))?;
+    let ra = spawn_server(Command::new("rust-analyzer").current_dir(&root), |_| {
+        let mut router = Router::new(());
+        router
+            .request::<WorkDoneProgressCreate, _>(|_, _| async { Ok(()) })
+            .unhandled_notification(|_, _| ControlFlow::Continue(()));
+        router
+    })?;
+
+    let init_ret = ra
+        .server
+        .request::<Initialize>(InitializeParams {
+            workspace_folders: Some(vec![WorkspaceFolder {
+                uri,
+                name: "root".into(),
+            }]),
+            capabilities: ClientCapabilities {
+                window: Some(WindowClientCapabilities {
+                    work_done_progress: Some(true),
+                    ..WindowClientCapabilities::default()
+                }),
+                ..ClientCapabilities::default()
+            },
+            ..InitializeParams::default()
+        })
+        .await?;
+    ra.server.notify::<Initialized>(InitializedParams {})?;
+    Ok((ra, init_ret))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use lsp_types::request::Shutdown;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn spawn_echo_server() {
+        // `cat` echoes our requests back, and then our responses to them.
+        let mut echo = spawn_server(Command::new("cat").stderr(Stdio::null()), |_| {
+            let mut router = Router::new(());
+            router.request::<Shutdown, _>(|_, ()| async { Ok(()) });
+            router
+        })
+        .unwrap();
+        echo.server.request::<Shutdown>(()).await.unwrap();
+
+        echo.child.kill().unwrap();
+        echo.child.wait().unwrap();
+        let ret = echo.mainloop.join().unwrap();
+        assert!(matches!(ret, Err(Error::Eof)), "{ret:?}");
+    }
+}
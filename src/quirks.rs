@@ -0,0 +1,404 @@
+//! Client-specific workarounds as post-processing on responses.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Editors have bugs and misinterpretations of the specification, which servers end up working
+//! around in handler code, eg. trimming fields a specific editor mishandles. This middleware
+//! centralizes such hacks. It captures
+//! [`InitializeParams::client_info`][lsp_types::InitializeParams::client_info] on `initialize`,
+//! and applies each registered [`Quirk`] matching the client name, version and request method to
+//! successful responses. Common fixes are available in [`fixes`].
+//!
+//! ```
+//! # use async_lsp::quirks::{fixes, Quirk, QuirksLayer};
+//! # use async_lsp::lsp_types::request::{Completion, DocumentSymbolRequest, Request};
+//! let layer = QuirksLayer::new()
+//!     .quirk(
+//!         Quirk::new("Some Editor", Completion::METHOD, fixes::remove_field("labelDetails"))
+//!             .before("1.2"),
+//!     )
+//!     .quirk(Quirk::new("Other Editor", DocumentSymbolRequest::METHOD, fixes::order_ranges));
+//! ```
+use std::cmp::Ordering;
+use std::fmt;
+use std::future::Future;
+use std::ops::ControlFlow;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use lsp_types::request::{self, Request};
+use lsp_types::ClientInfo;
+use pin_project_lite::pin_project;
+use serde_json::Value as JsonValue;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{AnyEvent, AnyNotification, AnyRequest, EventHints, LspService, Result};
+
+type Fix = Arc<dyn Fn(&mut JsonValue) + Send + Sync>;
+type VersionFilter = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// A workaround for a client, post-processing successful responses of a method.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone)]
+#[must_use]
+pub struct Quirk {
+    client: String,
+    versions: Option<VersionFilter>,
+    method: String,
+    fix: Fix,
+}
+
+impl fmt::Debug for Quirk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Quirk")
+            .field("client", &self.client)
+            .field("method", &self.method)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Quirk {
+    /// Create a quirk applying `fix` to JSON responses of `method` for all versions of the client
+    /// named `client`.
+    pub fn new(
+        client: impl Into<String>,
+        method: impl Into<String>,
+        fix: impl Fn(&mut JsonValue) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            client: client.into(),
+            versions: None,
+            method: method.into(),
+            fix: Arc::new(fix),
+        }
+    }
+
+    /// Create a quirk applying `fix` to typed responses of request `R`.
+    ///
+    /// Responses which cannot be deserialized as `R::Result` are kept unchanged.
+    pub fn request<R: Request>(
+        client: impl Into<String>,
+        fix: impl Fn(&mut R::Result) + Send + Sync + 'static,
+    ) -> Self {
+        Self::new(client, R::METHOD, move |value| {
+            if let Ok(mut resp) = serde_json::from_value::<R::Result>(value.clone()) {
+                fix(&mut resp);
+                *value = serde_json::to_value(resp).expect("Failed to serialize");
+            }
+        })
+    }
+
+    /// Only apply to client versions satisfying `filter`. Clients without a version never match.
+    pub fn versions(mut self, filter: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        self.versions = Some(Arc::new(filter));
+        self
+    }
+
+    /// Only apply to client versions before `version`, eg. those fixing the bug.
+    ///
+    /// Versions are compared by dot-separated components, numerically if both are numbers, or
+    /// lexicographically otherwise. Pre-release suffixes are not specially handled.
+    pub fn before(self, version: impl Into<String>) -> Self {
+        let version = version.into();
+        self.versions(move |v| compare_versions(v, &version) == Ordering::Less)
+    }
+
+    fn matches(&self, client: &ClientInfo, method: &str) -> bool {
+        self.method == method
+            && self.client == client.name
+            && self.versions.as_ref().map_or(true, |filter| {
+                client.version.as_deref().map_or(false, |v| filter(v))
+            })
+    }
+}
+
+fn compare_versions(lhs: &str, rhs: &str) -> Ordering {
+    let mut lhs = lhs.split('.');
+    let mut rhs = rhs.split('.');
+    loop {
+        let ord = match (lhs.next(), rhs.next()) {
+            (None, None) => return Ordering::Equal,
+            (Some(_), None) => return Ordering::Greater,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(a), Some(b)) => match (a.parse::<u64>(), b.parse::<u64>()) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                _ => a.cmp(b),
+            },
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+}
+
+/// Common fixes for [`Quirk::new`].
+pub mod fixes {
+    use serde_json::Value as JsonValue;
+
+    /// Remove the field `name` from all objects in the response, recursively.
+    pub fn remove_field(name: &'static str) -> impl Fn(&mut JsonValue) + Send + Sync + 'static {
+        fn go(value: &mut JsonValue, name: &str) {
+            match value {
+                JsonValue::Array(arr) => arr.iter_mut().for_each(|v| go(v, name)),
+                JsonValue::Object(obj) => {
+                    obj.remove(name);
+                    obj.values_mut().for_each(|v| go(v, name));
+                }
+                _ => {}
+            }
+        }
+        move |value| go(value, name)
+    }
+
+    /// Clamp the `end` of all ranges in the response to be no earlier than their `start`.
+    ///
+    /// Some clients reject the whole response on an inverted range.
+    pub fn order_ranges(value: &mut JsonValue) {
+        fn position(value: &JsonValue) -> Option<(u64, u64)> {
+            Some((
+                value.get("line")?.as_u64()?,
+                value.get("character")?.as_u64()?,
+            ))
+        }
+
+        match value {
+            JsonValue::Array(arr) => arr.iter_mut().for_each(order_ranges),
+            JsonValue::Object(obj) => {
+                if let (Some(start), Some(end)) = (obj.get("start"), obj.get("end")) {
+                    if let (Some(s), Some(e)) = (position(start), position(end)) {
+                        if e < s {
+                            let start = start.clone();
+                            obj.insert("end".into(), start);
+                        }
+                    }
+                }
+                obj.values_mut().for_each(order_ranges);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The middleware applying client-specific workarounds to responses.
+///
+/// See [module level documentations](self) for details.
+pub struct Quirks<S> {
+    service: S,
+    quirks: Arc<[Quirk]>,
+    client: Option<ClientInfo>,
+}
+
+define_getters!(impl[S] Quirks<S>, service: S);
+
+impl<S: LspService<Response = JsonValue>> Service<AnyRequest> for Quirks<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        if req.method == request::Initialize::METHOD {
+            self.client = req
+                .params
+                .get("clientInfo")
+                .and_then(|info| serde_json::from_value(info.clone()).ok());
+        }
+        let fixes = match &self.client {
+            Some(client) => self
+                .quirks
+                .iter()
+                .filter(|quirk| quirk.matches(client, &req.method))
+                .map(|quirk| quirk.fix.clone())
+                .collect(),
+            None => Vec::new(),
+        };
+        ResponseFuture {
+            fut: self.service.call(req),
+            fixes,
+        }
+    }
+}
+
+impl<S: LspService<Response = JsonValue>> LspService for Quirks<S> {
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        self.service.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.service.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+pin_project! {
+    /// The [`Future`] type used by the [`Quirks`] middleware.
+    pub struct ResponseFuture<Fut> {
+        #[pin]
+        fut: Fut,
+        fixes: Vec<Fix>,
+    }
+}
+
+impl<Fut, Error> Future for ResponseFuture<Fut>
+where
+    Fut: Future<Output = Result<JsonValue, Error>>,
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let mut ret = ready!(this.fut.poll(cx));
+        if let Ok(resp) = &mut ret {
+            for fix in this.fixes.drain(..) {
+                fix(resp);
+            }
+        }
+        Poll::Ready(ret)
+    }
+}
+
+/// The builder of [`Quirks`] middleware, holding the registry of [`Quirk`]s.
+///
+/// It's [`Default`] configuration has no quirks.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone, Debug, Default)]
+#[must_use]
+pub struct QuirksBuilder {
+    quirks: Vec<Quirk>,
+}
+
+impl QuirksBuilder {
+    /// Create the middleware with no quirks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a quirk. Quirks matching the same response are applied in registration order.
+    pub fn quirk(mut self, quirk: Quirk) -> Self {
+        self.quirks.push(quirk);
+        self
+    }
+}
+
+/// A type alias of [`QuirksBuilder`] conforming to the naming convention of [`tower_layer`].
+pub type QuirksLayer = QuirksBuilder;
+
+impl<S> Layer<S> for QuirksBuilder {
+    type Service = Quirks<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Quirks {
+            service: inner,
+            quirks: self.quirks.clone().into(),
+            client: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{poll_fn, ready};
+
+    use lsp_types::{DocumentSymbol, DocumentSymbolResponse, Position, Range, SymbolKind};
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::{RequestId, ResponseError};
+
+    #[test]
+    fn versions() {
+        assert_eq!(compare_versions("0.9.5", "0.10"), Ordering::Less);
+        assert_eq!(compare_versions("1.2", "1.2.0"), Ordering::Less);
+        assert_eq!(compare_versions("2.0", "1.10"), Ordering::Greater);
+        assert_eq!(compare_versions("1.2", "1.2"), Ordering::Equal);
+    }
+
+    #[tokio::test]
+    async fn apply() {
+        let range = |start, end| Range::new(Position::new(0, start), Position::new(0, end));
+        #[allow(deprecated)]
+        let symbol = DocumentSymbol {
+            name: "foo".into(),
+            detail: Some("fn()".into()),
+            kind: SymbolKind::FUNCTION,
+            tags: None,
+            deprecated: None,
+            range: range(5, 3),
+            selection_range: range(0, 3),
+            children: None,
+        };
+
+        let mut router = Router::<(), ResponseError>::new(());
+        router
+            .request::<request::Initialize, _>(|_, _| ready(Ok(Default::default())))
+            .request::<request::DocumentSymbolRequest, _>(move |_, _| {
+                ready(Ok(Some(DocumentSymbolResponse::Nested(vec![
+                    symbol.clone()
+                ]))))
+            });
+        let mut svc = QuirksLayer::new()
+            .quirk(Quirk::new(
+                "Editor",
+                request::DocumentSymbolRequest::METHOD,
+                fixes::order_ranges,
+            ))
+            .quirk(
+                Quirk::request::<request::DocumentSymbolRequest>("Editor", |resp| {
+                    if let Some(DocumentSymbolResponse::Nested(symbols)) = resp {
+                        symbols.iter_mut().for_each(|sym| sym.detail = None);
+                    }
+                })
+                .before("1.0"),
+            )
+            .layer(router);
+
+        let symbols = |svc: &mut Quirks<Router<(), ResponseError>>, name: &str, version: &str| {
+            let init = AnyRequest {
+                id: RequestId::Number(0),
+                method: request::Initialize::METHOD.into(),
+                params: json!({
+                    "capabilities": {},
+                    "clientInfo": { "name": name, "version": version },
+                }),
+                receipt: None,
+            };
+            let init = svc.call(init);
+            let req = AnyRequest {
+                id: RequestId::Number(1),
+                method: request::DocumentSymbolRequest::METHOD.into(),
+                params: json!({ "textDocument": { "uri": "file:///foo" } }),
+                receipt: None,
+            };
+            let resp = svc.call(req);
+            async move {
+                init.await.unwrap();
+                match serde_json::from_value(resp.await.unwrap()).unwrap() {
+                    Some(DocumentSymbolResponse::Nested(symbols)) => symbols,
+                    resp => panic!("unexpected response: {resp:?}"),
+                }
+            }
+        };
+
+        poll_fn(|cx| svc.poll_ready(cx)).await.unwrap();
+        let ret = symbols(&mut svc, "Other", "0.1").await;
+        assert_eq!((ret[0].range, ret[0].detail.is_some()), (range(5, 3), true));
+        let ret = symbols(&mut svc, "Editor", "1.0").await;
+        assert_eq!((ret[0].range, ret[0].detail.is_some()), (range(5, 5), true));
+        let ret = symbols(&mut svc, "Editor", "0.9").await;
+        assert_eq!(
+            (ret[0].range, ret[0].detail.is_some()),
+            (range(5, 5), false)
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! Record the wire traffic of main loops.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! A [`Recorder`] set via [`MainLoop::recorder`][crate::MainLoop::recorder] receives every
+//! incoming and outgoing JSON-RPC message, or batch, as a timestamped [`RecordEntry`]. It helps
+//! debugging and conformance analysis of Language Servers and Language Clients built on this
+//! crate.
+//!
+//! [`JsonLines`] writes entries into any [`io::Write`] as
+//! [JSON Lines](https://jsonlines.org), one entry per line, eg.
+//! `{"timestamp":1700000000000,"direction":"incoming","message":{"jsonrpc":"2.0",...}}`.
+//! Logs in this format can be parsed back via [`RecordEntry`]'s [`Deserialize`] implementation
+//! for replaying. Closures can also be used as recorders, eg. to forward entries to a channel.
+//!
+//! Messages are recorded after being decoded or before being encoded. Undecodable incoming
+//! messages are not recorded, since they fail the main loop anyway.
+use std::fmt;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+/// The direction of a recorded message, relative to the main loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Received from the peer.
+    Incoming,
+    /// Sent to the peer.
+    Outgoing,
+}
+
+/// A recorded message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct RecordEntry {
+    /// Milliseconds since the UNIX epoch when the message is received or sent.
+    pub timestamp: u64,
+    /// The direction of the message.
+    pub direction: Direction,
+    /// The JSON-RPC message including the `jsonrpc` field, or an array of them for a batch.
+    pub message: JsonValue,
+}
+
+impl RecordEntry {
+    /// Create an entry timestamped now.
+    #[must_use]
+    pub fn new(direction: Direction, message: JsonValue) -> Self {
+        Self {
+            timestamp: timestamp(),
+            direction,
+            message,
+        }
+    }
+}
+
+/// Milliseconds since the UNIX epoch, or 0 if the clock is before it.
+pub(crate) fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |dur| dur.as_millis() as u64)
+}
+
+/// A sink of recorded messages.
+///
+/// It is implemented for functions of the same signature as [`Recorder::record`].
+pub trait Recorder: Send + 'static {
+    /// Record a message.
+    ///
+    /// # Errors
+    ///
+    /// Errors are logged, if `tracing` is enabled, and otherwise ignored. They never stop the main
+    /// loop.
+    fn record(&mut self, entry: &RecordEntry) -> io::Result<()>;
+}
+
+impl<F> Recorder for F
+where
+    F: FnMut(&RecordEntry) -> io::Result<()> + Send + 'static,
+{
+    fn record(&mut self, entry: &RecordEntry) -> io::Result<()> {
+        self(entry)
+    }
+}
+
+/// The [`Recorder`] writing entries in JSON Lines format.
+///
+/// See [module level documentations](self) for details.
+pub struct JsonLines<W> {
+    writer: W,
+}
+
+impl<W> fmt::Debug for JsonLines<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonLines").finish_non_exhaustive()
+    }
+}
+
+define_getters!(impl[W] JsonLines<W>, writer: W);
+
+impl<W: Write> JsonLines<W> {
+    /// Create a recorder writing to `writer`.
+    ///
+    /// Each entry is written and flushed in whole, so `writer` is usually unbuffered, or a
+    /// [`io::BufWriter`] which is flushed anyway.
+    #[must_use]
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write + Send + 'static> Recorder for JsonLines<W> {
+    fn record(&mut self, entry: &RecordEntry) -> io::Result<()> {
+        let mut buf = serde_json::to_vec(entry)?;
+        buf.push(b'\n');
+        self.writer.write_all(&buf)?;
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures::AsyncReadExt;
+    use lsp_types::request::{self, Request};
+    use serde_json::json;
+    use tokio::io::AsyncWriteExt;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::MainLoop;
+
+    #[tokio::test]
+    async fn record() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (server_main, _server) = MainLoop::new_server(|_| {
+            let mut router = Router::new(());
+            router.request::<request::Shutdown, _>(|_, ()| async { Ok(()) });
+            router
+        });
+        let server_main = server_main.recorder(JsonLines::new(SharedBuf(log.clone())));
+
+        let (server_stream, mut client_stream) = tokio::io::duplex(64 << 10);
+        let (server_rx, server_tx) = server_stream.compat().split();
+        let server_main = tokio::spawn(server_main.run_buffered(server_rx, server_tx));
+
+        let req = json!({ "jsonrpc": "2.0", "id": 1, "method": request::Shutdown::METHOD });
+        let content = req.to_string();
+        let frame = format!("Content-Length: {}\r\n\r\n{content}", content.len());
+        client_stream.write_all(frame.as_bytes()).await.unwrap();
+        client_stream.shutdown().await.unwrap();
+        let mut output = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut client_stream, &mut output)
+            .await
+            .unwrap();
+        drop(client_stream);
+        server_main.await.unwrap().unwrap_err();
+
+        let log = log.lock().unwrap();
+        let entries = std::str::from_utf8(&log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<RecordEntry>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].direction, Direction::Incoming);
+        assert_eq!(entries[0].message, req);
+        assert_eq!(entries[1].direction, Direction::Outgoing);
+        assert_eq!(
+            entries[1].message,
+            json!({ "jsonrpc": "2.0", "id": 1, "result": null }),
+        );
+        assert!(entries[0].timestamp <= entries[1].timestamp);
+    }
+
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! Dynamic capability registration tracking.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Servers supporting dynamic registration often need to enable or disable capabilities on
+//! configuration changes, eg. when the user disables formatting. [`DynamicRegistrations`] keeps
+//! track of capabilities registered to the Language Client. On each change, the server
+//! recomputes the full set of desired registrations and passes it to
+//! [`DynamicRegistrations::update`], which diffs it against the current state and issues the
+//! minimal `client/unregisterCapability` and `client/registerCapability` requests.
+//!
+//! Registrations are identified by their [`Registration::id`]. A registration whose method or
+//! options change is unregistered and then registered again.
+//!
+//! ```
+//! # use async_lsp::registration::{registration, DynamicRegistrations};
+//! # use async_lsp::lsp_types::request::Formatting;
+//! # async fn work(registrations: DynamicRegistrations, format_enabled: bool) {
+//! let mut desired = Vec::new();
+//! if format_enabled {
+//!     desired.push(registration::<Formatting>("formatting", None::<()>));
+//! }
+//! registrations.update(desired).await.unwrap();
+//! # }
+//! ```
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use futures::lock::Mutex;
+use lsp_types::request::{RegisterCapability, Request, UnregisterCapability};
+use lsp_types::{Registration, RegistrationParams, Unregistration, UnregistrationParams};
+use serde::Serialize;
+
+use crate::{ClientSocket, Result};
+
+/// Build a [`Registration`] of request `R` with optional registration options.
+///
+/// # Panics
+///
+/// Panics if `options` fails to serialize.
+#[must_use]
+pub fn registration<R: Request>(
+    id: impl Into<String>,
+    options: Option<impl Serialize>,
+) -> Registration {
+    Registration {
+        id: id.into(),
+        method: R::METHOD.into(),
+        register_options: options
+            .map(|opts| serde_json::to_value(opts).expect("Failed to serialize")),
+    }
+}
+
+/// The cheaply cloneable tracker of dynamically registered capabilities.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone)]
+pub struct DynamicRegistrations {
+    client: ClientSocket,
+    current: Arc<Mutex<BTreeMap<String, Registration>>>,
+}
+
+impl DynamicRegistrations {
+    /// Create a tracker with nothing registered, sending requests via `client`.
+    #[must_use]
+    pub fn new(client: ClientSocket) -> Self {
+        Self {
+            client,
+            current: Arc::default(),
+        }
+    }
+
+    /// Get a snapshot of currently registered capabilities.
+    pub async fn registered(&self) -> Vec<Registration> {
+        self.current.lock().await.values().cloned().collect()
+    }
+
+    /// Make `desired` the exact set of registered capabilities, issuing at most one
+    /// `client/unregisterCapability` and one `client/registerCapability` request.
+    ///
+    /// Concurrent updates are serialized.
+    ///
+    /// # Errors
+    ///
+    /// Fails if either request fails, or the service main loop stopped. The tracked state
+    /// reflects the requests which succeeded, so a later update can recover.
+    pub async fn update(&self, desired: impl IntoIterator<Item = Registration>) -> Result<()> {
+        let mut current = self.current.lock().await;
+        let desired = desired
+            .into_iter()
+            .map(|reg| (reg.id.clone(), reg))
+            .collect::<BTreeMap<_, _>>();
+
+        let unregisterations = current
+            .values()
+            .filter(|reg| desired.get(&reg.id) != Some(reg))
+            .map(|reg| Unregistration {
+                id: reg.id.clone(),
+                method: reg.method.clone(),
+            })
+            .collect::<Vec<_>>();
+        let registrations = desired
+            .values()
+            .filter(|reg| current.get(&reg.id) != Some(reg))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if !unregisterations.is_empty() {
+            self.client
+                .request::<UnregisterCapability>(UnregistrationParams {
+                    unregisterations: unregisterations.clone(),
+                })
+                .await?;
+            for unreg in &unregisterations {
+                current.remove(&unreg.id);
+            }
+        }
+        if !registrations.is_empty() {
+            self.client
+                .request::<RegisterCapability>(RegistrationParams {
+                    registrations: registrations.clone(),
+                })
+                .await?;
+            current.extend(registrations.into_iter().map(|reg| (reg.id.clone(), reg)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use lsp_types::request::{Formatting, HoverRequest};
+    use serde_json::{json, Value as JsonValue};
+
+    use super::*;
+    use crate::{AnyResponse, MainLoopEvent, PeerSocket};
+
+    #[tokio::test]
+    async fn minimal_diff() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let regs = DynamicRegistrations::new(ClientSocket(PeerSocket { tx }));
+        let log = Arc::new(std::sync::Mutex::new(Vec::<(String, JsonValue)>::new()));
+        let server = tokio::spawn({
+            let log = log.clone();
+            async move {
+                while let Some(event) = rx.next().await {
+                    if let MainLoopEvent::OutgoingRequest(req, resp_tx) = event {
+                        log.lock().unwrap().push((req.method, req.params));
+                        let _ = resp_tx.send(AnyResponse {
+                            id: req.id,
+                            result: Some(json!(null)),
+                            error: None,
+                            raw: None,
+                        });
+                    }
+                }
+            }
+        });
+        let take_log = || std::mem::take(&mut *log.lock().unwrap());
+
+        let fmt = registration::<Formatting>("fmt", None::<()>);
+        let hover = registration::<HoverRequest>("hover", None::<()>);
+        regs.update([fmt.clone(), hover.clone()]).await.unwrap();
+        let log1 = take_log();
+        assert_eq!(log1.len(), 1);
+        assert_eq!(log1[0].0, RegisterCapability::METHOD);
+        assert_eq!(log1[0].1["registrations"].as_array().unwrap().len(), 2);
+
+        regs.update([hover.clone()]).await.unwrap();
+        let log2 = take_log();
+        assert_eq!(log2.len(), 1);
+        assert_eq!(log2[0].0, UnregisterCapability::METHOD);
+        assert_eq!(log2[0].1["unregisterations"][0]["id"], "fmt");
+
+        regs.update([hover.clone()]).await.unwrap();
+        assert!(take_log().is_empty());
+
+        let hover2 =
+            registration::<HoverRequest>("hover", Some(json!({ "workDoneProgress": true })));
+        regs.update([hover2.clone()]).await.unwrap();
+        let methods = take_log().into_iter().map(|(m, _)| m).collect::<Vec<_>>();
+        assert_eq!(
+            methods,
+            [UnregisterCapability::METHOD, RegisterCapability::METHOD]
+        );
+        assert_eq!(regs.registered().await, [hover2]);
+
+        drop(regs);
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,318 @@
+//! Consistent `textDocument/prepareRename` and `textDocument/rename` handling.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Servers commonly ship subtle mismatches between the two requests, eg. prepare accepts a
+//! position which rename rejects, or rename edits miss the very range shown to the user.
+//! [`RenameFlow`] wires both requests to a single target computation callback, which locates the
+//! renamed range and its placeholder text, so that:
+//! - `prepareRename` responds the range and placeholder of the target, or `null` if there is none.
+//! - `rename` rejects positions without a target, and empty or invalid new names, before
+//!   computing edits. Renaming to the same name results in no edits.
+//! - Edits returned by `rename` are validated to cover the target range.
+//! - [`RenameFlow::capability`] only advertises prepare support if the client supports it.
+use std::future::Future;
+use std::sync::Arc;
+
+use lsp_types::request::{PrepareRenameRequest, Rename};
+use lsp_types::{
+    ClientCapabilities, DocumentChangeOperation, DocumentChanges, OneOf, PrepareRenameResponse,
+    Range, RenameOptions, RenameParams, TextDocumentPositionParams, Url, WorkspaceEdit,
+};
+
+use crate::router::Router;
+use crate::{ErrorCode, ResponseError};
+
+/// The renamed range and the placeholder text presented to the user, usually the current name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenameTarget {
+    /// The range to be renamed.
+    pub range: Range,
+    /// The placeholder text of the rename input box.
+    pub placeholder: String,
+}
+
+impl RenameTarget {
+    /// Create a target.
+    #[must_use]
+    pub fn new(range: Range, placeholder: impl Into<String>) -> Self {
+        Self {
+            range,
+            placeholder: placeholder.into(),
+        }
+    }
+}
+
+type ValidateFn = Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+/// The helper wiring `textDocument/prepareRename` and `textDocument/rename` together.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone)]
+#[must_use]
+pub struct RenameFlow {
+    prepare_support: bool,
+    validate: Option<ValidateFn>,
+}
+
+impl std::fmt::Debug for RenameFlow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RenameFlow")
+            .field("prepare_support", &self.prepare_support)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RenameFlow {
+    /// Create the helper for a client with capabilities `caps`.
+    pub fn new(caps: &ClientCapabilities) -> Self {
+        let prepare_support = caps
+            .text_document
+            .as_ref()
+            .and_then(|caps| caps.rename.as_ref()?.prepare_support)
+            .unwrap_or(false);
+        Self {
+            prepare_support,
+            validate: None,
+        }
+    }
+
+    /// Validate new names by `f`, which returns an error message for invalid ones, eg. names
+    /// which are not valid identifiers.
+    pub fn validate_name(
+        mut self,
+        f: impl Fn(&str) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.validate = Some(Arc::new(f));
+        self
+    }
+
+    /// Whether the client supports `textDocument/prepareRename`.
+    #[must_use]
+    pub fn prepare_support(&self) -> bool {
+        self.prepare_support
+    }
+
+    /// The server capability `renameProvider`, advertising prepare support only if the client
+    /// supports it.
+    #[must_use]
+    pub fn capability(&self) -> OneOf<bool, RenameOptions> {
+        if self.prepare_support {
+            OneOf::Right(RenameOptions {
+                prepare_provider: Some(true),
+                work_done_progress_options: Default::default(),
+            })
+        } else {
+            OneOf::Left(true)
+        }
+    }
+
+    /// Register handlers of `textDocument/prepareRename` and `textDocument/rename` on `router`.
+    ///
+    /// `target` locates the rename target at a position, or returns `None` if there is nothing to
+    /// rename. `edit` computes the edits of a validated rename. Note that both are called
+    /// synchronously on each rename request, but the future of `edit` is only polled after
+    /// validation, so heavy computation should be done inside it.
+    pub fn register<St, Error, TargetFut, EditFut>(
+        &self,
+        router: &mut Router<St, Error>,
+        target: impl Fn(&mut St, TextDocumentPositionParams) -> TargetFut + Send + Sync + 'static,
+        edit: impl Fn(&mut St, RenameParams) -> EditFut + Send + 'static,
+    ) where
+        Error: From<ResponseError> + Send + 'static,
+        TargetFut: Future<Output = Result<Option<RenameTarget>, Error>> + Send + 'static,
+        EditFut: Future<Output = Result<Option<WorkspaceEdit>, Error>> + Send + 'static,
+    {
+        let target = Arc::new(target);
+        router.request::<PrepareRenameRequest, _>({
+            let target = target.clone();
+            move |st, params| {
+                let fut = target(st, params);
+                async move {
+                    Ok(fut
+                        .await?
+                        .map(|target| PrepareRenameResponse::RangeWithPlaceholder {
+                            range: target.range,
+                            placeholder: target.placeholder,
+                        }))
+                }
+            }
+        });
+        let validate = self.validate.clone();
+        router.request::<Rename, _>(move |st, params| {
+            let uri = params.text_document_position.text_document.uri.clone();
+            let new_name = params.new_name.clone();
+            let target_fut = target(st, params.text_document_position.clone());
+            let edit_fut = edit(st, params);
+            let validate = validate.clone();
+            async move {
+                let target = target_fut.await?.ok_or_else(|| {
+                    ResponseError::new(
+                        ErrorCode::REQUEST_FAILED,
+                        "The element at the position cannot be renamed",
+                    )
+                })?;
+                if new_name.is_empty() {
+                    return Err(
+                        ResponseError::new(ErrorCode::INVALID_PARAMS, "New name is empty").into(),
+                    );
+                }
+                if let Some(validate) = &validate {
+                    validate(&new_name)
+                        .map_err(|msg| ResponseError::new(ErrorCode::INVALID_PARAMS, msg))?;
+                }
+                if new_name == target.placeholder {
+                    return Ok(None);
+                }
+                let edit = edit_fut.await?;
+                if let Some(edit) = &edit {
+                    if !covers(edit, &uri, target.range) {
+                        return Err(ResponseError::new(
+                            ErrorCode::REQUEST_FAILED,
+                            "Rename edits do not cover the renamed range",
+                        )
+                        .into());
+                    }
+                }
+                Ok(edit)
+            }
+        });
+    }
+}
+
+/// Whether `edit` contains a text edit of exactly `range` in document `uri`.
+fn covers(edit: &WorkspaceEdit, uri: &Url, range: Range) -> bool {
+    let in_changes = edit
+        .changes
+        .as_ref()
+        .and_then(|changes| changes.get(uri))
+        .map_or(false, |edits| edits.iter().any(|edit| edit.range == range));
+    let in_document_changes = || {
+        let doc_edits = match &edit.document_changes {
+            Some(DocumentChanges::Edits(edits)) => edits.iter().collect::<Vec<_>>(),
+            Some(DocumentChanges::Operations(ops)) => ops
+                .iter()
+                .filter_map(|op| match op {
+                    DocumentChangeOperation::Edit(edit) => Some(edit),
+                    DocumentChangeOperation::Op(_) => None,
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        doc_edits
+            .into_iter()
+            .filter(|doc_edit| doc_edit.text_document.uri == *uri)
+            .flat_map(|doc_edit| &doc_edit.edits)
+            .any(|edit| match edit {
+                OneOf::Left(edit) => edit.range == range,
+                OneOf::Right(edit) => edit.text_edit.range == range,
+            })
+    };
+    in_changes || in_document_changes()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::future::{poll_fn, ready};
+
+    use lsp_types::request::Request;
+    use lsp_types::{Position, RenameClientCapabilities, TextDocumentClientCapabilities, TextEdit};
+    use serde_json::{json, Value as JsonValue};
+    use tower_service::Service;
+
+    use super::*;
+    use crate::{AnyRequest, RequestId};
+
+    #[test]
+    fn capability() {
+        let flow = RenameFlow::new(&ClientCapabilities::default());
+        assert_eq!(flow.capability(), OneOf::Left(true));
+
+        let caps = ClientCapabilities {
+            text_document: Some(TextDocumentClientCapabilities {
+                rename: Some(RenameClientCapabilities {
+                    prepare_support: Some(true),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(RenameFlow::new(&caps).prepare_support());
+    }
+
+    #[tokio::test]
+    async fn prepare_and_rename() {
+        let range = Range::new(Position::new(0, 4), Position::new(0, 7));
+        // The edit range of the second document is intentionally wrong.
+        let mut router = Router::<(), ResponseError>::new(());
+        RenameFlow::new(&ClientCapabilities::default())
+            .validate_name(|name| match name.contains(' ') {
+                true => Err("Invalid identifier".into()),
+                false => Ok(()),
+            })
+            .register(
+                &mut router,
+                move |_, params| {
+                    let pos = params.position;
+                    ready(Ok((pos.character >= 4 && pos.character < 7)
+                        .then(|| RenameTarget::new(range, "foo"))))
+                },
+                move |_, params| {
+                    let uri = params.text_document_position.text_document.uri;
+                    let range = match uri.path() {
+                        "/good" => range,
+                        _ => Range::default(),
+                    };
+                    let edits = vec![TextEdit::new(range, params.new_name)];
+                    ready(Ok(Some(WorkspaceEdit::new(HashMap::from([(uri, edits)])))))
+                },
+            );
+        poll_fn(|cx| router.poll_ready(cx)).await.unwrap();
+
+        let mut call = |method: &str, uri: &str, character: u32, new_name: &str| {
+            router.call(AnyRequest {
+                id: RequestId::Number(1),
+                method: method.into(),
+                params: json!({
+                    "textDocument": { "uri": uri },
+                    "position": { "line": 0, "character": character },
+                    "newName": new_name,
+                }),
+                receipt: None,
+            })
+        };
+        let prepare = PrepareRenameRequest::METHOD;
+        assert_eq!(
+            call(prepare, "file:///good", 5, "").await.unwrap(),
+            json!({ "range": range, "placeholder": "foo" }),
+        );
+        assert_eq!(
+            call(prepare, "file:///good", 0, "").await.unwrap(),
+            JsonValue::Null
+        );
+
+        let ok = call(Rename::METHOD, "file:///good", 5, "bar")
+            .await
+            .unwrap();
+        assert_eq!(ok["changes"]["file:///good"][0]["newText"], "bar");
+        assert_eq!(
+            call(Rename::METHOD, "file:///good", 5, "foo")
+                .await
+                .unwrap(),
+            JsonValue::Null
+        );
+        for (uri, character, new_name, code) in [
+            ("file:///good", 0, "bar", ErrorCode::REQUEST_FAILED),
+            ("file:///good", 5, "", ErrorCode::INVALID_PARAMS),
+            ("file:///good", 5, "a b", ErrorCode::INVALID_PARAMS),
+            ("file:///bad", 5, "bar", ErrorCode::REQUEST_FAILED),
+        ] {
+            let err = call(Rename::METHOD, uri, character, new_name)
+                .await
+                .unwrap_err();
+            assert_eq!(err.code, code, "{uri} {character} {new_name:?}");
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Replay recorded sessions against services for regression testing.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! [`Replay`] loads a session log, usually written by [`crate::record::JsonLines`], and feeds
+//! recorded incoming requests and notifications to an [`LspService`] in order. Responses of the
+//! service are compared with the recorded outgoing responses of the same request ids, and
+//! differences are collected into a [`ReplayReport`]. This allows turning captured editor sessions
+//! into regression tests.
+//!
+//! Requests are replayed one by one: each is awaited before feeding the next message. Recorded
+//! incoming responses are skipped since they belong to requests sent by the original service,
+//! which the replayed service cannot receive. Services should be built with a closed socket, eg.
+//! [`ClientSocket::new_closed`][crate::ClientSocket::new_closed], so their own requests and
+//! notifications fail instead of reaching anyone.
+//!
+//! ```
+//! # use async_lsp::replay::Replay;
+//! # use async_lsp::router::Router;
+//! # async fn work(log: &[u8]) -> async_lsp::Result<()> {
+//! let mut router = Router::new(());
+//! // Register handlers...
+//! let report = Replay::from_reader(log)?.run(&mut router).await?;
+//! assert!(report.mismatches.is_empty(), "{:?}", report.mismatches);
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::future::poll_fn;
+use std::io::BufRead;
+use std::ops::ControlFlow;
+
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+use crate::record::{Direction, RecordEntry};
+use crate::{
+    Error, LspService, Message, RawMessage, RequestFuture, RequestId, ResponseError, Result,
+};
+
+/// A recorded session to be replayed.
+///
+/// See [module level documentations](self) for details.
+#[must_use]
+pub struct Replay {
+    entries: Vec<RecordEntry>,
+    compare: CompareFn,
+}
+
+type CompareFn = Box<dyn Fn(&JsonValue, &JsonValue) -> bool + Send + Sync>;
+
+impl fmt::Debug for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Replay")
+            .field("entries", &self.entries)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Replay {
+    /// Create a replay of recorded entries.
+    pub fn new(entries: impl IntoIterator<Item = RecordEntry>) -> Self {
+        Self {
+            entries: entries.into_iter().collect(),
+            compare: Box::new(|expected, actual| expected == actual),
+        }
+    }
+
+    /// Load a session log in JSON Lines format written by [`crate::record::JsonLines`]. Empty
+    /// lines are ignored.
+    ///
+    /// # Errors
+    ///
+    /// - `Error::Io` when reading from `reader` fails.
+    /// - `Error::Deserialize` when a line is not a valid [`RecordEntry`].
+    pub fn from_reader(reader: impl BufRead) -> Result<Self> {
+        let mut entries = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                entries.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(Self::new(entries))
+    }
+
+    /// Set the function to check if an actual response matches the expected one. Both are full
+    /// JSON-RPC response messages. Default is the equality.
+    ///
+    /// It is useful to ignore volatile fields, like error messages or timestamps.
+    pub fn compare(
+        mut self,
+        compare: impl Fn(&JsonValue, &JsonValue) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.compare = Box::new(compare);
+        self
+    }
+
+    /// Feed the recorded session to `service`.
+    ///
+    /// It stops early, successfully, if a notification handler breaks the main loop with `Ok`.
+    ///
+    /// # Errors
+    ///
+    /// - `Error::Deserialize` when a recorded incoming message is invalid.
+    /// - `Error::Response` when `service` fails to get ready.
+    /// - Errors returned by notification or event handlers breaking the main loop.
+    pub async fn run<S>(&self, service: &mut S) -> Result<ReplayReport>
+    where
+        S: LspService<Response = JsonValue>,
+        ResponseError: From<S::Error>,
+    {
+        // Keep recorded responses as is, since `null` results do not survive a round trip.
+        let expected = self
+            .messages(Direction::Outgoing)
+            .filter_map(|msg| match RawMessage::<Message>::deserialize(msg) {
+                Ok(RawMessage {
+                    inner: Message::Response(resp),
+                    ..
+                }) => Some((resp.id, msg)),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut report = ReplayReport::default();
+        for msg in self.messages(Direction::Incoming) {
+            match RawMessage::<Message>::deserialize(msg)?.inner {
+                Message::Request(req) => {
+                    report.requests += 1;
+                    poll_fn(|cx| service.poll_ready(cx))
+                        .await
+                        .map_err(|err| Error::Response(err.into()))?;
+                    let (id, method) = (req.id.clone(), req.method.clone());
+                    let fut = RequestFuture::new(service.call(req), id.clone());
+                    let actual =
+                        serde_json::to_value(RawMessage::new(Message::Response(fut.await)))
+                            .expect("Failed to serialize");
+                    // Requests unanswered in the recorded session are not compared.
+                    let expected = match expected.get(&id) {
+                        Some(&resp) => resp.clone(),
+                        None => continue,
+                    };
+                    if !(self.compare)(&expected, &actual) {
+                        report.mismatches.push(Mismatch {
+                            id,
+                            method,
+                            expected,
+                            actual,
+                        });
+                    }
+                }
+                Message::Notification(notif) => {
+                    report.notifications += 1;
+                    match service.notify(notif) {
+                        ControlFlow::Continue(()) => {}
+                        ControlFlow::Break(ret) => return ret.map(|()| report),
+                    }
+                }
+                Message::Response(_) => {}
+            }
+        }
+        Ok(report)
+    }
+
+    /// Iterate recorded messages of `direction`, flattening batches.
+    fn messages(&self, direction: Direction) -> impl Iterator<Item = &JsonValue> + '_ {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.direction == direction)
+            .flat_map(|entry| match &entry.message {
+                JsonValue::Array(msgs) => msgs.iter().collect::<Vec<_>>(),
+                msg => vec![msg],
+            })
+    }
+}
+
+/// The result of [`Replay::run`].
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct ReplayReport {
+    /// The number of replayed requests.
+    pub requests: usize,
+    /// The number of replayed notifications.
+    pub notifications: usize,
+    /// Responses different from the recorded ones, in order of requests.
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// A response different from the recorded one.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Mismatch {
+    /// The request id.
+    pub id: RequestId,
+    /// The request method.
+    pub method: String,
+    /// The recorded response message.
+    pub expected: JsonValue,
+    /// The actual response message.
+    pub actual: JsonValue,
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::notification;
+    use lsp_types::request::{self, Request};
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+
+    #[tokio::test]
+    async fn replay() {
+        let incoming = |msg| RecordEntry::new(Direction::Incoming, msg);
+        let outgoing = |msg| RecordEntry::new(Direction::Outgoing, msg);
+        let log = [
+            incoming(json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} })),
+            incoming(json!([
+                { "jsonrpc": "2.0", "id": 1, "method": "shutdown" },
+                { "jsonrpc": "2.0", "id": 2, "method": "workspace/symbol", "params": {} },
+            ])),
+            outgoing(json!({ "jsonrpc": "2.0", "id": 2, "result": [] })),
+            outgoing(json!({ "jsonrpc": "2.0", "id": 1, "result": null })),
+            incoming(json!({ "jsonrpc": "2.0", "id": 3, "method": "shutdown" })),
+            incoming(json!({ "jsonrpc": "2.0", "method": "exit" })),
+            incoming(json!({ "jsonrpc": "2.0", "id": 4, "method": "shutdown" })),
+        ]
+        .map(|entry| serde_json::to_string(&entry).unwrap())
+        .join("\n");
+
+        let mut router = Router::<(), ResponseError>::new(());
+        router
+            .request::<request::Shutdown, _>(|_, ()| async { Ok(()) })
+            .request::<request::WorkspaceSymbolRequest, _>(|_, _| async { Ok(None) })
+            .notification::<notification::Initialized>(|_, _| ControlFlow::Continue(()))
+            .notification::<notification::Exit>(|_, ()| ControlFlow::Break(Ok(())));
+
+        let replay = Replay::from_reader(log.as_bytes()).unwrap();
+        let report = replay.run(&mut router).await.unwrap();
+        assert_eq!((report.requests, report.notifications), (3, 2));
+        assert_eq!(report.mismatches.len(), 1);
+        let mismatch = &report.mismatches[0];
+        assert_eq!(mismatch.id, RequestId::Number(2));
+        assert_eq!(mismatch.method, request::WorkspaceSymbolRequest::METHOD);
+        assert_eq!(mismatch.actual["result"], JsonValue::Null);
+
+        let replay = Replay::from_reader(log.as_bytes())
+            .unwrap()
+            .compare(|_, _| true);
+        let report = replay.run(&mut router).await.unwrap();
+        assert!(report.mismatches.is_empty());
+    }
+}
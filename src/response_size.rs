@@ -0,0 +1,305 @@
+//! Per-method accounting of response sizes.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! Pathologically large responses, eg. `textDocument/semanticTokens/full` of a huge generated
+//! file, may freeze editors while being parsed. This middleware measures the serialized size of
+//! each successful response, and accumulates statistics per method into a shared
+//! [`ResponseSizes`] handle set via [`ResponseSizeLayer::sizes`].
+//!
+//! Responses exceeding the threshold set via [`ResponseSizeLayer::warn_threshold`] are logged as
+//! warnings if `tracing` is enabled, and counted into the `lsp_oversized_responses_total` counter
+//! with label `method` if `metrics` is enabled.
+//!
+//! ```
+//! # use async_lsp::response_size::{ResponseSizeLayer, ResponseSizes};
+//! let sizes = ResponseSizes::new();
+//! let layer = ResponseSizeLayer::new()
+//!     .sizes(sizes.clone())
+//!     .warn_threshold(10 << 20);
+//! // Later, eg. in a custom command handler.
+//! let stats = sizes.get("textDocument/semanticTokens/full");
+//! ```
+use std::collections::HashMap;
+use std::future::Future;
+use std::ops::ControlFlow;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use pin_project_lite::pin_project;
+use serde_json::Value as JsonValue;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{json_size, AnyEvent, AnyNotification, AnyRequest, EventHints, LspService, Result};
+
+/// Accumulated response sizes of a method.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SizeStats {
+    /// The number of measured responses.
+    pub count: u64,
+    /// The total size of measured responses in bytes.
+    pub total: u64,
+    /// The size of the largest response in bytes.
+    pub max: usize,
+    /// The number of responses exceeding the warning threshold.
+    pub oversized: u64,
+}
+
+/// The shared handle of per-method response size statistics.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone, Default)]
+pub struct ResponseSizes(Arc<Mutex<HashMap<String, SizeStats>>>);
+
+impl ResponseSizes {
+    /// Create an empty handle.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the statistics of `method`, or `None` if no response of it is measured.
+    #[must_use]
+    pub fn get(&self, method: &str) -> Option<SizeStats> {
+        self.0.lock().unwrap().get(method).copied()
+    }
+
+    /// Get the statistics of all measured methods.
+    #[must_use]
+    pub fn all(&self) -> HashMap<String, SizeStats> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Take the statistics of all measured methods, resetting them.
+    #[must_use]
+    pub fn take(&self) -> HashMap<String, SizeStats> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+
+    fn add(&self, method: &str, size: usize, oversized: bool) {
+        let mut map = self.0.lock().unwrap();
+        let stats = match map.get_mut(method) {
+            Some(stats) => stats,
+            None => map.entry(method.to_owned()).or_default(),
+        };
+        stats.count += 1;
+        stats.total += size as u64;
+        stats.max = stats.max.max(size);
+        stats.oversized += u64::from(oversized);
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Config {
+    sizes: Option<ResponseSizes>,
+    threshold: Option<usize>,
+}
+
+/// The middleware accounting response sizes.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+pub struct ResponseSize<S> {
+    service: S,
+    config: Arc<Config>,
+}
+
+define_getters!(impl[S] ResponseSize<S>, service: S);
+
+impl<S: LspService<Response = JsonValue>> Service<AnyRequest> for ResponseSize<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        let cfg = &self.config;
+        let method = (cfg.sizes.is_some() || cfg.threshold.is_some()).then(|| req.method.clone());
+        ResponseFuture {
+            fut: self.service.call(req),
+            method,
+            config: cfg.clone(),
+        }
+    }
+}
+
+impl<S: LspService<Response = JsonValue>> LspService for ResponseSize<S> {
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        self.service.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.service.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+pin_project! {
+    /// The [`Future`] type used by the [`ResponseSize`] middleware.
+    pub struct ResponseFuture<Fut> {
+        #[pin]
+        fut: Fut,
+        // `None` if nothing to do.
+        method: Option<String>,
+        config: Arc<Config>,
+    }
+}
+
+impl<Fut, Error> Future for ResponseFuture<Fut>
+where
+    Fut: Future<Output = Result<JsonValue, Error>>,
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let ret = ready!(this.fut.poll(cx));
+        if let (Ok(value), Some(method)) = (&ret, this.method.take()) {
+            let size = json_size(value);
+            let oversized = this.config.threshold.map_or(false, |limit| size > limit);
+            if oversized {
+                #[cfg(feature = "tracing")]
+                ::tracing::warn!("Response of {method} has {size} bytes, exceeding the threshold");
+                #[cfg(feature = "metrics")]
+                ::metrics::counter!("lsp_oversized_responses_total", "method" => method.clone())
+                    .increment(1);
+            }
+            if let Some(sizes) = &this.config.sizes {
+                sizes.add(&method, size, oversized);
+            }
+        }
+        Poll::Ready(ret)
+    }
+}
+
+/// A [`tower_layer::Layer`] which builds [`ResponseSize`].
+///
+/// By default, it measures nothing. Set [`ResponseSizeLayer::sizes`] or
+/// [`ResponseSizeLayer::warn_threshold`] to enable it.
+#[derive(Clone, Debug, Default)]
+#[must_use]
+pub struct ResponseSizeLayer {
+    config: Config,
+}
+
+impl ResponseSizeLayer {
+    /// Create the layer measuring nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accumulate statistics into `sizes`.
+    pub fn sizes(mut self, sizes: ResponseSizes) -> Self {
+        self.config.sizes = Some(sizes);
+        self
+    }
+
+    /// Warn about responses larger than `bytes`.
+    pub fn warn_threshold(mut self, bytes: usize) -> Self {
+        self.config.threshold = Some(bytes);
+        self
+    }
+}
+
+impl<S> Layer<S> for ResponseSizeLayer {
+    type Service = ResponseSize<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ResponseSize {
+            service: inner,
+            config: Arc::new(self.config.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+
+    use lsp_types::request::{self, Request};
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::{ErrorCode, RequestId, ResponseError};
+
+    #[tokio::test]
+    async fn sizes() {
+        let mut router = Router::<(), ResponseError>::new(());
+        router
+            .request::<request::WorkspaceSymbolRequest, _>(|_, params| async move {
+                let symbols = (0..params.query.len())
+                    .map(|_| lsp_types::WorkspaceSymbol {
+                        name: "x".into(),
+                        kind: lsp_types::SymbolKind::FUNCTION,
+                        tags: None,
+                        container_name: None,
+                        location: lsp_types::OneOf::Right(lsp_types::WorkspaceLocation {
+                            uri: "file:///x".parse().unwrap(),
+                        }),
+                        data: None,
+                    })
+                    .collect();
+                Ok(Some(lsp_types::WorkspaceSymbolResponse::Nested(symbols)))
+            })
+            .request::<request::Shutdown, _>(|_, ()| async {
+                Err(ResponseError::new(
+                    ErrorCode::REQUEST_FAILED,
+                    "x".repeat(100),
+                ))
+            });
+        let sizes = ResponseSizes::new();
+        let mut service = ResponseSizeLayer::new()
+            .sizes(sizes.clone())
+            .warn_threshold(100)
+            .layer(router);
+
+        poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+        let mut call = |method: &str, params: JsonValue| {
+            let req = AnyRequest {
+                id: RequestId::Number(0),
+                method: method.into(),
+                params,
+                receipt: None,
+            };
+            service.call(req)
+        };
+        let small = call(
+            request::WorkspaceSymbolRequest::METHOD,
+            json!({ "query": "" }),
+        );
+        let small_size = json_size(&small.await.unwrap());
+        let large = call(
+            request::WorkspaceSymbolRequest::METHOD,
+            json!({ "query": "xxx" }),
+        );
+        let large_size = json_size(&large.await.unwrap());
+        call(request::Shutdown::METHOD, json!(null))
+            .await
+            .unwrap_err();
+
+        assert!(small_size <= 100 && large_size > 100);
+        assert_eq!(
+            sizes.get(request::WorkspaceSymbolRequest::METHOD),
+            Some(SizeStats {
+                count: 2,
+                total: (small_size + large_size) as u64,
+                max: large_size,
+                oversized: 1,
+            }),
+        );
+        // Errors are not measured.
+        assert_eq!(sizes.get(request::Shutdown::METHOD), None);
+        assert_eq!(sizes.take().len(), 1);
+        assert!(sizes.all().is_empty());
+    }
+}
@@ -1,34 +1,51 @@
 //! Dispatch requests and notifications to individual handlers.
+//!
+//! Handlers are usually registered before the main loop starts. To add or remove them on a
+//! running [`Router`], eg. after reading client capabilities in `initialize`, send updates via a
+//! [`RouterHandle`] from [`Router::handle`].
 use std::any::TypeId;
 use std::collections::HashMap;
+use std::fmt;
 use std::future::{ready, Future};
 use std::ops::ControlFlow;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use lsp_types::notification::Notification;
-use lsp_types::request::Request;
+use futures::channel::mpsc;
+use lsp_types::notification::{self, Notification};
+use lsp_types::request::{self, Request};
+use lsp_types::ServerCapabilities;
+use serde_json::json;
 use tower_service::Service;
 
+use crate::can_handle::CanHandle;
 use crate::{
-    AnyEvent, AnyNotification, AnyRequest, ErrorCode, JsonValue, LspService, ResponseError, Result,
+    AnyEvent, AnyNotification, AnyRequest, ErrorCode, EventHints, JsonValue, LspService,
+    RawResponse, RequestId, ResponseError, ResponseValue, Result,
 };
 
 /// A router dispatching requests and notifications to individual handlers.
-pub struct Router<St, Error = ResponseError> {
+///
+/// Handlers respond [`JsonValue`]s by default. Routers created via [`Router::new_raw`] respond
+/// [`ResponseValue`]s, so that handlers can also respond with pre-serialized results.
+pub struct Router<St, Error = ResponseError, Resp = JsonValue> {
     state: St,
-    req_handlers: HashMap<&'static str, BoxReqHandler<St, Error>>,
+    req_handlers: HashMap<&'static str, BoxReqHandler<St, Error, Resp>>,
     notif_handlers: HashMap<&'static str, BoxNotifHandler<St>>,
-    event_handlers: HashMap<TypeId, BoxEventHandler<St>>,
-    unhandled_req: BoxReqHandler<St, Error>,
+    event_handlers: HashMap<TypeId, (EventHints, BoxEventHandler<St>)>,
+    unhandled_req: BoxReqHandler<St, Error, Resp>,
     unhandled_notif: BoxNotifHandler<St>,
     unhandled_event: BoxEventHandler<St>,
+    update_tx: Option<mpsc::UnboundedSender<Update<St, Error, Resp>>>,
+    update_rx: Option<mpsc::UnboundedReceiver<Update<St, Error, Resp>>>,
 }
 
-type BoxReqFuture<Error> = Pin<Box<dyn Future<Output = Result<JsonValue, Error>> + Send>>;
-type BoxReqHandler<St, Error> = Box<dyn Fn(&mut St, AnyRequest) -> BoxReqFuture<Error> + Send>;
+type BoxReqFuture<Error, Resp> = Pin<Box<dyn Future<Output = Result<Resp, Error>> + Send>>;
+type BoxReqHandler<St, Error, Resp> =
+    Box<dyn Fn(&mut St, AnyRequest) -> BoxReqFuture<Error, Resp> + Send>;
 type BoxNotifHandler<St> = Box<dyn Fn(&mut St, AnyNotification) -> ControlFlow<Result<()>> + Send>;
 type BoxEventHandler<St> = Box<dyn Fn(&mut St, AnyEvent) -> ControlFlow<Result<()>> + Send>;
+type Update<St, Error, Resp> = Box<dyn FnOnce(&mut Router<St, Error, Resp>) + Send>;
 
 impl<St, Error> Default for Router<St, Error>
 where
@@ -40,7 +57,6 @@ where
     }
 }
 
-// TODO: Make it possible to construct with arbitrary `Error`, with no default handlers.
 impl<St, Error> Router<St, Error>
 where
     Error: From<ResponseError> + Send + 'static,
@@ -48,6 +64,29 @@ where
     /// Create a empty `Router`.
     #[must_use]
     pub fn new(state: St) -> Self {
+        Self::with_state(state)
+    }
+}
+
+impl<St, Error> Router<St, Error, ResponseValue>
+where
+    Error: From<ResponseError> + Send + 'static,
+{
+    /// Create a empty `Router` responding [`ResponseValue`]s, whose handlers registered via
+    /// [`Router::request_raw`] can respond with pre-serialized results.
+    #[must_use]
+    pub fn new_raw(state: St) -> Self {
+        Self::with_state(state)
+    }
+}
+
+// TODO: Make it possible to construct with arbitrary `Error`, with no default handlers.
+impl<St, Error, Resp> Router<St, Error, Resp>
+where
+    Error: From<ResponseError> + Send + 'static,
+    Resp: From<JsonValue> + Send + 'static,
+{
+    fn with_state(state: St) -> Self {
         Self {
             state,
             req_handlers: HashMap::new(),
@@ -76,6 +115,8 @@ where
                     "Unhandled event: {event:?}"
                 ))))
             }),
+            update_tx: None,
+            update_rx: None,
         }
     }
 
@@ -86,6 +127,17 @@ where
         &mut self,
         handler: impl Fn(&mut St, R::Params) -> Fut + Send + 'static,
     ) -> &mut Self
+    where
+        Fut: Future<Output = Result<R::Result, Error>> + Send + 'static,
+    {
+        self.request_with_id::<R, _>(move |state, _id, params| handler(state, params))
+    }
+
+    /// Same as [`Router::request`] but the handler also receives the request id.
+    pub(crate) fn request_with_id<R: Request, Fut>(
+        &mut self,
+        handler: impl Fn(&mut St, RequestId, R::Params) -> Fut + Send + 'static,
+    ) -> &mut Self
     where
         Fut: Future<Output = Result<R::Result, Error>> + Send + 'static,
     {
@@ -94,9 +146,10 @@ where
             Box::new(
                 move |state, req| match serde_json::from_value::<R::Params>(req.params) {
                     Ok(params) => {
-                        let fut = handler(state, params);
+                        let fut = handler(state, req.id, params);
                         Box::pin(async move {
-                            Ok(serde_json::to_value(fut.await?).expect("Serialization failed"))
+                            let v = serde_json::to_value(fut.await?).expect("Serialization failed");
+                            Ok(v.into())
                         })
                     }
                     Err(err) => Box::pin(ready(Err(ResponseError {
@@ -111,6 +164,38 @@ where
         self
     }
 
+    /// Same as [`Router::request`] but the handler responds with a pre-serialized
+    /// [`RawResponse`], which is sent to the peer without re-serialization.
+    ///
+    /// It is only available on routers created via [`Router::new_raw`].
+    pub fn request_raw<R: Request, Fut>(
+        &mut self,
+        handler: impl Fn(&mut St, R::Params) -> Fut + Send + 'static,
+    ) -> &mut Self
+    where
+        Fut: Future<Output = Result<RawResponse, Error>> + Send + 'static,
+        Resp: From<RawResponse>,
+    {
+        self.req_handlers.insert(
+            R::METHOD,
+            Box::new(
+                move |state, req| match serde_json::from_value::<R::Params>(req.params) {
+                    Ok(params) => {
+                        let fut = handler(state, params);
+                        Box::pin(async move { Ok(fut.await?.into()) })
+                    }
+                    Err(err) => Box::pin(ready(Err(ResponseError {
+                        code: ErrorCode::INVALID_PARAMS,
+                        message: format!("Failed to deserialize parameters: {err}"),
+                        data: None,
+                    }
+                    .into()))),
+                },
+            ),
+        );
+        self
+    }
+
     /// Add a synchronous request handler for a specific LSP notification `N`.
     ///
     /// If handler for the method already exists, it replaces the old one.
@@ -136,17 +221,66 @@ where
     pub fn event<E: Send + 'static>(
         &mut self,
         handler: impl Fn(&mut St, E) -> ControlFlow<Result<()>> + Send + 'static,
+    ) -> &mut Self {
+        self.event_with_hints(EventHints::default(), handler)
+    }
+
+    /// Add a synchronous event handler for event type `E`, with scheduling `hints` of the main
+    /// loop, eg. to coalesce high-frequency events. See [`EventHints`] for details.
+    ///
+    /// If handler for the method already exists, it replaces the old one.
+    pub fn event_with_hints<E: Send + 'static>(
+        &mut self,
+        hints: EventHints,
+        handler: impl Fn(&mut St, E) -> ControlFlow<Result<()>> + Send + 'static,
     ) -> &mut Self {
         self.event_handlers.insert(
             TypeId::of::<E>(),
-            Box::new(move |state, event| {
-                let event = event.downcast::<E>().expect("Checked TypeId");
-                handler(state, event)
-            }),
+            (
+                hints,
+                Box::new(move |state, event| {
+                    let event = event.downcast::<E>().expect("Checked TypeId");
+                    handler(state, event)
+                }),
+            ),
         );
         self
     }
 
+    /// Remove the request handler for `R`, if any. Requests of it are then passed to the
+    /// catch-all handler set via [`Router::unhandled_request`].
+    pub fn remove_request<R: Request>(&mut self) -> &mut Self {
+        self.req_handlers.remove(R::METHOD);
+        self
+    }
+
+    /// Remove the notification handler for `N`, if any. Notifications of it are then passed to
+    /// the catch-all handler set via [`Router::unhandled_notification`].
+    pub fn remove_notification<N: Notification>(&mut self) -> &mut Self {
+        self.notif_handlers.remove(N::METHOD);
+        self
+    }
+
+    /// Remove the event handler for event type `E`, if any. Events of it are then passed to the
+    /// catch-all handler set via [`Router::unhandled_event`].
+    pub fn remove_event<E: Send + 'static>(&mut self) -> &mut Self {
+        self.event_handlers.remove(&TypeId::of::<E>());
+        self
+    }
+
+    /// Get a handle to update this router while it is running in a main loop.
+    ///
+    /// See [`RouterHandle`] for details.
+    #[must_use]
+    pub fn handle(&mut self) -> RouterHandle<St, Error, Resp> {
+        let tx = self.update_tx.get_or_insert_with(|| {
+            let (tx, rx) = mpsc::unbounded();
+            self.update_rx = Some(rx);
+            tx
+        });
+        RouterHandle { tx: tx.clone() }
+    }
+
     /// Set an asynchronous catch-all request handler for any requests with no corresponding handler
     /// for its `method`.
     ///
@@ -159,7 +293,7 @@ where
         handler: impl Fn(&mut St, AnyRequest) -> Fut + Send + 'static,
     ) -> &mut Self
     where
-        Fut: Future<Output = Result<JsonValue, Error>> + Send + 'static,
+        Fut: Future<Output = Result<Resp, Error>> + Send + 'static,
     {
         self.unhandled_req = Box::new(move |state, req| Box::pin(handler(state, req)));
         self
@@ -198,18 +332,363 @@ where
         self.unhandled_event = Box::new(handler);
         self
     }
+
+    /// Install stub handlers for requests implied by `capabilities` but without a registered
+    /// handler, and return their methods.
+    ///
+    /// This protects against drift between advertised capabilities and implemented handlers,
+    /// which would otherwise respond [`ErrorCode::METHOD_NOT_FOUND`] to the client. Stubs
+    /// respond an empty result, typically `null`, and `*/resolve` stubs return the item
+    /// unchanged. A warning is logged for each stubbed method if feature `tracing` is enabled.
+    ///
+    /// It should be called after all handlers are registered.
+    pub fn stub_unimplemented(&mut self, capabilities: &ServerCapabilities) -> Vec<&'static str> {
+        let mut stubbed = Vec::new();
+        for method in capability_methods(capabilities) {
+            if self.req_handlers.contains_key(method) {
+                continue;
+            }
+            #[cfg(feature = "tracing")]
+            ::tracing::warn!("Capability of {method} is advertised but unimplemented, stubbed");
+            let ret = match method {
+                request::DocumentDiagnosticRequest::METHOD => {
+                    Some(serde_json::json!({ "kind": "full", "items": [] }))
+                }
+                request::WorkspaceDiagnosticRequest::METHOD => {
+                    Some(serde_json::json!({ "items": [] }))
+                }
+                _ if method.ends_with("/resolve") => None,
+                _ => Some(JsonValue::Null),
+            };
+            self.req_handlers.insert(
+                method,
+                Box::new(move |_, req| {
+                    Box::pin(ready(Ok(ret.clone().unwrap_or(req.params).into())))
+                }),
+            );
+            stubbed.push(method);
+        }
+        stubbed
+    }
+
+    /// Suggest [`ServerCapabilities`] advertising all registered request and notification
+    /// handlers, so that `initialize` responses stay in sync with them.
+    ///
+    /// Capabilities requiring information beyond the existence of handlers are left unset, and
+    /// should be filled before responding. They are `documentOnTypeFormattingProvider`,
+    /// `executeCommandProvider`, `semanticTokensProvider`, and `change` of `textDocumentSync`.
+    #[must_use]
+    pub fn suggested_capabilities(&self) -> ServerCapabilities {
+        let handled = |method: &str| self.req_handlers.contains_key(method);
+        let notified = |method: &str| self.notif_handlers.contains_key(method);
+
+        let mut caps = serde_json::Map::new();
+        for (cap, methods) in CAPABILITY_METHODS {
+            if !methods.iter().any(|method| handled(method)) {
+                continue;
+            }
+            let value = match *cap {
+                "documentOnTypeFormattingProvider" | "executeCommandProvider" => continue,
+                "completionProvider"
+                | "signatureHelpProvider"
+                | "codeLensProvider"
+                | "documentLinkProvider" => json!({}),
+                "diagnosticProvider" => json!({
+                    "interFileDependencies": false,
+                    "workspaceDiagnostics": false,
+                }),
+                _ => json!(true),
+            };
+            caps.insert((*cap).into(), value);
+        }
+        for (cap, opt, method) in CAPABILITY_OPTIONS {
+            let value = match caps.get_mut(*cap) {
+                Some(value) if handled(method) => value,
+                _ => continue,
+            };
+            if !value.is_object() {
+                *value = json!({});
+            }
+            value[opt] = json!(true);
+        }
+
+        let mut sync = serde_json::Map::new();
+        let mut set = |key: &str, enabled: bool| {
+            if enabled {
+                sync.insert(key.into(), json!(true));
+            }
+        };
+        set(
+            "openClose",
+            notified(notification::DidOpenTextDocument::METHOD)
+                || notified(notification::DidCloseTextDocument::METHOD),
+        );
+        set(
+            "willSave",
+            notified(notification::WillSaveTextDocument::METHOD),
+        );
+        set(
+            "willSaveWaitUntil",
+            handled(request::WillSaveWaitUntil::METHOD),
+        );
+        set("save", notified(notification::DidSaveTextDocument::METHOD));
+        if !sync.is_empty() {
+            caps.insert("textDocumentSync".into(), sync.into());
+        }
+
+        serde_json::from_value(caps.into()).expect("Invalid capabilities")
+    }
+}
+
+/// Request methods implied by each capability.
+const CAPABILITY_METHODS: &[(&str, &[&str])] = &[
+    ("hoverProvider", &[request::HoverRequest::METHOD]),
+    ("completionProvider", &[request::Completion::METHOD]),
+    (
+        "signatureHelpProvider",
+        &[request::SignatureHelpRequest::METHOD],
+    ),
+    ("declarationProvider", &[request::GotoDeclaration::METHOD]),
+    ("definitionProvider", &[request::GotoDefinition::METHOD]),
+    (
+        "typeDefinitionProvider",
+        &[request::GotoTypeDefinition::METHOD],
+    ),
+    (
+        "implementationProvider",
+        &[request::GotoImplementation::METHOD],
+    ),
+    ("referencesProvider", &[request::References::METHOD]),
+    (
+        "documentHighlightProvider",
+        &[request::DocumentHighlightRequest::METHOD],
+    ),
+    (
+        "documentSymbolProvider",
+        &[request::DocumentSymbolRequest::METHOD],
+    ),
+    ("codeActionProvider", &[request::CodeActionRequest::METHOD]),
+    ("codeLensProvider", &[request::CodeLensRequest::METHOD]),
+    (
+        "documentLinkProvider",
+        &[request::DocumentLinkRequest::METHOD],
+    ),
+    (
+        "colorProvider",
+        &[
+            request::DocumentColor::METHOD,
+            request::ColorPresentationRequest::METHOD,
+        ],
+    ),
+    ("documentFormattingProvider", &[request::Formatting::METHOD]),
+    (
+        "documentRangeFormattingProvider",
+        &[request::RangeFormatting::METHOD],
+    ),
+    (
+        "documentOnTypeFormattingProvider",
+        &[request::OnTypeFormatting::METHOD],
+    ),
+    ("renameProvider", &[request::Rename::METHOD]),
+    (
+        "foldingRangeProvider",
+        &[request::FoldingRangeRequest::METHOD],
+    ),
+    ("executeCommandProvider", &[request::ExecuteCommand::METHOD]),
+    (
+        "selectionRangeProvider",
+        &[request::SelectionRangeRequest::METHOD],
+    ),
+    (
+        "linkedEditingRangeProvider",
+        &[request::LinkedEditingRange::METHOD],
+    ),
+    (
+        "callHierarchyProvider",
+        &[
+            request::CallHierarchyPrepare::METHOD,
+            request::CallHierarchyIncomingCalls::METHOD,
+            request::CallHierarchyOutgoingCalls::METHOD,
+        ],
+    ),
+    ("monikerProvider", &[request::MonikerRequest::METHOD]),
+    (
+        "typeHierarchyProvider",
+        &[
+            request::TypeHierarchyPrepare::METHOD,
+            request::TypeHierarchySupertypes::METHOD,
+            request::TypeHierarchySubtypes::METHOD,
+        ],
+    ),
+    (
+        "inlineValueProvider",
+        &[request::InlineValueRequest::METHOD],
+    ),
+    ("inlayHintProvider", &[request::InlayHintRequest::METHOD]),
+    (
+        "diagnosticProvider",
+        &[request::DocumentDiagnosticRequest::METHOD],
+    ),
+    (
+        "workspaceSymbolProvider",
+        &[request::WorkspaceSymbolRequest::METHOD],
+    ),
+];
+
+/// Request methods implied by a boolean option of each capability.
+const CAPABILITY_OPTIONS: &[(&str, &str, &str)] = &[
+    (
+        "completionProvider",
+        "resolveProvider",
+        request::ResolveCompletionItem::METHOD,
+    ),
+    (
+        "codeActionProvider",
+        "resolveProvider",
+        request::CodeActionResolveRequest::METHOD,
+    ),
+    (
+        "codeLensProvider",
+        "resolveProvider",
+        request::CodeLensResolve::METHOD,
+    ),
+    (
+        "documentLinkProvider",
+        "resolveProvider",
+        request::DocumentLinkResolve::METHOD,
+    ),
+    (
+        "renameProvider",
+        "prepareProvider",
+        request::PrepareRenameRequest::METHOD,
+    ),
+    (
+        "inlayHintProvider",
+        "resolveProvider",
+        request::InlayHintResolveRequest::METHOD,
+    ),
+    (
+        "workspaceSymbolProvider",
+        "resolveProvider",
+        request::WorkspaceSymbolResolve::METHOD,
+    ),
+    (
+        "diagnosticProvider",
+        "workspaceDiagnostics",
+        request::WorkspaceDiagnosticRequest::METHOD,
+    ),
+    (
+        "semanticTokensProvider",
+        "range",
+        request::SemanticTokensRangeRequest::METHOD,
+    ),
+];
+
+/// Get request methods implied by server capabilities.
+fn capability_methods(capabilities: &ServerCapabilities) -> Vec<&'static str> {
+    let caps = serde_json::to_value(capabilities).expect("Serialization failed");
+    let enabled = |v: &JsonValue| !matches!(v, JsonValue::Null | JsonValue::Bool(false));
+
+    let mut methods = Vec::new();
+    for (cap, cap_methods) in CAPABILITY_METHODS {
+        if enabled(&caps[cap]) {
+            methods.extend_from_slice(cap_methods);
+        }
+    }
+    for (cap, opt, method) in CAPABILITY_OPTIONS {
+        if enabled(&caps[cap][opt]) {
+            methods.push(method);
+        }
+    }
+    let tokens = &caps["semanticTokensProvider"]["full"];
+    if enabled(tokens) {
+        methods.push(request::SemanticTokensFullRequest::METHOD);
+        if enabled(&tokens["delta"]) {
+            methods.push(request::SemanticTokensFullDeltaRequest::METHOD);
+        }
+    }
+    methods
+}
+
+impl<St, Error, Resp> Router<St, Error, Resp> {
+    /// Apply updates sent via [`RouterHandle`]s, in order.
+    fn apply_updates(&mut self) {
+        // Taken out, since updates may access the router.
+        let mut rx = match self.update_rx.take() {
+            Some(rx) => rx,
+            None => return,
+        };
+        while let Ok(Some(update)) = rx.try_next() {
+            update(self);
+        }
+        self.update_rx = Some(rx);
+    }
+}
+
+/// The cheaply cloneable handle to update a [`Router`] while it is running in a main loop.
+///
+/// It is created by [`Router::handle`]. Updates are applied in order before the router handles
+/// the next request, notification or event, thus they take effect for all messages received
+/// after sending them. Handlers can send updates too, eg. to register handlers of features
+/// enabled by client capabilities in `initialize`.
+///
+/// ```
+/// # use async_lsp::router::Router;
+/// # use lsp_types::request::HoverRequest;
+/// let mut router: Router<()> = Router::new(());
+/// let handle = router.handle();
+/// // Later, eg. in a handler.
+/// handle
+///     .update(|router| {
+///         router.request::<HoverRequest, _>(|_, _| async { Ok(None) });
+///     })
+///     .unwrap();
+/// ```
+pub struct RouterHandle<St, Error = ResponseError, Resp = JsonValue> {
+    tx: mpsc::UnboundedSender<Update<St, Error, Resp>>,
 }
 
-impl<St, Error> Service<AnyRequest> for Router<St, Error> {
-    type Response = JsonValue;
+impl<St, Error, Resp> Clone for RouterHandle<St, Error, Resp> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<St, Error, Resp> fmt::Debug for RouterHandle<St, Error, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RouterHandle").finish_non_exhaustive()
+    }
+}
+
+impl<St, Error, Resp> RouterHandle<St, Error, Resp> {
+    /// Send an update `f` to be applied to the router.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::ServiceStopped`](crate::Error::ServiceStopped) when the router is dropped.
+    pub fn update(
+        &self,
+        f: impl FnOnce(&mut Router<St, Error, Resp>) + Send + 'static,
+    ) -> Result<()> {
+        self.tx
+            .unbounded_send(Box::new(f))
+            .map_err(|_| crate::Error::ServiceStopped)
+    }
+}
+
+impl<St, Error, Resp> Service<AnyRequest> for Router<St, Error, Resp> {
+    type Response = Resp;
     type Error = Error;
-    type Future = BoxReqFuture<Error>;
+    type Future = BoxReqFuture<Error, Resp>;
 
     fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
     fn call(&mut self, req: AnyRequest) -> Self::Future {
+        self.apply_updates();
         let h = self
             .req_handlers
             .get(&*req.method)
@@ -218,8 +697,9 @@ impl<St, Error> Service<AnyRequest> for Router<St, Error> {
     }
 }
 
-impl<St> LspService for Router<St> {
+impl<St, Resp> LspService for Router<St, ResponseError, Resp> {
     fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        self.apply_updates();
         let h = self
             .notif_handlers
             .get(&*notif.method)
@@ -228,12 +708,44 @@ impl<St> LspService for Router<St> {
     }
 
     fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.apply_updates();
         let h = self
             .event_handlers
             .get(&event.inner_type_id())
-            .unwrap_or(&self.unhandled_event);
+            .map_or(&self.unhandled_event, |(_, h)| h);
         h(&mut self.state, event)
     }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.event_handlers
+            .get(&event.inner_type_id())
+            .map_or_else(EventHints::default, |(hints, _)| *hints)
+    }
+}
+
+/// A request can be handled if it has a registered handler, not counting the catch-all one.
+///
+/// Routers created from omnitraits, eg. via [`Router::from_language_server`], register handlers
+/// of all standard methods thus can handle all of them, even if the default implementation
+/// replies an error. Updates sent via [`RouterHandle`] are not considered until they are applied.
+impl<St, Error, Resp> CanHandle<AnyRequest> for Router<St, Error, Resp> {
+    fn can_handle(&self, req: &AnyRequest) -> bool {
+        self.req_handlers.contains_key(&*req.method)
+    }
+}
+
+/// A notification can be handled if it has a registered handler, not counting the catch-all one.
+impl<St, Error, Resp> CanHandle<AnyNotification> for Router<St, Error, Resp> {
+    fn can_handle(&self, notif: &AnyNotification) -> bool {
+        self.notif_handlers.contains_key(&*notif.method)
+    }
+}
+
+/// An event can be handled if it has a registered handler, not counting the catch-all one.
+impl<St, Error, Resp> CanHandle<AnyEvent> for Router<St, Error, Resp> {
+    fn can_handle(&self, event: &AnyEvent) -> bool {
+        self.event_handlers.contains_key(&event.inner_type_id())
+    }
 }
 
 #[cfg(test)]
@@ -243,4 +755,247 @@ mod tests {
     fn _assert_send<St: Send>(router: Router<St>) -> impl Send {
         router
     }
+
+    #[tokio::test]
+    async fn stub_unimplemented() {
+        use std::future::poll_fn;
+
+        use lsp_types::{CompletionOptions, HoverProviderCapability, OneOf};
+        use serde_json::json;
+
+        let mut router = Router::<()>::new(());
+        router.request::<request::HoverRequest, _>(|_, _| ready(Ok(None)));
+        let caps = ServerCapabilities {
+            hover_provider: Some(HoverProviderCapability::Simple(true)),
+            definition_provider: Some(OneOf::Left(false)),
+            references_provider: Some(OneOf::Left(true)),
+            completion_provider: Some(CompletionOptions {
+                resolve_provider: Some(true),
+                ..CompletionOptions::default()
+            }),
+            ..ServerCapabilities::default()
+        };
+        let mut stubbed = router.stub_unimplemented(&caps);
+        stubbed.sort_unstable();
+        assert_eq!(
+            stubbed,
+            [
+                request::ResolveCompletionItem::METHOD,
+                request::Completion::METHOD,
+                request::References::METHOD,
+            ]
+        );
+
+        poll_fn(|cx| router.poll_ready(cx)).await.unwrap();
+        let ret = router
+            .call(AnyRequest {
+                id: RequestId::Number(1),
+                method: request::ResolveCompletionItem::METHOD.into(),
+                params: json!({ "label": "foo" }),
+                receipt: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(ret, json!({ "label": "foo" }));
+    }
+
+    #[test]
+    fn suggested_capabilities() {
+        use lsp_types::{
+            CompletionOptions, HoverProviderCapability, TextDocumentSyncCapability,
+            TextDocumentSyncOptions,
+        };
+
+        let mut router = Router::<()>::new(());
+        router
+            .request::<request::HoverRequest, _>(|_, _| async { Ok(None) })
+            .request::<request::Completion, _>(|_, _| async { Ok(None) })
+            .request::<request::ResolveCompletionItem, _>(|_, item| async { Ok(item) })
+            .request::<request::PrepareRenameRequest, _>(|_, _| async { Ok(None) })
+            .request::<request::DocumentDiagnosticRequest, _>(|_, _| async { unreachable!() })
+            .request::<request::WorkspaceDiagnosticRequest, _>(|_, _| async { unreachable!() })
+            .notification::<notification::DidOpenTextDocument>(|_, _| ControlFlow::Continue(()))
+            .notification::<notification::DidSaveTextDocument>(|_, _| ControlFlow::Continue(()));
+
+        let caps = router.suggested_capabilities();
+        assert_eq!(
+            caps.hover_provider,
+            Some(HoverProviderCapability::Simple(true))
+        );
+        assert_eq!(
+            caps.completion_provider,
+            Some(CompletionOptions {
+                resolve_provider: Some(true),
+                ..CompletionOptions::default()
+            }),
+        );
+        // Options without the main method are not advertised.
+        assert_eq!(caps.rename_provider, None);
+        assert_eq!(
+            serde_json::to_value(&caps.diagnostic_provider).unwrap(),
+            json!({ "interFileDependencies": false, "workspaceDiagnostics": true }),
+        );
+        assert_eq!(
+            caps.text_document_sync,
+            Some(TextDocumentSyncCapability::Options(
+                TextDocumentSyncOptions {
+                    open_close: Some(true),
+                    save: Some(true.into()),
+                    ..TextDocumentSyncOptions::default()
+                }
+            )),
+        );
+
+        let mut methods = capability_methods(&caps);
+        methods.sort_unstable();
+        assert_eq!(
+            methods,
+            [
+                request::ResolveCompletionItem::METHOD,
+                request::Completion::METHOD,
+                request::DocumentDiagnosticRequest::METHOD,
+                request::HoverRequest::METHOD,
+                request::WorkspaceDiagnosticRequest::METHOD,
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn handle_update() {
+        use lsp_types::notification::Initialized;
+
+        let mut router = Router::<Vec<&'static str>>::new(Vec::new());
+        router.request::<request::Shutdown, _>(|_, ()| async { Ok(()) });
+        let handle = router.handle();
+        handle
+            .update(|router| {
+                router
+                    .remove_request::<request::Shutdown>()
+                    .notification::<Initialized>(|seen, _| {
+                        seen.push(Initialized::METHOD);
+                        ControlFlow::Continue(())
+                    });
+            })
+            .unwrap();
+        // Not applied until the next message.
+        assert!(router.req_handlers.contains_key(request::Shutdown::METHOD));
+
+        let notif = AnyNotification {
+            method: Initialized::METHOD.into(),
+            params: json!({}),
+            receipt: None,
+        };
+        assert!(router.notify(notif).is_continue());
+        assert_eq!(router.state, [Initialized::METHOD]);
+        let err = router
+            .call(AnyRequest {
+                id: RequestId::Number(1),
+                method: request::Shutdown::METHOD.into(),
+                params: JsonValue::Null,
+                receipt: None,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::METHOD_NOT_FOUND);
+
+        drop(router);
+        assert!(matches!(
+            handle.update(|_| {}),
+            Err(crate::Error::ServiceStopped)
+        ));
+    }
+
+    #[tokio::test]
+    async fn unhandled_handlers() {
+        use std::future::poll_fn;
+
+        use serde_json::json;
+
+        let mut router = Router::<Vec<String>>::new(Vec::new());
+        router
+            .unhandled_request(|seen, req| {
+                seen.push(req.method.clone());
+                ready(Ok(json!(req.method)))
+            })
+            .unhandled_notification(|seen, notif| {
+                seen.push(notif.method);
+                ControlFlow::Continue(())
+            })
+            .unhandled_event(|seen, event| {
+                seen.push(event.type_name().into());
+                ControlFlow::Continue(())
+            });
+
+        poll_fn(|cx| router.poll_ready(cx)).await.unwrap();
+        let ret = router
+            .call(AnyRequest {
+                id: RequestId::Number(1),
+                method: "foo/bar".into(),
+                params: JsonValue::Null,
+                receipt: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(ret, json!("foo/bar"));
+        let notif = AnyNotification {
+            method: "foo/baz".into(),
+            params: JsonValue::Null,
+            receipt: None,
+        };
+        assert!(matches!(router.notify(notif), ControlFlow::Continue(())));
+        assert!(matches!(
+            router.emit(AnyEvent::new(42i32)),
+            ControlFlow::Continue(())
+        ));
+        assert_eq!(router.state, ["foo/bar", "foo/baz", "i32"]);
+    }
+
+    #[tokio::test]
+    async fn fallback() {
+        use lsp_types::notification::Initialized;
+
+        use crate::can_handle::Fallback;
+
+        struct Tick;
+
+        let mut first = Router::<&'static str>::new("first");
+        first
+            .request::<request::Shutdown, _>(|st, ()| {
+                assert_eq!(*st, "first");
+                ready(Ok(()))
+            })
+            .event::<Tick>(|st, Tick| {
+                *st = "ticked";
+                ControlFlow::Continue(())
+            });
+        let mut rest = Router::<Vec<String>>::new(Vec::new());
+        rest.unhandled_notification(|seen, notif| {
+            seen.push(notif.method);
+            ControlFlow::Continue(())
+        });
+
+        let shutdown = AnyRequest {
+            id: RequestId::Number(1),
+            method: request::Shutdown::METHOD.into(),
+            params: JsonValue::Null,
+            receipt: None,
+        };
+        let initialized = AnyNotification {
+            method: Initialized::METHOD.into(),
+            params: json!({}),
+            receipt: None,
+        };
+        assert!(first.can_handle(&shutdown));
+        assert!(!first.can_handle(&initialized));
+        assert!(first.can_handle(&AnyEvent::new(Tick)));
+        assert!(!first.can_handle(&AnyEvent::new(42i32)));
+
+        let mut service = Fallback::new(first, rest);
+        assert_eq!(service.call(shutdown).await.unwrap(), JsonValue::Null);
+        assert!(service.notify(initialized).is_continue());
+        assert!(service.emit(AnyEvent::new(Tick)).is_continue());
+        let (first, rest) = service.into_inner();
+        assert_eq!(first.state, "ticked");
+        assert_eq!(rest.state, [Initialized::METHOD]);
+    }
 }
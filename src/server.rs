@@ -8,9 +8,23 @@
 //! - Exit the main loop with `ControlFlow::Break(Ok(()))` on `exit` notification.
 //! - Responds unrelated requests with errors and ignore unrelated notifications during
 //!   initialization and shutting down.
+//!
+//! By default, a server is done after `exit`. With [`Lifecycle::restartable`], the state is reset
+//! to uninitialized on `exit` instead, so that the same service can serve another session, eg.
+//! when an editor reconnects to a socket-based server via [`MainLoop::run_session`]. Use
+//! [`Lifecycle::on_restart`] to reset states of the inner service as well.
+//!
+//! Requests rejected and notifications discarded after `shutdown` can be recorded in a
+//! [`ShutdownLog`] via [`Lifecycle::shutdown_log`], which helps diagnosing flaky editor
+//! disconnects after the main loop exits. They are also logged at debug level if feature
+//! `tracing` is enabled.
+//!
+//! [`MainLoop::run_session`]: crate::MainLoop::run_session
+use std::fmt;
 use std::future::{ready, Future, Ready};
 use std::ops::ControlFlow;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
 
 use futures::future::Either;
@@ -20,8 +34,10 @@ use pin_project_lite::pin_project;
 use tower_layer::Layer;
 use tower_service::Service;
 
+use crate::status::StatusReporter;
 use crate::{
-    AnyEvent, AnyNotification, AnyRequest, Error, ErrorCode, LspService, ResponseError, Result,
+    AnyEvent, AnyNotification, AnyRequest, Error, ErrorCode, EventHints, LspService, RequestId,
+    ResponseError, Result,
 };
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
@@ -33,13 +49,65 @@ enum State {
     ShuttingDown,
 }
 
+/// Messages dropped by [`Lifecycle`] after `shutdown`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DroppedMessages {
+    /// Ids and methods of requests rejected with [`ErrorCode::INVALID_REQUEST`].
+    pub requests: Vec<(RequestId, String)>,
+    /// Methods of notifications discarded.
+    pub notifications: Vec<String>,
+}
+
+/// The cheaply cloneable record of messages dropped by [`Lifecycle`] after `shutdown`.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownLog(Arc<Mutex<DroppedMessages>>);
+
+impl ShutdownLog {
+    /// Create an empty log.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get a copy of messages recorded so far.
+    #[must_use]
+    pub fn get(&self) -> DroppedMessages {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Take messages recorded so far, leaving the log empty.
+    #[must_use]
+    pub fn take(&self) -> DroppedMessages {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+type RestartFn<S> = Box<dyn FnMut(&mut S) + Send>;
+
 /// The middleware handling Language Server lifecycle.
 ///
 /// See [module level documentations](self) for details.
-#[derive(Debug, Default)]
+#[derive(Default)]
 pub struct Lifecycle<S> {
     service: S,
     state: State,
+    restartable: bool,
+    on_restart: Option<RestartFn<S>>,
+    log: Option<ShutdownLog>,
+    status: Option<StatusReporter>,
+}
+
+impl<S: fmt::Debug> fmt::Debug for Lifecycle<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lifecycle")
+            .field("service", &self.service)
+            .field("state", &self.state)
+            .field("restartable", &self.restartable)
+            .finish_non_exhaustive()
+    }
 }
 
 define_getters!(impl[S] Lifecycle<S>, service: S);
@@ -51,8 +119,46 @@ impl<S> Lifecycle<S> {
         Self {
             service,
             state: State::Uninitialized,
+            restartable: false,
+            on_restart: None,
+            log: None,
+            status: None,
         }
     }
+
+    /// Record messages dropped after `shutdown` into `log`.
+    #[must_use]
+    pub fn shutdown_log(mut self, log: ShutdownLog) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    /// Mark the server ready in `status` on `initialized`, after the inner service handles it.
+    /// See [`status`](crate::status) for details.
+    #[must_use]
+    pub fn status(mut self, status: StatusReporter) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Reset to uninitialized state on `exit`, so that the service can be reused for another
+    /// session.
+    ///
+    /// The main loop of the session still exits with `ControlFlow::Break(Ok(()))`.
+    #[must_use]
+    pub fn restartable(mut self) -> Self {
+        self.restartable = true;
+        self
+    }
+
+    /// Reset to uninitialized state on `exit` like [`Lifecycle::restartable`], and call `f` on
+    /// the inner service to reset its states.
+    #[must_use]
+    pub fn on_restart(mut self, f: impl FnMut(&mut S) + Send + 'static) -> Self {
+        self.restartable = true;
+        self.on_restart = Some(Box::new(f));
+        self
+    }
 }
 
 impl<S: LspService> Service<AnyRequest> for Lifecycle<S>
@@ -93,12 +199,20 @@ where
                 }
                 Either::Left(self.service.call(req))
             }
-            (State::ShuttingDown, _) => Either::Right(ready(Err(ResponseError {
-                code: ErrorCode::INVALID_REQUEST,
-                message: "Server is shutting down".into(),
-                data: None,
+            (State::ShuttingDown, _) => {
+                #[cfg(feature = "tracing")]
+                ::tracing::debug!(id = ?req.id, method = req.method, "Rejected request after shutdown");
+                if let Some(log) = &self.log {
+                    let mut dropped = log.0.lock().unwrap();
+                    dropped.requests.push((req.id, req.method));
+                }
+                Either::Right(ready(Err(ResponseError {
+                    code: ErrorCode::INVALID_REQUEST,
+                    message: "Server is shutting down".into(),
+                    data: None,
+                }
+                .into())))
             }
-            .into()))),
         };
         ResponseFuture { inner }
     }
@@ -112,6 +226,15 @@ where
         match &*notif.method {
             notification::Exit::METHOD => {
                 self.service.notify(notif)?;
+                if self.restartable {
+                    self.state = State::Uninitialized;
+                    if let Some(status) = &self.status {
+                        status.reset();
+                    }
+                    if let Some(f) = &mut self.on_restart {
+                        f(&mut self.service);
+                    }
+                }
                 ControlFlow::Break(Ok(()))
             }
             notification::Initialized::METHOD => {
@@ -123,6 +246,21 @@ where
                 }
                 self.state = State::Ready;
                 self.service.notify(notif)?;
+                if let Some(status) = &self.status {
+                    status.set_ready(true);
+                }
+                ControlFlow::Continue(())
+            }
+            _ if self.state == State::ShuttingDown => {
+                #[cfg(feature = "tracing")]
+                ::tracing::debug!(
+                    method = notif.method,
+                    "Discarded notification after shutdown"
+                );
+                if let Some(log) = &self.log {
+                    let mut dropped = log.0.lock().unwrap();
+                    dropped.notifications.push(notif.method);
+                }
                 ControlFlow::Continue(())
             }
             _ => self.service.notify(notif),
@@ -132,6 +270,10 @@ where
     fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
         self.service.emit(event)
     }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
 }
 
 pin_project! {
@@ -154,13 +296,135 @@ impl<Fut: Future> Future for ResponseFuture<Fut> {
 #[must_use]
 #[derive(Clone, Default)]
 pub struct LifecycleLayer {
-    _private: (),
+    restartable: bool,
+}
+
+impl LifecycleLayer {
+    /// Build restartable [`Lifecycle`]s. See [`Lifecycle::restartable`].
+    pub fn restartable(mut self) -> Self {
+        self.restartable = true;
+        self
+    }
 }
 
 impl<S> Layer<S> for LifecycleLayer {
     type Service = Lifecycle<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        Lifecycle::new(inner)
+        let mut this = Lifecycle::new(inner);
+        this.restartable = self.restartable;
+        this
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use lsp_types::InitializeResult;
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::status::ServerState;
+    use crate::RequestId;
+
+    #[tokio::test]
+    async fn restart() {
+        let restarts = Arc::new(AtomicUsize::new(0));
+        let mut router = Router::new(());
+        router
+            .request::<request::Initialize, _>(|_, _| ready(Ok(InitializeResult::default())))
+            .request::<request::Shutdown, _>(|_, ()| ready(Ok(())))
+            .notification::<notification::Initialized>(|_, _| ControlFlow::Continue(()))
+            .notification::<notification::Exit>(|_, ()| ControlFlow::Continue(()));
+        let hook = restarts.clone();
+        let status = StatusReporter::new(crate::ClientSocket::new_closed());
+        let mut service = Lifecycle::new(router)
+            .status(status.clone())
+            .on_restart(move |_| {
+                hook.fetch_add(1, Ordering::Relaxed);
+            });
+
+        let req = |method: &str, params| AnyRequest {
+            id: RequestId::Number(0),
+            method: method.into(),
+            params,
+            receipt: None,
+        };
+        let notif = |method: &str, params| AnyNotification {
+            method: method.into(),
+            params,
+            receipt: None,
+        };
+        for _ in 0..2 {
+            let init = req(request::Initialize::METHOD, json!({ "capabilities": {} }));
+            service.call(init).await.unwrap();
+            assert!(service
+                .notify(notif(notification::Initialized::METHOD, json!({})))
+                .is_continue());
+            assert_eq!(status.state(), ServerState::Ready);
+            service
+                .call(req(request::Shutdown::METHOD, json!(null)))
+                .await
+                .unwrap();
+            let ret = service.notify(notif(notification::Exit::METHOD, json!(null)));
+            assert!(matches!(ret, ControlFlow::Break(Ok(()))));
+            assert_eq!(status.state(), ServerState::Starting);
+        }
+        assert_eq!(restarts.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn shutdown_log() {
+        let mut router = Router::new(());
+        router
+            .request::<request::Initialize, _>(|_, _| ready(Ok(InitializeResult::default())))
+            .request::<request::Shutdown, _>(|_, ()| ready(Ok(())))
+            .notification::<notification::Initialized>(|_, _| ControlFlow::Continue(()))
+            .notification::<notification::DidSaveTextDocument>(|_, _| {
+                panic!("Should be discarded")
+            });
+        let log = ShutdownLog::new();
+        let mut service = Lifecycle::new(router).shutdown_log(log.clone());
+
+        let req = |id, method: &str, params| AnyRequest {
+            id: RequestId::Number(id),
+            method: method.into(),
+            params,
+            receipt: None,
+        };
+        let init = req(
+            0,
+            request::Initialize::METHOD,
+            json!({ "capabilities": {} }),
+        );
+        service.call(init).await.unwrap();
+        let _ = service.notify(AnyNotification {
+            method: notification::Initialized::METHOD.into(),
+            params: json!({}),
+            receipt: None,
+        });
+        let shutdown = req(1, request::Shutdown::METHOD, json!(null));
+        service.call(shutdown).await.unwrap();
+        assert_eq!(log.get(), DroppedMessages::default());
+
+        let err = service.call(req(2, "foo", json!(null))).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::INVALID_REQUEST);
+        let ret = service.notify(AnyNotification {
+            method: notification::DidSaveTextDocument::METHOD.into(),
+            params: json!(null),
+            receipt: None,
+        });
+        assert!(ret.is_continue());
+
+        let dropped = log.take();
+        assert_eq!(dropped.requests, [(RequestId::Number(2), "foo".into())]);
+        assert_eq!(
+            dropped.notifications,
+            [notification::DidSaveTextDocument::METHOD]
+        );
+        assert_eq!(log.get(), DroppedMessages::default());
     }
 }
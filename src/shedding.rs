@@ -0,0 +1,276 @@
+//! Shedding and coalescing of incoming notification floods.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! Massive operations like `git checkout` may make the editor send thousands of notifications,
+//! eg. `textDocument/didChange`, faster than they can be processed. A [`SheddingPolicy`] set via
+//! [`MainLoop::shedding`][crate::MainLoop::shedding] makes the main loop read ahead messages
+//! which are already available, and once the queue of incoming messages waiting to be dispatched
+//! reaches the threshold, newly arrived notifications of configured methods are:
+//!
+//! - Shed, ie. dropped entirely, eg. for informational notifications.
+//! - Or coalesced with the previous queued one of the same method and document, keeping only the
+//!   latest. For `textDocument/didChange`, content changes of both are merged into one
+//!   notification, starting from the last full-text change if any, so the document content is
+//!   still correct.
+//!
+//! The coalesced notification takes the place of the latest one in the queue, and keeps its
+//! [`Receipt`](crate::Receipt). Notifications are only coalesced if no other message of the same
+//! document is queued in between, so requests never observe a document out of order.
+//!
+//! This trades the strict completeness of notifications for responsiveness. Only notifications
+//! whose loss is tolerable by the service should be configured.
+//!
+//! ```
+//! # use async_lsp::shedding::SheddingPolicy;
+//! # use async_lsp::lsp_types::notification::{DidChangeTextDocument, DidSaveTextDocument};
+//! let policy = SheddingPolicy::new(64)
+//!     .coalesce::<DidChangeTextDocument>()
+//!     .coalesce::<DidSaveTextDocument>();
+//! ```
+use std::collections::{HashSet, VecDeque};
+
+use lsp_types::notification::{DidChangeTextDocument, Notification};
+use lsp_types::DidChangeTextDocumentParams;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+use crate::{AnyNotification, Message};
+
+/// The policy shedding or coalescing incoming notifications when the main loop falls behind.
+///
+/// By default, no notifications are shed or coalesced, and at most 4 times the threshold
+/// messages are read ahead.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct SheddingPolicy {
+    threshold: usize,
+    pub(crate) read_ahead: usize,
+    shed: HashSet<String>,
+    coalesce: HashSet<String>,
+}
+
+impl SheddingPolicy {
+    /// Create a policy taking effect when `threshold` messages are queued.
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            read_ahead: threshold.saturating_mul(4),
+            shed: HashSet::new(),
+            coalesce: HashSet::new(),
+        }
+    }
+
+    /// Set the maximum number of queued messages when reading ahead, which is also bounded by
+    /// [`MainLoop::max_pending`](crate::MainLoop::max_pending).
+    pub fn read_ahead(mut self, messages: usize) -> Self {
+        self.read_ahead = messages;
+        self
+    }
+
+    /// Drop notifications of `N` when falling behind.
+    pub fn shed<N: Notification>(self) -> Self {
+        self.shed_method(N::METHOD)
+    }
+
+    /// Same as [`SheddingPolicy::shed`] but with a method name, eg. of a custom notification.
+    pub fn shed_method(mut self, method: impl Into<String>) -> Self {
+        self.shed.insert(method.into());
+        self
+    }
+
+    /// Keep only the latest notification of `N` for each document when falling behind. The
+    /// document is identified by `params.textDocument.uri`.
+    pub fn coalesce<N: Notification>(self) -> Self {
+        self.coalesce_method(N::METHOD)
+    }
+
+    /// Same as [`SheddingPolicy::coalesce`] but with a method name, eg. of a custom notification.
+    pub fn coalesce_method(mut self, method: impl Into<String>) -> Self {
+        self.coalesce.insert(method.into());
+        self
+    }
+
+    /// Queue an incoming message, shedding or coalescing it if the queue is too long.
+    pub(crate) fn push(&self, pending: &mut VecDeque<Message>, msg: Message) {
+        let mut notif = match msg {
+            Message::Notification(notif) if pending.len() >= self.threshold => notif,
+            msg => return pending.push_back(msg),
+        };
+        if self.shed.contains(&notif.method) {
+            #[cfg(feature = "tracing")]
+            ::tracing::debug!(method = notif.method, "notification shed");
+            return;
+        }
+        if self.coalesce.contains(&notif.method) {
+            if let Some(uri) = document_uri(&notif.params) {
+                // The last queued message of the same document.
+                let prev = pending.iter().rposition(|msg| {
+                    let params = match msg {
+                        Message::Request(req) => &req.params,
+                        Message::Notification(notif) => &notif.params,
+                        Message::Response(_) => return false,
+                    };
+                    document_uri(params) == Some(uri)
+                });
+                let prev = prev.filter(|&pos| {
+                    matches!(&pending[pos], Message::Notification(prev) if prev.method == notif.method)
+                });
+                if let Some(pos) = prev {
+                    let prev = match pending.remove(pos) {
+                        Some(Message::Notification(prev)) => prev,
+                        _ => unreachable!(),
+                    };
+                    match merge(&prev, notif.params) {
+                        Ok(params) => {
+                            #[cfg(feature = "tracing")]
+                            ::tracing::debug!(method = notif.method, "notification coalesced");
+                            notif.params = params;
+                        }
+                        Err(params) => {
+                            pending.insert(pos, Message::Notification(prev));
+                            notif.params = params;
+                        }
+                    }
+                }
+            }
+        }
+        pending.push_back(Message::Notification(notif));
+    }
+}
+
+fn document_uri(params: &JsonValue) -> Option<&str> {
+    params.get("textDocument")?.get("uri")?.as_str()
+}
+
+/// Merge parameters of `prev` into the next ones. Returns the next ones back if they cannot be
+/// merged.
+fn merge(prev: &AnyNotification, next: JsonValue) -> Result<JsonValue, JsonValue> {
+    if prev.method != DidChangeTextDocument::METHOD {
+        return Ok(next);
+    }
+    let parse = |params: &JsonValue| DidChangeTextDocumentParams::deserialize(params).ok();
+    let (prev, mut params) = match (parse(&prev.params), parse(&next)) {
+        (Some(prev), Some(params)) => (prev, params),
+        _ => return Err(next),
+    };
+    let mut changes = prev.content_changes;
+    changes.append(&mut params.content_changes);
+    // Changes before the last full-text one are superseded.
+    if let Some(pos) = changes.iter().rposition(|change| change.range.is_none()) {
+        changes.drain(..pos);
+    }
+    params.content_changes = changes;
+    Ok(serde_json::to_value(params).expect("Failed to serialize"))
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::notification::{DidSaveTextDocument, LogMessage};
+    use serde_json::json;
+
+    use super::*;
+    use crate::{AnyRequest, RequestId};
+
+    fn notif(method: &str, params: JsonValue) -> Message {
+        Message::Notification(AnyNotification {
+            method: method.into(),
+            params,
+            receipt: None,
+        })
+    }
+
+    fn did_change(uri: &str, version: i32, changes: &[(bool, &str)]) -> Message {
+        let changes = changes
+            .iter()
+            .map(|&(full, text)| match full {
+                true => json!({ "text": text }),
+                false => json!({
+                    "range": {
+                        "start": { "line": 0, "character": 0 },
+                        "end": { "line": 0, "character": 0 },
+                    },
+                    "text": text,
+                }),
+            })
+            .collect::<Vec<_>>();
+        notif(
+            DidChangeTextDocument::METHOD,
+            json!({
+                "textDocument": { "uri": uri, "version": version },
+                "contentChanges": changes,
+            }),
+        )
+    }
+
+    fn texts(msg: &Message) -> (i64, Vec<&str>) {
+        let params = match msg {
+            Message::Notification(notif) => &notif.params,
+            _ => panic!("unexpected message"),
+        };
+        let texts = params["contentChanges"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|change| change["text"].as_str().unwrap())
+            .collect();
+        (params["textDocument"]["version"].as_i64().unwrap(), texts)
+    }
+
+    #[test]
+    fn coalesce() {
+        let policy = SheddingPolicy::new(1)
+            .shed::<LogMessage>()
+            .coalesce::<DidChangeTextDocument>()
+            .coalesce::<DidSaveTextDocument>();
+        let mut pending = VecDeque::new();
+        let save = |uri: &str, text: &str| {
+            notif(
+                DidSaveTextDocument::METHOD,
+                json!({ "textDocument": { "uri": uri }, "text": text }),
+            )
+        };
+
+        // Under the threshold.
+        policy.push(&mut pending, notif(LogMessage::METHOD, json!({})));
+        policy.push(&mut pending, notif(LogMessage::METHOD, json!({})));
+        assert_eq!(pending.len(), 1);
+
+        pending.clear();
+        policy.push(&mut pending, did_change("file:///a", 1, &[(false, "1")]));
+        policy.push(&mut pending, did_change("file:///b", 1, &[(false, "b")]));
+        policy.push(&mut pending, did_change("file:///a", 2, &[(false, "2")]));
+        policy.push(&mut pending, save("file:///b", "x"));
+        policy.push(&mut pending, save("file:///b", "y"));
+        assert_eq!(pending.len(), 3);
+        assert_eq!(texts(&pending[0]), (1, vec!["b"]));
+        assert_eq!(texts(&pending[1]), (2, vec!["1", "2"]));
+        match &pending[2] {
+            Message::Notification(notif) => assert_eq!(notif.params["text"], "y"),
+            _ => panic!("unexpected message"),
+        }
+
+        // Full-text changes supersede previous ones.
+        policy.push(
+            &mut pending,
+            did_change("file:///a", 3, &[(true, "3"), (false, "4")]),
+        );
+        policy.push(&mut pending, did_change("file:///a", 4, &[(false, "5")]));
+        assert_eq!(pending.len(), 3);
+        assert_eq!(texts(&pending[2]), (4, vec!["3", "4", "5"]));
+
+        // Not across other messages of the same document.
+        let hover = AnyRequest {
+            id: RequestId::Number(1),
+            method: "textDocument/hover".into(),
+            params: json!({ "textDocument": { "uri": "file:///a" } }),
+            receipt: None,
+        };
+        pending.push_back(Message::Request(hover));
+        policy.push(&mut pending, did_change("file:///a", 5, &[(false, "6")]));
+        assert_eq!(pending.len(), 5);
+        assert_eq!(texts(&pending[4]), (5, vec!["6"]));
+    }
+}
@@ -0,0 +1,206 @@
+//! Signature help session tracking.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Signature help is stateful from the user's perspective: once triggered, eg. by typing `(`, it
+//! stays visible while typing arguments, and the client re-requests it with
+//! [`is_retrigger`](lsp_types::SignatureHelpContext::is_retrigger) set on each retrigger character
+//! or content change. The user may also have cycled through overloads, which is reported back via
+//! [`active_signature_help`](lsp_types::SignatureHelpContext::active_signature_help). Servers
+//! recomputing signature help from scratch on each request tend to reset the selected overload on
+//! every keystroke.
+//!
+//! [`SignatureHelpSession`] keeps the active signature help per document, and post-processes
+//! freshly computed results via [`SignatureHelpSession::resolve`]:
+//! - On retriggers, the previously active signature is kept if the set of signatures is unchanged,
+//!   while the active parameter is always taken from the fresh result.
+//! - An empty or `None` result ends the session of the document.
+//! - Out of range active signatures are reset to the first one.
+//!
+//! ```
+//! # use async_lsp::signature_help::SignatureHelpSession;
+//! # use async_lsp::lsp_types::{SignatureHelp, SignatureHelpParams};
+//! # fn compute(params: &SignatureHelpParams) -> Option<SignatureHelp> { None }
+//! fn signature_help(
+//!     session: &SignatureHelpSession,
+//!     params: SignatureHelpParams,
+//! ) -> Option<SignatureHelp> {
+//!     let help = compute(&params);
+//!     session.resolve(&params, help)
+//! }
+//! ```
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use lsp_types::{SignatureHelp, SignatureHelpParams, Url};
+
+/// The active signature help of each document.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Default)]
+pub struct SignatureHelpSession {
+    sessions: Mutex<HashMap<Url, SignatureHelp>>,
+}
+
+impl SignatureHelpSession {
+    /// Create an empty session tracker.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the signature help being shown for a retrigger request, or `None` for a fresh trigger.
+    ///
+    /// The one reported by the client is preferred, since it reflects the overload selected by
+    /// the user.
+    #[must_use]
+    pub fn previous(&self, params: &SignatureHelpParams) -> Option<SignatureHelp> {
+        let ctx = params.context.as_ref().filter(|ctx| ctx.is_retrigger)?;
+        ctx.active_signature_help.clone().or_else(|| {
+            let uri = &params.text_document_position_params.text_document.uri;
+            self.sessions.lock().unwrap().get(uri).cloned()
+        })
+    }
+
+    /// Post-process the freshly computed `help` for the request `params`, and record it as the
+    /// active signature help of the document.
+    #[must_use]
+    pub fn resolve(
+        &self,
+        params: &SignatureHelpParams,
+        help: Option<SignatureHelp>,
+    ) -> Option<SignatureHelp> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        let mut help = match help {
+            Some(help) if !help.signatures.is_empty() => help,
+            _ => {
+                self.remove(uri);
+                return None;
+            }
+        };
+        if let Some(prev) = self.previous(params) {
+            if same_signatures(&prev, &help) {
+                help.active_signature = prev.active_signature;
+            }
+        }
+        if help
+            .active_signature
+            .map_or(false, |idx| idx as usize >= help.signatures.len())
+        {
+            help.active_signature = Some(0);
+        }
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(uri.clone(), help.clone());
+        Some(help)
+    }
+
+    /// Whether there is an active signature help in a document.
+    #[must_use]
+    pub fn is_active(&self, uri: &Url) -> bool {
+        self.sessions.lock().unwrap().contains_key(uri)
+    }
+
+    /// End the session of a document, eg. when it is closed.
+    pub fn remove(&self, uri: &Url) {
+        self.sessions.lock().unwrap().remove(uri);
+    }
+}
+
+/// Whether both contain the same list of signatures, ignoring active indices.
+fn same_signatures(lhs: &SignatureHelp, rhs: &SignatureHelp) -> bool {
+    lhs.signatures.len() == rhs.signatures.len()
+        && lhs
+            .signatures
+            .iter()
+            .zip(&rhs.signatures)
+            .all(|(lhs, rhs)| lhs.label == rhs.label)
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::{
+        Position, SignatureHelpContext, SignatureHelpTriggerKind, SignatureInformation,
+        TextDocumentIdentifier, TextDocumentPositionParams,
+    };
+
+    use super::*;
+
+    fn help(labels: &[&str], active_signature: u32, active_parameter: u32) -> SignatureHelp {
+        SignatureHelp {
+            signatures: labels
+                .iter()
+                .map(|label| SignatureInformation {
+                    label: (*label).into(),
+                    documentation: None,
+                    parameters: None,
+                    active_parameter: None,
+                })
+                .collect(),
+            active_signature: Some(active_signature),
+            active_parameter: Some(active_parameter),
+        }
+    }
+
+    fn params(context: Option<SignatureHelpContext>) -> SignatureHelpParams {
+        SignatureHelpParams {
+            context,
+            text_document_position_params: TextDocumentPositionParams::new(
+                TextDocumentIdentifier::new(Url::parse("file:///foo.rs").unwrap()),
+                Position::new(0, 0),
+            ),
+            work_done_progress_params: Default::default(),
+        }
+    }
+
+    fn retrigger(shown: Option<SignatureHelp>) -> Option<SignatureHelpContext> {
+        Some(SignatureHelpContext {
+            trigger_kind: SignatureHelpTriggerKind::TRIGGER_CHARACTER,
+            trigger_character: Some(",".into()),
+            is_retrigger: true,
+            active_signature_help: shown,
+        })
+    }
+
+    #[test]
+    fn keep_selected_overload() {
+        let session = SignatureHelpSession::new();
+        let uri = params(None).text_document_position_params.text_document.uri;
+        let overloads = ["f(a)", "f(a, b)"];
+
+        let got = session.resolve(&params(None), Some(help(&overloads, 0, 0)));
+        assert_eq!(got, Some(help(&overloads, 0, 0)));
+        assert!(session.is_active(&uri));
+
+        // The user selects the second overload, then types a comma.
+        let shown = help(&overloads, 1, 0);
+        let got = session.resolve(
+            &params(retrigger(Some(shown))),
+            Some(help(&overloads, 0, 1)),
+        );
+        assert_eq!(got, Some(help(&overloads, 1, 1)));
+
+        // Falls back to the recorded one if the client does not report it.
+        let got = session.resolve(&params(retrigger(None)), Some(help(&overloads, 0, 1)));
+        assert_eq!(got, Some(help(&overloads, 1, 1)));
+
+        // A fresh trigger resets the selection.
+        let got = session.resolve(&params(None), Some(help(&overloads, 0, 0)));
+        assert_eq!(got, Some(help(&overloads, 0, 0)));
+
+        // Different signatures, eg. in a nested call.
+        let got = session.resolve(
+            &params(retrigger(Some(help(&overloads, 1, 0)))),
+            Some(help(&["g()"], 0, 0)),
+        );
+        assert_eq!(got, Some(help(&["g()"], 0, 0)));
+
+        // Out of range.
+        let got = session.resolve(&params(None), Some(help(&["g()"], 3, 0)));
+        assert_eq!(got, Some(help(&["g()"], 0, 0)));
+
+        assert_eq!(session.resolve(&params(retrigger(None)), None), None);
+        assert!(!session.is_active(&uri));
+    }
+}
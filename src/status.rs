@@ -0,0 +1,344 @@
+//! Server status reporting.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! Editor plugins commonly show whether their Language Server is starting, busy or broken, but
+//! LSP has no standard way to tell. This module defines a small protocol, the custom
+//! [`ServerStatus`] notification `asyncLsp/serverStatus` carrying a [`ServerState`]:
+//!
+//! - [`ServerState::Starting`] before the server is initialized.
+//! - [`ServerState::Indexing`] while background work is in progress.
+//! - [`ServerState::Ready`] otherwise.
+//! - [`ServerState::Degraded`] when the server works with reduced functionality, which overrides
+//!   the other states except [`ServerState::Starting`].
+//!
+//! On the server side, [`StatusReporter`] is a cheaply cloneable handle which sends the
+//! notification whenever the derived state changes. It can be driven automatically:
+//!
+//! - By [`Lifecycle::status`](crate::server::Lifecycle::status), which marks the server ready on
+//!   `initialized`, and resets it silently on restart.
+//! - By [`Jobs::set_status`](crate::jobs::Jobs::set_status), which reports jobs spawned via
+//!   [`Jobs::spawn_with_progress`](crate::jobs::Jobs::spawn_with_progress) as indexing.
+//!
+//! Degradation is set manually via [`StatusReporter::degrade`] and [`StatusReporter::recover`].
+//!
+//! On the client side, [`ServerStatusWatch`] records the latest state, and provides typed
+//! subscriptions of changes.
+//!
+//! ```
+//! # use async_lsp::router::Router;
+//! # use async_lsp::server::Lifecycle;
+//! # use async_lsp::status::{ServerStatusWatch, StatusReporter};
+//! # use futures::StreamExt;
+//! # async fn work(client: async_lsp::ClientSocket) {
+//! // Server side.
+//! let reporter = StatusReporter::new(client);
+//! let service = Lifecycle::new(Router::<()>::new(())).status(reporter.clone());
+//! reporter.degrade("Build script failed");
+//!
+//! // Client side.
+//! let watch = ServerStatusWatch::new();
+//! let mut router: Router<()> = Router::new(());
+//! watch.register(&mut router);
+//! let mut changes = watch.subscribe();
+//! while let Some(state) = changes.next().await {
+//!     println!("Server status: {state:?}");
+//! }
+//! # }
+//! ```
+use std::fmt;
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex};
+
+use futures::channel::mpsc;
+use futures::Stream;
+use lsp_types::notification::Notification;
+use serde::{Deserialize, Serialize};
+
+use crate::router::Router;
+use crate::{ClientSocket, ResponseError};
+
+/// The notification of the server status, sent from the server to the client on changes.
+#[derive(Debug)]
+pub enum ServerStatus {}
+
+impl Notification for ServerStatus {
+    type Params = ServerState;
+    const METHOD: &'static str = "asyncLsp/serverStatus";
+}
+
+/// The state of a Language Server, as the parameters of [`ServerStatus`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "camelCase")]
+#[non_exhaustive]
+pub enum ServerState {
+    /// The server is not initialized yet.
+    #[default]
+    Starting,
+    /// The server is working on background tasks, eg. indexing.
+    #[serde(rename_all = "camelCase")]
+    Indexing {
+        /// The title of the latest task.
+        title: String,
+        /// The latest message of the task, if any.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+        /// The latest percentage in `0..=100` of the task, if any.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        percentage: Option<u32>,
+    },
+    /// The server is idle and fully functional.
+    Ready,
+    /// The server works with reduced functionality.
+    Degraded {
+        /// The human-readable reason.
+        reason: String,
+    },
+}
+
+#[derive(Debug, Default)]
+struct Reporter {
+    ready: bool,
+    degraded: Option<String>,
+    /// Unfinished tasks keyed by job ids, in the order they begin.
+    tasks: Vec<(u64, Task)>,
+    /// The last derived state.
+    state: ServerState,
+}
+
+#[derive(Debug)]
+struct Task {
+    title: String,
+    message: Option<String>,
+    percentage: Option<u32>,
+}
+
+impl Reporter {
+    fn derive(&self) -> ServerState {
+        if !self.ready {
+            return ServerState::Starting;
+        }
+        if let Some(reason) = &self.degraded {
+            return ServerState::Degraded {
+                reason: reason.clone(),
+            };
+        }
+        match self.tasks.last() {
+            Some((_, task)) => ServerState::Indexing {
+                title: task.title.clone(),
+                message: task.message.clone(),
+                percentage: task.percentage,
+            },
+            None => ServerState::Ready,
+        }
+    }
+}
+
+/// The cheaply cloneable handle reporting the server status to the client.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone)]
+pub struct StatusReporter {
+    reporter: Arc<Mutex<Reporter>>,
+    client: ClientSocket,
+}
+
+impl fmt::Debug for StatusReporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StatusReporter")
+            .field("state", &self.reporter.lock().unwrap().state)
+            .finish_non_exhaustive()
+    }
+}
+
+impl StatusReporter {
+    /// Create a reporter in [`ServerState::Starting`] state, sending notifications via `client`.
+    #[must_use]
+    pub fn new(client: ClientSocket) -> Self {
+        Self {
+            reporter: Arc::default(),
+            client,
+        }
+    }
+
+    /// Get the current state.
+    #[must_use]
+    pub fn state(&self) -> ServerState {
+        self.reporter.lock().unwrap().state.clone()
+    }
+
+    /// Mark the server as initialized or not.
+    pub fn set_ready(&self, ready: bool) {
+        self.update(|reporter| reporter.ready = ready);
+    }
+
+    /// Mark the server as degraded for `reason`, replacing the previous reason if any.
+    pub fn degrade(&self, reason: impl Into<String>) {
+        let reason = reason.into();
+        self.update(|reporter| reporter.degraded = Some(reason));
+    }
+
+    /// Clear the degradation set by [`StatusReporter::degrade`].
+    pub fn recover(&self) {
+        self.update(|reporter| reporter.degraded = None);
+    }
+
+    /// Reset to [`ServerState::Starting`] without notifying, since the session is over.
+    pub(crate) fn reset(&self) {
+        let mut reporter = self.reporter.lock().unwrap();
+        reporter.ready = false;
+        reporter.state = ServerState::Starting;
+    }
+
+    pub(crate) fn begin_task(&self, key: u64, title: String) {
+        self.update(|reporter| {
+            let task = Task {
+                title,
+                message: None,
+                percentage: None,
+            };
+            reporter.tasks.push((key, task));
+        });
+    }
+
+    pub(crate) fn report_task(&self, key: u64, message: Option<String>, percentage: Option<u32>) {
+        self.update(|reporter| {
+            if let Some((_, task)) = reporter.tasks.iter_mut().find(|(k, _)| *k == key) {
+                task.message = message;
+                task.percentage = percentage;
+            }
+        });
+    }
+
+    pub(crate) fn end_task(&self, key: u64) {
+        self.update(|reporter| reporter.tasks.retain(|(k, _)| *k != key));
+    }
+
+    /// Apply `f` and send the derived state if it changes.
+    fn update(&self, f: impl FnOnce(&mut Reporter)) {
+        let mut reporter = self.reporter.lock().unwrap();
+        f(&mut reporter);
+        let state = reporter.derive();
+        if state == reporter.state {
+            return;
+        }
+        reporter.state = state.clone();
+        // Send under the lock, so that notifications are in order.
+        // Errors mean the main loop stopped, and the status is no longer interesting.
+        let _: Result<_, _> = self.client.notify::<ServerStatus>(state);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Watch {
+    state: ServerState,
+    subscribers: Vec<mpsc::UnboundedSender<ServerState>>,
+}
+
+/// The cheaply cloneable handle of the latest server status, on the client side.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone, Default)]
+pub struct ServerStatusWatch(Arc<Mutex<Watch>>);
+
+impl ServerStatusWatch {
+    /// Create a watch in [`ServerState::Starting`] state.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the latest state.
+    #[must_use]
+    pub fn get(&self) -> ServerState {
+        self.0.lock().unwrap().state.clone()
+    }
+
+    /// Subscribe changes of the state. The stream yields the current state first, and ends when
+    /// all clones of this watch are dropped.
+    pub fn subscribe(&self) -> impl Stream<Item = ServerState> + Send + Unpin {
+        let mut watch = self.0.lock().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        // Never fails since the receiver is alive.
+        let _: Result<_, _> = tx.unbounded_send(watch.state.clone());
+        watch.subscribers.push(tx);
+        rx
+    }
+
+    /// Handle `asyncLsp/serverStatus`.
+    pub fn update(&self, state: ServerState) {
+        let mut watch = self.0.lock().unwrap();
+        watch
+            .subscribers
+            .retain(|tx| tx.unbounded_send(state.clone()).is_ok());
+        watch.state = state;
+    }
+
+    /// Register a handler of `asyncLsp/serverStatus` to `router`, which updates this watch.
+    pub fn register<St, Error>(&self, router: &mut Router<St, Error>)
+    where
+        Error: From<ResponseError> + Send + 'static,
+    {
+        let this = self.clone();
+        router.notification::<ServerStatus>(move |_, state| {
+            this.update(state);
+            ControlFlow::Continue(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{LspService, MainLoopEvent, Message, PeerSocket};
+
+    #[tokio::test]
+    async fn report_and_watch() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let reporter = StatusReporter::new(ClientSocket(PeerSocket { tx }));
+        reporter.degrade("deferred until ready");
+        reporter.set_ready(true);
+        reporter.recover();
+        reporter.begin_task(1, "Indexing".into());
+        reporter.report_task(1, Some("foo".into()), Some(50));
+        reporter.end_task(1);
+        reporter.end_task(1);
+        drop(reporter);
+
+        let watch = ServerStatusWatch::new();
+        let mut router = Router::new(());
+        watch.register(&mut router);
+        let mut changes = watch.subscribe();
+        let mut notifs = Vec::new();
+        while let Some(event) = rx.next().await {
+            match event {
+                MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                    assert_eq!(notif.method, ServerStatus::METHOD);
+                    notifs.push(notif.params.clone());
+                    assert!(router.notify(notif).is_continue());
+                }
+                _ => panic!("unexpected event"),
+            }
+        }
+        assert_eq!(
+            notifs,
+            [
+                json!({ "state": "degraded", "reason": "deferred until ready" }),
+                json!({ "state": "ready" }),
+                json!({ "state": "indexing", "title": "Indexing" }),
+                json!({ "state": "indexing", "title": "Indexing", "message": "foo", "percentage": 50 }),
+                json!({ "state": "ready" }),
+            ]
+        );
+
+        assert_eq!(watch.get(), ServerState::Ready);
+        drop((watch, router));
+        let states = changes.by_ref().collect::<Vec<_>>().await;
+        assert_eq!(states.len(), 6);
+        assert_eq!(states[0], ServerState::Starting);
+        assert_eq!(states[5], ServerState::Ready);
+    }
+}
@@ -0,0 +1,340 @@
+//! Folding range and selection range computation from syntax trees.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Both `textDocument/foldingRange` and `textDocument/selectionRange` can be derived from a
+//! syntax tree alone, but getting the details right is tedious: clients may limit the number of
+//! folding ranges, or only support folding whole lines, and selection ranges must form a chain of
+//! strictly growing ranges for every requested position. Implement [`FoldableNode`] for the node
+//! type of a syntax tree, and use [`folding_ranges`] and [`selection_ranges`] to produce
+//! spec-compliant responses.
+//!
+//! ```
+//! # use async_lsp::syntax::{folding_ranges, FoldableNode, FoldingLimits};
+//! # use async_lsp::lsp_types::{ClientCapabilities, FoldingRange, Range};
+//! #[derive(Clone)]
+//! struct Node {
+//!     range: Range,
+//!     children: Vec<Node>,
+//! }
+//!
+//! impl FoldableNode for Node {
+//!     type Children = std::vec::IntoIter<Node>;
+//!
+//!     fn range(&self) -> Range {
+//!         self.range
+//!     }
+//!
+//!     fn children(&self) -> Self::Children {
+//!         self.children.clone().into_iter()
+//!     }
+//! }
+//!
+//! fn folding(root: &Node, caps: &ClientCapabilities) -> Vec<FoldingRange> {
+//!     folding_ranges(root.children(), &FoldingLimits::new(caps))
+//! }
+//! ```
+use std::collections::{HashSet, VecDeque};
+
+use lsp_types::{
+    ClientCapabilities, FoldingRange, FoldingRangeKind, Position, Range, SelectionRange,
+};
+
+/// A node of a syntax tree.
+///
+/// It is usually a cheap handle, like `tree_sitter::Node`, or a reference.
+pub trait FoldableNode: Sized {
+    /// The iterator type of children.
+    type Children: Iterator<Item = Self>;
+
+    /// The range of the node in the document.
+    fn range(&self) -> Range;
+
+    /// The children of the node, in document order.
+    fn children(&self) -> Self::Children;
+
+    /// Whether the node can be folded. By default, all multi-line nodes can be folded.
+    fn is_foldable(&self) -> bool {
+        true
+    }
+
+    /// The kind of the folding range of the node.
+    fn folding_kind(&self) -> Option<FoldingRangeKind> {
+        None
+    }
+
+    /// The text shown when the node is folded.
+    fn collapsed_text(&self) -> Option<String> {
+        None
+    }
+}
+
+/// The folding range limits of a client.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FoldingLimits {
+    /// The maximum number of folding ranges, if limited.
+    pub range_limit: Option<u32>,
+    /// Whether only whole lines can be folded.
+    pub line_folding_only: bool,
+    /// Whether custom collapsed text is supported.
+    pub collapsed_text: bool,
+}
+
+impl FoldingLimits {
+    /// Get the limits from client capabilities.
+    #[must_use]
+    pub fn new(caps: &ClientCapabilities) -> Self {
+        let caps = caps
+            .text_document
+            .as_ref()
+            .and_then(|caps| caps.folding_range.as_ref());
+        Self {
+            range_limit: caps.and_then(|caps| caps.range_limit),
+            line_folding_only: caps.and_then(|caps| caps.line_folding_only) == Some(true),
+            collapsed_text: caps
+                .and_then(|caps| caps.folding_range.as_ref()?.collapsed_text)
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// Compute folding ranges of `nodes` and all their descendants.
+///
+/// Single-line nodes are skipped. When only whole lines can be folded, at most one range starts
+/// on each line, and the outermost one wins. When the number of ranges exceeds the limit, outer
+/// ranges are preferred. The result is sorted by the start position.
+pub fn folding_ranges<N: FoldableNode>(
+    nodes: impl IntoIterator<Item = N>,
+    limits: &FoldingLimits,
+) -> Vec<FoldingRange> {
+    let limit = limits
+        .range_limit
+        .map_or(usize::MAX, |limit| limit as usize);
+    let mut ret = Vec::new();
+    let mut start_lines = HashSet::new();
+    // Breadth-first, so that outer ranges come first.
+    let mut queue = nodes.into_iter().collect::<VecDeque<_>>();
+    while let Some(node) = queue.pop_front() {
+        if ret.len() >= limit {
+            break;
+        }
+        let range = node.range();
+        if range.start.line < range.end.line
+            && node.is_foldable()
+            && (!limits.line_folding_only || start_lines.insert(range.start.line))
+        {
+            let (start_character, end_character) = match limits.line_folding_only {
+                true => (None, None),
+                false => (Some(range.start.character), Some(range.end.character)),
+            };
+            ret.push(FoldingRange {
+                start_line: range.start.line,
+                start_character,
+                end_line: range.end.line,
+                end_character,
+                kind: node.folding_kind(),
+                collapsed_text: node.collapsed_text().filter(|_| limits.collapsed_text),
+            });
+        }
+        queue.extend(node.children());
+    }
+    ret.sort_by_key(|range| (range.start_line, range.start_character));
+    ret
+}
+
+/// Compute selection ranges for each of `positions` in the tree of `root`.
+///
+/// Each result is the chain of strictly growing ranges of nodes containing the position, from the
+/// innermost one. Positions outside `root` result in an empty range at the position.
+pub fn selection_ranges<N: FoldableNode>(root: &N, positions: &[Position]) -> Vec<SelectionRange> {
+    positions
+        .iter()
+        .map(|&pos| {
+            let mut ret = None::<SelectionRange>;
+            let mut push = |range: Range| {
+                if ret.as_ref().map_or(true, |last| last.range != range) {
+                    ret = Some(SelectionRange {
+                        range,
+                        parent: ret.take().map(Box::new),
+                    });
+                }
+            };
+            let root_range = root.range();
+            if contains(root_range, pos) {
+                push(root_range);
+                let mut children = root.children();
+                while let Some(child) = find_child(children, pos) {
+                    push(child.range());
+                    children = child.children();
+                }
+            }
+            ret.unwrap_or(SelectionRange {
+                range: Range::new(pos, pos),
+                parent: None,
+            })
+        })
+        .collect()
+}
+
+fn contains(range: Range, pos: Position) -> bool {
+    range.start <= pos && pos <= range.end
+}
+
+/// Find the child containing `pos`. If `pos` is between two adjacent children, the latter wins.
+fn find_child<N: FoldableNode>(children: impl Iterator<Item = N>, pos: Position) -> Option<N> {
+    let mut found = None;
+    for child in children {
+        let range = child.range();
+        if pos < range.start {
+            break;
+        }
+        if contains(range, pos) {
+            let exclusive = pos < range.end;
+            found = Some(child);
+            if exclusive {
+                break;
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::{
+        FoldingRangeCapability, FoldingRangeClientCapabilities, TextDocumentClientCapabilities,
+    };
+
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    struct Node(Range, Vec<Node>);
+
+    impl FoldableNode for Node {
+        type Children = std::vec::IntoIter<Node>;
+
+        fn range(&self) -> Range {
+            self.0
+        }
+
+        fn children(&self) -> Self::Children {
+            self.1.clone().into_iter()
+        }
+
+        fn collapsed_text(&self) -> Option<String> {
+            Some("...".into())
+        }
+    }
+
+    fn node(start: (u32, u32), end: (u32, u32), children: Vec<Node>) -> Node {
+        let range = Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1));
+        Node(range, children)
+    }
+
+    /// ```text
+    /// fn f() {
+    ///     if x { y(); }
+    ///     while z {
+    ///     } }
+    /// ```
+    fn tree() -> Node {
+        let cond = node((1, 4), (1, 17), vec![node((1, 9), (1, 17), Vec::new())]);
+        let inner = node((2, 4), (3, 5), vec![node((2, 12), (3, 5), Vec::new())]);
+        let body = node((0, 7), (3, 7), vec![cond, inner]);
+        node((0, 0), (3, 7), vec![body])
+    }
+
+    fn fold(start: (u32, u32), end: (u32, u32)) -> (u32, u32, u32, u32) {
+        (start.0, start.1, end.0, end.1)
+    }
+
+    #[test]
+    fn folding() {
+        let root = tree();
+        let brief = |ranges: Vec<FoldingRange>| {
+            ranges
+                .into_iter()
+                .map(|r| {
+                    let (sc, ec) = (r.start_character.unwrap_or(0), r.end_character.unwrap_or(0));
+                    assert_eq!(r.collapsed_text, None);
+                    (r.start_line, sc, r.end_line, ec)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let limits = FoldingLimits::default();
+        assert_eq!(
+            brief(folding_ranges(root.children(), &limits)),
+            [
+                fold((0, 7), (3, 7)),
+                fold((2, 4), (3, 5)),
+                fold((2, 12), (3, 5))
+            ],
+        );
+
+        let limits = FoldingLimits {
+            line_folding_only: true,
+            ..limits
+        };
+        assert_eq!(
+            brief(folding_ranges([root.clone()], &limits)),
+            [fold((0, 0), (3, 0)), fold((2, 0), (3, 0))],
+        );
+
+        let limits = FoldingLimits {
+            range_limit: Some(1),
+            ..limits
+        };
+        assert_eq!(
+            brief(folding_ranges(root.children(), &limits)),
+            [fold((0, 0), (3, 0))],
+        );
+
+        let caps = ClientCapabilities {
+            text_document: Some(TextDocumentClientCapabilities {
+                folding_range: Some(FoldingRangeClientCapabilities {
+                    folding_range: Some(FoldingRangeCapability {
+                        collapsed_text: Some(true),
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let ranges = folding_ranges(root.children(), &FoldingLimits::new(&caps));
+        assert_eq!(ranges[0].collapsed_text.as_deref(), Some("..."));
+    }
+
+    #[test]
+    fn selection() {
+        let flatten = |mut sel: &SelectionRange| {
+            let mut ret = vec![sel.range];
+            while let Some(parent) = &sel.parent {
+                sel = parent;
+                ret.push(sel.range);
+            }
+            ret
+        };
+        let root = tree();
+        let ret = selection_ranges(&root, &[Position::new(1, 10), Position::new(9, 0)]);
+        assert_eq!(ret.len(), 2);
+        // The body and the root share the same end, but are different.
+        let expect = [
+            node((1, 9), (1, 17), Vec::new()).0,
+            node((1, 4), (1, 17), Vec::new()).0,
+            node((0, 7), (3, 7), Vec::new()).0,
+            root.0,
+        ];
+        assert_eq!(flatten(&ret[0]), expect);
+        assert_eq!(
+            flatten(&ret[1]),
+            [Range::new(Position::new(9, 0), Position::new(9, 0))]
+        );
+
+        // Ranges equal to the parent are skipped.
+        let dup = node((0, 0), (0, 3), vec![node((0, 0), (0, 3), Vec::new())]);
+        let ret = selection_ranges(&dup, &[Position::new(0, 1)]);
+        assert_eq!(flatten(&ret[0]), [dup.0]);
+    }
+}
@@ -0,0 +1,1254 @@
+//! Utilities for testing Language Servers and Language Clients.
+//!
+//! ## Capability matrix sweeps
+//!
+//! Servers often behave differently depending on client capabilities, and bugs hiding behind a
+//! specific combination only show up with specific editors. [`CapabilityMatrix`] runs a server
+//! service through the initialization sequence once for every combination of the selected
+//! capability axes, and hands the initialized service to test-supplied invariants.
+//!
+//! ```
+//! # use async_lsp::router::Router;
+//! # use async_lsp::testing::CapabilityMatrix;
+//! # use async_lsp::lsp_types::{notification, request, InitializeResult};
+//! # use std::ops::ControlFlow;
+//! # async fn work() {
+//! CapabilityMatrix::new()
+//!     .run(
+//!         || {
+//!             let mut router = Router::new(());
+//!             router
+//!                 .request::<request::Initialize, _>(|_, _| async {
+//!                     Ok(InitializeResult::default())
+//!                 })
+//!                 .notification::<notification::Initialized>(|_, _| ControlFlow::Continue(()));
+//!             router
+//!         },
+//!         |variant, init_ret, _service| async move {
+//!             assert!(init_ret.capabilities.completion_provider.is_none(), "{variant:?}");
+//!         },
+//!     )
+//!     .await;
+//! # }
+//! ```
+//!
+//! ## Request storms
+//!
+//! [`RequestStorm`] connects a server main loop and a client main loop in memory, and fires many
+//! interleaved requests in both directions simultaneously, eg. `textDocument/completion` from the
+//! client while the server asks `workspace/configuration`. It helps to catch deadlocks and request
+//! id confusion in a middleware stack. Since this crate is runtime-agnostic, there is no built-in
+//! timeout; wrap the returned future with one from the runtime to detect deadlocks.
+//!
+//! ## Mock clients
+//!
+//! Handlers of Language Servers often call back into the client, eg. to fetch
+//! `workspace/configuration` or to publish diagnostics. With feature `omni-trait`, [`MockClient`]
+//! is a [`LanguageClient`][crate::LanguageClient] recording everything it receives and replying
+//! requests with scripted responses. [`MockClient::socket`] creates a [`ClientSocket`] talking to
+//! it directly, without main loops or serialization of the wire format, for unit tests of
+//! handlers.
+//!
+//! ```
+//! # use async_lsp::router::Router;
+//! # use async_lsp::testing::{self, MockClient};
+//! # use async_lsp::lsp_types::{notification, request, ShowMessageParams, MessageType};
+//! # async fn work(make_server: impl FnOnce(async_lsp::ClientSocket) -> Router<()>) {
+//! let mock = MockClient::new();
+//! mock.respond::<request::WorkspaceConfiguration>(|params| {
+//!     Ok(params.items.iter().map(|_| serde_json::json!({ "enable": true })).collect())
+//! });
+//! let (client, driver) = mock.socket();
+//! let _driver = tokio::spawn(driver);
+//! let mut server = make_server(client);
+//!
+//! testing::request::<request::Shutdown, _>(&mut server, ()).await.unwrap();
+//! assert_eq!(mock.requests::<request::WorkspaceConfiguration>().len(), 1);
+//! assert!(mock.notifications::<notification::ShowMessage>().is_empty());
+//! # }
+//! ```
+//!
+//! ## In-memory connections
+//!
+//! [`connect_in_memory`] connects a server main loop to a built-in scripted client in memory. The
+//! [`ClientHandle`] performs the initialize handshake, sends requests and notifications on behalf
+//! of the client, and asserts on messages the server sends to the client. It drives the client
+//! main loop while being awaited, but the server main loop should be driven via
+//! [`ServerHandle::run`], eg. by spawning it.
+//!
+//! ```
+//! # use async_lsp::router::Router;
+//! # use async_lsp::testing::connect_in_memory;
+//! # use async_lsp::lsp_types::{notification, request, InitializeParams, InitializeResult};
+//! # use async_lsp::lsp_types::{ShowMessageParams, MessageType};
+//! # use std::ops::ControlFlow;
+//! # async fn work() {
+//! let (mut client, server) = connect_in_memory(|client| {
+//!     let mut router = Router::new(client);
+//!     router
+//!         .request::<request::Initialize, _>(|_, _| async { Ok(InitializeResult::default()) })
+//!         .notification::<notification::Initialized>(|client, _| {
+//!             let _ = client.notify::<notification::ShowMessage>(ShowMessageParams {
+//!                 typ: MessageType::INFO,
+//!                 message: "Hello".into(),
+//!             });
+//!             ControlFlow::Continue(())
+//!         });
+//!     router
+//! });
+//! let _server = tokio::spawn(server.run());
+//!
+//! client.initialize(InitializeParams::default()).await.unwrap();
+//! let params = client.expect_notification::<notification::ShowMessage>().await;
+//! assert_eq!(params.message, "Hello");
+//! # }
+//! ```
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::io;
+use std::marker::PhantomData;
+use std::ops::ControlFlow;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::channel::{mpsc, oneshot};
+use futures::future::{select, BoxFuture, Either, Fuse};
+use futures::stream::FuturesUnordered;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, FutureExt, StreamExt};
+
+use lsp_types::notification::{self, Notification};
+use lsp_types::request::{self, Request};
+use lsp_types::{
+    ClientCapabilities, CompletionClientCapabilities, CompletionItemCapability,
+    DiagnosticClientCapabilities, GeneralClientCapabilities, InitializeParams, InitializeResult,
+    InitializedParams, PositionEncodingKind, TextDocumentClientCapabilities,
+};
+use serde_json::Value as JsonValue;
+
+use crate::router::Router;
+use crate::{
+    AnyNotification, AnyRequest, ClientSocket, Error, ErrorCode, LspService, MainLoop, RequestId,
+    ResponseError, Result, ServerSocket,
+};
+
+/// Send a request to `service` directly and wait for its response, without a main loop.
+///
+/// # Errors
+///
+/// Returns the error response of the service. Undecodable results are reported as
+/// [`ErrorCode::INTERNAL_ERROR`][crate::ErrorCode::INTERNAL_ERROR].
+pub async fn request<R, S>(service: &mut S, params: R::Params) -> Result<R::Result, ResponseError>
+where
+    R: Request,
+    S: LspService<Response = JsonValue>,
+    ResponseError: From<S::Error>,
+{
+    poll_fn(|cx| service.poll_ready(cx)).await?;
+    let ret = service
+        .call(AnyRequest {
+            id: RequestId::Number(0),
+            method: R::METHOD.into(),
+            params: serde_json::to_value(params).expect("Failed to serialize"),
+            receipt: None,
+        })
+        .await?;
+    serde_json::from_value(ret).map_err(|err| {
+        ResponseError::new(
+            crate::ErrorCode::INTERNAL_ERROR,
+            format_args!("Failed to deserialize result of {}: {err}", R::METHOD),
+        )
+    })
+}
+
+/// Send a notification to `service` directly, without a main loop.
+///
+/// # Errors
+///
+/// Returns the error if the notification handler breaks the main loop with an error.
+pub fn notify<N, S>(service: &mut S, params: N::Params) -> Result<()>
+where
+    N: Notification,
+    S: LspService,
+{
+    let notif = AnyNotification {
+        method: N::METHOD.into(),
+        params: serde_json::to_value(params).expect("Failed to serialize"),
+        receipt: None,
+    };
+    match service.notify(notif) {
+        ControlFlow::Continue(()) | ControlFlow::Break(Ok(())) => Ok(()),
+        ControlFlow::Break(Err(err)) => Err(err),
+    }
+}
+
+/// A single combination of client capabilities in a [`CapabilityMatrix`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ClientVariant {
+    /// Whether completion snippets are supported.
+    pub snippet_support: bool,
+    /// Whether pull diagnostics (`textDocument/diagnostic`) are supported.
+    pub pull_diagnostics: bool,
+    /// The only position encoding supported.
+    pub position_encoding: PositionEncodingKind,
+}
+
+impl ClientVariant {
+    /// Build the [`ClientCapabilities`] of this combination.
+    #[must_use]
+    pub fn capabilities(&self) -> ClientCapabilities {
+        ClientCapabilities {
+            text_document: Some(TextDocumentClientCapabilities {
+                completion: Some(CompletionClientCapabilities {
+                    completion_item: Some(CompletionItemCapability {
+                        snippet_support: Some(self.snippet_support),
+                        ..CompletionItemCapability::default()
+                    }),
+                    ..CompletionClientCapabilities::default()
+                }),
+                diagnostic: self
+                    .pull_diagnostics
+                    .then(DiagnosticClientCapabilities::default),
+                ..TextDocumentClientCapabilities::default()
+            }),
+            general: Some(GeneralClientCapabilities {
+                position_encodings: Some(vec![self.position_encoding.clone()]),
+                ..GeneralClientCapabilities::default()
+            }),
+            ..ClientCapabilities::default()
+        }
+    }
+}
+
+/// The runner initializing a server with every combination of selected client capabilities.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct CapabilityMatrix {
+    snippet_support: Vec<bool>,
+    pull_diagnostics: Vec<bool>,
+    position_encodings: Vec<PositionEncodingKind>,
+}
+
+impl Default for CapabilityMatrix {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CapabilityMatrix {
+    /// Create the full matrix: snippets on/off, pull diagnostics on/off, and UTF-8/UTF-16
+    /// position encodings.
+    pub fn new() -> Self {
+        Self {
+            snippet_support: vec![false, true],
+            pull_diagnostics: vec![false, true],
+            position_encodings: vec![PositionEncodingKind::UTF16, PositionEncodingKind::UTF8],
+        }
+    }
+
+    /// Restrict the values of snippet support to sweep.
+    pub fn snippet_support(mut self, values: impl IntoIterator<Item = bool>) -> Self {
+        self.snippet_support = values.into_iter().collect();
+        self
+    }
+
+    /// Restrict the values of pull diagnostics support to sweep.
+    pub fn pull_diagnostics(mut self, values: impl IntoIterator<Item = bool>) -> Self {
+        self.pull_diagnostics = values.into_iter().collect();
+        self
+    }
+
+    /// Restrict the position encodings to sweep.
+    pub fn position_encodings(
+        mut self,
+        values: impl IntoIterator<Item = PositionEncodingKind>,
+    ) -> Self {
+        self.position_encodings = values.into_iter().collect();
+        self
+    }
+
+    /// Enumerate all combinations.
+    #[must_use]
+    pub fn variants(&self) -> Vec<ClientVariant> {
+        let mut ret = Vec::new();
+        for &snippet_support in &self.snippet_support {
+            for &pull_diagnostics in &self.pull_diagnostics {
+                for position_encoding in &self.position_encodings {
+                    ret.push(ClientVariant {
+                        snippet_support,
+                        pull_diagnostics,
+                        position_encoding: position_encoding.clone(),
+                    });
+                }
+            }
+        }
+        ret
+    }
+
+    /// For each combination, create a fresh service via `make_service`, send `initialize` and
+    /// `initialized` to it, and then call `check` with the initialized service.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the initialization sequence fails on any combination, or `check` panics.
+    pub async fn run<S, F, Fut>(&self, mut make_service: impl FnMut() -> S, mut check: F)
+    where
+        S: LspService<Response = JsonValue>,
+        ResponseError: From<S::Error>,
+        F: FnMut(ClientVariant, InitializeResult, S) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        for variant in self.variants() {
+            let mut service = make_service();
+            let params = InitializeParams {
+                capabilities: variant.capabilities(),
+                ..InitializeParams::default()
+            };
+            let init_ret = request::<request::Initialize, _>(&mut service, params)
+                .await
+                .unwrap_or_else(|err| panic!("initialize failed with {variant:?}: {err}"));
+            notify::<notification::Initialized, _>(&mut service, InitializedParams {})
+                .unwrap_or_else(|err| panic!("initialized failed with {variant:?}: {err}"));
+            check(variant, init_ret, service).await;
+        }
+    }
+}
+
+/// One end of an in-memory bidirectional byte stream created by [`memory_duplex`].
+#[derive(Debug)]
+pub struct MemoryStream {
+    tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+/// Create a pair of connected in-memory streams. Bytes written into one end can be read from the
+/// other end. Closing one end results in EOF on the other end.
+#[must_use]
+pub fn memory_duplex() -> (MemoryStream, MemoryStream) {
+    let (tx1, rx1) = mpsc::unbounded();
+    let (tx2, rx2) = mpsc::unbounded();
+    let end = |tx, rx| MemoryStream {
+        tx: Some(tx),
+        rx,
+        buf: Vec::new(),
+        pos: 0,
+    };
+    (end(tx1, rx2), end(tx2, rx1))
+}
+
+impl AsyncRead for MemoryStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.pos == self.buf.len() {
+            match futures::ready!(self.rx.poll_next_unpin(cx)) {
+                Some(buf) => {
+                    self.buf = buf;
+                    self.pos = 0;
+                }
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+        let len = out.len().min(self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for MemoryStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let ret = match &self.tx {
+            Some(tx) if tx.unbounded_send(buf.to_vec()).is_ok() => Ok(buf.len()),
+            _ => Err(io::ErrorKind::BrokenPipe.into()),
+        };
+        Poll::Ready(ret)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.tx = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+type StormFn<Socket> = Box<dyn Fn(usize, Socket) -> BoxFuture<'static, Result<()>>>;
+
+/// The runner of concurrent bidirectional requests between a server and a client.
+///
+/// See [module level documentations](self) for details.
+#[must_use]
+pub struct RequestStorm {
+    count: usize,
+    to_server: Option<StormFn<ServerSocket>>,
+    to_client: Option<StormFn<ClientSocket>>,
+}
+
+impl std::fmt::Debug for RequestStorm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestStorm")
+            .field("count", &self.count)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The statistics of a completed [`RequestStorm`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StormReport {
+    /// The number of completed tasks sending requests to the server.
+    pub to_server: usize,
+    /// The number of completed tasks sending requests to the client.
+    pub to_client: usize,
+}
+
+impl RequestStorm {
+    /// Create a storm of `count` tasks in each direction.
+    pub fn new(count: usize) -> Self {
+        Self {
+            count,
+            to_server: None,
+            to_client: None,
+        }
+    }
+
+    /// Set the task sending requests to the server. It receives the task index and should
+    /// verify the response it gets.
+    pub fn to_server<Fut>(mut self, f: impl Fn(usize, ServerSocket) -> Fut + 'static) -> Self
+    where
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.to_server = Some(Box::new(move |i, socket| f(i, socket).boxed()));
+        self
+    }
+
+    /// Set the task sending requests to the client. It receives the task index and should
+    /// verify the response it gets.
+    pub fn to_client<Fut>(mut self, f: impl Fn(usize, ClientSocket) -> Fut + 'static) -> Self
+    where
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.to_client = Some(Box::new(move |i, socket| f(i, socket).boxed()));
+        self
+    }
+
+    /// Connect `server` and `client` main loops in memory, run all tasks concurrently, and wait
+    /// for them to complete.
+    ///
+    /// `server_socket` and `client_socket` are the sockets returned by
+    /// [`MainLoop::new_client`] and [`MainLoop::new_server`] respectively.
+    ///
+    /// # Errors
+    ///
+    /// Fails with the first error returned by any task, or by either main loop if it stops
+    /// before all tasks complete.
+    pub async fn run<S, C>(
+        self,
+        server: MainLoop<S>,
+        client_socket: ClientSocket,
+        client: MainLoop<C>,
+        server_socket: ServerSocket,
+    ) -> Result<StormReport>
+    where
+        S: LspService<Response = JsonValue>,
+        ResponseError: From<S::Error>,
+        C: LspService<Response = JsonValue>,
+        ResponseError: From<C::Error>,
+    {
+        let (server_stream, client_stream) = memory_duplex();
+        let (server_rx, server_tx) = server_stream.split();
+        let (client_rx, client_tx) = client_stream.split();
+        let main_loops = futures::future::try_join(
+            server.run_buffered(server_rx, server_tx),
+            client.run_buffered(client_rx, client_tx),
+        );
+
+        let mut tasks = FuturesUnordered::new();
+        for i in 0..self.count {
+            if let Some(f) = &self.to_server {
+                tasks.push(
+                    f(i, server_socket.clone())
+                        .map(|ret| ret.map(|()| true))
+                        .boxed(),
+                );
+            }
+            if let Some(f) = &self.to_client {
+                tasks.push(
+                    f(i, client_socket.clone())
+                        .map(|ret| ret.map(|()| false))
+                        .boxed(),
+                );
+            }
+        }
+        let storm = async move {
+            let mut report = StormReport {
+                to_server: 0,
+                to_client: 0,
+            };
+            while let Some(to_server) = tasks.next().await {
+                if to_server? {
+                    report.to_server += 1;
+                } else {
+                    report.to_client += 1;
+                }
+            }
+            Ok::<_, Error>(report)
+        };
+
+        futures::pin_mut!(main_loops, storm);
+        match select(storm, main_loops).await {
+            Either::Left((ret, _)) => ret,
+            Either::Right((ret, _)) => {
+                ret?;
+                Err(Error::ServiceStopped)
+            }
+        }
+    }
+}
+
+/// A [`LanguageClient`][crate::LanguageClient] recording received messages and replying scripted
+/// responses.
+///
+/// Clones share the same records and responses. Requests without scripted responses are replied
+/// with [`ErrorCode::METHOD_NOT_FOUND`].
+///
+/// See [module level documentations](self) for details.
+#[cfg(feature = "omni-trait")]
+#[cfg_attr(docsrs, doc(cfg(feature = "omni-trait")))]
+#[derive(Clone, Default)]
+pub struct MockClient {
+    state: std::sync::Arc<std::sync::Mutex<mock_impl::MockState>>,
+}
+
+#[cfg(feature = "omni-trait")]
+mod mock_impl {
+    use std::collections::HashMap;
+    use std::future::ready;
+
+    use super::*;
+    use crate::{MainLoopEvent, Message, PeerSocket, RequestFuture};
+
+    #[derive(Default)]
+    pub(super) struct MockState {
+        /// Received methods and params, with whether each is a request.
+        received: Vec<(bool, String, JsonValue)>,
+        responders: HashMap<&'static str, Responder>,
+    }
+
+    type Responder = Box<dyn FnMut(JsonValue) -> Result<JsonValue, ResponseError> + Send>;
+
+    impl fmt::Debug for MockClient {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("MockClient")
+                .field("received", &self.received_methods())
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl MockClient {
+        /// Create a mock client without any scripted responses.
+        #[must_use]
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Reply requests `R` using `f`, replacing the previous one for `R`, if any.
+        pub fn respond<R: Request>(
+            &self,
+            mut f: impl FnMut(R::Params) -> Result<R::Result, ResponseError> + Send + 'static,
+        ) -> &Self {
+            let responder: Responder = Box::new(move |params| {
+                let params = serde_json::from_value(params).map_err(|err| {
+                    ResponseError::new(
+                        ErrorCode::INVALID_PARAMS,
+                        format_args!("Invalid params of {}: {err}", R::METHOD),
+                    )
+                })?;
+                Ok(serde_json::to_value(f(params)?).expect("Failed to serialize"))
+            });
+            self.state
+                .lock()
+                .unwrap()
+                .responders
+                .insert(R::METHOD, responder);
+            self
+        }
+
+        /// Create a socket talking to this client directly, and the driver future to be polled, eg. by
+        /// spawning it, for messages to be handled. The driver completes when all clones of the socket
+        /// are dropped.
+        #[must_use]
+        pub fn socket(&self) -> (ClientSocket, BoxFuture<'static, ()>) {
+            let (tx, mut rx) = mpsc::unbounded();
+            let mut router = Router::from_language_client(self.clone());
+            let driver = async move {
+                while let Some(event) = rx.next().await {
+                    match event {
+                        MainLoopEvent::OutgoingRequest(req, resp_tx)
+                        | MainLoopEvent::ForwardRequest(req, resp_tx)
+                        | MainLoopEvent::OutgoingRequestWithProgress(req, resp_tx, ..) => {
+                            let fut = RequestFuture::new(
+                                tower_service::Service::call(&mut router, req),
+                                RequestId::Number(0),
+                            );
+                            // The request may be cancelled.
+                            let _: Result<_, _> = resp_tx.send(fut.await);
+                        }
+                        MainLoopEvent::Outgoing(Message::Notification(notif)) => {
+                            let _ = router.notify(notif);
+                        }
+                        _ => {}
+                    }
+                }
+            };
+            (ClientSocket(PeerSocket { tx }), driver.boxed())
+        }
+
+        /// Get methods of all received requests and notifications, in order.
+        #[must_use]
+        pub fn received_methods(&self) -> Vec<String> {
+            let state = self.state.lock().unwrap();
+            state.received.iter().map(|(_, m, _)| m.clone()).collect()
+        }
+
+        /// Get params of all received requests `R`, in order.
+        ///
+        /// # Panics
+        ///
+        /// Panics if the params fail to deserialize, which should not happen.
+        #[must_use]
+        pub fn requests<R: Request>(&self) -> Vec<R::Params> {
+            self.received(true, R::METHOD)
+        }
+
+        /// Get params of all received notifications `N`, in order.
+        ///
+        /// # Panics
+        ///
+        /// Panics if the params fail to deserialize, which should not happen.
+        #[must_use]
+        pub fn notifications<N: Notification>(&self) -> Vec<N::Params> {
+            self.received(false, N::METHOD)
+        }
+
+        /// Clear all records. Scripted responses are kept.
+        pub fn clear(&self) {
+            self.state.lock().unwrap().received.clear();
+        }
+
+        fn received<T: serde::de::DeserializeOwned>(
+            &self,
+            is_request: bool,
+            method: &str,
+        ) -> Vec<T> {
+            let state = self.state.lock().unwrap();
+            state
+                .received
+                .iter()
+                .filter(|(req, m, _)| *req == is_request && m == method)
+                .map(|(_, _, params)| serde_json::from_value(params.clone()).expect("Checked"))
+                .collect()
+        }
+
+        fn record(&self, is_request: bool, method: &str, params: JsonValue) {
+            let mut state = self.state.lock().unwrap();
+            state.received.push((is_request, method.into(), params));
+        }
+
+        pub(crate) fn handle_request<R: Request>(
+            &self,
+            params: R::Params,
+        ) -> BoxFuture<'static, Result<R::Result, ResponseError>>
+        where
+            R::Result: Send + 'static,
+        {
+            let params = serde_json::to_value(params).expect("Failed to serialize");
+            self.record(true, R::METHOD, params.clone());
+            // Call it without the lock, so that it can access this client.
+            let responder = self.state.lock().unwrap().responders.remove(R::METHOD);
+            let ret = match responder {
+                Some(mut responder) => {
+                    let ret = responder(params);
+                    self.state
+                        .lock()
+                        .unwrap()
+                        .responders
+                        .entry(R::METHOD)
+                        .or_insert(responder);
+                    ret.and_then(|v| {
+                        serde_json::from_value(v).map_err(|err| {
+                            ResponseError::new(
+                                ErrorCode::INTERNAL_ERROR,
+                                format_args!("Invalid result of {}: {err}", R::METHOD),
+                            )
+                        })
+                    })
+                }
+                None => Err(ResponseError::new(
+                    ErrorCode::METHOD_NOT_FOUND,
+                    format_args!("No scripted response for {}", R::METHOD),
+                )),
+            };
+            Box::pin(ready(ret))
+        }
+
+        pub(crate) fn handle_notification<N: Notification>(
+            &self,
+            params: N::Params,
+        ) -> ControlFlow<Result<()>> {
+            let params = serde_json::to_value(params).expect("Failed to serialize");
+            self.record(false, N::METHOD, params);
+            ControlFlow::Continue(())
+        }
+    }
+}
+
+/// Connect a server main loop built by `server_builder` to a scripted client in memory.
+///
+/// See [module level documentations](self) for details.
+#[must_use]
+pub fn connect_in_memory<S>(
+    server_builder: impl FnOnce(ClientSocket) -> S,
+) -> (ClientHandle, ServerHandle<S>)
+where
+    S: LspService<Response = JsonValue>,
+    ResponseError: From<S::Error>,
+{
+    let (server_stream, client_stream) = memory_duplex();
+    let (server_main, client_socket) = MainLoop::new_server(server_builder);
+
+    let (notif_tx, notif_rx) = mpsc::unbounded();
+    let (req_tx, req_rx) = mpsc::unbounded();
+    let (client_main, server_socket) = MainLoop::<Router<_>>::new_client(|_| {
+        let mut router = Router::new(ScriptedClient { notif_tx, req_tx });
+        router
+            .unhandled_notification(|st, notif| {
+                // The receiver may be dropped.
+                let _ = st.notif_tx.unbounded_send(notif);
+                ControlFlow::Continue(())
+            })
+            .unhandled_request(|st, req| {
+                let (tx, rx) = oneshot::channel();
+                let _ = st.req_tx.unbounded_send((req, tx));
+                async move {
+                    rx.await.unwrap_or_else(|_| {
+                        Err(ResponseError::new(
+                            ErrorCode::INTERNAL_ERROR,
+                            "Request is dropped by the test",
+                        ))
+                    })
+                }
+            });
+        router
+    });
+    let (client_rx, client_tx) = client_stream.split();
+    let client = ClientHandle {
+        server: server_socket,
+        main_loop: client_main
+            .run_buffered(client_rx, client_tx)
+            .boxed()
+            .fuse(),
+        notifications: notif_rx,
+        requests: req_rx,
+    };
+    let server = ServerHandle {
+        main_loop: server_main,
+        client: client_socket,
+        stream: server_stream,
+    };
+    (client, server)
+}
+
+struct ScriptedClient {
+    notif_tx: mpsc::UnboundedSender<AnyNotification>,
+    req_tx: mpsc::UnboundedSender<(AnyRequest, ReplySender)>,
+}
+
+type ReplySender = oneshot::Sender<Result<JsonValue, ResponseError>>;
+
+/// The server side of a [`connect_in_memory`] connection.
+pub struct ServerHandle<S: LspService> {
+    main_loop: MainLoop<S>,
+    client: ClientSocket,
+    stream: MemoryStream,
+}
+
+impl<S: LspService> fmt::Debug for ServerHandle<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerHandle").finish_non_exhaustive()
+    }
+}
+
+impl<S> ServerHandle<S>
+where
+    S: LspService<Response = JsonValue>,
+    ResponseError: From<S::Error>,
+{
+    /// Get the socket for the server to talk to the client.
+    #[must_use]
+    pub fn client_socket(&self) -> &ClientSocket {
+        &self.client
+    }
+
+    /// Drive the server main loop.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`MainLoop::run`].
+    pub async fn run(self) -> Result<()> {
+        let (rx, tx) = self.stream.split();
+        self.main_loop.run_buffered(rx, tx).await
+    }
+}
+
+/// The client side of a [`connect_in_memory`] connection.
+///
+/// Requests and notifications from the server are queued until they are checked by
+/// [`ClientHandle::expect_request`] or [`ClientHandle::expect_notification`]. Unchecked requests
+/// are pending forever.
+pub struct ClientHandle {
+    server: ServerSocket,
+    main_loop: Fuse<BoxFuture<'static, Result<()>>>,
+    notifications: mpsc::UnboundedReceiver<AnyNotification>,
+    requests: mpsc::UnboundedReceiver<(AnyRequest, ReplySender)>,
+}
+
+impl fmt::Debug for ClientHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientHandle").finish_non_exhaustive()
+    }
+}
+
+impl ClientHandle {
+    /// Get the socket for the client to talk to the server.
+    ///
+    /// Note that the client main loop only runs while methods of [`ClientHandle`] are awaited.
+    #[must_use]
+    pub fn server_socket(&self) -> &ServerSocket {
+        &self.server
+    }
+
+    /// Perform the initialize handshake, ie. send the `initialize` request and then the
+    /// `initialized` notification.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`ClientHandle::request`].
+    pub async fn initialize(&mut self, params: InitializeParams) -> Result<InitializeResult> {
+        let ret = self.request::<request::Initialize>(params).await?;
+        self.notify::<notification::Initialized>(InitializedParams {})?;
+        Ok(ret)
+    }
+
+    /// Send a request to the server and wait for its response.
+    ///
+    /// # Errors
+    ///
+    /// - The error response from the server.
+    /// - Errors of the client main loop, eg. `Error::Eof` if the server stopped.
+    pub async fn request<R: Request>(&mut self, params: R::Params) -> Result<R::Result> {
+        let fut = self.server.request::<R>(params);
+        drive(&mut self.main_loop, fut).await?
+    }
+
+    /// Send a notification to the server.
+    ///
+    /// # Errors
+    ///
+    /// `Error::ServiceStopped` if the client main loop stopped.
+    pub fn notify<N: Notification>(&self, params: N::Params) -> Result<()> {
+        self.server.notify::<N>(params)
+    }
+
+    /// Wait for the next notification from the server, and check that it is `N`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the next notification is not `N`, or the client main loop stopped before it.
+    pub async fn expect_notification<N: Notification>(&mut self) -> N::Params {
+        let notif = drive(&mut self.main_loop, self.notifications.next())
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| panic!("client stopped before receiving {}", N::METHOD));
+        assert_eq!(
+            notif.method,
+            N::METHOD,
+            "unexpected notification: {notif:?}"
+        );
+        serde_json::from_value(notif.params)
+            .unwrap_or_else(|err| panic!("invalid params of {}: {err}", N::METHOD))
+    }
+
+    /// Wait for the next request from the server, and check that it is `R`. The request is
+    /// replied via the returned [`Reply`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the next request is not `R`, or the client main loop stopped before it.
+    pub async fn expect_request<R: Request>(&mut self) -> (R::Params, Reply<R>) {
+        let (req, tx) = drive(&mut self.main_loop, self.requests.next())
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| panic!("client stopped before receiving {}", R::METHOD));
+        assert_eq!(req.method, R::METHOD, "unexpected request: {req:?}");
+        let params = serde_json::from_value(req.params)
+            .unwrap_or_else(|err| panic!("invalid params of {}: {err}", R::METHOD));
+        let reply = Reply {
+            tx,
+            _marker: PhantomData,
+        };
+        (params, reply)
+    }
+
+    /// Drive the client main loop until `fut` completes, eg. to let the replies of
+    /// [`ClientHandle::expect_request`] be sent while waiting for something else.
+    ///
+    /// # Errors
+    ///
+    /// Errors of the client main loop if it stopped before `fut` completes.
+    pub async fn drive<T>(&mut self, fut: impl Future<Output = T>) -> Result<T> {
+        drive(&mut self.main_loop, fut).await
+    }
+}
+
+async fn drive<T>(
+    main_loop: &mut Fuse<BoxFuture<'static, Result<()>>>,
+    fut: impl Future<Output = T>,
+) -> Result<T> {
+    futures::pin_mut!(fut);
+    match select(fut, main_loop).await {
+        Either::Left((ret, _)) => Ok(ret),
+        Either::Right((ret, _)) => {
+            ret?;
+            Err(Error::ServiceStopped)
+        }
+    }
+}
+
+/// The reply to a request received by [`ClientHandle::expect_request`].
+pub struct Reply<R: Request> {
+    tx: ReplySender,
+    _marker: PhantomData<fn(R)>,
+}
+
+impl<R: Request> fmt::Debug for Reply<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reply")
+            .field("method", &R::METHOD)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: Request> Reply<R> {
+    /// Reply the request with `ret`. It is sent when the client main loop runs.
+    pub fn send(self, ret: Result<R::Result, ResponseError>) {
+        let ret = ret.map(|v| serde_json::to_value(v).expect("Failed to serialize"));
+        // The request may be cancelled.
+        let _: Result<_, _> = self.tx.send(ret);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[cfg(feature = "omni-trait")]
+    #[tokio::test]
+    async fn mock_client() {
+        use lsp_types::{
+            ConfigurationItem, ConfigurationParams, ExecuteCommandParams, MessageType,
+            ShowMessageParams,
+        };
+
+        let mock = MockClient::new();
+        mock.respond::<request::WorkspaceConfiguration>(|params| {
+            Ok(params
+                .items
+                .into_iter()
+                .map(|item| item.section.into())
+                .collect())
+        });
+        let (client, driver) = mock.socket();
+        let driver = tokio::spawn(driver);
+
+        let mut router = Router::new(client);
+        router.request::<request::ExecuteCommand, _>(|client, params| {
+            let client = client.clone();
+            async move {
+                let _ = ClientSocket::notify::<notification::ShowMessage>(
+                    &client,
+                    ShowMessageParams {
+                        typ: MessageType::INFO,
+                        message: params.command.clone(),
+                    },
+                );
+                let mut config = client
+                    .request::<request::WorkspaceConfiguration>(ConfigurationParams {
+                        items: vec![ConfigurationItem {
+                            scope_uri: None,
+                            section: Some(params.command),
+                        }],
+                    })
+                    .await
+                    .map_err(|err| {
+                        ResponseError::new(ErrorCode::INTERNAL_ERROR, err.to_string())
+                    })?;
+                // Not scripted.
+                let err = client
+                    .request::<request::ShowDocument>(lsp_types::ShowDocumentParams {
+                        uri: "file:///a".parse().unwrap(),
+                        external: None,
+                        take_focus: None,
+                        selection: None,
+                    })
+                    .await
+                    .unwrap_err();
+                assert!(
+                    matches!(err, Error::Response(err) if err.code == ErrorCode::METHOD_NOT_FOUND)
+                );
+                Ok(config.pop())
+            }
+        });
+
+        let ret = request::<request::ExecuteCommand, _>(
+            &mut router,
+            ExecuteCommandParams {
+                command: "foo".into(),
+                ..ExecuteCommandParams::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(ret, Some("foo".into()));
+        assert_eq!(
+            mock.received_methods(),
+            [
+                "window/showMessage",
+                "workspace/configuration",
+                "window/showDocument"
+            ]
+        );
+        let notifs = mock.notifications::<notification::ShowMessage>();
+        assert_eq!(notifs[0].message, "foo");
+        let reqs = mock.requests::<request::WorkspaceConfiguration>();
+        assert_eq!(reqs[0].items[0].section.as_deref(), Some("foo"));
+
+        mock.clear();
+        assert!(mock.received_methods().is_empty());
+        drop(router);
+        driver.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn in_memory() {
+        use lsp_types::{
+            ConfigurationItem, ConfigurationParams, MessageType, ShowMessageParams,
+            WorkspaceSymbolParams,
+        };
+
+        let (mut client, server) = connect_in_memory(|client| {
+            let mut router = Router::new(client);
+            router
+                .request::<request::Initialize, _>(|_, _| async { Ok(InitializeResult::default()) })
+                .notification::<notification::Initialized>(|client, _| {
+                    // Avoid `LspService::notify` with feature `forward`.
+                    let params = ShowMessageParams {
+                        typ: MessageType::INFO,
+                        message: "ready".into(),
+                    };
+                    let _ = ClientSocket::notify::<notification::ShowMessage>(client, params);
+                    ControlFlow::Continue(())
+                })
+                .request::<request::WorkspaceSymbolRequest, _>(|client, params| {
+                    let client = client.clone();
+                    async move {
+                        let config = client
+                            .request::<request::WorkspaceConfiguration>(ConfigurationParams {
+                                items: vec![ConfigurationItem {
+                                    scope_uri: None,
+                                    section: Some(params.query),
+                                }],
+                            })
+                            .await
+                            .map_err(|err| {
+                                ResponseError::new(ErrorCode::INTERNAL_ERROR, err.to_string())
+                            })?;
+                        assert_eq!(config, [JsonValue::from(42)]);
+                        Ok(None)
+                    }
+                });
+            router
+        });
+        let server = tokio::spawn(server.run());
+
+        client
+            .initialize(InitializeParams::default())
+            .await
+            .unwrap();
+        let params = client
+            .expect_notification::<notification::ShowMessage>()
+            .await;
+        assert_eq!(params.message, "ready");
+
+        // Requests are sent lazily, so spawn it to be sent before checking the callback.
+        let server_socket = client.server_socket().clone();
+        let symbols = tokio::spawn(async move {
+            server_socket
+                .request::<request::WorkspaceSymbolRequest>(WorkspaceSymbolParams {
+                    query: "answer".into(),
+                    ..WorkspaceSymbolParams::default()
+                })
+                .await
+        });
+        let (params, reply) = client
+            .expect_request::<request::WorkspaceConfiguration>()
+            .await;
+        assert_eq!(params.items[0].section.as_deref(), Some("answer"));
+        reply.send(Ok(vec![42.into()]));
+        let ret = client.drive(symbols).await.unwrap().unwrap();
+        assert_eq!(ret.unwrap(), None);
+
+        drop(client);
+        server.await.unwrap().unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn sweep_all_variants() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        CapabilityMatrix::new()
+            .run(
+                || {
+                    let mut router = Router::new(());
+                    router
+                        .request::<request::Initialize, _>(|_, params| {
+                            let snippet = params
+                                .capabilities
+                                .text_document
+                                .and_then(|c| c.completion?.completion_item?.snippet_support)
+                                == Some(true);
+                            async move {
+                                let mut ret = InitializeResult::default();
+                                ret.capabilities.experimental = Some(snippet.into());
+                                Ok(ret)
+                            }
+                        })
+                        .notification::<notification::Initialized>(
+                            |_, _| ControlFlow::Continue(()),
+                        );
+                    router
+                },
+                |variant, init_ret, _| {
+                    assert_eq!(
+                        init_ret.capabilities.experimental,
+                        Some(variant.snippet_support.into())
+                    );
+                    seen.lock().unwrap().push(variant);
+                    async {}
+                },
+            )
+            .await;
+        assert_eq!(seen.lock().unwrap().len(), 8);
+    }
+
+    #[tokio::test]
+    async fn bidirectional_storm() {
+        use lsp_types::{
+            CompletionItem, CompletionParams, CompletionResponse, ConfigurationItem,
+            ConfigurationParams, Position, TextDocumentIdentifier, TextDocumentPositionParams,
+        };
+        use tower_layer::Layer;
+
+        use crate::concurrency::ConcurrencyLayer;
+
+        fn configuration(section: String) -> ConfigurationParams {
+            ConfigurationParams {
+                items: vec![ConfigurationItem {
+                    scope_uri: None,
+                    section: Some(section),
+                }],
+            }
+        }
+
+        let concurrency = ConcurrencyLayer::new(2.try_into().unwrap());
+        let (server, client_socket) = MainLoop::new_server(|client| {
+            let mut router = Router::new(client);
+            router.request::<request::Completion, _>(|client, params| {
+                let client = client.clone();
+                let line = params.text_document_position.position.line;
+                async move {
+                    // Call back into the client while handling its request.
+                    let ret = client
+                        .request::<request::WorkspaceConfiguration>(configuration(line.to_string()))
+                        .await
+                        .unwrap();
+                    Ok(Some(CompletionResponse::Array(vec![
+                        CompletionItem::new_simple(ret[0].as_str().unwrap().into(), String::new()),
+                    ])))
+                }
+            });
+            concurrency.layer(router)
+        });
+        let (client, server_socket) = MainLoop::new_client(|_| {
+            let mut router = Router::new(());
+            router.request::<request::WorkspaceConfiguration, _>(|_, params| {
+                let section = params.items[0].section.clone().unwrap();
+                async move { Ok(vec![section.into()]) }
+            });
+            concurrency.layer(router)
+        });
+
+        let report = RequestStorm::new(100)
+            .to_server(|i, server| async move {
+                let params = CompletionParams {
+                    text_document_position: TextDocumentPositionParams {
+                        text_document: TextDocumentIdentifier::new("file:///foo".parse().unwrap()),
+                        position: Position::new(i as u32, 0),
+                    },
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                    context: None,
+                };
+                match server.request::<request::Completion>(params).await? {
+                    Some(CompletionResponse::Array(items)) => {
+                        assert_eq!(items[0].label, i.to_string())
+                    }
+                    ret => panic!("unexpected response: {ret:?}"),
+                }
+                Ok(())
+            })
+            .to_client(|i, client| async move {
+                let ret = client
+                    .request::<request::WorkspaceConfiguration>(configuration(format!("s{i}")))
+                    .await?;
+                assert_eq!(ret, [JsonValue::from(format!("s{i}"))]);
+                Ok(())
+            })
+            .run(server, client_socket, client, server_socket)
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            StormReport {
+                to_server: 100,
+                to_client: 100
+            }
+        );
+    }
+}
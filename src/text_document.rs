@@ -0,0 +1,393 @@
+//! Text synchronization of opened documents.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Nearly every Language Server keeps the text of opened documents in sync with the editor.
+//! [`DocumentStore`] is a cheaply cloneable handle of opened documents, which consumes
+//! `textDocument/didOpen`, `textDocument/didChange` and `textDocument/didClose`. Both full and
+//! incremental synchronization are supported. Texts are stored as [`Rope`]s, so that edits and
+//! snapshots of large documents are cheap, and positions are converted from UTF-16 code units,
+//! the default position encoding of LSP.
+//!
+//! The store can be updated either by handlers registered via [`DocumentStore::register`], or by
+//! the [`DocumentSync`] middleware, which leaves the notifications to the inner service as well.
+//!
+//! ```
+//! # use async_lsp::router::Router;
+//! # use async_lsp::text_document::DocumentStore;
+//! # use async_lsp::lsp_types::request::HoverRequest;
+//! let store = DocumentStore::new();
+//! let mut router: Router<DocumentStore> = Router::new(store.clone());
+//! store.register(&mut router);
+//! router.request::<HoverRequest, _>(|store, params| {
+//!     let pos = params.text_document_position_params;
+//!     let doc = store.get(&pos.text_document.uri);
+//!     let ch = doc.and_then(|doc| doc.text.get_char(doc.offset_at(pos.position)));
+//!     async move { Ok(None) }
+//! });
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::ControlFlow;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification,
+};
+use lsp_types::{
+    DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams, Position,
+    TextDocumentContentChangeEvent, Url,
+};
+pub use ropey::{self, Rope};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::router::Router;
+use crate::{AnyEvent, AnyNotification, AnyRequest, EventHints, LspService, ResponseError, Result};
+
+/// An opened document.
+///
+/// Cloning is cheap, since the text is shared until modified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Document {
+    /// The document URI.
+    pub uri: Url,
+    /// The language identifier given on `textDocument/didOpen`.
+    pub language_id: String,
+    /// The latest version.
+    pub version: i32,
+    /// The full text.
+    pub text: Rope,
+}
+
+impl Document {
+    /// Convert a UTF-16 based position into a char index of the text. Positions out of bounds
+    /// are clamped to the end of the line or the text.
+    #[must_use]
+    pub fn offset_at(&self, pos: Position) -> usize {
+        let line = pos.line as usize;
+        if line >= self.text.len_lines() {
+            return self.text.len_chars();
+        }
+        let line_start = self.text.line_to_char(line);
+        let line = self.text.line(line);
+        // Exclude the line break.
+        let mut len = line.len_chars();
+        while len > 0 && matches!(line.char(len - 1), '\n' | '\r') {
+            len -= 1;
+        }
+        let line = line.slice(..len);
+        let units = (pos.character as usize).min(line.len_utf16_cu());
+        line_start + line.utf16_cu_to_char(units)
+    }
+
+    /// Convert a char index of the text into a UTF-16 based position.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `offset` is out of bounds.
+    #[must_use]
+    pub fn position_at(&self, offset: usize) -> Position {
+        let line = self.text.char_to_line(offset);
+        let line_start = self.text.line_to_char(line);
+        let character = self.text.char_to_utf16_cu(offset) - self.text.char_to_utf16_cu(line_start);
+        Position::new(line as u32, character as u32)
+    }
+
+    /// Apply a content change of `textDocument/didChange`. Positions out of bounds are clamped.
+    pub fn apply_change(&mut self, change: TextDocumentContentChangeEvent) {
+        match change.range {
+            None => self.text = Rope::from_str(&change.text),
+            Some(range) => {
+                let start = self.offset_at(range.start);
+                let end = self.offset_at(range.end).max(start);
+                self.text.remove(start..end);
+                self.text.insert(start, &change.text);
+            }
+        }
+    }
+}
+
+/// The cheaply cloneable handle of opened documents.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone, Default)]
+pub struct DocumentStore(Arc<RwLock<HashMap<Url, Document>>>);
+
+impl fmt::Debug for DocumentStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DocumentStore")
+            .field("documents", &self.0.read().unwrap().len())
+            .finish()
+    }
+}
+
+impl DocumentStore {
+    /// Create an empty store.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the latest state of an opened document.
+    #[must_use]
+    pub fn get(&self, uri: &Url) -> Option<Document> {
+        self.0.read().unwrap().get(uri).cloned()
+    }
+
+    /// Get URIs of all opened documents.
+    #[must_use]
+    pub fn uris(&self) -> Vec<Url> {
+        self.0.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Register handlers of `textDocument/didOpen`, `textDocument/didChange` and
+    /// `textDocument/didClose` to `router`, which update this store.
+    pub fn register<St, Error>(&self, router: &mut Router<St, Error>)
+    where
+        Error: From<ResponseError> + Send + 'static,
+    {
+        let this = self.clone();
+        router.notification::<DidOpenTextDocument>(move |_, params| {
+            this.did_open(params);
+            ControlFlow::Continue(())
+        });
+        let this = self.clone();
+        router.notification::<DidChangeTextDocument>(move |_, params| {
+            this.did_change(params);
+            ControlFlow::Continue(())
+        });
+        let this = self.clone();
+        router.notification::<DidCloseTextDocument>(move |_, params| {
+            this.did_close(params);
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Handle `textDocument/didOpen`.
+    pub fn did_open(&self, params: DidOpenTextDocumentParams) {
+        let doc = params.text_document;
+        let doc = Document {
+            uri: doc.uri,
+            language_id: doc.language_id,
+            version: doc.version,
+            text: Rope::from_str(&doc.text),
+        };
+        self.0.write().unwrap().insert(doc.uri.clone(), doc);
+    }
+
+    /// Handle `textDocument/didChange`. Changes of documents which are not opened are ignored.
+    pub fn did_change(&self, params: DidChangeTextDocumentParams) {
+        let mut docs = self.0.write().unwrap();
+        let doc = match docs.get_mut(&params.text_document.uri) {
+            Some(doc) => doc,
+            None => return,
+        };
+        for change in params.content_changes {
+            doc.apply_change(change);
+        }
+        doc.version = params.text_document.version;
+    }
+
+    /// Handle `textDocument/didClose`, returning the closed document if it is opened.
+    pub fn did_close(&self, params: DidCloseTextDocumentParams) -> Option<Document> {
+        self.0.write().unwrap().remove(&params.text_document.uri)
+    }
+
+    /// Update the store by a notification, if it is one of `textDocument/didOpen`,
+    /// `textDocument/didChange` and `textDocument/didClose`. Invalid parameters are ignored.
+    fn sync(&self, notif: &AnyNotification) {
+        fn parse<P: serde::de::DeserializeOwned>(notif: &AnyNotification) -> Option<P> {
+            P::deserialize(&notif.params).ok()
+        }
+        match notif.method.as_str() {
+            DidOpenTextDocument::METHOD => parse(notif).map(|p| self.did_open(p)),
+            DidChangeTextDocument::METHOD => parse(notif).map(|p| self.did_change(p)),
+            DidCloseTextDocument::METHOD => parse(notif).map(|p| drop(self.did_close(p))),
+            _ => None,
+        };
+    }
+}
+
+/// The middleware updating a [`DocumentStore`] before passing notifications to the inner
+/// service.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+pub struct DocumentSync<S> {
+    service: S,
+    store: DocumentStore,
+}
+
+define_getters!(impl[S] DocumentSync<S>, service: S);
+
+impl<S: LspService> Service<AnyRequest> for DocumentSync<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        self.service.call(req)
+    }
+}
+
+impl<S: LspService> LspService for DocumentSync<S> {
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        self.store.sync(&notif);
+        self.service.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.service.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+/// A [`tower_layer::Layer`] which builds [`DocumentSync`].
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct DocumentSyncLayer {
+    store: DocumentStore,
+}
+
+impl DocumentSyncLayer {
+    /// Create the layer updating `store`.
+    pub fn new(store: DocumentStore) -> Self {
+        Self { store }
+    }
+}
+
+impl<S> Layer<S> for DocumentSyncLayer {
+    type Service = DocumentSync<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        DocumentSync {
+            service: inner,
+            store: self.store.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::Range;
+    use serde_json::json;
+
+    use super::*;
+
+    fn doc(text: &str) -> Document {
+        Document {
+            uri: Url::parse("file:///foo").unwrap(),
+            language_id: "rust".into(),
+            version: 0,
+            text: Rope::from_str(text),
+        }
+    }
+
+    #[test]
+    fn positions() {
+        let doc = doc("a😀b\r\ncd\n");
+        for (line, character, offset) in [
+            (0, 1, 1),
+            (0, 3, 2),
+            (0, 99, 3),
+            (1, 0, 5),
+            (1, 2, 7),
+            (2, 0, 8),
+            (9, 0, 8),
+        ] {
+            assert_eq!(doc.offset_at(Position::new(line, character)), offset);
+        }
+        assert_eq!(doc.position_at(2), Position::new(0, 3));
+        assert_eq!(doc.position_at(6), Position::new(1, 1));
+        assert_eq!(doc.position_at(8), Position::new(2, 0));
+    }
+
+    #[test]
+    fn incremental_change() {
+        let mut doc = doc("a😀b\ncd\n");
+        let change = |(l1, c1), (l2, c2), text: &str| TextDocumentContentChangeEvent {
+            range: Some(Range::new(Position::new(l1, c1), Position::new(l2, c2))),
+            range_length: None,
+            text: text.into(),
+        };
+        doc.apply_change(change((0, 1), (0, 3), "x"));
+        assert_eq!(doc.text, "axb\ncd\n");
+        doc.apply_change(change((0, 3), (1, 1), "y"));
+        assert_eq!(doc.text, "axbyd\n");
+        doc.apply_change(change((0, 99), (9, 0), "!"));
+        assert_eq!(doc.text, "axbyd!");
+    }
+
+    #[test]
+    fn sync() {
+        let store = DocumentStore::new();
+        let mut router = Router::<(), ResponseError>::new(());
+        router.unhandled_notification(|_, _| ControlFlow::Continue(()));
+        let mut service = DocumentSyncLayer::new(store.clone()).layer(router);
+        let uri = Url::parse("file:///foo").unwrap();
+
+        for (method, params) in [
+            (
+                DidOpenTextDocument::METHOD,
+                json!({ "textDocument": {
+                    "uri": uri,
+                    "languageId": "rust",
+                    "version": 1,
+                    "text": "fn main() {}\n",
+                } }),
+            ),
+            (
+                DidChangeTextDocument::METHOD,
+                json!({
+                    "textDocument": { "uri": uri, "version": 2 },
+                    "contentChanges": [
+                        {
+                            "range": {
+                                "start": { "line": 0, "character": 3 },
+                                "end": { "line": 0, "character": 7 },
+                            },
+                            "text": "foo",
+                        },
+                        { "text": "// full\n" },
+                        {
+                            "range": {
+                                "start": { "line": 1, "character": 0 },
+                                "end": { "line": 1, "character": 0 },
+                            },
+                            "text": "fn bar() {}",
+                        },
+                    ],
+                }),
+            ),
+        ] {
+            let notif = AnyNotification {
+                method: method.into(),
+                params,
+                receipt: None,
+            };
+            assert!(matches!(service.notify(notif), ControlFlow::Continue(())));
+        }
+        let doc = store.get(&uri).unwrap();
+        assert_eq!(doc.version, 2);
+        assert_eq!(doc.text, "// full\nfn bar() {}");
+        assert_eq!(store.uris(), std::slice::from_ref(&uri));
+
+        let notif = AnyNotification {
+            method: DidCloseTextDocument::METHOD.into(),
+            params: json!({ "textDocument": { "uri": uri } }),
+            receipt: None,
+        };
+        assert!(matches!(service.notify(notif), ControlFlow::Continue(())));
+        assert!(store.get(&uri).is_none());
+    }
+}
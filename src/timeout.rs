@@ -0,0 +1,280 @@
+//! Incoming request timeout.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! Handlers may take unbounded time, eg. when they are waiting for responses of slow peers, like
+//! `workspace/configuration` requests to a busy Language Client. This middleware fails requests
+//! which do not complete within a deadline, with [`ErrorCode::REQUEST_FAILED`] by default. The
+//! deadline can be set globally, and overridden per method.
+//!
+//! The inner future is dropped on timeout, thus any outgoing requests it is waiting for are
+//! abandoned, and cancelled via `$/cancelRequest`.
+//!
+//! Since this crate is runtime-agnostic, the [`Clock`] should be provided, eg. the timer function
+//! `tokio::time::sleep`.
+//!
+//! ```
+//! # use async_lsp::timeout::TimeoutLayer;
+//! # use async_lsp::lsp_types::request::Completion;
+//! # use std::time::Duration;
+//! let layer = TimeoutLayer::new(tokio::time::sleep)
+//!     .timeout(Duration::from_secs(10))
+//!     .method::<Completion>(Duration::from_secs(1));
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::ops::ControlFlow;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use lsp_types::request::Request;
+use pin_project_lite::pin_project;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::clock::{Clock, SharedClock};
+use crate::{
+    AnyEvent, AnyNotification, AnyRequest, ErrorCode, EventHints, LspService, ResponseError, Result,
+};
+
+/// The middleware failing requests which take too long.
+///
+/// See [module level documentations](self) for details.
+pub struct Timeout<S> {
+    service: S,
+    config: Arc<Config>,
+}
+
+struct Config {
+    timeout: Option<Duration>,
+    methods: HashMap<String, Duration>,
+    code: ErrorCode,
+    clock: SharedClock,
+}
+
+define_getters!(impl[S] Timeout<S>, service: S);
+
+impl<S: LspService> Service<AnyRequest> for Timeout<S>
+where
+    S::Error: From<ResponseError>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        let cfg = &self.config;
+        let timeout = cfg
+            .methods
+            .get(&*req.method)
+            .copied()
+            .or(cfg.timeout)
+            .map(|dur| (dur, cfg.clock.sleep(dur)));
+        let method = timeout.is_some().then(|| req.method.clone());
+        ResponseFuture {
+            fut: self.service.call(req),
+            timeout,
+            method,
+            code: cfg.code,
+        }
+    }
+}
+
+pin_project! {
+    /// The [`Future`] type used by the [`Timeout`] middleware.
+    pub struct ResponseFuture<Fut> {
+        #[pin]
+        fut: Fut,
+        timeout: Option<(Duration, BoxFuture<'static, ()>)>,
+        method: Option<String>,
+        code: ErrorCode,
+    }
+}
+
+impl<Fut, Response, Error> Future for ResponseFuture<Fut>
+where
+    Fut: Future<Output = Result<Response, Error>>,
+    Error: From<ResponseError>,
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if let Poll::Ready(ret) = this.fut.poll(cx) {
+            return Poll::Ready(ret);
+        }
+        match this.timeout {
+            Some((dur, sleep)) => {
+                ready!(sleep.poll_unpin(cx));
+                Poll::Ready(Err(ResponseError::new(
+                    *this.code,
+                    format_args!(
+                        "Request {} timed out after {dur:?}",
+                        this.method.as_deref().unwrap_or_default(),
+                    ),
+                )
+                .into()))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<S: LspService> LspService for Timeout<S>
+where
+    S::Error: From<ResponseError>,
+{
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        self.service.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.service.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+/// The builder of [`Timeout`] middleware.
+///
+/// By default, there is no deadline, and timed out requests fail with
+/// [`ErrorCode::REQUEST_FAILED`].
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone)]
+#[must_use]
+pub struct TimeoutBuilder {
+    timeout: Option<Duration>,
+    methods: HashMap<String, Duration>,
+    code: ErrorCode,
+    clock: SharedClock,
+}
+
+impl fmt::Debug for TimeoutBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TimeoutBuilder")
+            .field("timeout", &self.timeout)
+            .field("methods", &self.methods)
+            .field("code", &self.code)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TimeoutBuilder {
+    /// Create the middleware using `clock`, eg. the timer function `tokio::time::sleep`.
+    pub fn new(clock: impl Clock + 'static) -> Self {
+        Self {
+            timeout: None,
+            methods: HashMap::new(),
+            code: ErrorCode::REQUEST_FAILED,
+            clock: Arc::new(clock),
+        }
+    }
+
+    /// Set the deadline for all requests without a per-method deadline.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Set the deadline for requests of method `R`, overriding the global one.
+    pub fn method<R: Request>(self, timeout: Duration) -> Self {
+        self.method_name(R::METHOD, timeout)
+    }
+
+    /// Same as [`TimeoutBuilder::method`] but with a method name, eg. of a custom request or from
+    /// a configuration file.
+    pub fn method_name(mut self, method: impl Into<String>, timeout: Duration) -> Self {
+        self.methods.insert(method.into(), timeout);
+        self
+    }
+
+    /// Set the error code of timed out requests.
+    pub fn error_code(mut self, code: ErrorCode) -> Self {
+        self.code = code;
+        self
+    }
+}
+
+/// A type alias of [`TimeoutBuilder`] conforming to the naming convention of [`tower_layer`].
+pub type TimeoutLayer = TimeoutBuilder;
+
+impl<S> Layer<S> for TimeoutBuilder {
+    type Service = Timeout<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Timeout {
+            service: inner,
+            config: Arc::new(Config {
+                timeout: self.timeout,
+                methods: self.methods.clone(),
+                code: self.code,
+                clock: self.clock.clone(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{pending, poll_fn, ready};
+
+    use futures::{pin_mut, poll};
+    use lsp_types::request::{self, Request};
+    use serde_json::json;
+
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::router::Router;
+    use crate::RequestId;
+
+    #[tokio::test]
+    async fn per_method_timeout() {
+        let mut router = Router::new(());
+        router
+            .request::<request::HoverRequest, _>(|_, _| pending())
+            .request::<request::Shutdown, _>(|_, _| ready(Ok(())));
+        let clock = ManualClock::new();
+        let mut service = TimeoutLayer::new(clock.clone())
+            .timeout(Duration::from_secs(3600))
+            .method::<request::HoverRequest>(Duration::from_millis(10))
+            .layer(router);
+        poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+
+        let mut call = |method: &str, params| {
+            service.call(AnyRequest {
+                id: RequestId::Number(1),
+                method: method.into(),
+                params,
+                receipt: None,
+            })
+        };
+        let hover = call(
+            request::HoverRequest::METHOD,
+            json!({
+                "textDocument": { "uri": "file:///foo" },
+                "position": { "line": 0, "character": 0 },
+            }),
+        );
+        pin_mut!(hover);
+        let shutdown = call(request::Shutdown::METHOD, json!(null));
+
+        assert!(poll!(&mut hover).is_pending());
+        clock.advance(Duration::from_millis(10));
+        let err = hover.await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::REQUEST_FAILED);
+        assert!(err.message.contains(request::HoverRequest::METHOD));
+        assert_eq!(shutdown.await.unwrap(), json!(null));
+    }
+}
@@ -9,17 +9,54 @@
 //! - [`Future::poll`] of returned `Future` from [`Service::call`].
 //! - [`LspService::notify`].
 //! - [`LspService::emit`].
+//!
+//! The default request span records the method, the request id, the size of serialized
+//! parameters, and the latency in milliseconds as field `latency_ms` once the request completes.
+//! For messages received from the peer, the default request and notification spans also record
+//! the [sequence number](crate::Receipt) as field `seq`, and the queueing delay from the arrival
+//! to the start of the handler in milliseconds as field `queue_ms`.
+//! A `DEBUG` event is emitted inside the span when a request completes, or a notification is
+//! received. To export latencies as metrics, set a hook via [`TracingBuilder::on_complete`].
 use std::future::Future;
 use std::ops::ControlFlow;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
+use lsp_types::NumberOrString;
 use pin_project_lite::pin_project;
 use tower_layer::Layer;
 use tower_service::Service;
-use tracing::{info_span, Span};
+use tracing::{debug, field, info_span, Span};
+
+use crate::flags::FeatureFlags;
+use crate::{
+    json_size, AnyEvent, AnyNotification, AnyRequest, EventHints, LspService, Receipt, RequestId,
+    Result,
+};
 
-use crate::{AnyEvent, AnyNotification, AnyRequest, LspService, Result};
+type MetricsFn = Arc<dyn Fn(&RequestMetrics) + Send + Sync>;
+
+/// Metrics of a completed request, passed to the hook set by [`TracingBuilder::on_complete`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RequestMetrics {
+    /// The request method.
+    pub method: String,
+    /// The request id.
+    pub id: RequestId,
+    /// The size of serialized parameters in bytes.
+    pub params_size: usize,
+    /// The sequence number of the request, if it is received from the peer.
+    pub seq: Option<u64>,
+    /// The duration from the arrival to the call of the handler, if it is received from the peer.
+    pub queue_delay: Option<Duration>,
+    /// The duration from the call to the completion of the handler.
+    pub latency: Duration,
+    /// Whether the handler succeeded.
+    pub succeeded: bool,
+}
 
 /// The middleware attaching [`tracing::Span`]s over underlying handlers.
 ///
@@ -38,13 +75,31 @@ impl<S: LspService> Service<AnyRequest> for Tracing<S> {
     type Future = ResponseFuture<S::Future>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        let _guard = self.spans.service_ready.map(|f| f().entered());
+        let _guard = self
+            .spans
+            .enabled(self.spans.service_ready)
+            .map(|f| f().entered());
         self.service.poll_ready(cx)
     }
 
     fn call(&mut self, req: AnyRequest) -> Self::Future {
+        let span = self.spans.enabled(self.spans.request).map(|f| f(&req));
+        let metrics = self.spans.on_complete.clone().map(|f| {
+            let metrics = RequestMetrics {
+                method: req.method.clone(),
+                id: req.id.clone(),
+                params_size: json_size(&req.params),
+                seq: req.receipt.map(|receipt| receipt.seq),
+                queue_delay: req.receipt.map(|receipt| receipt.elapsed()),
+                latency: Duration::ZERO,
+                succeeded: false,
+            };
+            (metrics, f)
+        });
         ResponseFuture {
-            span: self.spans.request.map(|f| f(&req)),
+            span,
+            metrics,
+            start: Instant::now(),
             fut: self.service.call(req),
         }
     }
@@ -54,31 +109,64 @@ pin_project! {
     /// The [`Future`] type used by the [`Tracing`] middleware.
     pub struct ResponseFuture<Fut> {
         span: Option<Span>,
+        metrics: Option<(RequestMetrics, MetricsFn)>,
+        start: Instant,
         #[pin]
         fut: Fut,
     }
 }
 
-impl<Fut: Future> Future for ResponseFuture<Fut> {
+impl<Fut, T, E> Future for ResponseFuture<Fut>
+where
+    Fut: Future<Output = Result<T, E>>,
+{
     type Output = Fut::Output;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
-        let _guard = this.span.as_mut().map(|span| span.enter());
-        this.fut.poll(cx)
+        let _guard = this.span.as_ref().map(|span| span.enter());
+        let ret = match this.fut.poll(cx) {
+            Poll::Ready(ret) => ret,
+            Poll::Pending => return Poll::Pending,
+        };
+        let latency = this.start.elapsed();
+        if let Some(span) = &*this.span {
+            let latency_ms = latency.as_secs_f64() * 1e3;
+            span.record("latency_ms", latency_ms);
+            debug!(latency_ms, succeeded = ret.is_ok(), "request completed");
+        }
+        if let Some((mut metrics, f)) = this.metrics.take() {
+            metrics.latency = latency;
+            metrics.succeeded = ret.is_ok();
+            f(&metrics);
+        }
+        Poll::Ready(ret)
     }
 }
 
 impl<S: LspService> LspService for Tracing<S> {
     fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
-        let _guard = self.spans.notification.map(|f| f(&notif).entered());
+        let guard = self
+            .spans
+            .enabled(self.spans.notification)
+            .map(|f| f(&notif).entered());
+        if guard.is_some() {
+            debug!("notification received");
+        }
         self.service.notify(notif)
     }
 
     fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
-        let _guard = self.spans.event.map(|f| f(&event).entered());
+        let _guard = self
+            .spans
+            .enabled(self.spans.event)
+            .map(|f| f(&event).entered());
         self.service.emit(event)
     }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
 }
 
 /// The builder of [`Tracing`] middleware.
@@ -91,15 +179,40 @@ pub struct TracingBuilder {
     request: Option<fn(&AnyRequest) -> Span>,
     notification: Option<fn(&AnyNotification) -> Span>,
     event: Option<fn(&AnyEvent) -> Span>,
+    flags: Option<FeatureFlags>,
+    on_complete: Option<MetricsFn>,
 }
 
 impl Default for TracingBuilder {
     fn default() -> Self {
         Self {
             service_ready: Some(|| info_span!("service_ready")),
-            request: Some(|req| info_span!("request", method = req.method)),
-            notification: Some(|notif| info_span!("notification", method = notif.method)),
+            request: Some(|req| {
+                let id = match &req.id {
+                    NumberOrString::Number(id) => id.to_string(),
+                    NumberOrString::String(id) => id.clone(),
+                };
+                info_span!(
+                    "request",
+                    method = req.method,
+                    id,
+                    params_size = json_size(&req.params),
+                    seq = req.receipt.map(|receipt| receipt.seq),
+                    queue_ms = req.receipt.map(|receipt| queue_ms(&receipt)),
+                    latency_ms = field::Empty,
+                )
+            }),
+            notification: Some(|notif| {
+                info_span!(
+                    "notification",
+                    method = notif.method,
+                    seq = notif.receipt.map(|receipt| receipt.seq),
+                    queue_ms = notif.receipt.map(|receipt| queue_ms(&receipt)),
+                )
+            }),
             event: Some(|event| info_span!("event", type_name = event.type_name())),
+            flags: None,
+            on_complete: None,
         }
     }
 }
@@ -115,6 +228,8 @@ impl TracingBuilder {
             request: None,
             notification: None,
             event: None,
+            flags: None,
+            on_complete: None,
         }
     }
 
@@ -143,6 +258,27 @@ impl TracingBuilder {
         self
     }
 
+    /// Set a hook called with [`RequestMetrics`] on each completed request, eg. to export
+    /// latencies as metrics.
+    ///
+    /// It is called even if spans are disabled.
+    pub fn on_complete(mut self, f: impl Fn(&RequestMetrics) + Send + Sync + 'static) -> Self {
+        self.on_complete = Some(Arc::new(f));
+        self
+    }
+
+    /// Consult [`FeatureFlags::tracing`] of `flags` on each call, and skip all spans when it is
+    /// disabled.
+    pub fn flags(mut self, flags: FeatureFlags) -> Self {
+        self.flags = Some(flags);
+        self
+    }
+
+    /// Get the span builder `f` if spans are enabled.
+    fn enabled<F>(&self, f: Option<F>) -> Option<F> {
+        f.filter(|_| self.flags.as_ref().map_or(true, FeatureFlags::tracing))
+    }
+
     /// Build the middleware with the current configuration.
     pub fn build<S>(&self, service: S) -> Tracing<S> {
         Tracing {
@@ -152,6 +288,10 @@ impl TracingBuilder {
     }
 }
 
+fn queue_ms(receipt: &Receipt) -> f64 {
+    receipt.elapsed().as_secs_f64() * 1e3
+}
+
 /// A type alias of [`TracingLayer`] conforming to the naming convention of [`tower_layer`].
 pub type TracingLayer = TracingBuilder;
 
@@ -162,3 +302,61 @@ impl<S> Layer<S> for TracingBuilder {
         self.build(inner)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::future::{poll_fn, ready};
+    use std::sync::Mutex;
+
+    use lsp_types::request::{Request, Shutdown};
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+    use crate::ResponseError;
+
+    #[tokio::test]
+    async fn request_metrics() {
+        let mut router = Router::<(), ResponseError>::new(());
+        router.request::<Shutdown, _>(|_, ()| ready(Ok(())));
+        let metrics = Arc::new(Mutex::new(Vec::new()));
+        let mut svc = TracingBuilder::default()
+            .on_complete({
+                let metrics = metrics.clone();
+                move |m| metrics.lock().unwrap().push(m.clone())
+            })
+            .layer(router);
+
+        let receipt = Receipt {
+            seq: 7,
+            time: Instant::now(),
+        };
+        for (id, method, receipt) in [(1, Shutdown::METHOD, None), (2, "foo", Some(receipt))] {
+            poll_fn(|cx| svc.poll_ready(cx)).await.unwrap();
+            let _ = svc
+                .call(AnyRequest {
+                    id: RequestId::Number(id),
+                    method: method.into(),
+                    params: json!(null),
+                    receipt,
+                })
+                .await;
+        }
+        let metrics = metrics.lock().unwrap();
+        let brief = metrics
+            .iter()
+            .map(|m| (m.method.as_str(), m.id.clone(), m.params_size, m.succeeded))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            brief,
+            [
+                (Shutdown::METHOD, RequestId::Number(1), 4, true),
+                ("foo", RequestId::Number(2), 4, false),
+            ]
+        );
+        assert_eq!(metrics[0].seq, None);
+        assert_eq!(metrics[0].queue_delay, None);
+        assert_eq!(metrics[1].seq, Some(7));
+        assert!(metrics[1].queue_delay.is_some());
+    }
+}
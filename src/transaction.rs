@@ -0,0 +1,507 @@
+//! Multi-step `workspace/applyEdit` with rollback.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Multi-file refactorings often apply several workspace edits in a row, eg. creating an item,
+//! then updating its usages. If a later step fails, earlier ones leave the workspace half
+//! refactored. [`Transaction`] groups these steps:
+//!
+//! - Each step is sent with the versions of opened documents from a [`DocumentStore`] as
+//!   preconditions, so the editor rejects it if the documents changed in the meantime.
+//!   Documents not opened are read from a [`Vfs`] and sent without versions.
+//! - After the editor applies a step, opened documents are verified to have the expected content.
+//! - Reverse edits of each step are computed from the original content, so
+//!   [`Transaction::rollback`] can undo all applied steps in reverse order.
+//!
+//! Only text edits are supported, since resource operations, eg. file creation, can not be
+//! reversed reliably. The editor must support `workspace.workspaceEdit.documentChanges`.
+//!
+//! ```no_run
+//! # use async_lsp::text_document::DocumentStore;
+//! # use async_lsp::transaction::Transaction;
+//! # use async_lsp::lsp_types::WorkspaceEdit;
+//! # async fn work(client: async_lsp::ClientSocket, store: DocumentStore) {
+//! # let (create, update) = (WorkspaceEdit::default(), WorkspaceEdit::default());
+//! let mut tx = Transaction::new(client, store).label("Extract function");
+//! if tx.apply(create).await.is_err() || tx.apply(update).await.is_err() {
+//!     tx.rollback().await.unwrap();
+//! }
+//! # }
+//! ```
+use std::sync::Arc;
+use std::{fmt, io};
+
+use lsp_types::request::ApplyWorkspaceEdit;
+use lsp_types::{
+    ApplyWorkspaceEditParams, DocumentChangeOperation, DocumentChanges, OneOf,
+    OptionalVersionedTextDocumentIdentifier, Range, TextDocumentEdit, TextEdit, Url, WorkspaceEdit,
+};
+
+use crate::text_document::{Document, DocumentStore, Rope};
+use crate::vfs::{DiskVfs, Vfs};
+use crate::ClientSocket;
+
+/// Errors of [`Transaction`].
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum TransactionError {
+    /// The edit contains resource operations, overlapping edits, or multiple edits of the same
+    /// document.
+    #[error("unsupported edit: {0}")]
+    Unsupported(String),
+    /// A document which is not opened can not be read.
+    #[error("failed to read {uri}: {error}")]
+    Read {
+        /// The document URI.
+        uri: Url,
+        /// The error from the [`Vfs`].
+        #[source]
+        error: io::Error,
+    },
+    /// The editor does not apply the edit, eg. due to changed document versions.
+    #[error("edit is not applied: {}", .0.as_deref().unwrap_or("no reason"))]
+    Rejected(Option<String>),
+    /// The content of a document is not the expected one, after applying an edit, or before
+    /// rolling it back.
+    #[error("document {0} diverged from the expected content")]
+    Diverged(Url),
+    /// The `workspace/applyEdit` request fails.
+    #[error("{0}")]
+    Request(#[from] crate::Error),
+}
+
+/// An applied edit of a document in a step.
+struct AppliedEdit {
+    uri: Url,
+    /// The content after the edit.
+    expected: Rope,
+    reverse: Vec<TextEdit>,
+}
+
+/// The group of `workspace/applyEdit` steps, which can be rolled back together.
+///
+/// See [module level documentations](self) for details.
+#[must_use]
+pub struct Transaction {
+    client: ClientSocket,
+    store: DocumentStore,
+    vfs: Arc<dyn Vfs>,
+    label: Option<String>,
+    steps: Vec<Vec<AppliedEdit>>,
+}
+
+impl fmt::Debug for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Transaction")
+            .field("label", &self.label)
+            .field("steps", &self.steps.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Transaction {
+    /// Create an empty transaction sending edits via `client`, with versions of opened documents
+    /// from `store`.
+    pub fn new(client: ClientSocket, store: DocumentStore) -> Self {
+        Self {
+            client,
+            store,
+            vfs: Arc::new(DiskVfs),
+            label: None,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Set the label of edits presented to the user, eg. on the undo stack.
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// Set the [`Vfs`] to read documents which are not opened. Default is [`DiskVfs`].
+    pub fn vfs(mut self, vfs: impl Vfs + 'static) -> Self {
+        self.vfs = Arc::new(vfs);
+        self
+    }
+
+    /// Get the number of applied steps.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// Check if no steps are applied.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Apply `edit` as the next step.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the edit is unsupported, the editor does not apply it, or opened documents
+    /// diverge after applying it. In the last case, the step is still recorded, but rolling it
+    /// back will fail.
+    pub async fn apply(&mut self, edit: WorkspaceEdit) -> Result<(), TransactionError> {
+        let mut step = Vec::new();
+        let mut doc_edits = Vec::new();
+        for (uri, edits) in text_edits(edit)? {
+            let (version, text) = match self.store.get(&uri) {
+                Some(doc) => (Some(doc.version), doc.text),
+                None => match self.vfs.read_to_string(&uri) {
+                    Ok(text) => (None, Rope::from_str(&text)),
+                    Err(error) => return Err(TransactionError::Read { uri, error }),
+                },
+            };
+            let (expected, reverse) = reverse_edits(&uri, text, &edits)?;
+            doc_edits.push((uri.clone(), version, edits));
+            step.push(AppliedEdit {
+                uri,
+                expected,
+                reverse,
+            });
+        }
+        self.send(doc_edits).await?;
+        self.steps.push(step);
+
+        // Changes of opened documents are notified before the response.
+        for edit in self.steps.last().expect("Just pushed") {
+            if let Some(doc) = self.store.get(&edit.uri) {
+                if doc.text != edit.expected {
+                    return Err(TransactionError::Diverged(edit.uri.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Undo all applied steps in reverse order.
+    ///
+    /// # Errors
+    ///
+    /// Fails if opened documents diverged from the content after their steps, eg. edited by the
+    /// user, or the editor does not apply the reverse edits. Steps which are not rolled back yet
+    /// are kept, so rolling back can be retried.
+    pub async fn rollback(&mut self) -> Result<(), TransactionError> {
+        while let Some(step) = self.steps.last() {
+            let mut doc_edits = Vec::new();
+            for edit in step {
+                let version = match self.store.get(&edit.uri) {
+                    Some(doc) if doc.text != edit.expected => {
+                        return Err(TransactionError::Diverged(edit.uri.clone()));
+                    }
+                    Some(doc) => Some(doc.version),
+                    None => None,
+                };
+                doc_edits.push((edit.uri.clone(), version, edit.reverse.clone()));
+            }
+            self.send(doc_edits).await?;
+            self.steps.pop();
+        }
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        edits: Vec<(Url, Option<i32>, Vec<TextEdit>)>,
+    ) -> Result<(), TransactionError> {
+        let edits = edits
+            .into_iter()
+            .map(|(uri, version, edits)| TextDocumentEdit {
+                text_document: OptionalVersionedTextDocumentIdentifier { uri, version },
+                edits: edits.into_iter().map(OneOf::Left).collect(),
+            })
+            .collect();
+        let ret = self
+            .client
+            .request::<ApplyWorkspaceEdit>(ApplyWorkspaceEditParams {
+                label: self.label.clone(),
+                edit: WorkspaceEdit {
+                    document_changes: Some(DocumentChanges::Edits(edits)),
+                    ..WorkspaceEdit::default()
+                },
+            })
+            .await?;
+        if !ret.applied {
+            return Err(TransactionError::Rejected(ret.failure_reason));
+        }
+        Ok(())
+    }
+}
+
+/// Collect text edits of each document. Change annotations are dropped.
+fn text_edits(edit: WorkspaceEdit) -> Result<Vec<(Url, Vec<TextEdit>)>, TransactionError> {
+    let docs = match edit.document_changes {
+        Some(DocumentChanges::Edits(edits)) => edits,
+        Some(DocumentChanges::Operations(ops)) => ops
+            .into_iter()
+            .map(|op| match op {
+                DocumentChangeOperation::Edit(edit) => Ok(edit),
+                DocumentChangeOperation::Op(_) => {
+                    Err(TransactionError::Unsupported("resource operations".into()))
+                }
+            })
+            .collect::<Result<_, _>>()?,
+        None => {
+            let changes = edit.changes.unwrap_or_default();
+            return Ok(changes.into_iter().collect());
+        }
+    };
+    let mut ret: Vec<(Url, Vec<TextEdit>)> = Vec::new();
+    for doc in docs {
+        let uri = doc.text_document.uri;
+        if ret.iter().any(|(prev, _)| *prev == uri) {
+            return Err(TransactionError::Unsupported(format!(
+                "multiple edits of {uri}"
+            )));
+        }
+        let edits = doc
+            .edits
+            .into_iter()
+            .map(|edit| match edit {
+                OneOf::Left(edit) => edit,
+                OneOf::Right(edit) => edit.text_edit,
+            })
+            .collect();
+        ret.push((uri, edits));
+    }
+    Ok(ret)
+}
+
+/// Compute the content after applying `edits` on `text`, and the edits reversing them.
+fn reverse_edits(
+    uri: &Url,
+    text: Rope,
+    edits: &[TextEdit],
+) -> Result<(Rope, Vec<TextEdit>), TransactionError> {
+    let mut doc = Document {
+        uri: uri.clone(),
+        language_id: String::new(),
+        version: 0,
+        text,
+    };
+    let mut ranges = edits
+        .iter()
+        .map(|edit| {
+            let start = doc.offset_at(edit.range.start);
+            let end = doc.offset_at(edit.range.end).max(start);
+            (start, end, &*edit.new_text)
+        })
+        .collect::<Vec<_>>();
+    // Stable, so insertions at the same position keep their order.
+    ranges.sort_by_key(|&(start, ..)| start);
+    if ranges.windows(2).any(|w| w[0].1 > w[1].0) {
+        return Err(TransactionError::Unsupported(format!(
+            "overlapping edits of {uri}"
+        )));
+    }
+
+    // Offsets of the replaced ranges in the new content, and their original texts.
+    let mut reverse = Vec::with_capacity(ranges.len());
+    let mut delta = 0isize;
+    for &(start, end, new_text) in &ranges {
+        let new_len = new_text.chars().count();
+        let new_start = (start as isize + delta) as usize;
+        reverse.push((
+            new_start,
+            new_start + new_len,
+            doc.text.slice(start..end).to_string(),
+        ));
+        delta += new_len as isize - (end - start) as isize;
+    }
+    for &(start, end, new_text) in ranges.iter().rev() {
+        doc.text.remove(start..end);
+        doc.text.insert(start, new_text);
+    }
+    let reverse = reverse
+        .into_iter()
+        .map(|(start, end, text)| {
+            TextEdit::new(
+                Range::new(doc.position_at(start), doc.position_at(end)),
+                text,
+            )
+        })
+        .collect();
+    Ok((doc.text, reverse))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use lsp_types::request::Request;
+    use lsp_types::{
+        ApplyWorkspaceEditResponse, DidChangeTextDocumentParams, DidOpenTextDocumentParams,
+        Position, TextDocumentContentChangeEvent, TextDocumentItem,
+        VersionedTextDocumentIdentifier,
+    };
+
+    use super::*;
+    use crate::vfs::MemoryVfs;
+    use crate::{AnyResponse, MainLoopEvent, PeerSocket};
+
+    fn edit(l1: u32, c1: u32, l2: u32, c2: u32, text: &str) -> TextEdit {
+        TextEdit::new(
+            Range::new(Position::new(l1, c1), Position::new(l2, c2)),
+            text.into(),
+        )
+    }
+
+    #[test]
+    fn reverse() {
+        let uri = Url::parse("file:///foo").unwrap();
+        let text = Rope::from_str("fn foo() {}\nfoo();\n");
+        let edits = [
+            edit(1, 0, 1, 3, "bar"),
+            edit(0, 3, 0, 6, "bar_baz"),
+            edit(1, 6, 1, 6, "\n// 😀"),
+        ];
+        let (expected, reverse) = reverse_edits(&uri, text, &edits).unwrap();
+        assert_eq!(expected, "fn bar_baz() {}\nbar();\n// 😀\n");
+        assert_eq!(
+            reverse,
+            [
+                edit(0, 3, 0, 10, "foo"),
+                edit(1, 0, 1, 3, "foo"),
+                edit(1, 6, 2, 5, ""),
+            ],
+        );
+
+        let edits = [edit(0, 0, 0, 5, ""), edit(0, 4, 0, 6, "")];
+        let text = Rope::from_str("fn foo() {}\n");
+        assert!(matches!(
+            reverse_edits(&uri, text, &edits),
+            Err(TransactionError::Unsupported(_))
+        ));
+    }
+
+    /// Apply edits to `store` as an editor, rejecting ones with outdated versions.
+    fn apply_edit(store: &DocumentStore, params: ApplyWorkspaceEditParams) -> bool {
+        let edits = match params.edit.document_changes {
+            Some(DocumentChanges::Edits(edits)) => edits,
+            _ => panic!("unexpected edit"),
+        };
+        // Documents not opened can not be edited by this editor.
+        let outdated = edits.iter().any(|edit| {
+            let doc = store.get(&edit.text_document.uri);
+            doc.map_or(true, |doc| edit.text_document.version != Some(doc.version))
+        });
+        if outdated {
+            return false;
+        }
+        for edit in edits {
+            let uri = edit.text_document.uri;
+            let version = store.get(&uri).unwrap().version + 1;
+            let mut edits = edit
+                .edits
+                .into_iter()
+                .map(|edit| match edit {
+                    OneOf::Left(edit) => edit,
+                    OneOf::Right(_) => panic!("unexpected annotated edit"),
+                })
+                .collect::<Vec<_>>();
+            edits.sort_by_key(|edit| edit.range.start);
+            store.did_change(DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(uri, version),
+                content_changes: edits
+                    .into_iter()
+                    .rev()
+                    .map(|edit| TextDocumentContentChangeEvent {
+                        range: Some(edit.range),
+                        range_length: None,
+                        text: edit.new_text,
+                    })
+                    .collect(),
+            });
+        }
+        true
+    }
+
+    #[tokio::test]
+    async fn rollback() {
+        let foo = Url::parse("file:///foo").unwrap();
+        let bar = Url::parse("file:///bar").unwrap();
+        let store = DocumentStore::new();
+        store.did_open(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(foo.clone(), "rust".into(), 1, "foo\n".into()),
+        });
+        let vfs = MemoryVfs::new();
+        vfs.insert(bar.clone(), "bar\n");
+
+        let (tx, mut rx) = mpsc::unbounded();
+        let editor = tokio::spawn({
+            let store = store.clone();
+            async move {
+                let mut labels = Vec::new();
+                while let Some(event) = rx.next().await {
+                    let (req, resp_tx) = match event {
+                        MainLoopEvent::OutgoingRequest(req, resp_tx) => (req, resp_tx),
+                        _ => panic!("unexpected event"),
+                    };
+                    assert_eq!(req.method, ApplyWorkspaceEdit::METHOD);
+                    let params: ApplyWorkspaceEditParams =
+                        serde_json::from_value(req.params).unwrap();
+                    labels.push(params.label.clone().unwrap());
+                    let applied = apply_edit(&store, params);
+                    let result = ApplyWorkspaceEditResponse {
+                        applied,
+                        failure_reason: None,
+                        failed_change: None,
+                    };
+                    let _ = resp_tx.send(AnyResponse {
+                        id: req.id,
+                        result: Some(serde_json::to_value(result).unwrap()),
+                        error: None,
+                        raw: None,
+                    });
+                }
+                labels
+            }
+        });
+        let mut tx = Transaction::new(ClientSocket(PeerSocket { tx }), store.clone())
+            .label("test")
+            .vfs(vfs);
+
+        let step = |uri: &Url, edit: TextEdit| WorkspaceEdit {
+            changes: Some(HashMap::from([(uri.clone(), vec![edit])])),
+            ..WorkspaceEdit::default()
+        };
+        tx.apply(step(&foo, edit(0, 0, 0, 3, "foo_1")))
+            .await
+            .unwrap();
+        tx.apply(step(&foo, edit(0, 5, 0, 5, "_2"))).await.unwrap();
+        assert_eq!(store.get(&foo).unwrap().text, "foo_1_2\n");
+        assert!(matches!(
+            tx.apply(step(&bar, edit(0, 0, 0, 0, "x"))).await,
+            Err(TransactionError::Rejected(None))
+        ));
+        assert_eq!(tx.len(), 2);
+
+        tx.rollback().await.unwrap();
+        assert!(tx.is_empty());
+        let doc = store.get(&foo).unwrap();
+        assert_eq!(doc.text, "foo\n");
+        assert_eq!(doc.version, 5);
+
+        // Diverged documents are not rolled back.
+        tx.apply(step(&foo, edit(0, 0, 0, 0, "x"))).await.unwrap();
+        store.did_change(DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(foo.clone(), 7),
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: "user\n".into(),
+            }],
+        });
+        assert!(matches!(
+            tx.rollback().await,
+            Err(TransactionError::Diverged(uri)) if uri == foo
+        ));
+        assert_eq!(tx.len(), 1);
+        drop(tx);
+        assert_eq!(editor.await.unwrap(), ["test"; 6]);
+    }
+}
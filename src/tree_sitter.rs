@@ -0,0 +1,541 @@
+//! Integration with [`tree-sitter`](https://crates.io/crates/tree-sitter).
+//!
+//! *Only applies to Language Servers.*
+//!
+//! [`SyntaxTrees`] keeps the text and the syntax tree of each opened document in sync with
+//! `textDocument/didOpen`, `textDocument/didChange` and `textDocument/didClose`. Incremental
+//! changes are applied as [`InputEdit`]s, so that re-parsing reuses unchanged subtrees. Handlers
+//! get immutable [`SyntaxSnapshot`]s via [`SyntaxTrees::get`].
+//!
+//! On top of that, [`SyntaxTrees::register_providers`] registers ready-made handlers of:
+//! - `textDocument/foldingRange` and `textDocument/selectionRange`, via the [`crate::syntax`]
+//!   utilities on named nodes.
+//! - `textDocument/semanticTokens/full`, from a highlight query set by
+//!   [`SyntaxTrees::highlights`], like `highlights.scm` shipped with most grammars. Each capture
+//!   name is mapped to the token type of its first segment, eg. `@function.method` to `function`.
+//!   Captures starting with `_` are ignored.
+//!
+//! Parsing is done synchronously in notification handlers, which is usually fast enough for
+//! incremental changes.
+//!
+//! ```no_run
+//! # use async_lsp::tree_sitter::SyntaxTrees;
+//! # use async_lsp::lsp_types::{ClientCapabilities, ServerCapabilities};
+//! # use async_lsp::router::Router;
+//! # fn work(language: tree_sitter::Language, caps: &ClientCapabilities, router: &mut Router<()>) {
+//! let trees = SyntaxTrees::new(language)
+//!     .unwrap()
+//!     .highlights("(comment) @comment")
+//!     .unwrap();
+//! let mut server_caps = ServerCapabilities::default();
+//! trees.fill_capabilities(&mut server_caps);
+//! trees.register(router);
+//! trees.register_providers(router, caps);
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::future::ready;
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex};
+
+use ::tree_sitter::{
+    InputEdit, Language, LanguageError, Node, Parser, Point, Query, QueryCursor, QueryError, Tree,
+};
+use lsp_types::notification::{DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument};
+use lsp_types::request::{FoldingRangeRequest, SelectionRangeRequest, SemanticTokensFullRequest};
+use lsp_types::{
+    ClientCapabilities, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, FoldingRange, FoldingRangeKind, Position, Range, SelectionRange,
+    SelectionRangeProviderCapability, SemanticToken, SemanticTokenType, SemanticTokens,
+    SemanticTokensFullOptions, SemanticTokensLegend, SemanticTokensOptions, SemanticTokensResult,
+    SemanticTokensServerCapabilities, ServerCapabilities, Url,
+};
+
+use crate::pipeline::offset_of;
+use crate::router::Router;
+use crate::syntax::{folding_ranges, selection_ranges, FoldableNode, FoldingLimits};
+use crate::ResponseError;
+
+/// An immutable snapshot of a parsed document.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct SyntaxSnapshot {
+    /// The document URI.
+    pub uri: Url,
+    /// The version of this snapshot.
+    pub version: i32,
+    /// The full text of this snapshot.
+    pub text: Arc<str>,
+    /// The syntax tree of `text`.
+    pub tree: Tree,
+}
+
+impl SyntaxSnapshot {
+    /// Get the root node.
+    #[must_use]
+    pub fn root(&self) -> SyntaxNode<'_> {
+        SyntaxNode::new(self.tree.root_node(), &self.text)
+    }
+
+    /// Compute folding ranges of named nodes, excluding the root.
+    #[must_use]
+    pub fn folding_ranges(&self, limits: &FoldingLimits) -> Vec<FoldingRange> {
+        folding_ranges(self.root().children(), limits)
+    }
+
+    /// Compute selection ranges of named nodes for each of `positions`.
+    #[must_use]
+    pub fn selection_ranges(&self, positions: &[Position]) -> Vec<SelectionRange> {
+        selection_ranges(&self.root(), positions)
+    }
+}
+
+/// A syntax node with its source text, which implements [`FoldableNode`] with named children.
+///
+/// Nodes whose kinds contain `comment` are folded as [`FoldingRangeKind::Comment`].
+#[derive(Debug, Clone, Copy)]
+pub struct SyntaxNode<'a> {
+    node: Node<'a>,
+    text: &'a str,
+}
+
+impl<'a> SyntaxNode<'a> {
+    /// Wrap a node of the tree parsed from `text`.
+    #[must_use]
+    pub fn new(node: Node<'a>, text: &'a str) -> Self {
+        Self { node, text }
+    }
+
+    /// Get the underlying node.
+    #[must_use]
+    pub fn node(&self) -> Node<'a> {
+        self.node
+    }
+}
+
+impl<'a> FoldableNode for SyntaxNode<'a> {
+    type Children = std::vec::IntoIter<Self>;
+
+    fn range(&self) -> Range {
+        Range::new(
+            to_position(
+                self.text,
+                self.node.start_byte(),
+                self.node.start_position(),
+            ),
+            to_position(self.text, self.node.end_byte(), self.node.end_position()),
+        )
+    }
+
+    fn children(&self) -> Self::Children {
+        let mut cursor = self.node.walk();
+        self.node
+            .named_children(&mut cursor)
+            .map(|node| Self::new(node, self.text))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn folding_kind(&self) -> Option<FoldingRangeKind> {
+        self.node
+            .kind()
+            .contains("comment")
+            .then_some(FoldingRangeKind::Comment)
+    }
+}
+
+/// Convert a byte offset and its tree-sitter point into a UTF-16 based position.
+fn to_position(text: &str, byte: usize, point: Point) -> Position {
+    let line_start = byte - point.column;
+    let character = text[line_start..byte].encode_utf16().count();
+    Position::new(point.row as u32, character as u32)
+}
+
+/// Get the tree-sitter point of a byte offset.
+fn to_point(text: &str, byte: usize) -> Point {
+    let before = &text[..byte];
+    match before.rfind('\n') {
+        Some(i) => Point::new(before.matches('\n').count(), byte - i - 1),
+        None => Point::new(0, byte),
+    }
+}
+
+/// The highlight query and the mapping from its captures to semantic token types.
+struct Highlights {
+    query: Query,
+    legend: Vec<SemanticTokenType>,
+    /// The index into `legend` of each capture.
+    capture_types: Vec<Option<u32>>,
+}
+
+/// The cheaply cloneable store of syntax trees of opened documents.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone)]
+#[must_use]
+pub struct SyntaxTrees {
+    language: Language,
+    parser: Arc<Mutex<Parser>>,
+    highlights: Option<Arc<Highlights>>,
+    docs: Arc<Mutex<HashMap<Url, SyntaxSnapshot>>>,
+}
+
+impl fmt::Debug for SyntaxTrees {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyntaxTrees")
+            .field("language", &self.language)
+            .field("legend", &self.highlights.as_ref().map(|hl| &hl.legend))
+            .field("documents", &self.docs.lock().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SyntaxTrees {
+    /// Create an empty store parsing documents as `language`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the version of `language` is incompatible with the linked tree-sitter library.
+    pub fn new(language: Language) -> Result<Self, LanguageError> {
+        let mut parser = Parser::new();
+        parser.set_language(language)?;
+        Ok(Self {
+            language,
+            parser: Arc::new(Mutex::new(parser)),
+            highlights: None,
+            docs: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Set the highlight query for semantic tokens.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `source` is not a valid query of the language.
+    pub fn highlights(mut self, source: &str) -> Result<Self, QueryError> {
+        let query = Query::new(self.language, source)?;
+        let mut legend = Vec::<SemanticTokenType>::new();
+        let capture_types = query
+            .capture_names()
+            .iter()
+            .map(|name| {
+                if name.starts_with('_') {
+                    return None;
+                }
+                let ty = name.split('.').next().unwrap_or(name);
+                let idx = match legend.iter().position(|t| t.as_str() == ty) {
+                    Some(idx) => idx,
+                    None => {
+                        legend.push(SemanticTokenType::from(ty.to_owned()));
+                        legend.len() - 1
+                    }
+                };
+                Some(idx as u32)
+            })
+            .collect();
+        self.highlights = Some(Arc::new(Highlights {
+            query,
+            legend,
+            capture_types,
+        }));
+        Ok(self)
+    }
+
+    /// Get the semantic tokens legend, if the highlight query is set.
+    #[must_use]
+    pub fn legend(&self) -> Option<SemanticTokensLegend> {
+        self.highlights.as_ref().map(|hl| SemanticTokensLegend {
+            token_types: hl.legend.clone(),
+            token_modifiers: Vec::new(),
+        })
+    }
+
+    /// Advertise capabilities of providers registered by [`SyntaxTrees::register_providers`].
+    pub fn fill_capabilities(&self, caps: &mut ServerCapabilities) {
+        caps.folding_range_provider = Some(true.into());
+        caps.selection_range_provider = Some(SelectionRangeProviderCapability::Simple(true));
+        if let Some(legend) = self.legend() {
+            caps.semantic_tokens_provider = Some(
+                SemanticTokensServerCapabilities::SemanticTokensOptions(SemanticTokensOptions {
+                    legend,
+                    full: Some(SemanticTokensFullOptions::Bool(true)),
+                    ..SemanticTokensOptions::default()
+                }),
+            );
+        }
+    }
+
+    /// Register handlers of `textDocument/didOpen`, `textDocument/didChange` and
+    /// `textDocument/didClose` to `router`, which forward to this store.
+    pub fn register<St, Error>(&self, router: &mut Router<St, Error>)
+    where
+        Error: From<ResponseError> + Send + 'static,
+    {
+        let this = self.clone();
+        router.notification::<DidOpenTextDocument>(move |_, params| {
+            this.did_open(params);
+            ControlFlow::Continue(())
+        });
+        let this = self.clone();
+        router.notification::<DidChangeTextDocument>(move |_, params| {
+            this.did_change(params);
+            ControlFlow::Continue(())
+        });
+        let this = self.clone();
+        router.notification::<DidCloseTextDocument>(move |_, params| {
+            this.did_close(params);
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Register handlers of `textDocument/foldingRange`, `textDocument/selectionRange`, and
+    /// `textDocument/semanticTokens/full` if the highlight query is set, to `router`.
+    ///
+    /// Folding ranges respect the limits in client capabilities `caps`.
+    pub fn register_providers<St, Error>(
+        &self,
+        router: &mut Router<St, Error>,
+        caps: &ClientCapabilities,
+    ) where
+        Error: From<ResponseError> + Send + 'static,
+    {
+        let this = self.clone();
+        let limits = FoldingLimits::new(caps);
+        router.request::<FoldingRangeRequest, _>(move |_, params| {
+            let doc = this.get(&params.text_document.uri);
+            ready(Ok(doc.map(|doc| doc.folding_ranges(&limits))))
+        });
+        let this = self.clone();
+        router.request::<SelectionRangeRequest, _>(move |_, params| {
+            let doc = this.get(&params.text_document.uri);
+            ready(Ok(doc.map(|doc| doc.selection_ranges(&params.positions))))
+        });
+        if self.highlights.is_some() {
+            let this = self.clone();
+            router.request::<SemanticTokensFullRequest, _>(move |_, params| {
+                let tokens = this
+                    .get(&params.text_document.uri)
+                    .and_then(|doc| this.semantic_tokens(&doc))
+                    .map(SemanticTokensResult::Tokens);
+                ready(Ok(tokens))
+            });
+        }
+    }
+
+    /// Get the latest snapshot of an opened document.
+    #[must_use]
+    pub fn get(&self, uri: &Url) -> Option<SyntaxSnapshot> {
+        self.docs.lock().unwrap().get(uri).cloned()
+    }
+
+    /// Handle `textDocument/didOpen`, and parse the document.
+    pub fn did_open(&self, params: DidOpenTextDocumentParams) {
+        let doc = params.text_document;
+        let tree = self.parse(&doc.text, None);
+        self.docs.lock().unwrap().insert(
+            doc.uri.clone(),
+            SyntaxSnapshot {
+                uri: doc.uri,
+                version: doc.version,
+                text: doc.text.into(),
+                tree,
+            },
+        );
+    }
+
+    /// Handle `textDocument/didChange`, and re-parse the document incrementally.
+    ///
+    /// Changes of documents which are not opened are ignored.
+    pub fn did_change(&self, params: DidChangeTextDocumentParams) {
+        let mut snapshot = match self.get(&params.text_document.uri) {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+        let mut text = String::from(&*snapshot.text);
+        // Reset on full changes.
+        let mut old_tree = Some(snapshot.tree);
+        for change in params.content_changes {
+            let range = match change.range {
+                Some(range) => range,
+                None => {
+                    text = change.text;
+                    old_tree = None;
+                    continue;
+                }
+            };
+            let start_byte = offset_of(&text, range.start);
+            let old_end_byte = offset_of(&text, range.end).max(start_byte);
+            let start_position = to_point(&text, start_byte);
+            let old_end_position = to_point(&text, old_end_byte);
+            text.replace_range(start_byte..old_end_byte, &change.text);
+            let new_end_byte = start_byte + change.text.len();
+            if let Some(tree) = &mut old_tree {
+                tree.edit(&InputEdit {
+                    start_byte,
+                    old_end_byte,
+                    new_end_byte,
+                    start_position,
+                    old_end_position,
+                    new_end_position: to_point(&text, new_end_byte),
+                });
+            }
+        }
+        snapshot.tree = self.parse(&text, old_tree.as_ref());
+        snapshot.version = params.text_document.version;
+        snapshot.text = text.into();
+        self.docs
+            .lock()
+            .unwrap()
+            .insert(snapshot.uri.clone(), snapshot);
+    }
+
+    /// Handle `textDocument/didClose`.
+    pub fn did_close(&self, params: DidCloseTextDocumentParams) {
+        self.docs.lock().unwrap().remove(&params.text_document.uri);
+    }
+
+    fn parse(&self, text: &str, old_tree: Option<&Tree>) -> Tree {
+        self.parser
+            .lock()
+            .unwrap()
+            .parse(text, old_tree)
+            .expect("No timeout or cancellation is set")
+    }
+
+    /// Compute semantic tokens of a document from the highlight query.
+    ///
+    /// Returns `None` if the highlight query is not set. Overlapping captures are resolved by
+    /// keeping the earlier one, and multi-line captures are split into one token per line.
+    #[must_use]
+    pub fn semantic_tokens(&self, doc: &SyntaxSnapshot) -> Option<SemanticTokens> {
+        let hl = self.highlights.as_ref()?;
+        let text = &*doc.text;
+        let mut cursor = QueryCursor::new();
+        let mut data = Vec::new();
+        let (mut last_end, mut prev) = (0, Position::new(0, 0));
+        for (m, idx) in cursor.captures(&hl.query, doc.tree.root_node(), text.as_bytes()) {
+            let capture = m.captures[idx];
+            let token_type = match hl.capture_types[capture.index as usize] {
+                Some(token_type) => token_type,
+                None => continue,
+            };
+            let (start, end) = (capture.node.start_byte(), capture.node.end_byte());
+            if start < last_end {
+                continue;
+            }
+            last_end = end;
+            let mut line_start = start;
+            for segment in text[start..end].split('\n') {
+                let pos = to_position(text, line_start, to_point(text, line_start));
+                let length = segment.encode_utf16().count() as u32;
+                line_start += segment.len() + 1;
+                if length == 0 {
+                    continue;
+                }
+                let delta_line = pos.line - prev.line;
+                data.push(SemanticToken {
+                    delta_line,
+                    delta_start: match delta_line {
+                        0 => pos.character - prev.character,
+                        _ => pos.character,
+                    },
+                    length,
+                    token_type,
+                    token_modifiers_bitset: 0,
+                });
+                prev = pos;
+            }
+        }
+        Some(SemanticTokens {
+            result_id: None,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::{
+        TextDocumentContentChangeEvent, TextDocumentIdentifier, TextDocumentItem,
+        VersionedTextDocumentIdentifier,
+    };
+
+    use super::*;
+
+    fn token(delta_line: u32, delta_start: u32, length: u32, token_type: u32) -> SemanticToken {
+        SemanticToken {
+            delta_line,
+            delta_start,
+            length,
+            token_type,
+            token_modifiers_bitset: 0,
+        }
+    }
+
+    #[test]
+    fn incremental_parse() {
+        let uri = Url::parse("file:///main.rs").unwrap();
+        let trees = SyntaxTrees::new(tree_sitter_rust::language())
+            .unwrap()
+            .highlights(r#"["fn" "let"] @keyword (integer_literal) @number (_) @_ignored"#)
+            .unwrap();
+        assert_eq!(
+            trees.legend().unwrap().token_types,
+            [SemanticTokenType::KEYWORD, SemanticTokenType::NUMBER],
+        );
+
+        trees.did_open(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(
+                uri.clone(),
+                "rust".into(),
+                1,
+                "fn main() {\n    let x = 1;\n}\n".into(),
+            ),
+        });
+        let doc = trees.get(&uri).unwrap();
+        assert_eq!(
+            trees.semantic_tokens(&doc).unwrap().data,
+            [token(0, 0, 2, 0), token(1, 4, 3, 0), token(0, 8, 1, 1)],
+        );
+
+        // `1` -> `"😀"; 42`
+        trees.did_change(DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(uri.clone(), 2),
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: Some(Range::new(Position::new(1, 12), Position::new(1, 13))),
+                range_length: None,
+                text: "\"😀\"; 42".into(),
+            }],
+        });
+        let doc = trees.get(&uri).unwrap();
+        assert_eq!(doc.version, 2);
+        assert_eq!(&*doc.text, "fn main() {\n    let x = \"😀\"; 42;\n}\n");
+        assert!(!doc.tree.root_node().has_error());
+        assert_eq!(
+            trees.semantic_tokens(&doc).unwrap().data,
+            [token(0, 0, 2, 0), token(1, 4, 3, 0), token(0, 14, 2, 1)],
+        );
+
+        let folds = doc.folding_ranges(&FoldingLimits::default());
+        assert_eq!(
+            folds
+                .iter()
+                .map(|r| (r.start_line, r.start_character, r.end_line, r.end_character))
+                .collect::<Vec<_>>(),
+            [(0, Some(0), 2, Some(1)), (0, Some(10), 2, Some(1))],
+        );
+
+        // Inside the string literal, after the surrogate pair.
+        let sel = &doc.selection_ranges(&[Position::new(1, 15)])[0];
+        assert_eq!(
+            sel.range,
+            Range::new(Position::new(1, 12), Position::new(1, 16)),
+        );
+
+        trees.did_close(DidCloseTextDocumentParams {
+            text_document: TextDocumentIdentifier::new(uri.clone()),
+        });
+        assert!(trees.get(&uri).is_none());
+    }
+}
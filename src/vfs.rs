@@ -0,0 +1,153 @@
+//! Virtual file system abstraction.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Documents are identified by URIs, which are not always files on disk. Besides `file:`
+//! documents, servers may need to serve `untitled:` buffers, generated sources, documents inside
+//! archives (`jar:`), or documents of custom schemes provided by editor extensions. [`Vfs`]
+//! abstracts reading documents by URI, so that the rest of the server handles them uniformly.
+//!
+//! - [`DiskVfs`] reads `file:` URIs from the local file system.
+//! - [`MemoryVfs`] keeps documents in memory, for non-disk documents or tests.
+//!
+//! [`DocumentPipeline::read`][crate::pipeline::DocumentPipeline::read] layers opened documents
+//! over a [`Vfs`], so that unsaved editor contents take precedence.
+use std::collections::HashMap;
+use std::io;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use lsp_types::Url;
+
+/// A read-only file system of documents identified by URIs.
+///
+/// See [module level documentations](self) for details.
+pub trait Vfs: Send + Sync {
+    /// Read the full content of a document.
+    ///
+    /// # Errors
+    ///
+    /// - [`io::ErrorKind::NotFound`] if the document does not exist.
+    /// - [`io::ErrorKind::Unsupported`] if the URI scheme is not supported.
+    /// - Other errors from the underlying storage.
+    fn read(&self, uri: &Url) -> io::Result<Vec<u8>>;
+
+    /// Read the full content of a document as UTF-8 text.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Vfs::read`], or [`io::ErrorKind::InvalidData`] if the content is not valid
+    /// UTF-8.
+    fn read_to_string(&self, uri: &Url) -> io::Result<String> {
+        String::from_utf8(self.read(uri)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Get the last modification time of a document, if available.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Vfs::read`].
+    fn modified(&self, uri: &Url) -> io::Result<Option<SystemTime>>;
+}
+
+/// The [`Vfs`] of the local file system, which only supports `file:` URIs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiskVfs;
+
+fn to_file_path(uri: &Url) -> io::Result<std::path::PathBuf> {
+    uri.to_file_path().map_err(|()| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("not a local file URI: {uri}"),
+        )
+    })
+}
+
+impl Vfs for DiskVfs {
+    fn read(&self, uri: &Url) -> io::Result<Vec<u8>> {
+        std::fs::read(to_file_path(uri)?)
+    }
+
+    fn modified(&self, uri: &Url) -> io::Result<Option<SystemTime>> {
+        let meta = std::fs::metadata(to_file_path(uri)?)?;
+        Ok(meta.modified().ok())
+    }
+}
+
+/// The in-memory [`Vfs`] supporting any URI schemes.
+#[derive(Debug, Default)]
+pub struct MemoryVfs {
+    files: RwLock<HashMap<Url, (Vec<u8>, SystemTime)>>,
+}
+
+impl MemoryVfs {
+    /// Create an empty file system.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert or replace a document. Its modification time is set to now.
+    pub fn insert(&self, uri: Url, content: impl Into<Vec<u8>>) {
+        let mut files = self.files.write().unwrap();
+        files.insert(uri, (content.into(), SystemTime::now()));
+    }
+
+    /// Remove a document, returning whether it existed.
+    pub fn remove(&self, uri: &Url) -> bool {
+        self.files.write().unwrap().remove(uri).is_some()
+    }
+}
+
+impl Vfs for MemoryVfs {
+    fn read(&self, uri: &Url) -> io::Result<Vec<u8>> {
+        let files = self.files.read().unwrap();
+        match files.get(uri) {
+            Some((content, _)) => Ok(content.clone()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn modified(&self, uri: &Url) -> io::Result<Option<SystemTime>> {
+        let files = self.files.read().unwrap();
+        match files.get(uri) {
+            Some((_, modified)) => Ok(Some(*modified)),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disk_and_memory() {
+        let path = std::env::temp_dir().join(format!("async-lsp-vfs-{}", std::process::id()));
+        std::fs::write(&path, "hello").unwrap();
+        let file = Url::from_file_path(&path).unwrap();
+        assert_eq!(DiskVfs.read_to_string(&file).unwrap(), "hello");
+        assert!(DiskVfs.modified(&file).unwrap().is_some());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            DiskVfs.read(&file).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        let untitled = Url::parse("untitled:Untitled-1").unwrap();
+        assert_eq!(
+            DiskVfs.read(&untitled).unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+
+        let vfs = MemoryVfs::new();
+        vfs.insert(untitled.clone(), "world");
+        assert_eq!(vfs.read_to_string(&untitled).unwrap(), "world");
+        assert!(vfs.remove(&untitled));
+        assert_eq!(
+            vfs.read(&untitled).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! WebSocket transport.
+//!
+//! *Applies to both Language Servers and Language Clients.*
+//!
+//! Browser-based editors, like Monaco, can only talk to Language Servers via WebSocket.
+//! [`MainLoop::run_websocket`] drives the main loop over a stream of text frames, where each frame
+//! carries exactly one JSON-RPC message without base protocol headers.
+//!
+//! This module does not depend on any specific WebSocket implementation. Frames are plain
+//! [`String`]s, so any implementation can be adapted with a few combinators. For example, with
+//! [`tokio-tungstenite`](https://crates.io/crates/tokio-tungstenite):
+//!
+//! ```ignore
+//! use futures::{future, SinkExt, StreamExt, TryStreamExt};
+//! use tokio_tungstenite::tungstenite::{Error, Message};
+//!
+//! let ws = tokio_tungstenite::accept_async(tcp_stream).await?;
+//! let (tx, rx) = ws.split();
+//! let input = rx
+//!     .try_filter_map(|msg| future::ready(Ok(msg.into_text().ok())))
+//!     .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err));
+//! let output = tx.with(|text| future::ready(Ok::<_, Error>(Message::Text(text))));
+//! mainloop.run_websocket(input, output).await?;
+//! ```
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures::io::AsyncWrite;
+use futures::{Sink, Stream, TryStreamExt};
+use pin_project_lite::pin_project;
+use serde_json::Value as JsonValue;
+
+use crate::codec::{parse_header_part, CONTENT_LENGTH};
+use crate::{LspService, MainLoop, ResponseError, Result};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+impl<S> MainLoop<S>
+where
+    S: LspService<Response = JsonValue>,
+    ResponseError: From<S::Error>,
+{
+    /// Drive the service main loop over WebSocket text frames.
+    ///
+    /// See [module level documentations](crate::websocket) for details.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`MainLoop::run`]. Errors of `output` are converted into `Error::Io`.
+    pub async fn run_websocket<I, O>(self, input: I, output: O) -> Result<()>
+    where
+        I: Stream<Item = io::Result<String>>,
+        O: Sink<String>,
+        O::Error: Into<BoxError>,
+    {
+        let input = input
+            .map_ok(|text| {
+                let mut buf = format!("{CONTENT_LENGTH}: {}\r\n\r\n", text.len()).into_bytes();
+                buf.extend_from_slice(text.as_bytes());
+                buf
+            })
+            .into_async_read();
+        let output = FrameWriter {
+            sink: output,
+            buf: Vec::new(),
+        };
+        self.run(input, output).await
+    }
+}
+
+pin_project! {
+    /// The writer splitting outgoing bytes into frames.
+    struct FrameWriter<Si> {
+        #[pin]
+        sink: Si,
+        buf: Vec<u8>,
+    }
+}
+
+fn to_io_error(err: impl Into<BoxError>) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+impl<Si> AsyncWrite for FrameWriter<Si>
+where
+    Si: Sink<String>,
+    Si::Error: Into<BoxError>,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.project().buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        // Outgoing messages are always well-formed.
+        while let Some((start, len)) = parse_header_part(this.buf).map_err(to_io_error)? {
+            if this.buf.len() - start < len {
+                break;
+            }
+            ready!(this.sink.as_mut().poll_ready(cx)).map_err(to_io_error)?;
+            let text = String::from_utf8(this.buf[start..start + len].to_vec())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            this.buf.drain(..start + len);
+            this.sink.as_mut().start_send(text).map_err(to_io_error)?;
+        }
+        this.sink.poll_flush(cx).map_err(to_io_error)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        self.project().sink.poll_close(cx).map_err(to_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use lsp_types::request::Shutdown;
+    use serde_json::json;
+
+    use super::*;
+    use crate::router::Router;
+
+    #[tokio::test]
+    async fn text_frames() {
+        let (mainloop, _client) = MainLoop::new_server(|_| {
+            let mut router = Router::new(());
+            router.request::<Shutdown, _>(|_, ()| std::future::ready(Ok(())));
+            router
+        });
+        let (in_tx, in_rx) = mpsc::unbounded();
+        let (out_tx, mut out_rx) = mpsc::unbounded();
+        let task = tokio::spawn(mainloop.run_websocket(in_rx, out_tx));
+
+        for id in 1..=2 {
+            let req = json!({ "jsonrpc": "2.0", "id": id, "method": "shutdown" });
+            in_tx.unbounded_send(Ok(req.to_string())).unwrap();
+            let frame = out_rx.next().await.unwrap();
+            let resp: JsonValue = serde_json::from_str(&frame).unwrap();
+            assert_eq!(resp, json!({ "jsonrpc": "2.0", "id": id, "result": null }));
+        }
+
+        drop(in_tx);
+        assert!(matches!(task.await.unwrap(), Err(crate::Error::Eof)));
+    }
+}
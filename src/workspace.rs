@@ -0,0 +1,552 @@
+//! Multi-root workspace helpers.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! URIs from different clients and platforms are inconsistently encoded. For example, the same
+//! Windows file can be sent as `file:///C:/foo`, `file:///c%3A/foo` or `file:///c:/foo`, and the
+//! host of UNC paths like `file://Server/share` is case-insensitive. Comparing them literally is a
+//! perennial source of cross-platform bugs. The helpers here compare URIs by their normalized and
+//! percent-decoded path segments:
+//!
+//! - [`normalize_uri`] converts a `file:` URI into a canonical form.
+//! - [`owning_folder`] finds the innermost workspace folder containing a document.
+//! - [`relative_path`] computes the folder-relative path of a document.
+//! - [`uri_to_path`] and [`path_to_uri`] convert between [`Url`] and [`PathBuf`] consistently.
+//!
+//! [`WorkspaceFolders`] is a cheaply cloneable handle of the current workspace folders, recorded
+//! from `initialize` parameters and kept updated via `workspace/didChangeWorkspaceFolders`. It can
+//! be updated either by the [`WorkspaceFoldersSync`] middleware, which leaves the messages to the
+//! inner service as well, or manually via [`WorkspaceFolders::initialize`] and
+//! [`WorkspaceFolders::register`]. If a [`ClientSocket`] is set via [`WorkspaceFolders::events`],
+//! each change is also emitted as a [`WorkspaceFoldersChanged`] event.
+//!
+//! ```
+//! # use async_lsp::router::Router;
+//! # use async_lsp::workspace::{WorkspaceFolders, WorkspaceFoldersChanged, WorkspaceFoldersSyncLayer};
+//! # use async_lsp::lsp_types::request::HoverRequest;
+//! # use std::ops::ControlFlow;
+//! # use tower::ServiceBuilder;
+//! # fn work(client: async_lsp::ClientSocket) {
+//! let folders = WorkspaceFolders::new().events(client);
+//! let mut router: Router<WorkspaceFolders> = Router::new(folders.clone());
+//! router
+//!     .request::<HoverRequest, _>(|folders, params| {
+//!         let uri = &params.text_document_position_params.text_document.uri;
+//!         let folder = folders.owning_folder(uri);
+//!         async move { Ok(None) }
+//!     })
+//!     .event::<WorkspaceFoldersChanged>(|_, event| {
+//!         println!("Added: {:?}, removed: {:?}", event.added, event.removed);
+//!         ControlFlow::Continue(())
+//!     });
+//! let service = ServiceBuilder::new()
+//!     .layer(WorkspaceFoldersSyncLayer::new(folders))
+//!     .service(router);
+//! # }
+//! ```
+use std::fmt;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+
+use lsp_types::notification::{DidChangeWorkspaceFolders, Notification};
+use lsp_types::request::{Initialize, Request};
+use lsp_types::{DidChangeWorkspaceFoldersParams, InitializeParams, Url, WorkspaceFolder};
+use serde::Deserialize;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::router::Router;
+use crate::{
+    AnyEvent, AnyNotification, AnyRequest, ClientSocket, EventHints, LspService, ResponseError,
+    Result,
+};
+
+/// Normalize a `file:` URI: decode percent-encoded drive letter colons, lowercase drive letters,
+/// lowercase the host, and strip the trailing slash. Other URIs are returned unchanged.
+#[must_use]
+pub fn normalize_uri(uri: &Url) -> Url {
+    if uri.scheme() != "file" {
+        return uri.clone();
+    }
+    let mut uri = uri.clone();
+    let path = uri.path();
+    let mut normalized = String::with_capacity(path.len());
+    let rest = match drive_letter(path) {
+        Some((letter, rest)) => {
+            normalized.push('/');
+            normalized.push(letter.to_ascii_lowercase());
+            normalized.push(':');
+            rest
+        }
+        None => path,
+    };
+    normalized.push_str(rest);
+    if normalized.len() > 1 && normalized.ends_with('/') && !normalized.ends_with(":/") {
+        normalized.pop();
+    }
+    uri.set_path(&normalized);
+    if let Some(host) = uri.host_str().map(str::to_ascii_lowercase) {
+        // Always succeeds for `file:` URIs with a host.
+        let _: Result<_, _> = uri.set_host(Some(&host));
+    }
+    uri
+}
+
+/// Split `/C:/foo` or `/c%3A/foo` into the drive letter and the rest `/foo`.
+fn drive_letter(path: &str) -> Option<(char, &str)> {
+    let path = path.strip_prefix('/')?;
+    let letter = path.chars().next().filter(char::is_ascii_alphabetic)?;
+    let rest = &path[1..];
+    let rest = rest
+        .strip_prefix(':')
+        .or_else(|| rest.strip_prefix("%3A"))
+        .or_else(|| rest.strip_prefix("%3a"))?;
+    (rest.is_empty() || rest.starts_with('/')).then_some((letter, rest))
+}
+
+/// Get the normalized and percent-decoded path segments of `uri`, with empty segments removed.
+pub(crate) fn segments(uri: &Url) -> Vec<String> {
+    normalize_uri(uri)
+        .path_segments()
+        .into_iter()
+        .flatten()
+        .filter(|seg| !seg.is_empty())
+        .map(percent_decode)
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Get the path segments of `uri` relative to `base`, if `uri` is inside `base`.
+fn strip_base(base: &Url, uri: &Url) -> Option<Vec<String>> {
+    if base.scheme() != uri.scheme()
+        || !base
+            .host_str()
+            .unwrap_or_default()
+            .eq_ignore_ascii_case(uri.host_str().unwrap_or_default())
+        || base.port() != uri.port()
+    {
+        return None;
+    }
+    let base = segments(base);
+    let mut uri = segments(uri);
+    if uri.len() < base.len() || uri[..base.len()] != base[..] {
+        return None;
+    }
+    Some(uri.split_off(base.len()))
+}
+
+/// Find the innermost workspace folder containing `uri`.
+#[must_use]
+pub fn owning_folder<'a>(folders: &'a [WorkspaceFolder], uri: &Url) -> Option<&'a WorkspaceFolder> {
+    folders
+        .iter()
+        .filter_map(|folder| Some((folder, strip_base(&folder.uri, uri)?.len())))
+        .min_by_key(|(_, rest_len)| *rest_len)
+        .map(|(folder, _)| folder)
+}
+
+/// Get the percent-decoded path of `uri` relative to `base`, separated by `/`.
+///
+/// Returns an empty string if they are the same, or `None` if `uri` is not inside `base`.
+#[must_use]
+pub fn relative_path(base: &Url, uri: &Url) -> Option<String> {
+    strip_base(base, uri).map(|segs| segs.join("/"))
+}
+
+/// Convert a `file:` URI into a local file path, after [normalization](normalize_uri).
+///
+/// Returns `None` if it is not a `file:` URI, or it is not a valid path on the current platform.
+#[must_use]
+pub fn uri_to_path(uri: &Url) -> Option<PathBuf> {
+    if uri.scheme() != "file" {
+        return None;
+    }
+    normalize_uri(uri).to_file_path().ok()
+}
+
+/// Convert an absolute local file path into a [normalized](normalize_uri) `file:` URI.
+///
+/// Returns `None` if the path is not absolute.
+#[must_use]
+pub fn path_to_uri(path: &Path) -> Option<Url> {
+    Url::from_file_path(path)
+        .ok()
+        .map(|uri| normalize_uri(&uri))
+}
+
+/// The event of changed workspace folders, emitted by [`WorkspaceFolders`] if
+/// [`WorkspaceFolders::events`] is set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WorkspaceFoldersChanged {
+    /// Newly added folders, or all folders on initialization.
+    pub added: Vec<WorkspaceFolder>,
+    /// Removed folders.
+    pub removed: Vec<WorkspaceFolder>,
+}
+
+/// The cheaply cloneable handle of the current workspace folders.
+///
+/// See [module level documentations](self) for details.
+#[derive(Clone, Default)]
+#[must_use]
+pub struct WorkspaceFolders {
+    folders: Arc<RwLock<Vec<WorkspaceFolder>>>,
+    client: Option<ClientSocket>,
+}
+
+impl fmt::Debug for WorkspaceFolders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WorkspaceFolders")
+            .field("folders", &self.folders.read().unwrap())
+            .finish_non_exhaustive()
+    }
+}
+
+impl WorkspaceFolders {
+    /// Create an empty set of folders.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Emit a [`WorkspaceFoldersChanged`] event via `client` on each change.
+    pub fn events(mut self, client: ClientSocket) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Get the current workspace folders, in the order they are added.
+    #[must_use]
+    pub fn workspace_folders(&self) -> Vec<WorkspaceFolder> {
+        self.folders.read().unwrap().clone()
+    }
+
+    /// Find the innermost workspace folder containing `uri`. See [`owning_folder`].
+    #[must_use]
+    pub fn owning_folder(&self, uri: &Url) -> Option<WorkspaceFolder> {
+        owning_folder(&self.folders.read().unwrap(), uri).cloned()
+    }
+
+    /// Handle `initialize`, replacing all folders by `workspaceFolders`, or by the deprecated
+    /// `rootUri` if the former is absent.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::ServiceStopped`][crate::Error::ServiceStopped] if the event cannot be emitted.
+    pub fn initialize(&self, params: &InitializeParams) -> Result<()> {
+        #[allow(deprecated)]
+        let folders = match (&params.workspace_folders, &params.root_uri) {
+            (Some(folders), _) => folders.clone(),
+            (None, Some(root)) => vec![WorkspaceFolder {
+                uri: root.clone(),
+                name: segments(root).pop().unwrap_or_default(),
+            }],
+            (None, None) => Vec::new(),
+        };
+        let removed = std::mem::replace(&mut *self.folders.write().unwrap(), folders.clone());
+        self.emit(WorkspaceFoldersChanged {
+            added: folders,
+            removed,
+        })
+    }
+
+    /// Handle `workspace/didChangeWorkspaceFolders`. Folders are compared by their
+    /// [normalized](normalize_uri) URIs, and removing absent folders or adding present ones is
+    /// ignored.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::ServiceStopped`][crate::Error::ServiceStopped] if the event cannot be emitted.
+    pub fn did_change(&self, params: DidChangeWorkspaceFoldersParams) -> Result<()> {
+        let same = |a: &WorkspaceFolder, b: &WorkspaceFolder| {
+            normalize_uri(&a.uri) == normalize_uri(&b.uri)
+        };
+        let mut folders = self.folders.write().unwrap();
+        let mut removed = Vec::new();
+        for folder in params.event.removed {
+            if let Some(pos) = folders.iter().position(|f| same(f, &folder)) {
+                removed.push(folders.remove(pos));
+            }
+        }
+        let mut added = Vec::new();
+        for folder in params.event.added {
+            if !folders.iter().any(|f| same(f, &folder)) {
+                folders.push(folder.clone());
+                added.push(folder);
+            }
+        }
+        drop(folders);
+        self.emit(WorkspaceFoldersChanged { added, removed })
+    }
+
+    /// Register a handler of `workspace/didChangeWorkspaceFolders` to `router`, which updates
+    /// this set. `initialize` should be handled via [`WorkspaceFolders::initialize`] in its
+    /// handler.
+    pub fn register<St, Error>(&self, router: &mut Router<St, Error>)
+    where
+        Error: From<ResponseError> + Send + 'static,
+    {
+        let this = self.clone();
+        router.notification::<DidChangeWorkspaceFolders>(move |_, params| {
+            match this.did_change(params) {
+                Ok(()) => ControlFlow::Continue(()),
+                Err(err) => ControlFlow::Break(Err(err)),
+            }
+        });
+    }
+
+    fn emit(&self, change: WorkspaceFoldersChanged) -> Result<()> {
+        match &self.client {
+            Some(client) if !change.added.is_empty() || !change.removed.is_empty() => {
+                client.emit(change)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The middleware updating [`WorkspaceFolders`] from `initialize` requests and
+/// `workspace/didChangeWorkspaceFolders` notifications, before passing them to the inner
+/// service.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug)]
+pub struct WorkspaceFoldersSync<S> {
+    service: S,
+    folders: WorkspaceFolders,
+}
+
+define_getters!(impl[S] WorkspaceFoldersSync<S>, service: S);
+
+impl<S: LspService> Service<AnyRequest> for WorkspaceFoldersSync<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: AnyRequest) -> Self::Future {
+        if req.method == Initialize::METHOD {
+            if let Ok(params) = InitializeParams::deserialize(&req.params) {
+                // The main loop is stopped otherwise, thus the error is ignored.
+                let _: Result<_> = self.folders.initialize(&params);
+            }
+        }
+        self.service.call(req)
+    }
+}
+
+impl<S: LspService> LspService for WorkspaceFoldersSync<S> {
+    fn notify(&mut self, notif: AnyNotification) -> ControlFlow<Result<()>> {
+        if notif.method == DidChangeWorkspaceFolders::METHOD {
+            if let Ok(params) = DidChangeWorkspaceFoldersParams::deserialize(&notif.params) {
+                if let Err(err) = self.folders.did_change(params) {
+                    return ControlFlow::Break(Err(err));
+                }
+            }
+        }
+        self.service.notify(notif)
+    }
+
+    fn emit(&mut self, event: AnyEvent) -> ControlFlow<Result<()>> {
+        self.service.emit(event)
+    }
+
+    fn event_hints(&self, event: &AnyEvent) -> EventHints {
+        self.service.event_hints(event)
+    }
+}
+
+/// A [`tower_layer::Layer`] which builds [`WorkspaceFoldersSync`].
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct WorkspaceFoldersSyncLayer {
+    folders: WorkspaceFolders,
+}
+
+impl WorkspaceFoldersSyncLayer {
+    /// Create the layer updating `folders`.
+    pub fn new(folders: WorkspaceFolders) -> Self {
+        Self { folders }
+    }
+}
+
+impl<S> Layer<S> for WorkspaceFoldersSyncLayer {
+    type Service = WorkspaceFoldersSync<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        WorkspaceFoldersSync {
+            service: inner,
+            folders: self.folders.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uri(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    fn folder(s: &str) -> WorkspaceFolder {
+        WorkspaceFolder {
+            uri: uri(s),
+            name: s.into(),
+        }
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(
+            normalize_uri(&uri("file:///C%3A/Foo/")).as_str(),
+            "file:///c:/Foo"
+        );
+        assert_eq!(normalize_uri(&uri("file:///C:/")).as_str(), "file:///c:/");
+        assert_eq!(
+            normalize_uri(&uri("file://Server/share/a")).as_str(),
+            "file://server/share/a"
+        );
+        assert_eq!(
+            normalize_uri(&uri("untitled:Untitled-1")).as_str(),
+            "untitled:Untitled-1"
+        );
+    }
+
+    #[test]
+    fn owning_and_relative() {
+        let folders = [
+            folder("file:///c%3A/work"),
+            folder("file:///C:/work/sub"),
+            folder("file://server/share"),
+        ];
+        let doc = uri("file:///c:/work/sub/a%20b.rs");
+        assert_eq!(owning_folder(&folders, &doc), Some(&folders[1]));
+        assert_eq!(
+            relative_path(&folders[0].uri, &doc).as_deref(),
+            Some("sub/a b.rs")
+        );
+        assert_eq!(
+            relative_path(&folders[2].uri, &uri("file://SERVER/share/x/y")).as_deref(),
+            Some("x/y")
+        );
+        assert_eq!(
+            relative_path(&folders[0].uri, &folders[0].uri).as_deref(),
+            Some("")
+        );
+        assert_eq!(
+            owning_folder(&folders, &uri("file:///c:/workspace/a")),
+            None
+        );
+        assert_eq!(owning_folder(&folders, &uri("untitled:Untitled-1")), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn path_conversion() {
+        let uri = path_to_uri(Path::new("/tmp/a b/c.rs")).unwrap();
+        assert_eq!(uri.as_str(), "file:///tmp/a%20b/c.rs");
+        assert_eq!(uri_to_path(&uri).unwrap(), Path::new("/tmp/a b/c.rs"));
+        assert_eq!(path_to_uri(Path::new("relative")), None);
+    }
+
+    #[tokio::test]
+    async fn sync() {
+        use std::future::poll_fn;
+
+        use futures::channel::mpsc;
+        use futures::StreamExt;
+        use lsp_types::{InitializeResult, WorkspaceFoldersChangeEvent};
+        use serde_json::json;
+
+        use crate::{MainLoopEvent, PeerSocket, RequestId};
+
+        let (tx, mut rx) = mpsc::unbounded();
+        let folders = WorkspaceFolders::new().events(ClientSocket(PeerSocket { tx }));
+        let mut router = Router::new(());
+        router
+            .request::<Initialize, _>(|_, _| async { Ok(InitializeResult::default()) })
+            .notification::<DidChangeWorkspaceFolders>(|_, _| ControlFlow::Continue(()));
+        let mut service = WorkspaceFoldersSyncLayer::new(folders.clone()).layer(router);
+
+        poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+        service
+            .call(AnyRequest {
+                id: RequestId::Number(1),
+                method: Initialize::METHOD.into(),
+                params: json!({
+                    "capabilities": {},
+                    "rootUri": "file:///ignored",
+                    "workspaceFolders": [{ "uri": "file:///a", "name": "file:///a" }],
+                }),
+                receipt: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(folders.workspace_folders(), [folder("file:///a")]);
+
+        let params = DidChangeWorkspaceFoldersParams {
+            event: WorkspaceFoldersChangeEvent {
+                added: vec![folder("file:///b"), folder("file:///a/")],
+                removed: vec![folder("file:///a"), folder("file:///c")],
+            },
+        };
+        let ret = service.notify(AnyNotification {
+            method: DidChangeWorkspaceFolders::METHOD.into(),
+            params: serde_json::to_value(params).unwrap(),
+            receipt: None,
+        });
+        assert!(ret.is_continue());
+        assert_eq!(
+            folders.workspace_folders(),
+            [folder("file:///b"), folder("file:///a/")]
+        );
+        assert_eq!(
+            folders.owning_folder(&uri("file:///a/x")),
+            Some(folder("file:///a/"))
+        );
+
+        let mut events = Vec::new();
+        drop((service, folders));
+        while let Some(event) = rx.next().await {
+            match event {
+                MainLoopEvent::Any(event) => {
+                    events.push(event.downcast::<WorkspaceFoldersChanged>().unwrap());
+                }
+                _ => panic!("unexpected event"),
+            }
+        }
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].added[0].uri, uri("file:///a"));
+        assert!(events[0].removed.is_empty());
+        assert_eq!(events[1].added.len(), 2);
+        assert_eq!(events[1].removed[0].uri, uri("file:///a"));
+    }
+}
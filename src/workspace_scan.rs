@@ -0,0 +1,263 @@
+//! Workspace-wide file scanner honoring `.gitignore`.
+//!
+//! *Only applies to Language Servers.*
+//!
+//! Indexing servers usually need to discover all source files in the workspace folders, not only
+//! the opened ones. [`WorkspaceScanner`] walks the workspace roots, honoring `.gitignore`,
+//! `.ignore`, `.git/info/exclude` and the global git excludes, and emits each discovered file as a
+//! [`FileScanned`] loopback event into the service, which can be handled via
+//! [`Router::event`][crate::router::Router::event].
+//!
+//! The scanner remembers what it has seen. Later calls of [`WorkspaceScanner::scan`] are
+//! incremental: only new or modified files are emitted as [`FileScanned`], and vanished files are
+//! emitted as [`FileRemoved`]. Each scan ends with a [`ScanFinished`] event.
+//!
+//! Scanned files can be read via [`DiskVfs`][crate::vfs::DiskVfs], or via
+//! [`DocumentPipeline::read`][crate::pipeline::DocumentPipeline::read] to prefer unsaved
+//! contents of opened documents.
+//!
+//! Scanning is blocking IO, and is typically run on a dedicated thread. It stops early with
+//! [`Error::ServiceStopped`][crate::Error::ServiceStopped] when the main loop stopped, so it never
+//! outlives the server.
+//!
+//! ```no_run
+//! # use async_lsp::router::Router;
+//! # use async_lsp::workspace_scan::{FileScanned, WorkspaceScanner};
+//! # use std::ops::ControlFlow;
+//! # fn work(client: async_lsp::ClientSocket, router: &mut Router<()>) {
+//! router.event::<FileScanned>(|_, event| {
+//!     println!("Indexing {}", event.uri);
+//!     ControlFlow::Continue(())
+//! });
+//! let mut scanner = WorkspaceScanner::new(["/path/to/workspace"]);
+//! std::thread::spawn(move || scanner.scan(&client));
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use ignore::WalkBuilder;
+use lsp_types::{Url, WorkspaceFolder};
+
+use crate::{ClientSocket, Result};
+
+/// The loopback event of a new or modified file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FileScanned {
+    /// The file path.
+    pub path: PathBuf,
+    /// The `file:` URI of the path.
+    pub uri: Url,
+    /// The last modification time, if available.
+    pub modified: Option<SystemTime>,
+}
+
+/// The loopback event of a previously scanned file which no longer exists or is now ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FileRemoved {
+    /// The file path.
+    pub path: PathBuf,
+    /// The `file:` URI of the path.
+    pub uri: Url,
+}
+
+/// The loopback event emitted after each scan completes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ScanFinished {
+    /// The number of [`FileScanned`] events emitted in this scan.
+    pub scanned: usize,
+    /// The number of [`FileRemoved`] events emitted in this scan.
+    pub removed: usize,
+}
+
+/// The incremental workspace file scanner.
+///
+/// See [module level documentations](self) for details.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct WorkspaceScanner {
+    roots: Vec<PathBuf>,
+    hidden: bool,
+    follow_links: bool,
+    max_depth: Option<usize>,
+    seen: HashMap<PathBuf, Option<SystemTime>>,
+}
+
+impl WorkspaceScanner {
+    /// Create a scanner of the given root directories, which should be absolute paths.
+    pub fn new(roots: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        Self {
+            roots: roots.into_iter().map(Into::into).collect(),
+            hidden: false,
+            follow_links: false,
+            max_depth: None,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Create a scanner of workspace folders. Folders which are not `file:` URIs are skipped.
+    pub fn from_workspace_folders(folders: &[WorkspaceFolder]) -> Self {
+        Self::new(
+            folders
+                .iter()
+                .filter_map(|folder| folder.uri.to_file_path().ok()),
+        )
+    }
+
+    /// Whether to include hidden files and directories. Default is `false`.
+    pub fn hidden(mut self, hidden: bool) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    /// Whether to follow symbolic links. Default is `false`.
+    pub fn follow_links(mut self, follow_links: bool) -> Self {
+        self.follow_links = follow_links;
+        self
+    }
+
+    /// Set the maximum depth to descend into. Default is unlimited.
+    pub fn max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Add a root directory, typically on `workspace/didChangeWorkspaceFolders`.
+    pub fn add_root(&mut self, root: impl Into<PathBuf>) {
+        self.roots.push(root.into());
+    }
+
+    /// Remove a root directory. Its files are reported as [`FileRemoved`] in the next scan.
+    pub fn remove_root(&mut self, root: &Path) {
+        self.roots.retain(|r| r != root);
+    }
+
+    /// Walk all roots, and emit events of new, modified and removed files since the last scan,
+    /// followed by a [`ScanFinished`] event.
+    ///
+    /// Unreadable directories and entries are silently skipped.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::ServiceStopped`][crate::Error::ServiceStopped] when the service main loop
+    ///   stopped. The scan is aborted, and the next scan will start over from the state before
+    ///   this one.
+    pub fn scan(&mut self, client: &ClientSocket) -> Result<ScanFinished> {
+        let mut seen = HashMap::with_capacity(self.seen.len());
+        let mut scanned = 0;
+        for root in &self.roots {
+            let walker = WalkBuilder::new(root)
+                .hidden(!self.hidden)
+                .follow_links(self.follow_links)
+                .max_depth(self.max_depth)
+                .require_git(false)
+                .build();
+            for entry in walker.flatten() {
+                if !entry.file_type().map_or(false, |ty| ty.is_file()) {
+                    continue;
+                }
+                let path = entry.into_path();
+                if seen.contains_key(&path) {
+                    continue;
+                }
+                let uri = match Url::from_file_path(&path) {
+                    Ok(uri) => uri,
+                    Err(()) => continue,
+                };
+                let modified = path.metadata().and_then(|m| m.modified()).ok();
+                if self.seen.get(&path) != Some(&modified) {
+                    client.emit(FileScanned {
+                        path: path.clone(),
+                        uri,
+                        modified,
+                    })?;
+                    scanned += 1;
+                }
+                seen.insert(path, modified);
+            }
+        }
+
+        let mut removed = 0;
+        for path in self.seen.keys().filter(|path| !seen.contains_key(*path)) {
+            if let Ok(uri) = Url::from_file_path(path) {
+                client.emit(FileRemoved {
+                    path: path.clone(),
+                    uri,
+                })?;
+                removed += 1;
+            }
+        }
+
+        self.seen = seen;
+        let finished = ScanFinished { scanned, removed };
+        client.emit(finished)?;
+        Ok(finished)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use futures::channel::mpsc;
+
+    use super::*;
+    use crate::{MainLoopEvent, PeerSocket};
+
+    fn events(rx: &mut mpsc::UnboundedReceiver<MainLoopEvent>) -> (Vec<String>, Vec<String>) {
+        let (mut scanned, mut removed) = (Vec::new(), Vec::new());
+        while let Ok(Some(MainLoopEvent::Any(event))) = rx.try_next() {
+            if let Some(e) = event.downcast_ref::<FileScanned>() {
+                scanned.push(e.path.file_name().unwrap().to_string_lossy().into_owned());
+            } else if let Some(e) = event.downcast_ref::<FileRemoved>() {
+                removed.push(e.path.file_name().unwrap().to_string_lossy().into_owned());
+            } else {
+                assert!(event.is::<ScanFinished>());
+            }
+        }
+        scanned.sort();
+        (scanned, removed)
+    }
+
+    #[test]
+    fn incremental_scan() {
+        let root = std::env::temp_dir().join(format!("async-lsp-scan-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join(".gitignore"), "target/\n").unwrap();
+        fs::write(root.join("a.rs"), "").unwrap();
+        fs::write(root.join("sub/b.rs"), "").unwrap();
+        fs::write(root.join("target/c.rs"), "").unwrap();
+
+        let (tx, mut rx) = mpsc::unbounded();
+        let client = ClientSocket(PeerSocket { tx });
+        let mut scanner = WorkspaceScanner::new([&root]);
+
+        let ret = scanner.scan(&client).unwrap();
+        assert_eq!(
+            ret,
+            ScanFinished {
+                scanned: 2,
+                removed: 0
+            }
+        );
+        assert_eq!(
+            events(&mut rx),
+            (vec!["a.rs".into(), "b.rs".into()], vec![])
+        );
+
+        fs::remove_file(root.join("a.rs")).unwrap();
+        fs::write(root.join("d.rs"), "").unwrap();
+        scanner.scan(&client).unwrap();
+        assert_eq!(events(&mut rx), (vec!["d.rs".into()], vec!["a.rs".into()]));
+
+        drop(rx);
+        assert!(scanner.scan(&client).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}